@@ -1,6 +1,19 @@
 fn main() {
     let msg = "你好"; // 创建一个不可变文本
     println!("{}", msg);
+
+    // 把当前git提交号编进程序，get_app_info返回给前端（关于页面、反馈问题用）
+    let commit = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=CAMFC_GIT_COMMIT={}", commit);
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+
     tauri_build::build()
 }
 
@@ -0,0 +1,425 @@
+// 认证模块
+// 统一构造发给后端的认证头，并负责把日志里的认证信息打码
+//
+// 思考：原来AuthInfo和认证头构造放在download.rs里，upload.rs等模块都去那里引用，
+// 而且不少地方直接把TOTP打印到日志里（"收到TOTP: 123456"），
+// 用户把日志发出来排查问题时就把动态密码也发出来了。
+// 现在拿到TOTP/密钥的地方都调用remember_secret登记一下，
+// lib.rs里覆盖了println!/eprintln!，所有日志输出前都经过redact，把登记过的值替换掉。
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use reqwest::{header, StatusCode};
+
+use crate::event_emitter;
+use crate::settings::{self, AuthProviderKind};
+use crate::single_flight::SingleFlight;
+
+// 打码后显示的内容
+const REDACTED: &str = "******";
+// 最多记住最近多少个需要打码的值（TOTP每30秒换一次，保留几个就够了）
+const MAX_REMEMBERED_SECRETS: usize = 16;
+// 太短的值不登记，避免把日志里普通的数字也替换掉
+const MIN_SECRET_LEN: usize = 4;
+
+static SECRETS: OnceLock<Mutex<VecDeque<String>>> = OnceLock::new();
+
+fn secrets() -> &'static Mutex<VecDeque<String>> {
+    SECRETS.get_or_init(|| Mutex::new(VecDeque::new()))
+}
+
+// 登记一个需要在日志里打码的值
+pub fn remember_secret(secret: &str) {
+    let secret = secret.trim();
+    if secret.len() < MIN_SECRET_LEN {
+        return;
+    }
+
+    let mut secrets = secrets().lock().unwrap_or_else(|e| e.into_inner());
+    if secrets.iter().any(|s| s == secret) {
+        return;
+    }
+    secrets.push_back(secret.to_string());
+    while secrets.len() > MAX_REMEMBERED_SECRETS {
+        secrets.pop_front();
+    }
+}
+
+// 把文本里登记过的值替换成******
+pub fn redact(text: &str) -> String {
+    let secrets = secrets().lock().unwrap_or_else(|e| e.into_inner());
+    let mut result = text.to_string();
+    for secret in secrets.iter() {
+        if result.contains(secret.as_str()) {
+            result = result.replace(secret.as_str(), REDACTED);
+        }
+    }
+    result
+}
+
+// 认证失败退避
+// 思考：后端连续拒绝认证（笔的时间不准、连的不是配对的那支笔）时，
+// 原来会不停地问笔要TOTP然后马上重试，既没用又像在暴力猜密码。
+// 这里记录连续认证失败次数，超过阈值后锁定一段时间，每多失败一次锁定时间翻倍，
+// 锁定期间acquire_auth_info直接返回错误，不再去问笔；任何一次请求成功就清零。
+// 锁定时发auth-locked事件，前端提示用户检查设备时间和配对。
+// 并发的分片请求共用一份缓存的认证信息，一个错的TOTP会同时收到好几个401，
+// 所以按被拒绝的认证信息（设备ID+TOTP）计数，同一份只算一次失败。
+// 只有401算认证失败，403可能只是没有某个路径的权限，和认证信息对不对无关。
+
+// 连续失败几次后开始锁定
+const AUTH_FAILURE_THRESHOLD: u32 = 3;
+// 第一次锁定时长
+const AUTH_LOCK_BASE: Duration = Duration::from_secs(30);
+// 最长锁定时长
+const AUTH_LOCK_MAX: Duration = Duration::from_secs(600);
+
+#[derive(Default)]
+struct AuthFailureState {
+    failures: u32,                 // 连续失败次数
+    locked_until: Option<Instant>, // 锁定到什么时候
+    last_rejected: Option<String>, // 上一次计过数的被拒绝的认证信息
+}
+
+impl AuthFailureState {
+    // 记一次失败，credential是被拒绝的认证信息（拿不到时为None，每次都算）
+    // 返回这次开始锁定的时长，没有新锁定时为None
+    fn count_failure(&mut self, credential: Option<String>, now: Instant) -> Option<Duration> {
+        // 同一份认证信息只算一次；锁定期间的失败也不计数
+        if credential.is_some() && credential == self.last_rejected {
+            return None;
+        }
+        if self.locked_until.is_some_and(|until| until > now) {
+            return None;
+        }
+
+        self.failures += 1;
+        self.last_rejected = credential;
+        println!("后端拒绝认证，连续失败 {} 次", self.failures);
+        if self.failures < AUTH_FAILURE_THRESHOLD {
+            return None;
+        }
+        let duration = lock_duration(self.failures);
+        self.locked_until = Some(now + duration);
+        Some(duration)
+    }
+}
+
+static AUTH_FAILURES: OnceLock<Mutex<AuthFailureState>> = OnceLock::new();
+
+fn auth_failures() -> &'static Mutex<AuthFailureState> {
+    AUTH_FAILURES.get_or_init(|| Mutex::new(AuthFailureState::default()))
+}
+
+// 根据连续失败次数计算锁定时长
+fn lock_duration(failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(AUTH_FAILURE_THRESHOLD).min(16);
+    AUTH_LOCK_BASE
+        .saturating_mul(1u32 << exponent)
+        .min(AUTH_LOCK_MAX)
+}
+
+// 锁定剩余时间，没有锁定返回None
+pub fn lock_remaining() -> Option<Duration> {
+    let state = auth_failures().lock().unwrap_or_else(|e| e.into_inner());
+    state
+        .locked_until
+        .and_then(|until| until.checked_duration_since(Instant::now()))
+        .filter(|remaining| !remaining.is_zero())
+}
+
+// 锁定期间返回错误，调用方不要再去问笔要TOTP
+pub fn check_auth_lock() -> Result<()> {
+    match lock_remaining() {
+        Some(remaining) => Err(anyhow::anyhow!(
+            "认证失败次数过多，请检查设备时间和配对，{}秒后再试",
+            remaining.as_secs().max(1)
+        )),
+        None => Ok(()),
+    }
+}
+
+// 记录一次认证失败，达到阈值时锁定并通知前端
+// request_headers是被拒绝的请求的请求头，按里面的认证信息去重
+fn record_auth_failure(request_headers: &header::HeaderMap) {
+    // 被拒绝的认证信息不能再发给别的请求用
+    AUTH_MANAGER.invalidate();
+    crate::metrics::record(crate::metrics::Counter::AuthFailures);
+
+    let credential = credentials_in_header(request_headers).map(|(device_id, totp)| format!("{}:{}", device_id, totp));
+    let mut state = auth_failures().lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(duration) = state.count_failure(credential, Instant::now()) {
+        println!("认证已锁定 {} 秒", duration.as_secs());
+
+        event_emitter::emit_event("auth-locked", serde_json::json!({
+            "failures": state.failures,
+            "retry_after_secs": duration.as_secs(),
+            "message": "后端多次拒绝认证，请检查笔的时间是否准确、是否与本机正确配对",
+        }));
+    }
+}
+
+// 认证通过，清零失败计数
+pub fn record_auth_success() {
+    let mut state = auth_failures().lock().unwrap_or_else(|e| e.into_inner());
+    if state.failures > 0 {
+        println!("认证恢复正常，清除失败计数");
+    }
+    *state = AuthFailureState::default();
+}
+
+// 根据后端响应状态更新认证失败计数，request_headers是发出去的请求头
+// 401 算认证失败，2xx/304 算认证成功，其他状态（403、404、500等）和认证信息无关，不处理
+pub fn note_response_status(status: StatusCode, request_headers: &header::HeaderMap) {
+    if status == StatusCode::UNAUTHORIZED {
+        record_auth_failure(request_headers);
+    } else if status.is_success() || status == StatusCode::NOT_MODIFIED {
+        record_auth_success();
+    }
+}
+
+// 挑战应答（auth.challenge_response）
+// 思考：TOTP在30秒内一直有效，有人在旁边抓到蓝牙包就能拿着它去请求后端。
+// 开启后每次setTime带一个随机数，笔回复TOTP时同时返回用密钥算的HMAC(随机数)，
+// 随机数和HMAC放在认证头里一起发给后端校验，抓到的TOTP单独拿去用不了。
+// 随机数只能用一次：每次取认证信息都问笔要新的，不走TOTP缓存，也不缓存、不合并认证信息；
+// 设备管理器直接把挑战应答和TOTP一起返回，不经过全局变量，并发的请求不会拿到别人的随机数
+#[derive(Clone)]
+pub struct AuthChallenge {
+    pub nonce: String,   // setTime时发给笔的随机数
+    pub proof: String,   // 笔返回的HMAC(随机数)，十六进制
+}
+
+// 认证信息 - 从蓝牙设备获取
+#[derive(Clone)]
+pub struct AuthInfo {
+    pub device_id: String,                    // 设备ID
+    pub totp: String,                         // 动态密码
+    pub challenge: Option<AuthChallenge>,     // 挑战应答，没开启或者笔不支持时为None
+}
+
+// Debug输出时不显示TOTP
+impl fmt::Debug for AuthInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthInfo")
+            .field("device_id", &self.device_id)
+            .field("totp", &REDACTED)
+            .field("challenge", &self.challenge.is_some())
+            .finish()
+    }
+}
+
+impl AuthInfo {
+    // 获取认证头信息
+    // 后端要求Authorization头里放 {"Id": 设备ID, "Totp": 动态密码} 的JSON
+    // 有挑战应答时再加上 "Nonce": 随机数, "Proof": HMAC
+    pub fn get_auth_header(&self) -> Result<header::HeaderMap> {
+        remember_secret(&self.totp);
+
+        let mut auth_json = serde_json::json!({
+            "Id": self.device_id,
+            "Totp": self.totp
+        });
+        if let Some(challenge) = &self.challenge {
+            auth_json["Nonce"] = serde_json::Value::from(challenge.nonce.as_str());
+            auth_json["Proof"] = serde_json::Value::from(challenge.proof.as_str());
+        }
+        let auth_json = auth_json.to_string();
+
+        let mut value = header::HeaderValue::from_str(&auth_json)?;
+        // 标记为敏感，reqwest/hyper输出调试信息时不会显示
+        value.set_sensitive(true);
+
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, value);
+
+        Ok(headers)
+    }
+}
+
+// 从请求头里取出认证信息（设备ID, TOTP），格式见AuthInfo::get_auth_header
+pub fn credentials_in_header(headers: &header::HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let json: serde_json::Value = serde_json::from_str(value).ok()?;
+    Some((json["Id"].as_str()?.to_string(), json["Totp"].as_str()?.to_string()))
+}
+
+// 认证信息提供者
+// 思考：原来下载/上传都直接调get_device_id/get_totp问笔要认证信息，
+// 没有笔就什么都测不了。现在抽成一个trait，按设置里的auth.provider选择实现，
+// 传输相关代码只管调用current_credentials，不关心认证信息从哪来。
+pub trait AuthProvider {
+    fn credentials(&self) -> impl Future<Output = Result<AuthInfo>> + Send;
+}
+
+// 从Cpen设备获取设备ID和TOTP
+pub struct CpenProvider;
+
+impl AuthProvider for CpenProvider {
+    async fn credentials(&self) -> Result<AuthInfo> {
+        let device_id = crate::get_device_id()
+            .await
+            .map_err(|e| anyhow::anyhow!("获取设备ID失败: {}", e))?;
+        let result = if settings::get().auth.challenge_response {
+            crate::get_totp_with_challenge().await
+        } else {
+            crate::get_totp().await.map(|totp| (totp, None))
+        };
+        let (totp, challenge) = result.map_err(|e| anyhow::anyhow!("获取TOTP失败: {}", e))?;
+
+        Ok(AuthInfo { device_id, totp, challenge })
+    }
+}
+
+// 从环境变量读取固定令牌，开发时不需要连接硬件
+// CAMFC_ID 设备ID，CAMFC_TOKEN 后端配置的固定令牌（放在认证头的Totp字段里）
+pub struct EnvTokenProvider;
+
+impl AuthProvider for EnvTokenProvider {
+    async fn credentials(&self) -> Result<AuthInfo> {
+        dotenv::dotenv().ok();
+        let device_id = std::env::var("CAMFC_ID").unwrap_or_default();
+        let token = std::env::var("CAMFC_TOKEN").unwrap_or_default();
+        if device_id.is_empty() || token.is_empty() {
+            return Err(anyhow::anyhow!("使用环境变量认证需要设置CAMFC_ID和CAMFC_TOKEN"));
+        }
+
+        remember_secret(&token);
+        Ok(AuthInfo { device_id, totp: token, challenge: None })
+    }
+}
+
+// 按设置选择认证方式，获取认证信息
+async fn fetch_credentials() -> Result<AuthInfo> {
+    match settings::get().auth.provider {
+        AuthProviderKind::Cpen => CpenProvider.credentials().await,
+        AuthProviderKind::EnvToken => EnvTokenProvider.credentials().await,
+    }
+}
+
+// 认证信息缓存时间
+// TOTP在笔那边按30秒缓存、剩5秒时刷新，这里只能再缓存很短一段，不然可能拿到过期的TOTP
+const CREDENTIALS_TTL: Duration = Duration::from_secs(5);
+
+// 认证信息管理
+// 思考：一次开始20个下载时，每个下载都要get_device_id+get_totp，
+// 全部排队去拿设备管理器的锁，蓝牙交互慢的时候要等很久。
+// 所有传输都通过这里拿认证信息：
+// 1. 同时进来的请求合并成一次（single-flight），只问笔一次
+// 2. 拿到的认证信息缓存几秒，紧接着的请求直接用
+// 3. 后端拒绝认证时清掉缓存
+pub struct AuthManager {
+    cache: Mutex<Option<(AuthInfo, Instant)>>,
+    flight: SingleFlight<std::result::Result<AuthInfo, String>>,
+}
+
+impl AuthManager {
+    const fn new() -> Self {
+        Self {
+            cache: Mutex::new(None),
+            flight: SingleFlight::new(),
+        }
+    }
+
+    fn cached(&self) -> Option<AuthInfo> {
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache
+            .as_ref()
+            .filter(|(_, fetched_at)| fetched_at.elapsed() < CREDENTIALS_TTL)
+            .map(|(info, _)| info.clone())
+    }
+
+    // 清掉缓存，下次重新获取
+    pub fn invalidate(&self) {
+        *self.cache.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    pub async fn credentials(&'static self) -> Result<AuthInfo> {
+        check_auth_lock()?;
+
+        // 挑战应答的随机数只能用一次，每个请求单独问笔（见挑战应答的思考）
+        if settings::get().auth.challenge_response {
+            return fetch_credentials().await;
+        }
+
+        if let Some(info) = self.cached() {
+            return Ok(info);
+        }
+
+        self.flight
+            .run(|| async move {
+                let info = fetch_credentials().await.map_err(|e| e.to_string())?;
+                *self.cache.lock().unwrap_or_else(|e| e.into_inner()) = Some((info.clone(), Instant::now()));
+                Ok(info)
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!(e))
+    }
+}
+
+static AUTH_MANAGER: AuthManager = AuthManager::new();
+
+// 清掉缓存的认证信息（系统唤醒后、换了笔之后）
+pub fn invalidate_credentials() {
+    AUTH_MANAGER.invalidate();
+}
+
+// 清掉缓存的认证信息、挑战应答和失败锁定（clear_auth_cache/refresh_totp，排查认证问题时用）
+pub fn clear_auth_state() {
+    AUTH_MANAGER.invalidate();
+    *auth_failures().lock().unwrap_or_else(|e| e.into_inner()) = AuthFailureState::default();
+    println!("认证缓存和失败计数已清除");
+}
+
+// 获取认证信息（所有传输共用，带合并和缓存）
+pub async fn current_credentials() -> Result<AuthInfo> {
+    AUTH_MANAGER.credentials().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_each_rejected_credential_once() {
+        let now = Instant::now();
+        let mut state = AuthFailureState::default();
+        // 同一个TOTP的几个并发请求一起被拒绝，只算一次
+        for _ in 0..3 {
+            assert_eq!(state.count_failure(Some("pen:111111".into()), now), None);
+        }
+        assert_eq!(state.failures, 1);
+
+        assert_eq!(state.count_failure(Some("pen:222222".into()), now), None);
+        assert_eq!(state.count_failure(Some("pen:333333".into()), now), Some(AUTH_LOCK_BASE));
+        assert_eq!(state.failures, AUTH_FAILURE_THRESHOLD);
+        // 锁定期间不再计数
+        assert_eq!(state.count_failure(Some("pen:444444".into()), now), None);
+        assert_eq!(state.failures, AUTH_FAILURE_THRESHOLD);
+    }
+
+    #[test]
+    fn redacts_remembered_secrets() {
+        remember_secret("482913");
+        remember_secret("12");   // 太短，不登记
+        assert_eq!(redact("收到TOTP: 482913"), "收到TOTP: ******");
+        assert_eq!(redact("分片 12/30"), "分片 12/30");
+    }
+
+    #[test]
+    fn println_and_eprintln_output_is_redacted() {
+        remember_secret("totp-917364");
+        remember_secret("token-a1b2c3d4e5");
+        println!("收到TOTP: {}", "totp-917364");
+        eprintln!("认证头: Bearer {}", "token-a1b2c3d4e5");
+
+        let logs = crate::crash_report::recent_logs();
+        assert!(logs.iter().any(|line| line.ends_with("收到TOTP: ******")));
+        assert!(logs.iter().any(|line| line.ends_with("认证头: Bearer ******")));
+        assert!(!logs.iter().any(|line| line.contains("totp-917364") || line.contains("token-a1b2c3d4e5")));
+    }
+}
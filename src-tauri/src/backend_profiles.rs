@@ -0,0 +1,147 @@
+// 多后端配置
+// 用户可以存几个命名的后端（比如"家里"、"公司"，各自的地址、端口、代理、CA证书），
+// 存在设置的backend.profiles里，list_backend_profiles列出来，switch_backend_profile切换
+//
+// 思考：
+// 1. 切换时改设置里的active_profile（下次启动还用它），再换掉config里的当前后端，
+//    http_client发现后端配置变了，会按新的代理和证书重新创建客户端
+// 2. 一个文件不能一半传到旧后端、一半传到新后端。每个任务创建时记下当时的后端地址
+//    （见transfer_http::TransferHttp），之后的请求都发到那里，切换后端不影响已经创建的任务。
+//    已经创建的任务怎么办按设置里的queued_on_switch（排队中的）和in_flight_on_switch（正在传的）：
+//    finish_on_old：继续在原来的后端上传完；
+//    restart_on_new：先暂停，等当前分片写完停下来（最多RESTART_WAIT），切换后重新创建任务，在新后端上继续。
+//    默认排队中的去新后端，正在传的在旧后端上传完。
+//    下载靠本地的部分续传，上传的会话在新后端上不存在，会重新申请（见UploadTask::query_uploaded_chunks）。
+//    在旧后端上传完的任务如果失败了，自动重试时重新创建任务，就到了新后端上
+// 3. 调用时可以用mode临时指定正在传的任务怎么办：drain相当于finish_on_old，retarget相当于restart_on_new
+// 4. name为None表示不用后端配置，回到自动选择（远程配置/默认值），要重新检测一次远程配置
+// 5. 重新开始的任务不算重试次数
+
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, ConfigSource};
+use crate::settings::{self, BackendProfile, HostPolicy};
+use crate::transfer_manager;
+
+// 暂停的任务最多等多久停下来
+const RESTART_WAIT: Duration = Duration::from_secs(10);
+// 多久看一次任务停下来没有
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// 切换时临时指定正在传的任务怎么办（见思考3）
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SwitchMode {
+    Drain,      // 在旧后端上传完
+    Retarget,   // 暂停，切换后在新后端上继续
+}
+
+impl SwitchMode {
+    fn in_flight_policy(self) -> HostPolicy {
+        match self {
+            SwitchMode::Drain => HostPolicy::FinishOnOld,
+            SwitchMode::Retarget => HostPolicy::RestartOnNew,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendProfiles {
+    pub profiles: Vec<BackendProfile>,
+    pub active_profile: Option<String>,
+    pub current_url: Option<String>,        // 现在实际连的后端
+    pub source: Option<ConfigSource>,       // 现在的后端从哪来（环境变量优先于后端配置）
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SwitchResult {
+    pub active_profile: Option<String>,
+    pub url: String,
+    pub resumed: Vec<String>,   // 切换后在新后端上重新开始的任务
+    pub failed: Vec<String>,    // 没能重新开始的任务（还是暂停状态，用户可以手动重试）
+}
+
+pub fn list() -> BackendProfiles {
+    let backend = settings::get().backend;
+    let current = config::get_backend_config().ok();
+    BackendProfiles {
+        profiles: backend.profiles,
+        active_profile: backend.active_profile,
+        current_url: current.as_ref().map(|config| config.get_full_url()),
+        source: current.map(|config| config.source),
+    }
+}
+
+// 等暂停了的任务都停下来，最多等RESTART_WAIT
+async fn wait_until_stopped(ids: &[String]) {
+    let deadline = Instant::now() + RESTART_WAIT;
+    loop {
+        let running = transfer_manager::running_among(ids).await;
+        if running == 0 {
+            return;
+        }
+        if Instant::now() >= deadline {
+            println!("还有 {} 个传输没有停下来", running);
+            return;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// 把暂停的任务重新开始，返回(成功的, 失败的)
+// 还没停下来的不能重新创建（两个任务会同时写一个文件），留着暂停状态让用户手动重试
+async fn resume_all(ids: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let mut resumed = Vec::new();
+    let mut failed = Vec::new();
+    for id in ids {
+        if transfer_manager::running_among(std::slice::from_ref(&id)).await > 0 {
+            println!("任务 {} 还没有停下来，不重新开始", id);
+            failed.push(id);
+            continue;
+        }
+        match transfer_manager::restart_transfer(&id, false).await {
+            Ok(_) => resumed.push(id),
+            Err(e) => {
+                println!("任务 {} 重新开始失败: {}", id, e);
+                failed.push(id);
+            }
+        }
+    }
+    (resumed, failed)
+}
+
+// 切换到name对应的后端配置，None表示回到自动选择（见思考2、4）
+pub async fn switch(name: Option<&str>, mode: Option<SwitchMode>) -> Result<SwitchResult> {
+    let backend = settings::get().backend;
+    let profile = match name {
+        Some(name) => Some(
+            backend.profile(name).cloned()
+                .with_context(|| format!("后端配置不存在: {}", name))?,
+        ),
+        None => None,
+    };
+    let in_flight = mode.map(SwitchMode::in_flight_policy).unwrap_or(backend.in_flight_on_switch);
+
+    let paused = transfer_manager::pause_transfers(
+        backend.queued_on_switch == HostPolicy::RestartOnNew,
+        in_flight == HostPolicy::RestartOnNew,
+    ).await;
+    wait_until_stopped(&paused).await;
+
+    let config = match &profile {
+        Some(profile) => config::from_profile(profile),
+        None => config::resolve_automatic().await,
+    };
+    if let Err(e) = settings::update(serde_json::json!({ "backend": { "active_profile": name } })).await {
+        resume_all(paused).await;
+        return Err(e.context("保存后端配置失败"));
+    }
+    let url = config.get_full_url();
+    println!("切换后端: {:?} -> {}，重新开始 {} 个任务", name, url, paused.len());
+    config::set_backend_config(config);
+
+    let (resumed, failed) = resume_all(paused).await;
+    Ok(SwitchResult { active_profile: name.map(str::to_string), url, resumed, failed })
+}
@@ -0,0 +1,121 @@
+// 后台模式
+// 窗口都失去焦点（切到别的程序、最小化、关到托盘）时降低传输并发和速度，窗口回到前台后恢复
+//
+// 思考：用户一边开会/打游戏一边挂着大文件传输，传输把带宽全占了，前台的程序反而卡。
+// 窗口不在前台说明用户这会儿不盯着进度，慢一点没关系。做法：
+// 1. Tauri的窗口事件里收到Focused时记下哪些窗口有焦点，全都没有焦点就进入后台模式
+// 2. 焦点在主窗口和进度小窗口之间切换时会先失去再得到，所以失去焦点后等BACKGROUND_DELAY才算进后台
+// 3. 后台时并发数不超过background.max_concurrent_transfers（已经在传的任务不打断，传完一个少一个），
+//    每个分片传完后按background.bandwidth_cap_kbps限速，和计费网络的限速同时生效时按更慢的那个等
+// 4. 回到前台马上恢复，唤醒排队等名额的任务
+// 命令行模式没有窗口，不会进入后台模式。
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::rate_limiter::RateLimiter;
+use crate::settings::{self, BackgroundSettings};
+use crate::transfer_queue::Direction;
+use crate::{concurrency, event_emitter};
+
+// 所有窗口失去焦点多久后进入后台模式
+const BACKGROUND_DELAY: Duration = Duration::from_secs(2);
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+// 焦点变化的次数，延迟进入后台前检查期间有没有窗口重新得到焦点
+static FOCUS_GENERATION: AtomicU64 = AtomicU64::new(0);
+static FOCUSED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+// 后台限速的限速器
+static LIMITER: RateLimiter = RateLimiter::new();
+
+fn focused() -> &'static Mutex<HashSet<String>> {
+    FOCUSED.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+// 现在是不是后台模式（设置里关掉时一直是false）
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::SeqCst) && settings::get().background.enabled
+}
+
+// 窗口焦点变化，由窗口事件调用
+pub fn set_focused(window_label: &str, is_focused: bool) {
+    let any_focused = {
+        let mut focused = focused().lock().unwrap();
+        if is_focused {
+            focused.insert(window_label.to_string());
+        } else {
+            focused.remove(window_label);
+        }
+        !focused.is_empty()
+    };
+    let generation = FOCUS_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+
+    if any_focused {
+        set_active(false);
+        return;
+    }
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(BACKGROUND_DELAY).await;
+        if FOCUS_GENERATION.load(Ordering::SeqCst) == generation {
+            set_active(true);
+        }
+    });
+}
+
+fn set_active(active: bool) {
+    if ACTIVE.swap(active, Ordering::SeqCst) == active {
+        return;
+    }
+    println!("[后台模式] {}", if active { "窗口已不在前台，降低传输速度" } else { "窗口回到前台，恢复传输速度" });
+    if !active {
+        // 后台时排到很后面的发送时间作废，等名额的任务重新检查
+        LIMITER.reset();
+        concurrency::wake_waiters();
+    }
+    event_emitter::emit_event("background-mode-changed", serde_json::json!({
+        "active": active,
+        "enabled": settings::get().background.enabled,
+    }));
+}
+
+// 后台时的并发上限
+fn cap_limit(limit: usize, background: &BackgroundSettings) -> usize {
+    match background.max_concurrent_transfers {
+        0 => limit,
+        max => limit.min(max),
+    }
+}
+
+// 按后台模式调整并发上限，不在后台时原样返回
+pub fn limit_concurrency(limit: usize) -> usize {
+    if !is_active() {
+        return limit;
+    }
+    cap_limit(limit, &settings::get().background)
+}
+
+// 传完一个分片后记账，返回后台模式下按速度上限可以继续的时间，不在后台或不限速时为None
+pub fn reserve(direction: Direction, bytes: u64) -> Option<Instant> {
+    if !is_active() {
+        return None;
+    }
+    LIMITER.reserve(direction, bytes, settings::get().background.bandwidth_cap_kbps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_concurrency_only_when_configured() {
+        let background = BackgroundSettings { max_concurrent_transfers: 2, ..Default::default() };
+        assert_eq!(cap_limit(6, &background), 2);
+        assert_eq!(cap_limit(1, &background), 1);
+
+        let unlimited = BackgroundSettings { max_concurrent_transfers: 0, ..Default::default() };
+        assert_eq!(cap_limit(6, &unlimited), 6);
+    }
+}
@@ -0,0 +1,252 @@
+// 流量统计模块
+// 统计本次会话和每天的上传/下载字节数
+//
+// 思考：有些用户是按流量计费的网络，需要知道客户端到底用了多少流量。
+// 会话统计只放内存里，每日统计持久化到应用数据目录的bandwidth_usage.json，
+// 和app_data.json放在一起。写盘做了节流，不会每个分片都写一次文件。
+
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::storage::get_app_data_dir;
+
+// 最多保留最近90天的记录
+const MAX_DAYS_KEPT: usize = 90;
+// 两次写盘的最小间隔
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+// 某一天的流量
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DailyUsage {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+// 持久化的每日流量记录，日期(YYYY-MM-DD) -> 流量
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BandwidthHistory {
+    days: BTreeMap<String, DailyUsage>,
+}
+
+struct BandwidthState {
+    history: BandwidthHistory,
+    loaded: bool,
+    dirty: bool,
+    last_flush: Option<Instant>,
+}
+
+// 本次会话的统计
+static SESSION_UPLOADED: AtomicU64 = AtomicU64::new(0);
+static SESSION_DOWNLOADED: AtomicU64 = AtomicU64::new(0);
+static SESSION_STARTED_AT: OnceLock<i64> = OnceLock::new();
+
+// 每日统计
+static BANDWIDTH_STATE: OnceLock<Mutex<BandwidthState>> = OnceLock::new();
+// 写盘时持有：写文件时不拿着BANDWIDTH_STATE，两次写盘按生成内容的先后进行，旧数据不会覆盖新数据
+static WRITE_LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+
+fn state() -> &'static Mutex<BandwidthState> {
+    SESSION_STARTED_AT.get_or_init(|| chrono::Utc::now().timestamp());
+    BANDWIDTH_STATE.get_or_init(|| {
+        Mutex::new(BandwidthState {
+            history: BandwidthHistory::default(),
+            loaded: false,
+            dirty: false,
+            last_flush: None,
+        })
+    })
+}
+
+fn get_history_path() -> Result<std::path::PathBuf> {
+    let data_dir = get_app_data_dir().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(data_dir.join("bandwidth_usage.json"))
+}
+
+fn today() -> String {
+    chrono::Local::now().format("%Y-%m-%d").to_string()
+}
+
+// 第一次使用时从磁盘加载历史记录
+async fn ensure_loaded(state: &mut BandwidthState) {
+    if state.loaded {
+        return;
+    }
+    state.loaded = true;
+
+    let path = match get_history_path() {
+        Ok(path) => path,
+        Err(e) => {
+            println!("获取流量统计文件路径失败: {}", e);
+            return;
+        }
+    };
+
+    if !path.exists() {
+        return;
+    }
+
+    match fs::read_to_string(&path).await {
+        Ok(content) => {
+            let loaded: BandwidthHistory = match serde_json::from_str(&content) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    // 坏文件改名留着，不然下次写盘就把按天的记录全覆盖掉了
+                    let backup = path.with_extension("json.bak");
+                    println!("流量统计文件格式错误，改名为 {:?} 后重新统计: {}", backup, e);
+                    if let Err(e) = fs::rename(&path, &backup).await {
+                        println!("备份流量统计文件失败: {}", e);
+                    }
+                    BandwidthHistory::default()
+                }
+            };
+            // 合并加载前已经记录的数据
+            for (date, usage) in loaded.days {
+                let entry = state.history.days.entry(date).or_default();
+                entry.uploaded += usage.uploaded;
+                entry.downloaded += usage.downloaded;
+            }
+        }
+        Err(e) => println!("读取流量统计文件失败: {}", e),
+    }
+}
+
+// 在锁里整理好要写的内容
+fn take_snapshot(state: &mut BandwidthState) -> Result<String> {
+    // 只保留最近MAX_DAYS_KEPT天
+    while state.history.days.len() > MAX_DAYS_KEPT {
+        let oldest = state.history.days.keys().next().cloned();
+        if let Some(oldest) = oldest {
+            state.history.days.remove(&oldest);
+        }
+    }
+
+    let content = serde_json::to_string_pretty(&state.history)
+        .context("序列化流量统计失败")?;
+    state.dirty = false;
+    state.last_flush = Some(Instant::now());
+    Ok(content)
+}
+
+async fn write_history(content: String) -> Result<()> {
+    let path = get_history_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await
+            .context(format!("创建数据目录失败: {:?}", parent))?;
+    }
+
+    // 先写临时文件再重命名，写到一半崩溃不会留下读不出来的文件
+    let tmp_path = path.with_extension("json.tmp");
+    let mut file = fs::File::create(&tmp_path).await
+        .context(format!("创建流量统计临时文件失败: {:?}", tmp_path))?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, content.as_bytes()).await
+        .context("写入流量统计文件失败")?;
+    file.sync_all().await.context("同步流量统计文件失败")?;
+    drop(file);
+
+    fs::rename(&tmp_path, &path).await
+        .context(format!("保存流量统计文件失败: {:?}", path))
+}
+
+// 写盘，写文件期间不持有统计的锁，记录流量的分片不用等
+async fn flush() -> Result<()> {
+    let _writing = WRITE_LOCK.get_or_init(|| Mutex::new(())).lock().await;
+    let content = take_snapshot(&mut *state().lock().await)?;
+    if let Err(e) = write_history(content).await {
+        // 没写进去，下次再写
+        state().lock().await.dirty = true;
+        return Err(e);
+    }
+    Ok(())
+}
+
+async fn record(uploaded: u64, downloaded: u64) {
+    SESSION_UPLOADED.fetch_add(uploaded, Ordering::SeqCst);
+    SESSION_DOWNLOADED.fetch_add(downloaded, Ordering::SeqCst);
+
+    let should_flush = {
+        let mut state = state().lock().await;
+        ensure_loaded(&mut state).await;
+
+        let entry = state.history.days.entry(today()).or_default();
+        entry.uploaded += uploaded;
+        entry.downloaded += downloaded;
+        state.dirty = true;
+
+        // 写盘节流
+        state.last_flush.is_none_or(|t| t.elapsed() >= FLUSH_INTERVAL)
+    };
+    if should_flush {
+        if let Err(e) = flush().await {
+            println!("保存流量统计失败: {}", e);
+        }
+    }
+}
+
+// 记录上传的字节数
+pub async fn record_uploaded(bytes: u64) {
+    record(bytes, 0).await;
+}
+
+// 记录下载的字节数
+pub async fn record_downloaded(bytes: u64) {
+    record(0, bytes).await;
+}
+
+// 本次会话上传和下载的总字节数
+pub fn session_bytes() -> u64 {
+    SESSION_UPLOADED.load(Ordering::SeqCst) + SESSION_DOWNLOADED.load(Ordering::SeqCst)
+}
+
+// 把还没写盘的数据写入文件（退出前调用）
+pub async fn flush_pending() {
+    let dirty = state().lock().await.dirty;
+    if dirty {
+        if let Err(e) = flush().await {
+            println!("保存流量统计失败: {}", e);
+        }
+    }
+}
+
+// 获取流量统计汇总
+pub async fn get_usage_summary() -> serde_json::Value {
+    let mut state = state().lock().await;
+    ensure_loaded(&mut state).await;
+
+    let today_key = today();
+    let today_usage = state.history.days.get(&today_key).cloned().unwrap_or_default();
+
+    // 最近的在前面
+    let days: Vec<serde_json::Value> = state
+        .history
+        .days
+        .iter()
+        .rev()
+        .map(|(date, usage)| {
+            serde_json::json!({
+                "date": date,
+                "uploaded": usage.uploaded,
+                "downloaded": usage.downloaded,
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "session": {
+            "started_at": SESSION_STARTED_AT.get().copied().unwrap_or_default(),
+            "uploaded": SESSION_UPLOADED.load(Ordering::SeqCst),
+            "downloaded": SESSION_DOWNLOADED.load(Ordering::SeqCst),
+        },
+        "today": {
+            "date": today_key,
+            "uploaded": today_usage.uploaded,
+            "downloaded": today_usage.downloaded,
+        },
+        "days": days,
+    })
+}
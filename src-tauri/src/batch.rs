@@ -0,0 +1,150 @@
+// 批量上传模块
+// 记录批量上传中每个文件的结果（成功/失败）
+// 文件夹下载也按批次记录（DownloadBatch），全部下完后生成校验清单，见checksum_manifest.rs
+//
+// 思考：原来批量上传时只要有一个文件UploadTask::new失败，整个命令就直接返回错误，
+// 后面的文件也不传了。现在改成逐个收集结果，失败的文件记录下来，
+// 之后可以通过retry_failed_in_batch只重试失败的那部分。
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tokio::sync::Mutex;
+use serde::{Serialize, Deserialize};
+
+// 批量任务中单个文件的结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchItem {
+    pub file_path: String,            // 本地文件路径
+    pub upload_id: Option<String>,    // 成功创建任务后的upload_id
+    pub error: Option<String>,        // 失败原因（None表示成功）
+    // 这个文件自己的目标目录（文件夹上传时每个文件的目录不同），None表示用批次的target_path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_path: Option<String>,
+}
+
+impl BatchItem {
+    pub fn ok(file_path: String, upload_id: String) -> Self {
+        Self {
+            file_path,
+            upload_id: Some(upload_id),
+            error: None,
+            target_path: None,
+        }
+    }
+
+    pub fn failed(file_path: String, error: String) -> Self {
+        Self {
+            file_path,
+            upload_id: None,
+            error: Some(error),
+            target_path: None,
+        }
+    }
+
+    pub fn with_target_path(mut self, target_path: Option<String>) -> Self {
+        self.target_path = target_path;
+        self
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.error.is_some()
+    }
+}
+
+// 一次批量上传
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadBatch {
+    pub batch_id: String,
+    pub target_path: Option<String>,
+    pub items: Vec<BatchItem>,
+}
+
+impl UploadBatch {
+    pub fn new(target_path: Option<String>) -> Self {
+        Self {
+            batch_id: uuid::Uuid::new_v4().to_string(),
+            target_path,
+            items: Vec::new(),
+        }
+    }
+
+    // 生成返回给前端的结构化汇总
+    pub fn summary(&self) -> serde_json::Value {
+        let succeeded: Vec<&BatchItem> = self.items.iter().filter(|i| !i.is_failed()).collect();
+        let failed: Vec<&BatchItem> = self.items.iter().filter(|i| i.is_failed()).collect();
+
+        let upload_ids: Vec<&String> = succeeded
+            .iter()
+            .filter_map(|i| i.upload_id.as_ref())
+            .collect();
+        let file_paths: Vec<&String> = self.items.iter().map(|i| &i.file_path).collect();
+
+        serde_json::json!({
+            // 只要有一个文件成功就算成功，失败的文件在failed里单独列出；空批次没有失败，也算成功
+            "success": self.items.is_empty() || !succeeded.is_empty(),
+            "batch_id": self.batch_id,
+            "upload_ids": upload_ids,
+            "file_paths": file_paths,
+            "count": upload_ids.len(),
+            "succeeded_count": succeeded.len(),
+            "failed_count": failed.len(),
+            "failed": failed,
+            "items": self.items,
+            "target_path": self.target_path.clone().unwrap_or_default()
+        })
+    }
+}
+
+// 批量任务管理器，batch_id -> 批量任务
+static UPLOAD_BATCHES: OnceLock<Mutex<HashMap<String, UploadBatch>>> = OnceLock::new();
+
+fn batches() -> &'static Mutex<HashMap<String, UploadBatch>> {
+    UPLOAD_BATCHES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// 保存批量任务（已存在则覆盖）
+pub async fn save_batch(batch: UploadBatch) {
+    batches().lock().await.insert(batch.batch_id.clone(), batch);
+}
+
+// 获取批量任务的副本
+pub async fn get_batch(batch_id: &str) -> Option<UploadBatch> {
+    batches().lock().await.get(batch_id).cloned()
+}
+
+// 一次文件夹下载
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadBatch {
+    pub batch_id: String,
+    pub remote_path: String,    // 下载的云盘文件夹
+    pub save_dir: PathBuf,      // 文件夹在本地的位置，校验清单写在这里
+    pub file_ids: Vec<String>,  // 成功创建了下载任务的文件，以及已经是最新不用下载的文件
+    pub failed: Vec<BatchItem>, // 创建任务就失败的文件（file_path是云盘路径）
+}
+
+impl DownloadBatch {
+    pub fn new(remote_path: String, save_dir: PathBuf) -> Self {
+        Self {
+            batch_id: uuid::Uuid::new_v4().to_string(),
+            remote_path,
+            save_dir,
+            file_ids: Vec::new(),
+            failed: Vec::new(),
+        }
+    }
+}
+
+static DOWNLOAD_BATCHES: OnceLock<Mutex<HashMap<String, DownloadBatch>>> = OnceLock::new();
+
+fn download_batches() -> &'static Mutex<HashMap<String, DownloadBatch>> {
+    DOWNLOAD_BATCHES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub async fn save_download_batch(batch: DownloadBatch) {
+    download_batches().lock().await.insert(batch.batch_id.clone(), batch);
+}
+
+pub async fn get_download_batch(batch_id: &str) -> Option<DownloadBatch> {
+    download_batches().lock().await.get(batch_id).cloned()
+}
@@ -0,0 +1,4 @@
+// 命令行版本，不启动界面，用法见 camfc-cli --help
+fn main() {
+    std::process::exit(camfc_client_lib::cli::run())
+}
@@ -0,0 +1,183 @@
+// 蓝牙权限检查
+// 没有权限时蓝牙操作的错误都是"获取适配器失败"、"开始扫描失败"这种，看不出是权限问题。
+// 这里按平台检查一遍，返回诊断结果和处理办法，首次使用引导和命令行status都会先调用
+//
+// 思考：
+// 1. Windows：向系统申请控制无线电的权限（Radio::RequestAccessAsync），
+//    隐私设置里关掉了"允许应用控制设备无线电"时是DeniedByUser，组策略禁止时是DeniedBySystem。
+//    .get()会阻塞线程等结果（可能要等用户点弹窗），放到spawn_blocking里，不占tokio的工作线程
+// 2. macOS：应用第一次用蓝牙时系统会弹窗，用户拒绝后CoreBluetooth报告未授权，
+//    btleplug把未授权报成适配器状态Unknown，所以适配器在但状态拿不到就当作没有权限
+// 3. Linux：BlueZ走D-Bus系统总线。先试一次获取适配器，拿到了就是有权限（不在bluetooth组里
+//    但D-Bus策略允许访问的发行版也一样）；只有D-Bus真的拒绝访问（AccessDenied/NotPermitted）
+//    才算没有权限。这时再看当前进程在不在bluetooth组里，只用来决定给用户哪种处理办法
+// 4. 检查不出来的（没有适配器、其他错误）返回Unknown，不拦着后面的步骤，让它们报具体错误
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PermissionStatus {
+    Granted,   // 有权限
+    Denied,    // 没有权限，按remediation处理
+    Unknown,   // 检查不出来
+}
+
+// 权限检查结果
+#[derive(Debug, Clone, Serialize)]
+pub struct PermissionReport {
+    pub platform: &'static str,             // windows/macos/linux
+    pub status: PermissionStatus,
+    pub message: String,
+    pub remediation: Option<&'static str>,  // 给用户看的处理办法，有权限时为None
+}
+
+impl PermissionReport {
+    fn new(status: PermissionStatus, message: impl Into<String>, remediation: Option<&'static str>) -> Self {
+        Self { platform: std::env::consts::OS, status, message: message.into(), remediation }
+    }
+
+    #[cfg_attr(not(feature = "ble"), allow(dead_code))]
+    fn granted(message: impl Into<String>) -> Self {
+        Self::new(PermissionStatus::Granted, message, None)
+    }
+
+    #[cfg_attr(not(feature = "ble"), allow(dead_code))]
+    fn denied(message: impl Into<String>, remediation: &'static str) -> Self {
+        Self::new(PermissionStatus::Denied, message, Some(remediation))
+    }
+
+    fn unknown(message: impl Into<String>, remediation: Option<&'static str>) -> Self {
+        Self::new(PermissionStatus::Unknown, message, remediation)
+    }
+
+    pub fn is_denied(&self) -> bool {
+        self.status == PermissionStatus::Denied
+    }
+}
+
+// 检查当前平台上的蓝牙权限
+pub async fn check_ble_permissions() -> PermissionReport {
+    let report = check_platform().await;
+    println!("[蓝牙权限] {}: {:?} - {}", report.platform, report.status, report.message);
+    report
+}
+
+#[cfg(not(feature = "ble"))]
+async fn check_platform() -> PermissionReport {
+    PermissionReport::unknown(crate::disabled::BLE_DISABLED, None)
+}
+
+// 见思考1
+#[cfg(all(feature = "ble", windows))]
+async fn check_platform() -> PermissionReport {
+    use windows::Devices::Radios::{Radio, RadioAccessStatus};
+
+    let access = tokio::task::spawn_blocking(|| Radio::RequestAccessAsync().and_then(|op| op.get())).await;
+    let access = match access {
+        Ok(access) => access,
+        Err(e) => return PermissionReport::unknown(format!("申请蓝牙权限失败: {}", e), None),
+    };
+    match access {
+        Ok(RadioAccessStatus::Allowed) => PermissionReport::granted("允许控制蓝牙无线电"),
+        Ok(RadioAccessStatus::DeniedByUser) => PermissionReport::denied(
+            "Windows隐私设置不允许应用控制设备无线电",
+            "打开 设置 → 隐私和安全性 → 无线电，打开\"允许应用控制设备无线电\"，然后重试",
+        ),
+        Ok(RadioAccessStatus::DeniedBySystem) => PermissionReport::denied(
+            "系统策略禁止应用访问蓝牙",
+            "请联系电脑管理员，检查组策略或设备管理软件是否禁用了蓝牙",
+        ),
+        Ok(status) => PermissionReport::unknown(format!("无法确定蓝牙权限: {:?}", status), None),
+        Err(e) => PermissionReport::unknown(format!("申请蓝牙权限失败: {}", e), None),
+    }
+}
+
+// 见思考2
+#[cfg(all(feature = "ble", target_os = "macos"))]
+async fn check_platform() -> PermissionReport {
+    use crate::bluetooth::BluetoothManager;
+
+    const REMEDIATION: &str = "打开 系统设置 → 隐私与安全性 → 蓝牙，允许CAMFC使用蓝牙，然后重新打开应用";
+    match BluetoothManager::find_selected_adapter().await {
+        Ok(Some((_, adapter))) => match BluetoothManager::adapter_powered(&adapter).await {
+            Some(_) => PermissionReport::granted("已授权使用蓝牙"),
+            None => PermissionReport::denied("系统没有授权CAMFC使用蓝牙", REMEDIATION),
+        },
+        Ok(None) => PermissionReport::unknown("没有找到蓝牙适配器", None),
+        Err(e) => PermissionReport::denied(format!("无法访问蓝牙，可能没有授权: {}", e), REMEDIATION),
+    }
+}
+
+// 见思考3
+#[cfg(all(feature = "ble", target_os = "linux"))]
+async fn check_platform() -> PermissionReport {
+    use crate::bluetooth::BluetoothManager;
+
+    const GROUP_REMEDIATION: &str = "运行 sudo usermod -aG bluetooth $USER 把当前用户加入bluetooth组，然后注销并重新登录";
+    const POLICY_REMEDIATION: &str = "当前用户不能通过D-Bus访问BlueZ，请检查/etc/dbus-1/system.d/bluetooth.conf，或者把用户加入bluetooth组";
+    const SERVICE_REMEDIATION: &str = "请确认已经安装并启动了BlueZ：sudo systemctl enable --now bluetooth";
+
+    match BluetoothManager::find_selected_adapter().await {
+        Ok(Some(_)) => PermissionReport::granted("可以访问蓝牙适配器"),
+        Ok(None) => PermissionReport::unknown("没有找到蓝牙适配器", Some(SERVICE_REMEDIATION)),
+        Err(e) if e.contains("AccessDenied") || e.contains("NotPermitted") => {
+            let group_file = tokio::fs::read_to_string("/etc/group").await.unwrap_or_default();
+            let proc_status = tokio::fs::read_to_string("/proc/self/status").await.unwrap_or_default();
+            match in_group(&group_file, &proc_status, BLUETOOTH_GROUP) {
+                Some(false) => PermissionReport::denied(format!("D-Bus拒绝访问蓝牙，当前用户不在bluetooth组里: {}", e), GROUP_REMEDIATION),
+                _ => PermissionReport::denied(format!("D-Bus拒绝访问蓝牙: {}", e), POLICY_REMEDIATION),
+            }
+        }
+        Err(e) => PermissionReport::unknown(format!("无法访问蓝牙: {}", e), Some(SERVICE_REMEDIATION)),
+    }
+}
+
+#[cfg(all(feature = "ble", not(any(windows, target_os = "macos", target_os = "linux"))))]
+async fn check_platform() -> PermissionReport {
+    PermissionReport::unknown("这个平台不检查蓝牙权限", None)
+}
+
+// Linux上控制蓝牙访问的用户组
+#[cfg_attr(not(all(feature = "ble", target_os = "linux")), allow(dead_code))]
+const BLUETOOTH_GROUP: &str = "bluetooth";
+
+// 当前进程在不在group组里，没有这个组时返回None
+// group_file是/etc/group的内容，proc_status是/proc/self/status的内容（Gid和Groups两行）
+#[cfg_attr(not(all(feature = "ble", target_os = "linux")), allow(dead_code))]
+fn in_group(group_file: &str, proc_status: &str, group: &str) -> Option<bool> {
+    // /etc/group每行是 名字:密码:gid:成员
+    let gid = group_file.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        (fields.next()? == group).then_some(())?;
+        fields.nth(1)?.trim().parse::<u32>().ok()
+    })?;
+
+    let mut gids = proc_status.lines().filter_map(|line| {
+        // Gid行依次是real/effective/saved/fs，只看effective
+        if let Some(ids) = line.strip_prefix("Gid:") {
+            return Some(ids.split_whitespace().nth(1).into_iter().collect::<Vec<_>>());
+        }
+        line.strip_prefix("Groups:").map(|ids| ids.split_whitespace().collect())
+    });
+    Some(gids.any(|ids| ids.iter().any(|id| id.parse::<u32>().ok() == Some(gid))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_group_membership() {
+        let group_file = "root:x:0:\nlp:x:7:\nbluetooth:x:112:alice\n";
+        let member = "Name:\tcamfc\nGid:\t1000\t1000\t1000\t1000\nGroups:\t27 112 1000 \n";
+        let not_member = "Name:\tcamfc\nGid:\t1000\t1000\t1000\t1000\nGroups:\t27 1000 \n";
+        let primary = "Name:\tcamfc\nGid:\t112\t112\t112\t112\nGroups:\t\n";
+
+        assert_eq!(in_group(group_file, member, "bluetooth"), Some(true));
+        assert_eq!(in_group(group_file, not_member, "bluetooth"), Some(false));
+        assert_eq!(in_group(group_file, primary, "bluetooth"), Some(true));
+        // 没有bluetooth组的发行版不按组限制
+        assert_eq!(in_group("root:x:0:\n", member, "bluetooth"), None);
+    }
+}
@@ -0,0 +1,180 @@
+//! 蓝牙连接统计（调试用）
+//!
+//! 连接不稳定时，光看日志很难分清是笔的固件有问题还是电脑的蓝牙适配器有问题。
+//! 这里记下写入次数、收到的通知数、订阅失败、重连次数、命令往返时间，get_ble_stats给前端看。
+//! 写入一直成功但通知很少、往返时间很长，多半是笔那边没回；写入失败、订阅失败多，多半是适配器的问题。
+//!
+//! 思考：
+//! 1. 两组计数：total从程序启动或上次重置开始算，session从这次连上笔开始算，每次连上时清零
+//! 2. 通知在bluetooth的监听任务里计数，所以全用原子变量，不拿设备管理器的锁
+//! 3. 和device_state一样不依赖btleplug，关掉ble功能时照常编译，计数一直是0
+
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use serde::Serialize;
+
+struct Counters {
+    writes: AtomicU64,
+    write_failures: AtomicU64,
+    notifications: AtomicU64,
+    subscribe_failures: AtomicU64,
+    reconnects: AtomicU64,
+    round_trips: AtomicU64,
+    round_trip_total_ms: AtomicU64,
+    round_trip_max_ms: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkCounters {
+    pub writes: u64,
+    pub write_failures: u64,
+    pub notifications: u64,
+    pub subscribe_failures: u64,
+    pub reconnects: u64,
+    pub commands: u64,                       // 测过往返时间的命令数
+    pub avg_round_trip_ms: Option<u64>,      // 没有命令时为None
+    pub max_round_trip_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BleStats {
+    pub since: Option<i64>,             // 上次重置的时间（Unix时间戳，秒），None表示从程序启动开始
+    pub session_started: Option<i64>,   // 这次连上笔的时间，还没连过为None
+    pub total: LinkCounters,
+    pub session: LinkCounters,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            writes: AtomicU64::new(0),
+            write_failures: AtomicU64::new(0),
+            notifications: AtomicU64::new(0),
+            subscribe_failures: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            round_trips: AtomicU64::new(0),
+            round_trip_total_ms: AtomicU64::new(0),
+            round_trip_max_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self) {
+        for counter in [
+            &self.writes,
+            &self.write_failures,
+            &self.notifications,
+            &self.subscribe_failures,
+            &self.reconnects,
+            &self.round_trips,
+            &self.round_trip_total_ms,
+            &self.round_trip_max_ms,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> LinkCounters {
+        let commands = self.round_trips.load(Ordering::Relaxed);
+        let total_ms = self.round_trip_total_ms.load(Ordering::Relaxed);
+        LinkCounters {
+            writes: self.writes.load(Ordering::Relaxed),
+            write_failures: self.write_failures.load(Ordering::Relaxed),
+            notifications: self.notifications.load(Ordering::Relaxed),
+            subscribe_failures: self.subscribe_failures.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            commands,
+            avg_round_trip_ms: (commands > 0).then(|| total_ms / commands),
+            max_round_trip_ms: (commands > 0).then(|| self.round_trip_max_ms.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+static TOTAL: Counters = Counters::new();
+static SESSION: Counters = Counters::new();
+// 0表示还没有
+static SINCE: AtomicI64 = AtomicI64::new(0);
+static SESSION_STARTED: AtomicI64 = AtomicI64::new(0);
+
+// 两组计数都加上
+fn add(field: impl Fn(&Counters) -> &AtomicU64, n: u64) {
+    field(&TOTAL).fetch_add(n, Ordering::Relaxed);
+    field(&SESSION).fetch_add(n, Ordering::Relaxed);
+}
+
+fn timestamp(value: i64) -> Option<i64> {
+    (value != 0).then_some(value)
+}
+
+// 连上笔时调用，session重新开始计数；reconnect表示之前连着后来断了
+pub fn start_session(reconnect: bool) {
+    SESSION.reset();
+    SESSION_STARTED.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+    if reconnect {
+        add(|c| &c.reconnects, 1);
+    }
+}
+
+pub fn record_write(ok: bool) {
+    add(|c| &c.writes, 1);
+    if !ok {
+        add(|c| &c.write_failures, 1);
+    }
+}
+
+pub fn record_notification() {
+    add(|c| &c.notifications, 1);
+}
+
+pub fn record_subscribe_failure() {
+    add(|c| &c.subscribe_failures, 1);
+}
+
+// 从发命令到收到回复用了多久
+pub fn record_round_trip(elapsed: Duration) {
+    let ms = elapsed.as_millis() as u64;
+    add(|c| &c.round_trips, 1);
+    add(|c| &c.round_trip_total_ms, ms);
+    TOTAL.round_trip_max_ms.fetch_max(ms, Ordering::Relaxed);
+    SESSION.round_trip_max_ms.fetch_max(ms, Ordering::Relaxed);
+}
+
+pub fn snapshot() -> BleStats {
+    BleStats {
+        since: timestamp(SINCE.load(Ordering::Relaxed)),
+        session_started: timestamp(SESSION_STARTED.load(Ordering::Relaxed)),
+        total: TOTAL.snapshot(),
+        session: SESSION.snapshot(),
+    }
+}
+
+// 两组计数都清零，从现在开始重新算
+pub fn reset() {
+    TOTAL.reset();
+    SESSION.reset();
+    SINCE.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn averages_round_trips_and_resets() {
+        let counters = Counters::new();
+        assert_eq!(counters.snapshot().avg_round_trip_ms, None);
+
+        for ms in [100, 300] {
+            counters.round_trips.fetch_add(1, Ordering::Relaxed);
+            counters.round_trip_total_ms.fetch_add(ms, Ordering::Relaxed);
+            counters.round_trip_max_ms.fetch_max(ms, Ordering::Relaxed);
+        }
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.commands, 2);
+        assert_eq!(snapshot.avg_round_trip_ms, Some(200));
+        assert_eq!(snapshot.max_round_trip_ms, Some(300));
+
+        counters.reset();
+        assert_eq!(counters.snapshot().commands, 0);
+        assert_eq!(counters.snapshot().max_round_trip_ms, None);
+    }
+}
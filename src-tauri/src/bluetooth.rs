@@ -1,12 +1,15 @@
-use btleplug::api::{Central, Peripheral, ScanFilter, WriteType, CharPropFlags, Manager as _};
+use btleplug::api::{Central, CentralState, Characteristic, Peripheral, ScanFilter, WriteType, CharPropFlags, Manager as _};
 use btleplug::platform::{Manager, Adapter};
 use futures::StreamExt;
 use std::time::Duration;
 use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
 use std::error::Error;
+use std::future::Future;
 use uuid::Uuid;
-use serde::{Serialize, Deserialize};
+use crate::ble_stats;
 use crate::event_emitter::emit_button_event;
+use crate::settings;
 
 // Windows蓝牙API - 用来检测和开启蓝牙无线电
 // 注意：暂时只支持Windows平台，后面如果跨平台再考虑兼容
@@ -16,17 +19,28 @@ use windows::Devices::Radios::RadioKind;
 
 type BtError = String;
 
-/// 设备信息
-#[derive(Clone, Serialize, Deserialize)]
-pub struct DeviceInfo {
-    pub name: String,
-    pub address: String,
-    pub services: Vec<Uuid>,
+// 设备信息和适配器信息放在device_state里，关掉ble功能时也要用
+pub use crate::device_state::{AdapterInfo, DeviceInfo};
+
+/// 操作被取消时返回的错误
+pub const CANCELLED: &str = "蓝牙操作已取消";
+
+/// 等fut完成，cancel被取消时马上返回CANCELLED
+/// 
+/// 扫描、连接、接收这些操作要等好几秒（接收最多10秒），用户点断开或者退出应用时
+/// 不用等它们超时，取消令牌一触发就放弃，设备管理器的锁也随之释放
+async fn cancellable<T>(cancel: &CancellationToken, fut: impl Future<Output = T>) -> Result<T, BtError> {
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(CANCELLED.to_string()),
+        output = fut => Ok(output),
+    }
 }
 
 /// 蓝牙管理器
 pub struct BluetoothManager {
     adapter: Option<Adapter>,
+    adapter_id: Option<String>,
     connected_peripheral: Option<btleplug::platform::Peripheral>,
     listening_rx: Option<tokio::sync::mpsc::Receiver<Vec<u8>>>,
     listening_handle: Option<tokio::task::JoinHandle<()>>,
@@ -36,6 +50,7 @@ impl BluetoothManager {
     pub fn new() -> Self {
         Self {
             adapter: None,
+            adapter_id: None,
             connected_peripheral: None,
             listening_rx: None,
             listening_handle: None,
@@ -207,30 +222,159 @@ impl BluetoothManager {
         }
     }
 
+    /// 获取系统里所有的蓝牙适配器
+    async fn load_adapters() -> Result<Vec<Adapter>, BtError> {
+        let manager = Manager::new().await
+            .map_err(|e| format!("创建管理器失败: {}", e))?;
+        
+        manager.adapters().await
+            .map_err(|e| format!("获取适配器失败: {}", e))
+    }
+
+    /// 适配器的id：序号 + 描述
+    async fn adapter_id(index: usize, adapter: &Adapter) -> String {
+        let info = adapter.adapter_info().await.unwrap_or_else(|_| "未知适配器".to_string());
+        format!("{}:{}", index, info)
+    }
+
+    /// 适配器是否开启，获取不到返回None
+    pub async fn adapter_powered(adapter: &Adapter) -> Option<bool> {
+        match adapter.adapter_state().await {
+            Ok(CentralState::PoweredOn) => Some(true),
+            Ok(CentralState::PoweredOff) => Some(false),
+            _ => None,
+        }
+    }
+
+    /// 当前使用的适配器的描述（系统给的型号/驱动信息），没有适配器时返回None
+    pub async fn selected_adapter_info() -> Option<String> {
+        let (_, adapter) = Self::find_selected_adapter().await.ok()??;
+        adapter.adapter_info().await.ok()
+    }
+
+    /// 按设置找到要使用的适配器：优先用保存的，找不到（比如USB蓝牙被拔掉了）就用第一个
+    /// 
+    /// 一个适配器都没有时返回None
+    pub async fn find_selected_adapter() -> Result<Option<(String, Adapter)>, BtError> {
+        let saved_id = settings::get().bluetooth.adapter_id;
+        let mut first = None;
+        
+        for (index, adapter) in Self::load_adapters().await?.into_iter().enumerate() {
+            let id = Self::adapter_id(index, &adapter).await;
+            if saved_id.as_deref() == Some(id.as_str()) {
+                return Ok(Some((id, adapter)));
+            }
+            if first.is_none() {
+                first = Some((id, adapter));
+            }
+        }
+        
+        Ok(first)
+    }
+
     /// 初始化适配器
     async fn get_adapter(&mut self) -> Result<&Adapter, BtError> {
         if self.adapter.is_none() {
-            let manager = Manager::new().await
-                .map_err(|e| format!("创建管理器失败: {}", e))?;
+            if let Some((id, adapter)) = Self::find_selected_adapter().await? {
+                if let Some(saved_id) = settings::get().bluetooth.adapter_id {
+                    if saved_id != id {
+                        println!("保存的蓝牙适配器 {} 不存在，使用第一个适配器", saved_id);
+                    }
+                }
+                println!("使用蓝牙适配器: {}", id);
+                self.adapter = Some(adapter);
+                self.adapter_id = Some(id);
+            }
+        }
+        
+        self.adapter.as_ref().ok_or_else(|| "没有适配器".to_string())
+    }
+
+    /// 丢掉缓存的适配器和连接
+    /// 
+    /// 蓝牙被关闭或者适配器被拔掉后，旧的Adapter/Peripheral句柄都失效了，
+    /// 继续用只会一直报错。清掉之后下次使用时get_adapter会重新初始化。
+    pub async fn invalidate(&mut self) {
+        self.stop_listening().await;
+        self.cleanup_connection_state().await;
+        self.adapter = None;
+        self.adapter_id = None;
+        println!("[BLUETOOTH] 适配器句柄已失效，下次使用时重新初始化");
+    }
+
+    /// 列出所有蓝牙适配器
+    pub async fn list_adapters(&self) -> Result<Vec<AdapterInfo>, BtError> {
+        // 还没初始化适配器时，按设置里保存的来标记（没有保存就是第一个）
+        let selected_id = self.adapter_id.clone().or_else(|| settings::get().bluetooth.adapter_id);
+        
+        let mut result = Vec::new();
+        for (index, adapter) in Self::load_adapters().await?.into_iter().enumerate() {
+            let id = Self::adapter_id(index, &adapter).await;
+            let name = adapter.adapter_info().await.unwrap_or_else(|_| "未知适配器".to_string());
+            result.push(AdapterInfo {
+                selected: selected_id.as_deref() == Some(id.as_str()),
+                powered: Self::adapter_powered(&adapter).await,
+                id,
+                index,
+                name,
+            });
+        }
+        
+        // 保存的适配器不存在时实际会用第一个
+        if !result.iter().any(|a| a.selected) {
+            if let Some(first) = result.first_mut() {
+                first.selected = true;
+            }
+        }
+        
+        Ok(result)
+    }
+
+    /// 选择要使用的蓝牙适配器，并保存到设置里
+    /// 
+    /// 注意：调用前需要先断开当前连接，旧适配器上的连接不会自动迁移
+    pub async fn select_adapter(&mut self, id: &str) -> Result<AdapterInfo, BtError> {
+        for (index, adapter) in Self::load_adapters().await?.into_iter().enumerate() {
+            let adapter_id = Self::adapter_id(index, &adapter).await;
+            if adapter_id != id {
+                continue;
+            }
+            
+            let info = AdapterInfo {
+                id: adapter_id.clone(),
+                index,
+                name: adapter.adapter_info().await.unwrap_or_else(|_| "未知适配器".to_string()),
+                powered: Self::adapter_powered(&adapter).await,
+                selected: true,
+            };
             
-            let adapters = manager.adapters().await
-                .map_err(|e| format!("获取适配器失败: {}", e))?;
+            settings::update(serde_json::json!({ "bluetooth": { "adapter_id": adapter_id } }))
+                .await
+                .map_err(|e| format!("保存适配器选择失败: {}", e))?;
             
-            self.adapter = adapters.into_iter().next();
+            self.adapter = Some(adapter);
+            self.adapter_id = Some(adapter_id);
+            println!("已切换蓝牙适配器: {}", id);
+            return Ok(info);
         }
         
-        self.adapter.as_ref().ok_or_else(|| "没有适配器".to_string())
+        Err(format!("没有找到蓝牙适配器: {}", id))
     }
 
     /// 2. 扫描设备
-    pub async fn scan_devices(&mut self, duration_ms: u64) -> Result<Vec<DeviceInfo>, BtError> {
+    /// 
+    /// cancel被取消时停止扫描并返回CANCELLED
+    pub async fn scan_devices(&mut self, duration_ms: u64, cancel: &CancellationToken) -> Result<Vec<DeviceInfo>, BtError> {
         let adapter = self.get_adapter().await?;
         
         println!("扫描设备 {}ms...", duration_ms);
         adapter.start_scan(ScanFilter::default()).await
             .map_err(|e| format!("开始扫描失败: {}", e))?;
         
-        sleep(Duration::from_millis(duration_ms)).await;
+        if let Err(e) = cancellable(cancel, sleep(Duration::from_millis(duration_ms))).await {
+            let _ = adapter.stop_scan().await;
+            return Err(e);
+        }
         
         let peripherals = adapter.peripherals().await
             .map_err(|e| format!("获取设备列表失败: {}", e))?;
@@ -244,8 +388,8 @@ impl BluetoothManager {
             if let Ok(Some(props)) = p.properties().await {
                 let name = props.local_name.unwrap_or("未知设备".to_string());
                 let address = props.address.to_string();
-                // 简化处理：直接不包含services
-                devices.push(DeviceInfo { name, address, services: vec![] });
+                // 广播里的服务UUID，识别笔时用（见device_match）
+                devices.push(DeviceInfo { name, address, services: props.services });
             }
         }
         
@@ -256,7 +400,8 @@ impl BluetoothManager {
     /// 
     /// 改进：添加重试机制，提高连接成功率
     /// 改进：连接前强制清理旧的监听任务，避免使用旧对象
-    pub async fn connect(&mut self, address: &str) -> Result<(), BtError> {
+    /// 改进：cancel被取消时不再重试，正在进行的这次尝试也马上放弃
+    pub async fn connect(&mut self, address: &str, cancel: &CancellationToken) -> Result<(), BtError> {
         // 连接前强制清理旧的监听任务和状态
         // 这确保连接新设备时不会复用旧的监听任务
         println!("[BLUETOOTH] 连接前清理旧状态...");
@@ -268,16 +413,16 @@ impl BluetoothManager {
         for attempt in 1..=MAX_RETRIES {
             println!("[BLUETOOTH] 连接尝试 {}/{}: {}", attempt, MAX_RETRIES, address);
             
-            match self.connect_once(address).await {
+            match self.connect_once(address, cancel).await {
                 Ok(_) => {
                     println!("[BLUETOOTH] 连接成功");
                     return Ok(());
                 }
-                Err(e) if attempt < MAX_RETRIES => {
+                Err(e) if attempt < MAX_RETRIES && !cancel.is_cancelled() => {
                     println!("[BLUETOOTH] 连接失败，{}ms后重试: {}", RETRY_DELAY_MS, e);
                     // 清理状态后重试
                     self.cleanup_connection_state().await;
-                    sleep(Duration::from_millis(RETRY_DELAY_MS)).await;
+                    cancellable(cancel, sleep(Duration::from_millis(RETRY_DELAY_MS))).await?;
                 }
                 Err(e) => {
                     println!("[BLUETOOTH] 连接重试次数用尽: {}", e);
@@ -290,7 +435,7 @@ impl BluetoothManager {
     }
     
     /// 单次连接尝试（内部方法）
-    async fn connect_once(&mut self, address: &str) -> Result<(), BtError> {
+    async fn connect_once(&mut self, address: &str, cancel: &CancellationToken) -> Result<(), BtError> {
         println!("[BLUETOOTH] 开始连接 {}...", address);
         
         // 先扫描找到设备
@@ -298,7 +443,10 @@ impl BluetoothManager {
         adapter.start_scan(ScanFilter::default()).await
             .map_err(|e| format!("开始扫描失败: {}", e))?;
         
-        sleep(Duration::from_secs(2)).await;
+        if let Err(e) = cancellable(cancel, sleep(Duration::from_secs(2))).await {
+            let _ = adapter.stop_scan().await;
+            return Err(e);
+        }
         
         let peripherals = adapter.peripherals().await
             .map_err(|e| format!("获取设备列表失败: {}", e))?;
@@ -319,11 +467,20 @@ impl BluetoothManager {
         
         let peripheral = target.ok_or_else(|| format!("未找到设备: {}", address))?;
         
-        peripheral.connect().await
-            .map_err(|e| format!("连接失败: {}", e))?;
+        // 连接到一半被取消时断开，别留下一个没人管的连接
+        match cancellable(cancel, peripheral.connect()).await {
+            Ok(result) => result.map_err(|e| format!("连接失败: {}", e))?,
+            Err(e) => {
+                let _ = peripheral.disconnect().await;
+                return Err(e);
+            }
+        }
         
         // 连接后等待更长时间让连接稳定
-        sleep(Duration::from_millis(500)).await;
+        if let Err(e) = cancellable(cancel, sleep(Duration::from_millis(500))).await {
+            let _ = peripheral.disconnect().await;
+            return Err(e);
+        }
         
         // 验证连接状态
         if !peripheral.is_connected().await.map_err(|e| format!("检查连接失败: {}", e))? {
@@ -332,7 +489,15 @@ impl BluetoothManager {
         
         // 预先发现服务，避免后续操作时出错
         println!("[BLUETOOTH] 发现服务...");
-        match timeout(Duration::from_secs(5), peripheral.discover_services()).await {
+        let discovered = cancellable(cancel, timeout(Duration::from_secs(5), peripheral.discover_services())).await;
+        let discovered = match discovered {
+            Ok(discovered) => discovered,
+            Err(e) => {
+                let _ = peripheral.disconnect().await;
+                return Err(e);
+            }
+        };
+        match discovered {
             Ok(Ok(_)) => println!("[BLUETOOTH] 服务发现完成"),
             Ok(Err(e)) => {
                 println!("[BLUETOOTH] 服务发现失败: {}", e);
@@ -430,32 +595,61 @@ impl BluetoothManager {
     fn peripheral(&self) -> Result<&btleplug::platform::Peripheral, BtError> {
         self.connected_peripheral.as_ref().ok_or_else(|| "未连接".to_string())
     }
-    /// 4. 发送数据
-    pub async fn send(&mut self, service_uuid: &str, char_uuid: &str, data: &[u8]) -> Result<(), BtError> {
-        let peripheral = self.peripheral()?;
-        
-        // 发现服务
-        timeout(Duration::from_millis(5000), peripheral.discover_services()).await
-            .map_err(|_| "服务发现超时".to_string())?
-            .map_err(|e| format!("服务发现失败: {}", e))?;
-        
-        // 查找服务
+
+    /// 在已发现的服务里查找特性
+    fn find_characteristic(&self, service_uuid: &str, char_uuid: &str) -> Result<Characteristic, BtError> {
         let service_uuid = Uuid::parse_str(service_uuid)
             .map_err(|e| format!("解析服务UUID失败: {}", e))?;
+        let char_uuid = Uuid::parse_str(char_uuid)
+            .map_err(|e| format!("解析特性UUID失败: {}", e))?;
         
-        let services = peripheral.services();
+        let services = self.peripheral()?.services();
         let service = services
             .iter()
             .find(|s| s.uuid == service_uuid)
             .ok_or_else(|| format!("未找到服务: {}", service_uuid))?;
+        service.characteristics.iter()
+            .find(|c| c.uuid == char_uuid)
+            .cloned()
+            .ok_or_else(|| format!("未找到特性: {}", char_uuid))
+    }
+
+    /// 轻量的连接检查（GATT ping）
+    /// 
+    /// 有些蓝牙栈在设备走远或睡眠后，peripheral.is_connected()还会一直返回true，
+    /// 只有真的发一次请求才知道连接还在不在。特性可读时做一次GATT读，
+    /// 不可读时重新发现一次服务，两种都要设备真正回应，limit内没回应就当连接已失效。
+    /// 不发设备命令、不走通知通道，不会和recv抢数据。
+    pub async fn ping(&self, service_uuid: &str, char_uuid: &str, limit: Duration) -> Result<(), BtError> {
+        let peripheral = self.peripheral()?;
+        let readable = self.find_characteristic(service_uuid, char_uuid)
+            .ok()
+            .filter(|c| c.properties.contains(CharPropFlags::READ));
+        
+        let result = match &readable {
+            Some(characteristic) => timeout(limit, peripheral.read(characteristic)).await
+                .map(|r| r.map(|_| ())),
+            None => timeout(limit, peripheral.discover_services()).await,
+        };
+        match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(format!("连接检查失败: {}", e)),
+            Err(_) => Err(format!("连接检查超时（{}毫秒）", limit.as_millis())),
+        }
+    }
+
+    /// 4. 发送数据
+    pub async fn send(&mut self, service_uuid: &str, char_uuid: &str, data: &[u8], cancel: &CancellationToken) -> Result<(), BtError> {
+        self.drain_pending();
+        let peripheral = self.peripheral()?;
         
-        // 查找特性
-        let char_uuid = Uuid::parse_str(char_uuid)
-            .map_err(|e| format!("解析特性UUID失败: {}", e))?;
+        // 发现服务
+        cancellable(cancel, timeout(Duration::from_millis(5000), peripheral.discover_services())).await?
+            .map_err(|_| "服务发现超时".to_string())?
+            .map_err(|e| format!("服务发现失败: {}", e))?;
         
-        let characteristic = service.characteristics.iter()
-            .find(|c| c.uuid == char_uuid)
-            .ok_or_else(|| format!("未找到特性: {}", char_uuid))?;
+        // 查找特性
+        let characteristic = self.find_characteristic(service_uuid, char_uuid)?;
         
         // 检查可写
         if !characteristic.properties.contains(CharPropFlags::WRITE) && 
@@ -464,18 +658,37 @@ impl BluetoothManager {
         }
         
         // 发送
-        timeout(Duration::from_millis(2000), peripheral.write(characteristic, data, WriteType::WithoutResponse)).await
-            .map_err(|_| "发送超时".to_string())?
-            .map_err(|e| format!("发送失败: {}", e))?;
+        let result = cancellable(cancel, timeout(Duration::from_millis(2000), peripheral.write(&characteristic, data, WriteType::WithoutResponse))).await?
+            .map_err(|_| "发送超时".to_string())
+            .and_then(|r| r.map_err(|e| format!("发送失败: {}", e)));
+        ble_stats::record_write(result.is_ok());
+        result?;
         
         println!("发送成功: {} bytes", data.len());
         Ok(())
     }
 
+    /// 丢掉通知通道里还没被读走的数据
+    /// 
+    /// 上一条命令没等到回复就放弃了（getProtoVersion只等800ms、setTime的回复可有可无、
+    /// 操作被断开取消），晚到的回复还留在通道里，不清掉的话下一条命令的recv会把它当成自己的回复。
+    /// 每次发命令前调用
+    fn drain_pending(&mut self) {
+        let Some(rx) = &mut self.listening_rx else { return };
+        let mut dropped = 0;
+        while rx.try_recv().is_ok() {
+            dropped += 1;
+        }
+        if dropped > 0 {
+            println!("[BLUETOOTH] 丢掉 {} 个之前命令晚到的回复", dropped);
+        }
+    }
+
     /// 5. 阻塞接收（类似recv）
     /// 
     /// 改进：检测监听任务健康状态，必要时重启
-    pub async fn recv(&mut self, service_uuid: &str, char_uuid: &str) -> Result<Vec<u8>, BtError> {
+    /// 改进：cancel被取消时不等10秒超时，马上返回CANCELLED
+    pub async fn recv(&mut self, service_uuid: &str, char_uuid: &str, cancel: &CancellationToken) -> Result<Vec<u8>, BtError> {
         // 先检查监听任务是否健康，在获取peripheral之前
         let need_restart = self.listening_rx.is_none() || 
                            self.listening_handle.as_ref().map_or(true, |h| h.is_finished());
@@ -531,6 +744,7 @@ impl BluetoothManager {
                             Ok(_) => println!("[BLUETOOTH] 订阅成功"),
                             Err(e) => {
                                 println!("[BLUETOOTH] 订阅失败：{}", e);
+                                ble_stats::record_subscribe_failure();
                                 return;
                             }
                         }
@@ -538,6 +752,7 @@ impl BluetoothManager {
                         let mut stream = stream;
                         println!("[BLUETOOTH] 开始监听通知...");
                         while let Some(notif) = stream.next().await {
+                            ble_stats::record_notification();
                             let data_hex = notif.value.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
                             let data_str = String::from_utf8_lossy(&notif.value);
                             let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string();
@@ -619,7 +834,7 @@ impl BluetoothManager {
         // 阻塞等待数据（过滤按钮事件包）
         if let Some(rx) = &mut self.listening_rx {
             loop {
-                match timeout(Duration::from_secs(10), rx.recv()).await {
+                match cancellable(cancel, timeout(Duration::from_secs(10), rx.recv())).await? {
                     Ok(Some(data)) => {
                         // 检查是否是按钮事件包，如果是则跳过
                         // GPIO10: 0xAA/0xAB, GPIO9: 0xAC/0xAD
@@ -660,13 +875,14 @@ impl BluetoothManager {
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let mut bt = BluetoothManager::new();
+    let cancel = CancellationToken::new();
     
     // 1. 打开蓝牙
     bt.enable_bluetooth()?;
     
     // 2. 扫描设备
     println!("开始扫描蓝牙设备...\n");
-    let devices = bt.scan_devices(5000).await?;
+    let devices = bt.scan_devices(5000, &cancel).await?;
     
     println!("\n========== 扫描结果 ==========");
     println!("共找到 {} 个设备:\n", devices.len());
@@ -686,7 +902,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
     println!("==============================");
     
     // 3. 查找Cpen设备
-    let cpen_device = devices.iter().find(|d| d.name.starts_with("Cpen"));
+    let rules = crate::settings::get().bluetooth.device_match;
+    let cpen_device = devices.iter().find(|d| crate::device_match::matches(&rules, d));
     
     match cpen_device {
         Some(device) => {
@@ -694,21 +911,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
             
             // 4. 连接
             println!("正在连接...");
-            bt.connect(&device.address).await?;
+            bt.connect(&device.address, &cancel).await?;
             println!("连接成功！");
             
-            // Cpen设备UUID（来自原代码）
-            let service_uuid = "d816e4c6-1b99-4da7-bcd5-7c37cc2642c4";
-            let char_uuid = "d816e4c7-1b99-4da7-bcd5-7c37cc2642c4";
+            // Cpen设备的服务和特性（见设置bluetooth.gatt）
+            let gatt = crate::settings::get().bluetooth.gatt_for(Some(device.address.as_str()));
+            let service_uuid = gatt.service_uuid.as_str();
+            let char_uuid = gatt.char_uuid.as_str();
             
             // 5. 发送getTotp命令
             println!("\n发送 'getTotp' 命令...");
-            bt.send(service_uuid, char_uuid, b"getTotp").await?;
+            bt.send(service_uuid, char_uuid, b"getTotp", &cancel).await?;
             
             // 6. 接收响应
             println!("等待TOTP响应...");
-            let response = bt.recv(service_uuid, char_uuid).await?;
+            let response = bt.recv(service_uuid, char_uuid, &cancel).await?;
             let totp_str = String::from_utf8_lossy(&response);
+            crate::auth::remember_secret(&totp_str);
             println!("收到TOTP: {}", totp_str);
             
             // 7. 断开连接
@@ -717,7 +936,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
             println!("已断开");
         }
         None => {
-            println!("\n未找到以'Cpen'开头的设备");
+            println!("\n未找到Cpen设备（{}）", crate::device_match::describe(&rules));
         }
     }
     
@@ -0,0 +1,143 @@
+// 蓝牙适配器监控
+// 运行中关闭/打开蓝牙、拔掉USB蓝牙时自动处理
+//
+// 思考：原来Adapter和Peripheral句柄初始化一次就一直用，
+// 用户中途关掉蓝牙或拔掉适配器后，所有操作都会失败，只能重启程序。
+// 这里在后台监控当前使用的适配器：
+// 1. 订阅适配器事件，收到StateUpdate（开关蓝牙）马上检查
+// 2. 拔掉适配器不会有事件，所以同时定时检查适配器还在不在
+// 3. 变成不可用时让旧句柄全部失效，发bluetooth-unavailable事件
+// 4. 恢复可用时发bluetooth-restored事件，句柄在下次使用时重新初始化
+// 5. 顺便把适配器开关状态写进连接状态快照，并检查笔的物理连接还在不在
+//
+// 系统睡眠前和省电模式时暂停检查（见power.rs）。
+// 检查时不拿设备管理器的锁，只有状态变化时才去拿，避免扫描/连接时被卡住。
+// 检查笔的连接用try_lock，设备管理器正在忙就跳过这一轮。
+
+use std::time::Duration;
+use btleplug::api::{Central, CentralEvent};
+use btleplug::platform::Adapter;
+use futures::StreamExt;
+use tokio::time::{sleep, timeout};
+
+use crate::bluetooth::BluetoothManager;
+use crate::cpen_device_manager;
+use crate::{event_emitter, power, supervisor};
+
+// 定时检查的间隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// 启动后台监控，panic后由supervisor重启
+pub fn start() {
+    supervisor::spawn_service("bluetooth_watcher", watch);
+}
+
+// 检查当前适配器是否可用，不可用时返回原因
+async fn check_adapter() -> (Option<Adapter>, Result<String, String>) {
+    match BluetoothManager::find_selected_adapter().await {
+        Ok(Some((id, adapter))) => {
+            let powered = BluetoothManager::adapter_powered(&adapter).await;
+            cpen_device_manager::set_adapter_powered(powered);
+            if powered == Some(false) {
+                (Some(adapter), Err("蓝牙已关闭".to_string()))
+            } else {
+                (Some(adapter), Ok(id))
+            }
+        }
+        Ok(None) => {
+            cpen_device_manager::set_adapter_powered(None);
+            (None, Err("没有找到蓝牙适配器".to_string()))
+        }
+        Err(e) => (None, Err(e)),
+    }
+}
+
+// 快照里是已连接时，检查笔的物理连接，断开了会更新快照
+async fn check_connection() {
+    if !cpen_device_manager::connection_snapshot().is_connected() {
+        return;
+    }
+    let manager = match crate::get_cpen_device_manager() {
+        Ok(manager) => manager,
+        Err(_) => return,
+    };
+    // 设备管理器正在用蓝牙，说明连接正被使用，不用检查
+    if let Ok(mut manager) = manager.try_lock() {
+        if let Ok(false) = manager.is_connected().await {
+            println!("[蓝牙监控] 笔的连接已断开");
+        }
+    }
+}
+
+// 等待适配器的开关事件，最多等CHECK_INTERVAL
+async fn wait_for_state_change(adapter: Option<Adapter>) {
+    let adapter = match adapter {
+        Some(adapter) => adapter,
+        None => {
+            sleep(CHECK_INTERVAL).await;
+            return;
+        }
+    };
+
+    let mut events = match adapter.events().await {
+        Ok(events) => events,
+        Err(_) => {
+            sleep(CHECK_INTERVAL).await;
+            return;
+        }
+    };
+
+    let _ = timeout(CHECK_INTERVAL, async {
+        while let Some(event) = events.next().await {
+            if let CentralEvent::StateUpdate(state) = event {
+                println!("[蓝牙监控] 适配器状态变化: {:?}", state);
+                break;
+            }
+        }
+    })
+    .await;
+}
+
+async fn watch() {
+    println!("[蓝牙监控] 开始监控蓝牙适配器");
+
+    // 启动时蓝牙就不可用不发事件，前端自己会查询连接状态
+    let mut available: Option<bool> = None;
+
+    loop {
+        // 睡眠前和省电模式时不碰蓝牙，唤醒后power模块会重置连接
+        if power::wait_until_active().await {
+            println!("[蓝牙监控] 电源状态恢复，继续监控");
+        }
+
+        let (adapter, status) = check_adapter().await;
+
+        match (&status, available) {
+            (Err(reason), Some(true)) => {
+                println!("[蓝牙监控] 蓝牙不可用: {}", reason);
+                cpen_device_manager::cancel_operations();
+                match crate::get_cpen_device_manager() {
+                    Ok(manager) => manager.lock().await.invalidate_bluetooth().await,
+                    Err(e) => println!("[蓝牙监控] 获取设备管理器失败: {}", e),
+                }
+                event_emitter::emit_event("bluetooth-unavailable", serde_json::json!({
+                    "reason": reason,
+                }));
+            }
+            (Ok(adapter_id), Some(false)) => {
+                println!("[蓝牙监控] 蓝牙已恢复: {}", adapter_id);
+                event_emitter::emit_event("bluetooth-restored", serde_json::json!({
+                    "adapter_id": adapter_id,
+                }));
+            }
+            _ => {}
+        }
+        available = Some(status.is_ok());
+
+        if status.is_ok() {
+            check_connection().await;
+        }
+
+        wait_for_state_change(adapter).await;
+    }
+}
@@ -0,0 +1,218 @@
+// 文件夹下载的校验清单（SHA256SUMS）
+// 文件夹里的文件全部下载完成后，在本地文件夹里写一个SHA256SUMS，格式和sha256sum命令的输出一样，
+// 用户把下载的数据再分发给别人时，对方可以直接用 sha256sum -c SHA256SUMS 校验
+//
+// 思考：
+// 1. 下载任务失败后会被自动重试替换成新任务，所以不能拿着Arc<DownloadTask>等，
+//    按file_id每隔一会儿去任务表里查当前的任务
+// 2. 有文件彻底失败（不再自动重试）就不写清单，半个文件夹的清单容易让人误以为数据是完整的；
+//    这时用export_checksums还能给已下载完的部分导出清单
+// 3. 任务从任务表里清理掉以后，本地文件存在、没有下载元数据（.meta）就当作已完成
+// 4. 清单里的路径相对于文件夹，统一用/分隔，Windows上生成的清单在Linux上也能用
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::batch::{self, DownloadBatch};
+use crate::download::DownloadStatus;
+use crate::download_meta::DownloadMeta;
+use crate::{event_emitter, settings, supervisor, transfer_manager};
+
+pub const MANIFEST_NAME: &str = "SHA256SUMS";
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestReport {
+    pub path: String,
+    pub files: usize,
+}
+
+// 批次里一个文件现在的情况
+enum FileState {
+    Done(PathBuf),
+    Pending,
+    Failed,
+}
+
+async fn file_state(batch: &DownloadBatch, file_id: &str) -> FileState {
+    let task = transfer_manager::download_tasks().lock().await.get(file_id).cloned();
+    let Some(task) = task else {
+        let save_path = local_path(batch, file_id);
+        return if save_path.exists() && !DownloadMeta::meta_path(&save_path).exists() {
+            FileState::Done(save_path)
+        } else {
+            FileState::Failed
+        };
+    };
+    match task.get_progress().await.status {
+        DownloadStatus::Completed => FileState::Done(task.save_path().to_path_buf()),
+        DownloadStatus::Error(_) => {
+            let policy = settings::get().retry;
+            if !policy.auto_retry || task.retry_count() >= policy.max_attempts {
+                FileState::Failed
+            } else {
+                FileState::Pending
+            }
+        }
+        _ => FileState::Pending,
+    }
+}
+
+// 任务已经不在任务表里时推算本地路径，和download_file一样是下载目录加上云盘路径
+fn local_path(batch: &DownloadBatch, file_id: &str) -> PathBuf {
+    let relative = file_id
+        .strip_prefix(batch.remote_path.trim_end_matches('/'))
+        .unwrap_or(file_id)
+        .trim_start_matches('/');
+    batch.save_dir.join(relative)
+}
+
+// 计算文件的SHA256（十六进制）
+pub async fn hash_file(path: &Path) -> Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || -> Result<String> {
+        use std::io::Read;
+        let mut file = std::fs::File::open(&path).with_context(|| format!("打开文件失败: {:?}", path))?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 1024 * 1024];
+        loop {
+            let n = file.read(&mut buffer).with_context(|| format!("读取文件失败: {:?}", path))?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+        }
+        Ok(hex::encode(hasher.finalize()))
+    })
+    .await
+    .context("计算文件哈希失败")?
+}
+
+// 计算哈希并写清单，清单里按路径排序
+pub async fn write_manifest(manifest_path: &Path, base_dir: &Path, files: &[PathBuf]) -> Result<usize> {
+    let mut entries = Vec::with_capacity(files.len());
+    for file in files {
+        let relative = file.strip_prefix(base_dir).unwrap_or(file);
+        let relative = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        entries.push((relative, hash_file(file).await?));
+    }
+    entries.sort();
+    let lines: Vec<String> = entries
+        .iter()
+        .map(|(relative, hash)| format!("{}  {}", hash, relative))
+        .collect();
+
+    let mut content = lines.join("\n");
+    if !content.is_empty() {
+        content.push('\n');
+    }
+    if let Some(parent) = manifest_path.parent() {
+        tokio::fs::create_dir_all(parent).await.context("创建清单目录失败")?;
+    }
+    tokio::fs::write(manifest_path, content)
+        .await
+        .with_context(|| format!("写入校验清单失败: {:?}", manifest_path))?;
+    Ok(lines.len())
+}
+
+// 在后台等批次里的文件都下载完，然后在文件夹里写SHA256SUMS
+pub fn watch_batch(batch: DownloadBatch) {
+    supervisor::spawn(format!("checksums:{}", batch.batch_id), async move {
+        let (completed, failed) = loop {
+            let mut completed = Vec::new();
+            let mut failed = batch.failed.len();
+            let mut pending = false;
+            for file_id in &batch.file_ids {
+                match file_state(&batch, file_id).await {
+                    FileState::Done(path) => completed.push(path),
+                    FileState::Failed => failed += 1,
+                    FileState::Pending => pending = true,
+                }
+            }
+            if !pending {
+                break (completed, failed);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        };
+
+        let manifest = if failed == 0 {
+            let manifest_path = batch.save_dir.join(MANIFEST_NAME);
+            match write_manifest(&manifest_path, &batch.save_dir, &completed).await {
+                Ok(count) => {
+                    println!("文件夹 {} 下载完成，已写入校验清单 ({} 个文件)", batch.remote_path, count);
+                    Some(manifest_path.to_string_lossy().to_string())
+                }
+                Err(e) => {
+                    println!("写入校验清单失败: {:#}", e);
+                    None
+                }
+            }
+        } else {
+            println!("文件夹 {} 有 {} 个文件没有下载成功，不写校验清单", batch.remote_path, failed);
+            None
+        };
+
+        event_emitter::emit_event("download-batch-finished", serde_json::json!({
+            "batch_id": batch.batch_id,
+            "remote_path": batch.remote_path,
+            "completed": completed.len(),
+            "failed": failed,
+            "manifest": manifest,
+        }));
+    });
+}
+
+// 把批次里已下载完的文件导出成校验清单，path是目录时写到目录下的SHA256SUMS
+pub async fn export(batch_id: &str, path: &Path) -> Result<ManifestReport> {
+    let batch = batch::get_download_batch(batch_id)
+        .await
+        .with_context(|| format!("下载批次不存在: {}", batch_id))?;
+
+    let mut completed = Vec::new();
+    for file_id in &batch.file_ids {
+        if let FileState::Done(path) = file_state(&batch, file_id).await {
+            completed.push(path);
+        }
+    }
+    let manifest_path = if path.is_dir() { path.join(MANIFEST_NAME) } else { path.to_path_buf() };
+    let files = write_manifest(&manifest_path, &batch.save_dir, &completed).await?;
+    Ok(ManifestReport { path: manifest_path.to_string_lossy().to_string(), files })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_sorted_sha256sums() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("photos");
+        std::fs::create_dir_all(base.join("2024")).unwrap();
+        std::fs::write(base.join("b.txt"), b"hello").unwrap();
+        std::fs::write(base.join("2024").join("a.txt"), b"").unwrap();
+
+        let manifest = base.join(MANIFEST_NAME);
+        let files = vec![base.join("b.txt"), base.join("2024").join("a.txt")];
+        assert_eq!(write_manifest(&manifest, &base, &files).await.unwrap(), 2);
+
+        assert_eq!(
+            std::fs::read_to_string(&manifest).unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  2024/a.txt\n\
+             2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824  b.txt\n"
+        );
+    }
+
+    #[test]
+    fn locates_files_of_cleaned_up_tasks() {
+        let batch = DownloadBatch::new("photos/".to_string(), PathBuf::from("/data/photos"));
+        assert_eq!(local_path(&batch, "photos/2024/a.jpg"), Path::new("/data/photos/2024/a.jpg"));
+        assert_eq!(local_path(&batch, "photos/b.jpg"), Path::new("/data/photos/b.jpg"));
+    }
+}
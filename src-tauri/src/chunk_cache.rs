@@ -0,0 +1,339 @@
+// 分片磁盘缓存，边下边播用
+// 边下边播从后端拿到的分片存到临时目录（settings::scratch_dir）下的chunk_cache里，按(文件, 范围, ETag)查找，
+// 拖动进度条回看、重新打开预览、预览过的视频再下载时直接用缓存里的数据，不用再请求后端
+//
+// 思考：
+// 1. 键里带上ETag（后端没给ETag时用大小+修改时间代替），文件在云盘上被改过后键就变了，
+//    旧的分片不会再被命中，留着等淘汰就行；两个都没有时判断不了有没有变，不缓存
+// 2. 每个分片一个文件，文件名是键的SHA256；启动后第一次用时扫一遍目录，按修改时间恢复使用顺序，
+//    命中时更新修改时间，重启后最近用过的还排在后面
+// 3. 总大小超过storage.max_cache_mb时删最久没用的，设成0就不缓存
+// 4. 先写临时文件再改名，写到一半退出不会留下半个分片；读出来的长度和范围对不上就当没命中
+// 5. 锁只护着内存里的索引，读写分片文件都在锁外面，一个分片读写慢不会卡住其他播放和下载
+// 6. 只有边下边播往里存（播放器会反复读同一段）；普通下载只查不存，
+//    下载的分片已经写进目标文件了，再存一份等于每个分片写两遍盘
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::sync::OnceCell;
+
+use crate::download::RemoteFileMeta;
+use crate::settings;
+
+pub const CACHE_DIR_NAME: &str = "chunk_cache";
+const CHUNK_EXT: &str = "chunk";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStatus {
+    pub dir: String,
+    pub entries: usize,
+    pub used_bytes: u64,
+    pub capacity_bytes: u64,
+}
+
+struct Entry {
+    size: u64,
+    last_used: u64,   // 越大越近
+}
+
+// 内存里的索引（见思考5）
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    used: u64,
+    tick: u64,
+}
+
+impl Index {
+    fn insert(&mut self, name: String, size: u64) {
+        self.tick += 1;
+        if let Some(old) = self.entries.insert(name, Entry { size, last_used: self.tick }) {
+            self.used -= old.size;
+        }
+        self.used += size;
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(entry) = self.entries.remove(name) {
+            self.used -= entry.size;
+        }
+    }
+
+    // 从索引里去掉最久没用的分片，直到总大小不超过capacity，返回要删的文件
+    fn evict(&mut self, capacity: u64) -> Vec<String> {
+        if self.used <= capacity {
+            return Vec::new();
+        }
+        let mut by_age: Vec<(u64, String)> = self.entries
+            .iter()
+            .map(|(name, entry)| (entry.last_used, name.clone()))
+            .collect();
+        by_age.sort();
+        let mut evicted = Vec::new();
+        for (_, name) in by_age {
+            if self.used <= capacity {
+                break;
+            }
+            self.remove(&name);
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+pub struct ChunkCache {
+    dir: PathBuf,
+    index: Mutex<Index>,
+    loaded: OnceCell<()>,
+}
+
+// 判断文件有没有变的依据，None表示没法判断
+pub fn validator(meta: &RemoteFileMeta) -> Option<String> {
+    match (&meta.etag, meta.modified_at) {
+        (Some(etag), _) => Some(etag.clone()),
+        (None, Some(modified_at)) => Some(format!("{}-{}", meta.size, modified_at)),
+        (None, None) => None,
+    }
+}
+
+fn entry_name(file_id: &str, start: u64, end: u64, validator: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(file_id.as_bytes());
+    hasher.update([0]);
+    hasher.update(format!("{}-{}", start, end).as_bytes());
+    hasher.update([0]);
+    hasher.update(validator.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+impl ChunkCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, index: Mutex::new(Index::default()), loaded: OnceCell::new() }
+    }
+
+    fn path_of(&self, name: &str) -> PathBuf {
+        self.dir.join(name).with_extension(CHUNK_EXT)
+    }
+
+    // 第一次用时扫描目录，按修改时间排出使用顺序
+    async fn ensure_loaded(&self) {
+        self.loaded.get_or_init(|| async {
+            let Ok(mut dir) = fs::read_dir(&self.dir).await else { return };
+            let mut found = Vec::new();
+            while let Ok(Some(item)) = dir.next_entry().await {
+                let path = item.path();
+                let Ok(meta) = item.metadata().await else { continue };
+                if path.extension().and_then(|e| e.to_str()) != Some(CHUNK_EXT) {
+                    // 上次没写完的临时文件
+                    let _ = fs::remove_file(&path).await;
+                    continue;
+                }
+                let Some(name) = path.file_stem().and_then(|s| s.to_str()) else { continue };
+                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                found.push((modified, name.to_string(), meta.len()));
+            }
+            found.sort();
+            let mut index = self.index.lock().unwrap();
+            for (_, name, size) in found {
+                index.insert(name, size);
+            }
+        }).await;
+    }
+
+    async fn remove_files(&self, names: &[String]) {
+        for name in names {
+            let _ = fs::remove_file(self.path_of(name)).await;
+        }
+    }
+
+    pub async fn get(&self, file_id: &str, start: u64, end: u64, validator: &str) -> Option<Vec<u8>> {
+        self.ensure_loaded().await;
+        let name = entry_name(file_id, start, end, validator);
+        if !self.index.lock().unwrap().entries.contains_key(&name) {
+            return None;
+        }
+        let path = self.path_of(&name);
+        match fs::read(&path).await {
+            Ok(data) if data.len() as u64 == end - start + 1 => {
+                {
+                    let mut index = self.index.lock().unwrap();
+                    index.tick += 1;
+                    let tick = index.tick;
+                    if let Some(entry) = index.entries.get_mut(&name) {
+                        entry.last_used = tick;
+                    }
+                }
+                touch(&path).await;
+                Some(data)
+            }
+            _ => {
+                self.index.lock().unwrap().remove(&name);
+                let _ = fs::remove_file(&path).await;
+                None
+            }
+        }
+    }
+
+    pub async fn put(&self, file_id: &str, start: u64, end: u64, validator: &str, data: &[u8], capacity: u64) -> Result<()> {
+        // 比整个缓存还大的分片存了也会马上被挤掉
+        if data.len() as u64 != end - start + 1 || data.len() as u64 > capacity {
+            return Ok(());
+        }
+        self.ensure_loaded().await;
+        fs::create_dir_all(&self.dir).await.context("创建分片缓存目录失败")?;
+
+        // 同一个分片可能有两个请求同时在存，临时文件名各用各的
+        let name = entry_name(file_id, start, end, validator);
+        let path = self.path_of(&name);
+        let tmp_path = self.dir.join(format!("{}.{}.tmp", name, uuid::Uuid::new_v4().simple()));
+        fs::write(&tmp_path, data).await.context("写入分片缓存失败")?;
+        if let Err(e) = fs::rename(&tmp_path, &path).await {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(e).context("保存分片缓存失败");
+        }
+
+        let evicted = {
+            let mut index = self.index.lock().unwrap();
+            index.insert(name, data.len() as u64);
+            index.evict(capacity)
+        };
+        self.remove_files(&evicted).await;
+        Ok(())
+    }
+
+    // 删最久没用的分片，直到总大小不超过capacity
+    pub async fn evict(&self, capacity: u64) {
+        self.ensure_loaded().await;
+        let evicted = self.index.lock().unwrap().evict(capacity);
+        self.remove_files(&evicted).await;
+    }
+
+    pub async fn clear(&self) -> usize {
+        self.ensure_loaded().await;
+        let names: Vec<String> = {
+            let mut index = self.index.lock().unwrap();
+            let names = index.entries.keys().cloned().collect();
+            let tick = index.tick;
+            *index = Index { tick, ..Index::default() };
+            names
+        };
+        self.remove_files(&names).await;
+        names.len()
+    }
+
+    pub async fn status(&self, capacity: u64) -> CacheStatus {
+        self.ensure_loaded().await;
+        let index = self.index.lock().unwrap();
+        CacheStatus {
+            dir: self.dir.to_string_lossy().to_string(),
+            entries: index.entries.len(),
+            used_bytes: index.used,
+            capacity_bytes: capacity,
+        }
+    }
+}
+
+// 文件修改时间改成现在，重启后按修改时间恢复使用顺序
+async fn touch(path: &Path) {
+    if let Ok(file) = fs::OpenOptions::new().write(true).open(path).await {
+        let _ = file.into_std().await.set_modified(SystemTime::now());
+    }
+}
+
+static CACHE: OnceLock<ChunkCache> = OnceLock::new();
+
+fn cache() -> &'static ChunkCache {
+    CACHE.get_or_init(|| ChunkCache::new(settings::scratch_subdir(CACHE_DIR_NAME)))
+}
+
+fn capacity() -> u64 {
+    settings::get().storage.max_cache_mb.saturating_mul(1024 * 1024)
+}
+
+// 查缓存，validator为None或者缓存关闭时直接返回None
+pub async fn get(file_id: &str, start: u64, end: u64, validator: Option<&str>) -> Option<Vec<u8>> {
+    let validator = validator?;
+    if capacity() == 0 {
+        return None;
+    }
+    cache().get(file_id, start, end, validator).await
+}
+
+// 存进缓存，失败只打日志，不影响播放
+pub async fn put(file_id: &str, start: u64, end: u64, validator: Option<&str>, data: &[u8]) {
+    let Some(validator) = validator else { return };
+    let capacity = capacity();
+    if capacity == 0 {
+        return;
+    }
+    if let Err(e) = cache().put(file_id, start, end, validator, data, capacity).await {
+        println!("[分片缓存] {:#}", e);
+    }
+}
+
+pub async fn status() -> CacheStatus {
+    cache().status(capacity()).await
+}
+
+// 按现在的上限淘汰，上限调小以后不用等下一次写入
+pub async fn trim() {
+    cache().evict(capacity()).await;
+}
+
+pub async fn clear() -> usize {
+    cache().clear().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ChunkCache::new(dir.path().join(CACHE_DIR_NAME));
+        cache.put("a.mp4", 0, 3, "v1", &[0; 4], 10).await.unwrap();
+        cache.put("a.mp4", 4, 7, "v1", &[1; 4], 10).await.unwrap();
+        // 用过第一个分片，超出容量时淘汰第二个
+        assert!(cache.get("a.mp4", 0, 3, "v1").await.is_some());
+        cache.put("a.mp4", 8, 11, "v1", &[2; 4], 10).await.unwrap();
+
+        assert!(cache.get("a.mp4", 4, 7, "v1").await.is_none());
+        assert_eq!(cache.get("a.mp4", 0, 3, "v1").await, Some(vec![0; 4]));
+        assert_eq!(cache.index.lock().unwrap().used, 8);
+    }
+
+    #[tokio::test]
+    async fn changed_file_misses_and_index_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join(CACHE_DIR_NAME);
+        let cache = ChunkCache::new(cache_dir.clone());
+        cache.put("a.mp4", 0, 3, "v1", b"abcd", 100).await.unwrap();
+        assert!(cache.get("a.mp4", 0, 3, "v2").await.is_none());
+        // 长度和范围对不上的数据不存
+        cache.put("a.mp4", 4, 9, "v1", b"ef", 100).await.unwrap();
+        assert!(cache.get("a.mp4", 4, 9, "v1").await.is_none());
+
+        let reopened = ChunkCache::new(cache_dir);
+        assert_eq!(reopened.get("a.mp4", 0, 3, "v1").await, Some(b"abcd".to_vec()));
+        assert_eq!(reopened.index.lock().unwrap().used, 4);
+        assert_eq!(reopened.clear().await, 1);
+        assert!(reopened.get("a.mp4", 0, 3, "v1").await.is_none());
+    }
+
+    #[test]
+    fn validator_prefers_etag() {
+        let mut meta = RemoteFileMeta { size: 10, name: "a".into(), modified_at: Some(5), etag: Some("\"x\"".into()) };
+        assert_eq!(validator(&meta).as_deref(), Some("\"x\""));
+        meta.etag = None;
+        assert_eq!(validator(&meta).as_deref(), Some("10-5"));
+        meta.modified_at = None;
+        assert_eq!(validator(&meta), None);
+    }
+}
@@ -0,0 +1,163 @@
+// 分片计算
+// 下载、上传、续传、校验修复都要按分片大小切文件，计算统一放在这里
+//
+// 思考：原来每个地方自己算：分片数用浮点数ceil，分片结束位置写成
+// "最后一个分片就是total_size - 1"，空文件时total_size - 1直接溢出panic，
+// 服务器返回的分片大小是0时还会除零。这里的函数对空文件和分片大小为0都有定义：
+// 空文件是0个分片，分片大小为0时也当作0个分片，调用方自己决定怎么处理。
+// 分片大小可以由设置和后端决定，但必须在MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE之间：
+// 太小请求数爆炸，太大一个分片失败重传的代价太高，而且整块放在内存里。
+
+// 默认分片大小 256KB
+pub const DEFAULT_CHUNK_SIZE: u64 = 256 * 1024;
+// 分片大小下限 64KB
+pub const MIN_CHUNK_SIZE: u64 = 64 * 1024;
+// 分片大小上限 16MB
+pub const MAX_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+// 分片大小是否在允许范围内
+pub fn is_valid_chunk_size(chunk_size: u64) -> bool {
+    (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk_size)
+}
+
+// 文件一共有多少个分片
+pub fn chunk_count(total_size: u64, chunk_size: u64) -> u32 {
+    if chunk_size == 0 {
+        return 0;
+    }
+    total_size.div_ceil(chunk_size).min(u32::MAX as u64) as u32
+}
+
+// 第index个分片的字节范围 (start, end)，end包含在内，和HTTP Range的写法一致
+// 超出文件范围返回None
+pub fn chunk_range(index: u32, total_size: u64, chunk_size: u64) -> Option<(u64, u64)> {
+    if index >= chunk_count(total_size, chunk_size) {
+        return None;
+    }
+    let start = index as u64 * chunk_size;
+    let end = start.saturating_add(chunk_size).min(total_size) - 1;
+    Some((start, end))
+}
+
+// 第index个分片的字节数，超出文件范围是0
+pub fn chunk_len(index: u32, total_size: u64, chunk_size: u64) -> u64 {
+    chunk_range(index, total_size, chunk_size).map_or(0, |(start, end)| end - start + 1)
+}
+
+// 前completed个分片一共多少字节，续传时用来恢复已下载大小
+pub fn completed_bytes(completed: u32, total_size: u64, chunk_size: u64) -> u64 {
+    (completed as u64).saturating_mul(chunk_size).min(total_size)
+}
+
+// 已经传完的字节数对应几个完整的分片（给进度显示用）
+pub fn completed_chunks(transferred: u64, total_size: u64, chunk_size: u64) -> u32 {
+    if chunk_size == 0 {
+        return 0;
+    }
+    if transferred >= total_size {
+        return chunk_count(total_size, chunk_size);
+    }
+    (transferred / chunk_size) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // 文件大小覆盖空文件、刚好整数倍和大文件
+    fn sizes() -> impl Strategy<Value = (u64, u64)> {
+        let chunk_size = prop_oneof![Just(1u64), Just(256 * 1024), Just(4 * 1024 * 1024), 1u64..10_000];
+        chunk_size.prop_flat_map(|chunk_size| {
+            let total_size = prop_oneof![
+                Just(0u64),
+                Just(chunk_size),
+                (1u64..64).prop_map(move |n| n * chunk_size),
+                (1u64..64).prop_map(move |n| n * chunk_size + 1),
+                (1u64..64).prop_map(move |n| n * chunk_size - 1),
+                0u64..100_000_000,
+            ];
+            (total_size, Just(chunk_size))
+        })
+    }
+
+    proptest! {
+        // 所有分片首尾相接，正好覆盖整个文件
+        #[test]
+        fn chunks_cover_file_exactly((total_size, chunk_size) in sizes()) {
+            let count = chunk_count(total_size, chunk_size);
+            let mut next_start = 0u64;
+            for index in 0..count {
+                let (start, end) = chunk_range(index, total_size, chunk_size).unwrap();
+                prop_assert_eq!(start, next_start);
+                prop_assert!(end >= start);
+                prop_assert!(end - start < chunk_size);
+                // 只有最后一个分片可以不满
+                if index + 1 < count {
+                    prop_assert_eq!(end - start + 1, chunk_size);
+                }
+                next_start = end + 1;
+            }
+            prop_assert_eq!(next_start, total_size);
+            prop_assert_eq!(chunk_range(count, total_size, chunk_size), None);
+        }
+
+        // 分片长度加起来等于文件大小
+        #[test]
+        fn chunk_lengths_sum_to_total((total_size, chunk_size) in sizes()) {
+            let count = chunk_count(total_size, chunk_size);
+            let sum: u64 = (0..count).map(|i| chunk_len(i, total_size, chunk_size)).sum();
+            prop_assert_eq!(sum, total_size);
+        }
+
+        // 续传：前n个分片的字节数就是第n个分片的起点，全部完成时等于文件大小
+        #[test]
+        fn resume_offset_matches_next_chunk((total_size, chunk_size) in sizes(), completed in 0u32..80) {
+            let count = chunk_count(total_size, chunk_size);
+            let offset = completed_bytes(completed, total_size, chunk_size);
+            prop_assert!(offset <= total_size);
+            match chunk_range(completed, total_size, chunk_size) {
+                Some((start, _)) => prop_assert_eq!(offset, start),
+                None => {
+                    prop_assert!(completed >= count);
+                    prop_assert_eq!(offset, total_size);
+                }
+            }
+        }
+
+        // 进度里的完成分片数不会超过总分片数，传完时正好相等
+        #[test]
+        fn completed_chunks_bounded((total_size, chunk_size) in sizes(), transferred in 0u64..200_000_000) {
+            let count = chunk_count(total_size, chunk_size);
+            let transferred = transferred.min(total_size);
+            let completed = completed_chunks(transferred, total_size, chunk_size);
+            prop_assert!(completed <= count);
+            if transferred == total_size {
+                prop_assert_eq!(completed, count);
+            }
+            prop_assert!(completed_bytes(completed, total_size, chunk_size) <= transferred);
+        }
+    }
+
+    #[test]
+    fn zero_chunk_size_has_no_chunks() {
+        assert_eq!(chunk_count(1024, 0), 0);
+        assert_eq!(chunk_range(0, 1024, 0), None);
+        assert_eq!(completed_chunks(512, 1024, 0), 0);
+    }
+
+    #[test]
+    fn chunk_size_bounds() {
+        assert!(is_valid_chunk_size(DEFAULT_CHUNK_SIZE));
+        assert!(is_valid_chunk_size(MIN_CHUNK_SIZE) && is_valid_chunk_size(MAX_CHUNK_SIZE));
+        assert!(!is_valid_chunk_size(0));
+        assert!(!is_valid_chunk_size(MAX_CHUNK_SIZE + 1));
+    }
+
+    #[test]
+    fn empty_file_has_no_chunks() {
+        assert_eq!(chunk_count(0, 256 * 1024), 0);
+        assert_eq!(chunk_range(0, 0, 256 * 1024), None);
+        assert_eq!(completed_bytes(3, 0, 256 * 1024), 0);
+    }
+}
@@ -0,0 +1,288 @@
+// 清理残留文件
+// 下载到一半放弃的文件、没有对应文件的下载元数据、写了一半的临时文件，时间长了会越积越多
+//
+// 思考：只动我们自己能认出来的文件，下载目录是用户的Downloads，不能乱删：
+// 1. 有<文件名>.camfc.json元数据的文件：说明没下载完（下载完会删元数据）
+// 2. 只有元数据没有文件：文件被用户删了，元数据没用了
+// 3. .camfc.json.tmp：保存元数据时崩溃留下的临时文件
+// 4. 应用数据目录里的.tmp临时文件
+// 正在下载/暂停中的任务（在任务表里）不算。
+// 先扫描返回可以释放多少空间，前端让用户确认后再带confirm=true真正删除。
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+use serde::Serialize;
+use tokio::fs;
+
+use crate::download_meta::META_SUFFIX;
+
+// 下载目录往下最多扫几层，Downloads里可能有很深的目录，没必要全扫
+const MAX_SCAN_DEPTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanKind {
+    PartialDownload,  // 没下载完的文件（连同元数据）
+    OrphanMeta,       // 文件已经不在了的元数据
+    TempFile,         // 临时文件
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OrphanFile {
+    pub path: PathBuf,
+    pub kind: OrphanKind,
+    pub size: u64,         // 包括元数据文件
+    pub age_days: u64,
+    #[serde(skip)]
+    pub remove: Vec<PathBuf>,  // 删除时要删的文件
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CleanupReport {
+    pub files: Vec<OrphanFile>,
+    pub reclaimable_bytes: u64,
+    pub deleted: bool,            // 是否已经删除（没有confirm时只扫描）
+    pub freed_bytes: u64,
+    pub failed: Vec<String>,      // 删除失败的文件和原因
+}
+
+// 文件多少天没改过了
+async fn age_of(path: &Path) -> Option<Duration> {
+    let modified = fs::metadata(path).await.ok()?.modified().ok()?;
+    Some(SystemTime::now().duration_since(modified).unwrap_or_default())
+}
+
+async fn size_of(path: &Path) -> u64 {
+    fs::metadata(path).await.map(|m| m.len()).unwrap_or(0)
+}
+
+fn has_suffix(path: &Path, suffix: &str) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(suffix))
+}
+
+// 扫描下载目录里的残留文件
+// active是正在下载的任务的保存路径，不算残留
+async fn scan_download_dir(
+    dir: &Path,
+    older_than: Duration,
+    active: &HashSet<PathBuf>,
+    found: &mut Vec<OrphanFile>,
+) -> Result<()> {
+    let tmp_suffix = format!("{}.tmp", META_SUFFIX);
+    let mut pending = vec![(dir.to_path_buf(), 0usize)];
+
+    while let Some((dir, depth)) = pending.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("读取目录失败，跳过: {:?} - {}", dir, e);
+                continue;
+            }
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let file_type = match entry.file_type().await {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+            if file_type.is_dir() {
+                if depth < MAX_SCAN_DEPTH {
+                    pending.push((path, depth + 1));
+                }
+                continue;
+            }
+
+            let kind_and_target = if has_suffix(&path, &tmp_suffix) {
+                Some((OrphanKind::TempFile, None))
+            } else if has_suffix(&path, META_SUFFIX) {
+                // 去掉后缀就是下载文件的路径
+                let name = path.to_string_lossy();
+                let target = PathBuf::from(&name[..name.len() - META_SUFFIX.len()]);
+                if active.contains(&target) {
+                    None
+                } else if target.exists() {
+                    Some((OrphanKind::PartialDownload, Some(target)))
+                } else {
+                    Some((OrphanKind::OrphanMeta, None))
+                }
+            } else {
+                None
+            };
+
+            let (kind, target) = match kind_and_target {
+                Some(found) => found,
+                None => continue,
+            };
+            let age = match age_of(&path).await {
+                Some(age) if age >= older_than => age,
+                _ => continue,
+            };
+
+            let mut size = size_of(&path).await;
+            let mut remove = vec![path.clone()];
+            let display_path = match target {
+                Some(target) => {
+                    size += size_of(&target).await;
+                    remove.insert(0, target.clone());
+                    target
+                }
+                None => path,
+            };
+
+            found.push(OrphanFile {
+                path: display_path,
+                kind,
+                size,
+                age_days: age.as_secs() / 86400,
+                remove,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// 扫描应用数据目录里的.tmp临时文件（只看第一层）
+async fn scan_temp_files(dir: &Path, older_than: Duration, found: &mut Vec<OrphanFile>) -> Result<()> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if !has_suffix(&path, ".tmp") || !path.is_file() {
+            continue;
+        }
+        let age = match age_of(&path).await {
+            Some(age) if age >= older_than => age,
+            _ => continue,
+        };
+        found.push(OrphanFile {
+            size: size_of(&path).await,
+            age_days: age.as_secs() / 86400,
+            remove: vec![path.clone()],
+            path,
+            kind: OrphanKind::TempFile,
+        });
+    }
+    Ok(())
+}
+
+// 扫描残留文件，confirm为true时删除
+pub async fn cleanup(
+    download_dir: &Path,
+    data_dir: &Path,
+    older_than: Duration,
+    active: &HashSet<PathBuf>,
+    confirm: bool,
+) -> Result<CleanupReport> {
+    let mut files = Vec::new();
+    scan_download_dir(download_dir, older_than, active, &mut files).await?;
+    scan_temp_files(data_dir, older_than, &mut files).await?;
+
+    let reclaimable_bytes = files.iter().map(|f| f.size).sum();
+    println!("找到 {} 个残留文件，可释放 {} 字节", files.len(), reclaimable_bytes);
+
+    let mut report = CleanupReport {
+        files,
+        reclaimable_bytes,
+        deleted: false,
+        freed_bytes: 0,
+        failed: Vec::new(),
+    };
+    if !confirm {
+        return Ok(report);
+    }
+
+    for file in &report.files {
+        let mut ok = true;
+        for path in &file.remove {
+            if let Err(e) = fs::remove_file(path).await {
+                ok = false;
+                report.failed.push(format!("{:?}: {}", path, e));
+            }
+        }
+        if ok {
+            report.freed_bytes += file.size;
+        }
+    }
+    report.deleted = true;
+    println!("清理完成，释放 {} 字节，失败 {} 个", report.freed_bytes, report.failed.len());
+
+    Ok(report)
+}
+
+// 正在下载的任务的保存路径
+pub async fn active_download_paths() -> HashSet<PathBuf> {
+    crate::transfer_manager::download_tasks()
+        .lock()
+        .await
+        .values()
+        .map(|task| task.save_path().to_path_buf())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download_meta::DownloadMeta;
+
+    fn meta_for(save_path: &Path) -> PathBuf {
+        DownloadMeta::meta_path(save_path)
+    }
+
+    #[tokio::test]
+    async fn finds_and_deletes_only_leftovers() {
+        let downloads = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+
+        // 没下载完的文件
+        let partial = downloads.path().join("partial.bin");
+        std::fs::write(&partial, vec![0u8; 100]).unwrap();
+        std::fs::write(meta_for(&partial), "{}").unwrap();
+        // 正在下载的文件
+        let active_file = downloads.path().join("active.bin");
+        std::fs::write(&active_file, vec![0u8; 10]).unwrap();
+        std::fs::write(meta_for(&active_file), "{}").unwrap();
+        // 只剩元数据
+        std::fs::write(meta_for(&downloads.path().join("gone.bin")), "{}").unwrap();
+        // 下载完的普通文件，不能动
+        let done = downloads.path().join("done.bin");
+        std::fs::write(&done, vec![0u8; 50]).unwrap();
+        // 临时文件
+        std::fs::write(data.path().join("settings.json.tmp"), "x").unwrap();
+
+        let active: HashSet<PathBuf> = [active_file.clone()].into_iter().collect();
+
+        let report = cleanup(downloads.path(), data.path(), Duration::ZERO, &active, false).await.unwrap();
+        assert!(!report.deleted);
+        assert_eq!(report.files.len(), 3);
+        assert_eq!(report.reclaimable_bytes, 100 + 2 + 2 + 1);
+        assert!(partial.exists());
+
+        let report = cleanup(downloads.path(), data.path(), Duration::ZERO, &active, true).await.unwrap();
+        assert!(report.deleted);
+        assert!(report.failed.is_empty());
+        assert!(!partial.exists());
+        assert!(!meta_for(&partial).exists());
+        assert!(active_file.exists() && meta_for(&active_file).exists());
+        assert!(done.exists());
+    }
+
+    #[tokio::test]
+    async fn skips_recent_files() {
+        let downloads = tempfile::tempdir().unwrap();
+        let data = tempfile::tempdir().unwrap();
+        std::fs::write(meta_for(&downloads.path().join("gone.bin")), "{}").unwrap();
+
+        let report = cleanup(downloads.path(), data.path(), Duration::from_secs(86400), &HashSet::new(), false)
+            .await
+            .unwrap();
+        assert!(report.files.is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+// 命令行模式
+// 不打开界面，在服务器上或者脚本里直接用：取TOTP、上传、下载、同步文件夹、查看状态
+//
+// 思考：认证、配置、上传下载这些逻辑本来就都在库里，界面只是Tauri命令包了一层，
+// 命令行直接调同样的模块，不用另外实现一遍。和界面的区别：
+// 1. 任务在前台执行，传完（或失败）才返回，退出码表示成功与否，脚本好判断
+// 2. 标准输出只输出结果（JSON），日志默认不输出，--verbose时输出到标准错误
+// 3. 没有笔的服务器用--auth env-token，从环境变量CAMFC_ID/CAMFC_TOKEN读认证信息，只对这次运行有效
+// sync目前是单向的：把本地文件夹推到云端，规则和界面上的文件夹上传一样。
+
+use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::download::DownloadTask;
+use crate::settings::{self, AuthProviderKind};
+use crate::transfer_filter::{FilterRules, TransferFilter};
+use crate::upload::UploadTask;
+use crate::{auth, ble_permissions, cloud_api, config, transfer_plan};
+
+#[derive(Parser)]
+#[command(name = "camfc-cli", version, about = "CAMFC客户端命令行")]
+struct Cli {
+    /// 把日志输出到标准错误
+    #[arg(long, global = true)]
+    verbose: bool,
+
+    /// 认证方式，默认用设置里的
+    #[arg(long, global = true, value_enum)]
+    auth: Option<AuthArg>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum AuthArg {
+    Cpen,
+    EnvToken,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 输出设备ID和TOTP
+    Totp,
+    /// 输出后端地址、认证方式和云盘容量
+    Status,
+    /// 上传文件
+    Upload {
+        files: Vec<PathBuf>,
+        /// 云端目标目录
+        #[arg(long)]
+        target: Option<String>,
+        /// 只输出上传计划，不上传
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// 下载文件
+    Download {
+        /// 云端路径，例如 ds/下载.png
+        remote_path: String,
+        /// 保存位置，默认是当前目录下的同名文件
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// 本地已有一致的文件时也重新下载
+        #[arg(long)]
+        force: bool,
+    },
+    /// 把本地文件夹同步到云端（单向）
+    Sync {
+        folder: PathBuf,
+        /// 云端目标目录
+        #[arg(long)]
+        target: Option<String>,
+        /// 排除规则（gitignore风格），可以写多次
+        #[arg(long)]
+        exclude: Vec<String>,
+        /// 排除隐藏文件
+        #[arg(long)]
+        exclude_hidden: bool,
+        /// 超过这个大小的文件不上传（字节）
+        #[arg(long)]
+        max_file_size: Option<u64>,
+        /// 只输出同步计划，不上传
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+// 命令失败时输出到标准错误的内容，错误信息里可能带着TOTP/令牌，和日志一样打码
+fn error_line(e: &anyhow::Error) -> String {
+    crate::auth::redact(&e.to_string())
+}
+
+// 输出命令结果
+fn print_json(value: &serde_json::Value) {
+    ::std::println!("{}", serde_json::to_string_pretty(value).unwrap_or_default());
+}
+
+// 命令行入口，返回退出码
+pub fn run() -> i32 {
+    let cli = Cli::parse();
+    let mode = if cli.verbose { crate::CONSOLE_LOG_STDERR } else { crate::CONSOLE_LOG_OFF };
+    crate::CONSOLE_LOG_MODE.store(mode, std::sync::atomic::Ordering::Relaxed);
+
+    let rt = match tokio::runtime::Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            ::std::eprintln!("创建运行时失败: {}", e);
+            return 1;
+        }
+    };
+    rt.block_on(async move {
+        if let Err(e) = settings::init_settings().await {
+            println!("设置加载失败: {}", e);
+        }
+        if let Some(auth) = cli.auth {
+            settings::set_auth_provider_for_session(match auth {
+                AuthArg::Cpen => AuthProviderKind::Cpen,
+                AuthArg::EnvToken => AuthProviderKind::EnvToken,
+            });
+        }
+
+        match execute(cli.command).await {
+            Ok(success) => {
+                if success { 0 } else { 1 }
+            }
+            Err(e) => {
+                ::std::eprintln!("{}", error_line(&e));
+                1
+            }
+        }
+    })
+}
+
+// 执行子命令，返回是否全部成功
+async fn execute(command: Command) -> Result<bool, String> {
+    if let Command::Totp = command {
+        let info = auth::current_credentials().await.map_err(|e| e.to_string())?;
+        print_json(&serde_json::json!({ "device_id": info.device_id, "totp": info.totp }));
+        return Ok(true);
+    }
+
+    config::init_config().await.map_err(|e| format!("配置初始化失败: {}", e))?;
+
+    match command {
+        Command::Totp => unreachable!(),
+        Command::Status => status().await,
+        Command::Upload { files, target, dry_run } => upload(files, target, dry_run).await,
+        Command::Download { remote_path, output, force } => download(remote_path, output, force).await,
+        Command::Sync { folder, target, exclude, exclude_hidden, max_file_size, dry_run } => {
+            let rules = FilterRules { exclude, exclude_hidden, max_file_size };
+            sync(folder, target, rules, dry_run).await
+        }
+    }
+}
+
+async fn status() -> Result<bool, String> {
+    let backend_url = config::get_backend_url().map_err(|e| e.to_string())?;
+    let provider = settings::get().auth.provider;
+
+    let quota = match auth::current_credentials().await {
+        Ok(auth_info) => match cloud_api::get_storage_quota(&auth_info).await {
+            Ok(quota) => serde_json::json!({ "used": quota.used, "total": quota.total, "remaining": quota.remaining() }),
+            Err(e) => serde_json::json!({ "error": e.to_string() }),
+        },
+        Err(e) => serde_json::json!({ "error": e.to_string() }),
+    };
+    let reachable = quota.get("error").is_none();
+
+    // 用笔认证时顺便检查蓝牙权限，认证失败时能看出是不是权限问题
+    let mut status = serde_json::json!({
+        "backend_url": backend_url,
+        "auth_provider": provider,
+        "quota": quota,
+    });
+    if provider == AuthProviderKind::Cpen {
+        status["ble_permissions"] = serde_json::json!(ble_permissions::check_ble_permissions().await);
+    }
+    print_json(&status);
+    Ok(reachable)
+}
+
+// 逐个上传，返回每个文件的结果
+async fn upload_all(files: &[(PathBuf, Option<String>)]) -> (Vec<serde_json::Value>, bool) {
+    let mut results = Vec::new();
+    let mut all_ok = true;
+    for (path, target) in files {
+        let result = async {
+            let auth_info = auth::current_credentials().await?;
+            let task = UploadTask::new(path.clone(), auth_info, target.as_deref()).await?;
+            task.start().await?;
+            anyhow::Ok(task.upload_id().to_string())
+        }
+        .await;
+
+        results.push(match result {
+            Ok(upload_id) => serde_json::json!({ "path": path, "upload_id": upload_id }),
+            Err(e) => {
+                all_ok = false;
+                serde_json::json!({ "path": path, "error": format!("{:#}", e) })
+            }
+        });
+    }
+    (results, all_ok)
+}
+
+async fn upload(files: Vec<PathBuf>, target: Option<String>, dry_run: bool) -> Result<bool, String> {
+    if files.is_empty() {
+        return Err("没有提供文件路径".to_string());
+    }
+
+    if dry_run {
+        let paths: Vec<String> = files.iter().map(|p| p.to_string_lossy().to_string()).collect();
+        let mut plan = transfer_plan::plan_upload(&paths, target.as_deref(), settings::get().transfer.symlink_policy).await;
+        if let Ok(auth_info) = auth::current_credentials().await {
+            mark_conflicts(&auth_info, &mut plan).await;
+        }
+        print_json(&serde_json::json!(plan));
+        return Ok(true);
+    }
+
+    let files: Vec<(PathBuf, Option<String>)> = files.into_iter().map(|path| (path, target.clone())).collect();
+    let (results, all_ok) = upload_all(&files).await;
+    print_json(&serde_json::json!({ "uploads": results }));
+    Ok(all_ok)
+}
+
+async fn download(remote_path: String, output: Option<PathBuf>, force: bool) -> Result<bool, String> {
+    let save_path = match output {
+        Some(path) => path,
+        None => {
+            let name = remote_path.rsplit('/').next().unwrap_or(&remote_path);
+            PathBuf::from(name)
+        }
+    };
+
+    let auth_info = auth::current_credentials().await.map_err(|e| e.to_string())?;
+    let task = DownloadTask::new(remote_path.clone(), save_path.clone(), auth_info)
+        .await
+        .map_err(|e| format!("创建下载任务失败: {:#}", e))?;
+    let already_downloaded = !force && task.complete_if_already_downloaded().await;
+    if !already_downloaded {
+        task.start().await.map_err(|e| format!("下载失败: {:#}", e))?;
+    }
+
+    // start()遇到错误状态也可能正常返回，以最终状态为准
+    let progress = task.get_progress().await;
+    if let crate::download::DownloadStatus::Error(e) = &progress.status {
+        return Err(format!("下载失败: {}", e));
+    }
+    print_json(&serde_json::json!({
+        "remote_path": remote_path,
+        "saved_to": save_path,
+        "size": progress.total_size,
+        "already_downloaded": already_downloaded,
+    }));
+    Ok(true)
+}
+
+async fn sync(folder: PathBuf, target: Option<String>, rules: FilterRules, dry_run: bool) -> Result<bool, String> {
+    let filter = TransferFilter::new(&rules).map_err(|e| format!("{:#}", e))?;
+    let mut plan = transfer_plan::plan_folder_upload(
+        &folder,
+        target.as_deref(),
+        &filter,
+        settings::get().transfer.symlink_policy,
+    )
+    .await
+    .map_err(|e| format!("{:#}", e))?;
+
+    let auth_info = auth::current_credentials().await.map_err(|e| e.to_string())?;
+    if dry_run {
+        mark_conflicts(&auth_info, &mut plan).await;
+        print_json(&serde_json::json!(plan));
+        return Ok(true);
+    }
+
+    transfer_plan::create_remote_dirs(&auth_info, &plan).await;
+    let files: Vec<(PathBuf, Option<String>)> = plan.files
+        .iter()
+        .filter(|file| file.action == transfer_plan::PlanAction::Upload)
+        .map(|file| (PathBuf::from(&file.local_path), file.remote_dir.clone()))
+        .collect();
+    let (results, all_ok) = upload_all(&files).await;
+    print_json(&serde_json::json!({ "uploads": results, "skipped": plan.skipped_count }));
+    Ok(all_ok)
+}
+
+// 列出计划里每个云端目录，标记会覆盖的同名文件
+async fn mark_conflicts(auth_info: &auth::AuthInfo, plan: &mut transfer_plan::TransferPlan) {
+    for remote_dir in plan.remote_dirs() {
+        if let Ok(entries) = cloud_api::list_dir(auth_info, remote_dir.as_deref().unwrap_or("")).await {
+            plan.mark_conflicts(remote_dir.as_deref(), &entries);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_definition_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parses_sync_filters() {
+        let cli = Cli::try_parse_from([
+            "camfc-cli", "--auth", "env-token", "sync", "./docs",
+            "--exclude", "node_modules", "--exclude", "*.tmp", "--max-file-size", "1024", "--dry-run",
+        ])
+        .unwrap();
+        assert!(matches!(cli.auth, Some(AuthArg::EnvToken)));
+        match cli.command {
+            Command::Sync { exclude, max_file_size, dry_run, .. } => {
+                assert_eq!(exclude, vec!["node_modules", "*.tmp"]);
+                assert_eq!(max_file_size, Some(1024));
+                assert!(dry_run);
+            }
+            _ => panic!("应该解析成sync"),
+        }
+    }
+
+    #[test]
+    fn redacts_secrets_in_errors() {
+        crate::auth::remember_secret("cli-token-5f2a9c");
+        let e = anyhow::anyhow!("认证失败: token=cli-token-5f2a9c");
+        assert_eq!(error_line(&e), "认证失败: token=******");
+    }
+}
@@ -0,0 +1,211 @@
+// 笔的时钟偏差补偿
+// 后端因为TOTP时间窗口对不上拒绝认证时，算出偏了多少秒，按设备记下来，
+// 以后给这支笔发setTime时把偏差补上
+//
+// 思考：
+// 1. 偏差从哪来：后端在前后几个窗口里找到了匹配的TOTP时会在X-Totp-Skew里告诉我们偏了多少秒
+//    （正数表示笔的时间快了）；老后端没有这个头，就用响应的Date和本机时间比，
+//    这种情况其实是电脑的时间不准，setTime发过去的时间本来就是错的，一样要补
+// 2. 一次拒绝不算数：笔配错了、TOTP刚好过期都会被拒绝。连着两个不同的TOTP都偏了差不多的秒数才记下来，
+//    中间有一次认证成功就重新开始
+// 3. 记下的是"发setTime时要加多少秒"，后端报的偏差是在已经补过的基础上算的，所以新的补偿是旧的减去偏差；
+//    Date算出来的是电脑和后端的差，先加上已有的补偿再按同样的方式算，补过以后不会越补越多
+// 4. 存在应用数据目录的clock_drift.json，换电脑/重启后同一支笔还能接着用；
+//    补偿变了以后下次问TOTP时一定发setTime，不等set_time_interval_secs
+// 5. 偏差太大（超过MAX_OFFSET_SECS）多半不是时钟漂移，是笔配错了或者时区设置有问题，不补
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use anyhow::{Context, Result};
+use reqwest::header::{self, HeaderMap};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::event_emitter;
+use crate::storage::get_app_data_dir;
+
+// 后端报告TOTP偏差的响应头（秒）
+pub const SKEW_HEADER: &str = "X-Totp-Skew";
+// 偏差小于这个不处理（Date只精确到秒，再加上网络延迟）
+const MIN_SKEW_SECS: i64 = 10;
+// 两次偏差相差不超过这么多算"一样"
+const MATCH_TOLERANCE_SECS: i64 = 5;
+// 连续几次一样的偏差才记下来
+const CONFIRMATIONS: u32 = 2;
+// 补偿最多多少秒
+const MAX_OFFSET_SECS: i64 = 3600;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceDrift {
+    pub offset_secs: i64,   // 发setTime时加上的秒数
+    pub updated_at: i64,    // Unix时间戳（秒）
+}
+
+// 还没确认的偏差
+struct Observation {
+    totp: String,
+    skew: i64,
+    count: u32,
+}
+
+#[derive(Default)]
+struct DriftTracker {
+    offsets: HashMap<String, DeviceDrift>,
+    pending: HashMap<String, Observation>,
+}
+
+impl DriftTracker {
+    fn offset(&self, device_id: &str) -> i64 {
+        self.offsets.get(device_id).map(|d| d.offset_secs).unwrap_or(0)
+    }
+
+    // 记一次被拒绝时的偏差，确认以后返回新的补偿
+    fn observe(&mut self, device_id: &str, totp: &str, skew: i64) -> Option<i64> {
+        if skew.abs() < MIN_SKEW_SECS {
+            self.pending.remove(device_id);
+            return None;
+        }
+        let count = match self.pending.get(device_id) {
+            // 同一个TOTP被并发的请求一起拒绝，只算一次
+            Some(last) if last.totp == totp => return None,
+            Some(last) if (last.skew - skew).abs() <= MATCH_TOLERANCE_SECS => last.count + 1,
+            _ => 1,
+        };
+        if count < CONFIRMATIONS {
+            self.pending.insert(device_id.to_string(), Observation { totp: totp.to_string(), skew, count });
+            return None;
+        }
+
+        self.pending.remove(device_id);
+        let offset = self.offset(device_id) - skew;
+        if offset.abs() > MAX_OFFSET_SECS {
+            println!("设备 {} 的时间偏差 {} 秒太大，不补偿", device_id, offset);
+            return None;
+        }
+        self.offsets.insert(device_id.to_string(), DeviceDrift {
+            offset_secs: offset,
+            updated_at: chrono::Utc::now().timestamp(),
+        });
+        Some(offset)
+    }
+}
+
+static TRACKER: OnceLock<Mutex<DriftTracker>> = OnceLock::new();
+// 补偿变了，下次问TOTP时要重新setTime
+static RESYNC: AtomicBool = AtomicBool::new(false);
+
+fn drift_path() -> Result<PathBuf> {
+    let data_dir = get_app_data_dir().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(data_dir.join("clock_drift.json"))
+}
+
+// 第一次用到时从磁盘加载，文件很小，直接同步读
+fn tracker() -> std::sync::MutexGuard<'static, DriftTracker> {
+    TRACKER
+        .get_or_init(|| {
+            let offsets = drift_path()
+                .ok()
+                .and_then(|path| std::fs::read_to_string(path).ok())
+                .and_then(|content| serde_json::from_str(&content).ok())
+                .unwrap_or_default();
+            Mutex::new(DriftTracker { offsets, pending: HashMap::new() })
+        })
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+fn save(offsets: &HashMap<String, DeviceDrift>) -> Result<()> {
+    let path = drift_path()?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).context("创建数据目录失败")?;
+    }
+    let content = serde_json::to_string_pretty(offsets)?;
+    std::fs::write(&path, content).with_context(|| format!("写入时钟偏差文件失败: {:?}", path))
+}
+
+// 这支笔发setTime时要加多少秒
+pub fn offset_secs(device_id: &str) -> i64 {
+    tracker().offset(device_id)
+}
+
+// 补偿有没有变过，取完清掉
+pub fn take_resync() -> bool {
+    RESYNC.swap(false, Ordering::Relaxed)
+}
+
+// 被拒绝时笔偏了多少秒：优先用后端给的，没有就拿Date和本机时间比
+fn detect_skew(headers: &HeaderMap, current_offset: i64, now: i64) -> Option<i64> {
+    let value = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    if let Some(skew) = value(SKEW_HEADER).and_then(|v| v.trim().parse::<i64>().ok()) {
+        return Some(skew);
+    }
+    let server_now = crate::transfer_http::parse_http_date(value(header::DATE.as_str())?)?;
+    Some(now - server_now + current_offset)
+}
+
+// 每个响应都过一下：request_headers是发出去的请求头，response_headers是响应头
+pub fn note_response(status: StatusCode, request_headers: &HeaderMap, response_headers: &HeaderMap) {
+    let rejected = status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN;
+    if !rejected && !status.is_success() {
+        return;
+    }
+    let Some((device_id, totp)) = crate::auth::credentials_in_header(request_headers) else { return };
+
+    let mut tracker = tracker();
+    if !rejected {
+        tracker.pending.remove(&device_id);
+        return;
+    }
+    let now = chrono::Utc::now().timestamp();
+    let Some(skew) = detect_skew(response_headers, tracker.offset(&device_id), now) else { return };
+    let Some(offset) = tracker.observe(&device_id, &totp, skew) else { return };
+
+    println!("设备 {} 的时间偏了 {} 秒，以后setTime补偿 {} 秒", device_id, skew, offset);
+    if let Err(e) = save(&tracker.offsets) {
+        println!("保存时钟偏差失败: {:#}", e);
+    }
+    RESYNC.store(true, Ordering::Relaxed);
+    event_emitter::emit_event("clock-drift-updated", serde_json::json!({
+        "device_id": device_id,
+        "offset_secs": offset,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirms_consistent_skew_before_compensating() {
+        let mut tracker = DriftTracker::default();
+        assert_eq!(tracker.observe("pen", "111111", 30), None);
+        // 同一个TOTP的并发请求不算第二次
+        assert_eq!(tracker.observe("pen", "111111", 30), None);
+        assert_eq!(tracker.observe("pen", "222222", 28), Some(-28));
+        assert_eq!(tracker.offset("pen"), -28);
+
+        // 补过以后又偏了，在原来的基础上继续补
+        assert_eq!(tracker.observe("pen", "333333", -30), None);
+        assert_eq!(tracker.observe("pen", "444444", -30), Some(2));
+        // 偏差对不上就重新计数，太小的不算
+        assert_eq!(tracker.observe("other", "111111", 60), None);
+        assert_eq!(tracker.observe("other", "222222", -60), None);
+        assert_eq!(tracker.observe("other", "333333", 3), None);
+        assert_eq!(tracker.offset("other"), 0);
+    }
+
+    #[test]
+    fn detects_skew_from_date_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::DATE, "Sun, 06 Nov 1994 08:49:37 GMT".parse().unwrap());
+        let now = 784111777 + 40; // 本机快了40秒
+        assert_eq!(detect_skew(&headers, 0, now), Some(40));
+        // 已经补了-40秒，笔的时间是准的
+        assert_eq!(detect_skew(&headers, -40, now), Some(0));
+
+        headers.insert(SKEW_HEADER, "-30".parse().unwrap());
+        assert_eq!(detect_skew(&headers, 0, now), Some(-30));
+    }
+}
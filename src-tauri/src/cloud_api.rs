@@ -0,0 +1,569 @@
+// 云盘文件管理接口
+// 封装后端 /files、/share、/storage 相关的接口：列目录、建目录、删除、回收站、搜索、分享、容量查询
+//
+// 思考：这些接口原来只有前端fileSystem.js在调，Rust端用不到。
+// 现在WebDAV等功能也要在Rust里操作远程文件，放到这里统一调用，
+// 认证信息由调用方传进来（和ChunkDownloader一样）。
+
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
+
+use reqwest::Method;
+
+use crate::config;
+use crate::auth::AuthInfo;
+use crate::download::{FileType, get_file_type_from_extension};
+use crate::transfer_http::{self, Endpoint, TransferHttp};
+
+// 远程文件/目录信息，字段和 /files/?path= 返回的entries一致
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteEntry {
+    pub name: String,
+    pub path: String,
+    #[serde(default)]
+    pub is_dir: bool,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    // 后端可能返回时间戳或者字符串，原样保留
+    #[serde(default)]
+    pub modified_at: Option<serde_json::Value>,
+}
+
+impl RemoteEntry {
+    // 修改时间转成Unix时间戳（秒），解析不了就返回None
+    pub fn modified_timestamp(&self) -> Option<i64> {
+        parse_timestamp(self.modified_at.as_ref()?)
+    }
+}
+
+// 后端返回的时间可能是时间戳或者字符串，统一转成Unix时间戳（秒）
+fn parse_timestamp(value: &serde_json::Value) -> Option<i64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_f64().map(|t| t as i64),
+        serde_json::Value::String(s) => chrono::DateTime::parse_from_rfc3339(s)
+            .map(|t| t.timestamp())
+            .or_else(|_| {
+                chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+                    .map(|t| t.and_utc().timestamp())
+            })
+            .ok(),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ListResponse {
+    #[serde(default)]
+    entries: Vec<RemoteEntry>,
+    #[serde(default)]
+    next_cursor: Option<String>,   // 后端支持分页时返回
+}
+
+// 本地分页用的游标前缀，后端不支持分页时用偏移量当游标
+const LOCAL_CURSOR_PREFIX: &str = "local:";
+
+// 目录列表的一页
+#[derive(Debug, Clone, Serialize)]
+pub struct ListPage {
+    pub entries: Vec<RemoteEntry>,
+    pub next_cursor: Option<String>,   // 为None表示已经是最后一页
+}
+
+// 构建带认证头的请求
+fn build_request(auth_info: &AuthInfo, method: Method, endpoint: Endpoint<'_>) -> Result<reqwest::RequestBuilder> {
+    TransferHttp::new(auth_info.clone())?.request(method, endpoint)
+}
+
+// 列出目录内容 - 调用 /files/?path=
+pub async fn list_dir(auth_info: &AuthInfo, path: &str) -> Result<Vec<RemoteEntry>> {
+    let request = build_request(auth_info, Method::GET, Endpoint::Files)?.query(&[("path", path)]);
+    let response = transfer_http::send(request, "获取文件列表").await?;
+
+    let list: ListResponse = response
+        .json()
+        .await
+        .context("解析文件列表失败")?;
+    Ok(list.entries)
+}
+
+// 带ETag的目录列表请求结果
+pub enum ConditionalList {
+    NotModified,
+    Modified { entries: Vec<RemoteEntry>, etag: Option<String> },
+}
+
+// 列出目录内容，带上If-None-Match，目录没变化时后端返回304
+pub async fn list_dir_if_changed(auth_info: &AuthInfo, path: &str, etag: Option<&str>) -> Result<ConditionalList> {
+    let mut request = build_request(auth_info, Method::GET, Endpoint::Files)?.query(&[("path", path)]);
+    if let Some(etag) = etag {
+        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+
+    let response = transfer_http::send_raw(request, "获取文件列表").await?;
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(ConditionalList::NotModified);
+    }
+    let response = transfer_http::check_response(response, "获取文件列表").await?;
+
+    let etag = response
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+    let list: ListResponse = response
+        .json()
+        .await
+        .context("解析文件列表失败")?;
+    Ok(ConditionalList::Modified { entries: list.entries, etag })
+}
+
+// 分页列出目录内容 - 调用 /files/?path=&cursor=&limit=
+// 后端支持分页时返回next_cursor；不支持时会返回全部条目，这里按偏移量在本地切页
+pub async fn list_dir_page(auth_info: &AuthInfo, path: &str, cursor: Option<&str>, limit: usize) -> Result<ListPage> {
+    let local_offset = cursor
+        .and_then(|c| c.strip_prefix(LOCAL_CURSOR_PREFIX))
+        .and_then(|offset| offset.parse::<usize>().ok());
+
+    let mut params = vec![("path", path.to_string()), ("limit", limit.to_string())];
+    if let (Some(cursor), None) = (cursor, local_offset) {
+        params.push(("cursor", cursor.to_string()));
+    }
+
+    let request = build_request(auth_info, Method::GET, Endpoint::Files)?.query(&params);
+    let response = transfer_http::send(request, "获取文件列表").await?;
+
+    let list: ListResponse = response
+        .json()
+        .await
+        .context("解析文件列表失败")?;
+
+    if list.next_cursor.is_some() || (local_offset.is_none() && list.entries.len() <= limit) {
+        return Ok(ListPage {
+            entries: list.entries,
+            next_cursor: list.next_cursor,
+        });
+    }
+
+    // 后端忽略了分页参数，本地切页
+    let offset = local_offset.unwrap_or(0);
+    let total = list.entries.len();
+    let entries: Vec<RemoteEntry> = list.entries.into_iter().skip(offset).take(limit).collect();
+    let next_offset = offset + entries.len();
+    Ok(ListPage {
+        entries,
+        next_cursor: (next_offset < total).then(|| format!("{}{}", LOCAL_CURSOR_PREFIX, next_offset)),
+    })
+}
+
+// 查找单个文件/目录的信息（列出父目录后查找）
+// 根目录直接当作目录返回
+pub async fn find_entry(auth_info: &AuthInfo, path: &str) -> Result<Option<RemoteEntry>> {
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        return Ok(Some(RemoteEntry {
+            name: String::new(),
+            path: "/".to_string(),
+            is_dir: true,
+            size: 0,
+            mime_type: None,
+            modified_at: None,
+        }));
+    }
+
+    let (parent, name) = match trimmed.rsplit_once('/') {
+        Some((parent, name)) => (parent, name),
+        None => ("", trimmed),
+    };
+
+    let entries = list_dir(auth_info, &format!("/{}", parent)).await?;
+    Ok(entries.into_iter().find(|e| e.name == name))
+}
+
+// 创建目录 - 调用 POST /files/directories
+pub async fn create_dir(auth_info: &AuthInfo, parent: &str, directory_name: &str) -> Result<()> {
+    let request = build_request(auth_info, Method::POST, Endpoint::Directories)?
+        .query(&[("path", parent), ("directory_name", directory_name)]);
+    transfer_http::send(request, "创建目录").await?;
+    Ok(())
+}
+
+// 删除文件或目录 - 调用 DELETE /files/{path}
+// permanent为false时进入回收站
+pub async fn delete(auth_info: &AuthInfo, path: &str, permanent: bool) -> Result<()> {
+    let request = build_request(auth_info, Method::DELETE, Endpoint::File(path))?
+        .query(&[("permanent", permanent.to_string())]);
+    transfer_http::send(request, "删除文件").await?;
+    forget_storage_quota();
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ShareResponse {
+    url: Option<String>,      // 完整链接或者以/开头的相对路径
+    token: Option<String>,    // 没有url时用token拼出链接
+    #[serde(default)]
+    expires_at: Option<serde_json::Value>,
+}
+
+// 分享链接
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareLink {
+    pub url: String,
+    pub expires_at: Option<serde_json::Value>,
+}
+
+// 创建分享链接 - 调用 POST /share/create
+// expires_in为链接有效秒数，None表示永久有效
+pub async fn create_share_link(auth_info: &AuthInfo, path: &str, expires_in: Option<u64>) -> Result<ShareLink> {
+    let mut params = vec![("path", path.to_string())];
+    if let Some(expires_in) = expires_in {
+        params.push(("expires_in", expires_in.to_string()));
+    }
+
+    let request = build_request(auth_info, Method::POST, Endpoint::ShareCreate)?.query(&params);
+    let response = transfer_http::send(request, "创建分享链接").await?;
+
+    let share: ShareResponse = response
+        .json()
+        .await
+        .context("解析分享链接失败")?;
+
+    let base_url = config::get_backend_url()?;
+    let url = match (share.url, share.token) {
+        (Some(url), _) if url.starts_with("http://") || url.starts_with("https://") => url,
+        (Some(url), _) => format!("{}/{}", base_url, url.trim_start_matches('/')),
+        (None, Some(token)) => format!("{}/share/{}", base_url, token),
+        (None, None) => return Err(anyhow::anyhow!("后端没有返回分享链接")),
+    };
+
+    Ok(ShareLink {
+        url,
+        expires_at: share.expires_at,
+    })
+}
+
+// 云盘容量
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageQuota {
+    pub used: u64,    // 已使用字节数
+    pub total: u64,   // 总容量字节数
+}
+
+impl StorageQuota {
+    pub fn remaining(&self) -> u64 {
+        self.total.saturating_sub(self.used)
+    }
+}
+
+// 最近一次查到的云盘容量，上传前先用它检查，不用为了查容量去找笔拿TOTP
+static LAST_QUOTA: OnceLock<Mutex<Option<(Instant, StorageQuota)>>> = OnceLock::new();
+// 查到的容量多久内可以直接用
+const QUOTA_CACHE_TTL: Duration = Duration::from_secs(300);
+
+fn last_quota() -> &'static Mutex<Option<(Instant, StorageQuota)>> {
+    LAST_QUOTA.get_or_init(|| Mutex::new(None))
+}
+
+// 最近QUOTA_CACHE_TTL内查到的容量，没有时返回None
+pub fn cached_storage_quota() -> Option<StorageQuota> {
+    last_quota().lock().unwrap().as_ref()
+        .filter(|(at, _)| at.elapsed() < QUOTA_CACHE_TTL)
+        .map(|(_, quota)| quota.clone())
+}
+
+// 删除文件后容量变了，下次重新查
+fn forget_storage_quota() {
+    *last_quota().lock().unwrap() = None;
+}
+
+// 查询云盘容量 - 调用 GET /storage/quota
+pub async fn get_storage_quota(auth_info: &AuthInfo) -> Result<StorageQuota> {
+    let request = build_request(auth_info, Method::GET, Endpoint::StorageQuota)?;
+    let response = transfer_http::send(request, "查询云盘容量").await?;
+
+    let quota: StorageQuota = response
+        .json()
+        .await
+        .context("解析云盘容量失败")?;
+    *last_quota().lock().unwrap() = Some((Instant::now(), quota.clone()));
+    Ok(quota)
+}
+
+// 回收站里的文件/目录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub name: String,
+    pub path: String,                      // 在回收站里的路径，恢复时使用
+    #[serde(default)]
+    pub original_path: Option<String>,     // 删除前的路径
+    #[serde(default)]
+    pub is_dir: bool,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub deleted_at: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TrashListResponse {
+    #[serde(default)]
+    entries: Vec<TrashEntry>,
+}
+
+// 列出回收站 - 调用 GET /files/trash
+pub async fn list_trash(auth_info: &AuthInfo) -> Result<Vec<TrashEntry>> {
+    let request = build_request(auth_info, Method::GET, Endpoint::Trash)?;
+    let response = transfer_http::send(request, "获取回收站列表").await?;
+
+    let list: TrashListResponse = response
+        .json()
+        .await
+        .context("解析回收站列表失败")?;
+    Ok(list.entries)
+}
+
+// 从回收站恢复 - 调用 POST /files/trash/restore?path=
+pub async fn restore_from_trash(auth_info: &AuthInfo, path: &str) -> Result<()> {
+    let request = build_request(auth_info, Method::POST, Endpoint::TrashRestore)?.query(&[("path", path)]);
+    transfer_http::send(request, "恢复文件").await?;
+    Ok(())
+}
+
+// 清空回收站 - 调用 DELETE /files/trash
+pub async fn empty_trash(auth_info: &AuthInfo) -> Result<()> {
+    let request = build_request(auth_info, Method::DELETE, Endpoint::Trash)?;
+    transfer_http::send(request, "清空回收站").await?;
+    forget_storage_quota();
+    Ok(())
+}
+
+// 远程文件详细信息
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteFileStat {
+    pub path: String,
+    pub size: u64,
+    pub modified_at: Option<i64>,         // Unix时间戳（秒）
+    pub content_type: Option<String>,
+    pub etag: Option<String>,
+    pub hash: Option<String>,             // 服务器端计算的SHA256
+    pub shared: bool,                     // 是否有有效的分享链接
+    pub share_url: Option<String>,
+}
+
+// /files/metadata 返回的额外信息，字段都可能缺失
+#[derive(Debug, Default, Deserialize)]
+struct MetadataResponse {
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(default)]
+    modified_at: Option<serde_json::Value>,
+    #[serde(default)]
+    mime_type: Option<String>,
+    #[serde(default)]
+    hash: Option<String>,
+    #[serde(default)]
+    shared: bool,
+    #[serde(default)]
+    share_url: Option<String>,
+}
+
+// 获取文件详细信息
+// 先用HEAD /download/{path}拿大小、类型、修改时间，再调 GET /files/metadata?path= 补充哈希和分享状态
+// 后端没有metadata接口时只返回HEAD能拿到的信息
+pub async fn stat_file(auth_info: &AuthInfo, path: &str) -> Result<RemoteFileStat> {
+    let http = TransferHttp::new(auth_info.clone())?;
+
+    let request = http.request(Method::HEAD, Endpoint::Download(path))?;
+    let response = transfer_http::send_raw(request, "获取文件信息").await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(anyhow::anyhow!("文件不存在: {}", path));
+    }
+    let response = transfer_http::check_response(response, "获取文件信息").await?;
+
+    let headers = response.headers();
+    let header_str = |name: reqwest::header::HeaderName| {
+        headers.get(name).and_then(|v| v.to_str().ok()).map(|s| s.to_string())
+    };
+    let head_size = header_str(reqwest::header::CONTENT_LENGTH).and_then(|s| s.parse::<u64>().ok());
+    let content_type = header_str(reqwest::header::CONTENT_TYPE);
+    let etag = header_str(reqwest::header::ETAG);
+    let head_modified = header_str(reqwest::header::LAST_MODIFIED)
+        .and_then(|s| transfer_http::parse_http_date(&s));
+
+    let request = http.request(Method::GET, Endpoint::Metadata)?.query(&[("path", path)]);
+    let metadata = match transfer_http::send_raw(request, "获取文件元数据").await {
+        Ok(response) if response.status().is_success() => {
+            response.json::<MetadataResponse>().await.unwrap_or_default()
+        }
+        Ok(response) => {
+            println!("获取文件元数据失败: {}，只使用HEAD信息", response.status());
+            MetadataResponse::default()
+        }
+        Err(e) => {
+            println!("{:#}，只使用HEAD信息", e);
+            MetadataResponse::default()
+        }
+    };
+
+    let metadata_modified = metadata.modified_at.as_ref().and_then(parse_timestamp);
+
+    Ok(RemoteFileStat {
+        path: path.to_string(),
+        size: metadata.size.or(head_size).unwrap_or(0),
+        modified_at: metadata_modified.or(head_modified),
+        content_type: metadata.mime_type.or(content_type),
+        etag,
+        hash: metadata.hash,
+        shared: metadata.shared,
+        share_url: metadata.share_url,
+    })
+}
+
+// 后端不支持搜索时，本地遍历最多列出的目录数量
+const MAX_FALLBACK_SEARCH_DIRS: usize = 500;
+
+// 搜索结果（一页）
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub entries: Vec<RemoteEntry>,
+    pub total: usize,
+    pub page: usize,          // 从1开始
+    pub page_size: usize,
+    pub has_more: bool,
+    pub fallback: bool,       // 是否是本地遍历得到的结果
+    pub truncated: bool,      // 本地遍历是否因为目录太多提前停止
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchResponse {
+    #[serde(default)]
+    entries: Vec<RemoteEntry>,
+    #[serde(default)]
+    total: Option<usize>,
+}
+
+// 搜索参数
+#[derive(Debug, Clone)]
+pub struct SearchQuery<'a> {
+    pub query: &'a str,
+    pub path_prefix: Option<&'a str>,
+    pub file_type: Option<&'a str>,   // image/video/.../folder
+    pub page: usize,
+    pub page_size: usize,
+}
+
+impl SearchQuery<'_> {
+    // 本地过滤时判断一个条目是否匹配
+    fn matches(&self, entry: &RemoteEntry) -> bool {
+        if !entry.name.to_lowercase().contains(&self.query.to_lowercase()) {
+            return false;
+        }
+        match self.file_type {
+            None => true,
+            Some(t) if t.eq_ignore_ascii_case("folder") => entry.is_dir,
+            Some(t) => {
+                let ext = std::path::Path::new(&entry.name)
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .unwrap_or_default();
+                !entry.is_dir && FileType::from_name(t) == Some(get_file_type_from_extension(ext))
+            }
+        }
+    }
+}
+
+// 搜索云盘文件 - 调用 GET /files/search
+// 后端没有搜索接口（404/405）时，递归列目录后在本地过滤
+pub async fn search(auth_info: &AuthInfo, query: &SearchQuery<'_>) -> Result<SearchResult> {
+    let mut params = vec![
+        ("q", query.query.to_string()),
+        ("page", query.page.to_string()),
+        ("page_size", query.page_size.to_string()),
+    ];
+    if let Some(prefix) = query.path_prefix {
+        params.push(("path", prefix.to_string()));
+    }
+    if let Some(file_type) = query.file_type {
+        params.push(("type", file_type.to_string()));
+    }
+
+    let request = build_request(auth_info, Method::GET, Endpoint::Search)?.query(&params);
+    let response = transfer_http::send_raw(request, "搜索文件").await?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::METHOD_NOT_ALLOWED {
+        println!("后端不支持搜索接口，改为本地遍历搜索");
+        return search_by_listing(auth_info, query).await;
+    }
+    let response = transfer_http::check_response(response, "搜索文件").await?;
+
+    let result: SearchResponse = response
+        .json()
+        .await
+        .context("解析搜索结果失败")?;
+    let total = result.total.unwrap_or(result.entries.len());
+
+    Ok(SearchResult {
+        has_more: query.page * query.page_size < total,
+        entries: result.entries,
+        total,
+        page: query.page,
+        page_size: query.page_size,
+        fallback: false,
+        truncated: false,
+    })
+}
+
+// 递归列目录后本地过滤
+async fn search_by_listing(auth_info: &AuthInfo, query: &SearchQuery<'_>) -> Result<SearchResult> {
+    let mut pending_dirs = std::collections::VecDeque::from([query.path_prefix.unwrap_or("/").to_string()]);
+    let mut matched = Vec::new();
+    let mut listed_dirs = 0;
+
+    while let Some(dir) = pending_dirs.pop_front() {
+        if listed_dirs >= MAX_FALLBACK_SEARCH_DIRS {
+            break;
+        }
+        listed_dirs += 1;
+
+        let entries = match list_dir(auth_info, &dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("搜索时列出目录 {} 失败，跳过: {}", dir, e);
+                continue;
+            }
+        };
+
+        for entry in entries {
+            if entry.is_dir {
+                pending_dirs.push_back(entry.path.clone());
+            }
+            if query.matches(&entry) {
+                matched.push(entry);
+            }
+        }
+    }
+
+    let truncated = !pending_dirs.is_empty();
+    if truncated {
+        println!("本地搜索已列出 {} 个目录，剩余目录不再搜索", listed_dirs);
+    }
+
+    let total = matched.len();
+    let start = query.page.saturating_sub(1) * query.page_size;
+    let entries: Vec<RemoteEntry> = matched.into_iter().skip(start).take(query.page_size).collect();
+
+    Ok(SearchResult {
+        has_more: start + entries.len() < total,
+        entries,
+        total,
+        page: query.page,
+        page_size: query.page_size,
+        fallback: true,
+        truncated,
+    })
+}
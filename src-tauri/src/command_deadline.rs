@@ -0,0 +1,134 @@
+// 命令总超时和进度事件
+// 需要蓝牙的命令（get_totp、get_device_id、扫描、连接）整体加一个截止时间
+//
+// 思考：get_totp第一次调用要扫描5秒+连接（带重试）+发命令（带重试），
+// 再加上等设备管理器的锁，15秒以上很常见，蓝牙出问题时甚至一直不返回，
+// 前端看起来就像卡死了。这里做两件事：
+// 1. 整个命令包一层超时，超时返回结构化的Timeout错误（带命令名、超时时间、卡在哪一步）
+// 2. 设备管理器在扫描/连接/认证前发device-progress事件，前端可以显示当前在干什么
+// 3. 卡在哪一步按命令记（task_local），不用全局变量，同时跑的两个命令不会互相覆盖
+// 4. 超时时future被丢弃，设备管理器来不及把"connecting"改回去，这里替它改成未连接
+//
+// 错误用带kind字段的JSON返回，前端可以区分超时和普通失败（显示时用message字段）：
+// {"kind":"timeout","command":"get_totp","timeout_secs":30,"stage":"connecting","message":"..."}
+// {"kind":"failed","message":"..."}
+// 笔的固件不支持要用的功能时（见pen_protocol）：
+// {"kind":"unsupported_firmware","feature":"battery","protocol_version":1,"message":"..."}
+
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::Serialize;
+
+use crate::event_emitter;
+use crate::pen_protocol::PenFeature;
+
+// 各命令的总超时
+pub const GET_TOTP_TIMEOUT: Duration = Duration::from_secs(30);
+pub const GET_DEVICE_ID_TIMEOUT: Duration = Duration::from_secs(30);
+pub const SCAN_TIMEOUT: Duration = Duration::from_secs(20);
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const GET_BATTERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// 设备操作进行到哪一步
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressStage {
+    Scanning,        // 扫描蓝牙设备
+    Connecting,      // 连接笔
+    Authenticating,  // 向笔要设备ID/TOTP
+}
+
+impl ProgressStage {
+    fn message(self) -> &'static str {
+        match self {
+            ProgressStage::Scanning => "正在扫描蓝牙设备",
+            ProgressStage::Connecting => "正在连接设备",
+            ProgressStage::Authenticating => "正在读取设备认证信息",
+        }
+    }
+}
+
+tokio::task_local! {
+    // 当前命令最近一次进度，超时的时候告诉前端卡在哪一步（见思考3）
+    static STAGE: Arc<Mutex<Option<ProgressStage>>>;
+}
+
+// 报告进度，发device-progress事件
+pub fn report(stage: ProgressStage) {
+    // 不在with_deadline里调用时没有要记的地方，只发事件
+    let _ = STAGE.try_with(|current| *current.lock().unwrap() = Some(stage));
+    event_emitter::emit_event("device-progress", serde_json::json!({
+        "stage": stage,
+        "message": stage.message(),
+    }));
+}
+
+// 命令错误
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CommandError {
+    Timeout {
+        command: &'static str,
+        timeout_secs: u64,
+        stage: Option<ProgressStage>,
+        message: String,
+    },
+    UnsupportedFirmware {
+        feature: PenFeature,
+        protocol_version: u32,
+        message: String,
+    },
+    Failed {
+        message: String,
+    },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Timeout { message, .. }
+            | CommandError::UnsupportedFirmware { message, .. }
+            | CommandError::Failed { message } => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl From<String> for CommandError {
+    fn from(message: String) -> Self {
+        CommandError::Failed { message }
+    }
+}
+
+// 给命令加总超时
+// 超时后命令的future会被丢弃，设备管理器的锁随之释放，下次调用会重新检查连接
+pub async fn with_deadline<T, E, F>(command: &'static str, limit: Duration, fut: F) -> Result<T, CommandError>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Into<CommandError>,
+{
+    let stage_cell = Arc::new(Mutex::new(None));
+
+    match tokio::time::timeout(limit, STAGE.scope(stage_cell.clone(), fut)).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => {
+            let stage = *stage_cell.lock().unwrap();
+            // 见思考4
+            crate::device_state::abandon_connecting();
+            let message = match stage {
+                Some(stage) => format!("{}超时（{}秒），{}时没有响应", command, limit.as_secs(), stage.message()),
+                None => format!("{}超时（{}秒）", command, limit.as_secs()),
+            };
+            println!("[超时] {}", message);
+            Err(CommandError::Timeout {
+                command,
+                timeout_secs: limit.as_secs(),
+                stage,
+                message,
+            })
+        }
+    }
+}
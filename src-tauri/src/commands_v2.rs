@@ -0,0 +1,355 @@
+// v2命令
+// 返回带类型的结构体和结构化错误，命令名都以v2_开头
+//
+// 思考：旧命令大多返回serde_json::Value和String错误，字段全靠前后端约定，
+// 任务不存在时get_download_progress还会返回一个假的Pending进度，前端分不清"还没开始"和"没有这个任务"。
+// 直接改旧命令会让已经发出去的前端版本出错，所以新开一组v2命令，
+// 旧命令保留下来，变成调用这里再转换成旧格式的薄封装（已废弃），前端可以一个页面一个页面地迁移。
+// Tauri的应用命令没有命名空间，只能用前缀区分版本。
+//
+// 错误统一是 {"code": "not_found"|"timeout"|"failed", "message": "...", "detail": ...}，
+// 超时的detail里是command_deadline的超时信息（命令名、超时时间、卡在哪一步）。
+
+use serde::Serialize;
+
+use crate::command_deadline::CommandError;
+use crate::device_state::{self, DeviceInfo};
+use crate::download::{DownloadProgress, DownloadStatus};
+use crate::transfer_manager::{download_tasks, upload_tasks, TransferPriority};
+use crate::upload::{UploadProgress, UploadStatus};
+
+// 错误类型
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NotFound,              // 任务或文件不存在
+    Timeout,               // 命令超时
+    UnsupportedFirmware,   // 笔的固件不支持这个功能
+    Failed,                // 其他失败
+}
+
+// v2命令的错误
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn not_found(message: String) -> Self {
+        Self { code: ErrorCode::NotFound, message, detail: None }
+    }
+}
+
+impl From<String> for ApiError {
+    fn from(message: String) -> Self {
+        Self { code: ErrorCode::Failed, message, detail: None }
+    }
+}
+
+impl From<CommandError> for ApiError {
+    fn from(err: CommandError) -> Self {
+        match &err {
+            CommandError::Timeout { message, .. } => Self {
+                code: ErrorCode::Timeout,
+                message: message.clone(),
+                detail: serde_json::to_value(&err).ok(),
+            },
+            CommandError::UnsupportedFirmware { message, .. } => Self {
+                code: ErrorCode::UnsupportedFirmware,
+                message: message.clone(),
+                detail: serde_json::to_value(&err).ok(),
+            },
+            CommandError::Failed { message } => Self::from(message.clone()),
+        }
+    }
+}
+
+// 传输方向
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferDirection {
+    Download,
+    Upload,
+}
+
+// 传输状态，下载和上传共用
+// 出错时错误信息放在TransferProgress.error里，不再拼进状态字符串
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferState {
+    Pending,
+    Running,
+    Paused,
+    Completed,
+    FileInUse,
+    Failed,
+    WaitingForServer,   // 后端维护中，resume_at是预计恢复时间
+    Verifying,          // 下载完成后校验中，verify_percentage是校验进度
+    Following,          // 下载跟随模式，已经下载到当前末尾，等文件继续增长
+}
+
+// 传输进度
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferProgress {
+    pub direction: TransferDirection,
+    pub id: String,                         // 下载是file_id，上传是upload_id
+    pub name: String,                       // 文件名
+    pub total_size: u64,
+    pub transferred: u64,                   // 已传输字节数
+    pub state: TransferState,
+    pub error: Option<String>,              // state为failed时的错误信息
+    pub resume_at: Option<i64>,             // state为waiting_for_server时预计恢复的Unix时间戳
+    pub verify_percentage: Option<u8>,      // state为verifying时的校验进度（0-100）
+    pub chunks_total: u32,
+    pub chunks_completed: u32,
+    pub speed_kbps: f64,
+    pub retry_count: u32,
+    pub priority: Option<TransferPriority>, // 只有下载有优先级
+    pub progress_percentage: u32,
+}
+
+fn percentage(done: u64, total: u64) -> u32 {
+    if total > 0 {
+        (done as f64 / total as f64 * 100.0).round() as u32
+    } else {
+        0
+    }
+}
+
+impl From<DownloadProgress> for TransferProgress {
+    fn from(p: DownloadProgress) -> Self {
+        let resume_at = match p.status {
+            DownloadStatus::WaitingForServer(resume_at) => Some(resume_at),
+            _ => None,
+        };
+        let verify_percentage = match p.status {
+            DownloadStatus::Verifying(percent) => Some(percent),
+            _ => None,
+        };
+        let (state, error) = match p.status {
+            DownloadStatus::Pending => (TransferState::Pending, None),
+            DownloadStatus::Downloading => (TransferState::Running, None),
+            DownloadStatus::Paused => (TransferState::Paused, None),
+            DownloadStatus::Completed => (TransferState::Completed, None),
+            DownloadStatus::Error(msg) => (TransferState::Failed, Some(msg)),
+            DownloadStatus::WaitingForServer(_) => (TransferState::WaitingForServer, None),
+            DownloadStatus::Verifying(_) => (TransferState::Verifying, None),
+            DownloadStatus::Following => (TransferState::Following, None),
+        };
+        Self {
+            direction: TransferDirection::Download,
+            progress_percentage: percentage(p.downloaded, p.total_size),
+            id: p.file_id,
+            name: p.file_name,
+            total_size: p.total_size,
+            transferred: p.downloaded,
+            state,
+            error,
+            resume_at,
+            verify_percentage,
+            chunks_total: p.chunks_total,
+            chunks_completed: p.chunks_completed,
+            speed_kbps: p.speed_kbps,
+            retry_count: p.retry_count,
+            priority: Some(p.priority),
+        }
+    }
+}
+
+impl From<UploadProgress> for TransferProgress {
+    fn from(p: UploadProgress) -> Self {
+        let resume_at = match p.status {
+            UploadStatus::WaitingForServer(resume_at) => Some(resume_at),
+            _ => None,
+        };
+        let (state, error) = match p.status {
+            UploadStatus::Pending => (TransferState::Pending, None),
+            UploadStatus::Uploading => (TransferState::Running, None),
+            UploadStatus::Paused => (TransferState::Paused, None),
+            UploadStatus::Completed => (TransferState::Completed, None),
+            UploadStatus::FileInUse => (TransferState::FileInUse, None),
+            UploadStatus::Error(msg) => (TransferState::Failed, Some(msg)),
+            UploadStatus::WaitingForServer(_) => (TransferState::WaitingForServer, None),
+        };
+        Self {
+            direction: TransferDirection::Upload,
+            progress_percentage: percentage(p.uploaded, p.total_size),
+            id: p.upload_id,
+            name: p.filename,
+            total_size: p.total_size,
+            transferred: p.uploaded,
+            state,
+            error,
+            resume_at,
+            verify_percentage: None,
+            chunks_total: p.chunks_total,
+            chunks_completed: p.chunks_completed,
+            speed_kbps: p.speed_kbps,
+            retry_count: p.retry_count,
+            priority: None,
+        }
+    }
+}
+
+impl TransferProgress {
+    // 转成旧的get_download_progress/get_upload_progress返回格式
+    pub fn to_legacy_json(&self) -> serde_json::Value {
+        let status = match (self.state, self.direction) {
+            (TransferState::Failed, _) => format!("Error: {}", self.error.as_deref().unwrap_or_default()),
+            (TransferState::Pending, _) => "Pending".to_string(),
+            (TransferState::Running, TransferDirection::Download) => "Downloading".to_string(),
+            (TransferState::Running, TransferDirection::Upload) => "Uploading".to_string(),
+            (TransferState::Paused, _) => "Paused".to_string(),
+            (TransferState::Completed, _) => "Completed".to_string(),
+            (TransferState::FileInUse, _) => "FileInUse".to_string(),
+            (TransferState::WaitingForServer, _) => "WaitingForServer".to_string(),
+            (TransferState::Verifying, _) => "Verifying".to_string(),
+            (TransferState::Following, _) => "Following".to_string(),
+        };
+        match self.direction {
+            TransferDirection::Download => serde_json::json!({
+                "file_id": self.id,
+                "file_name": self.name,
+                "total_size": self.total_size,
+                "downloaded": self.transferred,
+                "status": status,
+                "chunks_total": self.chunks_total,
+                "chunks_completed": self.chunks_completed,
+                "speed_kbps": self.speed_kbps,
+                "retry_count": self.retry_count,
+                "priority": self.priority,
+                "progress_percentage": self.progress_percentage,
+                "verify_percentage": self.verify_percentage,
+            }),
+            TransferDirection::Upload => serde_json::json!({
+                "upload_id": self.id,
+                "filename": self.name,
+                "total_size": self.total_size,
+                "uploaded": self.transferred,
+                "status": status,
+                "chunks_total": self.chunks_total,
+                "chunks_completed": self.chunks_completed,
+                "speed_kbps": self.speed_kbps,
+                "retry_count": self.retry_count,
+                "progress_percentage": self.progress_percentage,
+            }),
+        }
+    }
+}
+
+// 连接状态
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionStatus {
+    pub status: String,               // disconnected/connecting/connected
+    pub connected: bool,
+    pub device: Option<DeviceInfo>,
+    pub adapter_powered: Option<bool>,
+    pub description: String,          // 给用户看的状态文字（旧get_connection_status的返回值）
+}
+
+/// 获取下载进度（v2）
+///
+/// 任务不存在时返回code为not_found的错误，不再返回假的Pending进度
+#[tauri::command]
+pub async fn v2_get_download_progress(file_id: String) -> Result<TransferProgress, ApiError> {
+    let task = download_tasks()
+        .lock()
+        .await
+        .get(&file_id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found(format!("下载任务不存在: {}", file_id)))?;
+    Ok(task.get_progress().await.into())
+}
+
+/// 获取上传进度（v2）
+///
+/// 任务不存在时返回code为not_found的错误
+#[tauri::command]
+pub async fn v2_get_upload_progress(upload_id: String) -> Result<TransferProgress, ApiError> {
+    let task = upload_tasks()
+        .lock()
+        .await
+        .get(&upload_id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found(format!("上传任务不存在: {}", upload_id)))?;
+    Ok(task.get_progress().await.into())
+}
+
+/// 获取连接状态（v2）
+///
+/// 和get_connection_status一样读状态快照，不等设备管理器的锁，返回结构化的状态
+#[tauri::command]
+pub async fn v2_get_connection_status() -> Result<ConnectionStatus, ApiError> {
+    let snapshot = device_state::connection_snapshot();
+    Ok(ConnectionStatus {
+        status: snapshot.status.clone(),
+        connected: snapshot.is_connected(),
+        device: snapshot.device.clone(),
+        adapter_powered: snapshot.adapter_powered,
+        description: snapshot.describe(),
+    })
+}
+
+/// 获取TOTP（v2）
+///
+/// 和get_totp走同一套流程（缓存、合并并发调用、总超时），错误换成v2的格式
+#[tauri::command]
+pub async fn v2_get_totp() -> Result<String, ApiError> {
+    crate::get_totp().await.map_err(ApiError::from)
+}
+
+/// 获取设备ID（v2）
+#[tauri::command]
+pub async fn v2_get_device_id() -> Result<String, ApiError> {
+    crate::get_device_id().await.map_err(ApiError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download(status: DownloadStatus) -> DownloadProgress {
+        DownloadProgress {
+            file_id: "ds/a.bin".to_string(),
+            file_name: "a.bin".to_string(),
+            total_size: 200,
+            downloaded: 50,
+            status,
+            chunks_total: 4,
+            chunks_completed: 1,
+            chunk_size: 50,
+            speed_kbps: 1.5,
+            retry_count: 0,
+            priority: TransferPriority::Low,
+        }
+    }
+
+    #[test]
+    fn download_error_is_split_out_of_status() {
+        let progress = TransferProgress::from(download(DownloadStatus::Error("断网".to_string())));
+        assert_eq!(progress.state, TransferState::Failed);
+        assert_eq!(progress.error.as_deref(), Some("断网"));
+        assert_eq!(progress.progress_percentage, 25);
+
+        // 旧格式仍然把错误拼在status里
+        let legacy = progress.to_legacy_json();
+        assert_eq!(legacy["status"], "Error: 断网");
+        assert_eq!(legacy["downloaded"], 50);
+        assert_eq!(legacy["priority"], "low");
+    }
+
+    #[test]
+    fn timeout_keeps_detail() {
+        let err = ApiError::from(CommandError::Timeout {
+            command: "get_totp",
+            timeout_secs: 30,
+            stage: None,
+            message: "get_totp超时（30秒）".to_string(),
+        });
+        assert_eq!(err.code, ErrorCode::Timeout);
+        assert_eq!(err.detail.unwrap()["timeout_secs"], 30);
+    }
+}
@@ -0,0 +1,376 @@
+// 传输并发控制
+// 限制同时进行的传输数，自动模式下按实测吞吐量和出错率找到每个后端合适的并发数
+//
+// 思考：原来每个任务spawn出去就直接开始传，同时点几十个下载就是几十路请求一起打到后端，
+// 小水管的后端反而更慢，还容易超时。现在任务开始前先在这里拿一个名额：
+// 1. 设置里max_concurrent_transfers大于0时，就是固定的并发数
+// 2. 为0时是自动模式：没测过的后端从1路开始，每个统计周期如果名额都用满了、
+//    吞吐量比上一档高了MIN_GAIN以上，就再加一路；加了没有明显变快就退回上一档并定下来；
+//    周期内出错率超过MAX_ERROR_RATE就减半，连续CLEAN_PERIODS_TO_REPROBE个周期没问题后从减半后的值重新往上探测
+// 按吞吐量定下来的值按主机保存到设置的learned_concurrency里，下次直接用，不再从1开始探测。
+// 因为出错减下来的值不保存：断一次Wi-Fi或者后端抖一下，每个分片都失败，
+// 保存的话会8→4→2→1一路减下去，重启后也一直是1。
+// 想重新探测的话删掉对应主机的记录就行。吞吐量用流量统计的会话字节数算，
+// 出错率按分片请求的成功/失败次数算。
+
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::{background_mode, bandwidth, config, settings};
+
+// 自动模式最多加到几路
+const MAX_AUTO_CONCURRENCY: usize = 8;
+// 统计周期
+const TUNE_INTERVAL: Duration = Duration::from_secs(15);
+// 加一路之后吞吐量至少要提高这么多才算有用
+const MIN_GAIN: f64 = 0.1;
+// 出错率超过这个值就减少并发
+const MAX_ERROR_RATE: f64 = 0.2;
+// 周期内请求太少时不看出错率，偶尔一次失败不算
+const MIN_REQUESTS_FOR_ERROR_RATE: u64 = 5;
+// 因为出错减半后，连续这么多个周期出错率正常就重新往上探测
+const CLEAN_PERIODS_TO_REPROBE: u32 = 4;
+
+// 一个统计周期的测量结果
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub bytes_per_sec: f64,
+    pub requests: u64,
+    pub errors: u64,
+    pub saturated: bool,   // 名额是不是都用满了，没用满时吞吐量和并发数无关
+}
+
+// 慢启动探测，只管算数，不碰全局状态
+#[derive(Debug, Clone)]
+pub struct Tuner {
+    limit: usize,
+    max: usize,
+    best: Option<(usize, f64)>,   // 探测过程中吞吐量最高的一档（并发数, 字节/秒）
+    settled: bool,
+    backoff: Option<u32>,         // 因为出错减半以后已经连续正常了几个周期，没减过时为None
+}
+
+impl Tuner {
+    // 没测过的后端，从1路开始探测
+    pub fn probing(max: usize) -> Self {
+        Self { limit: 1, max: max.max(1), best: None, settled: false, backoff: None }
+    }
+
+    // 已经学到并发数的后端，直接用
+    pub fn learned(limit: usize, max: usize) -> Self {
+        let max = max.max(1);
+        Self { limit: limit.clamp(1, max), max, best: None, settled: true, backoff: None }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.settled
+    }
+
+    // 根据一个周期的测量结果调整并发数，返回true表示得到了需要保存的值
+    pub fn observe(&mut self, sample: &Sample) -> bool {
+        if sample.requests >= MIN_REQUESTS_FOR_ERROR_RATE
+            && sample.errors as f64 / sample.requests as f64 > MAX_ERROR_RATE
+        {
+            // 出错减下来的值不保存（见文件开头）
+            self.limit = (self.limit / 2).max(1);
+            self.best = None;
+            self.settled = true;
+            self.backoff = Some(0);
+            return false;
+        }
+
+        if let Some(clean) = self.backoff {
+            // 请求太少的周期看不出有没有恢复，不算
+            if sample.requests < MIN_REQUESTS_FOR_ERROR_RATE {
+                return false;
+            }
+            if clean + 1 < CLEAN_PERIODS_TO_REPROBE {
+                self.backoff = Some(clean + 1);
+                return false;
+            }
+            // 恢复正常了，从现在的并发数往上重新探测
+            self.backoff = None;
+            self.settled = false;
+        }
+
+        if self.settled || !sample.saturated || sample.requests == 0 {
+            return false;
+        }
+
+        match self.best {
+            Some((best_limit, best_speed)) if sample.bytes_per_sec < best_speed * (1.0 + MIN_GAIN) => {
+                // 加了一路没有明显变快，退回上一档
+                self.limit = best_limit;
+                self.settled = true;
+                true
+            }
+            _ => {
+                self.best = Some((self.limit, sample.bytes_per_sec));
+                if self.limit >= self.max {
+                    self.settled = true;
+                    return true;
+                }
+                self.limit += 1;
+                false
+            }
+        }
+    }
+}
+
+struct ControllerState {
+    host: Option<String>,
+    tuner: Tuner,
+    active: usize,
+    requests: u64,
+    errors: u64,
+}
+
+static STATE: OnceLock<Mutex<ControllerState>> = OnceLock::new();
+static SLOT_NOTIFY: OnceLock<Notify> = OnceLock::new();
+
+fn state() -> &'static Mutex<ControllerState> {
+    STATE.get_or_init(|| {
+        Mutex::new(ControllerState {
+            host: None,
+            tuner: Tuner::probing(MAX_AUTO_CONCURRENCY),
+            active: 0,
+            requests: 0,
+            errors: 0,
+        })
+    })
+}
+
+fn slot_notify() -> &'static Notify {
+    SLOT_NOTIFY.get_or_init(Notify::new)
+}
+
+// 当前后端的主机名（带端口），学到的并发数按这个保存
+fn current_host() -> Option<String> {
+    let url = reqwest::Url::parse(&config::get_backend_url().ok()?).ok()?;
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+// 后端换了就换成新后端的探测状态
+fn sync_host(state: &mut ControllerState) {
+    let host = current_host();
+    if host == state.host {
+        return;
+    }
+    let learned = host
+        .as_ref()
+        .and_then(|host| settings::get().transfer.learned_concurrency.get(host).copied());
+    state.tuner = match learned {
+        Some(limit) => Tuner::learned(limit, MAX_AUTO_CONCURRENCY),
+        None => Tuner::probing(MAX_AUTO_CONCURRENCY),
+    };
+    state.host = host;
+    state.requests = 0;
+    state.errors = 0;
+}
+
+// 当前的并发上限
+fn current_limit(state: &mut ControllerState) -> usize {
+    let fixed = settings::get().transfer.max_concurrent_transfers;
+    if fixed > 0 {
+        return fixed;
+    }
+    sync_host(state);
+    state.tuner.limit()
+}
+
+// 传输期间持有，drop时把名额还回去
+pub struct TransferPermit(());
+
+impl Drop for TransferPermit {
+    fn drop(&mut self) {
+        state().lock().unwrap().active -= 1;
+        slot_notify().notify_waiters();
+    }
+}
+
+// 等到有空闲名额
+pub async fn acquire() -> TransferPermit {
+    loop {
+        // 先注册等待再检查名额，避免错过中间发出的通知
+        let notified = slot_notify().notified();
+        {
+            let mut state = state().lock().unwrap();
+            if state.active < background_mode::limit_concurrency(current_limit(&mut state)) {
+                state.active += 1;
+                return TransferPermit(());
+            }
+        }
+        notified.await;
+    }
+}
+
+// 并发上限变大了（比如退出后台模式），让排队的任务重新检查名额
+pub fn wake_waiters() {
+    slot_notify().notify_waiters();
+}
+
+// 记录一次分片请求的结果，用来算出错率
+pub fn record_chunk(ok: bool) {
+    let mut state = state().lock().unwrap();
+    state.requests += 1;
+    if !ok {
+        state.errors += 1;
+    }
+}
+
+// 当前的并发状态，给前端显示
+pub fn status() -> serde_json::Value {
+    let mut state = state().lock().unwrap();
+    let limit = current_limit(&mut state);
+    let fixed = settings::get().transfer.max_concurrent_transfers > 0;
+    serde_json::json!({
+        "mode": if fixed { "fixed" } else { "auto" },
+        "host": state.host,
+        "limit": limit,
+        "effective_limit": background_mode::limit_concurrency(limit),
+        "background": background_mode::is_active(),
+        "active": state.active,
+        "settled": fixed || state.tuner.is_settled(),
+    })
+}
+
+// 常驻服务：每个统计周期调整一次自动模式的并发数，定下来的值保存到设置
+pub async fn tune_loop() {
+    let mut last_bytes = bandwidth::session_bytes();
+    let mut interval = tokio::time::interval(TUNE_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let bytes = bandwidth::session_bytes();
+        let transferred = bytes.saturating_sub(last_bytes);
+        last_bytes = bytes;
+
+        let learned = {
+            let mut state = state().lock().unwrap();
+            let before = current_limit(&mut state);
+            let sample = Sample {
+                bytes_per_sec: transferred as f64 / TUNE_INTERVAL.as_secs_f64(),
+                requests: state.requests,
+                errors: state.errors,
+                saturated: state.active >= before,
+            };
+            state.requests = 0;
+            state.errors = 0;
+
+            // 后台模式限了并发和速度，这时的吞吐量不能用来探测
+            if settings::get().transfer.max_concurrent_transfers > 0 || background_mode::is_active() {
+                continue;
+            }
+            let settled = state.tuner.observe(&sample);
+            let after = state.tuner.limit();
+            if after != before {
+                println!("自动并发数调整: {} -> {}（{:.0} 字节/秒，{}/{} 次请求失败）",
+                    before, after, sample.bytes_per_sec, sample.errors, sample.requests);
+            }
+            if after > before {
+                slot_notify().notify_waiters();
+            }
+            match (&state.host, settled) {
+                (Some(host), true) => Some((host.clone(), after)),
+                _ => None,
+            }
+        };
+
+        if let Some((host, limit)) = learned {
+            let patch = serde_json::json!({ "transfer": { "learned_concurrency": { host.clone(): limit } } });
+            match settings::update(patch).await {
+                Ok(_) => println!("已保存 {} 的并发数: {}", host, limit),
+                Err(e) => println!("保存 {} 的并发数失败: {}", host, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(bytes_per_sec: f64) -> Sample {
+        Sample { bytes_per_sec, requests: 20, errors: 0, saturated: true }
+    }
+
+    #[test]
+    fn adds_streams_while_throughput_improves() {
+        let mut tuner = Tuner::probing(8);
+        assert!(!tuner.observe(&sample(100.0)));
+        assert!(!tuner.observe(&sample(180.0)));
+        assert!(!tuner.observe(&sample(250.0)));
+        assert_eq!(tuner.limit(), 4);
+
+        // 第4路只快了4%，退回3路并定下来
+        assert!(tuner.observe(&sample(260.0)));
+        assert_eq!(tuner.limit(), 3);
+        assert!(tuner.is_settled());
+        assert!(!tuner.observe(&sample(500.0)));
+        assert_eq!(tuner.limit(), 3);
+    }
+
+    #[test]
+    fn stops_at_max() {
+        let mut tuner = Tuner::probing(2);
+        assert!(!tuner.observe(&sample(100.0)));
+        assert!(tuner.observe(&sample(200.0)));
+        assert_eq!(tuner.limit(), 2);
+    }
+
+    #[test]
+    fn ignores_idle_periods() {
+        let mut tuner = Tuner::probing(8);
+        let idle = Sample { saturated: false, ..sample(10.0) };
+        assert!(!tuner.observe(&idle));
+        assert!(!tuner.observe(&Sample { requests: 0, ..sample(0.0) }));
+        assert_eq!(tuner.limit(), 1);
+        assert!(!tuner.is_settled());
+    }
+
+    #[test]
+    fn halves_on_high_error_rate() {
+        let mut tuner = Tuner::learned(6, 8);
+        let failing = Sample { errors: 8, ..sample(100.0) };
+        // 出错减下来的值不保存
+        assert!(!tuner.observe(&failing));
+        assert_eq!(tuner.limit(), 3);
+
+        // 请求太少时不看出错率
+        let few = Sample { requests: 2, errors: 2, ..sample(100.0) };
+        assert!(!tuner.observe(&few));
+        assert_eq!(tuner.limit(), 3);
+    }
+
+    #[test]
+    fn reprobes_after_errors_clear() {
+        let mut tuner = Tuner::learned(8, 8);
+        let failing = Sample { errors: 20, ..sample(0.0) };
+        for expected in [4, 2, 1] {
+            assert!(!tuner.observe(&failing));
+            assert_eq!(tuner.limit(), expected);
+        }
+
+        // 连续几个正常的周期以后重新往上探测，按吞吐量定下来的值才保存
+        for _ in 0..CLEAN_PERIODS_TO_REPROBE - 1 {
+            assert!(!tuner.observe(&sample(100.0)));
+            assert_eq!(tuner.limit(), 1);
+        }
+        assert!(!tuner.observe(&sample(100.0)));
+        assert!(!tuner.is_settled());
+        assert_eq!(tuner.limit(), 2);
+        assert!(tuner.observe(&sample(101.0)));
+        assert_eq!(tuner.limit(), 1);
+        assert!(tuner.is_settled());
+    }
+}
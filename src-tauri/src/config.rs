@@ -3,17 +3,44 @@
 //
 // 优先级：
 // 1. 环境变量 CAMFC_BASE 和 CAMFC_PORT
-// 2. 远程配置 https://me.011420.xyz/api/camfc/data.json
-// 3. 默认值 http://localhost:8005
+// 2. 设置里选中的后端配置（backend.active_profile，见backend_profiles.rs）
+// 3. 远程配置 https://me.011420.xyz/api/camfc/data.json
+// 4. 默认值 http://localhost:8005
+//
+// 运行中可以切换后端配置（switch_backend_profile），所以当前配置放在ArcSwapOption里，
+// 读的时候拿一份Arc，切换时整个换掉
 
-use std::sync::OnceLock;
-use serde::Deserialize;
+use std::sync::Arc;
+use arc_swap::ArcSwapOption;
+use serde::{Deserialize, Serialize};
 use anyhow::{Result, Context};
 
+use crate::settings::{self, BackendProfile};
+
+// 远程配置地址
+const REMOTE_CONFIG_URL: &str = "https://me.011420.xyz/api/camfc/data.json";
+
 // 远程配置响应结构
+// latest_version/download_url是后来加的，给检查更新用，旧的配置里没有
 #[derive(Debug, Deserialize)]
-struct RemoteConfig {
-    base_url: Vec<String>,
+pub struct RemoteConfig {
+    pub base_url: Vec<String>,
+    #[serde(default)]
+    pub latest_version: Option<String>,
+    #[serde(default)]
+    pub download_url: Option<String>,
+    #[serde(default)]
+    pub release_notes: Option<String>,
+}
+
+// 配置来源
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    Env,      // 环境变量
+    Profile,  // 用户选的后端配置
+    Remote,   // 远程配置
+    Default,  // 默认值
 }
 
 // 后端配置
@@ -21,6 +48,8 @@ struct RemoteConfig {
 pub struct BackendConfig {
     pub base_url: String,
     pub port: u16,
+    pub source: ConfigSource,
+    pub profile: Option<BackendProfile>,   // 来自后端配置时带上，HTTP客户端要用里面的代理和证书
 }
 
 impl BackendConfig {
@@ -31,9 +60,9 @@ impl BackendConfig {
 }
 
 // 全局配置实例
-static BACKEND_CONFIG: OnceLock<BackendConfig> = OnceLock::new();
+static BACKEND_CONFIG: ArcSwapOption<BackendConfig> = ArcSwapOption::const_empty();
 
-// 初始化配置
+// 初始化配置（要在settings::init_settings之后，选中的后端配置在设置里）
 pub async fn init_config() -> Result<()> {
     println!("开始初始化后端配置...");
     
@@ -45,38 +74,76 @@ pub async fn init_config() -> Result<()> {
         println!("检测环境变量指定的服务器是否可用...");
         if check_env_backend_available(&config).await {
             println!("环境变量指定的服务器可用");
-            BACKEND_CONFIG.set(config)
-                .map_err(|_| anyhow::anyhow!("配置已初始化"))?;
+            set_backend_config(config);
             return Ok(());
         } else {
             println!("环境变量指定的服务器不可用，继续尝试其他配置源...");
         }
     }
     
-    // 2. 环境变量不存在或不可用，尝试从远程 API 获取
+    // 2. 用户选了后端配置就直接用，不检测可用性（家里的后端在公司连不上，也要让用户知道连的是哪个）
+    let backend = settings::get().backend;
+    if let Some(profile) = backend.active_profile.as_deref().and_then(|name| backend.profile(name)) {
+        let config = from_profile(profile);
+        println!("使用后端配置 {}: {}", profile.name, config.get_full_url());
+        set_backend_config(config);
+        return Ok(());
+    }
+    
+    set_backend_config(resolve_automatic().await);
+    Ok(())
+}
+
+// 没选后端配置时：远程配置里第一个可用的地址，都不可用就用默认值
+pub async fn resolve_automatic() -> BackendConfig {
     println!("尝试从远程 API 获取配置...");
     match try_load_from_remote().await {
         Ok(config) => {
             println!("从远程 API 加载配置: {}", config.get_full_url());
-            BACKEND_CONFIG.set(config)
-                .map_err(|_| anyhow::anyhow!("配置已初始化"))?;
-            Ok(())
+            config
         }
         Err(e) => {
             println!("远程配置加载失败: {}，使用默认配置", e);
-            // 3. 远程获取失败，使用默认配置
             let default_config = BackendConfig {
                 base_url: "http://localhost".to_string(),
                 port: 8005,
+                source: ConfigSource::Default,
+                profile: None,
             };
             println!("使用默认配置: {}", default_config.get_full_url());
-            BACKEND_CONFIG.set(default_config)
-                .map_err(|_| anyhow::anyhow!("配置已初始化"))?;
-            Ok(())
+            default_config
         }
     }
 }
 
+// 后端配置对应的地址，地址里写了端口时以地址里的为准
+pub fn from_profile(profile: &BackendProfile) -> BackendConfig {
+    let base_url = profile.base_url.trim().trim_end_matches('/');
+    let (base_url, port) = match base_url.rsplit_once(':') {
+        Some((host, port)) if !port.starts_with("//") => match port.parse::<u16>() {
+            Ok(port) => (host.to_string(), port),
+            Err(_) => (base_url.to_string(), profile.port),
+        },
+        _ => (base_url.to_string(), profile.port),
+    };
+    let base_url = if base_url.starts_with("http://") || base_url.starts_with("https://") {
+        base_url
+    } else {
+        format!("http://{}", base_url)
+    };
+    BackendConfig {
+        base_url,
+        port,
+        source: ConfigSource::Profile,
+        profile: Some(profile.clone()),
+    }
+}
+
+// 换掉当前的后端配置，之后新发出的请求都用新的
+pub fn set_backend_config(config: BackendConfig) {
+    BACKEND_CONFIG.store(Some(Arc::new(config)));
+}
+
 // 检测环境变量指定的服务器是否可用
 async fn check_env_backend_available(config: &BackendConfig) -> bool {
     let test_url = format!("{}:{}/test", config.base_url, config.port);
@@ -142,6 +209,8 @@ fn try_load_from_env() -> Option<BackendConfig> {
     Some(BackendConfig {
         base_url,
         port,
+        source: ConfigSource::Env,
+        profile: None,
     })
 }
 
@@ -152,29 +221,7 @@ async fn try_load_from_remote() -> Result<BackendConfig> {
         .build()
         .context("创建HTTP客户端失败")?;
     
-    let url = "https://me.011420.xyz/api/camfc/data.json";
-    println!("请求远程配置: {}", url);
-    
-    let response = client
-        .get(url)
-        .send()
-        .await
-        .context("请求远程配置失败")?;
-    
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(anyhow::anyhow!(
-            "远程配置请求失败: {} - {}", 
-            status, 
-            error_text
-        ));
-    }
-    
-    let remote_config: RemoteConfig = response
-        .json()
-        .await
-        .context("解析远程配置失败")?;
+    let remote_config = fetch_remote_config(&client).await?;
     
     println!("远程配置解析成功，收到 {} 个候选地址", remote_config.base_url.len());
     
@@ -191,6 +238,8 @@ async fn try_load_from_remote() -> Result<BackendConfig> {
             return Ok(BackendConfig {
                 base_url,
                 port,
+                source: ConfigSource::Remote,
+                profile: None,
             });
         } else {
             println!("候选地址不可用: {}", candidate);
@@ -200,6 +249,32 @@ async fn try_load_from_remote() -> Result<BackendConfig> {
     Err(anyhow::anyhow!("所有候选地址都不可用"))
 }
 
+// 请求远程配置
+pub async fn fetch_remote_config(client: &reqwest::Client) -> Result<RemoteConfig> {
+    println!("请求远程配置: {}", REMOTE_CONFIG_URL);
+    
+    let response = client
+        .get(REMOTE_CONFIG_URL)
+        .send()
+        .await
+        .context("请求远程配置失败")?;
+    
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(anyhow::anyhow!(
+            "远程配置请求失败: {} - {}", 
+            status, 
+            error_text
+        ));
+    }
+    
+    response
+        .json()
+        .await
+        .context("解析远程配置失败")
+}
+
 // 解析后端 URL，返回 (base_url, port)
 fn parse_backend_url(url: &str) -> Result<(String, u16)> {
     let url = url.trim();
@@ -279,13 +354,50 @@ async fn check_backend_available(client: &reqwest::Client, backend_url: &str) ->
     }
 }
 
+// 测试时把后端指向模拟后端（mock_backend）
+#[cfg(test)]
+pub fn set_backend_for_test(base_url: &str, port: u16) {
+    set_backend_config(BackendConfig {
+        base_url: base_url.to_string(),
+        port,
+        source: ConfigSource::Env,
+        profile: None,
+    });
+}
+
 // 获取后端配置（必须在 init_config 之后调用）
-pub fn get_backend_config() -> Result<&'static BackendConfig> {
-    BACKEND_CONFIG.get()
+pub fn get_backend_config() -> Result<Arc<BackendConfig>> {
+    BACKEND_CONFIG.load_full()
         .ok_or_else(|| anyhow::anyhow!("后端配置未初始化，请先调用 init_config"))
 }
 
+// 当前后端配置里的代理和证书设置，不是来自后端配置时为None
+pub fn current_profile() -> Option<BackendProfile> {
+    BACKEND_CONFIG.load().as_ref().and_then(|config| config.profile.clone())
+}
+
 // 获取完整的后端 URL（便捷函数）
 pub fn get_backend_url() -> Result<String> {
     Ok(get_backend_config()?.get_full_url())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_url_may_carry_its_own_port() {
+        let profile = BackendProfile {
+            name: "office".to_string(),
+            base_url: "https://cloud.example.com:8443/".to_string(),
+            port: 8005,
+            ..Default::default()
+        };
+        let config = from_profile(&profile);
+        assert_eq!(config.get_full_url(), "https://cloud.example.com:8443");
+        assert_eq!(config.source, ConfigSource::Profile);
+
+        let profile = BackendProfile { base_url: "192.168.1.10".to_string(), port: 9000, ..profile };
+        assert_eq!(from_profile(&profile).get_full_url(), "http://192.168.1.10:9000");
+    }
+}
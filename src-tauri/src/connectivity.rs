@@ -0,0 +1,353 @@
+// 后端连通性检查
+// 创建传输任务前先快速检查一遍后端：带认证发一个HEAD请求，请求失败时再解析域名、建立TCP连接，
+// 看是哪一步出的问题，返回对应的诊断（dns_failed / refused / timeout / tls_error / auth_error / http_error）
+//
+// 思考：
+// 1. 原来后端不通时任务照样创建，然后每个分片重试CHUNK_ATTEMPTS次、任务再自动重试，
+//    用户等很久才看到一句"发送请求失败"，也不知道是网络、地址配错了还是认证不对。
+//    现在先检查，不通就直接返回错误，错误信息以[诊断]开头，前端可以按诊断显示处理建议
+// 2. 每一步单独超时，整个检查最多十几秒；成功的结果按后端地址缓存PROBE_TTL，
+//    批量创建任务时不用每个文件都检查一遍。失败的不缓存，用户修好后马上可以重试
+// 3. 认证检查用HEAD一个不存在的文件：认证不对后端返回401/403，认证通过返回404，都不用传数据
+// 4. 先发HEAD，后端正常时只有一次请求；HEAD失败了才单独解析域名、连TCP，用来判断是哪一步的问题。
+//    用了代理（后端配置里的proxy，或者reqwest也会读的HTTP_PROXY/HTTPS_PROXY/ALL_PROXY环境变量）时，
+//    域名解析和TCP连接都由代理做，本地直接连后端没有意义，只按HEAD的错误判断
+// 5. TLS错误reqwest没有单独的判断方法，只能沿着错误链看信息里有没有certificate/tls之类的字样
+// 6. 后端维护中（server_maintenance）返回的503不算不通：任务创建后会等到维护结束再传
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::auth::AuthInfo;
+use crate::transfer_http::{Endpoint, REQUEST_ID_HEADER, new_request_id};
+
+// 域名解析的超时时间
+const DNS_TIMEOUT: Duration = Duration::from_secs(3);
+// TCP连接的超时时间
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+// HEAD请求的超时时间
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// 检查成功后多久内不再检查
+const PROBE_TTL: Duration = Duration::from_secs(30);
+// 认证检查时HEAD的文件，不存在也没关系
+const PROBE_PATH: &str = ".camfc-probe";
+
+// 最近一次成功的检查：(后端地址, 时间)
+static LAST_OK: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Diagnosis {
+    Ok,
+    InvalidUrl,   // 后端地址格式不对
+    DnsFailed,    // 域名解析失败
+    Refused,      // 连接被拒绝（地址对，但端口上没有服务）
+    Timeout,      // 连接或请求超时
+    TlsError,     // TLS握手失败（证书不受信任、过期、域名不匹配）
+    AuthError,    // 后端拒绝了认证信息
+    HttpError,    // 其他网络错误或后端返回5xx
+}
+
+impl Diagnosis {
+    pub fn code(self) -> &'static str {
+        match self {
+            Diagnosis::Ok => "ok",
+            Diagnosis::InvalidUrl => "invalid_url",
+            Diagnosis::DnsFailed => "dns_failed",
+            Diagnosis::Refused => "refused",
+            Diagnosis::Timeout => "timeout",
+            Diagnosis::TlsError => "tls_error",
+            Diagnosis::AuthError => "auth_error",
+            Diagnosis::HttpError => "http_error",
+        }
+    }
+
+    // 给用户的处理建议
+    pub fn hint(self) -> Option<&'static str> {
+        match self {
+            Diagnosis::Ok => None,
+            Diagnosis::InvalidUrl => Some("后端地址格式不对，请检查CAMFC_BASE/CAMFC_PORT或后端配置"),
+            Diagnosis::DnsFailed => Some("无法解析后端域名，请检查网络连接和后端地址"),
+            Diagnosis::Refused => Some("后端拒绝连接，请确认后端服务已启动、端口配置正确"),
+            Diagnosis::Timeout => Some("连接后端超时，请检查网络、防火墙或代理设置"),
+            Diagnosis::TlsError => Some("TLS握手失败，请检查后端证书，或在后端配置里指定CA证书"),
+            Diagnosis::AuthError => Some("后端拒绝了认证信息，请确认笔已连接并且系统时间准确"),
+            Diagnosis::HttpError => Some("后端暂时无法正常响应，请稍后重试"),
+        }
+    }
+}
+
+// 检查结果
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeReport {
+    pub diagnosis: Diagnosis,
+    pub url: String,
+    pub addresses: Vec<String>,   // 域名解析出来的地址，用了代理时为空
+    pub status: Option<u16>,      // HEAD请求的状态码
+    pub elapsed_ms: u64,
+    pub message: String,
+    pub hint: Option<&'static str>,
+}
+
+impl ProbeReport {
+    pub fn is_ok(&self) -> bool {
+        self.diagnosis == Diagnosis::Ok
+    }
+}
+
+// 按HEAD请求的状态码判断；404、405说明后端在，认证也过了
+pub fn classify_status(status: u16) -> Diagnosis {
+    match status {
+        401 | 403 => Diagnosis::AuthError,
+        500..=599 => Diagnosis::HttpError,
+        _ => Diagnosis::Ok,
+    }
+}
+
+// 按TCP连接的错误判断
+pub fn classify_io_error(error: &std::io::Error) -> Diagnosis {
+    match error.kind() {
+        std::io::ErrorKind::ConnectionRefused => Diagnosis::Refused,
+        std::io::ErrorKind::TimedOut => Diagnosis::Timeout,
+        _ => Diagnosis::HttpError,
+    }
+}
+
+// 按reqwest的错误判断（见思考5）
+fn classify_request_error(error: &reqwest::Error) -> Diagnosis {
+    if error.is_timeout() {
+        return Diagnosis::Timeout;
+    }
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(e) = source {
+        let text = e.to_string().to_lowercase();
+        if ["certificate", "tls", "ssl", "handshake"].iter().any(|k| text.contains(k)) {
+            return Diagnosis::TlsError;
+        }
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            return classify_io_error(io);
+        }
+        source = e.source();
+    }
+    if error.is_connect() {
+        return Diagnosis::Refused;
+    }
+    Diagnosis::HttpError
+}
+
+struct Probe {
+    url: String,
+    started: Instant,
+    addresses: Vec<String>,
+    status: Option<u16>,
+}
+
+impl Probe {
+    fn finish(self, diagnosis: Diagnosis, message: String) -> ProbeReport {
+        println!("[连通性检查] {}: {} - {}", self.url, diagnosis.code(), message);
+        ProbeReport {
+            diagnosis,
+            url: self.url,
+            addresses: self.addresses,
+            status: self.status,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            message,
+            hint: diagnosis.hint(),
+        }
+    }
+}
+
+// HEAD返回503时后端是不是在维护（见思考6），带了Retry-After就顺便记下维护时间
+fn in_maintenance(response: &reqwest::Response) -> bool {
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| crate::server_maintenance::parse_retry_after(v, chrono::Utc::now().timestamp()));
+    if let Some(retry_after) = retry_after {
+        crate::server_maintenance::note_unavailable(retry_after);
+    }
+    crate::server_maintenance::resume_at().is_some()
+}
+
+// HEAD失败后直接解析域名、连TCP，哪一步不通就返回对应的诊断，都通了返回None（见思考4）
+async fn diagnose_network(probe: &mut Probe, host: &str, port: u16) -> Option<(Diagnosis, String)> {
+    let lookup = tokio::time::timeout(DNS_TIMEOUT, tokio::net::lookup_host((host, port))).await;
+    let addresses: Vec<std::net::SocketAddr> = match lookup {
+        Ok(Ok(addresses)) => addresses.collect(),
+        Ok(Err(e)) => return Some((Diagnosis::DnsFailed, format!("解析 {} 失败: {}", host, e))),
+        Err(_) => return Some((Diagnosis::DnsFailed, format!("解析 {} 超时", host))),
+    };
+    if addresses.is_empty() {
+        return Some((Diagnosis::DnsFailed, format!("{} 没有解析到地址", host)));
+    }
+    probe.addresses = addresses.iter().map(|a| a.to_string()).collect();
+
+    // 任意一个地址连上就行
+    let connect = tokio::time::timeout(CONNECT_TIMEOUT, tokio::net::TcpStream::connect(&addresses[..])).await;
+    match connect {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some((classify_io_error(&e), format!("连接 {}:{} 失败: {}", host, port, e))),
+        Err(_) => Some((Diagnosis::Timeout, format!("连接 {}:{} 超时", host, port))),
+    }
+}
+
+// 有没有走代理：后端配置里的proxy，或者代理环境变量（见思考4）
+fn uses_proxy(scheme: &str) -> bool {
+    let profile_proxy = crate::config::current_profile()
+        .and_then(|profile| profile.proxy)
+        .is_some_and(|proxy| !proxy.trim().is_empty());
+    let scheme_var = if scheme == "https" { "HTTPS_PROXY" } else { "HTTP_PROXY" };
+    let env_proxy = [scheme_var, "ALL_PROXY"].iter().any(|name| {
+        [name.to_string(), name.to_lowercase()]
+            .iter()
+            .any(|var| std::env::var(var).is_ok_and(|value| !value.trim().is_empty()))
+    });
+    profile_proxy || env_proxy
+}
+
+// 检查base_url，auth_info为None时只看后端在不在，不检查认证
+pub async fn probe_url(
+    client: &reqwest::Client,
+    base_url: &str,
+    auth_info: Option<&AuthInfo>,
+    via_proxy: bool,
+) -> ProbeReport {
+    let mut probe = Probe {
+        url: base_url.to_string(),
+        started: Instant::now(),
+        addresses: Vec::new(),
+        status: None,
+    };
+
+    let parsed = match reqwest::Url::parse(base_url) {
+        Ok(parsed) => parsed,
+        Err(e) => return probe.finish(Diagnosis::InvalidUrl, e.to_string()),
+    };
+    let (Some(host), Some(port)) = (parsed.host_str(), parsed.port_or_known_default()) else {
+        return probe.finish(Diagnosis::InvalidUrl, "缺少主机名或端口".to_string());
+    };
+
+    // 1. HEAD请求，带上认证信息
+    let url = format!("{}{}", base_url, Endpoint::Download(PROBE_PATH).path());
+    let mut request = client
+        .head(url)
+        .timeout(REQUEST_TIMEOUT)
+        .header(REQUEST_ID_HEADER, new_request_id());
+    if let Some(auth_info) = auth_info {
+        match auth_info.get_auth_header() {
+            Ok(headers) => request = request.headers(headers),
+            Err(e) => return probe.finish(Diagnosis::AuthError, format!("生成认证头失败: {}", e)),
+        }
+    }
+    let error = match request.send().await {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            probe.status = Some(status);
+            let diagnosis = match (classify_status(status), auth_info) {
+                // 没带认证信息时401也说明后端在
+                (Diagnosis::AuthError, None) => Diagnosis::Ok,
+                (Diagnosis::HttpError, _) if status == 503 && in_maintenance(&response) => Diagnosis::Ok,
+                (diagnosis, _) => diagnosis,
+            };
+            return probe.finish(diagnosis, format!("后端返回 {}", response.status()));
+        }
+        Err(e) => e,
+    };
+
+    // 2. HEAD失败了，直连时再看域名解析和TCP连接哪一步不通
+    if !via_proxy {
+        if let Some((diagnosis, message)) = diagnose_network(&mut probe, host, port).await {
+            return probe.finish(diagnosis, message);
+        }
+    }
+    let diagnosis = classify_request_error(&error);
+    probe.finish(diagnosis, format!("请求失败: {:#}", anyhow::Error::from(error)))
+}
+
+// 检查当前后端
+pub async fn probe(auth_info: Option<&AuthInfo>) -> ProbeReport {
+    let base_url = match crate::config::get_backend_url() {
+        Ok(base_url) => base_url,
+        Err(e) => {
+            let probe = Probe { url: String::new(), started: Instant::now(), addresses: Vec::new(), status: None };
+            return probe.finish(Diagnosis::InvalidUrl, format!("{:#}", e));
+        }
+    };
+    let client = match crate::http_client::shared_client() {
+        Ok(client) => client,
+        Err(e) => {
+            let probe = Probe { url: base_url, started: Instant::now(), addresses: Vec::new(), status: None };
+            return probe.finish(Diagnosis::HttpError, format!("{:#}", e));
+        }
+    };
+    let via_proxy = reqwest::Url::parse(&base_url).is_ok_and(|url| uses_proxy(url.scheme()));
+
+    let report = probe_url(&client, &base_url, auth_info, via_proxy).await;
+    if report.is_ok() {
+        *LAST_OK.lock().unwrap() = Some((base_url, Instant::now()));
+    }
+    report
+}
+
+// 创建任务前调用：最近检查过就跳过（见思考2），不通时返回"[诊断] 信息，建议"
+pub async fn ensure_reachable(auth_info: &AuthInfo) -> Result<(), String> {
+    if let (Ok(base_url), Some((cached_url, at))) = (crate::config::get_backend_url(), LAST_OK.lock().unwrap().clone()) {
+        if cached_url == base_url && at.elapsed() < PROBE_TTL {
+            return Ok(());
+        }
+    }
+
+    let report = probe(Some(auth_info)).await;
+    if report.is_ok() {
+        return Ok(());
+    }
+    Err(format!(
+        "[{}] {}，{}",
+        report.diagnosis.code(),
+        report.message,
+        report.hint.unwrap_or_default(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_backend;
+
+    #[test]
+    fn classifies_status_codes() {
+        assert_eq!(classify_status(404), Diagnosis::Ok);
+        assert_eq!(classify_status(200), Diagnosis::Ok);
+        assert_eq!(classify_status(401), Diagnosis::AuthError);
+        assert_eq!(classify_status(403), Diagnosis::AuthError);
+        assert_eq!(classify_status(503), Diagnosis::HttpError);
+    }
+
+    #[tokio::test]
+    async fn diagnoses_backend_problems() {
+        let base_url = mock_backend::start();
+        let client = reqwest::Client::new();
+
+        let report = probe_url(&client, &base_url, Some(&mock_backend::valid_auth()), false).await;
+        assert_eq!(report.diagnosis, Diagnosis::Ok);
+        assert_eq!(report.status, Some(404));
+
+        let report = probe_url(&client, &base_url, Some(&mock_backend::invalid_auth()), false).await;
+        assert_eq!(report.diagnosis, Diagnosis::AuthError);
+
+        // 找一个没人监听的端口
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let report = probe_url(&client, &format!("http://127.0.0.1:{}", port), None, false).await;
+        assert_eq!(report.diagnosis, Diagnosis::Refused);
+
+        let report = probe_url(&client, "http://camfc-probe.invalid:8005", None, false).await;
+        assert_eq!(report.diagnosis, Diagnosis::DnsFailed);
+
+        let report = probe_url(&client, "not a url", None, false).await;
+        assert_eq!(report.diagnosis, Diagnosis::InvalidUrl);
+    }
+}
@@ -0,0 +1,88 @@
+// 上传文件的MIME类型检测
+// 上传时把文件类型告诉后端（init和finish都带content_type），后端存下来，
+// 网页预览和浏览器下载时才能给出正确的Content-Type
+//
+// 思考：
+// 1. 先按扩展名猜（mime_guess），大多数文件这样就够了，不用读文件
+// 2. 扩展名靠不住的（没有扩展名、.bin/.dat/.tmp这种什么都能装的、.ts既可能是TypeScript也可能是视频流）
+//    读开头SNIFF_LEN字节按文件头识别（infer）；认不出来时看内容是不是文本，都不是就是application/octet-stream
+// 3. 文件头识别只在扩展名靠不住时用：扩展名是.docx而文件头是zip时，应该是docx而不是zip
+
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+// 识别文件头时读多少字节
+const SNIFF_LEN: usize = 8192;
+// 认不出来时的类型
+pub const FALLBACK: &str = "application/octet-stream";
+
+// 扩展名不能说明内容的（见思考2）
+const AMBIGUOUS_EXTENSIONS: &[&str] = &["bin", "dat", "tmp", "ts", "part", "download"];
+
+// 扩展名能确定类型时返回
+fn guess_from_name(file_name: &str) -> Option<String> {
+    let extension = Path::new(file_name).extension()?.to_str()?.to_ascii_lowercase();
+    if AMBIGUOUS_EXTENSIONS.contains(&extension.as_str()) {
+        return None;
+    }
+    mime_guess::from_ext(&extension)
+        .first()
+        .map(|mime| mime.essence_str().to_string())
+        .filter(|mime| mime != FALLBACK)
+}
+
+// 没有NUL、是合法UTF-8的当作文本（截断处可能切在一个字符中间，不算错）
+fn looks_like_text(head: &[u8]) -> bool {
+    if head.is_empty() || head.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none() && head.len() - e.valid_up_to() < 4,
+    }
+}
+
+// 按文件名和文件开头的内容判断类型
+pub fn detect(file_name: &str, head: &[u8]) -> String {
+    if let Some(mime) = guess_from_name(file_name) {
+        return mime;
+    }
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type().to_string();
+    }
+    if looks_like_text(head) {
+        return "text/plain".to_string();
+    }
+    FALLBACK.to_string()
+}
+
+// 检测本地文件的类型，读不了文件时只按文件名判断
+pub async fn detect_file(path: &Path) -> String {
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    if let Some(mime) = guess_from_name(file_name) {
+        return mime;
+    }
+
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    if let Ok(file) = tokio::fs::File::open(path).await {
+        let _ = file.take(SNIFF_LEN as u64).read_to_end(&mut head).await;
+    }
+    detect(file_name, &head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_by_extension_then_magic_bytes() {
+        assert_eq!(detect("photo.JPG", b""), "image/jpeg");
+        assert_eq!(detect("report.pdf", b"not really a pdf"), "application/pdf");
+        // 扩展名靠不住时看文件头
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(detect("image.bin", png), "image/png");
+        assert_eq!(detect("README", b"# CAMFC\n"), "text/plain");
+        assert_eq!(detect("中文说明", "你好".as_bytes()), "text/plain");
+        assert_eq!(detect("blob.dat", &[0, 1, 2, 3]), FALLBACK);
+    }
+}
@@ -1,19 +1,32 @@
 //! Cpen设备管理器
 //!
 //! 这个模块负责处理Cpen蓝牙设备的完整业务逻辑：
-//! 1. 扫描蓝牙设备并识别Cpen设备（根据名前缀）
+//! 1. 扫描蓝牙设备并识别Cpen设备（按设置里的识别规则，见device_match）
 //! 2. 保证全局只连接一个Cpen设备（重要要求！）
 //! 3. 自动处理连接、断开、重连
 //! 4. 实现TOTP缓存（30秒有效）
 //! 5. 管理设备ID缓存
+//! 6. 有传输在进行时定时给笔发保活包（见open_session）
+//! 7. 连上后问一次协议版本，新功能按版本开关（见pen_protocol）
+//! 8. 断开、清理、退出时取消正在进行的蓝牙操作（见cancel_operations）
 //!
 //! 思考：为啥要单独搞这个模块？
 //! 计划业务逻辑全在Rust，前端只调简单接口。这样前端代码能大幅简化。
 //! 另外，保证单设备连接也是用户明确要求的。
 
-use std::time::{SystemTime, Duration};
-use crate::bluetooth::{BluetoothManager, DeviceInfo};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, Duration, Instant};
+use crate::{ble_stats, clock_drift, device_match};
+use crate::bluetooth::{AdapterInfo, BluetoothManager, DeviceInfo};
+use crate::command_deadline::{self, CommandError, ProgressStage};
+use crate::auth::AuthChallenge;
+use crate::pen_protocol::{self, PenFeature, PenProtocol};
+use crate::metrics::{self, Counter};
+use crate::{settings, supervisor};
+use crate::settings::GattUuids;
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
 use totp_rs::{TOTP, Secret};
 
 // 错误类型别名，简单点就用String
@@ -23,6 +36,98 @@ type CpenError = String;
 const TOTP_CACHE_DURATION_SECONDS: u64 = 30;
 const SCAN_DURATION_MS: u64 = 5000; // 扫描3秒
 
+// 等getProtoVersion回复多久，老固件不回复
+const PROTO_VERSION_TIMEOUT: Duration = Duration::from_millis(800);
+// 等TOTP时最多跳过几个不像TOTP的回复（之前命令晚到的回复）
+const MAX_STALE_REPLIES: u32 = 2;
+
+// 保活包的超时，和保活间隔无关，只是别让一次没回应的GATT读把设备管理器占太久
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_millis(500);
+// 保活关闭时隔多久再看一次设置
+const KEEP_ALIVE_IDLE_CHECK: Duration = Duration::from_secs(1);
+
+// 连接状态快照放在device_state里，没有蓝牙功能时也能读
+pub use crate::device_state::{connection_snapshot, set_adapter_powered};
+
+// 打开着的逻辑会话数和保活任务是否在运行
+static OPEN_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+static KEEP_ALIVE_RUNNING: AtomicBool = AtomicBool::new(false);
+
+// 当前蓝牙操作的取消令牌
+// 放在设备管理器外面：扫描、连接、接收期间设备管理器的锁一直被拿着，
+// 断开命令要先在锁外面取消，正在进行的操作才会马上放弃并释放锁
+static OPERATION_CANCEL: OnceLock<Mutex<CancellationToken>> = OnceLock::new();
+
+fn operation_cancel() -> &'static Mutex<CancellationToken> {
+    OPERATION_CANCEL.get_or_init(|| Mutex::new(CancellationToken::new()))
+}
+
+/// 取消正在进行的蓝牙操作（扫描、连接、收发）
+/// 
+/// 断开、清理、退出应用和蓝牙失效时，在拿设备管理器的锁之前调用。
+/// 被取消的操作返回bluetooth::CANCELLED，不再重试；下一个操作开始时换一个新的令牌
+pub fn cancel_operations() {
+    let token = operation_cancel().lock().unwrap_or_else(|e| e.into_inner());
+    if !token.is_cancelled() {
+        println!("[CPEN] 取消正在进行的蓝牙操作");
+        token.cancel();
+    }
+}
+
+/// 逻辑会话
+/// 
+/// 笔的固件在连接空闲大约1.5秒后就会睡眠断开，传输过程中隔一会儿才取一次TOTP，
+/// 每次都要重新扫描连接。传输任务运行期间持有一个会话，期间每隔bluetooth.keep_alive_ms
+/// 给笔发一个保活包；所有会话都结束（没有待处理的操作）后保活任务自动退出。
+pub struct BleSession;
+
+/// 打开一个逻辑会话，drop时关闭
+pub fn open_session() -> BleSession {
+    OPEN_SESSIONS.fetch_add(1, Ordering::SeqCst);
+    if KEEP_ALIVE_RUNNING.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+        supervisor::spawn("ble_keep_alive", keep_alive_loop());
+    }
+    BleSession
+}
+
+impl Drop for BleSession {
+    fn drop(&mut self) {
+        OPEN_SESSIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn keep_alive_loop() {
+    println!("[CPEN] 保活已开始");
+    loop {
+        let interval_ms = settings::get().bluetooth.keep_alive_ms;
+        sleep(if interval_ms == 0 { KEEP_ALIVE_IDLE_CHECK } else { Duration::from_millis(interval_ms) }).await;
+
+        if OPEN_SESSIONS.load(Ordering::SeqCst) == 0 {
+            KEEP_ALIVE_RUNNING.store(false, Ordering::SeqCst);
+            // 停下的同时又有会话打开了，而且它没能启动新的保活任务，就接着跑
+            if OPEN_SESSIONS.load(Ordering::SeqCst) == 0 || KEEP_ALIVE_RUNNING.swap(true, Ordering::SeqCst) {
+                break;
+            }
+        }
+        if interval_ms > 0 {
+            send_keep_alive().await;
+        }
+    }
+    println!("[CPEN] 没有进行中的操作，保活已停止");
+}
+
+// 发一次保活包，没连着或者设备管理器正在用蓝牙（连接本来就不空闲）时跳过
+async fn send_keep_alive() {
+    if !connection_snapshot().is_connected() {
+        return;
+    }
+    let Ok(manager) = crate::get_cpen_device_manager() else { return };
+    let Ok(manager) = manager.try_lock() else { return };
+    if let Err(e) = manager.keep_alive().await {
+        println!("[CPEN] 保活包发送失败: {}", e);
+    }
+}
+
 /// Cpen设备管理器
 /// 
 /// 核心设计：保证全局只连接一个Cpen设备！
@@ -45,9 +150,17 @@ pub struct CpenDeviceManager {
     /// 设备ID缓存（设备UUID）
     device_id_cache: Option<String>,
     
-    /// 连接状态标记，用来给前端返回状态信息
-    /// 简化：就用字符串表示状态吧
-    connection_status: String,
+    /// 连上后问到的协议版本，None表示还没问过
+    protocol: Option<PenProtocol>,
+    
+    /// 这次连接上次setTime成功的时间，None表示这次连接还没同步过
+    last_time_sync: Option<Instant>,
+    
+    /// 当前操作的取消令牌，每个对外的操作开始时从OPERATION_CANCEL取（见begin_operation）
+    cancel: CancellationToken,
+    
+    // 连接状态（disconnected/connecting/connected）不放在这里，
+    // 通过set_status写到连接状态快照里，前端读快照不用等锁
 }
 
 impl CpenDeviceManager {
@@ -59,8 +172,22 @@ impl CpenDeviceManager {
             current_device: None,
             totp_cache: None,
             device_id_cache: None,
-            connection_status: "disconnected".to_string(),
+            protocol: None,
+            last_time_sync: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+    
+    /// 对外的操作开始时调用，拿到这次操作的取消令牌
+    /// 
+    /// 上一个令牌已经被取消（上次的操作被断开打断了）时换一个新的，这次操作照常进行。
+    /// 只在对外的方法开头调用，内部互相调用时不调用，否则被取消的操作会拿到新令牌接着跑
+    fn begin_operation(&mut self) {
+        let mut token = operation_cancel().lock().unwrap_or_else(|e| e.into_inner());
+        if token.is_cancelled() {
+            *token = CancellationToken::new();
         }
+        self.cancel = token.clone();
     }
 
     /// 检查是否DEBUG模式
@@ -84,6 +211,7 @@ impl CpenDeviceManager {
         if id.is_empty() || key.is_empty() {
             return None;
         }
+        crate::auth::remember_secret(&key);
         Some((id, key))
     }
 
@@ -111,6 +239,18 @@ impl CpenDeviceManager {
         Ok(totp.generate(timestamp))
     }
     
+    /// 当前连接的笔用哪个服务和特性收发命令
+    /// 
+    /// 默认是设置里的bluetooth.gatt，改过固件的开发板可以在bluetooth.gatt_overrides里按地址单独指定
+    fn gatt(&self) -> GattUuids {
+        settings::get().bluetooth.gatt_for(self.connected_address.as_deref())
+    }
+    
+    /// 修改连接状态，写入快照
+    fn set_status(&self, status: &str) {
+        crate::device_state::set_connection(status, self.current_device.clone());
+    }
+    
     /// 彻底清理连接状态
     /// 
     /// 当检测到连接已断开或需要重新连接时调用
@@ -120,7 +260,9 @@ impl CpenDeviceManager {
         self.current_device = None;
         self.totp_cache = None;
         self.device_id_cache = None;
-        self.connection_status = "disconnected".to_string();
+        self.protocol = None;
+        self.last_time_sync = None;
+        self.set_status("disconnected");
         println!("[CPEN] 连接状态已彻底清理");
     }
     
@@ -128,7 +270,7 @@ impl CpenDeviceManager {
     /// 
     /// 这个函数实现了完整的连接逻辑：
     /// 1. 在一切最开始，检查蓝牙是否开启，如果没开就尝试开启
-    /// 2. 如果已经连接了设备，直接返回成功（复用连接）
+    /// 2. 如果已经连接了设备并且连接检查通过（见connection_alive），直接返回成功（复用连接）
     /// 3. 如果没有连接，扫描设备
     /// 4. 从扫描结果中找出Cpen设备
     /// 5. 如果有多个Cpen设备，只连接第一个（单设备保证）
@@ -136,6 +278,12 @@ impl CpenDeviceManager {
     /// 
     /// 改进：检测到连接断开时彻底清理状态
     pub async fn ensure_connected(&mut self) -> Result<(), CpenError> {
+        self.begin_operation();
+        self.connect_if_needed().await
+    }
+    
+    /// ensure_connected的实现，内部调用时用这个（见begin_operation）
+    async fn connect_if_needed(&mut self) -> Result<(), CpenError> {
         println!("[CPEN] 开始Cpen设备连接流程...");
         
         // 检查蓝牙状态
@@ -163,12 +311,13 @@ impl CpenDeviceManager {
         
         println!("[CPEN] === 蓝牙状态检查完成 ===");
         
-        // 检查是否已经连接
+        // 检查是否已经连接，之前连着但已经断开的，这次连接算重连
+        let mut reconnecting = false;
         if self.connected_address.is_some() {
             // 检查连接是否真的还活着
-            match self.bluetooth_manager.is_connected().await {
+            match self.connection_alive().await {
                 Ok(true) => {
-                    self.connection_status = "connected".to_string();
+                    self.set_status("connected");
                     println!("[CPEN] 已经连接到设备，连接状态正常，直接复用连接");
                     return Ok(());
                 }
@@ -176,23 +325,31 @@ impl CpenDeviceManager {
                     println!("[CPEN] 之前记录的连接已断开，清理状态后重新连接");
                     // 彻底清理状态
                     self.cleanup_connection_state();
+                    reconnecting = true;
                 }
                 Err(e) => {
                     println!("[CPEN] 检查连接状态失败: {}，清理状态后重新连接", e);
                     // 检查失败，彻底清理状态后重新连接
                     self.cleanup_connection_state();
+                    reconnecting = true;
                 }
             }
         }
         
         // 更新状态为连接中
-        self.connection_status = "connecting".to_string();
+        self.set_status("connecting");
         println!("[CPEN] 开始扫描并连接Cpen设备...");
         
         // 扫描设备
         println!("[CPEN] 开始扫描蓝牙设备（蓝牙状态已确认）...");
-        let devices = self.bluetooth_manager.scan_devices(SCAN_DURATION_MS).await
-            .map_err(|e| format!("扫描设备失败: {}", e))?;
+        command_deadline::report(ProgressStage::Scanning);
+        let devices = match self.bluetooth_manager.scan_devices(SCAN_DURATION_MS, &self.cancel).await {
+            Ok(devices) => devices,
+            Err(e) => {
+                self.set_status("disconnected");
+                return Err(format!("扫描设备失败: {}", e));
+            }
+        };
         
         println!("[CPEN] 扫描完成，发现 {} 个设备", devices.len());
         
@@ -200,8 +357,9 @@ impl CpenDeviceManager {
         let cpen_devices = Self::filter_cpen_devices(&devices);
         
         if cpen_devices.is_empty() {
-            self.connection_status = "disconnected".to_string();
-            return Err("没有找到Cpen设备（设备名需以'Cpen'开头）".to_string());
+            self.set_status("disconnected");
+            let rules = settings::get().bluetooth.device_match;
+            return Err(format!("没有找到Cpen设备（{}）", device_match::describe(&rules)));
         }
         
         println!("[CPEN] 找到 {} 个Cpen设备，连接第一个", cpen_devices.len());
@@ -218,82 +376,115 @@ impl CpenDeviceManager {
         }
         
         // 连接设备（bluetooth_manager.connect 已有重试机制）
-        self.bluetooth_manager.connect(&target_device.address).await
-            .map_err(|e| format!("连接设备失败: {}", e))?;
+        command_deadline::report(ProgressStage::Connecting);
+        if let Err(e) = self.bluetooth_manager.connect(&target_device.address, &self.cancel).await {
+            self.set_status("disconnected");
+            if !self.cancel.is_cancelled() {
+                metrics::record(Counter::BleConnectFailures);
+            }
+            return Err(format!("连接设备失败: {}", e));
+        }
+        metrics::record(if reconnecting { Counter::BleReconnects } else { Counter::BleConnects });
+        ble_stats::start_session(reconnecting);
         
         // 记录连接状态
         self.connected_address = Some(target_device.address.clone());
         self.current_device = Some(target_device.clone());
-        self.connection_status = "connected".to_string();
+        self.set_status("connected");
         
         println!("[CPEN] 成功连接到Cpen设备: {} ({})", 
                  target_device.name, target_device.address);
         
         // 连接后等待一小会儿，让设备稳定
         sleep(Duration::from_millis(500)).await;
+        self.handshake().await;
         
         println!("[CPEN] 设备连接成功，TOTP刷新策略已启用（提前5秒刷新）");
         
         Ok(())
     }
     
+    /// 复用连接前检查连接是否真的还活着
+    /// 
+    /// btleplug的is_connected在有些蓝牙栈上会一直报告过期的true，
+    /// 开启了bluetooth.health_check时再做一次GATT ping（见BluetoothManager::ping），
+    /// 没回应就当已断开，重新连接，免得拿着死连接发认证命令等到接收超时
+    async fn connection_alive(&mut self) -> Result<bool, CpenError> {
+        if !self.bluetooth_manager.is_connected().await? {
+            return Ok(false);
+        }
+        let config = settings::get().bluetooth;
+        if !config.health_check {
+            return Ok(true);
+        }
+        let limit = Duration::from_millis(config.health_check_timeout_ms);
+        let gatt = config.gatt_for(self.connected_address.as_deref());
+        match self.bluetooth_manager.ping(&gatt.service_uuid, &gatt.char_uuid, limit).await {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                println!("[CPEN] 连接检查没有通过: {}，当作已断开", e);
+                Ok(false)
+            }
+        }
+    }
+    
     /// 过滤出Cpen设备
     /// 
-    /// 根据设备名前缀判断是否为Cpen设备。
-    /// 原JavaScript代码检查前4个字符是否为'cpen'（不区分大小写）。
-    /// 这里需要正确处理UTF-8字符串，使用字符迭代而不是字节切片。
+    /// 按设置里的识别规则判断（默认是设备名以Cpen开头，不区分大小写），见device_match。
     fn filter_cpen_devices(devices: &[DeviceInfo]) -> Vec<DeviceInfo> {
-        let mut cpen_devices = Vec::new();
-        
-        for device in devices {
-            // 先检查设备名长度是否足够
-            if device.name.chars().count() >= 4 {
-                // 获取前4个字符并转换为小写进行比较
-                let prefix: String = device.name.chars().take(4).collect();
-                if prefix.to_lowercase() == "cpen" {
-                    cpen_devices.push(device.clone());
-                    println!("识别为Cpen设备: {} - {}", device.name, device.address);
-                }
-            }
+        let cpen_devices = device_match::filter(devices);
+        for device in &cpen_devices {
+            println!("识别为Cpen设备: {} - {}", device.name, device.address);
         }
-        
         cpen_devices
     }
     
-    /// 扫描并返回所有Cpen设备列表（不自动连接）
-    /// 
-    /// 这个方法会：
-    /// 1. 确保蓝牙已开启
-    /// 2. 扫描蓝牙设备
-    /// 3. 过滤出所有Cpen设备（不连接）
+    /// 确保蓝牙已开启
     /// 
-    /// 返回：所有发现的Cpen设备列表
-    pub async fn scan_cpen_devices(&mut self) -> Result<Vec<DeviceInfo>, CpenError> {
-        println!("开始扫描Cpen设备列表...");
-        
-        // 1. 确保蓝牙已开启
+    /// 先用Windows Radio API检查并尝试开启，失败了再用btleplug检测一下适配器是否可用。
+    /// 首次使用引导和扫描设备都会先调用这个。
+    pub async fn ensure_bluetooth_enabled(&mut self) -> Result<(), CpenError> {
         match self.bluetooth_manager.enable_bluetooth() {
             Ok(_) => {
                 println!("✅ 蓝牙状态检查通过（Windows API）");
+                Ok(())
             }
             Err(e) => {
                 println!("⚠️ Windows蓝牙API检查失败，尝试用btleplug检测: {}", e);
                 match self.bluetooth_manager.check_bluetooth_via_btleplug().await {
                     Ok(_) => {
                         println!("✅ 蓝牙状态检查通过（btleplug fallback）");
+                        Ok(())
                     }
                     Err(btleplug_err) => {
                         let err_msg = format!("蓝牙检测失败: {}, {}", e, btleplug_err);
                         println!("❌ {}", err_msg);
-                        return Err(err_msg);
+                        Err(err_msg)
                     }
                 }
             }
         }
+    }
+    
+    /// 扫描并返回所有Cpen设备列表（不自动连接）
+    /// 
+    /// 这个方法会：
+    /// 1. 确保蓝牙已开启
+    /// 2. 扫描蓝牙设备
+    /// 3. 过滤出所有Cpen设备（不连接）
+    /// 
+    /// 返回：所有发现的Cpen设备列表
+    pub async fn scan_cpen_devices(&mut self) -> Result<Vec<DeviceInfo>, CpenError> {
+        println!("开始扫描Cpen设备列表...");
+        self.begin_operation();
+        
+        // 1. 确保蓝牙已开启
+        self.ensure_bluetooth_enabled().await?;
         
         // 2. 扫描设备
         println!("开始扫描蓝牙设备...");
-        let devices = self.bluetooth_manager.scan_devices(SCAN_DURATION_MS).await
+        command_deadline::report(ProgressStage::Scanning);
+        let devices = self.bluetooth_manager.scan_devices(SCAN_DURATION_MS, &self.cancel).await
             .map_err(|e| format!("扫描设备失败: {}", e))?;
         
         println!("扫描完成，发现 {} 个设备", devices.len());
@@ -321,6 +512,7 @@ impl CpenDeviceManager {
     /// 参数：设备地址（Bluetooth address）
     pub async fn connect_to_device(&mut self, address: &str) -> Result<DeviceInfo, CpenError> {
         println!("开始连接到指定Cpen设备: {}", address);
+        self.begin_operation();
         
         // 1. 如果已经连接，先断开
         if self.connected_address.is_some() {
@@ -328,14 +520,24 @@ impl CpenDeviceManager {
             let _ = self.bluetooth_manager.disconnect().await;
             self.connected_address = None;
             self.current_device = None;
+            self.protocol = None;
+            self.last_time_sync = None;
         }
         
         // 2. 更新状态
-        self.connection_status = "connecting".to_string();
+        self.set_status("connecting");
         
         // 3. 连接到指定设备
-        self.bluetooth_manager.connect(address).await
-            .map_err(|e| format!("连接设备失败: {}", e))?;
+        command_deadline::report(ProgressStage::Connecting);
+        if let Err(e) = self.bluetooth_manager.connect(address, &self.cancel).await {
+            self.set_status("disconnected");
+            if !self.cancel.is_cancelled() {
+                metrics::record(Counter::BleConnectFailures);
+            }
+            return Err(format!("连接设备失败: {}", e));
+        }
+        metrics::record(Counter::BleConnects);
+        ble_stats::start_session(false);
         
         // 4. 获取设备信息（需要从扫描结果中获取，或者重新扫描）
         // 这里简化处理：使用地址作为设备名
@@ -348,12 +550,13 @@ impl CpenDeviceManager {
         // 5. 记录连接状态
         self.connected_address = Some(address.to_string());
         self.current_device = Some(device_info.clone());
-        self.connection_status = "connected".to_string();
+        self.set_status("connected");
         
         println!("成功连接到Cpen设备: {} ({})", device_info.name, address);
         
-        // 6. 连接后等待一小会儿
+        // 6. 连接后等待一小会儿，然后问协议版本
         sleep(Duration::from_millis(500)).await;
+        self.handshake().await;
         
         Ok(device_info)
     }
@@ -433,6 +636,7 @@ impl CpenDeviceManager {
             if let Some((_, key)) = Self::get_debug_config() {
                 match Self::generate_totp_locally(&key) {
                     Ok(totp) => {
+                        crate::auth::remember_secret(&totp);
                         println!("[CPEN] DEBUG模式TOTP生成成功: {}", totp);
                         println!("[CPEN] ===== TOTP获取结束（DEBUG模式） =====");
                         return Ok(totp);
@@ -447,6 +651,8 @@ impl CpenDeviceManager {
             }
         }
         
+        self.begin_operation();
+        
         // 检查是否需要刷新TOTP
         let need_refresh = self.should_refresh_totp();
         
@@ -467,17 +673,35 @@ impl CpenDeviceManager {
             println!("[CPEN] TOTP刷新触发：没有缓存");
         }
         
+        self.fetch_totp().await.map(|(totp, _)| totp)
+    }
+    
+    /// 获取TOTP和这次的挑战应答（传输认证用）
+    /// 
+    /// 开启挑战应答时随机数只能用一次，所以不看TOTP缓存，每次都setTime带新随机数再getTotp。
+    /// 没开启或者笔不支持挑战应答时挑战应答为None
+    pub async fn get_totp_with_challenge(&mut self) -> Result<(String, Option<AuthChallenge>), CpenError> {
+        if Self::is_debug_mode() {
+            return self.get_totp().await.map(|totp| (totp, None));
+        }
+        self.begin_operation();
+        self.fetch_totp().await
+    }
+    
+    /// 问笔要TOTP，带重试（内部方法）
+    async fn fetch_totp(&mut self) -> Result<(String, Option<AuthChallenge>), CpenError> {
         // 添加重试机制
         const MAX_RETRIES: u32 = 2;
         for attempt in 1..=MAX_RETRIES {
             println!("[CPEN] TOTP获取尝试 {}/{}", attempt, MAX_RETRIES);
             
             match self.get_totp_once().await {
-                Ok(totp) => {
+                Ok(result) => {
                     println!("[CPEN] ===== TOTP获取成功 =====");
-                    return Ok(totp);
+                    return Ok(result);
                 }
-                Err(e) if attempt < MAX_RETRIES => {
+                // 被断开打断的不重试
+                Err(e) if attempt < MAX_RETRIES && !self.cancel.is_cancelled() => {
                     println!("[CPEN] TOTP获取失败: {}，清理状态后重试", e);
                     // 清理状态后重试
                     self.cleanup_connection_state();
@@ -493,41 +717,124 @@ impl CpenDeviceManager {
         Err("获取TOTP重试次数用尽".to_string())
     }
     
-    /// 单次TOTP获取尝试（内部方法）
-    async fn get_totp_once(&mut self) -> Result<String, CpenError> {
+    /// 单次TOTP获取尝试（内部方法），返回TOTP和挑战应答
+    async fn get_totp_once(&mut self) -> Result<(String, Option<AuthChallenge>), CpenError> {
         // 检查是否已有连接
         let was_already_connected = self.connected_address.is_some();
         
         if was_already_connected {
             println!("[CPEN] 复用现有蓝牙连接");
-            match self.bluetooth_manager.is_connected().await {
+            match self.connection_alive().await {
                 Ok(true) => {
                     println!("[CPEN] 现有连接状态正常");
-                    self.connection_status = "connected".to_string();
+                    self.set_status("connected");
                 }
                 _ => {
                     println!("[CPEN] 现有连接已断开，重新连接");
-                    self.ensure_connected().await?;
+                    self.connect_if_needed().await?;
                 }
             }
         } else {
             println!("[CPEN] 没有现有连接，开始连接设备");
-            self.ensure_connected().await?;
+            self.connect_if_needed().await?;
+        }
+        
+        // 发送setTime命令（设备时间刚同步过时跳过，见time_sync_due）
+        command_deadline::report(ProgressStage::Authenticating);
+        let nonce = self.challenge_nonce();
+        // 挑战应答每次都要带新的随机数，不能跳过；时钟偏差补偿变了也要马上重发
+        if nonce.is_some() || self.time_sync_due() || clock_drift::take_resync() {
+            self.send_set_time(nonce.as_deref()).await?;
+            self.last_time_sync = Some(Instant::now());
+        } else {
+            println!("[CPEN] 设备时间刚同步过，跳过setTime");
         }
         
-        // 发送setTime命令
-        let timestamp = chrono::Utc::now().timestamp().to_string();
-        let set_time_command = format!("setTime:{}", timestamp);
+        let gatt = self.gatt();
+        let service_uuid = gatt.service_uuid.as_str();
+        let char_uuid = gatt.char_uuid.as_str();
+        
+        // 发送getTotp命令
+        println!("[CPEN] 发送getTotp命令");
+        let sent_at = Instant::now();
+        self.bluetooth_manager.send(
+            service_uuid, 
+            char_uuid, 
+            b"getTotp",
+            &self.cancel,
+        ).await
+        .map_err(|e| format!("发送getTotp命令失败: {}", e))?;
+        
+        // 接收TOTP响应；发命令前已经清过通道，还是收到不像TOTP的回复时（清完以后才到的旧回复）跳过接着等
+        let mut skipped = 0;
+        let (totp, proof) = loop {
+            let response = self.bluetooth_manager.recv(service_uuid, char_uuid, &self.cancel).await
+                .map_err(|e| format!("接收TOTP失败: {}", e))?;
+            let response = String::from_utf8_lossy(&response).trim().to_string();
+            let (totp, proof) = match nonce {
+                Some(_) => pen_protocol::split_totp_response(&response),
+                None => (response, None),
+            };
+            if pen_protocol::is_totp_code(&totp) {
+                break (totp, proof);
+            }
+            skipped += 1;
+            if skipped > MAX_STALE_REPLIES {
+                return Err("笔的回复不是TOTP".to_string());
+            }
+            println!("[CPEN] 收到的回复不是TOTP（可能是之前命令晚到的回复），跳过");
+        };
+        ble_stats::record_round_trip(sent_at.elapsed());
+        
+        // 发了随机数笔却没有回HMAC：这支笔说自己支持挑战应答，不能悄悄退回只用TOTP
+        let challenge = match (nonce, proof) {
+            (Some(nonce), Some(proof)) => {
+                crate::auth::remember_secret(&proof);
+                Some(AuthChallenge { nonce, proof })
+            }
+            (Some(_), None) => return Err("笔没有返回挑战应答".to_string()),
+            (None, _) => None,
+        };
+        
+        // 更新缓存
+        crate::auth::remember_secret(&totp);
+        self.update_totp_cache(totp.clone());
+        
+        println!("[CPEN] TOTP获取成功: {}", totp);
+        
+        Ok((totp, challenge))
+    }
+    
+    /// 这次连接里是不是该重新同步设备时间了
+    /// 
+    /// setTime要一次完整的蓝牙往返，原来每次取TOTP都发。笔的时钟几分钟内不会漂多少，
+    /// 这次连接上次setTime成功后bluetooth.set_time_interval_secs内不再发，设成0表示每次都发
+    fn time_sync_due(&self) -> bool {
+        let window = Duration::from_secs(settings::get().bluetooth.set_time_interval_secs);
+        !self.last_time_sync.is_some_and(|synced_at| synced_at.elapsed() < window)
+    }
+    
+    /// 发setTime同步设备时间，nonce是挑战应答的随机数
+    async fn send_set_time(&mut self, nonce: Option<&str>) -> Result<(), CpenError> {
+        // 后端发现这支笔的时间总是偏的，按记下的偏差补上（见clock_drift）
+        let offset = self.device_id_cache.as_deref().map(clock_drift::offset_secs).unwrap_or(0);
+        let timestamp = (chrono::Utc::now().timestamp() + offset).to_string();
+        let set_time_command = match nonce {
+            Some(nonce) => format!("setTime:{}:{}", timestamp, nonce),
+            None => format!("setTime:{}", timestamp),
+        };
         
         println!("[CPEN] 发送setTime命令: {}", set_time_command);
         
-        let service_uuid = "d816e4c6-1b99-4da7-bcd5-7c37cc2642c4";
-        let char_uuid = "d816e4c7-1b99-4da7-bcd5-7c37cc2642c4";
+        let gatt = self.gatt();
+        let service_uuid = gatt.service_uuid.as_str();
+        let char_uuid = gatt.char_uuid.as_str();
         
         self.bluetooth_manager.send(
             service_uuid, 
             char_uuid, 
-            set_time_command.as_bytes()
+            set_time_command.as_bytes(),
+            &self.cancel,
         ).await
         .map_err(|e| format!("发送setTime命令失败: {}", e))?;
         
@@ -536,7 +843,7 @@ impl CpenDeviceManager {
         // 尝试读取setTime的响应（设备可能不响应）
         match tokio::time::timeout(
             Duration::from_millis(500), 
-            self.bluetooth_manager.recv(service_uuid, char_uuid)
+            self.bluetooth_manager.recv(service_uuid, char_uuid, &self.cancel)
         ).await {
             Ok(Ok(response)) => {
                 let response_str = String::from_utf8_lossy(&response);
@@ -546,29 +853,19 @@ impl CpenDeviceManager {
                 println!("[CPEN] setTime无响应（可能正常）");
             }
         }
-        
-        // 发送getTotp命令
-        println!("[CPEN] 发送getTotp命令");
-        self.bluetooth_manager.send(
-            service_uuid, 
-            char_uuid, 
-            b"getTotp"
-        ).await
-        .map_err(|e| format!("发送getTotp命令失败: {}", e))?;
-        
-        // 接收TOTP响应
-        let response = self.bluetooth_manager.recv(service_uuid, char_uuid).await
-            .map_err(|e| format!("接收TOTP失败: {}", e))?;
-        
-        let totp = String::from_utf8(response)
-            .map_err(|e| format!("TOTP响应不是有效UTF-8: {}", e))?;
-        
-        // 更新缓存
-        self.update_totp_cache(totp.clone());
-        
-        println!("[CPEN] TOTP获取成功: {}", totp);
-        
-        Ok(totp)
+        Ok(())
+    }
+    
+    /// 挑战应答模式下这次setTime带的随机数，没开启或者笔不支持时为None
+    fn challenge_nonce(&self) -> Option<String> {
+        if !settings::get().auth.challenge_response {
+            return None;
+        }
+        if !self.protocol.as_ref().is_some_and(|p| p.supports(PenFeature::Challenge)) {
+            println!("[CPEN] 已开启挑战应答，但笔的固件不支持，只用TOTP认证");
+            return None;
+        }
+        Some(uuid::Uuid::new_v4().simple().to_string())
     }
     
     /// 获取设备ID（设备UUID）
@@ -599,23 +896,29 @@ impl CpenDeviceManager {
         }
         
         // 2. 确保设备已连接
-        self.ensure_connected().await?;
+        self.begin_operation();
+        self.connect_if_needed().await?;
         
         // 3. 发送getId命令
-        let service_uuid = "d816e4c6-1b99-4da7-bcd5-7c37cc2642c4";
-        let char_uuid = "d816e4c7-1b99-4da7-bcd5-7c37cc2642c4";
+        command_deadline::report(ProgressStage::Authenticating);
+        let gatt = self.gatt();
+        let service_uuid = gatt.service_uuid.as_str();
+        let char_uuid = gatt.char_uuid.as_str();
         
         println!("发送getId命令...");
+        let sent_at = Instant::now();
         self.bluetooth_manager.send(
             service_uuid, 
             char_uuid, 
-            b"getId"
+            b"getId",
+            &self.cancel,
         ).await
         .map_err(|e| format!("发送getId命令失败: {}", e))?;
         
         // 4. 接收设备ID响应
-        let response = self.bluetooth_manager.recv(service_uuid, char_uuid).await
+        let response = self.bluetooth_manager.recv(service_uuid, char_uuid, &self.cancel).await
             .map_err(|e| format!("接收设备ID失败: {}", e))?;
+        ble_stats::record_round_trip(sent_at.elapsed());
         
         let device_id = String::from_utf8(response)
             .map_err(|e| format!("设备ID响应不是有效UTF-8: {}", e))?;
@@ -628,33 +931,66 @@ impl CpenDeviceManager {
         Ok(device_id)
     }
     
-    /// 获取连接状态
-    /// 
-    /// 返回格式化的状态字符串，包含：
-    /// - 连接状态（disconnected/connecting/connected/error）
-    /// - 设备信息（如果已连接）
+    /// 协议版本握手
     /// 
-    /// 思考：这个要给前端用，所以要包含足够信息但不要太复杂。
-    pub fn get_connection_status(&self) -> String {
-        match (&self.connection_status[..], &self.current_device) {
-            ("connected", Some(device)) => {
-                format!("已连接到设备: {} ({})", device.name, device.address)
-            }
-            ("connected", None) => {
-                "已连接（设备信息未知）".to_string()
-            }
-            ("connecting", _) => {
-                "正在连接设备...".to_string()
-            }
-            ("disconnected", _) => {
-                "未连接设备".to_string()
-            }
-            (status, _) => {
-                format!("状态: {}", status)
+    /// 发getProtoVersion，没回复或者回复看不懂就当作老固件（版本0），不算连接失败
+    async fn handshake(&mut self) {
+        command_deadline::report(ProgressStage::Authenticating);
+        let gatt = self.gatt();
+        let protocol = match self.bluetooth_manager.send(&gatt.service_uuid, &gatt.char_uuid, b"getProtoVersion", &self.cancel).await {
+            Ok(()) => match tokio::time::timeout(
+                PROTO_VERSION_TIMEOUT,
+                self.bluetooth_manager.recv(&gatt.service_uuid, &gatt.char_uuid, &self.cancel)
+            ).await {
+                Ok(Ok(response)) => PenProtocol::parse(&String::from_utf8_lossy(&response)),
+                _ => None,
+            },
+            Err(e) => {
+                println!("[CPEN] 发送getProtoVersion失败: {}", e);
+                None
             }
+        };
+        let protocol = protocol.unwrap_or_else(PenProtocol::legacy);
+        println!("[CPEN] 笔的协议版本: {}，支持的功能: {:?}", protocol.version, protocol.features);
+        self.protocol = Some(protocol);
+    }
+    
+    /// 当前连接的笔的协议版本，没连接时为None
+    pub fn protocol(&self) -> Option<PenProtocol> {
+        self.protocol.clone()
+    }
+    
+    /// 确保已连接并且笔的固件支持feature，不支持时返回UnsupportedFirmware错误
+    async fn require_feature(&mut self, feature: PenFeature) -> Result<(), CommandError> {
+        self.begin_operation();
+        self.connect_if_needed().await?;
+        match &self.protocol {
+            Some(protocol) => protocol.require(feature),
+            None => PenProtocol::legacy().require(feature),
         }
     }
     
+    /// 查询笔的电量（百分比）
+    pub async fn get_battery_level(&mut self) -> Result<u8, CommandError> {
+        self.require_feature(PenFeature::Battery).await?;
+        
+        command_deadline::report(ProgressStage::Authenticating);
+        let gatt = self.gatt();
+        let sent_at = Instant::now();
+        self.bluetooth_manager.send(&gatt.service_uuid, &gatt.char_uuid, b"getBattery", &self.cancel).await
+            .map_err(|e| format!("发送getBattery命令失败: {}", e))?;
+        let response = self.bluetooth_manager.recv(&gatt.service_uuid, &gatt.char_uuid, &self.cancel).await
+            .map_err(|e| format!("接收电量失败: {}", e))?;
+        ble_stats::record_round_trip(sent_at.elapsed());
+        
+        let text = String::from_utf8_lossy(&response);
+        let level = text.trim().trim_end_matches('%').parse::<u8>()
+            .ok()
+            .filter(|level| *level <= 100)
+            .ok_or_else(|| format!("电量回复格式不对: {}", text.trim()))?;
+        Ok(level)
+    }
+    
     /// 断开连接并清理资源
     /// 
     /// 改进：使用cleanup_connection_state彻底清理状态
@@ -689,15 +1025,29 @@ impl CpenDeviceManager {
         match self.bluetooth_manager.is_connected().await {
             Ok(true) => Ok(true),
             Ok(false) => {
-                self.connection_status = "disconnected".to_string();
                 self.connected_address = None;
                 self.current_device = None;
+                self.protocol = None;
+            self.last_time_sync = None;
+                self.set_status("disconnected");
                 Ok(false)
             }
             Err(e) => Err(format!("检查连接状态失败: {}", e))
         }
     }
     
+    /// 发一次保活包
+    /// 
+    /// 用GATT读（见BluetoothManager::ping）而不是给笔发命令，
+    /// 命令的回复会进通知通道，可能被下一次recv当成TOTP读走
+    pub async fn keep_alive(&self) -> Result<(), CpenError> {
+        if self.connected_address.is_none() {
+            return Ok(());
+        }
+        let gatt = self.gatt();
+        self.bluetooth_manager.ping(&gatt.service_uuid, &gatt.char_uuid, KEEP_ALIVE_TIMEOUT).await
+    }
+    
     /// 获取当前连接的设备信息（调试用）
     pub fn get_current_device_info(&self) -> Option<String> {
         self.current_device.as_ref().map(|dev| {
@@ -705,8 +1055,55 @@ impl CpenDeviceManager {
         })
     }
     
+    /// 列出所有蓝牙适配器
+    pub async fn list_adapters(&self) -> Result<Vec<AdapterInfo>, CpenError> {
+        self.bluetooth_manager.list_adapters().await
+    }
+    
+    /// 切换蓝牙适配器
+    /// 
+    /// 旧适配器上的连接不能带过去，先断开并清理状态，下次使用时会在新适配器上重新扫描连接
+    pub async fn select_adapter(&mut self, id: &str) -> Result<AdapterInfo, CpenError> {
+        self.disconnect().await?;
+        self.bluetooth_manager.select_adapter(id).await
+    }
+    
+    /// 蓝牙不可用时调用（被关闭或适配器被拔掉）
+    /// 
+    /// 旧的蓝牙句柄全部作废，连接状态清空，蓝牙恢复后下次使用会重新扫描连接
+    pub async fn invalidate_bluetooth(&mut self) {
+        self.bluetooth_manager.invalidate().await;
+        self.cleanup_connection_state();
+    }
+    
+    /// 丢掉缓存的TOTP，下次get_totp重新setTime+getTotp（排查认证问题时用）
+    pub fn clear_totp_cache(&mut self) {
+        self.totp_cache = None;
+        self.last_time_sync = None;
+        println!("[CPEN] TOTP缓存已清除");
+    }
+    
     // 注意：移除了复杂的后台任务实现
     // 改为简单的"提前5秒刷新"策略，这样更简单可靠
     // 照逻辑每30秒重新请求TOTP，我们的策略是在缓存还有5秒过期时就刷新
     // 这样get_totp方法返回的值总是新鲜的（最多25秒内的）
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel_only_affects_the_running_operation() {
+        let mut manager = CpenDeviceManager::new();
+        manager.begin_operation();
+        let running = manager.cancel.clone();
+
+        cancel_operations();
+        assert!(running.is_cancelled());
+
+        // 下一个操作拿到新的令牌
+        manager.begin_operation();
+        assert!(!manager.cancel.is_cancelled());
+    }
+}
@@ -0,0 +1,132 @@
+// 崩溃报告
+// 程序panic时把现场写到应用数据目录的crash_report.json，下次启动时前端用
+// get_last_crash_report取出来，提示用户把报告发给我们
+//
+// 思考：release版本panic = "abort"，程序直接退出，用户只能说"闪退了"，
+// 日志在控制台里也没了。panic hook在abort之前还会执行，这里把能拿到的都写下来：
+// 1. panic信息、位置、线程名和backtrace（release版strip了符号，backtrace只有地址，聊胜于无）
+// 2. 最近的日志：println!/eprintln!已经在lib.rs里被覆盖，顺便在这里留一份最近N行
+// 3. 状态摘要：版本、连接状态、后端地址
+// tokio任务里的panic也走同一个hook（hook在panic的线程上执行），线程名是tokio-runtime-worker。
+// 日志都经过了redact，报告里不会有TOTP。
+
+use std::collections::VecDeque;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+// 报告里保留最近多少行日志
+const LOG_TAIL_LINES: usize = 200;
+// 报告文件名
+const CRASH_REPORT_FILE: &str = "crash_report.json";
+
+static LOG_TAIL: OnceLock<Mutex<VecDeque<String>>> = OnceLock::new();
+
+fn log_tail() -> &'static Mutex<VecDeque<String>> {
+    LOG_TAIL.get_or_init(|| Mutex::new(VecDeque::with_capacity(LOG_TAIL_LINES)))
+}
+
+// 记录一行日志（println!/eprintln!里调用）
+pub fn record_log(line: &str) {
+    let entry = format!("{} {}", chrono::Local::now().format("%H:%M:%S%.3f"), line);
+    let mut tail = log_tail().lock().unwrap_or_else(|e| e.into_inner());
+    if tail.len() >= LOG_TAIL_LINES {
+        tail.pop_front();
+    }
+    tail.push_back(entry);
+}
+
+// 最近记录的日志（测试用）
+#[cfg(test)]
+pub fn recent_logs() -> Vec<String> {
+    log_tail().lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+}
+
+fn report_path() -> Result<PathBuf, String> {
+    Ok(crate::storage::get_app_data_dir()?.join(CRASH_REPORT_FILE))
+}
+
+// 安装panic hook，要在程序启动时最先调用
+pub fn install() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        write_report(info);
+        // 原来的hook负责把panic信息打到控制台
+        previous(info);
+    }));
+}
+
+fn write_report(info: &panic::PanicHookInfo<'_>) {
+    let message = match info.payload().downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match info.payload().downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "未知panic".to_string(),
+        },
+    };
+    let location = info
+        .location()
+        .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()));
+    let thread = std::thread::current().name().unwrap_or("未命名线程").to_string();
+    let backtrace = std::backtrace::Backtrace::force_capture().to_string();
+
+    let logs: Vec<String> = match log_tail().try_lock() {
+        Ok(tail) => tail.iter().cloned().collect(),
+        Err(_) => vec!["（日志被占用，无法读取）".to_string()],
+    };
+
+    let report = serde_json::json!({
+        "time": chrono::Local::now().to_rfc3339(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "message": crate::auth::redact(&message),
+        "location": location,
+        "thread": thread,
+        "backtrace": backtrace,
+        "state": state_summary(),
+        "log_tail": logs,
+    });
+
+    // 写报告失败也只能打到控制台，panic里不能再panic
+    let result = report_path().and_then(|path| {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        let text = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+        std::fs::write(&path, text).map_err(|e| e.to_string())?;
+        Ok(path)
+    });
+    match result {
+        Ok(path) => ::std::eprintln!("崩溃报告已写入: {}", path.display()),
+        Err(e) => ::std::eprintln!("写入崩溃报告失败: {}", e),
+    }
+}
+
+// 崩溃时的状态摘要，只读不加锁的东西
+fn state_summary() -> serde_json::Value {
+    let connection = crate::cpen_device_manager::connection_snapshot();
+    serde_json::json!({
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "connection": connection.describe(),
+        "backend": crate::config::get_backend_url().ok(),
+    })
+}
+
+// 读取上一次的崩溃报告，没有返回None
+// clear为true时读完删掉，下次启动不再提示
+pub fn last_report(clear: bool) -> Result<Option<serde_json::Value>, String> {
+    let path = report_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("读取崩溃报告失败: {}", e))?;
+    let report = serde_json::from_str(&text).map_err(|e| format!("解析崩溃报告失败: {}", e))?;
+
+    if clear {
+        if let Err(e) = std::fs::remove_file(&path) {
+            println!("删除崩溃报告失败: {}", e);
+        }
+    }
+    Ok(Some(report))
+}
@@ -0,0 +1,158 @@
+// 自定义链接处理模块
+// 处理 camfc:// 开头的链接，例如网页或聊天里分享的文件链接
+//
+// 支持的链接：
+// camfc://download/ds/下载.png  下载云盘文件 ds/下载.png
+// camfc://open/ds               在主窗口打开云盘目录 ds
+//
+// 思考：下载直接走download_file（由传输管理器在后台执行），
+// 打开目录需要前端切换页面，所以发navigate事件给前端。
+// 处理结果都会发deep-link事件，前端可以用来弹提示。
+// 链接可能来自任何网页，所以：
+// 1. 路径先解码再检查，每一段都必须是普通的文件名/目录名（Component::Normal），
+//    ..、反斜杠分隔、绝对路径、C:这种盘符都不行，否则下载时保存路径会跳出下载目录
+// 2. 下载前弹窗让用户确认，网页不能不经用户同意就开始传输
+
+use std::path::{Component, Path};
+use anyhow::Result;
+use serde::Serialize;
+use tauri::Manager;
+
+use crate::event_emitter;
+
+pub const SCHEME: &str = "camfc";
+
+// 链接对应的操作
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum DeepLinkAction {
+    Download { path: String },
+    OpenFolder { path: String },
+}
+
+// 解析链接
+pub fn parse(url: &str) -> Result<DeepLinkAction> {
+    let rest = url
+        .strip_prefix(&format!("{}://", SCHEME))
+        .ok_or_else(|| anyhow::anyhow!("不是{}链接: {}", SCHEME, url))?;
+
+    // 去掉查询参数和锚点
+    let rest = rest.split(['?', '#']).next().unwrap_or_default();
+
+    let (action, raw_path) = rest.split_once('/').unwrap_or((rest, ""));
+    let path = urlencoding::decode(raw_path.trim_matches('/'))
+        .map_err(|e| anyhow::anyhow!("链接路径编码错误: {}", e))?
+        .into_owned();
+
+    // 不允许跳出云盘目录（见思考1）
+    if !path.is_empty() && !path.split(['/', '\\']).all(is_normal_component) {
+        return Err(anyhow::anyhow!("链接路径不合法: {}", path));
+    }
+
+    match action {
+        "download" if !path.is_empty() => Ok(DeepLinkAction::Download { path }),
+        "download" => Err(anyhow::anyhow!("下载链接缺少文件路径")),
+        "open" => Ok(DeepLinkAction::OpenFolder { path }),
+        _ => Err(anyhow::anyhow!("不支持的链接操作: {}", action)),
+    }
+}
+
+// 路径里的一段是不是普通的文件名/目录名
+fn is_normal_component(part: &str) -> bool {
+    let mut components = Path::new(part).components();
+    !part.contains(':')
+        && matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+}
+
+// 下载前让用户确认（见思考2）
+async fn confirm_download(path: &str) -> bool {
+    let result = rfd::AsyncMessageDialog::new()
+        .set_level(rfd::MessageLevel::Warning)
+        .set_title("CAMFC")
+        .set_description(format!("有一个外部链接请求下载云盘文件：\n{}\n\n要开始下载吗？", path))
+        .set_buttons(rfd::MessageButtons::YesNo)
+        .show()
+        .await;
+    matches!(result, rfd::MessageDialogResult::Yes)
+}
+
+// 显示主窗口，让用户看到链接的处理结果
+fn show_main_window() {
+    if let Some(window) = event_emitter::get_app_handle().and_then(|h| h.get_webview_window("main")) {
+        if let Err(e) = window.show() {
+            eprintln!("显示主窗口失败: {}", e);
+        }
+        if let Err(e) = window.set_focus() {
+            eprintln!("设置主窗口焦点失败: {}", e);
+        }
+    }
+}
+
+// 处理一个链接
+pub async fn handle_url(url: &str) {
+    println!("收到链接: {}", url);
+
+    let action = match parse(url) {
+        Ok(action) => action,
+        Err(e) => {
+            println!("链接解析失败: {}", e);
+            event_emitter::emit_event("deep-link", serde_json::json!({
+                "url": url,
+                "success": false,
+                "error": e.to_string(),
+            }));
+            return;
+        }
+    };
+
+    show_main_window();
+
+    let result = match &action {
+        DeepLinkAction::Download { path } => {
+            if confirm_download(path).await {
+                crate::download_file(path.clone(), Some("high".to_string()), None, None).await
+            } else {
+                Err("用户取消了下载".to_string())
+            }
+        }
+        DeepLinkAction::OpenFolder { path } => {
+            event_emitter::emit_event("navigate", serde_json::json!({ "path": path }));
+            Ok(format!("打开目录: {}", path))
+        }
+    };
+
+    match &result {
+        Ok(message) => println!("链接处理完成: {}", message),
+        Err(e) => println!("链接处理失败: {}", e),
+    }
+
+    event_emitter::emit_event("deep-link", serde_json::json!({
+        "url": url,
+        "request": action,
+        "success": result.is_ok(),
+        "error": result.err(),
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_paths_escaping_download_dir() {
+        assert_eq!(parse("camfc://download/ds/%E4%B8%8B%E8%BD%BD.png").unwrap(), DeepLinkAction::Download { path: "ds/下载.png".into() });
+        assert_eq!(parse("camfc://open/").unwrap(), DeepLinkAction::OpenFolder { path: String::new() });
+        for url in [
+            "camfc://download/ds/../secret",
+            "camfc://download/ds/..%5C..%5Cevil.exe",
+            "camfc://download/..\\evil.exe",
+            "camfc://download/%2Fetc%2Fpasswd",
+            "camfc://download/C:%5CWindows%5Cevil.dll",
+            "camfc://download/ds/./a",
+            "camfc://download/ds//a",
+        ] {
+            assert!(parse(url).is_err(), "{}", url);
+        }
+    }
+}
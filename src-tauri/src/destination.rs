@@ -0,0 +1,167 @@
+// 下载目标路径检查
+// 开始下载或拉取文件夹前，先确认要写入的目录能写，不能写时返回带具体路径的错误
+//
+// 思考：
+// 1. 原来目录没权限、文件是只读的、盘是只读挂载的，都要等到第一个分片写入时才失败，
+//    错误是一句"写入文件失败"，任务还会自动重试好几轮。现在创建任务前先检查，
+//    错误以[问题]开头（missing / not_a_directory / read_only / permission_denied），带上出问题的那个路径
+// 2. 目录还不存在时检查最近一个存在的上级目录，下载时会逐级创建下面的目录
+// 3. 目录的只读属性不可靠（Windows上目录的只读属性不影响写入，资源管理器自定义过的文件夹都带着它），
+//    所以真的在目录里创建一个临时文件再删掉，能创建就算可写。已经存在的目标文件看只读属性
+// 4. 拉取（同步）时本地根目录必须已经存在：U盘或网络盘没挂上时不能当成"本地文件都没了"全部重新下载
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+// 检查目录可写时创建的临时文件名
+const PROBE_FILE_NAME: &str = ".camfc-write-test";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DestinationProblem {
+    Missing,            // 目录不存在
+    NotADirectory,      // 路径存在但不是目录
+    ReadOnly,           // 文件只读，或者所在的盘是只读的
+    PermissionDenied,   // 没有写入权限
+}
+
+impl DestinationProblem {
+    pub fn code(self) -> &'static str {
+        match self {
+            DestinationProblem::Missing => "missing",
+            DestinationProblem::NotADirectory => "not_a_directory",
+            DestinationProblem::ReadOnly => "read_only",
+            DestinationProblem::PermissionDenied => "permission_denied",
+        }
+    }
+}
+
+/// 目标路径不能写入
+#[derive(Debug)]
+pub struct DestinationError {
+    pub problem: DestinationProblem,
+    pub path: PathBuf,   // 出问题的路径，不一定是要写的文件本身（可能是它的上级目录）
+    pub message: String,
+}
+
+impl fmt::Display for DestinationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {:?}", self.problem.code(), self.message, self.path)
+    }
+}
+
+impl std::error::Error for DestinationError {}
+
+fn error(problem: DestinationProblem, path: &Path, message: impl Into<String>) -> DestinationError {
+    DestinationError { problem, path: path.to_path_buf(), message: message.into() }
+}
+
+// 按IO错误判断是哪种问题
+fn classify(e: &std::io::Error) -> DestinationProblem {
+    match e.kind() {
+        ErrorKind::ReadOnlyFilesystem => DestinationProblem::ReadOnly,
+        ErrorKind::NotFound => DestinationProblem::Missing,
+        ErrorKind::NotADirectory => DestinationProblem::NotADirectory,
+        _ => DestinationProblem::PermissionDenied,
+    }
+}
+
+// 在已经存在的目录里试着创建一个临时文件（见思考3）
+async fn probe_dir(dir: &Path) -> Result<(), DestinationError> {
+    match fs::metadata(dir).await {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => return Err(error(DestinationProblem::NotADirectory, dir, "路径不是文件夹")),
+        Err(e) => return Err(error(classify(&e), dir, format!("无法访问文件夹: {}", e))),
+    }
+
+    let probe = dir.join(format!("{}-{}", PROBE_FILE_NAME, std::process::id()));
+    match fs::OpenOptions::new().write(true).create(true).truncate(true).open(&probe).await {
+        Ok(file) => {
+            drop(file);
+            let _ = fs::remove_file(&probe).await;
+            Ok(())
+        }
+        Err(e) => Err(error(classify(&e), dir, format!("文件夹不能写入: {}", e))),
+    }
+}
+
+// 确认dir可以写入，还不存在时检查最近一个存在的上级目录（见思考2）
+pub async fn ensure_writable_dir(dir: &Path) -> Result<(), DestinationError> {
+    let mut existing = dir;
+    loop {
+        match fs::symlink_metadata(existing).await {
+            Ok(_) => break,
+            // 不存在，或者上级是个文件，都往上找
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                match existing.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => existing = parent,
+                    _ => return Err(error(DestinationProblem::Missing, dir, "文件夹及其上级都不存在")),
+                }
+            }
+            Err(e) => return Err(error(classify(&e), existing, format!("无法访问: {}", e))),
+        }
+    }
+    probe_dir(existing).await
+}
+
+// 确认可以把文件下载到save_path：所在目录可写，已经存在的文件不是只读的
+pub async fn ensure_writable_file(save_path: &Path) -> Result<(), DestinationError> {
+    if let Ok(meta) = fs::metadata(save_path).await {
+        if meta.is_dir() {
+            return Err(error(DestinationProblem::NotADirectory, save_path, "同名的文件夹已经存在"));
+        }
+        if meta.permissions().readonly() {
+            return Err(error(DestinationProblem::ReadOnly, save_path, "文件是只读的"));
+        }
+    }
+    match save_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => ensure_writable_dir(parent).await,
+        _ => Ok(()),
+    }
+}
+
+// 拉取前检查本地根目录：必须已经存在并且可写（见思考4）
+pub async fn ensure_sync_root(root: &Path) -> Result<(), DestinationError> {
+    if fs::metadata(root).await.is_err() {
+        return Err(error(DestinationProblem::Missing, root, "同步的本地文件夹不存在，请确认所在的磁盘已经连接"));
+    }
+    probe_dir(root).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_the_offending_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+
+        // 还不存在的子目录按上级目录判断
+        assert!(ensure_writable_dir(&dir.join("a/b")).await.is_ok());
+        let e = ensure_sync_root(&dir.join("a")).await.unwrap_err();
+        assert_eq!(e.problem, DestinationProblem::Missing);
+        assert_eq!(e.path, dir.join("a"));
+
+        // 路径上有一个文件
+        let file = dir.join("file.txt");
+        fs::write(&file, b"x").await.unwrap();
+        let e = ensure_writable_dir(&file.join("sub")).await.unwrap_err();
+        assert_eq!(e.problem, DestinationProblem::NotADirectory);
+        assert_eq!(e.path, file);
+
+        // 只读文件（测完改回来，临时目录才删得掉）
+        let mut permissions = fs::metadata(&file).await.unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&file, permissions).await.unwrap();
+        let e = ensure_writable_file(&file).await.unwrap_err();
+        assert_eq!(e.problem, DestinationProblem::ReadOnly);
+        assert!(e.to_string().starts_with("[read_only]"));
+
+        let mut permissions = fs::metadata(&file).await.unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&file, permissions).await.unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+// 识别Cpen设备的规则
+// 扫描到的蓝牙设备里哪些算笔，原来写死成"设备名以Cpen开头"，改了名字的笔和下一代设备都认不出来。
+// 现在规则放在设置里（bluetooth.device_match），用set_device_match_rules命令修改
+//
+// 思考：
+// 1. 三种规则：设备名前缀（不区分大小写）、广播的服务UUID、设备地址
+// 2. 地址列表是白名单：不为空时只认列表里的地址，名字和服务都不看，用来在多支笔里固定用某几支
+// 3. 地址列表为空时，名字前缀和服务UUID满足一个就算
+// 4. 保存前整理：去掉空白和重复，地址转大写、UUID转小写；三种规则都为空时什么都认不出来，不让保存
+
+use uuid::Uuid;
+
+use crate::device_state::DeviceInfo;
+use crate::settings::{self, DeviceMatchRules};
+
+// 去掉空白和重复，保持原来的顺序
+fn clean(values: &[String], normalize: fn(&str) -> String) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    for value in values.iter().map(|v| normalize(v.trim())).filter(|v| !v.is_empty()) {
+        if !result.contains(&value) {
+            result.push(value);
+        }
+    }
+    result
+}
+
+// 整理规则（见思考4）
+pub fn normalize(rules: &DeviceMatchRules) -> DeviceMatchRules {
+    DeviceMatchRules {
+        name_prefixes: clean(&rules.name_prefixes, str::to_string),
+        service_uuids: clean(&rules.service_uuids, str::to_ascii_lowercase),
+        addresses: clean(&rules.addresses, str::to_ascii_uppercase),
+    }
+}
+
+// 检查规则能不能用
+pub fn validate(rules: &DeviceMatchRules) -> Result<(), String> {
+    if rules.name_prefixes.iter().chain(&rules.service_uuids).chain(&rules.addresses).all(|v| v.trim().is_empty()) {
+        return Err("设备识别规则不能全部为空，否则扫描不到任何设备".to_string());
+    }
+    for uuid in &rules.service_uuids {
+        Uuid::parse_str(uuid.trim()).map_err(|e| format!("服务UUID格式不对: {} ({})", uuid, e))?;
+    }
+    Ok(())
+}
+
+// 设备是否符合规则（见思考2、3）
+pub fn matches(rules: &DeviceMatchRules, device: &DeviceInfo) -> bool {
+    if !rules.addresses.is_empty() {
+        return rules.addresses.iter().any(|a| a.trim().eq_ignore_ascii_case(&device.address));
+    }
+
+    let name = device.name.to_lowercase();
+    let by_name = rules.name_prefixes.iter()
+        .map(|p| p.trim().to_lowercase())
+        .any(|p| !p.is_empty() && name.starts_with(&p));
+    let by_service = rules.service_uuids.iter()
+        .filter_map(|u| Uuid::parse_str(u.trim()).ok())
+        .any(|u| device.services.contains(&u));
+    by_name || by_service
+}
+
+// 按当前设置过滤出笔
+pub fn filter(devices: &[DeviceInfo]) -> Vec<DeviceInfo> {
+    let rules = settings::get().bluetooth.device_match;
+    devices.iter().filter(|d| matches(&rules, d)).cloned().collect()
+}
+
+// 找不到设备时告诉用户规则是什么
+pub fn describe(rules: &DeviceMatchRules) -> String {
+    if !rules.addresses.is_empty() {
+        return format!("设备地址需为 {}", rules.addresses.join("、"));
+    }
+    let mut parts = Vec::new();
+    if !rules.name_prefixes.is_empty() {
+        parts.push(format!("设备名以 {} 开头", rules.name_prefixes.join("/")));
+    }
+    if !rules.service_uuids.is_empty() {
+        parts.push(format!("广播服务 {}", rules.service_uuids.join("/")));
+    }
+    parts.join("，或")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &str, address: &str, services: Vec<Uuid>) -> DeviceInfo {
+        DeviceInfo { name: name.to_string(), address: address.to_string(), services }
+    }
+
+    #[test]
+    fn matches_by_prefix_service_or_address() {
+        let service = Uuid::parse_str("d816e4c6-1b99-4da7-bcd5-7c37cc2642c4").unwrap();
+        let pen = device("CPEN-42", "AA:BB:CC:DD:EE:01", vec![]);
+        let next_gen = device("Nib 2", "AA:BB:CC:DD:EE:02", vec![service]);
+        let other = device("Keyboard", "AA:BB:CC:DD:EE:03", vec![]);
+
+        let rules = DeviceMatchRules::default();
+        assert!(matches(&rules, &pen));
+        assert!(!matches(&rules, &next_gen));
+
+        let rules = normalize(&DeviceMatchRules {
+            name_prefixes: vec!["cpen".into(), " ".into()],
+            service_uuids: vec!["D816E4C6-1B99-4DA7-BCD5-7C37CC2642C4".into()],
+            addresses: vec![],
+        });
+        assert_eq!(rules.service_uuids, vec!["d816e4c6-1b99-4da7-bcd5-7c37cc2642c4"]);
+        assert!(matches(&rules, &pen) && matches(&rules, &next_gen) && !matches(&rules, &other));
+
+        // 地址白名单不为空时只认这些地址
+        let rules = normalize(&DeviceMatchRules { addresses: vec!["aa:bb:cc:dd:ee:03".into()], ..DeviceMatchRules::default() });
+        assert!(matches(&rules, &other) && !matches(&rules, &pen));
+
+        let empty = DeviceMatchRules { name_prefixes: vec![], service_uuids: vec![], addresses: vec![] };
+        assert!(validate(&empty).is_err());
+        assert!(validate(&DeviceMatchRules { service_uuids: vec!["not-a-uuid".into()], ..DeviceMatchRules::default() }).is_err());
+    }
+}
@@ -0,0 +1,130 @@
+//! 设备和连接状态
+//!
+//! 设备信息、适配器信息和连接状态快照，界面、本地接口、运行指标、崩溃报告都要读。
+//!
+//! 思考：这些原来放在bluetooth和cpen_device_manager里，但它们本身不依赖btleplug。
+//! 关掉ble功能编译时蓝牙模块整个不编译，这些类型还要在，所以单独拿出来，
+//! 没有蓝牙时快照一直是"未连接"。
+
+use std::sync::{Arc, OnceLock};
+use arc_swap::ArcSwap;
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+/// 设备信息
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub address: String,
+    pub services: Vec<Uuid>,
+}
+
+/// 蓝牙适配器信息
+/// 
+/// id由序号和适配器描述组成，用来在设置里记住用户选择的适配器。
+/// Windows下btleplug拿不到适配器的具体名字，name都是"WinRT"，只能靠序号区分。
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AdapterInfo {
+    pub id: String,
+    pub index: usize,
+    pub name: String,
+    pub powered: Option<bool>,   // None表示无法获取电源状态
+    pub selected: bool,          // 是否是当前使用的适配器
+}
+
+/// 连接状态快照
+/// 
+/// get_connection_status和is_connected原来要拿设备管理器的锁，
+/// 获取TOTP时（扫描+连接可能十几秒）这两个命令会一直卡住。
+/// 现在设备管理器每次改状态都写一份快照，这两个命令直接读快照，不用等锁。
+#[derive(Clone, Serialize)]
+pub struct ConnectionSnapshot {
+    /// disconnected/connecting/connected
+    pub status: String,
+    /// 当前连接的设备
+    pub device: Option<DeviceInfo>,
+    /// 蓝牙适配器是否开启，由蓝牙监控更新，None表示还不知道
+    pub adapter_powered: Option<bool>,
+}
+
+impl ConnectionSnapshot {
+    /// 是否已连接
+    pub fn is_connected(&self) -> bool {
+        self.status == "connected" && self.device.is_some()
+    }
+    
+    /// 给前端看的状态文字
+    /// 
+    /// 蓝牙适配器被关闭时会在后面加上"（蓝牙已关闭）"
+    pub fn describe(&self) -> String {
+        let mut text = match (&self.status[..], &self.device) {
+            ("connected", Some(device)) => {
+                format!("已连接到设备: {} ({})", device.name, device.address)
+            }
+            ("connected", None) => {
+                "已连接（设备信息未知）".to_string()
+            }
+            ("connecting", _) => {
+                "正在连接设备...".to_string()
+            }
+            ("disconnected", _) => {
+                "未连接设备".to_string()
+            }
+            (status, _) => {
+                format!("状态: {}", status)
+            }
+        };
+        if self.adapter_powered == Some(false) {
+            text.push_str("（蓝牙已关闭）");
+        }
+        text
+    }
+}
+
+static CONNECTION_SNAPSHOT: OnceLock<ArcSwap<ConnectionSnapshot>> = OnceLock::new();
+
+fn snapshot_cell() -> &'static ArcSwap<ConnectionSnapshot> {
+    CONNECTION_SNAPSHOT.get_or_init(|| {
+        ArcSwap::from_pointee(ConnectionSnapshot {
+            status: "disconnected".to_string(),
+            device: None,
+            adapter_powered: None,
+        })
+    })
+}
+
+/// 读取当前连接状态快照（不加锁）
+pub fn connection_snapshot() -> Arc<ConnectionSnapshot> {
+    snapshot_cell().load_full()
+}
+
+/// 更新快照里的适配器开关状态（蓝牙监控调用）
+pub fn set_adapter_powered(powered: Option<bool>) {
+    snapshot_cell().rcu(|old| ConnectionSnapshot {
+        adapter_powered: powered,
+        ..ConnectionSnapshot::clone(old)
+    });
+}
+
+/// 更新快照里的连接状态和设备（设备管理器调用）
+pub fn set_connection(status: &str, device: Option<DeviceInfo>) {
+    snapshot_cell().rcu(|old| ConnectionSnapshot {
+        status: status.to_string(),
+        device: device.clone(),
+        adapter_powered: old.adapter_powered,
+    });
+}
+
+/// 连接被中途放弃（命令超时）时把"connecting"改回未连接
+/// 
+/// 其他状态不动：已经连上或已经失败时设备管理器自己写过了
+pub fn abandon_connecting() {
+    snapshot_cell().rcu(|old| {
+        let mut snapshot = ConnectionSnapshot::clone(old);
+        if snapshot.status == "connecting" {
+            snapshot.status = "disconnected".to_string();
+            snapshot.device = None;
+        }
+        snapshot
+    });
+}
@@ -0,0 +1,206 @@
+// 编译时关掉的功能的替代实现
+// 只需要HTTP传输和env_token认证的部署（没有笔）可以不编译蓝牙、本地HTTP接口、文件夹同步，
+// 少了btleplug/windows/axum/dav-server/globset这些依赖
+//
+// 思考：Tauri命令列表和命令行都直接用这些模块，如果每个调用的地方都加cfg，lib.rs会到处是条件编译。
+// 所以关掉的模块换成这里同名的替代模块，接口一样，调用时返回"功能未编译"的错误，
+// 前端和脚本照常调用，看到错误就知道这个版本没有这个功能。
+// 替代模块只保留外面真正用到的接口，内部实现细节不用对上。
+
+#[cfg(not(feature = "ble"))]
+pub const BLE_DISABLED: &str = "当前版本编译时没有开启蓝牙功能（ble），请改用env_token认证";
+
+#[cfg(not(feature = "ble"))]
+pub mod cpen_device_manager {
+    pub use crate::device_state::connection_snapshot;
+    use crate::command_deadline::CommandError;
+    use crate::device_state::{AdapterInfo, DeviceInfo};
+    use crate::pen_protocol::PenProtocol;
+    use super::BLE_DISABLED;
+
+    type CpenError = String;
+
+    fn disabled<T>() -> Result<T, CpenError> {
+        Err(BLE_DISABLED.to_string())
+    }
+
+    /// 没有蓝牙功能时不用保活，会话什么也不做
+    pub struct BleSession;
+
+    pub fn open_session() -> BleSession {
+        BleSession
+    }
+
+    /// 没有蓝牙操作可取消
+    pub fn cancel_operations() {}
+
+    /// 没有蓝牙功能时的设备管理器，所有操作都返回错误
+    pub struct CpenDeviceManager;
+
+    impl CpenDeviceManager {
+        pub fn new() -> Self {
+            Self
+        }
+
+        pub async fn ensure_connected(&mut self) -> Result<(), CpenError> {
+            disabled()
+        }
+
+        pub async fn ensure_bluetooth_enabled(&mut self) -> Result<(), CpenError> {
+            disabled()
+        }
+
+        pub async fn scan_cpen_devices(&mut self) -> Result<Vec<DeviceInfo>, CpenError> {
+            disabled()
+        }
+
+        pub async fn connect_to_device(&mut self, _address: &str) -> Result<DeviceInfo, CpenError> {
+            disabled()
+        }
+
+        pub async fn get_totp(&mut self) -> Result<String, CpenError> {
+            disabled()
+        }
+
+        pub async fn get_totp_with_challenge(&mut self) -> Result<(String, Option<crate::auth::AuthChallenge>), CpenError> {
+            disabled()
+        }
+
+        pub async fn get_device_id(&mut self) -> Result<String, CpenError> {
+            disabled()
+        }
+
+        pub async fn disconnect(&mut self) -> Result<(), CpenError> {
+            Ok(())
+        }
+
+        pub async fn is_connected(&mut self) -> Result<bool, CpenError> {
+            Ok(false)
+        }
+
+        pub fn get_current_device_info(&self) -> Option<String> {
+            None
+        }
+
+        pub async fn list_adapters(&self) -> Result<Vec<AdapterInfo>, CpenError> {
+            Ok(Vec::new())
+        }
+
+        pub async fn select_adapter(&mut self, _id: &str) -> Result<AdapterInfo, CpenError> {
+            disabled()
+        }
+
+        pub async fn invalidate_bluetooth(&mut self) {}
+
+        pub fn clear_totp_cache(&mut self) {}
+
+        pub fn protocol(&self) -> Option<PenProtocol> {
+            None
+        }
+
+        pub async fn get_battery_level(&mut self) -> Result<u8, CommandError> {
+            disabled().map_err(CommandError::from)
+        }
+    }
+}
+
+#[cfg(not(feature = "http"))]
+const HTTP_DISABLED: &str = "当前版本编译时没有开启本地HTTP接口功能（http）";
+
+#[cfg(not(feature = "http"))]
+pub mod webdav {
+    use anyhow::Result;
+
+    pub const SCRATCH_DIR_NAME: &str = "webdav";
+
+    pub async fn start(_port: u16) -> Result<u16> {
+        Err(anyhow::anyhow!(super::HTTP_DISABLED))
+    }
+
+    pub async fn stop() -> bool {
+        false
+    }
+
+    pub async fn running_port() -> Option<u16> {
+        None
+    }
+}
+
+#[cfg(not(feature = "http"))]
+pub mod local_api {
+    use std::path::PathBuf;
+    use anyhow::Result;
+
+    // 令牌文件的位置和开启http功能时一样，状态查询照常返回
+    pub fn get_token_path() -> Result<PathBuf> {
+        let data_dir = crate::storage::get_app_data_dir().map_err(|e| anyhow::anyhow!(e))?;
+        Ok(data_dir.join("local_api_token"))
+    }
+
+    pub async fn start(_port: u16) -> Result<u16> {
+        Err(anyhow::anyhow!(super::HTTP_DISABLED))
+    }
+
+    pub async fn stop() -> bool {
+        false
+    }
+
+    pub async fn running_port() -> Option<u16> {
+        None
+    }
+}
+
+#[cfg(not(feature = "http"))]
+pub mod media_stream {
+    use anyhow::Result;
+    use serde::Serialize;
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct StreamInfo {
+        pub url: String,
+        pub file_id: String,
+        pub size: u64,
+        pub content_type: String,
+    }
+
+    pub async fn open(_file_id: &str) -> Result<StreamInfo> {
+        Err(anyhow::anyhow!(super::HTTP_DISABLED))
+    }
+
+    pub async fn stop() -> bool {
+        false
+    }
+}
+
+#[cfg(not(feature = "sync"))]
+pub mod transfer_filter {
+    use std::path::Path;
+    use anyhow::Result;
+    use serde::{Deserialize, Serialize};
+
+    // 和开启sync功能时的格式一样，前端传过来的参数照常能解析
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct FilterRules {
+        pub exclude: Vec<String>,
+        pub exclude_hidden: bool,
+        pub max_file_size: Option<u64>,
+    }
+
+    // 不能创建，文件夹上传和同步在创建过滤器时就返回错误
+    pub struct TransferFilter(());
+
+    impl TransferFilter {
+        pub fn new(_rules: &FilterRules) -> Result<Self> {
+            Err(anyhow::anyhow!("当前版本编译时没有开启文件夹同步功能（sync）"))
+        }
+
+        pub fn excludes_dir(&self, _relative: &Path) -> Option<String> {
+            None
+        }
+
+        pub fn excludes_file(&self, _relative: &Path, _size: u64) -> Option<String> {
+            None
+        }
+    }
+}
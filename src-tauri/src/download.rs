@@ -6,29 +6,61 @@
 // 2. 支持分片下载（默认4MB）
 // 3. 支持断点续传
 // 4. 提供下载进度信息
+// 5. 跟随模式（follow）：给正在被追加的文件用（日志、录制中的视频）。下载到当前末尾后不算结束，
+//    每隔FOLLOW_INTERVAL查一次云端大小，变大了就把新增的部分接着下载，直到用户停止跟随或暂停。
+//    跟随时不占并发名额；TOTP会过期，每次检查都重新获取认证信息，请求还是发到任务原来的后端。
+//    蓝牙会话只在检查的那一会儿打开，两次检查之间笔可以睡眠；文件一直没变大时检查间隔逐步拉长到
+//    FOLLOW_MAX_INTERVAL，少找笔要TOTP，变大了马上回到FOLLOW_INTERVAL。
+//    云端文件变小了（被截断或轮转）说明已经不是同一个文件，停止跟随并报错。
+//    暂停（包括退出时的暂停）后保持Paused，传输队列照常记下，重试或下次启动恢复时下载完接着跟随
 
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Mutex;
-use tokio::fs::{self, File, OpenOptions};
-use tokio::io::{AsyncWriteExt, AsyncReadExt, AsyncSeekExt};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify};
+use arc_swap::ArcSwap;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use anyhow::{Result, Context};
 use serde::{Serialize, Deserialize};
-use reqwest::{Client, header};
+use reqwest::{Method, header};
 use sha2::{Sha256, Digest};
 use hex::encode as hex_encode;
 
-// 导入配置模块
-use crate::config;
+// 导入认证模块
+use crate::auth::AuthInfo;
+// 导入设置模块
+use crate::settings::{self, FsyncPolicy};
+// 导入下载元数据模块
+use crate::download_meta::DownloadMeta;
+// 导入分片文件写入模块
+use crate::file_writer::ChunkFileWriter;
+// 导入传输优先级
+use crate::transfer_manager::{self, TransferPriority, HighPriorityGuard, TaskAlreadyRunning};
+// 导入速度采样模块
+use crate::speed::{SpeedSampler, SpeedSample};
+// 导入分片计算
+use crate::chunks;
+// 导入分片磁盘缓存
+use crate::chunk_cache;
+// 导入后端维护状态
+use crate::server_maintenance;
+// 导入传输HTTP层
+use crate::transfer_http::{self, Endpoint, TransferHttp, CHUNK_ATTEMPTS, CHUNK_RETRY_DELAY};
+// 导入网络类型检测（计费网络策略）
+use crate::network_profile;
+// 导入电源状态（睡眠/省电模式时暂停）
+use crate::power;
+// 导入任务日志
+use crate::transfer_log::{LogLine, TransferLog};
+// 导入传输队列（排队序号）
+use crate::transfer_queue::{self, Direction};
 
-// 默认分片大小 256KB
-const CHUNK_SIZE: u64 = 256 * 1024; // 256KB
-
-// 获取基础URL的辅助函数
-fn get_base_url() -> Result<String> {
-    config::get_backend_url()
-}
+// 跟随模式下多久查一次云端文件的大小
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(10);
+// 文件一直没变大时检查间隔最长拉到多少
+const FOLLOW_MAX_INTERVAL: Duration = Duration::from_secs(120);
 
 // 文件类型分类
 #[derive(Debug, Clone, PartialEq)]
@@ -55,6 +87,20 @@ impl FileType {
             FileType::Other => "其他",
         }
     }
+    
+    // 解析前端传来的类型名称（image/video/audio/document/archive/code/other）
+    pub fn from_name(name: &str) -> Option<FileType> {
+        match name.to_lowercase().as_str() {
+            "image" => Some(FileType::Image),
+            "video" => Some(FileType::Video),
+            "audio" => Some(FileType::Audio),
+            "document" => Some(FileType::Document),
+            "archive" => Some(FileType::Archive),
+            "code" => Some(FileType::Code),
+            "other" => Some(FileType::Other),
+            _ => None,
+        }
+    }
 }
 
 // 根据文件扩展名判断文件类型
@@ -93,6 +139,9 @@ pub enum DownloadStatus {
     Paused,       // 已暂停
     Completed,    // 已完成
     Error(String), // 错误
+    WaitingForServer(i64), // 后端维护中，等到这个时间（Unix时间戳）再继续
+    Verifying(u8), // 分片都下载完了，正在校验，后面是校验进度（0-100）
+    Following,    // 跟随模式，已经下载到当前末尾，等文件继续增长
 }
 
 // 下载进度信息
@@ -105,50 +154,81 @@ pub struct DownloadProgress {
     pub status: DownloadStatus,    // 下载状态
     pub chunks_total: u32,         // 总分片数
     pub chunks_completed: u32,     // 已完成分片数
+    pub chunk_size: u64,           // 分片大小（字节）
     pub speed_kbps: f64,           // 下载速度 KB/s
+    pub retry_count: u32,          // 已重试次数
+    pub priority: TransferPriority, // 下载优先级
+}
+
+// 单个分片的状态
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkState {
+    Pending,    // 还没开始
+    InFlight,   // 正在下载
+    Done,       // 已写入文件
+    Failed,     // 重试后仍然失败
+}
+
+// 分片详细信息，给前端画分段进度条用
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkInfo {
+    pub index: u32,
+    pub start: u64,          // 起始字节（包含）
+    pub end: u64,            // 结束字节（包含）
+    pub state: ChunkState,
+    pub retry_count: u32,    // 这个分片重试了几次
+}
+
+// 按文件大小生成全部分片，初始都是Pending
+fn build_chunk_list(total_size: u64, chunk_size: u64) -> Vec<ChunkInfo> {
+    (0..chunks::chunk_count(total_size, chunk_size))
+        .filter_map(|index| {
+            let (start, end) = chunks::chunk_range(index, total_size, chunk_size)?;
+            Some(ChunkInfo {
+                index,
+                start,
+                end,
+                state: ChunkState::Pending,
+                retry_count: 0,
+            })
+        })
+        .collect()
 }
 
-// 认证信息 - 从蓝牙设备获取
+// 服务器返回的分片哈希列表
+#[derive(Debug, Deserialize)]
+struct ChunkHashesResponse {
+    chunk_size: u64,
+    hashes: Vec<String>,  // 每个分片的SHA256（十六进制）
+}
+
+// 服务器上文件的基本信息（HEAD /download/{path}）
 #[derive(Debug, Clone)]
-pub struct AuthInfo {
-    pub device_id: String,  // 设备ID
-    pub totp: String,       // 动态密码
+pub struct RemoteFileMeta {
+    pub size: u64,
+    pub name: String,
+    pub modified_at: Option<i64>,   // Last-Modified，Unix时间戳（秒）
+    pub etag: Option<String>,       // 分片缓存靠它判断文件有没有变
 }
 
-impl AuthInfo {
-    // 获取认证头信息
-    pub fn get_auth_header(&self) -> Result<header::HeaderMap> {
-        let auth_json = serde_json::json!({
-            "Id": self.device_id,
-            "Totp": self.totp
-        }).to_string();
-        
-        let mut headers = header::HeaderMap::new();
-        headers.insert(
-            header::AUTHORIZATION,
-            header::HeaderValue::from_str(&auth_json)?
-        );
-        
-        Ok(headers)
-    }
+// 完整性修复结果
+#[derive(Debug, Clone, Serialize)]
+pub struct RepairReport {
+    pub chunks_total: u32,          // 总分片数
+    pub corrupt_chunks: Vec<u32>,   // 校验不通过的分片
+    pub repaired_bytes: u64,        // 重新下载的字节数
 }
 
 // 分片下载器
 pub struct ChunkDownloader {
-    client: Client,
-    auth_info: AuthInfo,
+    http: TransferHttp,
 }
 
 impl ChunkDownloader {
     // 创建新的下载器
     pub fn new(auth_info: AuthInfo) -> Result<Self> {
-        // 创建HTTP客户端，设置合适的超时时间
-        let client = Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()
-            .context("创建HTTP客户端失败")?;
-            
-        Ok(Self { client, auth_info })
+        Ok(Self { http: TransferHttp::new(auth_info)? })
     }
     
     // 下载单个分片
@@ -159,42 +239,14 @@ impl ChunkDownloader {
         range_start: u64,
         range_end: u64,
     ) -> Result<Vec<u8>> {
-        let base_url = get_base_url()?;
-        
-        let encoded_file_id = urlencoding::encode(file_id);
-        let url = format!("{}/download/{}", base_url, encoded_file_id);
-        
-        println!("下载请求URL: {}", url);
-        println!("原始文件路径: {}", file_id);
+        println!("下载分片: {} 字节 {}-{}", file_id, range_start, range_end);
         
         // 构建Range头
         let range_header = format!("bytes={}-{}", range_start, range_end);
-        
-        // 获取认证头
-        let mut headers = self.auth_info.get_auth_header()?;
-        headers.insert(
-            header::RANGE,
-            header::HeaderValue::from_str(&range_header)?
-        );
-        
-        // 发送请求
-        let response = self.client
-            .get(&url)
-            .headers(headers)
-            .send()
-            .await
-            .context("发送下载请求失败")?;
-            
-        // 检查响应状态
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "下载请求失败: {} - {}", 
-                status, 
-                error_text
-            ));
-        }
+        let request = self.http
+            .request(Method::GET, Endpoint::Download(file_id))?
+            .header(header::RANGE, range_header);
+        let response = transfer_http::send(request, "下载请求").await?;
         
         // 读取响应内容
         let chunk_data = response
@@ -205,39 +257,34 @@ impl ChunkDownloader {
         Ok(chunk_data.to_vec())
     }
     
-    // 获取文件元数据（大小等信息）
-    pub async fn get_file_metadata(&self, file_id: &str) -> Result<(u64, String)> {
-        let base_url = get_base_url()?;
-        
-        let encoded_file_id = urlencoding::encode(file_id);
-        let url = format!("{}/download/{}", base_url, encoded_file_id);
-        
-        println!("获取文件元数据URL (HEAD): {}", url);
-        println!("原始文件路径: {}", file_id);
-        
-        let headers = self.auth_info.get_auth_header()?;
+    // 先查分片缓存（边下边播存进去的），没有再下载；下载到的不存，见chunk_cache.rs思考6
+    // validator为None时不走缓存
+    pub async fn fetch_chunk(
+        &self,
+        file_id: &str,
+        chunk_index: u32,
+        range_start: u64,
+        range_end: u64,
+        validator: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        if let Some(data) = chunk_cache::get(file_id, range_start, range_end, validator).await {
+            println!("分片缓存命中: {} 字节 {}-{}", file_id, range_start, range_end);
+            return Ok(data);
+        }
+        self.download_chunk(file_id, chunk_index, range_start, range_end).await
+    }
+    
+    // 获取文件元数据（大小、修改时间等信息）
+    pub async fn get_file_metadata(&self, file_id: &str) -> Result<RemoteFileMeta> {
+        println!("获取文件元数据 (HEAD): {}", file_id);
         
         // 发送HEAD请求获取文件元数据
-        let response = self.client
-            .head(&url)
-            .headers(headers)
-            .send()
-            .await
-            .context("获取文件元数据失败")?;
-            
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = if status == reqwest::StatusCode::NOT_FOUND {
-                "文件不存在".to_string()
-            } else {
-                response.text().await.unwrap_or_default()
-            };
-            return Err(anyhow::anyhow!(
-                "获取文件元数据失败: {} - {}", 
-                status, 
-                error_text
-            ));
+        let request = self.http.request(Method::HEAD, Endpoint::Download(file_id))?;
+        let response = transfer_http::send_raw(request, "获取文件元数据").await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(anyhow::anyhow!("获取文件元数据失败: {} - 文件不存在", response.status()));
         }
+        let response = transfer_http::check_response(response, "获取文件元数据").await?;
         
         // 从响应头获取文件大小
         let content_length = response
@@ -246,6 +293,18 @@ impl ChunkDownloader {
             .and_then(|v| v.to_str().ok())
             .and_then(|s| s.parse::<u64>().ok())
             .unwrap_or(0);
+        
+        // 服务器上的修改时间，下载完成后设置到本地文件上
+        let modified_at = response
+            .headers()
+            .get(header::LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .and_then(transfer_http::parse_http_date);
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string());
             
         // 从文件路径中提取文件名
         let filename = std::path::Path::new(file_id)
@@ -254,9 +313,44 @@ impl ChunkDownloader {
             .unwrap_or(file_id)
             .to_string();
         
-        println!("获取到文件元数据: 文件名={}, 大小={}字节", filename, content_length);
+        println!("获取到文件元数据: 文件名={}, 大小={}字节, 修改时间={:?}", filename, content_length, modified_at);
         
-        Ok((content_length, filename))
+        Ok(RemoteFileMeta {
+            size: content_length,
+            name: filename,
+            modified_at,
+            etag,
+        })
+    }
+    
+    // 获取服务器端按分片计算的哈希 - 调用 /download/hashes/{file_id}?chunk_size=
+    // 服务器可能使用自己的分片大小，以返回的chunk_size为准
+    pub async fn get_chunk_hashes(&self, file_id: &str, chunk_size: u64) -> Result<(u64, Vec<String>)> {
+        let request = self.http
+            .request(Method::GET, Endpoint::ChunkHashes(file_id))?
+            .query(&[("chunk_size", chunk_size)]);
+        let response = transfer_http::send(request, "获取分片哈希").await?;
+        
+        let hashes: ChunkHashesResponse = response
+            .json()
+            .await
+            .context("解析分片哈希失败")?;
+            
+        if hashes.chunk_size == 0 {
+            return Err(anyhow::anyhow!("服务器返回的分片大小无效"));
+        }
+        
+        Ok((hashes.chunk_size, hashes.hashes))
+    }
+    
+    // 新任务的分片大小（按设置和后端能力协商）
+    pub async fn negotiate_chunk_size(&self) -> u64 {
+        self.http.negotiate_chunk_size().await
+    }
+    
+    // 换一份认证信息，还是发到原来的后端
+    pub fn with_auth(&self, auth_info: AuthInfo) -> Self {
+        Self { http: self.http.with_auth(auth_info) }
     }
 }
 
@@ -266,9 +360,24 @@ pub struct DownloadTask {
     file_name: String,
     save_path: PathBuf,
     total_size: u64,
-    downloaded_size: Arc<Mutex<u64>>,
-    status: Arc<Mutex<DownloadStatus>>,
+    chunk_size: u64,
+    modified_at: Option<i64>,
+    cache_validator: Option<String>,
+    downloaded_size: AtomicU64,
+    // 状态放在ArcSwap里，查进度时不用等锁（见status/set_status/transition）
+    status: ArcSwap<DownloadStatus>,
     downloader: ChunkDownloader,
+    speed: Arc<SpeedSampler>,
+    retry_count: AtomicU32,
+    queue_seq: AtomicU64,
+    priority: Mutex<TransferPriority>,
+    chunks: Mutex<Vec<ChunkInfo>>,
+    log: Arc<TransferLog>,
+    running: Mutex<()>,   // start()执行期间一直拿着，保证同一时间只有一个start()
+    sha256: std::sync::OnceLock<String>,   // 校验时算出的哈希，同步状态库记下来
+    follow: AtomicBool,                    // 下载完后继续跟随文件增长
+    follow_stop: Notify,                   // 停止跟随或暂停时叫醒等待中的跟随
+    followed_bytes: AtomicU64,             // 跟随期间新下载的字节数（接在total_size后面）
 }
 
 impl DownloadTask {
@@ -282,7 +391,15 @@ impl DownloadTask {
         let downloader = ChunkDownloader::new(auth_info)?;
         
         // 获取文件元数据 - file_id应该包含完整的云盘路径
-        let (total_size, file_name) = downloader.get_file_metadata(&file_id).await?;
+        let remote = downloader.get_file_metadata(&file_id).await?;
+        let cache_validator = chunk_cache::validator(&remote);
+        let (total_size, file_name) = (remote.size, remote.name);
+        
+        // 有上次留下的元数据就沿用当时的分片大小，否则按设置和后端协商
+        let chunk_size = match DownloadMeta::saved_chunk_size(&save_path, &file_id, total_size).await {
+            Some(chunk_size) => chunk_size,
+            None => downloader.negotiate_chunk_size().await,
+        };
         
         // 确保保存目录存在
         if let Some(parent) = save_path.parent() {
@@ -295,59 +412,141 @@ impl DownloadTask {
             file_name,
             save_path,
             total_size,
-            downloaded_size: Arc::new(Mutex::new(0)),
-            status: Arc::new(Mutex::new(DownloadStatus::Pending)),
+            chunk_size,
+            modified_at: remote.modified_at,
+            cache_validator,
+            downloaded_size: AtomicU64::new(0),
+            status: ArcSwap::from_pointee(DownloadStatus::Pending),
             downloader,
+            speed: Arc::new(SpeedSampler::new()),
+            retry_count: AtomicU32::new(0),
+            queue_seq: AtomicU64::new(transfer_queue::next_seq()),
+            priority: Mutex::new(TransferPriority::High),
+            chunks: Mutex::new(build_chunk_list(total_size, chunk_size)),
+            log: TransferLog::new(),
+            running: Mutex::new(()),
+            sha256: std::sync::OnceLock::new(),
+            follow: AtomicBool::new(false),
+            follow_stop: Notify::new(),
+            followed_bytes: AtomicU64::new(0),
         })
     }
     
     // 开始下载（或恢复下载）
     pub async fn start(&self) -> Result<()> {
+        // 已经有一个start()在跑，不能再改状态
+        let Ok(_running) = self.running.try_lock() else {
+            return Err(TaskAlreadyRunning.into());
+        };
+        
         // 更新状态为下载中
-        *self.status.lock().await = DownloadStatus::Downloading;
+        self.set_status(DownloadStatus::Downloading);
         
         // 计算分片信息
-        let chunks_count = if self.total_size > 0 {
-            ((self.total_size as f64) / (CHUNK_SIZE as f64)).ceil() as u32
-        } else {
-            1 // 如果不知道大小，就按一个分片处理
-        };
+        let chunks_count = chunks::chunk_count(self.total_size, self.chunk_size);
+        
+        self.note(format!("开始下载文件: {}, 总分片数: {}", self.file_name, chunks_count));
         
-        println!("开始下载文件: {}, 总分片数: {}", self.file_name, chunks_count);
+        // 空文件和不超过一个分片的小文件走快速路径
+        if self.total_size <= self.chunk_size {
+            return self.download_small_file().await;
+        }
+        
+        // 写盘策略，决定多久sync一次、什么时候更新元数据
+        let write_settings = settings::get().write;
         
         // 检查哪些分片已经下载（断点续传）
-        // 如果文件已存在，检查已下载的大小，跳过已下载的分片
-        let mut starting_chunk = 0;
+        // 以元数据里确定落盘的分片为准，文件长度可能包含没有落盘的数据
+        let mut meta = match DownloadMeta::load(&self.save_path, &self.file_id, self.total_size, self.chunk_size).await {
+            Some(meta) => meta,
+            None => self.init_meta().await?,
+        };
         
-        if self.save_path.exists() {
-            let file_size = fs::metadata(&self.save_path).await
-                .context("检查已下载文件失败")?
-                .len();
-            
-            starting_chunk = (file_size / CHUNK_SIZE) as u32;
-            
-            println!("发现已下载文件: {} 字节，从分片 {} 开始继续下载", 
-                file_size, starting_chunk);
-            
-            // 更新已下载大小
-            let mut downloaded = self.downloaded_size.lock().await;
-            *downloaded = file_size;
+        // 先把元数据落盘，保证预分配出来的文件一定有元数据对应，
+        // 否则崩溃后会把整个预分配的文件当成已下载完成
+        meta.save(&self.save_path).await?;
+        
+        // 整个下载过程共用一个文件句柄，按需预分配到完整大小
+        let writer = ChunkFileWriter::open(&self.save_path, self.total_size, write_settings.preallocate).await?;
+        
+        // 按元数据逐个确定要下载的分片，中间没落盘的空洞也要补上
+        let missing_chunks = meta.missing_chunks();
+        {
+            let mut chunks = self.chunks.lock().await;
+            *chunks = build_chunk_list(self.total_size, self.chunk_size);
+            for chunk in chunks.iter_mut() {
+                if meta.is_chunk_complete(chunk.index) {
+                    chunk.state = ChunkState::Done;
+                }
+            }
+        }
+        if !meta.completed_chunks.is_empty() {
+            let already = meta.completed_bytes();
+            let holes = meta.holes();
+            if !holes.is_empty() {
+                println!("发现 {} 个未落盘的空洞分片，续传时补齐: {:?}", holes.len(), holes);
+            }
+            self.note(format!("发现已下载数据: {} 字节，还需下载 {} 个分片", already, missing_chunks.len()));
+            self.downloaded_size.store(already, Ordering::SeqCst);
         } else {
-            println!("开始新下载，文件不存在");
+            println!("开始新下载");
         }
         
+        // 已写入但还没sync的分片和写入的字节数，sync之后才记入元数据
+        let mut pending_chunks: Vec<(u32, u64)> = Vec::new();
+        let mut last_sync = Instant::now();
+        // 分片按顺序写完时顺便算整个文件的哈希，下载完不用再从头读一遍
+        let mut stream_hash = StreamingHash::new();
+        
+        // 高优先级下载运行期间，低优先级下载会让出带宽
+        let priority = self.priority().await;
+        let _priority_guard = (priority == TransferPriority::High).then(HighPriorityGuard::new);
+        
         // 分片下载，增加重试机制
-        for chunk_index in starting_chunk..chunks_count {
+        for chunk_index in missing_chunks {
+            // 低优先级任务遇到高优先级下载时先等着，等待前把已写入的分片落盘
+            if priority == TransferPriority::Low {
+                if !pending_chunks.is_empty() && transfer_manager::is_high_priority_active() {
+                    self.commit_chunks(&writer, &mut meta, &mut pending_chunks, false).await?;
+                }
+                if transfer_manager::yield_to_high_priority().await {
+                    println!("高优先级下载已结束，继续下载: {}", self.file_name);
+                }
+            }
+            
+            // 系统要睡眠或者省电模式时先停下，唤醒后再继续
+            if power::should_pause() {
+                self.commit_chunks(&writer, &mut meta, &mut pending_chunks, false).await?;
+                self.note(format!("系统睡眠/省电模式，下载暂停等待: {}", self.file_name));
+                while power::should_pause() && matches!(self.status(), DownloadStatus::Downloading) {
+                    power::wait_for_change().await;
+                }
+            }
+            
+            // 计费网络上的大文件先停下，换网络、用户允许或者暂停时才往下走
+            if network_profile::should_hold(self.total_size) {
+                self.commit_chunks(&writer, &mut meta, &mut pending_chunks, false).await?;
+                self.note("当前是计费网络，大文件暂停等待".to_string());
+                network_profile::notify_held("download", &self.file_id, &self.file_name, self.total_size);
+                while network_profile::should_hold(self.total_size)
+                    && matches!(self.status(), DownloadStatus::Downloading)
+                {
+                    network_profile::wait_for_change().await;
+                }
+            }
+            
             // 检查状态，如果暂停了就退出循环
             {
-                let status = self.status.lock().await;
-                match *status {
+                let status = self.status();
+                match status {
                     DownloadStatus::Paused => {
                         println!("下载已暂停");
+                        self.commit_chunks(&writer, &mut meta, &mut pending_chunks, false).await?;
                         return Ok(());
                     }
                     DownloadStatus::Error(_) => {
                         // 如果已经有错误，直接返回
+                        self.commit_chunks(&writer, &mut meta, &mut pending_chunks, false).await?;
                         return Ok(());
                     }
                     _ => {}
@@ -355,52 +554,58 @@ impl DownloadTask {
             }
             
             // 计算分片范围
-            let start = (chunk_index as u64) * CHUNK_SIZE;
-            let end = if chunk_index == chunks_count - 1 {
-                self.total_size - 1
-            } else {
-                start + CHUNK_SIZE - 1
-            };
+            let (start, end) = chunks::chunk_range(chunk_index, self.total_size, self.chunk_size)
+                .context(format!("分片 {} 超出文件范围", chunk_index))?;
+            
+            // 每个分片都sync的话，写完直接sync
+            let sync_each_chunk = write_settings.fsync_policy == FsyncPolicy::PerChunk;
             
             // 分片重试机制
             let mut last_error = None;
-            for retry_count in 0..3 { // 最多重试3次
-                match self.downloader.download_chunk(
-                    &self.file_id,
-                    chunk_index,
-                    start,
-                    end,
-                ).await {
+            let mut written = 0u64;
+            self.set_chunk_state(chunk_index, ChunkState::InFlight).await;
+            for retry_count in 0..CHUNK_ATTEMPTS {
+                if retry_count > 0 {
+                    self.bump_chunk_retry(chunk_index).await;
+                }
+                match self.fetch_waiting_for_server(chunk_index, start, end).await {
                     Ok(chunk_data) => {
-                        // 检查分片大小是否合理
+                        // 分片大小必须和请求的范围一致（最后一个分片的范围本来就按文件末尾算好了）。
+                        // 文件预分配过，短了会在文件里留下全是0的空洞；长了（比如服务器忽略Range返回整个文件）
+                        // 会覆盖后面分片的位置，都不能写，按失败重试
                         let expected_size = (end - start + 1) as usize;
                         let actual_size = chunk_data.len();
-                        
-                        // 最后一个分片可能小于CHUNK_SIZE，这是正常的
-                        let is_last_chunk = chunk_index == chunks_count - 1;
-                        if !is_last_chunk && actual_size != expected_size {
-                            println!("警告: 分片 {} 大小异常，期望 {} 字节，实际 {} 字节", 
-                                chunk_index, expected_size, actual_size);
-                            // 继续处理，不中断下载
+                        if actual_size != expected_size {
+                            self.note(format!("分片 {} 大小异常，期望 {} 字节，实际 {} 字节, 重试 {}/{}",
+                                chunk_index, expected_size, actual_size, retry_count + 1, CHUNK_ATTEMPTS));
+                            crate::concurrency::record_chunk(false);
+                            last_error = Some(anyhow::anyhow!("分片大小异常，期望 {} 字节，实际 {} 字节", expected_size, actual_size));
+                            tokio::time::sleep(CHUNK_RETRY_DELAY).await;
+                            continue;
                         }
                         
                         // 写入文件
-                        if let Err(e) = self.write_chunk(start, &chunk_data).await {
-                            println!("写入分片 {} 失败: {}, 重试 {}/3", chunk_index, e, retry_count + 1);
+                        if let Err(e) = self.write_chunk(&writer, start, &chunk_data, sync_each_chunk).await {
+                            self.note(format!("写入分片 {} 失败: {}, 重试 {}/{}", chunk_index, e, retry_count + 1, CHUNK_ATTEMPTS));
                             last_error = Some(e);
                             continue; // 写入失败也重试
                         }
+                        stream_hash.feed(start, &chunk_data);
+                        written = actual_size as u64;
                         
                         // 更新进度
-                        let mut downloaded = self.downloaded_size.lock().await;
-                        *downloaded += actual_size as u64;
+                        let downloaded = self.downloaded_size.fetch_add(actual_size as u64, Ordering::SeqCst) + actual_size as u64;
+                        self.speed.record(actual_size as u64);
+                        crate::bandwidth::record_downloaded(actual_size as u64).await;
+                        crate::concurrency::record_chunk(true);
+                        transfer_manager::throttle(Direction::Download, actual_size as u64).await;
                         
                         println!("分片 {}/{} 下载完成 ({}/{} 字节)，当前进度: {}/{} 字节", 
                             chunk_index + 1, 
                             chunks_count,
                             actual_size,
                             expected_size,
-                            *downloaded,
+                            downloaded,
                             self.total_size
                         );
                         
@@ -408,19 +613,49 @@ impl DownloadTask {
                         break; // 成功，跳出重试循环
                     }
                     Err(e) => {
-                        println!("下载分片 {} 失败: {}, 重试 {}/3", chunk_index, e, retry_count + 1);
+                        self.note(format!("下载分片 {} 失败: {}, 重试 {}/{}", chunk_index, e, retry_count + 1, CHUNK_ATTEMPTS));
+                        crate::concurrency::record_chunk(false);
                         last_error = Some(e);
                         // 等待一下再重试
-                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        tokio::time::sleep(CHUNK_RETRY_DELAY).await;
                     }
                 }
             }
             
             // 检查重试后是否还有错误
             if let Some(e) = last_error {
-                *self.status.lock().await = DownloadStatus::Error(format!("分片 {} 下载失败: {}", chunk_index, e));
+                // 之前写好的分片还是有效的，落盘后记下来，重试时不用重新下载
+                if let Err(commit_err) = self.commit_chunks(&writer, &mut meta, &mut pending_chunks, false).await {
+                    println!("保存下载进度失败: {}", commit_err);
+                }
+                self.set_chunk_state(chunk_index, ChunkState::Failed).await;
+                self.set_error(format!("分片 {} 下载失败: {}", chunk_index, e)).await;
                 return Err(anyhow::anyhow!("分片 {} 下载失败: {}", chunk_index, e));
             }
+            
+            // 分片已写入，按策略决定什么时候sync并更新元数据
+            self.set_chunk_state(chunk_index, ChunkState::Done).await;
+            pending_chunks.push((chunk_index, written));
+            let should_commit = match write_settings.fsync_policy {
+                FsyncPolicy::PerChunk => true,
+                FsyncPolicy::Periodic => last_sync.elapsed() >= Duration::from_secs(write_settings.fsync_interval_secs),
+                FsyncPolicy::OnComplete => false,
+            };
+            if should_commit {
+                self.commit_chunks(&writer, &mut meta, &mut pending_chunks, sync_each_chunk).await?;
+                last_sync = Instant::now();
+            }
+        }
+        
+        // 剩下没sync的分片统一落盘
+        self.commit_chunks(&writer, &mut meta, &mut pending_chunks, false).await?;
+        
+        // 元数据里还有没落盘或者没写满的分片，文件里对应的位置是空洞，不能当成下载完成
+        let holes = meta.missing_chunks();
+        if !holes.is_empty() {
+            let error_msg = format!("还有 {} 个分片没有下载: {:?}", holes.len(), holes);
+            self.set_error(error_msg.clone()).await;
+            return Err(anyhow::anyhow!(error_msg));
         }
         
         // 下载完成，验证文件完整性
@@ -433,19 +668,23 @@ impl DownloadTask {
         
         if file_size != self.total_size {
             let error_msg = format!("文件大小不匹配: 期望 {} 字节，实际 {} 字节", self.total_size, file_size);
-            println!("错误: {}", error_msg);
-            *self.status.lock().await = DownloadStatus::Error(error_msg.clone());
+            self.set_error(error_msg.clone()).await;
             return Err(anyhow::anyhow!(error_msg));
         }
         
         println!("文件大小验证通过: {} 字节", file_size);
         
-        // 尝试计算文件哈希进行基本校验
+        // 计算文件哈希进行基本校验，边下载边算过的部分不用再读
         // 注意：这个校验只是本地校验，无法验证与服务器端是否一致
-        match calculate_file_hash(&self.save_path).await {
-            Ok(hash) => {
+        match self.verify_hash(stream_hash).await {
+            Ok(Some(hash)) => {
                 println!("文件SHA256哈希: {}", hash);
-                // 这里可以记录哈希值，将来可以与服务器端对比
+                let _ = self.sha256.set(hash);
+            }
+            Ok(None) => {
+                // 校验途中暂停了，元数据还在，下次开始时直接校验
+                println!("校验已暂停: {}", self.file_name);
+                return Ok(());
             }
             Err(e) => {
                 println!("警告: 无法计算文件哈希: {}", e);
@@ -453,90 +692,316 @@ impl DownloadTask {
             }
         }
         
+        // 下载完成，不再需要元数据
+        DownloadMeta::remove(&self.save_path).await;
+        self.apply_modified_time().await;
+        
         // 更新状态为完成
-        *self.status.lock().await = DownloadStatus::Completed;
-        println!("文件下载和验证完成: {}", self.file_name);
+        self.set_status(DownloadStatus::Completed);
+        self.note(format!("文件下载和验证完成: {}", self.file_name));
         
         Ok(())
     }
     
-    // 写入分片到文件
-    async fn write_chunk(&self, offset: u64, data: &[u8]) -> Result<()> {
-        // 确保父目录存在
-        if let Some(parent) = self.save_path.parent() {
-            if !parent.exists() {
-                fs::create_dir_all(parent).await
-                    .context(format!("创建父目录失败: {:?}", parent))?;
+    // 把边下载边算的哈希补完：从没算到的位置开始读文件，状态改成Verifying并更新进度
+    // 中途暂停或出错时返回None
+    async fn verify_hash(&self, mut hash: StreamingHash) -> Result<Option<String>> {
+        if hash.hashed() < self.total_size {
+            println!("从 {} 字节处开始补算哈希（之前的分片不是按顺序下载的）", hash.hashed());
+        }
+        let mut file = File::open(&self.save_path).await
+            .context("打开文件失败")?;
+        loop {
+            let percent = (hash.hashed() * 100 / self.total_size.max(1)) as u8;
+            let verifying = |s: &DownloadStatus| matches!(s, DownloadStatus::Downloading | DownloadStatus::Verifying(_));
+            if !self.transition(verifying, DownloadStatus::Verifying(percent)) {
+                return Ok(None);
+            }
+            if hash.hashed() >= self.total_size {
+                return Ok(Some(hash.finish()));
             }
+            let until = hash.hashed().saturating_add(VERIFY_STEP).min(self.total_size);
+            hash.read_from(&mut file, until).await?;
         }
+    }
+    
+    // 空文件和不超过一个分片的小文件：一次请求下载完，直接写入文件
+    // 空文件不发请求；不写元数据也不预分配，只有一个分片，失败了重新下载和续传的代价一样
+    async fn download_small_file(&self) -> Result<()> {
+        let data = if self.total_size == 0 {
+            println!("空文件，直接创建: {:?}", self.save_path);
+            Vec::new()
+        } else {
+            self.set_chunk_state(0, ChunkState::InFlight).await;
+            match self.fetch_small_file().await {
+                Ok(data) => data,
+                Err(e) => {
+                    self.set_chunk_state(0, ChunkState::Failed).await;
+                    self.set_error(format!("下载失败: {}", e)).await;
+                    return Err(e);
+                }
+            }
+        };
         
-        // 如果文件不存在，创建新文件
-        if !self.save_path.exists() {
-            File::create(&self.save_path).await
-                .context(format!("创建文件失败: {:?}", self.save_path))?;
+        if let Err(e) = fs::write(&self.save_path, &data).await {
+            self.set_error(format!("写入文件失败: {}", e)).await;
+            return Err(anyhow::anyhow!("写入文件失败: {}", e));
         }
+        // 之前按别的分片大小下载了一半留下的元数据已经没用了
+        DownloadMeta::remove(&self.save_path).await;
+        self.apply_modified_time().await;
         
-        // 以读写模式打开文件，允许追加
-        let mut file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .open(&self.save_path)
-            .await
-            .context(format!("打开文件失败: {:?}, offset: {}", self.save_path, offset))?;
+        if !data.is_empty() {
+            self.set_chunk_state(0, ChunkState::Done).await;
+            self.downloaded_size.store(data.len() as u64, Ordering::SeqCst);
+            self.speed.record(data.len() as u64);
+            crate::bandwidth::record_downloaded(data.len() as u64).await;
+        }
         
-        // 获取当前文件大小
-        let file_size = file.metadata().await
-            .context("获取文件元数据失败")?
-            .len();
+        self.set_status(DownloadStatus::Completed);
+        self.note(format!("小文件下载完成: {}，{} 字节", self.file_name, data.len()));
+        Ok(())
+    }
+    
+    // 把服务器上的修改时间设置到下载好的文件上，同步时靠它判断文件有没有变化
+    // 服务器没给修改时间就保持"现在"；设置失败不影响下载结果
+    async fn apply_modified_time(&self) {
+        let modified_at = match self.modified_at {
+            Some(modified_at) => modified_at,
+            None => return,
+        };
+        if let Err(e) = set_modified_time(&self.save_path, modified_at).await {
+            println!("设置文件修改时间失败: {:?} - {}", self.save_path, e);
+        }
+    }
+    
+    // 请求分片，后端维护中（503 + Retry-After）时等到恢复时间再请求，这样的失败不算重试次数
+    async fn fetch_waiting_for_server(&self, chunk_index: u32, start: u64, end: u64) -> Result<Vec<u8>> {
+        loop {
+            let result = self.downloader
+                .fetch_chunk(&self.file_id, chunk_index, start, end, self.cache_validator.as_deref())
+                .await;
+            match (result, server_maintenance::resume_at()) {
+                (Err(_), Some(resume_at)) if self.wait_for_server(resume_at).await => continue,
+                (result, _) => return result,
+            }
+        }
+    }
+    
+    // 状态改成WaitingForServer，等到后端恢复再改回下载中；已经暂停或出错时不等，返回false
+    async fn wait_for_server(&self, resume_at: i64) -> bool {
+        let running = |s: &DownloadStatus| matches!(s, DownloadStatus::Downloading | DownloadStatus::WaitingForServer(_));
+        if !self.transition(running, DownloadStatus::WaitingForServer(resume_at)) {
+            return false;
+        }
+        self.note(format!("后端维护中，等到 {} 再继续下载", resume_at));
+        server_maintenance::wait().await;
+        self.transition(|s| matches!(s, DownloadStatus::WaitingForServer(_)), DownloadStatus::Downloading);
+        true
+    }
+    
+    // 一次请求下载整个小文件，失败或者大小不对时重试
+    async fn fetch_small_file(&self) -> Result<Vec<u8>> {
+        let mut last_error = anyhow::anyhow!("下载失败");
+        for retry_count in 0..CHUNK_ATTEMPTS {
+            if retry_count > 0 {
+                self.bump_chunk_retry(0).await;
+            }
+            match self.fetch_waiting_for_server(0, 0, self.total_size - 1).await {
+                Ok(data) if data.len() as u64 == self.total_size => return Ok(data),
+                Ok(data) => {
+                    last_error = anyhow::anyhow!("文件大小不匹配: 期望 {} 字节，实际 {} 字节", self.total_size, data.len());
+                }
+                Err(e) => last_error = e,
+            }
+            println!("下载文件 {} 失败: {}, 重试 {}/{}", self.file_name, last_error, retry_count + 1, CHUNK_ATTEMPTS);
+            tokio::time::sleep(CHUNK_RETRY_DELAY).await;
+        }
+        Err(last_error)
+    }
+    
+    // 没有可用的元数据时初始化
+    // 兼容旧版本：本地已有文件但没有元数据，按文件长度推算已下载的分片
+    async fn init_meta(&self) -> Result<DownloadMeta> {
+        let mut meta = DownloadMeta::new(&self.file_id, self.total_size, self.chunk_size);
         
-        // 检查offset是否合理
-        // offset应该 <= file_size，否则说明有分片间隙
-        // 但这种情况在断点续传中可能发生（比如之前下载中断了）
-        if offset > file_size {
-            println!("警告: offset {} > 文件大小 {}，可能存在分片间隙，扩展文件", offset, file_size);
-            // 这里不处理，seek会扩展文件
+        if !self.save_path.exists() {
+            return Ok(meta);
         }
         
-        // 移动到指定位置
-        file.seek(std::io::SeekFrom::Start(offset)).await
-            .context("移动文件指针失败")?;
+        let meta_path = DownloadMeta::meta_path(&self.save_path);
+        if meta_path.exists() {
+            // 元数据存在但对不上，说明服务器上的文件变了，本地数据不能用
+            println!("本地数据与服务器文件不一致，清空后重新下载: {:?}", self.save_path);
+            fs::write(&self.save_path, b"").await
+                .context("清空旧文件失败")?;
+            return Ok(meta);
+        }
         
-        // 验证当前位置是否正确
-        let actual_pos = file.stream_position().await
-            .context("获取当前位置失败")?;
-        if actual_pos != offset {
-            return Err(anyhow::anyhow!(
-                "文件位置不匹配: 期望 {}，实际 {}", 
-                offset, 
-                actual_pos
-            ));
+        let file_size = fs::metadata(&self.save_path).await
+            .context("检查已下载文件失败")?
+            .len();
+        let completed = std::cmp::min(file_size / self.chunk_size, self.total_size / self.chunk_size) as u32;
+        for index in 0..completed {
+            meta.record_chunk(index, self.chunk_size);
         }
         
-        // 写入数据
-        file.write_all(data).await
-            .context("写入文件失败")?;
+        println!("发现没有元数据的已下载文件: {} 字节，视为已完成 {} 个分片", file_size, completed);
+        Ok(meta)
+    }
+    
+    // 把已写入的分片sync到磁盘，然后再记入元数据
+    // already_synced为true表示write_chunk里已经sync过了
+    async fn commit_chunks(
+        &self,
+        writer: &ChunkFileWriter,
+        meta: &mut DownloadMeta,
+        pending: &mut Vec<(u32, u64)>,
+        already_synced: bool,
+    ) -> Result<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
         
-        // 确保数据写入磁盘
-        file.flush().await
-            .context("刷新文件失败")?;
+        if !already_synced {
+            writer.sync_data().await?;
+        }
         
-        // 验证写入后的文件大小
-        let new_file_size = file.metadata().await
-            .context("获取更新后的文件元数据失败")?
-            .len();
+        for (index, written) in pending.drain(..) {
+            meta.record_chunk(index, written);
+        }
+        meta.save(&self.save_path).await
+    }
+    
+    // 写入分片到文件
+    // 直接按偏移量写入共用的文件句柄，sync为true时写完立即把数据sync到磁盘
+    async fn write_chunk(&self, writer: &ChunkFileWriter, offset: u64, data: &[u8], sync: bool) -> Result<()> {
+        writer.write_at(offset, data).await?;
         
-        let expected_new_size = std::cmp::max(offset + data.len() as u64, file_size);
-        if new_file_size < expected_new_size {
-            println!("警告: 写入后文件大小 {} < 期望大小 {}", new_file_size, expected_new_size);
+        if sync {
+            writer.sync_data().await?;
         }
         
         Ok(())
     }
     
+    // 当前状态
+    fn status(&self) -> DownloadStatus {
+        (**self.status.load()).clone()
+    }
+    
+    fn set_status(&self, status: DownloadStatus) {
+        self.status.store(Arc::new(status));
+    }
+    
+    // 当前状态满足from时改成to，返回有没有改
+    // 判断和修改是一次原子操作，不会把同时发生的暂停覆盖掉
+    fn transition(&self, from: impl Fn(&DownloadStatus) -> bool, to: DownloadStatus) -> bool {
+        let previous = self.status.rcu(|current| {
+            if from(current) { Arc::new(to.clone()) } else { Arc::clone(current) }
+        });
+        from(&previous)
+    }
+    
     // 暂停下载
     pub async fn pause(&self) {
-        *self.status.lock().await = DownloadStatus::Paused;
-        println!("下载已暂停");
+        self.set_status(DownloadStatus::Paused);
+        self.follow_stop.notify_one();
+        self.note("下载已暂停".to_string());
+    }
+    
+    // 打开或关闭跟随模式，关闭时正在跟随的任务停下来，状态变成Completed
+    pub fn set_follow(&self, follow: bool) {
+        self.follow.store(follow, Ordering::SeqCst);
+        if !follow {
+            self.follow_stop.notify_one();
+        }
+    }
+    
+    pub fn follow_enabled(&self) -> bool {
+        self.follow.load(Ordering::SeqCst)
+    }
+    
+    // 跟随文件增长（见思考5），下载完成（Completed）后调用，停止跟随或暂停后返回
+    pub async fn follow(&self) -> Result<()> {
+        let Ok(_running) = self.running.try_lock() else {
+            return Err(TaskAlreadyRunning.into());
+        };
+        if !self.transition(|s| matches!(s, DownloadStatus::Completed), DownloadStatus::Following) {
+            return Ok(());
+        }
+        self.note(format!("开始跟随文件增长: {}", self.file_name));
+        
+        let writer = ChunkFileWriter::open(&self.save_path, 0, false).await?;
+        let mut local_size = self.total_size + self.followed_bytes.load(Ordering::SeqCst);
+        let mut interval = FOLLOW_INTERVAL;
+        let following = || matches!(self.status(), DownloadStatus::Following);
+        while self.follow_enabled() && following() {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = self.follow_stop.notified() => continue,
+            }
+            if !self.follow_enabled() || !following() {
+                break;
+            }
+            
+            // 只在这次检查期间保持蓝牙连接，检查完就关掉会话
+            let _session = crate::cpen_device_manager::open_session();
+            let downloader = match crate::acquire_auth_info().await {
+                Ok(auth_info) => self.downloader.with_auth(auth_info),
+                Err(e) => {
+                    println!("跟随 {} 时获取认证信息失败: {}，下次再试", self.file_name, e);
+                    continue;
+                }
+            };
+            match self.fetch_tail(&downloader, &writer, local_size).await {
+                Ok(size) => {
+                    interval = if size > local_size { FOLLOW_INTERVAL } else { (interval * 2).min(FOLLOW_MAX_INTERVAL) };
+                    local_size = size;
+                }
+                // 文件变小了，fetch_tail已经把状态改成了Error
+                Err(e) if matches!(self.status(), DownloadStatus::Error(_)) => return Err(e),
+                // 网络问题等下次检查再试，不结束跟随
+                Err(e) => println!("跟随 {} 时下载新增内容失败: {:#}，下次再试", self.file_name, e),
+            }
+        }
+        
+        // 暂停的保持暂停，重试（重新创建任务）时下载完接着跟随；只有停止跟随才算完成
+        if self.transition(|s| matches!(s, DownloadStatus::Following), DownloadStatus::Completed) {
+            self.note(format!("停止跟随: {}，共 {} 字节", self.file_name, local_size));
+        } else {
+            self.note(format!("跟随已暂停: {}，共 {} 字节", self.file_name, local_size));
+        }
+        Ok(())
+    }
+    
+    // 查一次云端大小，把local_size之后新增的部分按分片大小下载下来，返回新的本地大小
+    async fn fetch_tail(&self, downloader: &ChunkDownloader, writer: &ChunkFileWriter, local_size: u64) -> Result<u64> {
+        let remote = downloader.get_file_metadata(&self.file_id).await?;
+        if remote.size < local_size {
+            let message = format!("云端文件变小了（{} -> {} 字节），可能被截断或轮转，停止跟随", local_size, remote.size);
+            self.set_error(message.clone()).await;
+            return Err(anyhow::anyhow!(message));
+        }
+        
+        let mut offset = local_size;
+        while offset < remote.size {
+            let end = offset.saturating_add(self.chunk_size).min(remote.size) - 1;
+            let data = downloader.download_chunk(&self.file_id, 0, offset, end).await?;
+            if data.is_empty() {
+                break;
+            }
+            writer.write_at(offset, &data).await?;
+            offset += data.len() as u64;
+            self.followed_bytes.fetch_add(data.len() as u64, Ordering::SeqCst);
+            self.speed.record(data.len() as u64);
+            crate::bandwidth::record_downloaded(data.len() as u64).await;
+        }
+        if offset > local_size {
+            writer.sync_data().await?;
+            println!("{} 增长了 {} 字节，现在 {} 字节", self.file_name, offset - local_size, offset);
+        }
+        Ok(offset)
     }
     
     // 验证文件完整性 - 公开方法，可以在下载后调用
@@ -570,34 +1035,261 @@ impl DownloadTask {
         Ok(true)
     }
     
-    // 获取下载进度
-    pub async fn get_progress(&self) -> DownloadProgress {
-        let downloaded = *self.downloaded_size.lock().await;
-        let status = self.status.lock().await.clone();
+    // 本地已经有完整且和服务器一致的文件时，直接把任务标记为完成，返回true
+    // 有下载元数据说明是没下完的文件，要续传，不算已下载
+    // 服务器给不了分片哈希时，退回比较大小和修改时间（下载完成时会把修改时间设成服务器上的）
+    pub async fn complete_if_already_downloaded(&self) -> bool {
+        if DownloadMeta::meta_path(&self.save_path).exists() {
+            return false;
+        }
+        let metadata = match fs::metadata(&self.save_path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return false,
+        };
+        if metadata.len() != self.total_size {
+            return false;
+        }
         
-        let chunks_total = if self.total_size > 0 {
-            ((self.total_size as f64) / (CHUNK_SIZE as f64)).ceil() as u32
-        } else {
-            0
+        let verified = match self.downloader.get_chunk_hashes(&self.file_id, self.chunk_size).await {
+            Ok((chunk_size, hashes)) => {
+                match find_corrupt_chunks(&self.save_path, self.total_size, chunk_size, &hashes).await {
+                    Ok(corrupt_chunks) => corrupt_chunks.is_empty(),
+                    Err(e) => {
+                        println!("校验本地文件失败，重新下载: {}", e);
+                        false
+                    }
+                }
+            }
+            Err(e) => {
+                println!("获取分片哈希失败，按大小和修改时间判断是否已下载: {}", e);
+                let local_modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs() as i64);
+                self.modified_at.is_some() && self.modified_at == local_modified
+            }
         };
+        if !verified {
+            return false;
+        }
         
-        let chunks_completed = if self.total_size > 0 {
-            ((downloaded as f64) / (self.total_size as f64) * (chunks_total as f64)) as u32
-        } else {
-            0
+        for chunk in self.chunks.lock().await.iter_mut() {
+            chunk.state = ChunkState::Done;
+        }
+        self.downloaded_size.store(self.total_size, Ordering::SeqCst);
+        self.set_status(DownloadStatus::Completed);
+        println!("文件已下载且与服务器一致，跳过下载: {:?}", self.save_path);
+        true
+    }
+    
+    // 校验并修复已下载的文件
+    // 按服务器给的分片哈希逐个校验本地文件，只重新下载校验不通过的分片
+    // 用于杀毒软件或磁盘问题损坏了已下载文件的情况
+    pub async fn verify_and_repair(&self) -> Result<RepairReport> {
+        // start()/follow()还在写这个文件时不能修复
+        let Ok(_running) = self.running.try_lock() else {
+            return Err(TaskAlreadyRunning.into());
         };
+        if !self.save_path.exists() {
+            return Err(anyhow::anyhow!("文件不存在: {:?}", self.save_path));
+        }
+        
+        self.set_status(DownloadStatus::Downloading);
+        let result = self.repair_corrupt_chunks().await;
+        if result.is_ok() {
+            // 重新写入分片会改掉修改时间
+            self.apply_modified_time().await;
+        }
+        match &result {
+            Ok(report) => {
+                self.note(format!("校验完成，修复了 {} 个分片", report.corrupt_chunks.len()));
+                self.set_status(DownloadStatus::Completed);
+            }
+            Err(e) => self.set_error(format!("修复文件失败: {}", e)).await,
+        }
+        result
+    }
+    
+    async fn repair_corrupt_chunks(&self) -> Result<RepairReport> {
+        let (chunk_size, hashes) = self.downloader.get_chunk_hashes(&self.file_id, self.chunk_size).await?;
+        let chunks_count = chunks::chunk_count(self.total_size, chunk_size);
+        
+        println!("开始校验文件: {}，分片大小 {} 字节，共 {} 个分片", self.file_name, chunk_size, chunks_count);
+        
+        // 文件长度不对时先调整到完整大小，多出来或缺少的部分会在下面校验不通过
+        let writer = ChunkFileWriter::open(&self.save_path, self.total_size, true).await?;
+        let corrupt_chunks = find_corrupt_chunks(&self.save_path, self.total_size, chunk_size, &hashes).await?;
+        
+        println!("校验完成: {} 个分片损坏", corrupt_chunks.len());
+        
+        let mut repaired_bytes = 0u64;
+        for &chunk_index in &corrupt_chunks {
+            let (start, end) = chunks::chunk_range(chunk_index, self.total_size, chunk_size)
+                .context(format!("分片 {} 超出文件范围", chunk_index))?;
+            
+            let mut last_error = None;
+            for retry_count in 0..CHUNK_ATTEMPTS {
+                let chunk_data = match self.downloader.download_chunk(&self.file_id, chunk_index, start, end).await {
+                    Ok(data) => data,
+                    Err(e) => {
+                        println!("重新下载分片 {} 失败: {}, 重试 {}/{}", chunk_index, e, retry_count + 1, CHUNK_ATTEMPTS);
+                        last_error = Some(e);
+                        tokio::time::sleep(CHUNK_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                
+                // 下载到的数据也要校验，避免把错误数据写进去
+                let hash = hex_encode(Sha256::digest(&chunk_data));
+                if !hash.eq_ignore_ascii_case(&hashes[chunk_index as usize]) {
+                    println!("重新下载的分片 {} 校验不通过, 重试 {}/{}", chunk_index, retry_count + 1, CHUNK_ATTEMPTS);
+                    last_error = Some(anyhow::anyhow!("分片 {} 数据与服务器哈希不一致", chunk_index));
+                    continue;
+                }
+                
+                writer.write_at(start, &chunk_data).await?;
+                self.speed.record(chunk_data.len() as u64);
+                crate::bandwidth::record_downloaded(chunk_data.len() as u64).await;
+                repaired_bytes += chunk_data.len() as u64;
+                last_error = None;
+                break;
+            }
+            
+            if let Some(e) = last_error {
+                writer.sync_data().await?;
+                return Err(anyhow::anyhow!("修复分片 {} 失败: {}", chunk_index, e));
+            }
+        }
+        
+        writer.sync_data().await?;
+        self.downloaded_size.store(self.total_size, Ordering::SeqCst);
+        
+        println!("文件修复完成: {}，重新下载 {} 字节", self.file_name, repaired_bytes);
+        Ok(RepairReport {
+            chunks_total: chunks_count,
+            corrupt_chunks,
+            repaired_bytes,
+        })
+    }
+    
+    // 获取下载进度
+    pub async fn get_progress(&self) -> DownloadProgress {
+        let downloaded = self.downloaded_size.load(Ordering::SeqCst);
+        let status = self.status();
+        
+        let chunks_total = chunks::chunk_count(self.total_size, self.chunk_size);
+        let chunks_completed = chunks::completed_chunks(downloaded, self.total_size, self.chunk_size);
+        
+        // 跟随期间新下载的部分两边都加上
+        let followed = self.followed_bytes.load(Ordering::SeqCst);
         
         DownloadProgress {
             file_id: self.file_id.clone(),
             file_name: self.file_name.clone(),
-            total_size: self.total_size,
-            downloaded,
+            total_size: self.total_size + followed,
+            downloaded: downloaded + followed,
             status,
             chunks_total,
             chunks_completed,
-            speed_kbps: 0.0, // 暂时不计算速度，先实现基本功能
+            chunk_size: self.chunk_size,
+            speed_kbps: self.speed.current_speed_kbps(),
+            retry_count: self.retry_count.load(Ordering::SeqCst),
+            priority: *self.priority.lock().await,
         }
     }
+    
+    pub fn file_id(&self) -> &str {
+        &self.file_id
+    }
+    
+    pub fn save_path(&self) -> &Path {
+        &self.save_path
+    }
+    
+    // 校验时算出的SHA256，小文件和校验失败的没有；跟随模式下文件变长以后就不对了，也没有
+    pub fn sha256(&self) -> Option<&str> {
+        if self.followed_bytes.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+        self.sha256.get().map(String::as_str)
+    }
+    
+    // start()是不是还在执行（暂停以后要等当前的分片写完才返回）
+    pub fn is_running(&self) -> bool {
+        self.running.try_lock().is_err()
+    }
+    
+    // 重试时新建的任务要延续之前的重试次数
+    pub fn set_retry_count(&self, count: u32) {
+        self.retry_count.store(count, Ordering::SeqCst);
+    }
+    
+    pub fn retry_count(&self) -> u32 {
+        self.retry_count.load(Ordering::SeqCst)
+    }
+    
+    // 排队序号，重启后按这个顺序恢复
+    pub fn queue_seq(&self) -> u64 {
+        self.queue_seq.load(Ordering::SeqCst)
+    }
+    
+    // 重试或恢复时新建的任务沿用原来的序号
+    pub fn set_queue_seq(&self, seq: u64) {
+        self.queue_seq.store(seq, Ordering::SeqCst);
+    }
+    
+    // 重试时新建的任务接着写之前任务的日志
+    pub fn inherit_log(&mut self, previous: &DownloadTask) {
+        self.log = previous.log.clone();
+    }
+    
+    // 任务日志，最近的在后
+    pub fn log_lines(&self) -> Vec<LogLine> {
+        self.log.lines()
+    }
+    
+    // 输出日志并记到任务日志里
+    pub fn note(&self, message: String) {
+        println!("{}", message);
+        self.log.push(&message);
+    }
+    
+    // 标记为出错，错误原因记到任务日志里
+    async fn set_error(&self, message: String) {
+        self.note(format!("下载出错: {}", message));
+        self.set_status(DownloadStatus::Error(message));
+    }
+    
+    // 获取每个分片的状态
+    pub async fn get_chunks(&self) -> Vec<ChunkInfo> {
+        self.chunks.lock().await.clone()
+    }
+    
+    async fn set_chunk_state(&self, index: u32, state: ChunkState) {
+        if let Some(chunk) = self.chunks.lock().await.get_mut(index as usize) {
+            chunk.state = state;
+        }
+    }
+    
+    async fn bump_chunk_retry(&self, index: u32) {
+        if let Some(chunk) = self.chunks.lock().await.get_mut(index as usize) {
+            chunk.retry_count += 1;
+        }
+    }
+    
+    pub async fn set_priority(&self, priority: TransferPriority) {
+        *self.priority.lock().await = priority;
+    }
+    
+    pub async fn priority(&self) -> TransferPriority {
+        *self.priority.lock().await
+    }
+    
+    // 获取速度历史（每秒一个采样点），给前端画速度曲线用
+    pub fn get_speed_history(&self) -> Vec<SpeedSample> {
+        self.speed.history()
+    }
 }
 
 // 工具函数：获取应用数据目录
@@ -624,6 +1316,109 @@ pub async fn get_app_data_dir() -> Result<PathBuf> {
     Ok(download_dir)
 }
 
+// 设置文件的修改时间（Unix时间戳，秒）
+pub async fn set_modified_time(path: &Path, modified_at: i64) -> Result<()> {
+    let time = if modified_at >= 0 {
+        std::time::UNIX_EPOCH + Duration::from_secs(modified_at as u64)
+    } else {
+        std::time::UNIX_EPOCH - Duration::from_secs(modified_at.unsigned_abs())
+    };
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(time))
+            .context(format!("设置文件修改时间失败: {:?}", path))
+    })
+    .await
+    .context("设置文件修改时间失败")?
+}
+
+// 按服务器给的分片哈希逐个校验本地文件，返回校验不通过的分片
+// 文件比total_size短时读取会失败，调用前要保证长度
+async fn find_corrupt_chunks(path: &Path, total_size: u64, chunk_size: u64, hashes: &[String]) -> Result<Vec<u32>> {
+    if chunk_size == 0 {
+        return Err(anyhow::anyhow!("服务器返回的分片大小无效: 0"));
+    }
+    let chunks_count = chunks::chunk_count(total_size, chunk_size);
+    if hashes.len() != chunks_count as usize {
+        return Err(anyhow::anyhow!(
+            "分片哈希数量不对: 期望 {} 个，服务器返回 {} 个", chunks_count, hashes.len()
+        ));
+    }
+    
+    let mut file = File::open(path).await
+        .context("打开文件失败")?;
+    let mut corrupt_chunks = Vec::new();
+    let mut buffer = vec![0u8; chunk_size.min(total_size) as usize];
+    
+    for chunk_index in 0..chunks_count {
+        let start = chunk_index as u64 * chunk_size;
+        let len = chunks::chunk_len(chunk_index, total_size, chunk_size) as usize;
+        
+        file.seek(std::io::SeekFrom::Start(start)).await
+            .context("定位文件失败")?;
+        file.read_exact(&mut buffer[..len]).await
+            .context("读取文件失败")?;
+        
+        let hash = hex_encode(Sha256::digest(&buffer[..len]));
+        if !hash.eq_ignore_ascii_case(&hashes[chunk_index as usize]) {
+            println!("分片 {} 校验不通过", chunk_index);
+            corrupt_chunks.push(chunk_index);
+        }
+    }
+    Ok(corrupt_chunks)
+}
+
+// 下载完成后补算哈希时，每读这么多更新一次校验进度
+const VERIFY_STEP: u64 = 8 * 1024 * 1024;
+
+// 边下载边算整个文件的SHA256
+// 分片按顺序到达时接着往下算；前面有没算到的地方（续传时之前下载的部分、补下的空洞）就停在那里，
+// 下载完成后从文件里把剩下的部分读出来补算（见DownloadTask::verify_hash）
+struct StreamingHash {
+    hasher: Sha256,
+    hashed: u64,   // 从文件开头算到了哪里
+}
+
+impl StreamingHash {
+    fn new() -> Self {
+        Self { hasher: Sha256::new(), hashed: 0 }
+    }
+
+    fn hashed(&self) -> u64 {
+        self.hashed
+    }
+
+    // 分片刚好接在已经算到的位置后面才算，否则留给下载完成后补算
+    fn feed(&mut self, offset: u64, data: &[u8]) {
+        if offset == self.hashed {
+            self.hasher.update(data);
+            self.hashed += data.len() as u64;
+        }
+    }
+
+    // 从文件里读出[hashed, until)接着算
+    async fn read_from(&mut self, file: &mut File, until: u64) -> Result<()> {
+        file.seek(std::io::SeekFrom::Start(self.hashed)).await
+            .context("定位文件失败")?;
+        let mut buffer = vec![0u8; 1024 * 1024];
+        while self.hashed < until {
+            let len = (until - self.hashed).min(buffer.len() as u64) as usize;
+            file.read_exact(&mut buffer[..len]).await
+                .context("读取文件失败")?;
+            self.hasher.update(&buffer[..len]);
+            self.hashed += len as u64;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> String {
+        hex_encode(self.hasher.finalize())
+    }
+}
+
 // 工具函数：计算文件SHA256哈希
 pub async fn calculate_file_hash(path: &Path) -> Result<String> {
     let mut file = File::open(path).await
@@ -644,4 +1439,362 @@ pub async fn calculate_file_hash(path: &Path) -> Result<String> {
     }
     
     Ok(hex_encode(hasher.finalize()))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_backend;
+    
+    const CHUNK_SIZE: u64 = chunks::DEFAULT_CHUNK_SIZE;
+
+    // 生成测试文件内容，不是全0，错位写入能被发现
+    fn test_content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    // 2.5个分片大小，最后一个分片不满
+    fn test_size() -> usize {
+        (CHUNK_SIZE * 2 + CHUNK_SIZE / 2) as usize
+    }
+
+    #[tokio::test]
+    async fn downloads_file_in_ranged_chunks() {
+        mock_backend::start();
+        let path = "tests/download/ranged.bin";
+        let content = test_content(test_size());
+        mock_backend::put_file(path, content.clone());
+
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("ranged.bin");
+        let task = DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth())
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        assert_eq!(fs::read(&save_path).await.unwrap(), content);
+        assert_eq!(mock_backend::requests(path), vec![0, CHUNK_SIZE, CHUNK_SIZE * 2]);
+        assert!(matches!(task.get_progress().await.status, DownloadStatus::Completed));
+    }
+
+    #[tokio::test]
+    async fn follows_appended_data() {
+        mock_backend::start();
+        let path = "tests/download/follow.log";
+        let content = test_content((CHUNK_SIZE + CHUNK_SIZE / 2) as usize);
+        mock_backend::put_file(path, content[..100].to_vec());
+
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("follow.log");
+        let task = DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth())
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        // 文件追加了一个多分片，新增部分分两次请求接在后面
+        mock_backend::put_file(path, content.clone());
+        let downloader = task.downloader.with_auth(mock_backend::valid_auth());
+        let writer = ChunkFileWriter::open(&save_path, 0, false).await.unwrap();
+        let size = task.fetch_tail(&downloader, &writer, 100).await.unwrap();
+        assert_eq!(size, content.len() as u64);
+        assert_eq!(fs::read(&save_path).await.unwrap(), content);
+        assert_eq!(task.get_progress().await.total_size, content.len() as u64);
+        assert!(task.sha256().is_none());
+
+        // 没有变化时什么都不做
+        assert_eq!(task.fetch_tail(&downloader, &writer, size).await.unwrap(), size);
+
+        // 文件被截断，停止跟随
+        mock_backend::put_file(path, content[..10].to_vec());
+        assert!(task.fetch_tail(&downloader, &writer, size).await.is_err());
+        assert!(matches!(task.get_progress().await.status, DownloadStatus::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn resumes_from_committed_chunks() {
+        mock_backend::start();
+        let path = "tests/download/resume.bin";
+        let content = test_content(test_size());
+        mock_backend::put_file(path, content.clone());
+
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("resume.bin");
+
+        // 第三个分片一直失败，前两个分片应该已经落盘
+        mock_backend::fail_from(path, CHUNK_SIZE * 2);
+        let task = DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth())
+            .await
+            .unwrap();
+        assert!(task.start().await.is_err());
+        assert!(DownloadMeta::meta_path(&save_path).exists());
+
+        // 恢复后重新创建任务，只需要下载第三个分片
+        mock_backend::clear_faults(path);
+        let task = DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth())
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        assert_eq!(fs::read(&save_path).await.unwrap(), content);
+        assert_eq!(mock_backend::requests(path), vec![0, CHUNK_SIZE, CHUNK_SIZE * 2]);
+        assert!(!DownloadMeta::meta_path(&save_path).exists());
+    }
+
+    #[tokio::test]
+    async fn resume_keeps_chunk_size_from_meta() {
+        mock_backend::start();
+        let path = "tests/download/old_chunk_size.bin";
+        let content = test_content(test_size());
+        mock_backend::put_file(path, content.clone());
+
+        // 上次用最小分片下载了前两个分片，和现在的默认分片大小不一样
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("old_chunk_size.bin");
+        let old_chunk_size = chunks::MIN_CHUNK_SIZE;
+        fs::write(&save_path, &content[..(old_chunk_size * 2) as usize]).await.unwrap();
+        let mut meta = DownloadMeta::new(path, content.len() as u64, old_chunk_size);
+        meta.record_chunk(0, old_chunk_size);
+        meta.record_chunk(1, old_chunk_size);
+        meta.save(&save_path).await.unwrap();
+
+        let task = DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth())
+            .await
+            .unwrap();
+        assert_eq!(task.get_progress().await.chunk_size, old_chunk_size);
+        task.start().await.unwrap();
+
+        assert_eq!(fs::read(&save_path).await.unwrap(), content);
+        let expected: Vec<u64> = (2..chunks::chunk_count(content.len() as u64, old_chunk_size) as u64)
+            .map(|index| index * old_chunk_size)
+            .collect();
+        assert_eq!(mock_backend::requests(path), expected);
+    }
+
+    #[tokio::test]
+    async fn fills_holes_left_by_out_of_order_chunks() {
+        mock_backend::start();
+        let path = "tests/download/holes.bin";
+        let content = test_content(test_size());
+        mock_backend::put_file(path, content.clone());
+
+        // 第一和第三个分片已经落盘，中间的分片还是预分配出来的0
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("holes.bin");
+        let mut partial = vec![0u8; content.len()];
+        partial[..CHUNK_SIZE as usize].copy_from_slice(&content[..CHUNK_SIZE as usize]);
+        partial[(CHUNK_SIZE * 2) as usize..].copy_from_slice(&content[(CHUNK_SIZE * 2) as usize..]);
+        fs::write(&save_path, &partial).await.unwrap();
+        let mut meta = DownloadMeta::new(path, content.len() as u64, CHUNK_SIZE);
+        meta.record_chunk(0, CHUNK_SIZE);
+        meta.record_chunk(2, chunks::chunk_len(2, content.len() as u64, CHUNK_SIZE));
+        meta.save(&save_path).await.unwrap();
+
+        let task = DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth())
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        // 只补下载中间的空洞，后面已经落盘的分片不重新下载
+        assert_eq!(fs::read(&save_path).await.unwrap(), content);
+        assert_eq!(mock_backend::requests(path), vec![CHUNK_SIZE]);
+        assert!(!DownloadMeta::meta_path(&save_path).exists());
+        assert!(matches!(task.get_progress().await.status, DownloadStatus::Completed));
+    }
+
+    #[tokio::test]
+    async fn rejects_short_range_responses() {
+        mock_backend::start();
+        let path = "tests/download/short.bin";
+        let content = test_content(test_size());
+        mock_backend::put_file(path, content.clone());
+
+        // 第二个分片开始的响应都只有一半，不能写进文件，也不能算下载完成
+        mock_backend::truncate_from(path, CHUNK_SIZE);
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("short.bin");
+        let task = DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth())
+            .await
+            .unwrap();
+        assert!(task.start().await.is_err());
+        assert!(matches!(task.get_progress().await.status, DownloadStatus::Error(_)));
+        let meta = DownloadMeta::load(&save_path, path, content.len() as u64, CHUNK_SIZE).await.unwrap();
+        assert_eq!(meta.missing_chunks(), vec![1, 2]);
+
+        // 上次记下的分片没写满时，续传要重新下载它
+        mock_backend::clear_faults(path);
+        let mut meta = DownloadMeta::new(path, content.len() as u64, CHUNK_SIZE);
+        meta.record_chunk(0, CHUNK_SIZE);
+        meta.record_chunk(1, CHUNK_SIZE / 2);
+        meta.save(&save_path).await.unwrap();
+        let task = DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth())
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+        assert_eq!(fs::read(&save_path).await.unwrap(), content);
+        assert!(matches!(task.get_progress().await.status, DownloadStatus::Completed));
+    }
+
+    #[tokio::test]
+    async fn retries_flaky_chunk_requests() {
+        mock_backend::start();
+        let path = "tests/download/flaky.bin";
+        let content = test_content(test_size());
+        mock_backend::put_file(path, content.clone());
+
+        // 连续失败两次，第三次重试成功
+        mock_backend::fail_next(path, 2);
+
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("flaky.bin");
+        let task = DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth())
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        assert_eq!(fs::read(&save_path).await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn downloads_empty_file() {
+        mock_backend::start();
+        let path = "tests/download/empty.bin";
+        mock_backend::put_file(path, Vec::new());
+
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("empty.bin");
+        let task = DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth())
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        assert_eq!(fs::read(&save_path).await.unwrap(), Vec::<u8>::new());
+        assert!(mock_backend::requests(path).is_empty());
+        assert_eq!(task.get_progress().await.chunks_total, 0);
+    }
+
+    #[tokio::test]
+    async fn applies_remote_modified_time() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        // 小文件和多分片文件两条路径都要设置
+        for (name, size) in [("mtime_small.bin", 100), ("mtime_large.bin", test_size())] {
+            let path = format!("tests/download/{}", name);
+            mock_backend::put_file(&path, test_content(size));
+            mock_backend::set_modified(&path, 1_600_000_000);
+
+            let save_path = dir.path().join(name);
+            let task = DownloadTask::new(path.clone(), save_path.clone(), mock_backend::valid_auth())
+                .await
+                .unwrap();
+            task.start().await.unwrap();
+
+            let modified = std::fs::metadata(&save_path).unwrap().modified().unwrap();
+            assert_eq!(modified, std::time::UNIX_EPOCH + Duration::from_secs(1_600_000_000), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_file_that_is_already_downloaded() {
+        mock_backend::start();
+        let path = "tests/download/already.bin";
+        let content = test_content(test_size());
+        mock_backend::put_file(path, content.clone());
+        mock_backend::set_modified(path, 1_600_000_000);
+
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("already.bin");
+        let new_task = || DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth());
+
+        // 还没下载过
+        assert!(!new_task().await.unwrap().complete_if_already_downloaded().await);
+        new_task().await.unwrap().start().await.unwrap();
+        let downloaded_requests = mock_backend::requests(path).len();
+
+        // 测试后端没有分片哈希接口，按大小和修改时间判断
+        let task = new_task().await.unwrap();
+        assert!(task.complete_if_already_downloaded().await);
+        assert!(matches!(task.get_progress().await.status, DownloadStatus::Completed));
+        assert_eq!(task.get_progress().await.downloaded, content.len() as u64);
+        assert_eq!(mock_backend::requests(path).len(), downloaded_requests);
+
+        // 本地文件被改过就重新下载
+        set_modified_time(&save_path, 1_700_000_000).await.unwrap();
+        assert!(!new_task().await.unwrap().complete_if_already_downloaded().await);
+
+        // 有下载元数据的是没下完的文件
+        set_modified_time(&save_path, 1_600_000_000).await.unwrap();
+        DownloadMeta::new(path, content.len() as u64, CHUNK_SIZE).save(&save_path).await.unwrap();
+        assert!(!new_task().await.unwrap().complete_if_already_downloaded().await);
+    }
+
+    #[tokio::test]
+    async fn downloads_tiny_file_in_one_request() {
+        mock_backend::start();
+        let path = "tests/download/tiny.bin";
+        let content = test_content(100);
+        mock_backend::put_file(path, content.clone());
+
+        // 上次留下的旧数据和元数据会被覆盖
+        let dir = tempfile::tempdir().unwrap();
+        let save_path = dir.path().join("tiny.bin");
+        fs::write(&save_path, vec![0u8; 500]).await.unwrap();
+        DownloadMeta::new(path, 100, CHUNK_SIZE).save(&save_path).await.unwrap();
+
+        let task = DownloadTask::new(path.to_string(), save_path.clone(), mock_backend::valid_auth())
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        assert_eq!(fs::read(&save_path).await.unwrap(), content);
+        assert_eq!(mock_backend::requests(path), vec![0]);
+        assert!(!DownloadMeta::meta_path(&save_path).exists());
+        let progress = task.get_progress().await;
+        assert_eq!((progress.downloaded, progress.chunks_completed), (100, 1));
+    }
+
+    #[tokio::test]
+    async fn finishes_streaming_hash_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hash.bin");
+        let content = test_content(3000);
+        fs::write(&path, &content).await.unwrap();
+
+        // 分片0按顺序到达，分片2先于分片1到达，只能算到分片0结束的位置
+        let mut hash = StreamingHash::new();
+        hash.feed(0, &content[..1000]);
+        hash.feed(2000, &content[2000..]);
+        assert_eq!(hash.hashed(), 1000);
+
+        let mut file = File::open(&path).await.unwrap();
+        hash.read_from(&mut file, 3000).await.unwrap();
+        assert_eq!(hash.finish(), calculate_file_hash(&path).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_credentials() {
+        mock_backend::start();
+        let path = "tests/download/auth.bin";
+        mock_backend::put_file(path, test_content(1024));
+
+        let dir = tempfile::tempdir().unwrap();
+        let result = DownloadTask::new(path.to_string(), dir.path().join("auth.bin"), mock_backend::invalid_auth()).await;
+
+        let error = result.err().expect("认证失败时不应该创建任务").to_string();
+        assert!(error.contains("401"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_file() {
+        mock_backend::start();
+
+        let dir = tempfile::tempdir().unwrap();
+        let result = DownloadTask::new(
+            "tests/download/missing.bin".to_string(),
+            dir.path().join("missing.bin"),
+            mock_backend::valid_auth(),
+        )
+        .await;
+
+        let error = result.err().expect("文件不存在时不应该创建任务").to_string();
+        assert!(error.contains("文件不存在"), "{}", error);
+    }
+}
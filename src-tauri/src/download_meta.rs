@@ -0,0 +1,255 @@
+// 下载元数据模块
+// 每个下载中的文件旁边放一个 <文件名>.camfc.json，记录哪些分片已经确定写入磁盘
+//
+// 思考：原来断点续传直接看本地文件有多大，但write_chunk只flush不sync，
+// 崩溃/断电后文件长度可能包含还在系统缓存里没落盘的数据，续传时会把它当成已下载。
+// 现在只有在数据sync到磁盘以后才更新这个文件，续传以它为准。
+//
+// 分片不一定按顺序落盘：预分配的文件中间是全0，不预分配时写到文件末尾之后会留下稀疏的空洞，
+// 只看文件长度或者"从0开始连续完成了多少个"都会把空洞当成已下载的数据，或者把后面已经下载好的分片重新下载一遍。
+// 所以续传按completed_chunks逐个判断：没记录的分片（中间的空洞和末尾没下载的）都要补下载，
+// 全部分片都记录了才算下载完成。
+//
+// 响应比请求的范围短时，预分配的文件里这个分片后半截还是0，光记"落盘了"看不出来。
+// 所以written_bytes同时记下每个分片实际写了多少字节，等于分片长度才算这个分片下载完成，
+// 不够的和没记录的一样要重新下载。
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
+use tokio::fs;
+
+use crate::chunks;
+
+// 元数据文件后缀
+pub const META_SUFFIX: &str = ".camfc.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadMeta {
+    pub file_id: String,
+    pub total_size: u64,
+    pub chunk_size: u64,
+    pub completed_chunks: BTreeSet<u32>,  // 已经确定落盘的分片
+    // 每个落盘的分片实际写入的字节数；旧版本的元数据没有这个字段，读取时按分片长度补上
+    #[serde(default)]
+    pub written_bytes: BTreeMap<u32, u64>,
+}
+
+impl DownloadMeta {
+    pub fn new(file_id: &str, total_size: u64, chunk_size: u64) -> Self {
+        Self {
+            file_id: file_id.to_string(),
+            total_size,
+            chunk_size,
+            completed_chunks: BTreeSet::new(),
+            written_bytes: BTreeMap::new(),
+        }
+    }
+
+    // 记下一个已经落盘的分片和实际写入的字节数
+    pub fn record_chunk(&mut self, index: u32, written: u64) {
+        self.completed_chunks.insert(index);
+        self.written_bytes.insert(index, written);
+    }
+
+    // 分片已经落盘，而且写满了整个分片
+    pub fn is_chunk_complete(&self, index: u32) -> bool {
+        self.completed_chunks.contains(&index)
+            && self.written_bytes.get(&index) == Some(&chunks::chunk_len(index, self.total_size, self.chunk_size))
+    }
+
+    // 下载文件对应的元数据文件路径
+    pub fn meta_path(save_path: &Path) -> PathBuf {
+        let mut name = save_path.as_os_str().to_os_string();
+        name.push(META_SUFFIX);
+        PathBuf::from(name)
+    }
+
+    // 读取元数据，不存在或者和当前任务对不上（服务器文件变了）就返回None
+    pub async fn load(save_path: &Path, file_id: &str, total_size: u64, chunk_size: u64) -> Option<Self> {
+        let meta = Self::read(save_path, file_id, total_size).await?;
+        if meta.chunk_size != chunk_size {
+            println!("下载元数据的分片大小 {} 与当前任务 {} 不一致，重新下载: {:?}",
+                meta.chunk_size, chunk_size, Self::meta_path(save_path));
+            return None;
+        }
+        Some(meta)
+    }
+
+    // 上次下载这个文件时用的分片大小，续传时必须沿用
+    // 默认分片大小或者设置改了以后，已完成的分片序号才不会对不上
+    pub async fn saved_chunk_size(save_path: &Path, file_id: &str, total_size: u64) -> Option<u64> {
+        let meta = Self::read(save_path, file_id, total_size).await?;
+        if !chunks::is_valid_chunk_size(meta.chunk_size) {
+            println!("下载元数据里的分片大小无效，忽略: {}", meta.chunk_size);
+            return None;
+        }
+        Some(meta.chunk_size)
+    }
+
+    // 读取并检查文件ID和大小
+    async fn read(save_path: &Path, file_id: &str, total_size: u64) -> Option<Self> {
+        let path = Self::meta_path(save_path);
+        let content = fs::read_to_string(&path).await.ok()?;
+
+        let mut meta: DownloadMeta = match serde_json::from_str(&content) {
+            Ok(meta) => meta,
+            Err(e) => {
+                println!("下载元数据格式错误，忽略: {:?} - {}", path, e);
+                return None;
+            }
+        };
+
+        if meta.file_id != file_id || meta.total_size != total_size {
+            println!("下载元数据与当前任务不匹配（文件可能已变化），重新下载: {:?}", path);
+            return None;
+        }
+
+        // 旧版本没有记每个分片写了多少，只能相信已经记录的分片是完整的
+        if meta.written_bytes.is_empty() {
+            let (total_size, chunk_size) = (meta.total_size, meta.chunk_size);
+            meta.written_bytes = meta.completed_chunks.iter()
+                .map(|&index| (index, chunks::chunk_len(index, total_size, chunk_size)))
+                .collect();
+        }
+
+        Some(meta)
+    }
+
+    // 保存元数据，先写临时文件再重命名，避免写一半崩溃留下坏文件
+    pub async fn save(&self, save_path: &Path) -> Result<()> {
+        let path = Self::meta_path(save_path);
+        let mut tmp_name = path.as_os_str().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+
+        let content = serde_json::to_string(self).context("序列化下载元数据失败")?;
+
+        let mut file = fs::File::create(&tmp_path).await
+            .context(format!("创建下载元数据失败: {:?}", tmp_path))?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, content.as_bytes()).await
+            .context("写入下载元数据失败")?;
+        file.sync_all().await.context("同步下载元数据失败")?;
+        drop(file);
+
+        fs::rename(&tmp_path, &path).await
+            .context(format!("保存下载元数据失败: {:?}", path))?;
+        Ok(())
+    }
+
+    // 下载完成后删除元数据
+    pub async fn remove(save_path: &Path) {
+        let path = Self::meta_path(save_path);
+        if path.exists() {
+            if let Err(e) = fs::remove_file(&path).await {
+                println!("删除下载元数据失败: {:?} - {}", path, e);
+            }
+        }
+    }
+
+    // 还没有落盘或者没写满的分片，续传时都要下载
+    // 元数据里超出文件分片总数的序号不算
+    pub fn missing_chunks(&self) -> Vec<u32> {
+        let total = chunks::chunk_count(self.total_size, self.chunk_size);
+        (0..total).filter(|&index| !self.is_chunk_complete(index)).collect()
+    }
+
+    // 空洞：后面已经有完整的分片落盘，自己却还没下载完的分片
+    pub fn holes(&self) -> Vec<u32> {
+        let last_completed = match self.completed_chunks.iter().rev().find(|&&index| self.is_chunk_complete(index)) {
+            Some(&last) => last,
+            None => return Vec::new(),
+        };
+        self.missing_chunks()
+            .into_iter()
+            .take_while(|&index| index < last_completed)
+            .collect()
+    }
+
+    // 完整落盘的分片的字节数，续传时用来恢复已下载大小
+    pub fn completed_bytes(&self) -> u64 {
+        self.completed_chunks
+            .iter()
+            .filter(|&&index| self.is_chunk_complete(index))
+            .map(|&index| chunks::chunk_len(index, self.total_size, self.chunk_size))
+            .sum()
+    }
+
+    // 所有分片都已经落盘（只有测试用，下载流程按missing_chunks判断）
+    #[cfg(test)]
+    pub fn is_complete(&self) -> bool {
+        self.missing_chunks().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        // 待下载的分片和已完成的分片正好覆盖整个文件，空洞都在最后一个已完成分片之前
+        #[test]
+        fn missing_chunks_cover_rest_of_file(
+            total_size in 0u64..10_000_000,
+            chunk_size in 1u64..1_000_000,
+            completed in proptest::collection::btree_set(0u32..64, 0..64),
+        ) {
+            let mut meta = DownloadMeta::new("file", total_size, chunk_size);
+            for index in completed {
+                meta.record_chunk(index, chunks::chunk_len(index, total_size, chunk_size));
+            }
+
+            let total = chunks::chunk_count(total_size, chunk_size);
+            let missing = meta.missing_chunks();
+            for index in 0..total {
+                prop_assert_ne!(missing.contains(&index), meta.completed_chunks.contains(&index));
+            }
+            prop_assert!(missing.iter().all(|&index| index < total));
+            prop_assert!(meta.completed_bytes() <= total_size);
+            prop_assert_eq!(meta.is_complete(), missing.is_empty());
+
+            for hole in meta.holes() {
+                prop_assert!(missing.contains(&hole));
+                prop_assert!(meta.completed_chunks.range(hole..).next().is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn finds_holes_between_completed_chunks() {
+        let mut meta = DownloadMeta::new("file", 10 * 100, 100);
+        for index in [0, 2, 3, 6] {
+            meta.record_chunk(index, 100);
+        }
+
+        assert_eq!(meta.missing_chunks(), vec![1, 4, 5, 7, 8, 9]);
+        assert_eq!(meta.holes(), vec![1, 4, 5]);
+        assert_eq!(meta.completed_bytes(), 400);
+        assert!(!meta.is_complete());
+
+        for index in [1, 4, 5, 7, 8, 9] {
+            meta.record_chunk(index, 100);
+        }
+        assert!(meta.holes().is_empty());
+        assert!(meta.is_complete());
+    }
+
+    #[test]
+    fn short_chunks_are_not_complete() {
+        // 250字节分成100/100/50，最后一个分片只要50字节
+        let mut meta = DownloadMeta::new("file", 250, 100);
+        meta.record_chunk(0, 100);
+        meta.record_chunk(1, 60);
+        meta.record_chunk(2, 50);
+
+        assert_eq!(meta.missing_chunks(), vec![1]);
+        assert_eq!(meta.holes(), vec![1]);
+        assert_eq!(meta.completed_bytes(), 150);
+        assert!(!meta.is_complete());
+
+        meta.record_chunk(1, 100);
+        assert!(meta.is_complete());
+    }
+}
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
-use std::sync::OnceLock;
-use tauri::Emitter;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use tauri::{Emitter, EventTarget};
 
 static APP_HANDLE: OnceLock<tauri::AppHandle> = OnceLock::new();
 
@@ -27,3 +28,147 @@ pub fn emit_button_event(event_type: &str) {
         let _ = handle.emit("button-event", event);
     }
 }
+
+pub fn emit_event<S: Serialize + Clone>(event: &str, payload: S) {
+    if let Some(handle) = get_app_handle() {
+        if let Err(e) = handle.emit(event, payload) {
+            eprintln!("发送事件 {} 失败: {}", event, e);
+        }
+    }
+}
+
+// 按窗口订阅传输事件
+// 主窗口之外还可能开一个小的进度窗口，原来所有事件都发给全部窗口，每个窗口都要自己过滤。
+// 窗口调用subscribe_transfer_events登记自己关心的事件/方向/任务后，
+// 传输事件只发给过滤条件匹配的窗口；没订阅的窗口照常收到全部事件（兼容现在的前端）。
+// 注意：订阅后要用窗口自己的listen（getCurrentWebviewWindow().listen）监听，
+// 全局的listen不区分窗口，还是会收到全部事件。
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransferEventFilter {
+    #[serde(default)]
+    pub events: Option<Vec<String>>,    // 只要这些事件，不填表示全部传输事件
+    #[serde(default)]
+    pub direction: Option<String>,      // "download"/"upload"
+    #[serde(default)]
+    pub ids: Option<Vec<String>>,       // 只要这些任务（下载的file_id或上传的upload_id）
+}
+
+impl TransferEventFilter {
+    // 事件是否符合过滤条件
+    // 指定了方向或任务时，不针对单个任务的事件（比如transfers-restored）不发
+    fn matches(&self, event: &str, payload: &serde_json::Value) -> bool {
+        if let Some(events) = &self.events {
+            if !events.iter().any(|e| e == event) {
+                return false;
+            }
+        }
+        if let Some(direction) = &self.direction {
+            if payload.get("direction").and_then(|d| d.as_str()) != Some(direction.as_str()) {
+                return false;
+            }
+        }
+        if let Some(ids) = &self.ids {
+            match payload.get("id").and_then(|id| id.as_str()) {
+                Some(id) if ids.iter().any(|i| i == id) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+static SUBSCRIPTIONS: OnceLock<Mutex<HashMap<String, TransferEventFilter>>> = OnceLock::new();
+
+fn subscriptions() -> &'static Mutex<HashMap<String, TransferEventFilter>> {
+    SUBSCRIPTIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn subscribe(window_label: &str, filter: TransferEventFilter) {
+    subscriptions().lock().unwrap().insert(window_label.to_string(), filter);
+}
+
+// 取消订阅，窗口恢复成收到全部事件；窗口关闭时也会调用
+pub fn unsubscribe(window_label: &str) -> bool {
+    subscriptions().lock().unwrap().remove(window_label).is_some()
+}
+
+// 订阅了的窗口按过滤条件决定，没订阅的窗口和全局监听照常发
+fn target_wants(subs: &HashMap<String, TransferEventFilter>, target: &EventTarget, event: &str, payload: &serde_json::Value) -> bool {
+    let label = match target {
+        EventTarget::Window { label } | EventTarget::Webview { label } | EventTarget::WebviewWindow { label } => label,
+        _ => return true,
+    };
+    subs.get(label).is_none_or(|filter| filter.matches(event, payload))
+}
+
+// 发送传输事件（传输状态变化、计费网络暂停、队列恢复等），按窗口订阅过滤
+pub fn emit_transfer_event(event: &str, payload: serde_json::Value) {
+    if let Some(handle) = get_app_handle() {
+        let subs = subscriptions().lock().unwrap().clone();
+        let result = if subs.is_empty() {
+            handle.emit(event, payload)
+        } else {
+            let filter_payload = payload.clone();
+            handle.emit_filter(event, payload, |target| target_wants(&subs, target, event, &filter_payload))
+        };
+        if let Err(e) = result {
+            eprintln!("发送事件 {} 失败: {}", event, e);
+        }
+    }
+}
+
+/// 订阅传输事件
+/// 
+/// window_label是窗口的label（比如"main"、"float"），filter里的events/direction/ids都可以不填，
+/// 不填的条件不过滤。重复调用会替换之前的过滤条件。窗口关闭时自动取消订阅。
+#[tauri::command]
+pub async fn subscribe_transfer_events(window_label: String, filter: Option<TransferEventFilter>) -> Result<(), String> {
+    println!("窗口 {} 订阅传输事件: {:?}", window_label, filter);
+    subscribe(&window_label, filter.unwrap_or_default());
+    Ok(())
+}
+
+/// 取消订阅传输事件，窗口恢复成收到全部事件
+/// 
+/// 返回值：之前有没有订阅
+#[tauri::command]
+pub async fn unsubscribe_transfer_events(window_label: String) -> Result<bool, String> {
+    Ok(unsubscribe(&window_label))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_by_event_direction_and_id() {
+        let filter = TransferEventFilter {
+            events: Some(vec!["transfer-status".to_string()]),
+            direction: Some("upload".to_string()),
+            ids: Some(vec!["u1".to_string()]),
+        };
+        let payload = serde_json::json!({"direction": "upload", "id": "u1", "status": "completed"});
+        assert!(filter.matches("transfer-status", &payload));
+        assert!(!filter.matches("metered-transfer-held", &payload));
+        assert!(!filter.matches("transfer-status", &serde_json::json!({"direction": "upload", "id": "u2"})));
+        assert!(!filter.matches("transfer-status", &serde_json::json!({"direction": "download", "id": "u1"})));
+        // 指定了任务时，不针对单个任务的事件不发
+        assert!(!filter.matches("transfer-status", &serde_json::json!({"restored": []})));
+    }
+
+    #[test]
+    fn unsubscribed_windows_get_everything() {
+        let mut subs = HashMap::new();
+        subs.insert("float".to_string(), TransferEventFilter {
+            ids: Some(vec!["a".to_string()]),
+            ..Default::default()
+        });
+        let payload = serde_json::json!({"direction": "download", "id": "b"});
+        let float = EventTarget::WebviewWindow { label: "float".to_string() };
+        let main = EventTarget::WebviewWindow { label: "main".to_string() };
+        assert!(!target_wants(&subs, &float, "transfer-status", &payload));
+        assert!(target_wants(&subs, &main, "transfer-status", &payload));
+        assert!(target_wants(&subs, &EventTarget::Any, "transfer-status", &payload));
+    }
+}
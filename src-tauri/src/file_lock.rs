@@ -0,0 +1,128 @@
+// 文件占用检测
+// 上传前检查文件是不是正在被别的程序编辑（Word/Excel打开的文档），
+// 保存时正好读到一半，传上去的就是一个坏文件，同步时还会覆盖掉云端的好文件。
+//
+// 思考：Windows上Office打开文档时不允许别人写入，我们用"只共享读"的方式打开文件：
+// 已经有别的进程带写权限打开着，打开就会失败（ERROR_SHARING_VIOLATION），说明文件正在被编辑。
+// macOS/Linux上文件锁只是建议性的，没法可靠判断，当作没被占用。
+// 检测到占用后怎么办由设置决定：直接失败、等一会儿再试、或者复制一份快照上传快照。
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+
+// 等待文件释放时的检查间隔
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// 快照放在临时目录下的这个子目录里
+pub const SNAPSHOT_DIR_NAME: &str = "snapshots";
+
+// Windows的共享冲突错误码（ERROR_SHARING_VIOLATION / ERROR_LOCK_VIOLATION）
+#[cfg(windows)]
+pub fn is_sharing_violation(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(32) | Some(33))
+}
+
+#[cfg(not(windows))]
+pub fn is_sharing_violation(_error: &io::Error) -> bool {
+    false
+}
+
+// 用"只共享读"的方式打开，别的进程正在写时会失败
+#[cfg(windows)]
+fn open_deny_write(path: &Path) -> io::Result<std::fs::File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_SHARE_READ: u32 = 0x1;
+    std::fs::OpenOptions::new()
+        .read(true)
+        .share_mode(FILE_SHARE_READ)
+        .open(path)
+}
+
+#[cfg(not(windows))]
+fn open_deny_write(path: &Path) -> io::Result<std::fs::File> {
+    std::fs::File::open(path)
+}
+
+// 文件是否正在被别的程序占用
+// 打不开的其他原因（不存在、没权限）不算占用，交给后面读文件时报错
+pub async fn is_in_use(path: &Path) -> bool {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || match open_deny_write(&path) {
+        Ok(_) => false,
+        Err(e) => is_sharing_violation(&e),
+    })
+    .await
+    .unwrap_or(false)
+}
+
+// 等文件被释放，最多等timeout，释放了返回true
+pub async fn wait_until_free(path: &Path, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if !is_in_use(path).await {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        println!("文件正在被其他程序使用，{:?}后再检查: {:?}", WAIT_POLL_INTERVAL, path);
+        tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+    }
+}
+
+// 复制一份快照到临时目录（settings::scratch_dir下的snapshots），返回的临时文件drop时自动删除
+// 用默认的共享方式（允许别人读写）打开源文件，对方只禁止写入时也能复制
+pub async fn snapshot(path: &Path) -> Result<tempfile::NamedTempFile> {
+    snapshot_in(path, crate::settings::scratch_subdir(SNAPSHOT_DIR_NAME)).await
+}
+
+// 复制快照到dir下
+async fn snapshot_in(path: &Path, dir: PathBuf) -> Result<tempfile::NamedTempFile> {
+    let source: PathBuf = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut reader = std::fs::File::open(&source)
+            .context(format!("文件被其他程序独占，无法复制快照: {:?}", source))?;
+        std::fs::create_dir_all(&dir).context(format!("创建快照目录失败: {:?}", dir))?;
+        let mut snapshot = tempfile::NamedTempFile::new_in(&dir).context("创建快照临时文件失败")?;
+        io::copy(&mut reader, snapshot.as_file_mut()).context("复制文件快照失败")?;
+        println!("文件正在被其他程序使用，已复制快照: {:?} -> {:?}", source, snapshot.path());
+        Ok(snapshot)
+    })
+    .await
+    .context("复制文件快照失败")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unused_file_is_free() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        assert!(!is_in_use(file.path()).await);
+        assert!(wait_until_free(file.path(), Duration::ZERO).await);
+        // 不存在的文件不算占用
+        assert!(!is_in_use(Path::new("/definitely/not/here.docx")).await);
+    }
+
+    #[tokio::test]
+    async fn snapshot_copies_content() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        io::Write::write_all(&mut file, b"report").unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let copy = snapshot_in(file.path(), dir.path().to_path_buf()).await.unwrap();
+        assert!(copy.path().starts_with(dir.path()));
+        assert_ne!(copy.path(), file.path());
+        assert_eq!(std::fs::read(copy.path()).unwrap(), b"report");
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn detects_file_open_for_writing() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let _writer = std::fs::OpenOptions::new().write(true).open(file.path()).unwrap();
+        assert!(is_in_use(file.path()).await);
+    }
+}
@@ -0,0 +1,197 @@
+// 分片文件写入模块
+// 整个下载过程共用一个文件句柄，按偏移量直接写（positional write）
+//
+// 思考：原来每写一个分片都要open一次文件、seek、write、再查一遍文件大小，
+// 续传时offset超过文件长度还会打"分片间隙"的警告。
+// 现在任务开始时把文件预分配到total_size，之后每个分片直接写到自己的位置，
+// 不需要seek，也不会出现间隙。同一个区域同时只允许一个写入，防止并发分片互相覆盖。
+// Windows上杀毒软件（Defender）扫描正在下载的文件时，写入偶尔会遇到共享冲突，
+// 过一会儿就好了，原来这样的分片直接失败。现在暂时性的IO错误（共享冲突、锁冲突、被中断）
+// 按WRITE_RETRY_DELAY开始翻倍等待，最多尝试WRITE_ATTEMPTS次，其他错误（磁盘满、没权限）还是直接失败。
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{Result, Context};
+
+use crate::file_lock::is_sharing_violation;
+
+// 暂时性IO错误最多尝试几次
+const WRITE_ATTEMPTS: u32 = 5;
+// 第一次重试前等多久，之后每次翻倍
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+pub struct ChunkFileWriter {
+    path: PathBuf,
+    file: Arc<std::fs::File>,
+    // 正在写入的区域 (start, end)，左闭右开
+    busy_regions: Mutex<BTreeSet<(u64, u64)>>,
+}
+
+impl ChunkFileWriter {
+    // 打开（或创建）目标文件，preallocate为true时把文件扩展到total_size
+    pub async fn open(path: &Path, total_size: u64, preallocate: bool) -> Result<Self> {
+        let path_buf = path.to_path_buf();
+        let file = tokio::task::spawn_blocking(move || -> Result<std::fs::File> {
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path_buf)
+                .context(format!("打开文件失败: {:?}", path_buf))?;
+
+            if preallocate {
+                let current_len = file.metadata().context("获取文件元数据失败")?.len();
+                if current_len != total_size {
+                    file.set_len(total_size)
+                        .context(format!("预分配文件空间失败: {:?}, 大小: {}", path_buf, total_size))?;
+                    println!("已预分配文件空间: {:?}, {} 字节", path_buf, total_size);
+                }
+            }
+
+            Ok(file)
+        })
+            .await
+            .context("文件操作任务异常退出")??;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Arc::new(file),
+            busy_regions: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    // 占用一个区域，和正在写入的区域有重叠就报错
+    fn claim_region(&self, start: u64, end: u64) -> Result<()> {
+        let mut regions = self.busy_regions.lock().unwrap();
+        let overlaps = regions.iter().any(|&(s, e)| start < e && s < end);
+        if overlaps {
+            return Err(anyhow::anyhow!("区域 {}-{} 正在被其他分片写入", start, end));
+        }
+        regions.insert((start, end));
+        Ok(())
+    }
+
+    fn release_region(&self, start: u64, end: u64) {
+        self.busy_regions.lock().unwrap().remove(&(start, end));
+    }
+
+    // 把数据写到offset位置
+    pub async fn write_at(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let end = offset + data.len() as u64;
+        self.claim_region(offset, end)?;
+
+        let file = self.file.clone();
+        let buffer = data.to_vec();
+        let result = retry_transient(move || write_all_at(&file, &buffer, offset)).await;
+
+        self.release_region(offset, end);
+
+        result?.context(format!("写入文件失败: {:?}, offset: {}", self.path, offset))?;
+        Ok(())
+    }
+
+    // 把已写入的数据sync到磁盘
+    pub async fn sync_data(&self) -> Result<()> {
+        let file = self.file.clone();
+        retry_transient(move || file.sync_data())
+            .await?
+            .context(format!("同步文件数据到磁盘失败: {:?}", self.path))?;
+        Ok(())
+    }
+}
+
+// 过一会儿可能就好了的IO错误
+fn is_transient_io_error(error: &std::io::Error) -> bool {
+    is_sharing_violation(error)
+        || matches!(error.kind(), std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock)
+}
+
+// 在阻塞线程里执行op，遇到暂时性错误时等一会儿重试
+// 外层的错误是线程任务异常退出，内层是op最后一次的结果
+async fn retry_transient<F>(op: F) -> Result<std::io::Result<()>>
+where
+    F: Fn() -> std::io::Result<()> + Send + Sync + 'static,
+{
+    let op = Arc::new(op);
+    let mut delay = WRITE_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let attempt_op = op.clone();
+        let result = tokio::task::spawn_blocking(move || attempt_op())
+            .await
+            .context("文件写入任务异常退出")?;
+        match result {
+            Err(e) if attempt < WRITE_ATTEMPTS && is_transient_io_error(&e) => {
+                println!("文件暂时无法写入: {}，{} ms后重试 {}/{}", e, delay.as_millis(), attempt, WRITE_ATTEMPTS);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return Ok(result),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &std::fs::File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(data, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &std::fs::File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    // seek_write可能只写一部分，需要循环写完
+    let mut written = 0usize;
+    while written < data.len() {
+        let n = file.seek_write(&data[written..], offset + written as u64)?;
+        if n == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "写入0字节"));
+        }
+        written += n;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn retries_transient_errors_only() {
+        // 前两次被打断，第三次成功
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = calls.clone();
+        let result = retry_transient(move || match counter.fetch_add(1, Ordering::SeqCst) {
+            0 | 1 => Err(Error::from(ErrorKind::Interrupted)),
+            _ => Ok(()),
+        }).await.unwrap();
+        assert!(result.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // 没权限不重试
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = calls.clone();
+        let result = retry_transient(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Err(Error::from(ErrorKind::PermissionDenied))
+        }).await.unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // 一直失败，最多尝试WRITE_ATTEMPTS次
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = calls.clone();
+        let result = retry_transient(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Err(Error::from(ErrorKind::WouldBlock))
+        }).await.unwrap();
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), WRITE_ATTEMPTS);
+    }
+}
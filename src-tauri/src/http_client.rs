@@ -0,0 +1,203 @@
+// HTTP客户端模块
+// 上传和下载共用一个reqwest客户端，连接参数从设置里读取
+//
+// 思考：原来每个ChunkDownloader/ChunkUploader都自己建一个Client，
+// 连接池没法复用，每个任务都要重新握手。现在统一从这里拿共享的客户端，
+// 设置里的网络参数变了才重新创建。
+// 不同的后端（直连、反向代理、是否支持HTTP/2）最优的参数不一样，
+// 所以提供一个测速功能，实际请求几次后端，看哪种配置最快。
+// 所有请求都带同一个User-Agent（应用名+版本+系统），后端能区分不同版本的客户端。
+// 用的是后端配置（见backend_profiles.rs）时，按配置里的代理和CA证书创建客户端，切换配置后重新创建。
+
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use anyhow::{Result, Context};
+use reqwest::Client;
+
+use crate::config;
+use crate::settings::{self, BackendProfile, NetworkSettings};
+
+// 请求超时时间
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// 测速时每种配置请求的次数
+const BENCHMARK_ROUNDS: usize = 8;
+// 测速时同时发出的请求数
+const BENCHMARK_CONCURRENCY: usize = 4;
+
+// 当前共享的客户端，以及创建它时使用的网络设置和后端配置
+type ClientKey = (NetworkSettings, Option<BackendProfile>);
+static SHARED_CLIENT: OnceLock<Mutex<Option<(ClientKey, Client)>>> = OnceLock::new();
+
+// 默认的User-Agent，如 CAMFC-client/0.1.0 (windows; x86_64)
+pub fn default_user_agent() -> String {
+    format!(
+        "CAMFC-client/{} ({}; {})",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+    )
+}
+
+// 设置里指定了就用设置里的
+pub fn user_agent(network: &NetworkSettings) -> String {
+    match network.user_agent.trim() {
+        "" => default_user_agent(),
+        custom => custom.to_string(),
+    }
+}
+
+// 按网络设置和当前的后端配置创建客户端
+pub fn build_client(network: &NetworkSettings) -> Result<Client> {
+    build_client_for(network, config::current_profile().as_ref())
+}
+
+fn build_client_for(network: &NetworkSettings, profile: Option<&BackendProfile>) -> Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent(user_agent(network))
+        .timeout(REQUEST_TIMEOUT)
+        .pool_max_idle_per_host(network.pool_max_idle_per_host)
+        .tcp_nodelay(network.tcp_nodelay);
+
+    if network.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+
+    if let Some(profile) = profile {
+        if let Some(proxy) = profile.proxy.as_deref().map(str::trim).filter(|p| !p.is_empty()) {
+            let proxy = reqwest::Proxy::all(proxy).context(format!("代理地址无效: {}", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = profile.ca_cert.as_deref().map(str::trim).filter(|p| !p.is_empty()) {
+            let pem = std::fs::read(path).context(format!("读取CA证书失败: {}", path))?;
+            let cert = reqwest::Certificate::from_pem(&pem).context(format!("CA证书格式不对: {}", path))?;
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+
+    builder.build().context("创建HTTP客户端失败")
+}
+
+// 获取共享的客户端（Client内部是Arc，clone很便宜）
+pub fn shared_client() -> Result<Client> {
+    let key = (settings::get().network, config::current_profile());
+    let mut cached = SHARED_CLIENT.get_or_init(|| Mutex::new(None)).lock().unwrap();
+
+    if let Some((cached_key, client)) = cached.as_ref() {
+        if *cached_key == key {
+            return Ok(client.clone());
+        }
+    }
+
+    println!("创建共享HTTP客户端: {:?}", key);
+    let client = build_client_for(&key.0, key.1.as_ref())?;
+    *cached = Some((key, client.clone()));
+    Ok(client)
+}
+
+// 一种配置的测速结果
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BenchmarkResult {
+    pub network: NetworkSettings,
+    pub avg_latency_ms: Option<f64>,   // 全部失败时为None
+    pub total_ms: f64,
+    pub failed: usize,
+    pub error: Option<String>,
+}
+
+// 测速时尝试的配置
+fn benchmark_candidates() -> Vec<NetworkSettings> {
+    let current = settings::get().network;
+    let mut candidates = Vec::new();
+    for http2_prior_knowledge in [false, true] {
+        for tcp_nodelay in [true, false] {
+            for pool_max_idle_per_host in [1, 8] {
+                candidates.push(NetworkSettings {
+                    http2_prior_knowledge,
+                    pool_max_idle_per_host,
+                    tcp_nodelay,
+                    user_agent: current.user_agent.clone(),
+                });
+            }
+        }
+    }
+    candidates
+}
+
+async fn benchmark_one(network: NetworkSettings, url: &str) -> BenchmarkResult {
+    let client = match build_client(&network) {
+        Ok(client) => client,
+        Err(e) => {
+            return BenchmarkResult {
+                network,
+                avg_latency_ms: None,
+                total_ms: 0.0,
+                failed: BENCHMARK_ROUNDS,
+                error: Some(e.to_string()),
+            };
+        }
+    };
+
+    let started = Instant::now();
+    let mut latencies = Vec::new();
+    let mut failed = 0;
+    let mut last_error = None;
+
+    // 分几批并发请求，这样连接复用和多路复用的差别才能体现出来
+    for _ in 0..BENCHMARK_ROUNDS / BENCHMARK_CONCURRENCY {
+        let requests = (0..BENCHMARK_CONCURRENCY).map(|_| {
+            let client = client.clone();
+            async move {
+                let request_started = Instant::now();
+                let response = client.get(url).send().await?.error_for_status()?;
+                response.bytes().await?;
+                Ok::<_, reqwest::Error>(request_started.elapsed())
+            }
+        });
+
+        for result in futures::future::join_all(requests).await {
+            match result {
+                Ok(elapsed) => latencies.push(elapsed.as_secs_f64() * 1000.0),
+                Err(e) => {
+                    failed += 1;
+                    last_error = Some(e.to_string());
+                }
+            }
+        }
+    }
+
+    let avg_latency_ms = if latencies.is_empty() {
+        None
+    } else {
+        Some(latencies.iter().sum::<f64>() / latencies.len() as f64)
+    };
+
+    BenchmarkResult {
+        network,
+        avg_latency_ms,
+        total_ms: started.elapsed().as_secs_f64() * 1000.0,
+        failed,
+        error: last_error,
+    }
+}
+
+// 对当前后端测速，返回按总耗时从快到慢排好的结果
+// 有请求失败的配置（比如后端不支持HTTP/2）排在最后
+pub async fn benchmark_backend() -> Result<Vec<BenchmarkResult>> {
+    let base_url = config::get_backend_url()?;
+    let url = format!("{}/test", base_url);
+    println!("开始测试网络配置，测速地址: {}", url);
+
+    let mut results = Vec::new();
+    for network in benchmark_candidates() {
+        let result = benchmark_one(network, &url).await;
+        println!("网络配置 {:?}: 平均延迟 {:?} ms，总耗时 {:.1} ms，失败 {} 次",
+            result.network, result.avg_latency_ms, result.total_ms, result.failed);
+        results.push(result);
+    }
+
+    results.sort_by(|a, b| {
+        a.failed.cmp(&b.failed)
+            .then(a.total_ms.total_cmp(&b.total_ms))
+    });
+    Ok(results)
+}
@@ -1,18 +1,197 @@
-// 蓝牙模块导入
+// 日志打码：覆盖println!/eprintln!，输出前把TOTP等认证信息替换掉
+// 要放在所有mod声明前面，子模块里的println!才会用到这里的版本
+// 打码后的日志同时留一份给崩溃报告（见crash_report模块）
+// 输出到哪里由console_log决定（命令行模式下默认不输出）
+macro_rules! println {
+    () => { ::std::println!() };
+    ($($arg:tt)*) => {{
+        let line = $crate::auth::redact(&format!($($arg)*));
+        $crate::crash_report::record_log(&line);
+        $crate::console_log(&line, false)
+    }};
+}
+macro_rules! eprintln {
+    () => { ::std::eprintln!() };
+    ($($arg:tt)*) => {{
+        let line = $crate::auth::redact(&format!($($arg)*));
+        $crate::crash_report::record_log(&line);
+        $crate::console_log(&line, true)
+    }};
+}
+
+// 日志输出方式
+// 界面模式照常输出；命令行模式下标准输出留给命令结果（脚本要解析），
+// 日志默认不输出，加--verbose时全部输出到标准错误
+const CONSOLE_LOG_NORMAL: u8 = 0;
+const CONSOLE_LOG_STDERR: u8 = 1;
+const CONSOLE_LOG_OFF: u8 = 2;
+static CONSOLE_LOG_MODE: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(CONSOLE_LOG_NORMAL);
+
+fn console_log(line: &str, is_error: bool) {
+    match CONSOLE_LOG_MODE.load(std::sync::atomic::Ordering::Relaxed) {
+        CONSOLE_LOG_OFF => {}
+        CONSOLE_LOG_STDERR => ::std::eprintln!("{}", line),
+        _ if is_error => ::std::eprintln!("{}", line),
+        _ => ::std::println!("{}", line),
+    }
+}
+
+// 认证模块导入
+mod auth;
+// 崩溃报告
+mod crash_report;
+// 蓝牙模块导入（ble功能）
+#[cfg(feature = "ble")]
 mod bluetooth;
+#[cfg(feature = "ble")]
+mod bluetooth_watcher;
+#[cfg(feature = "ble")]
 mod cpen_device_manager;
+// 设备信息和连接状态快照，没有蓝牙功能时也要用
+#[cfg_attr(not(feature = "ble"), allow(dead_code))]
+mod device_state;
+// 笔的协议版本和功能开关
+#[cfg_attr(not(feature = "ble"), allow(dead_code))]
+mod pen_protocol;
+// 识别Cpen设备的规则（设备名前缀、服务UUID、地址白名单）
+#[cfg_attr(not(feature = "ble"), allow(dead_code))]
+mod device_match;
+// 蓝牙权限检查（按平台给出处理办法）
+mod ble_permissions;
+// 蓝牙连接统计（调试用），没有蓝牙功能时计数一直是0
+#[cfg_attr(not(feature = "ble"), allow(dead_code))]
+mod ble_stats;
+// 笔的时钟偏差补偿（按设备记在clock_drift.json里）
+#[cfg_attr(not(feature = "ble"), allow(dead_code))]
+mod clock_drift;
+// 命令总超时和进度事件
+mod command_deadline;
+// 并发请求合并
+mod single_flight;
 // 下载模块导入
 mod download;
 // 上传模块导入
 mod upload;
+// 上传文件的MIME类型检测
+mod content_type;
+// 后端维护状态（503 + Retry-After）
+mod server_maintenance;
+// 文件夹下载的校验清单
+mod checksum_manifest;
+// 从网址上传到云盘
+mod url_upload;
 // 配置模块导入
 mod config;
+// 多后端配置和切换
+mod backend_profiles;
+// 创建任务前的后端连通性检查
+mod connectivity;
 // 存储模块导入
 mod storage;
 // 事件发射模块导入
 mod event_emitter;
 // 截图模块导入
 mod screenshot;
+// 批量上传结果记录
+mod batch;
+// 传输速度采样
+mod speed;
+// 流量统计
+mod bandwidth;
+// Rust端设置
+mod settings;
+// 传输任务管理
+mod transfer_manager;
+// 下载断点续传元数据
+mod download_meta;
+// 分片文件写入
+mod file_writer;
+// 下载前检查目标路径能不能写
+mod destination;
+// 分片计算
+mod chunks;
+// 分片磁盘缓存（边下边播和下载共用）
+mod chunk_cache;
+// 共享HTTP客户端
+mod http_client;
+// 下载、上传和云盘接口共用的请求层
+mod transfer_http;
+// 上传前的文件占用检测
+mod file_lock;
+// 云盘文件管理接口
+mod cloud_api;
+// 批量操作执行前的传输计划（dry run）
+mod transfer_plan;
+// 文件夹上传的排除规则（sync功能）
+#[cfg(feature = "sync")]
+mod transfer_filter;
+// 运行指标（本地接口/api/metrics和metrics.json）
+mod metrics;
+// 匿名统计上报（用户打开后才发）
+mod telemetry;
+// 命令行模式（camfc-cli）
+pub mod cli;
+// 后台任务监督
+mod supervisor;
+// 已清理任务的传输历史
+mod transfer_history;
+// 单个传输任务的日志
+mod transfer_log;
+// 传输队列持久化（重启后恢复）
+mod transfer_queue;
+// 传输并发控制（自动探测每个后端的并发数）
+mod concurrency;
+// 网络类型检测（计费网络策略）
+mod network_profile;
+// 电源状态（睡眠/唤醒、省电模式）
+mod power;
+// 后台模式（窗口不在前台时降低传输速度）
+mod background_mode;
+// 共享限速器（计费网络、后台模式、方向调度共用）
+mod rate_limiter;
+// 发件箱模式（放进目录的文件自动上传）
+mod outbox;
+// 本地WebDAV服务（http功能）
+#[cfg(feature = "http")]
+mod webdav;
+// 本地HTTP接口（http功能）
+#[cfg(feature = "http")]
+mod local_api;
+// 云盘媒体文件边下边播（http功能）
+#[cfg(feature = "http")]
+mod media_stream;
+// 编译时关掉的功能用同名的替代模块，调用时返回"功能未编译"
+#[cfg(not(all(feature = "ble", feature = "http", feature = "sync")))]
+mod disabled;
+#[cfg(not(feature = "ble"))]
+use disabled::cpen_device_manager;
+#[cfg(not(feature = "http"))]
+use disabled::{local_api, media_stream, webdav};
+#[cfg(not(feature = "sync"))]
+use disabled::transfer_filter;
+// camfc:// 链接处理
+mod deep_link;
+// 目录列表缓存
+mod listing_cache;
+// 首次使用引导
+mod onboarding;
+// 复制TOTP到剪贴板并自动清空
+mod totp_clipboard;
+// v2命令（带类型的返回值和结构化错误）
+mod commands_v2;
+// 检查更新
+mod updater;
+// 清理残留文件
+mod cleanup;
+// 磁盘占用统计
+mod storage_usage;
+// 选择性同步（只同步选中的云盘文件夹）
+mod selective_sync;
+// 同步状态库（云盘文件的本地元数据，拉取时做增量对比）
+mod sync_state;
+// 测试用的模拟后端
+#[cfg(test)]
+mod mock_backend;
 
 // 托盘相关导入
 use tauri::tray::{TrayIconBuilder, MouseButton, MouseButtonState, TrayIconEvent};
@@ -22,23 +201,24 @@ use tauri::WindowEvent;
 
 // 使用新的Cpen设备管理器作为业务逻辑层
 use cpen_device_manager::CpenDeviceManager;
-use bluetooth::DeviceInfo;
-use download::{DownloadTask, AuthInfo, get_app_data_dir};
+use device_state::{AdapterInfo, DeviceInfo};
+use download::{DownloadTask, get_app_data_dir};
+use auth::AuthInfo;
+use command_deadline::CommandError;
+use single_flight::SingleFlight;
 use upload::UploadTask;
 use storage::{load_app_data, save_app_data, get_download_file_path};
 use event_emitter::set_app_handle;
+use batch::{BatchItem, UploadBatch};
 
 // 导入同步原语
 // 原来用tokio::sync::Mutex，继续用这个，适合异步环境
 use tokio::sync::Mutex;
 use std::sync::OnceLock;
-use std::collections::HashMap;
 use std::sync::Arc;
 
-// 下载任务管理器
-static DOWNLOAD_TASKS: OnceLock<Mutex<HashMap<String, Arc<download::DownloadTask>>>> = OnceLock::new();
-// 上传任务管理器
-static UPLOAD_TASKS: OnceLock<Mutex<HashMap<String, Arc<upload::UploadTask>>>> = OnceLock::new();
+// 下载/上传任务表统一由transfer_manager管理
+use transfer_manager::{download_tasks, upload_tasks, spawn_download, spawn_upload, TransferPriority};
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
@@ -51,11 +231,37 @@ fn greet(name: &str) -> String {
 /// 前端调用这个命令来完全退出应用
 /// 会清理所有资源并关闭应用
 #[tauri::command]
-fn exit_app(app_handle: tauri::AppHandle) {
+async fn exit_app(app_handle: tauri::AppHandle) {
     println!("前端请求退出应用...");
+    shutdown_gracefully().await;
     app_handle.exit(0);
 }
 
+// 退出时等后台任务收尾的最长时间
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// 退出前的收尾：停掉本地服务，暂停传输让已下载的分片落盘，等后台任务结束，
+// 最后把还没写盘的流量统计和目录缓存保存下来
+async fn shutdown_gracefully() {
+    // 正在进行的蓝牙操作不用等到超时
+    cpen_device_manager::cancel_operations();
+    webdav::stop().await;
+    local_api::stop().await;
+    media_stream::stop().await;
+    // 暂停之前记下队列，下次启动时按退出前的状态恢复
+    if let Err(e) = transfer_queue::save_for_exit().await {
+        println!("保存传输队列失败: {:#}", e);
+    }
+    transfer_manager::pause_active().await;
+    let aborted = supervisor::global().shutdown(SHUTDOWN_TIMEOUT).await;
+    if aborted > 0 {
+        println!("{} 个后台任务没有按时结束，已强制结束", aborted);
+    }
+    bandwidth::flush_pending().await;
+    listing_cache::flush_pending().await;
+    sync_state::flush_pending().await;
+}
+
 // 全局Cpen设备管理器实例
 // 用OnceLock确保只初始化一次，Mutex保证线程安全
 // 思考：原来的蓝牙管理器现在作为底层被CpenDeviceManager使用
@@ -91,73 +297,172 @@ fn get_cpen_device_manager() -> Result<&'static Mutex<CpenDeviceManager>, String
 
 // 现在只暴露少数几个简洁的命令给前端
 
+// 正在进行中的get_totp，并发调用合并成一次
+static TOTP_FLIGHT: SingleFlight<Result<String, CommandError>> = SingleFlight::new();
+
 /// 获取TOTP（主要业务功能）
 /// 
 /// 前端只需要调用这个命令，所有业务逻辑都在Rust端处理：
 /// 1. 自动扫描蓝牙设备
 /// 2. 自动识别Cpen设备（根据名前缀）
 /// 3. 保证只连接一个Cpen设备（重要！）
-/// 4. 自动发送setTime和getTotp命令
+/// 4. 自动发送setTime和getTotp命令（同一次连接里设备时间刚同步过时跳过setTime）
 /// 5. 30秒TOTP缓存
+/// 6. 后端连续拒绝认证时锁定一段时间，锁定期间直接返回错误（见auth模块）
+/// 7. 整个命令最多30秒，超时返回kind为timeout的错误；过程中会发device-progress事件
+/// 8. 同时有多个调用时只和笔交互一次，后来的调用等第一个的结果（见single_flight模块）
 /// 
-/// 返回值：TOTP字符串，或包含错误信息的字符串
+/// 返回值：TOTP字符串，或包含错误信息的结构化错误（见command_deadline模块）
 #[tauri::command]
-async fn get_totp() -> Result<String, String> {
+async fn get_totp() -> Result<String, CommandError> {
     println!("前端调用get_totp命令...");
     
-    let mut manager = get_cpen_device_manager()?.lock().await;
+    TOTP_FLIGHT.run(|| command_deadline::with_deadline("get_totp", command_deadline::GET_TOTP_TIMEOUT, async {
+        // 后端连续拒绝认证被锁定时，不再去问笔要TOTP
+        auth::check_auth_lock().map_err(|e| e.to_string())?;
     
-    match manager.get_totp().await {
-        Ok(totp) => {
-            // 成功获取TOTP，返回给前端
-            println!("TOTP获取成功，返回给前端");
-            Ok(totp)
+        let mut manager = get_cpen_device_manager()?.lock().await;
+    
+        match manager.get_totp().await {
+            Ok(totp) => {
+                // 成功获取TOTP，返回给前端
+                println!("TOTP获取成功，返回给前端");
+                Ok(totp)
+            }
+            Err(e) => {
+                // 获取失败，返回错误信息
+                println!("TOTP获取失败: {}", e);
+                Err(format!("获取TOTP失败: {}", e))
+            }
         }
-        Err(e) => {
-            // 获取失败，返回错误信息
+    })).await
+}
+
+/// 传输认证用的TOTP和挑战应答
+/// 
+/// 开启挑战应答时auth::CpenProvider用这个代替get_totp：随机数只能用一次，
+/// 每次都问笔要新的，不走TOTP缓存，也不和其他调用合并。不是前端命令
+pub(crate) async fn get_totp_with_challenge() -> Result<(String, Option<auth::AuthChallenge>), CommandError> {
+    command_deadline::with_deadline("get_totp", command_deadline::GET_TOTP_TIMEOUT, async {
+        auth::check_auth_lock().map_err(|e| e.to_string())?;
+    
+        let mut manager = get_cpen_device_manager()?.lock().await;
+        manager.get_totp_with_challenge().await.map_err(|e| {
             println!("TOTP获取失败: {}", e);
-            Err(format!("获取TOTP失败: {}", e))
+            format!("获取TOTP失败: {}", e)
+        })
+    }).await
+}
+
+/// 强制重新获取TOTP
+/// 
+/// 排查认证问题时用：不等30秒缓存过期，丢掉缓存的TOTP和认证信息，马上重新setTime+getTotp。
+/// force为true时连认证失败锁定也一起清掉（用户已经检查过笔的时间和配对）；
+/// 为false时锁定期间和get_totp一样直接返回错误。
+/// 整个命令最多30秒，超时返回kind为timeout的错误。
+/// 
+/// 返回值：新的TOTP字符串，或包含错误信息的结构化错误
+#[tauri::command]
+async fn refresh_totp(force: Option<bool>) -> Result<String, CommandError> {
+    println!("前端调用refresh_totp命令...");
+    
+    command_deadline::with_deadline("refresh_totp", command_deadline::GET_TOTP_TIMEOUT, async {
+        if force.unwrap_or(false) {
+            auth::clear_auth_state();
+        } else {
+            auth::check_auth_lock().map_err(|e| e.to_string())?;
+            auth::invalidate_credentials();
         }
-    }
+    
+        let mut manager = get_cpen_device_manager()?.lock().await;
+        manager.clear_totp_cache();
+        manager.get_totp().await.map_err(|e| {
+            println!("TOTP刷新失败: {}", e);
+            format!("获取TOTP失败: {}", e)
+        })
+    }).await
+}
+
+/// 清除认证缓存
+/// 
+/// 丢掉笔的TOTP缓存、传输用的认证信息缓存、挑战应答，并清零认证失败计数和锁定，
+/// 下一次请求会重新问笔要认证信息。不会断开蓝牙连接。
+#[tauri::command]
+async fn clear_auth_cache() -> Result<(), String> {
+    println!("前端调用clear_auth_cache命令...");
+    
+    auth::clear_auth_state();
+    let mut manager = get_cpen_device_manager()?.lock().await;
+    manager.clear_totp_cache();
+    Ok(())
+}
+
+/// 复制TOTP到剪贴板
+/// 
+/// 和get_totp一样获取验证码（30秒内有缓存就直接用），写到系统剪贴板，
+/// clear_after_secs秒后清空剪贴板（不传时用设置里的auth.totp_clipboard_clear_secs，0表示不清空）。
+/// 倒计时期间每秒发totp-clipboard-countdown事件，清空时发totp-clipboard-cleared事件；
+/// 剪贴板内容已经被用户换掉时不清空。
+/// 
+/// 返回值：{"copied": true, "clear_after_secs"}
+#[tauri::command]
+async fn copy_totp_to_clipboard(app_handle: tauri::AppHandle, clear_after_secs: Option<u64>) -> Result<serde_json::Value, CommandError> {
+    println!("前端调用copy_totp_to_clipboard命令...");
+    
+    let totp = get_totp().await?;
+    let clear_after = clear_after_secs.unwrap_or(settings::get().auth.totp_clipboard_clear_secs);
+    totp_clipboard::copy(&app_handle, totp, clear_after)?;
+    
+    Ok(serde_json::json!({
+        "copied": true,
+        "clear_after_secs": clear_after,
+    }))
 }
 
 /// 获取设备ID（设备UUID）
 /// 
 /// 前端调用这个命令获取设备唯一标识。
 /// 内部会自动处理连接、发送getId命令等。
+/// 整个命令最多30秒，超时返回kind为timeout的错误。
 /// 
-/// 返回值：设备ID字符串，或包含错误信息的字符串
+/// 返回值：设备ID字符串，或包含错误信息的结构化错误
 #[tauri::command]
-async fn get_device_id() -> Result<String, String> {
+async fn get_device_id() -> Result<String, CommandError> {
     println!("前端调用get_device_id命令...");
     
-    let mut manager = get_cpen_device_manager()?.lock().await;
+    command_deadline::with_deadline("get_device_id", command_deadline::GET_DEVICE_ID_TIMEOUT, async {
+        let mut manager = get_cpen_device_manager()?.lock().await;
     
-    match manager.get_device_id().await {
-        Ok(device_id) => {
-            println!("设备ID获取成功，返回给前端");
-            Ok(device_id)
-        }
-        Err(e) => {
-            println!("设备ID获取失败: {}", e);
-            Err(format!("获取设备ID失败: {}", e))
+        match manager.get_device_id().await {
+            Ok(device_id) => {
+                println!("设备ID获取成功，返回给前端");
+                Ok(device_id)
+            }
+            Err(e) => {
+                println!("设备ID获取失败: {}", e);
+                Err(format!("获取设备ID失败: {}", e))
+            }
         }
-    }
+    }).await
 }
 
 /// 获取连接状态
 /// 
 /// 前端可以调用这个命令获取当前连接状态。
 /// 返回格式化的状态字符串，包含设备信息。
+/// 蓝牙适配器被关闭时会在后面加上"（蓝牙已关闭）"。
 /// 
 /// 思考：这个命令比较简单，不会尝试连接设备，只返回当前状态。
+/// 读的是设备管理器写的状态快照，不用等设备管理器的锁，获取TOTP时也能马上返回。
+/// 
+/// 已废弃：新代码用v2_get_connection_status，返回结构化的状态
 #[tauri::command]
 async fn get_connection_status() -> Result<String, String> {
     println!("前端调用get_connection_status命令...");
     
-    let manager = get_cpen_device_manager()?.lock().await;
-    
-    let status = manager.get_connection_status();
+    let status = commands_v2::v2_get_connection_status().await
+        .map_err(|e| e.message)?
+        .description;
     println!("当前连接状态: {}", status);
     
     Ok(status)
@@ -168,39 +473,129 @@ async fn get_connection_status() -> Result<String, String> {
 /// 前端可以调用这个命令检查连接是否真的还活着。
 /// 返回布尔值：true表示已建立稳定连接，false表示未连接或连接已断开。
 /// 
-/// 注意：这个方法会实际检查蓝牙物理连接状态，而不仅仅是内存中的记录。
+/// 注意：这个命令读的是状态快照，不等锁。蓝牙物理连接由蓝牙监控定时检查，
+/// 连接断开后最多几秒快照就会更新。
 #[tauri::command]
 async fn is_connected() -> Result<bool, String> {
-    let mut manager = get_cpen_device_manager()?.lock().await;
+    Ok(cpen_device_manager::connection_snapshot().is_connected())
+}
+
+/// 获取笔的协议版本和支持的功能
+/// 
+/// 连上笔时会问一次，这里只返回记下的结果，不会去连接；还没连接过时返回null。
+/// 
+/// 返回值：{"version", "features": ["battery", "dfu"]}
+#[tauri::command]
+async fn get_pen_protocol() -> Result<Option<pen_protocol::PenProtocol>, String> {
+    let manager = get_cpen_device_manager()?.lock().await;
+    Ok(manager.protocol())
+}
+
+/// 查询笔的电量（百分比）
+/// 
+/// 需要笔的固件支持（协议版本2以上），不支持时返回kind为unsupported_firmware的错误。
+/// 整个命令最多30秒，超时返回kind为timeout的错误。
+#[tauri::command]
+async fn get_pen_battery() -> Result<u8, CommandError> {
+    println!("前端调用get_pen_battery命令...");
     
-    match manager.is_connected().await {
-        Ok(connected) => Ok(connected),
-        Err(e) => Err(format!("检查连接状态失败: {}", e))
+    command_deadline::with_deadline("get_pen_battery", command_deadline::GET_BATTERY_TIMEOUT, async {
+        let mut manager = get_cpen_device_manager()?.lock().await;
+        manager.get_battery_level().await
+    }).await
+}
+
+/// 获取蓝牙连接统计
+/// 
+/// 排查连接不稳定用：写入次数、收到的通知数、订阅失败、重连次数、命令往返时间。
+/// total从程序启动或上次重置开始算，session从这次连上笔开始算。
+/// reset为true时返回当前统计后清零。
+/// 
+/// 返回值：{"since", "session_started", "total": {...}, "session": {...}}
+#[tauri::command]
+async fn get_ble_stats(reset: Option<bool>) -> Result<ble_stats::BleStats, String> {
+    let stats = ble_stats::snapshot();
+    if reset.unwrap_or(false) {
+        println!("前端调用get_ble_stats命令，统计已重置");
+        ble_stats::reset();
     }
+    Ok(stats)
+}
+
+/// 列出所有蓝牙适配器
+/// 
+/// 电脑上有多个蓝牙适配器时（比如内置蓝牙+USB蓝牙），前端用这个命令让用户选择。
+/// 返回值：适配器列表（id、序号、名称、是否开启、是否正在使用）
+/// 
+/// 注意：Windows下适配器名称都是"WinRT"，只能按序号区分。
+#[tauri::command]
+async fn list_bluetooth_adapters() -> Result<Vec<AdapterInfo>, String> {
+    println!("前端调用list_bluetooth_adapters命令...");
+    
+    let manager = get_cpen_device_manager()?.lock().await;
+    manager.list_adapters().await
+}
+
+/// 选择要使用的蓝牙适配器
+/// 
+/// 参数：id - list_bluetooth_adapters返回的适配器id
+/// 
+/// 选择会保存到设置里，下次启动继续使用。
+/// 切换时会断开当前的笔，之后的操作会在新适配器上重新扫描连接。
+#[tauri::command]
+async fn select_adapter(id: String) -> Result<AdapterInfo, String> {
+    println!("前端调用select_adapter命令: {}", id);
+    
+    let mut manager = get_cpen_device_manager()?.lock().await;
+    manager.select_adapter(&id).await
+}
+
+/// 设置识别笔的规则
+/// 
+/// 扫描到的设备哪些算笔：设备名前缀（不区分大小写）或者广播的服务UUID满足一个就算；
+/// 地址列表不为空时只认列表里的地址。改了名字的笔、新一代设备不用改代码。
+/// 
+/// 参数：rules - {"name_prefixes": ["Cpen"], "service_uuids": [], "addresses": []}，
+///       不传的字段用默认值（前缀Cpen，另外两个为空），三个都为空时返回错误
+/// 返回值：整理后保存的规则（去掉空白和重复，地址转大写、UUID转小写）
+/// 
+/// 注意：已经连着的笔不受影响，下次扫描时按新规则识别
+#[tauri::command]
+async fn set_device_match_rules(rules: settings::DeviceMatchRules) -> Result<settings::DeviceMatchRules, String> {
+    println!("前端调用set_device_match_rules命令: {:?}", rules);
+    
+    let rules = device_match::normalize(&rules);
+    settings::update(serde_json::json!({ "bluetooth": { "device_match": rules } }))
+        .await
+        .map(|settings| settings.bluetooth.device_match)
+        .map_err(|e| format!("保存设备识别规则失败: {:#}", e))
 }
 
 /// 扫描并返回所有Cpen设备列表
 /// 
 /// 前端调用这个命令获取所有可连接的Cpen设备。
 /// 不会自动连接，只返回设备列表供用户选择。
+/// 整个命令最多20秒，超时返回kind为timeout的错误。
 /// 
 /// 返回值：设备列表（包含name和address）
 #[tauri::command]
-async fn scan_cpen_devices() -> Result<Vec<DeviceInfo>, String> {
+async fn scan_cpen_devices() -> Result<Vec<DeviceInfo>, CommandError> {
     println!("前端调用scan_cpen_devices命令...");
     
-    let mut manager = get_cpen_device_manager()?.lock().await;
+    command_deadline::with_deadline("scan_cpen_devices", command_deadline::SCAN_TIMEOUT, async {
+        let mut manager = get_cpen_device_manager()?.lock().await;
     
-    match manager.scan_cpen_devices().await {
-        Ok(devices) => {
-            println!("扫描成功，找到 {} 个Cpen设备", devices.len());
-            Ok(devices)
-        }
-        Err(e) => {
-            println!("扫描失败: {}", e);
-            Err(format!("扫描失败: {}", e))
+        match manager.scan_cpen_devices().await {
+            Ok(devices) => {
+                println!("扫描成功，找到 {} 个Cpen设备", devices.len());
+                Ok(devices)
+            }
+            Err(e) => {
+                println!("扫描失败: {}", e);
+                Err(format!("扫描失败: {}", e))
+            }
         }
-    }
+    }).await
 }
 
 /// 连接到指定的Cpen设备
@@ -208,35 +603,71 @@ async fn scan_cpen_devices() -> Result<Vec<DeviceInfo>, String> {
 /// 前端调用这个命令连接用户选择的设备。
 /// 参数为设备的Bluetooth地址。
 /// 
+/// 整个命令最多30秒，超时返回kind为timeout的错误。
+/// 
 /// 参数：设备地址（address）
 /// 返回值：设备信息
 #[tauri::command]
-async fn connect_cpen_device(address: String) -> Result<DeviceInfo, String> {
+async fn connect_cpen_device(address: String) -> Result<DeviceInfo, CommandError> {
     println!("前端调用connect_cpen_device命令，地址: {}", address);
     
-    let mut manager = get_cpen_device_manager()?.lock().await;
+    command_deadline::with_deadline("connect_cpen_device", command_deadline::CONNECT_TIMEOUT, async {
+        let mut manager = get_cpen_device_manager()?.lock().await;
     
-    match manager.connect_to_device(&address).await {
-        Ok(device_info) => {
-            println!("连接成功: {}", device_info.name);
-            Ok(device_info)
-        }
-        Err(e) => {
-            println!("连接失败: {}", e);
-            Err(format!("连接失败: {}", e))
+        match manager.connect_to_device(&address).await {
+            Ok(device_info) => {
+                println!("连接成功: {}", device_info.name);
+                Ok(device_info)
+            }
+            Err(e) => {
+                println!("连接失败: {}", e);
+                Err(format!("连接失败: {}", e))
+            }
         }
-    }
+    }).await
+}
+
+/// 首次使用引导
+/// 
+/// 按顺序执行：开启蓝牙 → 扫描 → 选择笔 → 连接 → 读取设备ID → 检查后端连通，
+/// 每一步都会发onboarding-step事件（带状态和处理建议），前端据此显示向导。
+/// 找到多支笔时返回needs_selection和候选列表，用户选好后带上address再调用一次。
+/// 
+/// 参数：address - 已选择的设备地址，不传则自动扫描
+#[tauri::command]
+async fn start_onboarding(address: Option<String>) -> Result<onboarding::OnboardingResult, String> {
+    println!("前端调用start_onboarding命令，地址: {:?}", address);
+    
+    Ok(onboarding::run(address).await)
+}
+
+/// 检查蓝牙权限
+/// 
+/// macOS需要用户授权应用使用蓝牙，Linux需要当前用户能通过D-Bus访问BlueZ（通常要在bluetooth组里），
+/// Windows需要隐私设置允许应用控制无线电。没有权限时蓝牙操作只会报"获取适配器失败"之类的错误，
+/// 用这个命令可以知道具体原因。首次使用引导开始时也会先检查一次。
+/// 
+/// 返回值：{"platform": "windows/macos/linux", "status": "granted/denied/unknown",
+///          "message": "诊断信息", "remediation": "处理办法，有权限时为null"}
+#[tauri::command]
+async fn check_ble_permissions() -> Result<ble_permissions::PermissionReport, String> {
+    println!("前端调用check_ble_permissions命令...");
+    
+    Ok(ble_permissions::check_ble_permissions().await)
 }
+
 /// 断开连接并清理资源
 /// 
 /// 前端可以调用这个命令手动断开蓝牙连接。
 /// 会清理所有缓存和连接状态。
 /// 
 /// 注意：断开后，下次调用get_totp或get_device_id会自动重新连接。
+/// 正在进行的扫描、连接、收发会被取消，不用等它们超时。
 #[tauri::command]
 async fn disconnect() -> Result<(), String> {
     println!("前端调用disconnect命令...");
     
+    cpen_device_manager::cancel_operations();
     let mut manager = get_cpen_device_manager()?.lock().await;
     
     match manager.disconnect().await {
@@ -260,6 +691,7 @@ async fn cleanup() -> Result<(), String> {
     println!("前端调用cleanup命令...");
     
     // 实际上和disconnect差不多，就叫cleanup保持兼容性
+    cpen_device_manager::cancel_operations();
     let mut manager = get_cpen_device_manager()?.lock().await;
     
     match manager.disconnect().await {
@@ -283,6 +715,25 @@ async fn cleanup() -> Result<(), String> {
 // 如果前端需要设备扫描功能，可以考虑加一个简单的scan命令，但用户说尽量简化接口。
 // 先不加，等有需求再说。
 
+/// 获取认证信息（设备ID + TOTP）
+/// 
+/// 按设置里的auth.provider选择认证方式，默认从Cpen设备获取
+/// 传输相关的命令和传输管理器的自动重试都用这个
+/// 同时发起的多个传输只会问笔一次，拿到的认证信息会缓存几秒（见auth::AuthManager）
+async fn acquire_auth_info() -> Result<AuthInfo, String> {
+    auth::current_credentials().await.map_err(|e| e.to_string())
+}
+
+/// 获取认证信息并检查后端连通（域名解析、TCP连接、认证）
+/// 
+/// 创建传输任务的命令用这个，后端不通时直接返回"[诊断] 原因，建议"，
+/// 不再创建任务后一个分片一个分片地重试（见connectivity模块）
+async fn acquire_checked_auth_info() -> Result<AuthInfo, String> {
+    let auth_info = acquire_auth_info().await?;
+    connectivity::ensure_reachable(&auth_info).await?;
+    Ok(auth_info)
+}
+
 // 下载相关命令
 
 /// 下载文件
@@ -294,20 +745,58 @@ async fn cleanup() -> Result<(), String> {
 /// 因为后端API需要完整的路径信息：http://localhost:8005/download/ds/下载.png
 /// 
 /// 这个版本支持真正的分片下载和断点续传
+/// 
+/// priority可选"high"（默认，用户手动点击的下载）或"low"（后台同步），
+/// 有高优先级下载在进行时，低优先级下载会暂时让出带宽
+/// 
+/// 本地已经有完整且和服务器一致的文件时不再下载，任务直接是Completed状态，
+/// 返回的文字以"文件已下载"开头；force为true时照常重新下载
+/// 
+/// 保存位置不能写时不创建任务，错误以[permission_denied]/[read_only]等开头，带上出问题的路径
+/// 
+/// follow为true时是跟随模式（给还在不断追加的日志、录制文件用）：下载到当前末尾后状态是Following，
+/// 每隔一段时间检查云端文件有没有变大，新增的部分接着下载，直到调用stop_following_download或暂停
 #[tauri::command]
-async fn download_file(file_id: String) -> Result<String, String> {
-    println!("前端调用download_file命令，文件路径: {}", file_id);
+async fn download_file(file_id: String, priority: Option<String>, force: Option<bool>, follow: Option<bool>) -> Result<String, String> {
+    println!("前端调用download_file命令，文件路径: {}，优先级: {:?}，跟随: {:?}", file_id, priority, follow);
     
-    // 先获取设备ID和TOTP
-    let device_id = get_device_id().await.map_err(|e| format!("获取设备ID失败: {}", e))?;
-    let totp = get_totp().await.map_err(|e| format!("获取TOTP失败: {}", e))?;
+    // 获取认证信息
+    let auth_info = acquire_checked_auth_info().await?;
     
-    // 创建认证信息
-    let auth_info = AuthInfo {
-        device_id,
-        totp,
-    };
+    // 目标文件所在的目录不能写时不创建任务
+    let download_dir = get_app_data_dir()
+        .await
+        .map_err(|e| format!("获取下载目录失败: {}", e))?;
+    destination::ensure_writable_file(&download_dir.join(&file_id))
+        .await
+        .map_err(|e| e.to_string())?;
+    
+    let (task_arc, already_downloaded) = create_and_register_download(&file_id, auth_info, priority.as_deref(), force.unwrap_or(false)).await?;
+    let save_path = task_arc.save_path().to_path_buf();
+    let follow = follow.unwrap_or(false);
+    task_arc.set_follow(follow);
+    if already_downloaded && !follow {
+        return Ok(format!("文件已下载，跳过: {:?}", save_path));
+    }
+    
+    println!("下载任务已添加到管理器，开始后台下载...");
+    
+    // 在后台异步执行下载，不阻塞前端响应（失败后会自动重试）
+    spawn_download(task_arc);
     
+    // 立即返回，不等待下载完成
+    let result = format!("下载已开始，文件将保存到: {:?}，可使用get_download_progress查询进度", save_path);
+    println!("{}", result);
+    Ok(result)
+}
+
+// 创建下载任务并放进任务管理器，本地已经有一致的文件时任务直接完成，返回的bool为true
+async fn create_and_register_download(
+    file_id: &str,
+    auth_info: AuthInfo,
+    priority: Option<&str>,
+    force: bool,
+) -> Result<(Arc<DownloadTask>, bool), String> {
     // 获取下载目录
     let download_dir = get_app_data_dir()
         .await
@@ -316,158 +805,953 @@ async fn download_file(file_id: String) -> Result<String, String> {
     // 保持用户原始的目录结构
     // file_id 格式可能是 "ds/下载.png" 或 "新建文件夹/python.zip"
     // 直接使用 file_id 作为相对路径，保持原始目录结构
-    let save_path = download_dir.join(&file_id);
+    let save_path = download_dir.join(file_id);
     
     println!("创建下载任务: {} -> {:?}", file_id, save_path);
     
     // 创建下载任务
-    let task = DownloadTask::new(file_id.clone(), save_path.clone(), auth_info)
+    let task = DownloadTask::new(file_id.to_string(), save_path, auth_info)
         .await
         .map_err(|e| format!("创建下载任务失败: {}", e))?;
+    task.set_priority(TransferPriority::parse(priority)).await;
     
-    // 将任务保存到全局管理器中
+    // 已经下载过的文件直接完成，任务照样放进管理器，查询进度时是Completed
+    let already_downloaded = !force && task.complete_if_already_downloaded().await;
     let task_arc = Arc::new(task);
+    download_tasks().lock().await.insert(file_id.to_string(), task_arc.clone());
+    Ok((task_arc, already_downloaded))
+}
+
+/// 下载整个云盘文件夹
+/// 
+/// 文件夹里的文件（包括子文件夹）都创建下载任务，保存到下载目录下同样的路径。
+/// 全部下载完成后在本地文件夹里写一个SHA256SUMS校验清单，并发download-batch-finished事件；
+/// 有文件最终没下载成功时不写清单，可以用export_checksums导出已完成部分的清单
+/// 
+/// 返回值：{"batch_id", "remote_path", "save_dir", "count", "failed"}
+#[tauri::command]
+async fn download_folder(remote_path: String, priority: Option<String>) -> Result<serde_json::Value, String> {
+    println!("前端调用download_folder命令，文件夹: {}", remote_path);
+    let auth_info = acquire_checked_auth_info().await?;
+    let download_dir = get_app_data_dir()
+        .await
+        .map_err(|e| format!("获取下载目录失败: {}", e))?;
+    let remote_path = remote_path.trim_matches('/').to_string();
+    let mut batch = batch::DownloadBatch::new(remote_path.clone(), download_dir.join(&remote_path));
+    destination::ensure_writable_dir(&batch.save_dir)
+        .await
+        .map_err(|e| e.to_string())?;
     
-    // 初始化下载任务管理器
-    let download_tasks = DOWNLOAD_TASKS.get_or_init(|| Mutex::new(HashMap::new()));
-    let mut tasks_map = download_tasks.lock().await;
-    tasks_map.insert(file_id.clone(), task_arc.clone());
-    
-    println!("下载任务已添加到管理器，开始后台下载...");
-    
-    // 在后台异步执行下载，不阻塞前端响应
-    let task_for_spawn = task_arc.clone();
-    let file_id_for_spawn = file_id.clone();
-    let save_path_for_spawn = save_path.clone();
-    
-    tokio::spawn(async move {
-        println!("后台下载任务开始: {}", file_id_for_spawn);
-        
-        match task_for_spawn.start().await {
-            Ok(_) => {
-                println!("后台下载完成: {}，保存到: {:?}", file_id_for_spawn, save_path_for_spawn);
-                
-                // 下载完成后更新状态为完成
-                // 状态已经在start()方法中更新了
+    // 逐层列出文件夹里的所有文件，按选择性同步的设置过滤，已经同步过的不再下载
+    let plan = selective_sync::plan_pull(&auth_info, &remote_path, &download_dir)
+        .await
+        .map_err(|e| format!("{:#}", e))?;
+    println!("文件夹 {}：{} 个文件要下载，{} 个已是最新", remote_path, plan.files.len(), plan.up_to_date.len());
+    batch.file_ids.extend(plan.up_to_date);
+    for entry in plan.files {
+        match create_and_register_download(&entry.path, auth_info.clone(), priority.as_deref(), false).await {
+            Ok((task, already_downloaded)) => {
+                if !already_downloaded {
+                    spawn_download(task);
+                }
+                batch.file_ids.push(entry.path);
             }
             Err(e) => {
-                println!("后台下载失败: {}，错误: {}", file_id_for_spawn, e);
+                println!("文件 {} 创建下载任务失败: {}", entry.path, e);
+                batch.failed.push(BatchItem::failed(entry.path, e));
             }
         }
-    });
+    }
     
-    // 立即返回，不等待下载完成
-    let result = format!("下载已开始，文件将保存到: {:?}，可使用get_download_progress查询进度", save_path);
-    println!("{}", result);
-    Ok(result)
+    let summary = serde_json::json!({
+        "batch_id": batch.batch_id,
+        "remote_path": batch.remote_path,
+        "save_dir": batch.save_dir.to_string_lossy(),
+        "count": batch.file_ids.len(),
+        "failed": batch.failed,
+    });
+    batch::save_download_batch(batch.clone()).await;
+    checksum_manifest::watch_batch(batch);
+    Ok(summary)
+}
+
+/// 获取云盘目录树（选择性同步的文件夹选择器用）
+/// 
+/// 只列文件夹，从path（默认根目录）开始展开depth层（默认1，最多5层），
+/// 每个文件夹带state：selected（整个同步）、partial（只同步部分子文件夹）、unselected（不同步）
+/// 
+/// 返回值：{"name", "path", "state", "children": [...]}，没展开的文件夹children为null
+#[tauri::command]
+async fn get_remote_tree(path: Option<String>, depth: Option<u32>) -> Result<selective_sync::RemoteTreeNode, String> {
+    println!("前端调用get_remote_tree命令，路径: {:?}，层数: {:?}", path, depth);
+    let auth_info = acquire_auth_info().await?;
+    selective_sync::remote_tree(&auth_info, path.as_deref().unwrap_or_default(), depth.unwrap_or(1))
+        .await
+        .map_err(|e| format!("获取目录树失败: {:#}", e))
+}
+
+/// 设置选择性同步的文件夹
+/// 
+/// paths是要同步到本地的云盘文件夹，子文件夹跟着上级一起同步；空列表表示全部同步。
+/// download_folder按这个选择过滤（见selective_sync模块）
+/// 
+/// 返回值：整理后的文件夹列表（去掉了重复的和被上级包含的）
+#[tauri::command]
+async fn set_selected_folders(paths: Vec<String>) -> Result<Vec<String>, String> {
+    println!("前端调用set_selected_folders命令，{} 个文件夹", paths.len());
+    selective_sync::set_selected(&paths)
+        .await
+        .map_err(|e| format!("保存同步文件夹失败: {:#}", e))
+}
+
+/// 导出文件夹下载的校验清单
+/// 
+/// 把批次里已经下载完成的文件写成SHA256SUMS格式（和sha256sum命令兼容），
+/// path是目录时写到目录下的SHA256SUMS文件
+/// 
+/// 返回值：{"path", "files"}
+#[tauri::command]
+async fn export_checksums(batch_id: String, path: String) -> Result<checksum_manifest::ManifestReport, String> {
+    println!("前端调用export_checksums命令，批次: {}，路径: {}", batch_id, path);
+    checksum_manifest::export(&batch_id, std::path::Path::new(&path))
+        .await
+        .map_err(|e| format!("导出校验清单失败: {:#}", e))
 }
 
 /// 获取下载进度
 /// 
-/// 从下载任务管理器中获取真实的下载进度信息
+/// 已废弃：新代码用v2_get_download_progress（见commands_v2模块），这里只转换成旧格式
 /// 如果任务不存在，返回一个默认的进度信息
 #[tauri::command]
 async fn get_download_progress(file_id: String) -> Result<serde_json::Value, String> {
     println!("前端调用get_download_progress命令，文件ID: {}", file_id);
     
-    // 尝试从下载任务管理器中获取任务
-    let download_tasks = DOWNLOAD_TASKS.get_or_init(|| Mutex::new(HashMap::new()));
-    let tasks_map = download_tasks.lock().await;
+    match commands_v2::v2_get_download_progress(file_id.clone()).await {
+        Ok(progress) => Ok(progress.to_legacy_json()),
+        Err(_) => {
+            // 如果任务不存在，返回一个默认的进度信息
+            println!("下载任务 {} 不存在，返回默认进度信息", file_id);
+            Ok(serde_json::json!({
+                "file_id": file_id,
+                "file_name": "未知文件",
+                "total_size": 0,
+                "downloaded": 0,
+                "status": "Pending",
+                "chunks_total": 0,
+                "chunks_completed": 0,
+                "speed_kbps": 0.0,
+                "retry_count": 0,
+                "progress_percentage": 0,
+            }))
+        }
+    }
+}
+
+/// 获取下载任务每个分片的状态
+/// 
+/// 用于前端画分段进度条（类似传统下载管理器），
+/// 每个分片包含index、start、end、state（pending/in_flight/done/failed）和retry_count
+#[tauri::command]
+async fn get_download_chunks(file_id: String) -> Result<serde_json::Value, String> {
+    println!("前端调用get_download_chunks命令，文件ID: {}", file_id);
     
-    if let Some(task) = tasks_map.get(&file_id) {
-        // 获取真实的进度信息
-        let progress = task.get_progress().await;
-        
-        // 将进度信息转换为JSON
-        let status_str = match &progress.status {
-            download::DownloadStatus::Pending => "Pending",
-            download::DownloadStatus::Downloading => "Downloading",
-            download::DownloadStatus::Paused => "Paused",
-            download::DownloadStatus::Completed => "Completed",
-            download::DownloadStatus::Error(err_msg) => {
-                // 错误信息包含在状态字符串中
-                return Ok(serde_json::json!({
-                    "file_id": progress.file_id,
-                    "file_name": progress.file_name,
-                    "total_size": progress.total_size,
-                    "downloaded": progress.downloaded,
-                    "status": format!("Error: {}", err_msg),
-                    "chunks_total": progress.chunks_total,
-                    "chunks_completed": progress.chunks_completed,
-                    "speed_kbps": progress.speed_kbps,
-                    "progress_percentage": if progress.total_size > 0 {
-                        (progress.downloaded as f64 / progress.total_size as f64 * 100.0).round() as u32
-                    } else {
-                        0
-                    },
-                }));
+    let task = download_tasks()
+        .lock()
+        .await
+        .get(&file_id)
+        .cloned()
+        .ok_or_else(|| format!("下载任务不存在: {}", file_id))?;
+    
+    let chunks = task.get_chunks().await;
+    Ok(serde_json::json!({
+        "file_id": file_id,
+        "chunks_total": chunks.len(),
+        "chunks": chunks,
+    }))
+}
+
+/// 暂停下载
+/// 
+/// TODO: 需要下载任务管理器来实现真正的暂停功能
+/// 先简单返回成功
+#[tauri::command]
+async fn pause_download(file_id: String) -> Result<(), String> {
+    println!("前端调用pause_download命令，文件ID: {}", file_id);
+    
+    // 暂时简单实现
+    println!("下载暂停功能待实现");
+    Ok(())
+}
+
+/// 恢复下载
+/// 
+/// TODO: 需要下载任务管理器来实现真正的恢复功能
+/// 先简单返回成功
+#[tauri::command]
+async fn resume_download(file_id: String) -> Result<(), String> {
+    println!("前端调用resume_download命令，文件ID: {}", file_id);
+    
+    // 暂时简单实现
+    println!("下载恢复功能待实现");
+    Ok(())
+}
+
+/// 停止跟随下载
+/// 
+/// 跟随模式（download_file的follow）的任务停止等文件增长，已经下载的部分保留，状态变成Completed
+#[tauri::command]
+async fn stop_following_download(file_id: String) -> Result<(), String> {
+    println!("前端调用stop_following_download命令，文件ID: {}", file_id);
+    let task = download_tasks()
+        .lock()
+        .await
+        .get(&file_id)
+        .cloned()
+        .ok_or_else(|| format!("下载任务不存在: {}", file_id))?;
+    task.set_follow(false);
+    Ok(())
+}
+
+/// 获取传输历史
+/// 
+/// 已完成/失败的任务在任务表里保留一段时间（设置里的transfer.finished_task_ttl_mins）后会被清理，
+/// 清理前记到传输历史里。limit默认100，新的在前。
+/// 返回值：[{"direction", "id", "file_name", "local_path", "total_size", "status", "error", "finished_at", "log"}]
+#[tauri::command]
+async fn get_transfer_history(limit: Option<usize>) -> Result<Vec<transfer_history::HistoryEntry>, String> {
+    transfer_history::load(limit.unwrap_or(100)).await.map_err(|e| format!("读取传输历史失败: {:#}", e))
+}
+
+/// 获取单个传输任务的日志
+/// 
+/// 任务自己记录的分片重试、HTTP错误、暂停、出错等事件（最近200行），用来查看某个任务为什么失败。
+/// id可以是下载任务的file_id，也可以是上传任务的upload_id；已经从任务表清理掉的任务从传输历史里找。
+/// 返回值：[{"at": 毫秒时间戳, "message"}]
+#[tauri::command]
+async fn get_transfer_log(id: String) -> Result<Vec<transfer_log::LogLine>, String> {
+    if let Some(task) = download_tasks().lock().await.get(&id) {
+        return Ok(task.log_lines());
+    }
+    if let Some(task) = upload_tasks().lock().await.get(&id) {
+        return Ok(task.log_lines());
+    }
+
+    let history = transfer_history::load(usize::MAX).await.map_err(|e| format!("读取传输历史失败: {:#}", e))?;
+    history
+        .into_iter()
+        .find(|entry| entry.id == id)
+        .map(|entry| entry.log)
+        .ok_or_else(|| format!("未找到传输任务: {}", id))
+}
+
+/// 获取传输并发状态
+/// 
+/// 设置里transfer.max_concurrent_transfers为0时是自动模式，并发数从1开始按实测吞吐量逐步增加。
+/// 窗口不在前台时（后台模式）实际并发数还会受background.max_concurrent_transfers限制。
+/// 返回值：{"mode": "auto"/"fixed", "host", "limit", "effective_limit", "background", "active", "settled"}
+#[tauri::command]
+async fn get_transfer_concurrency() -> Result<serde_json::Value, String> {
+    Ok(concurrency::status())
+}
+
+/// 获取当前网络信息
+/// 
+/// 重新检测一次网络，返回网络类型、是否按流量计费和设置里的计费网络策略。
+/// 返回值：{"kind": "ethernet"/"wifi"/"cellular"/"unknown", "metered", "detected_metered", "source", "name", "policy", "approved"}
+#[tauri::command]
+async fn get_network_profile() -> Result<serde_json::Value, String> {
+    network_profile::refresh().await;
+    let profile = network_profile::current();
+    Ok(serde_json::json!({
+        "kind": profile.kind,
+        "metered": profile.is_metered(),
+        "detected_metered": profile.detected_metered,
+        "source": profile.source,
+        "name": profile.name,
+        "policy": settings::get().metered.policy,
+        "approved": network_profile::is_approved(),
+    }))
+}
+
+/// 允许在当前的计费网络上传输大文件
+/// 
+/// 收到metered-transfer-held事件、用户确认后调用，停下等待的传输会继续。换网络后需要重新确认。
+#[tauri::command]
+async fn approve_metered_transfers() -> Result<(), String> {
+    network_profile::approve();
+    Ok(())
+}
+
+/// 获取传输速度历史
+/// 
+/// 返回任务最近一段时间内每秒的传输字节数，前端可以用来画实时速度曲线。
+/// id可以是下载任务的file_id，也可以是上传任务的upload_id。
+#[tauri::command]
+async fn get_transfer_speed_history(id: String) -> Result<serde_json::Value, String> {
+    let download_tasks = download_tasks();
+    if let Some(task) = download_tasks.lock().await.get(&id) {
+        return Ok(serde_json::json!({
+            "id": id,
+            "direction": "download",
+            "interval_ms": 1000,
+            "samples": task.get_speed_history(),
+        }));
+    }
+    
+    let upload_tasks = upload_tasks();
+    if let Some(task) = upload_tasks.lock().await.get(&id) {
+        return Ok(serde_json::json!({
+            "id": id,
+            "direction": "upload",
+            "interval_ms": 1000,
+            "samples": task.get_speed_history(),
+        }));
+    }
+    
+    Err(format!("传输任务不存在: {}", id))
+}
+
+/// 获取流量使用统计
+/// 
+/// 返回本次会话和每天的上传/下载字节数，
+/// 方便按流量计费的用户了解客户端消耗了多少流量。
+/// 返回格式：{"session": {...}, "today": {...}, "days": [{"date", "uploaded", "downloaded"}]}
+#[tauri::command]
+async fn get_bandwidth_usage() -> Result<serde_json::Value, String> {
+    println!("前端调用get_bandwidth_usage命令...");
+    Ok(bandwidth::get_usage_summary().await)
+}
+
+/// 手动重试失败的传输任务
+/// 
+/// 重新获取认证信息后重建任务：下载从已写入磁盘的位置继续，
+/// 上传复用原来的upload_id，只补传服务器上缺少的分片。
+/// id可以是下载任务的file_id，也可以是上传任务的upload_id。
+/// 
+/// 返回值：{"id", "direction", "retry_count"}
+#[tauri::command]
+async fn retry_transfer(id: String) -> Result<serde_json::Value, String> {
+    println!("前端调用retry_transfer命令，id: {}", id);
+    
+    let (direction, retry_count) = transfer_manager::retry_transfer(&id).await?;
+    println!("传输任务 {} 已重新开始，第 {} 次重试", id, retry_count);
+    
+    Ok(serde_json::json!({
+        "id": id,
+        "direction": direction,
+        "retry_count": retry_count,
+    }))
+}
+
+/// 清理残留文件
+/// 
+/// 扫描下载目录里没下载完的文件、没用的下载元数据和临时文件，以及应用数据目录里的临时文件。
+/// 正在下载或暂停中的任务不会被清理。
+/// 
+/// 参数：
+/// - older_than_days: 只清理多少天前的文件，默认7天
+/// - confirm: 为true时才真正删除，否则只返回扫描结果（先给用户确认）
+/// 
+/// 返回值：{"files": [{path, kind, size, age_days}], "reclaimable_bytes", "deleted", "freed_bytes", "failed"}
+#[tauri::command]
+async fn cleanup_partial_files(older_than_days: Option<u64>, confirm: Option<bool>) -> Result<cleanup::CleanupReport, String> {
+    println!("前端调用cleanup_partial_files命令: {:?}天前, confirm={:?}", older_than_days, confirm);
+    
+    let older_than = std::time::Duration::from_secs(older_than_days.unwrap_or(7) * 86400);
+    let download_dir = get_app_data_dir().await.map_err(|e| e.to_string())?;
+    let data_dir = storage::get_app_data_dir()?;
+    let active = cleanup::active_download_paths().await;
+    
+    cleanup::cleanup(&download_dir, &data_dir, older_than, &active, confirm.unwrap_or(false))
+        .await
+        .map_err(|e| format!("清理残留文件失败: {}", e))
+}
+
+/// 校验并修复已下载的文件
+/// 
+/// 从服务器获取每个分片的哈希，逐个校验本地文件，
+/// 只重新下载校验不通过的分片。适用于下载完成后文件被杀毒软件或磁盘问题损坏的情况。
+/// 
+/// 返回值：{"file_id", "save_path", "chunks_total", "corrupt_chunks", "repaired_bytes"}
+#[tauri::command]
+async fn verify_and_repair_download(file_id: String) -> Result<serde_json::Value, String> {
+    println!("前端调用verify_and_repair_download命令，文件路径: {}", file_id);
+    
+    // 任务表里已经有这个文件时用原来的任务，它的running锁保证修复和下载不会同时写同一个文件；
+    // 只有没有任务，或者原来的任务已经结束（完成/出错）而且没在执行时才新建
+    async fn reusable(task: Option<&Arc<DownloadTask>>) -> Option<Arc<DownloadTask>> {
+        let task = task?;
+        let finished = matches!(task.get_progress().await.status, download::DownloadStatus::Completed | download::DownloadStatus::Error(_));
+        (!finished || task.is_running()).then(|| task.clone())
+    }
+    
+    let existing = reusable(download_tasks().lock().await.get(&file_id)).await;
+    let task_arc = match existing {
+        Some(task) => task,
+        None => {
+            let auth_info = acquire_auth_info().await?;
+            let download_dir = get_app_data_dir()
+                .await
+                .map_err(|e| format!("获取下载目录失败: {}", e))?;
+            let task = DownloadTask::new(file_id.clone(), download_dir.join(&file_id), auth_info)
+                .await
+                .map_err(|e| format!("创建下载任务失败: {}", e))?;
+            let task = Arc::new(task);
+            
+            // 检查和放进任务表在同一次加锁里做，创建期间别人登记了没结束的任务就用别人的
+            let mut tasks = download_tasks().lock().await;
+            match reusable(tasks.get(&file_id)).await {
+                Some(current) => current,
+                None => {
+                    // 放进任务表，修复过程中可以用get_download_progress查看状态
+                    tasks.insert(file_id.clone(), task.clone());
+                    task
+                }
             }
-        };
+        }
+    };
+    let save_path = task_arc.save_path().to_path_buf();
+    
+    let report = match task_arc.verify_and_repair().await {
+        Ok(report) => report,
+        Err(e) if e.is::<transfer_manager::TaskAlreadyRunning>() => {
+            return Err(format!("文件 {} 正在下载中，请等待下载完成后再校验", file_id));
+        }
+        Err(e) => return Err(format!("校验修复文件失败: {}", e)),
+    };
+    
+    Ok(serde_json::json!({
+        "file_id": file_id,
+        "save_path": save_path.to_string_lossy(),
+        "chunks_total": report.chunks_total,
+        "corrupt_chunks": report.corrupt_chunks,
+        "repaired_bytes": report.repaired_bytes,
+    }))
+}
+
+/// 测试哪种网络配置访问当前后端最快
+/// 
+/// 依次用不同的HTTP/2、连接池、tcp_nodelay组合请求后端，按耗时排序返回。
+/// apply为true时把最快的配置保存到设置里，之后的上传下载都会使用它。
+/// 
+/// 返回值：{"best", "applied", "results": [{network, avg_latency_ms, total_ms, failed, error}]}
+#[tauri::command]
+async fn benchmark_network_settings(apply: Option<bool>) -> Result<serde_json::Value, String> {
+    println!("前端调用benchmark_network_settings命令...");
+    
+    let results = http_client::benchmark_backend()
+        .await
+        .map_err(|e| format!("网络测速失败: {}", e))?;
+    
+    // 全部请求都失败说明后端连不上，不能拿来当结果
+    let best = results.first().filter(|r| r.avg_latency_ms.is_some()).cloned();
+    
+    let mut applied = false;
+    if let (Some(best), true) = (&best, apply.unwrap_or(false)) {
+        let network = serde_json::to_value(&best.network)
+            .map_err(|e| format!("序列化网络设置失败: {}", e))?;
+        settings::update(serde_json::json!({ "network": network }))
+            .await
+            .map_err(|e| format!("保存网络设置失败: {}", e))?;
+        applied = true;
+        println!("已应用最快的网络配置: {:?}", best.network);
+    }
+    
+    Ok(serde_json::json!({
+        "best": best,
+        "applied": applied,
+        "results": results,
+    }))
+}
+
+/// 启动本地WebDAV服务
+/// 
+/// 启动后可以在资源管理器/访达里添加返回的url（http://127.0.0.1:端口/<随机前缀>/）作为网络位置访问云盘，
+/// 读写操作都通过笔提供的认证信息转发给后端。只监听本机地址，前缀每次启动都不一样。
+/// port不传时使用设置里的端口；remember为true时保存为开机自动启动。
+/// 
+/// 返回值：{"running", "port", "url"}
+#[tauri::command]
+async fn start_webdav_server(port: Option<u16>, remember: Option<bool>) -> Result<serde_json::Value, String> {
+    println!("前端调用start_webdav_server命令，端口: {:?}", port);
+    
+    let port = port.unwrap_or_else(|| settings::get().webdav.port);
+    let port = webdav::start(port)
+        .await
+        .map_err(|e| format!("启动WebDAV服务失败: {}", e))?;
+    
+    if remember.unwrap_or(false) {
+        settings::update(serde_json::json!({ "webdav": { "enabled": true, "port": port } }))
+            .await
+            .map_err(|e| format!("保存WebDAV设置失败: {}", e))?;
+    }
+    
+    Ok(serde_json::json!({
+        "running": true,
+        "port": port,
+        "url": webdav::running_url().await,
+    }))
+}
+
+/// 停止本地WebDAV服务
+/// 
+/// 同时取消开机自动启动
+#[tauri::command]
+async fn stop_webdav_server() -> Result<bool, String> {
+    println!("前端调用stop_webdav_server命令...");
+    
+    let stopped = webdav::stop().await;
+    if settings::get().webdav.enabled {
+        settings::update(serde_json::json!({ "webdav": { "enabled": false } }))
+            .await
+            .map_err(|e| format!("保存WebDAV设置失败: {}", e))?;
+    }
+    Ok(stopped)
+}
+
+/// 获取本地WebDAV服务状态
+/// 
+/// 返回值：{"running", "port", "url"}，没有运行时port和url为null
+#[tauri::command]
+async fn get_webdav_status() -> Result<serde_json::Value, String> {
+    let port = webdav::running_port().await;
+    Ok(serde_json::json!({
+        "running": port.is_some(),
+        "port": port,
+        "url": webdav::running_url().await,
+    }))
+}
+
+/// 启动本地HTTP接口
+/// 
+/// 开启后可以用脚本通过 http://127.0.0.1:端口/api/ 调用客户端功能
+/// （status、totp、transfers、downloads、uploads、transfers/{id}/retry），
+/// 请求头需要带 Authorization: Bearer <token>，令牌保存在token_path文件里。
+/// port不传时使用设置里的端口；remember为true时保存为开机自动启动。
+/// 
+/// 返回值：{"running", "port", "token_path"}
+#[tauri::command]
+async fn start_local_api(port: Option<u16>, remember: Option<bool>) -> Result<serde_json::Value, String> {
+    println!("前端调用start_local_api命令，端口: {:?}", port);
+    
+    let port = port.unwrap_or_else(|| settings::get().local_api.port);
+    let port = local_api::start(port)
+        .await
+        .map_err(|e| format!("启动本地接口失败: {}", e))?;
+    
+    if remember.unwrap_or(false) {
+        settings::update(serde_json::json!({ "local_api": { "enabled": true, "port": port } }))
+            .await
+            .map_err(|e| format!("保存本地接口设置失败: {}", e))?;
+    }
+    
+    let token_path = local_api::get_token_path().map_err(|e| e.to_string())?;
+    Ok(serde_json::json!({
+        "running": true,
+        "port": port,
+        "token_path": token_path.to_string_lossy(),
+    }))
+}
+
+/// 停止本地HTTP接口
+/// 
+/// 同时取消开机自动启动
+#[tauri::command]
+async fn stop_local_api() -> Result<bool, String> {
+    println!("前端调用stop_local_api命令...");
+    
+    let stopped = local_api::stop().await;
+    if settings::get().local_api.enabled {
+        settings::update(serde_json::json!({ "local_api": { "enabled": false } }))
+            .await
+            .map_err(|e| format!("保存本地接口设置失败: {}", e))?;
+    }
+    Ok(stopped)
+}
+
+/// 获取运行指标
+/// 
+/// 返回值：正在进行/暂停/失败/完成的传输数量、传输失败率、蓝牙连接和重连次数、认证失败次数等，
+/// 格式和本地接口 /api/metrics?format=json 一样
+#[tauri::command]
+async fn get_metrics() -> Result<metrics::MetricsSnapshot, String> {
+    Ok(metrics::snapshot().await)
+}
+
+/// 打开或关闭匿名统计上报
+/// 
+/// 参数：
+/// - enabled: true打开（生成新的匿名ID），false关闭（删掉匿名ID，不再上报）
+/// 
+/// 返回值：更新后的统计上报设置
+#[tauri::command]
+async fn set_telemetry_enabled(enabled: bool) -> Result<settings::TelemetrySettings, String> {
+    println!("前端调用set_telemetry_enabled命令: {}", enabled);
+    telemetry::set_enabled(enabled).await.map_err(|e| format!("{:#}", e))
+}
+
+/// 预览匿名统计上报的内容
+/// 
+/// 返回值：是否打开、上报地址、距下次上报的秒数，以及现在上报时会发出的完整内容（report）
+#[tauri::command]
+async fn preview_telemetry() -> Result<telemetry::TelemetryPreview, String> {
+    println!("前端调用preview_telemetry命令...");
+    Ok(telemetry::preview().await)
+}
+
+/// 获取后端维护状态
+/// 
+/// 后端返回503并带Retry-After时进入维护状态，传输会等到resume_at再继续
+/// 
+/// 返回值：{"active", "resume_at"}，resume_at是Unix时间戳（秒）
+#[tauri::command]
+async fn get_server_maintenance_status() -> Result<server_maintenance::MaintenanceStatus, String> {
+    Ok(server_maintenance::status())
+}
+
+/// 获取本地HTTP接口状态
+/// 
+/// 返回值：{"running", "port", "token_path"}，没有运行时port为null
+#[tauri::command]
+async fn get_local_api_status() -> Result<serde_json::Value, String> {
+    let port = local_api::running_port().await;
+    let token_path = local_api::get_token_path().map_err(|e| e.to_string())?;
+    Ok(serde_json::json!({
+        "running": port.is_some(),
+        "port": port,
+        "token_path": token_path.to_string_lossy(),
+    }))
+}
+
+/// 给云盘上的音视频文件生成本地播放地址
+/// 
+/// 返回的url可以直接放进<video>/<audio>，播放器拖动进度条时按需下载对应的部分
+/// 
+/// 返回值：{"url", "file_id", "size", "content_type"}
+#[tauri::command]
+async fn stream_remote_file(file_id: String) -> Result<media_stream::StreamInfo, String> {
+    println!("前端调用stream_remote_file命令，文件路径: {}", file_id);
+    media_stream::open(&file_id).await.map_err(|e| format!("生成播放地址失败: {:#}", e))
+}
+
+/// 获取分片缓存状态
+/// 
+/// 返回值：{"dir", "entries", "used_bytes", "capacity_bytes"}
+#[tauri::command]
+async fn get_chunk_cache_status() -> Result<chunk_cache::CacheStatus, String> {
+    Ok(chunk_cache::status().await)
+}
+
+/// 清空分片缓存，返回删掉的分片数
+#[tauri::command]
+async fn clear_chunk_cache() -> Result<usize, String> {
+    println!("前端调用clear_chunk_cache命令...");
+    Ok(chunk_cache::clear().await)
+}
+
+/// 按类别统计本应用占用的磁盘空间
+/// 
+/// 包括临时目录（分片缓存、上传快照、WebDAV写入中的文件）、应用数据目录、下载目录里没下载完的文件。
+/// 
+/// 返回值：{"scratch_dir", "items": [{kind, path, bytes, files}], "total_bytes", "cache_limit_bytes"}
+#[tauri::command]
+async fn get_storage_usage_breakdown() -> Result<storage_usage::StorageBreakdown, String> {
+    println!("前端调用get_storage_usage_breakdown命令...");
+    let data_dir = storage::get_app_data_dir()?;
+    // 下载目录拿不到时只统计临时目录和数据目录
+    let download_dir = get_app_data_dir().await.ok();
+    storage_usage::breakdown(&settings::scratch_dir(), &data_dir, download_dir.as_deref())
+        .await
+        .map_err(|e| format!("统计磁盘占用失败: {:#}", e))
+}
+
+/// 创建文件分享链接
+/// 
+/// file_id是完整的云盘路径，expiry是链接有效秒数（不传表示永久有效），
+/// copy_to_clipboard为true时同时把链接复制到剪贴板
+/// 
+/// 返回值：{"file_id", "url", "expires_at", "copied"}
+#[tauri::command]
+async fn create_share_link(
+    app_handle: tauri::AppHandle,
+    file_id: String,
+    expiry: Option<u64>,
+    copy_to_clipboard: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    println!("前端调用create_share_link命令，文件路径: {}，有效期: {:?}秒", file_id, expiry);
+    
+    let auth_info = acquire_auth_info().await?;
+    let link = cloud_api::create_share_link(&auth_info, &file_id, expiry)
+        .await
+        .map_err(|e| format!("创建分享链接失败: {}", e))?;
+    
+    let mut copied = false;
+    if copy_to_clipboard.unwrap_or(false) {
+        use tauri_plugin_clipboard_manager::ClipboardExt;
+        match app_handle.clipboard().write_text(link.url.clone()) {
+            Ok(_) => copied = true,
+            Err(e) => println!("复制分享链接到剪贴板失败: {}", e),
+        }
+    }
+    
+    println!("分享链接已创建: {}", link.url);
+    Ok(serde_json::json!({
+        "file_id": file_id,
+        "url": link.url,
+        "expires_at": link.expires_at,
+        "copied": copied,
+    }))
+}
+
+/// 获取云盘文件的详细信息
+/// 
+/// 不用下载文件就能拿到大小、修改时间、类型、服务器哈希和分享状态，
+/// 用于详情面板，也可以用来判断文件有没有变化
+/// 
+/// 返回值：{"path", "size", "modified_at", "content_type", "etag", "hash", "shared", "share_url"}
+#[tauri::command]
+async fn stat_remote_file(path: String) -> Result<cloud_api::RemoteFileStat, String> {
+    println!("前端调用stat_remote_file命令，路径: {}", path);
+    
+    let auth_info = acquire_auth_info().await?;
+    cloud_api::stat_file(&auth_info, &path)
+        .await
+        .map_err(|e| format!("获取文件信息失败: {}", e))
+}
+
+/// 搜索云盘文件
+/// 
+/// 按文件名搜索（不区分大小写），可以限定目录（path_prefix）和类型
+/// （file_type: image/video/audio/document/archive/code/other/folder）。
+/// 后端不支持搜索时会递归列目录在本地过滤，返回值里fallback为true。
+/// page从1开始，page_size默认50。
+/// 
+/// 返回值：{"entries", "total", "page", "page_size", "has_more", "fallback", "truncated"}
+#[tauri::command]
+async fn search_remote_files(
+    query: String,
+    path_prefix: Option<String>,
+    file_type: Option<String>,
+    page: Option<usize>,
+    page_size: Option<usize>,
+) -> Result<cloud_api::SearchResult, String> {
+    println!("前端调用search_remote_files命令，关键词: {}，目录: {:?}，类型: {:?}", query, path_prefix, file_type);
+    
+    let auth_info = acquire_auth_info().await?;
+    let search_query = cloud_api::SearchQuery {
+        query: &query,
+        path_prefix: path_prefix.as_deref(),
+        file_type: file_type.as_deref(),
+        page: page.unwrap_or(1).max(1),
+        page_size: page_size.unwrap_or(50).clamp(1, 500),
+    };
+    
+    cloud_api::search(&auth_info, &search_query)
+        .await
+        .map_err(|e| format!("搜索文件失败: {}", e))
+}
+
+/// 分页获取云盘目录内容
+/// 
+/// 目录里有几万个文件时一次全部返回会卡住界面，用这个命令按页获取。
+/// cursor传上一页返回的next_cursor，第一页不传；limit默认500。
+/// 
+/// 返回值：{"entries", "next_cursor"}，next_cursor为null表示已经是最后一页
+#[tauri::command]
+async fn list_remote_dir(path: String, cursor: Option<String>, limit: Option<usize>) -> Result<cloud_api::ListPage, String> {
+    println!("前端调用list_remote_dir命令，路径: {}，游标: {:?}", path, cursor);
+    
+    let auth_info = acquire_auth_info().await?;
+    cloud_api::list_dir_page(&auth_info, &path, cursor.as_deref(), limit.unwrap_or(500).clamp(1, 5000))
+        .await
+        .map_err(|e| format!("获取文件列表失败: {}", e))
+}
+
+/// 以事件流的方式获取云盘目录内容
+/// 
+/// 立即返回request_id，之后在后台逐页获取，每页发一个dir-listing-page事件：
+/// {"request_id", "path", "page", "entries", "done", "error"}，done为true表示结束。
+/// 前端收到一页就可以先显示，不用等整个目录加载完。
+#[tauri::command]
+async fn stream_remote_dir(path: String, page_size: Option<usize>) -> Result<String, String> {
+    println!("前端调用stream_remote_dir命令，路径: {}", path);
+    
+    let auth_info = acquire_auth_info().await?;
+    let request_id = uuid::Uuid::new_v4().to_string();
+    let page_size = page_size.unwrap_or(500).clamp(1, 5000);
+    
+    let id = request_id.clone();
+    supervisor::spawn(format!("stream_remote_dir:{}", request_id), async move {
+        let mut auth_info = auth_info;
+        let mut cursor: Option<String> = None;
+        let mut page = 0;
         
-        println!("获取到真实下载进度: {} - {}%", file_id, 
-            if progress.total_size > 0 {
-                (progress.downloaded as f64 / progress.total_size as f64 * 100.0).round() as u32
-            } else {
-                0
-            });
+        loop {
+            page += 1;
+            let result = cloud_api::list_dir_page(&auth_info, &path, cursor.as_deref(), page_size).await;
+            let (entries, next_cursor, error) = match result {
+                Ok(list) => (list.entries, list.next_cursor, None),
+                Err(e) => (Vec::new(), None, Some(e.to_string())),
+            };
+            let done = next_cursor.is_none();
+            
+            event_emitter::emit_event("dir-listing-page", serde_json::json!({
+                "request_id": id,
+                "path": path,
+                "page": page,
+                "entries": entries,
+                "done": done,
+                "error": error,
+            }));
+            
+            if done {
+                println!("目录 {} 加载完成，共 {} 页", path, page);
+                break;
+            }
+            cursor = next_cursor;
+            
+            // 大目录加载时间可能超过TOTP有效期，每页前重新获取认证信息
+            match acquire_auth_info().await {
+                Ok(fresh) => auth_info = fresh,
+                Err(e) => println!("刷新认证信息失败，继续使用旧的: {}", e),
+            }
+        }
+    });
+    
+    Ok(request_id)
+}
+
+// 向后端确认目录有没有变化，变了就更新缓存，返回新的条目
+async fn revalidate_listing(auth_info: &AuthInfo, path: &str, etag: Option<&str>) -> Result<Option<Vec<cloud_api::RemoteEntry>>, String> {
+    match cloud_api::list_dir_if_changed(auth_info, path, etag).await {
+        Ok(cloud_api::ConditionalList::NotModified) => {
+            listing_cache::touch(path).await;
+            Ok(None)
+        }
+        Ok(cloud_api::ConditionalList::Modified { entries, etag }) => {
+            listing_cache::put(path, entries.clone(), etag).await;
+            Ok(Some(entries))
+        }
+        Err(e) => Err(format!("获取文件列表失败: {}", e)),
+    }
+}
+
+/// 获取云盘目录内容（带本地缓存）
+/// 
+/// 有缓存时立即返回缓存（cached为true），同时在后台向后端确认，
+/// 目录有变化会更新缓存并发dir-listing-updated事件：{"path", "entries"}。
+/// 没有缓存时直接请求后端。
+/// 
+/// 返回值：{"path", "entries", "cached", "fetched_at"}
+#[tauri::command]
+async fn list_remote_dir_cached(path: String) -> Result<serde_json::Value, String> {
+    println!("前端调用list_remote_dir_cached命令，路径: {}", path);
+    
+    if let Some(cached) = listing_cache::get(&path).await {
+        let revalidate_path = path.clone();
+        let etag = cached.etag.clone();
+        supervisor::spawn(format!("revalidate_listing:{}", path), async move {
+            let auth_info = match acquire_auth_info().await {
+                Ok(auth_info) => auth_info,
+                Err(e) => {
+                    println!("后台刷新目录 {} 失败: {}", revalidate_path, e);
+                    return;
+                }
+            };
+            match revalidate_listing(&auth_info, &revalidate_path, etag.as_deref()).await {
+                Ok(Some(entries)) => {
+                    println!("目录 {} 有变化，通知前端刷新", revalidate_path);
+                    event_emitter::emit_event("dir-listing-updated", serde_json::json!({
+                        "path": revalidate_path,
+                        "entries": entries,
+                    }));
+                }
+                Ok(None) => {}
+                Err(e) => println!("后台刷新目录 {} 失败: {}", revalidate_path, e),
+            }
+        });
         
         return Ok(serde_json::json!({
-            "file_id": progress.file_id,
-            "file_name": progress.file_name,
-            "total_size": progress.total_size,
-            "downloaded": progress.downloaded,
-            "status": status_str,
-            "chunks_total": progress.chunks_total,
-            "chunks_completed": progress.chunks_completed,
-            "speed_kbps": progress.speed_kbps,
-            "progress_percentage": if progress.total_size > 0 {
-                (progress.downloaded as f64 / progress.total_size as f64 * 100.0).round() as u32
-            } else {
-                0
-            },
+            "path": path,
+            "entries": cached.entries,
+            "cached": true,
+            "fetched_at": cached.fetched_at,
         }));
     }
     
-    // 如果任务不存在，返回一个默认的进度信息
-    println!("下载任务 {} 不存在，返回默认进度信息", file_id);
-    
+    let auth_info = acquire_auth_info().await?;
+    let entries = revalidate_listing(&auth_info, &path, None).await?.unwrap_or_default();
     Ok(serde_json::json!({
-        "file_id": file_id,
-        "file_name": "未知文件",
-        "total_size": 0,
-        "downloaded": 0,
-        "status": "Pending",
-        "chunks_total": 0,
-        "chunks_completed": 0,
-        "speed_kbps": 0.0,
-        "progress_percentage": 0,
+        "path": path,
+        "entries": entries,
+        "cached": false,
+        "fetched_at": chrono::Utc::now().timestamp(),
     }))
 }
 
-/// 暂停下载
+/// 清空目录列表缓存
+#[tauri::command]
+async fn clear_listing_cache() -> Result<(), String> {
+    println!("前端调用clear_listing_cache命令...");
+    listing_cache::clear()
+        .await
+        .map_err(|e| format!("清空目录缓存失败: {}", e))
+}
+
+// 回收站相关命令
+
+/// 把云盘文件移到回收站
 /// 
-/// TODO: 需要下载任务管理器来实现真正的暂停功能
-/// 先简单返回成功
+/// path是完整的云盘路径，移到回收站后可以用restore_from_trash恢复
+#[tauri::command]
+async fn trash_remote_file(path: String) -> Result<(), String> {
+    println!("前端调用trash_remote_file命令，路径: {}", path);
+    
+    let auth_info = acquire_auth_info().await?;
+    cloud_api::delete(&auth_info, &path, false)
+        .await
+        .map_err(|e| format!("移到回收站失败: {}", e))
+}
+
+/// 列出回收站内容
+/// 
+/// 返回值：{"entries": [{name, path, original_path, is_dir, size, deleted_at}]}
+#[tauri::command]
+async fn list_trash() -> Result<serde_json::Value, String> {
+    println!("前端调用list_trash命令...");
+    
+    let auth_info = acquire_auth_info().await?;
+    let entries = cloud_api::list_trash(&auth_info)
+        .await
+        .map_err(|e| format!("获取回收站列表失败: {}", e))?;
+    
+    Ok(serde_json::json!({ "entries": entries }))
+}
+
+/// 从回收站恢复文件
+/// 
+/// path使用list_trash返回的path字段
 #[tauri::command]
-async fn pause_download(file_id: String) -> Result<(), String> {
-    println!("前端调用pause_download命令，文件ID: {}", file_id);
+async fn restore_from_trash(path: String) -> Result<(), String> {
+    println!("前端调用restore_from_trash命令，路径: {}", path);
     
-    // 暂时简单实现
-    println!("下载暂停功能待实现");
-    Ok(())
+    let auth_info = acquire_auth_info().await?;
+    cloud_api::restore_from_trash(&auth_info, &path)
+        .await
+        .map_err(|e| format!("恢复文件失败: {}", e))
 }
 
-/// 恢复下载
+/// 清空回收站
 /// 
-/// TODO: 需要下载任务管理器来实现真正的恢复功能
-/// 先简单返回成功
+/// 回收站里的文件会被永久删除，无法恢复
 #[tauri::command]
-async fn resume_download(file_id: String) -> Result<(), String> {
-    println!("前端调用resume_download命令，文件ID: {}", file_id);
+async fn empty_trash() -> Result<(), String> {
+    println!("前端调用empty_trash命令...");
     
-    // 暂时简单实现
-    println!("下载恢复功能待实现");
-    Ok(())
+    let auth_info = acquire_auth_info().await?;
+    cloud_api::empty_trash(&auth_info)
+        .await
+        .map_err(|e| format!("清空回收站失败: {}", e))
 }
 
 // 上传相关命令
@@ -484,15 +1768,13 @@ async fn resume_download(file_id: String) -> Result<(), String> {
 async fn upload_file(file_path: String) -> Result<String, String> {
     println!("前端调用upload_file命令，文件路径: {}", file_path);
     
-    // 先获取设备ID和TOTP
-    let device_id = get_device_id().await.map_err(|e| format!("获取设备ID失败: {}", e))?;
-    let totp = get_totp().await.map_err(|e| format!("获取TOTP失败: {}", e))?;
+    // 先用最近查到的容量检查，放不下就不用找笔了
+    check_cached_quota(std::slice::from_ref(&file_path))?;
     
-    // 创建认证信息
-    let auth_info = AuthInfo {
-        device_id,
-        totp,
-    };
+    // 获取认证信息
+    let auth_info = acquire_checked_auth_info().await?;
+    
+    check_quota(&auth_info, std::slice::from_ref(&file_path)).await?;
     
     // 创建上传任务
     let task = UploadTask::new(std::path::PathBuf::from(&file_path), auth_info, None)
@@ -507,28 +1789,14 @@ async fn upload_file(file_path: String) -> Result<String, String> {
     };
     
     // 初始化上传任务管理器
-    let upload_tasks = UPLOAD_TASKS.get_or_init(|| Mutex::new(HashMap::new()));
+    let upload_tasks = upload_tasks();
     let mut tasks_map = upload_tasks.lock().await;
     tasks_map.insert(upload_id.clone(), task_arc.clone());
     
     println!("上传任务已添加到管理器，upload_id: {}，开始后台上传...", upload_id);
     
-    // 在后台异步执行上传，不阻塞前端响应
-    let task_for_spawn = task_arc.clone();
-    let upload_id_for_spawn = upload_id.clone();
-    
-    tokio::spawn(async move {
-        println!("后台上传任务开始: {}", upload_id_for_spawn);
-        
-        match task_for_spawn.start().await {
-            Ok(_) => {
-                println!("后台上传完成: {}", upload_id_for_spawn);
-            }
-            Err(e) => {
-                println!("后台上传失败: {}，错误: {}", upload_id_for_spawn, e);
-            }
-        }
-    });
+    // 在后台异步执行上传，不阻塞前端响应（失败后会自动重试）
+    spawn_upload(task_arc);
     
     // 立即返回，不等待上传完成
     let result = format!("上传已开始，upload_id: {}，可使用get_upload_progress查询进度", upload_id);
@@ -538,84 +1806,31 @@ async fn upload_file(file_path: String) -> Result<String, String> {
 
 /// 获取上传进度
 /// 
-/// 从上传任务管理器中获取真实的上传进度信息
+/// 已废弃：新代码用v2_get_upload_progress（见commands_v2模块），这里只转换成旧格式
 /// 如果任务不存在，返回一个默认的进度信息
 #[tauri::command]
 async fn get_upload_progress(upload_id: String) -> Result<serde_json::Value, String> {
     println!("前端调用get_upload_progress命令，upload_id: {}", upload_id);
     
-    // 尝试从上转任务管理器中获取任务
-    let upload_tasks = UPLOAD_TASKS.get_or_init(|| Mutex::new(HashMap::new()));
-    let tasks_map = upload_tasks.lock().await;
-    
-    if let Some(task) = tasks_map.get(&upload_id) {
-        // 获取真实的进度信息
-        let progress = task.get_progress().await;
-        
-        // 将进度信息转换为JSON
-        let status_str = match &progress.status {
-            upload::UploadStatus::Pending => "Pending",
-            upload::UploadStatus::Uploading => "Uploading",
-            upload::UploadStatus::Paused => "Paused",
-            upload::UploadStatus::Completed => "Completed",
-            upload::UploadStatus::Error(err_msg) => {
-                // 错误信息包含在状态字符串中
-                return Ok(serde_json::json!({
-                    "upload_id": progress.upload_id,
-                    "filename": progress.filename,
-                    "total_size": progress.total_size,
-                    "uploaded": progress.uploaded,
-                    "status": format!("Error: {}", err_msg),
-                    "chunks_total": progress.chunks_total,
-                    "chunks_completed": progress.chunks_completed,
-                    "speed_kbps": progress.speed_kbps,
-                    "progress_percentage": if progress.total_size > 0 {
-                        (progress.uploaded as f64 / progress.total_size as f64 * 100.0).round() as u32
-                    } else {
-                        0
-                    },
-                }));
-            }
-        };
-        
-        println!("获取到真实上传进度: {} - {}%", upload_id, 
-            if progress.total_size > 0 {
-                (progress.uploaded as f64 / progress.total_size as f64 * 100.0).round() as u32
-            } else {
-                0
-            });
-        
-        return Ok(serde_json::json!({
-            "upload_id": progress.upload_id,
-            "filename": progress.filename,
-            "total_size": progress.total_size,
-            "uploaded": progress.uploaded,
-            "status": status_str,
-            "chunks_total": progress.chunks_total,
-            "chunks_completed": progress.chunks_completed,
-            "speed_kbps": progress.speed_kbps,
-            "progress_percentage": if progress.total_size > 0 {
-                (progress.uploaded as f64 / progress.total_size as f64 * 100.0).round() as u32
-            } else {
-                0
-            },
-        }));
+    match commands_v2::v2_get_upload_progress(upload_id.clone()).await {
+        Ok(progress) => Ok(progress.to_legacy_json()),
+        Err(_) => {
+            // 如果任务不存在，返回一个默认的进度信息
+            println!("上传任务 {} 不存在，返回默认进度信息", upload_id);
+            Ok(serde_json::json!({
+                "upload_id": upload_id,
+                "filename": "未知文件",
+                "total_size": 0,
+                "uploaded": 0,
+                "status": "Pending",
+                "chunks_total": 0,
+                "chunks_completed": 0,
+                "speed_kbps": 0.0,
+                "retry_count": 0,
+                "progress_percentage": 0,
+            }))
+        }
     }
-    
-    // 如果任务不存在，返回一个默认的进度信息
-    println!("上传任务 {} 不存在，返回默认进度信息", upload_id);
-    
-    Ok(serde_json::json!({
-        "upload_id": upload_id,
-        "filename": "未知文件",
-        "total_size": 0,
-        "uploaded": 0,
-        "status": "Pending",
-        "chunks_total": 0,
-        "chunks_completed": 0,
-        "speed_kbps": 0.0,
-        "progress_percentage": 0,
-    }))
 }
 
 /// 暂停上传
@@ -627,7 +1842,7 @@ async fn pause_upload(upload_id: String) -> Result<(), String> {
     println!("前端调用pause_upload命令，upload_id: {}", upload_id);
     
     // 尝试从上传任务管理器中获取任务
-    let upload_tasks = UPLOAD_TASKS.get_or_init(|| Mutex::new(HashMap::new()));
+    let upload_tasks = upload_tasks();
     let tasks_map = upload_tasks.lock().await;
     
     if let Some(task) = tasks_map.get(&upload_id) {
@@ -653,16 +1868,115 @@ async fn resume_upload(upload_id: String) -> Result<(), String> {
     Ok(())
 }
 
+/// 创建上传任务并注册到上传任务管理器
+/// 
+/// 批量上传和批量重试共用这个辅助函数，返回upload_id和任务本身
+async fn create_and_register_upload(
+    file_path: &str,
+    auth_info: &AuthInfo,
+    target_path: Option<&str>,
+) -> Result<(String, Arc<UploadTask>), String> {
+    let task = UploadTask::new(
+        std::path::PathBuf::from(file_path),
+        auth_info.clone(),
+        target_path
+    )
+        .await
+        .map_err(|e| format!("创建上传任务失败: {}", e))?;
+    
+    let task_arc = Arc::new(task);
+    let upload_id = {
+        let progress = task_arc.get_progress().await;
+        progress.upload_id.clone()
+    };
+    
+    let upload_tasks = upload_tasks();
+    upload_tasks.lock().await.insert(upload_id.clone(), task_arc.clone());
+    
+    Ok((upload_id, task_arc))
+}
+
+// 文件放不下时发quota-warning事件提醒前端，并返回[quota_exceeded]错误，不创建任务
+fn ensure_fits(quota: &cloud_api::StorageQuota, file_paths: &[String]) -> Result<(), String> {
+    let required: u64 = file_paths
+        .iter()
+        .filter_map(|p| std::fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum();
+    if required <= quota.remaining() {
+        return Ok(());
+    }
+    
+    println!("上传需要 {} 字节，云盘剩余 {} 字节，容量不够", required, quota.remaining());
+    event_emitter::emit_event("quota-warning", serde_json::json!({
+        "file_paths": file_paths,
+        "required": required,
+        "remaining": quota.remaining(),
+        "used": quota.used,
+        "total": quota.total,
+    }));
+    Err(format!("[quota_exceeded] 云盘剩余空间不够：需要 {} 字节，剩余 {} 字节", required, quota.remaining()))
+}
+
+// 找笔拿认证信息之前调用：最近查过容量时直接用查到的结果检查，放不下就不用叫醒笔；
+// 没有查过或者已经过期时跳过，拿到认证信息后由check_quota检查
+fn check_cached_quota(file_paths: &[String]) -> Result<(), String> {
+    match cloud_api::cached_storage_quota() {
+        Some(quota) => ensure_fits(&quota, file_paths),
+        None => Ok(()),
+    }
+}
+
+// 上传前查询云盘剩余容量，放不下时拒绝；查询失败（比如后端没有这个接口）就跳过
+async fn check_quota(auth_info: &AuthInfo, file_paths: &[String]) -> Result<(), String> {
+    match cloud_api::get_storage_quota(auth_info).await {
+        Ok(quota) => ensure_fits(&quota, file_paths),
+        Err(e) => {
+            println!("查询云盘容量失败，跳过容量检查: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// 查询云盘容量
+/// 
+/// 返回值：{"used", "total", "remaining"}，单位都是字节
+#[tauri::command]
+async fn get_storage_quota() -> Result<serde_json::Value, String> {
+    println!("前端调用get_storage_quota命令...");
+    
+    let auth_info = acquire_auth_info().await?;
+    let quota = cloud_api::get_storage_quota(&auth_info)
+        .await
+        .map_err(|e| format!("查询云盘容量失败: {}", e))?;
+    
+    Ok(serde_json::json!({
+        "used": quota.used,
+        "total": quota.total,
+        "remaining": quota.remaining(),
+    }))
+}
+
 /// 批量上传文件（从文件路径列表）
 /// 
 /// 前端提供文件路径列表，后端依次上传每个文件
 /// 支持分片上传和断点续传，分片大小为4MB
 /// 
+/// 单个文件创建任务失败不会中断整个批次，失败的文件记录在返回值的failed里，
+/// 可以用返回的batch_id调用retry_failed_in_batch重试
+/// 
+/// dry_run为true时只返回上传计划（要传的文件、总字节数、会覆盖的云端文件、跳过的文件），
+/// 不创建任何任务，前端让用户确认后再不带dry_run调用一次
+/// 
 /// 注意：上传过程可能需要较长时间，特别是大文件
 /// 会在后台异步执行上传，不阻塞前端响应
 #[tauri::command]
-async fn upload_files_from_paths(file_paths: Vec<String>, target_path: Option<String>) -> Result<serde_json::Value, String> {
-    println!("前端调用upload_files_from_paths命令，文件数量: {}, 目标路径: {:?}", file_paths.len(), target_path);
+async fn upload_files_from_paths(
+    file_paths: Vec<String>,
+    target_path: Option<String>,
+    dry_run: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    println!("前端调用upload_files_from_paths命令，文件数量: {}, 目标路径: {:?}, dry_run: {:?}", file_paths.len(), target_path, dry_run);
     
     if file_paths.is_empty() {
         return Ok(serde_json::json!({
@@ -671,76 +1985,226 @@ async fn upload_files_from_paths(file_paths: Vec<String>, target_path: Option<St
         }));
     }
     
-    // 先获取设备ID和TOTP（只需要获取一次）
-    let device_id = get_device_id().await.map_err(|e| format!("获取设备ID失败: {}", e))?;
-    let totp = get_totp().await.map_err(|e| format!("获取TOTP失败: {}", e))?;
+    if !dry_run.unwrap_or(false) {
+        check_cached_quota(&file_paths)?;
+    }
     
-    // 创建认证信息
-    let auth_info = AuthInfo {
-        device_id,
-        totp,
-    };
+    // 获取认证信息（只需要获取一次）
+    let auth_info = acquire_checked_auth_info().await?;
     
-    let mut upload_ids = Vec::new();
-    let mut file_paths_str = Vec::new();
+    if dry_run.unwrap_or(false) {
+        let mut plan = transfer_plan::plan_upload(
+            &file_paths,
+            target_path.as_deref(),
+            settings::get().transfer.symlink_policy,
+        ).await;
+        mark_plan_conflicts(&auth_info, &mut plan).await;
+        return Ok(serde_json::json!({
+            "success": true,
+            "dry_run": true,
+            "plan": plan,
+        }));
+    }
     
-    // 初始化上传任务管理器
-    let upload_tasks = UPLOAD_TASKS.get_or_init(|| Mutex::new(HashMap::new()));
+    check_quota(&auth_info, &file_paths).await?;
     
-    // 为每个文件创建上传任务
+    let mut batch = UploadBatch::new(target_path.clone());
+    
+    // 为每个文件创建上传任务，失败的记录下来继续处理下一个
     for file_path in file_paths {
-        let file_path_str = file_path.clone();
-        file_paths_str.push(file_path_str.clone());
-        
-        // 创建上传任务，传递目标路径
-        let task = UploadTask::new(
-            std::path::PathBuf::from(&file_path), 
-            auth_info.clone(), 
-            target_path.as_deref()
-        )
-            .await
-            .map_err(|e| format!("创建上传任务失败: {}", e))?;
-        
-        // 将任务保存到全局管理器中
-        let task_arc = Arc::new(task);
-        let upload_id = {
-            let progress = task_arc.get_progress().await;
-            progress.upload_id.clone()
+        match create_and_register_upload(&file_path, &auth_info, target_path.as_deref()).await {
+            Ok((upload_id, task_arc)) => {
+                spawn_upload(task_arc);
+                batch.items.push(BatchItem::ok(file_path, upload_id));
+            }
+            Err(e) => {
+                println!("文件 {} 创建上传任务失败: {}", file_path, e);
+                batch.items.push(BatchItem::failed(file_path, e));
+            }
+        }
+    }
+    
+    let summary = batch.summary();
+    println!("批量上传任务已添加到管理器，batch_id: {}，目标路径: {:?}，结果: {}", 
+        batch.batch_id, target_path, summary["succeeded_count"]);
+    
+    batch::save_batch(batch).await;
+    
+    Ok(summary)
+}
+
+// 列出计划里每个云端目录，标记会覆盖的同名文件
+// 目录还不存在（列目录失败）时当作没有冲突
+async fn mark_plan_conflicts(auth_info: &AuthInfo, plan: &mut transfer_plan::TransferPlan) {
+    for remote_dir in plan.remote_dirs() {
+        match cloud_api::list_dir(auth_info, remote_dir.as_deref().unwrap_or("")).await {
+            Ok(entries) => plan.mark_conflicts(remote_dir.as_deref(), &entries),
+            Err(e) => println!("获取目录 {:?} 列表失败，不检查冲突: {}", remote_dir, e),
+        }
+    }
+}
+
+/// 上传整个文件夹
+/// 
+/// 文件夹上传到target_path/<文件夹名>下，保持目录结构。
+/// filters是这次上传的过滤条件：exclude（gitignore风格的排除规则，如node_modules、*.tmp）、
+/// exclude_hidden（排除隐藏文件）、max_file_size（字节），遍历时就按规则跳过，排除的目录不会进去。
+/// 
+/// dry_run为true时只返回上传计划，不创建目录和任务。
+/// 
+/// 返回值：批量上传汇总（格式同upload_files_from_paths），加上skipped（被跳过的文件和原因）
+#[tauri::command]
+async fn upload_folder(
+    folder_path: String,
+    target_path: Option<String>,
+    filters: Option<transfer_filter::FilterRules>,
+    dry_run: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    println!("前端调用upload_folder命令，文件夹: {}, 目标路径: {:?}, 过滤条件: {:?}, dry_run: {:?}",
+        folder_path, target_path, filters, dry_run);
+    
+    let filter = transfer_filter::TransferFilter::new(&filters.unwrap_or_default())
+        .map_err(|e| format!("{:#}", e))?;
+    let mut plan = transfer_plan::plan_folder_upload(
+        std::path::Path::new(&folder_path),
+        target_path.as_deref(),
+        &filter,
+        settings::get().transfer.symlink_policy,
+    )
+        .await
+        .map_err(|e| format!("{:#}", e))?;
+    
+    let upload_paths: Vec<String> = plan.files
+        .iter()
+        .filter(|file| file.action == transfer_plan::PlanAction::Upload)
+        .map(|file| file.local_path.clone())
+        .collect();
+    if !dry_run.unwrap_or(false) {
+        check_cached_quota(&upload_paths)?;
+    }
+    
+    let auth_info = acquire_checked_auth_info().await?;
+    
+    if dry_run.unwrap_or(false) {
+        mark_plan_conflicts(&auth_info, &mut plan).await;
+        return Ok(serde_json::json!({
+            "success": true,
+            "dry_run": true,
+            "plan": plan,
+        }));
+    }
+    
+    check_quota(&auth_info, &upload_paths).await?;
+    transfer_plan::create_remote_dirs(&auth_info, &plan).await;
+    
+    let mut batch = UploadBatch::new(target_path.clone());
+    let mut skipped = Vec::new();
+    for file in plan.files {
+        if file.action == transfer_plan::PlanAction::Skip {
+            skipped.push(serde_json::json!({ "path": file.local_path, "reason": file.reason }));
+            continue;
+        }
+        let item = match create_and_register_upload(&file.local_path, &auth_info, file.remote_dir.as_deref()).await {
+            Ok((upload_id, task_arc)) => {
+                spawn_upload(task_arc);
+                BatchItem::ok(file.local_path, upload_id)
+            }
+            Err(e) => {
+                println!("文件 {} 创建上传任务失败: {}", file.local_path, e);
+                BatchItem::failed(file.local_path, e)
+            }
         };
+        batch.items.push(item.with_target_path(file.remote_dir));
+    }
+    
+    let mut summary = batch.summary();
+    summary["skipped"] = serde_json::json!(skipped);
+    println!("文件夹上传任务已添加，batch_id: {}，跳过 {} 个", batch.batch_id, skipped.len());
+    batch::save_batch(batch).await;
+    
+    Ok(summary)
+}
+
+/// 把网址上的文件存到云盘
+/// 
+/// 后端支持时由后端直接下载（mode为"server"，返回时已经存好）；
+/// 否则客户端边下载边分片上传（mode为"client"），在后台进行，
+/// 进度通过url-upload-progress事件通知，结束时发url-upload-completed或url-upload-failed事件
+/// 
+/// 返回值：{"job_id", "mode", "filename"}
+#[tauri::command]
+async fn upload_from_url(url: String, target_path: Option<String>) -> Result<url_upload::UrlUploadJob, String> {
+    println!("前端调用upload_from_url命令，网址: {}, 目标路径: {:?}", url, target_path);
+    let auth_info = acquire_checked_auth_info().await?;
+    url_upload::start(auth_info, &url, target_path)
+        .await
+        .map_err(|e| format!("从网址上传失败: {:#}", e))
+}
+
+/// 重试批量上传中失败的文件
+/// 
+/// 包括创建任务就失败的文件，以及后台上传过程中进入Error状态的文件。
+/// 重新获取认证信息后为这些文件创建新的上传任务，成功的文件不受影响。
+/// 
+/// 返回值：更新后的批量上传汇总（格式同upload_files_from_paths）
+#[tauri::command]
+async fn retry_failed_in_batch(batch_id: String) -> Result<serde_json::Value, String> {
+    println!("前端调用retry_failed_in_batch命令，batch_id: {}", batch_id);
+    
+    let mut batch = batch::get_batch(&batch_id)
+        .await
+        .ok_or_else(|| format!("批量任务不存在: {}", batch_id))?;
+    
+    // 找出需要重试的文件：创建失败的 + 上传过程中出错的
+    let upload_tasks = upload_tasks();
+    let mut retry_indices = Vec::new();
+    for (index, item) in batch.items.iter().enumerate() {
+        if item.is_failed() {
+            retry_indices.push(index);
+            continue;
+        }
         
-        upload_ids.push(upload_id.clone());
-        
-        let mut tasks_map = upload_tasks.lock().await;
-        tasks_map.insert(upload_id.clone(), task_arc.clone());
-        
-        // 在后台异步执行上传，不阻塞前端响应
-        let task_for_spawn = task_arc.clone();
-        let upload_id_for_spawn = upload_id.clone();
-        
-        tokio::spawn(async move {
-            println!("后台上传任务开始: {}", upload_id_for_spawn);
-            
-            match task_for_spawn.start().await {
-                Ok(_) => {
-                    println!("后台上传完成: {}", upload_id_for_spawn);
-                }
-                Err(e) => {
-                    println!("后台上传失败: {}，错误: {}", upload_id_for_spawn, e);
+        if let Some(upload_id) = &item.upload_id {
+            let task = upload_tasks.lock().await.get(upload_id).cloned();
+            if let Some(task) = task {
+                if let upload::UploadStatus::Error(_) | upload::UploadStatus::FileInUse = task.get_progress().await.status {
+                    retry_indices.push(index);
                 }
             }
-        });
+        }
     }
     
-    println!("批量上传任务已添加到管理器，共 {} 个文件，目标路径: {:?}", upload_ids.len(), target_path);
+    if retry_indices.is_empty() {
+        println!("批量任务 {} 没有需要重试的文件", batch_id);
+        return Ok(batch.summary());
+    }
     
-    // 返回上传ID列表
-    Ok(serde_json::json!({
-        "success": true,
-        "upload_ids": upload_ids,
-        "file_paths": file_paths_str,
-        "count": upload_ids.len(),
-        "target_path": target_path.unwrap_or_default()
-    }))
+    println!("批量任务 {} 需要重试 {} 个文件", batch_id, retry_indices.len());
+    
+    // 重新获取认证信息，之前的TOTP可能已经过期
+    let auth_info = acquire_checked_auth_info().await?;
+    
+    for index in retry_indices {
+        let file_path = batch.items[index].file_path.clone();
+        let item_target = batch.items[index].target_path.clone();
+        let target_path = item_target.as_deref().or(batch.target_path.as_deref());
+        batch.items[index] = match create_and_register_upload(&file_path, &auth_info, target_path).await {
+            Ok((upload_id, task_arc)) => {
+                spawn_upload(task_arc);
+                BatchItem::ok(file_path, upload_id)
+            }
+            Err(e) => {
+                println!("文件 {} 重试失败: {}", file_path, e);
+                BatchItem::failed(file_path, e)
+            }
+        }
+        .with_target_path(item_target);
+    }
+    
+    let summary = batch.summary();
+    batch::save_batch(batch).await;
+    
+    Ok(summary)
 }
 
 /// 选择文件并上传（支持指定目标路径）
@@ -762,15 +2226,12 @@ async fn select_and_upload_file(target_path: Option<String>) -> Result<serde_jso
             // 转换为字符串
             let file_path_str = file_path.to_string_lossy().to_string();
             
-            // 先获取设备ID和TOTP
-            let device_id = get_device_id().await.map_err(|e| format!("获取设备ID失败: {}", e))?;
-            let totp = get_totp().await.map_err(|e| format!("获取TOTP失败: {}", e))?;
+            check_cached_quota(std::slice::from_ref(&file_path_str))?;
             
-            // 创建认证信息
-            let auth_info = AuthInfo {
-                device_id,
-                totp,
-            };
+            // 获取认证信息
+            let auth_info = acquire_checked_auth_info().await?;
+            
+            check_quota(&auth_info, std::slice::from_ref(&file_path_str)).await?;
             
             // 创建上传任务，传递目标路径
             println!("[DEBUG] 开始创建上传任务，目标路径: {:?}", target_path);
@@ -791,7 +2252,7 @@ async fn select_and_upload_file(target_path: Option<String>) -> Result<serde_jso
             };
             
             // 初始化上传任务管理器
-            let upload_tasks = UPLOAD_TASKS.get_or_init(|| Mutex::new(HashMap::new()));
+            let upload_tasks = upload_tasks();
             let mut tasks_map = upload_tasks.lock().await;
             tasks_map.insert(upload_id.clone(), task_arc.clone());
             
@@ -850,65 +2311,57 @@ async fn select_and_upload_multiple_files() -> Result<serde_json::Value, String>
                 }));
             }
             
-            // 先获取设备ID和TOTP（只需要获取一次）
-            let device_id = get_device_id().await.map_err(|e| format!("获取设备ID失败: {}", e))?;
-            let totp = get_totp().await.map_err(|e| format!("获取TOTP失败: {}", e))?;
+            let path_strings: Vec<String> = file_paths.iter().map(|p| p.to_string_lossy().to_string()).collect();
+            check_cached_quota(&path_strings)?;
             
-            let auth_info = AuthInfo {
-                device_id,
-                totp,
-            };
+            // 获取认证信息（只需要获取一次）
+            let auth_info = acquire_checked_auth_info().await?;
             
-            let mut upload_ids = Vec::new();
-            let mut file_paths_str = Vec::new();
+            check_quota(&auth_info, &path_strings).await?;
             
-            // 为每个文件创建上传任务
+            let mut batch = UploadBatch::new(None);
+            
+            // 为每个文件创建上传任务，单个文件失败不影响其他文件
             for file_path in file_paths {
                 let file_path_str = file_path.to_string_lossy().to_string();
-                file_paths_str.push(file_path_str.clone());
-                
-                // 创建上传任务
-                let task = UploadTask::new(file_path.clone(), auth_info.clone(), None)
-                    .await
-                    .map_err(|e| format!("创建上传任务失败: {}", e))?;
                 
-                // 将任务保存到全局管理器中
-                let task_arc = Arc::new(task);
-                let upload_id = {
-                    let progress = task_arc.get_progress().await;
-                    progress.upload_id.clone()
+                let (upload_id, task_arc) = match create_and_register_upload(&file_path_str, &auth_info, None).await {
+                    Ok(created) => created,
+                    Err(e) => {
+                        println!("文件 {} 创建上传任务失败: {}", file_path_str, e);
+                        batch.items.push(BatchItem::failed(file_path_str, e));
+                        continue;
+                    }
                 };
                 
-                upload_ids.push(upload_id.clone());
-                
-                // 初始化上传任务管理器
-                let upload_tasks = UPLOAD_TASKS.get_or_init(|| Mutex::new(HashMap::new()));
-                let mut tasks_map = upload_tasks.lock().await;
-                tasks_map.insert(upload_id.clone(), task_arc.clone());
-                
                 // 同步执行上传，等待完成
                 println!("开始上传: {}", file_path_str);
                 
                 match task_arc.start().await {
                     Ok(_) => {
                         println!("上传完成: {}", upload_id);
+                        batch.items.push(BatchItem::ok(file_path_str, upload_id));
                     }
                     Err(e) => {
                         println!("上传失败: {}，错误: {}", upload_id, e);
-                        return Err(format!("上传失败: {}", e));
+                        batch.items.push(BatchItem {
+                            file_path: file_path_str,
+                            upload_id: Some(upload_id),
+                            error: Some(format!("上传失败: {}", e)),
+                            target_path: None,
+                        });
                     }
                 }
             }
             
-            println!("批量上传完成，共 {} 个文件", upload_ids.len());
+            let summary = batch.summary();
+            println!("批量上传完成，batch_id: {}，成功 {} 个，失败 {} 个", 
+                batch.batch_id, summary["succeeded_count"], summary["failed_count"]);
             
-            // 返回上传ID列表
-            Ok(serde_json::json!({
-                "success": true,
-                "upload_ids": upload_ids,
-                "file_paths": file_paths_str,
-                "count": upload_ids.len()
-            }))
+            batch::save_batch(batch).await;
+            
+            // 返回批量上传汇总
+            Ok(summary)
         }
         None => {
             println!("用户取消了文件选择");
@@ -970,10 +2423,79 @@ fn select_files() -> Result<serde_json::Value, String> {
     }
 }
 
+/// 获取应用信息
+/// 
+/// 关于页面和用户反馈问题时用，返回：
+/// - version: 版本号（Cargo.toml里的）
+/// - git_commit: 构建时的git提交号，不在git仓库里构建时是unknown
+/// - build_profile: debug/release
+/// - backend: 当前后端地址、端口和配置来源（env/profile/remote/default）
+/// - platform: 操作系统、CPU架构
+#[tauri::command]
+async fn get_app_info() -> Result<serde_json::Value, String> {
+    println!("前端调用get_app_info命令...");
+    
+    let backend = match config::get_backend_config() {
+        Ok(config) => serde_json::json!({
+            "base_url": config.base_url,
+            "port": config.port,
+            "full_url": config.get_full_url(),
+            "source": config.source,
+        }),
+        Err(e) => {
+            println!("获取后端配置失败: {}", e);
+            serde_json::Value::Null
+        }
+    };
+    
+    Ok(serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": updater::CURRENT_VERSION,
+        "git_commit": env!("CAMFC_GIT_COMMIT"),
+        "build_profile": if cfg!(debug_assertions) { "debug" } else { "release" },
+        "backend": backend,
+        "platform": {
+            "os": std::env::consts::OS,
+            "arch": std::env::consts::ARCH,
+            "family": std::env::consts::FAMILY,
+        },
+    }))
+}
+
+/// 获取上一次的崩溃报告
+/// 
+/// 程序panic时会把错误信息、backtrace、最近的日志和状态摘要写到应用数据目录，
+/// 前端启动时调用这个命令，有报告就提示用户。
+/// 
+/// 参数：clear - 读完后删除报告（用户看过或者发送过之后传true）
+/// 返回值：报告JSON，没有崩溃过返回null
+#[tauri::command]
+async fn get_last_crash_report(clear: Option<bool>) -> Result<Option<serde_json::Value>, String> {
+    println!("前端调用get_last_crash_report命令...");
+    
+    crash_report::last_report(clear.unwrap_or(false))
+}
+
+/// 检查更新
+/// 
+/// 从远程配置读取最新版本号，和当前版本比较。
+/// 返回：current_version、latest_version、update_available、download_url、release_notes
+/// 
+/// 注意：只检查不下载，有新版本时前端用download_url打开下载页面。
+#[tauri::command]
+async fn check_for_updates() -> Result<updater::UpdateInfo, String> {
+    println!("前端调用check_for_updates命令...");
+    
+    updater::check_for_updates().await.map_err(|e| {
+        println!("检查更新失败: {}", e);
+        format!("检查更新失败: {}", e)
+    })
+}
+
 /// 获取当前使用的后端配置
 /// 
 /// 前端可以调用这个命令获取当前使用的后端地址和端口
-/// 返回格式：{"base_url": "xxx", "port": 8005, "full_url": "xxx:8005"}
+/// 返回格式：{"base_url": "xxx", "port": 8005, "full_url": "xxx:8005", "source": "env/profile/remote/default"}
 #[tauri::command]
 async fn get_backend_config() -> Result<serde_json::Value, String> {
     println!("前端调用get_backend_config命令...");
@@ -986,7 +2508,8 @@ async fn get_backend_config() -> Result<serde_json::Value, String> {
             Ok(serde_json::json!({
                 "base_url": config.base_url,
                 "port": config.port,
-                "full_url": full_url
+                "full_url": full_url,
+                "source": config.source
             }))
         }
         Err(e) => {
@@ -996,6 +2519,54 @@ async fn get_backend_config() -> Result<serde_json::Value, String> {
     }
 }
 
+/// 列出保存的后端配置
+/// 
+/// 后端配置在设置的backend.profiles里增删改（update_settings），这里列出来给前端的切换菜单用
+/// 
+/// 返回值：{"profiles": [{"name", "base_url", "port", "proxy", "ca_cert"}], "active_profile",
+/// "current_url": 现在实际连的后端, "source": env/profile/remote/default}
+#[tauri::command]
+async fn list_backend_profiles() -> Result<backend_profiles::BackendProfiles, String> {
+    println!("前端调用list_backend_profiles命令...");
+    Ok(backend_profiles::list())
+}
+
+/// 切换后端配置
+/// 
+/// 参数：
+/// - name: 后端配置名，不传表示回到自动选择（远程配置/默认值）
+/// - mode: 正在传的任务怎么办，不传按设置里的backend.in_flight_on_switch（默认在旧后端上传完）：
+///   "drain"在旧后端上传完，"retarget"立即暂停，切换后在新后端上继续
+/// 
+/// 每个任务创建时就定下了发到哪个后端，切换不会让一个文件传到一半换了主机；
+/// 排队中的任务按设置里的backend.queued_on_switch处理（默认切换后在新后端上开始）。
+/// 返回值：{"active_profile", "url", "resumed": [任务ID], "failed": [没能重新开始的任务ID]}
+#[tauri::command]
+async fn switch_backend_profile(name: Option<String>, mode: Option<backend_profiles::SwitchMode>) -> Result<backend_profiles::SwitchResult, String> {
+    println!("前端调用switch_backend_profile命令，配置: {:?}，方式: {:?}", name, mode);
+    backend_profiles::switch(name.as_deref(), mode)
+        .await
+        .map_err(|e| format!("切换后端失败: {:#}", e))
+}
+
+/// 检查后端连通性
+///
+/// 依次解析域名、建立TCP连接、带认证HEAD一次后端，哪一步不通就停在哪一步。
+/// check_auth为false时不从笔获取认证信息，只看后端在不在（默认true）
+///
+/// 返回值：{"diagnosis": ok/invalid_url/dns_failed/refused/timeout/tls_error/auth_error/http_error,
+/// "url", "addresses", "status", "elapsed_ms", "message", "hint": 给用户的处理建议}
+#[tauri::command]
+async fn probe_backend(check_auth: Option<bool>) -> Result<connectivity::ProbeReport, String> {
+    println!("前端调用probe_backend命令，检查认证: {:?}", check_auth);
+    let auth_info = if check_auth.unwrap_or(true) {
+        Some(acquire_auth_info().await?)
+    } else {
+        None
+    };
+    Ok(connectivity::probe(auth_info.as_ref()).await)
+}
+
 /// 截取屏幕截图
 /// 
 /// 前端调用这个命令截取当前屏幕
@@ -1045,6 +2616,7 @@ fn get_monitors() -> Result<serde_json::Value, String> {
 /// 
 /// 前端调用这个命令来模拟键盘点击右箭头键
 /// 会先按下右箭头键，然后松开
+#[cfg(feature = "ble")]
 #[tauri::command]
 fn press_win_key() -> Result<(), String> {
     use windows::Win32::UI::Input::KeyboardAndMouse::{
@@ -1097,6 +2669,7 @@ fn press_win_key() -> Result<(), String> {
 /// 模拟按下并松开左箭头键
 /// 
 /// GPIO9按钮松开时调用，模拟按下左箭头键
+#[cfg(feature = "ble")]
 #[tauri::command]
 fn press_left_key() -> Result<(), String> {
     use windows::Win32::UI::Input::KeyboardAndMouse::{
@@ -1144,11 +2717,30 @@ fn press_left_key() -> Result<(), String> {
     Ok(())
 }
 
+/// 按键模拟由笔的按钮触发，和windows依赖一起放在ble功能里
+#[cfg(not(feature = "ble"))]
+#[tauri::command]
+fn press_win_key() -> Result<(), String> {
+    Err(disabled::BLE_DISABLED.to_string())
+}
+
+#[cfg(not(feature = "ble"))]
+#[tauri::command]
+fn press_left_key() -> Result<(), String> {
+    Err(disabled::BLE_DISABLED.to_string())
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    // 初始化后端配置（必须在其他模块使用之前）
+    // 最先安装panic hook，后面任何地方panic都能留下崩溃报告
+    crash_report::install();
+
+    // 加载设置、初始化后端配置（必须在其他模块使用之前，后端配置要用到设置里选中的后端）
     let rt = tokio::runtime::Runtime::new().expect("创建运行时失败");
     rt.block_on(async {
+        if let Err(e) = settings::init_settings().await {
+            eprintln!("设置加载失败: {}", e);
+        }
         if let Err(e) = config::init_config().await {
             eprintln!("配置初始化失败: {}", e);
         }
@@ -1159,6 +2751,53 @@ pub fn run() {
         .setup(|app| {
             set_app_handle(app.handle().clone());
 
+            // 后台服务交给supervisor管理，supervisor用tokio::spawn，要在运行时里启动
+            tauri::async_runtime::spawn(async move {
+                // 监控蓝牙适配器，运行中关闭蓝牙或拔掉适配器时自动处理
+                #[cfg(feature = "ble")]
+                bluetooth_watcher::start();
+
+                // 按设置定时把运行指标写到metrics.json
+                supervisor::spawn_service("metrics_dump", metrics::dump_loop);
+
+                // 用户打开统计上报后按间隔上报
+                supervisor::spawn_service("telemetry", telemetry::report_loop);
+
+                // 定时把结束很久的任务从任务表移到传输历史
+                supervisor::spawn_service("transfer_gc", transfer_manager::prune_loop);
+
+                // 恢复上次没传完的任务，之后定时保存传输队列
+                supervisor::spawn_service("transfer_queue", transfer_queue::run);
+
+                // 自动模式下按实测吞吐量调整同时进行的传输数
+                supervisor::spawn_service("concurrency_tuner", concurrency::tune_loop);
+
+                // 定时检测是不是按流量计费的网络
+                supervisor::spawn_service("network_profile", network_profile::watch_loop);
+
+                // 跟踪睡眠/唤醒和省电模式，唤醒后重置蓝牙连接
+                supervisor::spawn_service("power", power::watch_loop);
+
+                // 发件箱模式：检查发件箱，新文件自动上传
+                supervisor::spawn_service("outbox", outbox::run);
+
+                // 设置里开启了WebDAV就自动启动
+                let webdav_settings = settings::get().webdav;
+                if webdav_settings.enabled {
+                    if let Err(e) = webdav::start(webdav_settings.port).await {
+                        eprintln!("自动启动WebDAV服务失败: {}", e);
+                    }
+                }
+
+                // 设置里开启了本地接口就自动启动
+                let local_api_settings = settings::get().local_api;
+                if local_api_settings.enabled {
+                    if let Err(e) = local_api::start(local_api_settings.port).await {
+                        eprintln!("自动启动本地接口失败: {}", e);
+                    }
+                }
+            });
+
             // 创建托盘右键菜单
             // 提供"显示主窗口"和"退出"两个选项
             let show_item = MenuItem::with_id(app, "show", "显示主窗口", true, None::<&str>)?;
@@ -1185,8 +2824,12 @@ pub fn run() {
                             }
                         }
                         "quit" => {
-                            // 退出应用
-                            app.exit(0);
+                            // 退出应用，先等后台任务收尾
+                            let app = app.clone();
+                            tauri::async_runtime::spawn(async move {
+                                shutdown_gracefully().await;
+                                app.exit(0);
+                            });
                         }
                         _ => {}
                     }
@@ -1225,30 +2868,155 @@ pub fn run() {
                 });
             }
 
+            // 注册并处理 camfc:// 链接
+            // 开发模式和Linux下需要在运行时注册，安装包会在安装时注册
+            {
+                use tauri_plugin_deep_link::DeepLinkExt;
+
+                #[cfg(any(windows, target_os = "linux"))]
+                if let Err(e) = app.deep_link().register_all() {
+                    eprintln!("注册camfc链接失败: {}", e);
+                }
+
+                app.deep_link().on_open_url(|event| {
+                    for url in event.urls() {
+                        let url = url.to_string();
+                        tauri::async_runtime::spawn(async move {
+                            deep_link::handle_url(&url).await;
+                        });
+                    }
+                });
+
+                // 通过链接启动应用时，链接在启动参数里
+                if let Ok(Some(urls)) = app.deep_link().get_current() {
+                    for url in urls {
+                        let url = url.to_string();
+                        tauri::async_runtime::spawn(async move {
+                            deep_link::handle_url(&url).await;
+                        });
+                    }
+                }
+            }
+
             Ok(())
         })
+        // 只允许运行一个实例，再次打开（比如点击camfc链接）时把链接转给已运行的实例
+        .plugin(tauri_plugin_single_instance::init(|app, _argv, _cwd| {
+            if let Some(window) = app.get_webview_window("main") {
+                let _ = window.show();
+                let _ = window.set_focus();
+            }
+        }))
+        .plugin(tauri_plugin_deep_link::init())
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_dialog::init())
+        .plugin(tauri_plugin_clipboard_manager::init())
+        // 窗口焦点变化时切换后台模式，窗口关闭时取消它的传输事件订阅
+        .on_window_event(|window, event| {
+            match event {
+                WindowEvent::Focused(focused) => background_mode::set_focused(window.label(), *focused),
+                WindowEvent::Destroyed => {
+                    background_mode::set_focused(window.label(), false);
+                    event_emitter::unsubscribe(window.label());
+                }
+                _ => {}
+            }
+        })
         .invoke_handler(tauri::generate_handler![
             greet,  // 保留测试用的greet命令
             exit_app,  // 退出应用
             get_backend_config,  // 获取后端配置
+            list_backend_profiles,  // 列出后端配置
+            probe_backend,  // 检查后端连通性
+            switch_backend_profile,  // 切换后端配置
+            get_app_info,        // 获取应用信息（版本、构建、后端）
+            check_for_updates,   // 检查更新
+            get_last_crash_report, // 获取上一次的崩溃报告
+            cleanup_partial_files, // 清理残留的下载文件和临时文件
             get_totp,           // 主要功能：获取TOTP
+            copy_totp_to_clipboard, // 复制TOTP到剪贴板（自动清空）
+            refresh_totp,       // 强制重新获取TOTP
+            clear_auth_cache,   // 清除认证缓存
             scan_cpen_devices,  // 扫描Cpen设备列表
             connect_cpen_device, // 连接指定的Cpen设备
+            start_onboarding,    // 首次使用引导
+            check_ble_permissions, // 检查蓝牙权限
+            list_bluetooth_adapters, // 列出蓝牙适配器
+            select_adapter,      // 选择蓝牙适配器
+            set_device_match_rules, // 设置识别笔的规则
             get_device_id,      // 获取设备ID
             get_connection_status, // 获取连接状态
             is_connected,       // 检查是否已建立稳定连接
+            get_ble_stats,      // 蓝牙连接统计
+            get_pen_protocol,   // 笔的协议版本
+            get_pen_battery,    // 笔的电量
             disconnect,         // 断开连接
             cleanup,            // 清理资源
             // 下载相关命令
             download_file,
+            download_folder,
+            get_remote_tree,             // 云盘目录树（选择性同步）
+            set_selected_folders,        // 设置选择性同步的文件夹
+            export_checksums,
             get_download_progress,
+            get_download_chunks,
             pause_download,
             resume_download,
+            stop_following_download,     // 停止跟随模式的下载
+            get_transfer_speed_history,  // 传输速度历史
+            get_transfer_history,        // 已清理任务的传输历史
+            get_transfer_concurrency,    // 传输并发状态
+            get_transfer_log,            // 单个传输任务的日志
+            get_network_profile,         // 当前网络信息（是否计费）
+            approve_metered_transfers,   // 允许在计费网络上传大文件
+            get_bandwidth_usage,         // 流量统计
+            retry_transfer,              // 手动重试失败的传输
+            verify_and_repair_download,  // 校验并修复已下载文件
+            benchmark_network_settings,  // 网络配置测速
+            create_share_link,           // 创建分享链接
+            get_storage_quota,           // 查询云盘容量
+            stat_remote_file,            // 云盘文件详细信息
+            search_remote_files,         // 搜索云盘文件
+            list_remote_dir,             // 分页获取目录内容
+            stream_remote_dir,           // 以事件流获取目录内容
+            list_remote_dir_cached,      // 带缓存获取目录内容
+            clear_listing_cache,         // 清空目录缓存
+            // 回收站命令
+            trash_remote_file,
+            list_trash,
+            restore_from_trash,
+            empty_trash,
+            // WebDAV命令
+            start_webdav_server,
+            stop_webdav_server,
+            get_webdav_status,
+            // 本地HTTP接口命令
+            start_local_api,
+            stop_local_api,
+            get_local_api_status,
+            get_metrics,
+            set_telemetry_enabled,   // 打开/关闭匿名统计上报
+            preview_telemetry,       // 预览统计上报内容
+            get_server_maintenance_status,
+            // 边下边播命令
+            stream_remote_file,
+            // 分片缓存命令
+            get_chunk_cache_status,
+            clear_chunk_cache,
+            get_storage_usage_breakdown,
+            // 设置命令
+            settings::get_settings,
+            settings::update_settings,
             // 上传相关命令
             upload_file,
             upload_files_from_paths,
+            upload_folder,
+            upload_from_url,
+            retry_failed_in_batch,
+            // 发件箱模式命令
+            outbox::enable_outbox,
+            outbox::disable_outbox,
+            outbox::get_outbox_status,
             get_upload_progress,
             pause_upload,
             resume_upload,
@@ -1266,6 +3034,15 @@ pub fn run() {
             // 键盘模拟命令
             press_win_key,
             press_left_key,
+            // v2命令，旧命令逐步迁移到这里
+            commands_v2::v2_get_download_progress,
+            commands_v2::v2_get_upload_progress,
+            commands_v2::v2_get_connection_status,
+            commands_v2::v2_get_totp,
+            commands_v2::v2_get_device_id,
+            // 按窗口订阅传输事件
+            event_emitter::subscribe_transfer_events,
+            event_emitter::unsubscribe_transfer_events,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,179 @@
+// 目录列表缓存
+// 把列过的目录（路径 -> 条目 + ETag）保存在应用数据目录的listing_cache.json里
+//
+// 思考：网络慢的时候每进一个目录都要等后端返回，体验很差。
+// 有缓存就先直接返回缓存，同时在后台带If-None-Match去后端确认，
+// 目录变了再更新缓存并通知前端刷新（dir-listing-updated事件）。
+// 缓存文件里是所有目录，每次更新都整个重写太浪费，所以更新后等SAVE_DELAY再写一次，
+// 这段时间里的更新合在一起写；退出时把还没写的写下去（flush_pending）。
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::cloud_api::RemoteEntry;
+use crate::storage::get_app_data_dir;
+
+// 最多缓存的目录数量，超过后删掉最久没更新的
+const MAX_CACHED_DIRS: usize = 200;
+// 更新后多久写盘
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedListing {
+    pub entries: Vec<RemoteEntry>,
+    pub etag: Option<String>,
+    pub fetched_at: i64,   // Unix时间戳（秒）
+}
+
+struct CacheState {
+    listings: HashMap<String, CachedListing>,
+    loaded: bool,
+    dirty: bool,            // 有没写盘的更新
+    save_scheduled: bool,   // 已经安排了延迟写盘
+}
+
+static LISTING_CACHE: OnceLock<Mutex<CacheState>> = OnceLock::new();
+// 写盘时持有，写文件时不拿着LISTING_CACHE，两次写盘按先后进行
+static WRITE_LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+
+fn cache() -> &'static Mutex<CacheState> {
+    LISTING_CACHE.get_or_init(|| {
+        Mutex::new(CacheState {
+            listings: HashMap::new(),
+            loaded: false,
+            dirty: false,
+            save_scheduled: false,
+        })
+    })
+}
+
+fn get_cache_path() -> Result<std::path::PathBuf> {
+    let data_dir = get_app_data_dir().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(data_dir.join("listing_cache.json"))
+}
+
+// 同一个目录可能写成 "/ds"、"ds/"，统一一下
+fn normalize(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+async fn ensure_loaded(state: &mut CacheState) {
+    if state.loaded {
+        return;
+    }
+    state.loaded = true;
+
+    let path = match get_cache_path() {
+        Ok(path) => path,
+        Err(e) => {
+            println!("获取目录缓存路径失败: {}", e);
+            return;
+        }
+    };
+    if let Ok(content) = fs::read_to_string(&path).await {
+        match serde_json::from_str(&content) {
+            Ok(listings) => state.listings = listings,
+            Err(e) => println!("目录缓存格式错误，忽略: {}", e),
+        }
+    }
+}
+
+async fn write_file(content: String) -> Result<()> {
+    let path = get_cache_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await
+            .context(format!("创建数据目录失败: {:?}", parent))?;
+    }
+    fs::write(&path, content).await
+        .context("写入目录缓存失败")?;
+    Ok(())
+}
+
+// 写盘：在锁里序列化，写文件时不持有缓存的锁
+async fn save() -> Result<()> {
+    let _writing = WRITE_LOCK.get_or_init(|| Mutex::new(())).lock().await;
+    let content = {
+        let mut state = cache().lock().await;
+        state.dirty = false;
+        state.save_scheduled = false;
+        serde_json::to_string(&state.listings).context("序列化目录缓存失败")?
+    };
+    if let Err(e) = write_file(content).await {
+        cache().lock().await.dirty = true;
+        return Err(e);
+    }
+    Ok(())
+}
+
+// 把还没写盘的更新写下去（退出前调用）
+pub async fn flush_pending() {
+    let dirty = cache().lock().await.dirty;
+    if dirty {
+        if let Err(e) = save().await {
+            println!("保存目录缓存失败: {}", e);
+        }
+    }
+}
+
+// 获取缓存的目录列表
+pub async fn get(path: &str) -> Option<CachedListing> {
+    let mut state = cache().lock().await;
+    ensure_loaded(&mut state).await;
+    state.listings.get(&normalize(path)).cloned()
+}
+
+// 更新缓存并写盘
+pub async fn put(path: &str, entries: Vec<RemoteEntry>, etag: Option<String>) {
+    let mut state = cache().lock().await;
+    ensure_loaded(&mut state).await;
+
+    state.listings.insert(normalize(path), CachedListing {
+        entries,
+        etag,
+        fetched_at: chrono::Utc::now().timestamp(),
+    });
+
+    while state.listings.len() > MAX_CACHED_DIRS {
+        let oldest = state
+            .listings
+            .iter()
+            .min_by_key(|(_, listing)| listing.fetched_at)
+            .map(|(path, _)| path.clone());
+        if let Some(oldest) = oldest {
+            state.listings.remove(&oldest);
+        }
+    }
+
+    // 延迟写盘，这段时间里的更新一起写
+    state.dirty = true;
+    if !state.save_scheduled {
+        state.save_scheduled = true;
+        tauri::async_runtime::spawn(async {
+            tokio::time::sleep(SAVE_DELAY).await;
+            flush_pending().await;
+        });
+    }
+}
+
+// 目录没变化时只更新时间
+pub async fn touch(path: &str) {
+    let mut state = cache().lock().await;
+    if let Some(listing) = state.listings.get_mut(&normalize(path)) {
+        listing.fetched_at = chrono::Utc::now().timestamp();
+    }
+}
+
+// 清空缓存
+pub async fn clear() -> Result<()> {
+    {
+        let mut state = cache().lock().await;
+        state.listings.clear();
+        state.loaded = true;
+    }
+    save().await
+}
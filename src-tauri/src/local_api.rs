@@ -0,0 +1,284 @@
+// 本地HTTP接口
+// 给想用脚本（shell/python）操作客户端的用户用，不需要打开界面
+//
+// 思考：接口和Tauri命令做的是同一件事，这里直接调用lib.rs里的命令函数，
+// 不重复实现一遍逻辑。只监听127.0.0.1，并且每个请求都要带上本地生成的令牌
+// （Authorization: Bearer <token>），令牌保存在应用数据目录的local_api_token文件里，
+// 脚本从这个文件读取，防止本机其他程序随便调用。
+
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use anyhow::{Result, Context};
+use axum::extract::{Path, Query, Request};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{oneshot, Mutex};
+
+use crate::storage::get_app_data_dir;
+use crate::transfer_manager::{download_tasks, upload_tasks};
+use crate::supervisor;
+
+struct RunningServer {
+    port: u16,
+    shutdown: oneshot::Sender<()>,
+}
+
+static LOCAL_API_SERVER: OnceLock<Mutex<Option<RunningServer>>> = OnceLock::new();
+static API_TOKEN: OnceLock<String> = OnceLock::new();
+
+fn server_state() -> &'static Mutex<Option<RunningServer>> {
+    LOCAL_API_SERVER.get_or_init(|| Mutex::new(None))
+}
+
+pub fn get_token_path() -> Result<PathBuf> {
+    let data_dir = get_app_data_dir().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(data_dir.join("local_api_token"))
+}
+
+// 令牌文件只让当前用户读写（Unix上是0600），以前版本按0644创建的文件也改过来
+#[cfg(unix)]
+async fn restrict_permissions(path: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await
+        .context("设置本地接口令牌文件权限失败")
+}
+
+#[cfg(not(unix))]
+async fn restrict_permissions(_path: &std::path::Path) -> Result<()> {
+    Ok(())
+}
+
+async fn write_token_file(path: &std::path::Path, token: &str) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await.context("创建本地接口令牌文件失败")?;
+    file.write_all(token.as_bytes()).await.context("保存本地接口令牌失败")?;
+    restrict_permissions(path).await
+}
+
+// 读取令牌，第一次使用时生成并保存
+async fn load_or_create_token() -> Result<String> {
+    if let Some(token) = API_TOKEN.get() {
+        return Ok(token.clone());
+    }
+
+    let path = get_token_path()?;
+    let token = match fs::read_to_string(&path).await {
+        Ok(token) if !token.trim().is_empty() => {
+            restrict_permissions(&path).await?;
+            token.trim().to_string()
+        }
+        _ => {
+            let token = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await
+                    .context(format!("创建数据目录失败: {:?}", parent))?;
+            }
+            write_token_file(&path, &token).await?;
+            println!("已生成本地接口令牌: {:?}", path);
+            token
+        }
+    };
+
+    crate::auth::remember_secret(&token);
+    Ok(API_TOKEN.get_or_init(|| token).clone())
+}
+
+// 比较令牌，不因为前缀相同提前返回
+fn token_matches(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len()
+        && expected
+            .bytes()
+            .zip(provided.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn require_token(req: Request, next: Next) -> Response {
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    let authorized = API_TOKEN
+        .get()
+        .is_some_and(|expected| token_matches(expected, provided));
+    if !authorized {
+        return error_response(StatusCode::UNAUTHORIZED, "令牌无效".to_string());
+    }
+
+    next.run(req).await
+}
+
+fn error_response(status: StatusCode, message: String) -> Response {
+    (status, Json(serde_json::json!({ "success": false, "error": message }))).into_response()
+}
+
+// 把命令的返回值转成HTTP响应，出错时返回400
+fn to_response<T: serde::Serialize>(result: Result<T, String>) -> Response {
+    match result {
+        Ok(value) => Json(value).into_response(),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, e),
+    }
+}
+
+async fn status_handler() -> Response {
+    let connection = crate::get_connection_status().await;
+    let connected = crate::is_connected().await.unwrap_or(false);
+    let webdav_port = crate::webdav::running_port().await;
+    let adapter_powered = crate::cpen_device_manager::connection_snapshot().adapter_powered;
+    to_response(connection.map(|status| {
+        serde_json::json!({
+            "connected": connected,
+            "connection_status": status,
+            "adapter_powered": adapter_powered,
+            "webdav_port": webdav_port,
+        })
+    }))
+}
+
+async fn totp_handler() -> Response {
+    let device_id = match crate::get_device_id().await {
+        Ok(id) => id,
+        Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+    };
+    let totp = match crate::get_totp().await {
+        Ok(totp) => totp,
+        Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+    };
+    Json(serde_json::json!({ "device_id": device_id, "totp": totp })).into_response()
+}
+
+async fn transfers_handler() -> Response {
+    let download_ids: Vec<String> = download_tasks().lock().await.keys().cloned().collect();
+    let upload_ids: Vec<String> = upload_tasks().lock().await.keys().cloned().collect();
+
+    let mut downloads = Vec::new();
+    for id in download_ids {
+        if let Ok(progress) = crate::get_download_progress(id).await {
+            downloads.push(progress);
+        }
+    }
+    let mut uploads = Vec::new();
+    for id in upload_ids {
+        if let Ok(progress) = crate::get_upload_progress(id).await {
+            uploads.push(progress);
+        }
+    }
+
+    Json(serde_json::json!({ "downloads": downloads, "uploads": uploads })).into_response()
+}
+
+#[derive(Deserialize)]
+struct MetricsQuery {
+    format: Option<String>,
+}
+
+// 运行指标，默认Prometheus文本格式，?format=json返回JSON
+async fn metrics_handler(Query(query): Query<MetricsQuery>) -> Response {
+    let snapshot = crate::metrics::snapshot().await;
+    if query.format.as_deref() == Some("json") {
+        return Json(snapshot).into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        crate::metrics::render_prometheus(&snapshot),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct DownloadRequest {
+    file_id: String,
+    priority: Option<String>,
+    force: Option<bool>,
+    follow: Option<bool>,
+}
+
+async fn download_handler(Json(body): Json<DownloadRequest>) -> Response {
+    to_response(crate::download_file(body.file_id, body.priority, body.force, body.follow).await)
+}
+
+#[derive(Deserialize)]
+struct UploadRequest {
+    file_paths: Vec<String>,
+    target_path: Option<String>,
+    dry_run: Option<bool>,
+}
+
+async fn upload_handler(Json(body): Json<UploadRequest>) -> Response {
+    to_response(crate::upload_files_from_paths(body.file_paths, body.target_path, body.dry_run).await)
+}
+
+async fn retry_handler(Path(id): Path<String>) -> Response {
+    to_response(crate::retry_transfer(id).await)
+}
+
+fn build_router() -> Router {
+    Router::new()
+        .route("/api/status", get(status_handler))
+        .route("/api/totp", get(totp_handler))
+        .route("/api/transfers", get(transfers_handler))
+        .route("/api/metrics", get(metrics_handler))
+        .route("/api/downloads", post(download_handler))
+        .route("/api/uploads", post(upload_handler))
+        .route("/api/transfers/{id}/retry", post(retry_handler))
+        .layer(middleware::from_fn(require_token))
+}
+
+// 启动本地接口，返回实际监听的端口
+pub async fn start(port: u16) -> Result<u16> {
+    let mut state = server_state().lock().await;
+    if let Some(server) = state.as_ref() {
+        return Err(anyhow::anyhow!("本地接口已在运行，端口: {}", server.port));
+    }
+
+    load_or_create_token().await?;
+
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+        .await
+        .context(format!("监听端口 {} 失败", port))?;
+    let port = listener.local_addr().context("获取监听地址失败")?.port();
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    supervisor::spawn("local_api", async move {
+        let result = axum::serve(listener, build_router())
+            .with_graceful_shutdown(async {
+                shutdown_rx.await.ok();
+            })
+            .await;
+        if let Err(e) = result {
+            println!("本地接口异常退出: {}", e);
+        }
+        println!("本地接口已停止");
+    });
+
+    println!("本地接口已启动: http://127.0.0.1:{}/api/", port);
+    *state = Some(RunningServer { port, shutdown: shutdown_tx });
+    Ok(port)
+}
+
+// 停止本地接口，没有在运行时返回false
+pub async fn stop() -> bool {
+    match server_state().lock().await.take() {
+        Some(server) => {
+            let _ = server.shutdown.send(());
+            true
+        }
+        None => false,
+    }
+}
+
+pub async fn running_port() -> Option<u16> {
+    server_state().lock().await.as_ref().map(|s| s.port)
+}
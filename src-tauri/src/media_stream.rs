@@ -0,0 +1,306 @@
+// 云盘媒体文件边下边播（http功能）
+// stream_remote_file给一个本地地址，前端的<video>/<audio>直接用，拖动进度条时只下载需要的部分
+//
+// 思考：前端的播放器不能带我们的认证头（TOTP每30秒变一次），直接放后端地址是播不了的；
+// 整个文件下载完再播，几个G的视频要等很久。所以在127.0.0.1上开一个只给播放器用的小服务：
+// 1. stream_remote_file先HEAD拿到文件大小，登记一个随机令牌，返回 http://127.0.0.1:端口/stream/令牌
+// 2. 播放器发Range请求时，把请求的范围换算成和下载任务一样大小的分片，缺的分片用Range请求从后端拿，
+//    每次请求后端前重新取认证信息（见auth::current_credentials的缓存）
+// 3. 拿到的分片存进分片磁盘缓存（chunk_cache.rs），拖回来重看、播放器反复请求文件头尾时不用再下载，
+//    分片大小和下载一致，看过的部分之后下载这个文件时也能直接用
+// 4. 没带Range的请求按整个文件一个分片一个分片地流式返回，不会一下子读进内存
+// 地址里的令牌是随机的，本机其他程序猜不到；最多保留MAX_SESSIONS个，多了就丢掉最早的。
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex as StdMutex, OnceLock};
+use anyhow::{Context, Result};
+use axum::body::Body;
+use axum::extract::Path;
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use serde::Serialize;
+use tokio::sync::{oneshot, Mutex};
+
+use crate::chunk_cache;
+use crate::chunks;
+use crate::download::ChunkDownloader;
+use crate::supervisor;
+
+// 最多保留的播放地址数
+const MAX_SESSIONS: usize = 16;
+
+// 一个播放地址对应的云盘文件
+#[derive(Debug, Clone)]
+struct StreamSource {
+    file_id: String,
+    size: u64,
+    chunk_size: u64,
+    validator: Option<String>,   // 见chunk_cache::validator
+    content_type: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamInfo {
+    pub url: String,
+    pub file_id: String,
+    pub size: u64,
+    pub content_type: String,
+}
+
+struct RunningServer {
+    port: u16,
+    shutdown: oneshot::Sender<()>,
+}
+
+static SERVER: OnceLock<Mutex<Option<RunningServer>>> = OnceLock::new();
+static SESSIONS: OnceLock<StdMutex<(HashMap<String, StreamSource>, VecDeque<String>)>> = OnceLock::new();
+
+fn server_state() -> &'static Mutex<Option<RunningServer>> {
+    SERVER.get_or_init(|| Mutex::new(None))
+}
+
+fn sessions() -> &'static StdMutex<(HashMap<String, StreamSource>, VecDeque<String>)> {
+    SESSIONS.get_or_init(|| StdMutex::new((HashMap::new(), VecDeque::new())))
+}
+
+// 按扩展名猜媒体类型，播放器靠这个决定怎么解码
+fn guess_content_type(file_id: &str) -> &'static str {
+    let ext = std::path::Path::new(file_id)
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "aac" => "audio/aac",
+        "wav" => "audio/wav",
+        "ogg" | "oga" => "audio/ogg",
+        "flac" => "audio/flac",
+        _ => "application/octet-stream",
+    }
+}
+
+// 解析Range请求头，只支持单个范围，返回包含两端的[start, end]
+// 格式不对返回Some(Err)，调用方回416；没有Range头返回None
+fn parse_range(value: Option<&str>, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let value = value?;
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return Some(Err(())),
+    };
+    let (start, end) = spec.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => suffix
+            .parse::<u64>()
+            .ok()
+            .filter(|&n| n > 0 && size > 0)
+            .map(|n| (size.saturating_sub(n), size - 1)),
+        (start, "") => start.parse::<u64>().ok().filter(|&s| s < size).map(|s| (s, size - 1)),
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(s), Ok(e)) if s <= e && s < size => Some((s, e.min(size - 1))),
+            _ => None,
+        },
+    };
+    Some(range.ok_or(()))
+}
+
+// 取一个分片，缓存里没有就从后端下载
+async fn fetch_chunk(source: &StreamSource, index: u32) -> Result<Vec<u8>> {
+    let (start, end) = chunks::chunk_range(index, source.size, source.chunk_size)
+        .context(format!("分片 {} 超出文件范围", index))?;
+    let validator = source.validator.as_deref();
+    if let Some(data) = chunk_cache::get(&source.file_id, start, end, validator).await {
+        return Ok(data);
+    }
+
+    let auth_info = crate::acquire_auth_info().await.map_err(|e| anyhow::anyhow!(e))?;
+    let data = ChunkDownloader::new(auth_info)?
+        .download_chunk(&source.file_id, index, start, end)
+        .await?;
+    crate::bandwidth::record_downloaded(data.len() as u64).await;
+    chunk_cache::put(&source.file_id, start, end, validator, &data).await;
+    Ok(data)
+}
+
+// 按分片依次返回[start, end]范围内的数据，播放器读多少才下载多少
+fn range_body(source: StreamSource, start: u64, end: u64) -> Body {
+    let stream = futures::stream::unfold(start, move |pos| {
+        let source = source.clone();
+        async move {
+            if pos > end {
+                return None;
+            }
+            let index = (pos / source.chunk_size) as u32;
+            let chunk_start = index as u64 * source.chunk_size;
+            match fetch_chunk(&source, index).await {
+                Ok(data) => {
+                    let from = (pos - chunk_start) as usize;
+                    let to = ((end - chunk_start + 1) as usize).min(data.len());
+                    if from >= to {
+                        // 后端返回的数据比预期短，不能再往下读了
+                        let err = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "分片数据不完整");
+                        return Some((Err(err), end + 1));
+                    }
+                    let next = chunk_start + to as u64;
+                    Some((Ok(bytes::Bytes::copy_from_slice(&data[from..to])), next))
+                }
+                Err(e) => {
+                    println!("[边下边播] 获取分片 {} 失败: {:#}", index, e);
+                    Some((Err(std::io::Error::other(e.to_string())), end + 1))
+                }
+            }
+        }
+    });
+    Body::from_stream(stream)
+}
+
+async fn stream_handler(method: Method, Path(token): Path<String>, headers: HeaderMap) -> Response {
+    let source = sessions().lock().unwrap().0.get(&token).cloned();
+    let Some(source) = source else {
+        return (StatusCode::NOT_FOUND, "播放地址已失效").into_response();
+    };
+
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    let (status, start, end) = match parse_range(range, source.size) {
+        None => (StatusCode::OK, 0, source.size.saturating_sub(1)),
+        Some(Ok((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end),
+        Some(Err(())) => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", source.size))],
+            )
+                .into_response();
+        }
+    };
+
+    let length = if source.size == 0 { 0 } else { end - start + 1 };
+    let mut builder = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, source.content_type)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_LENGTH, length);
+    if status == StatusCode::PARTIAL_CONTENT {
+        builder = builder.header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, source.size));
+    }
+
+    let body = if method == Method::HEAD || length == 0 {
+        Body::empty()
+    } else {
+        range_body(source, start, end)
+    };
+    builder.body(body).unwrap_or_else(|e| {
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+    })
+}
+
+fn build_router() -> Router {
+    Router::new().route("/stream/{token}", get(stream_handler).head(stream_handler))
+}
+
+// 播放服务没在运行就启动，返回端口
+async fn ensure_server() -> Result<u16> {
+    let mut state = server_state().lock().await;
+    if let Some(server) = state.as_ref() {
+        return Ok(server.port);
+    }
+
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+        .await
+        .context("启动边下边播服务失败")?;
+    let port = listener.local_addr().context("获取监听地址失败")?.port();
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    supervisor::spawn("media_stream", async move {
+        let result = axum::serve(listener, build_router())
+            .with_graceful_shutdown(async {
+                shutdown_rx.await.ok();
+            })
+            .await;
+        if let Err(e) = result {
+            println!("边下边播服务异常退出: {}", e);
+        }
+        println!("边下边播服务已停止");
+    });
+
+    println!("边下边播服务已启动: http://127.0.0.1:{}/stream/", port);
+    *state = Some(RunningServer { port, shutdown: shutdown_tx });
+    Ok(port)
+}
+
+// 给云盘文件登记一个本地播放地址
+pub async fn open(file_id: &str) -> Result<StreamInfo> {
+    let auth_info = crate::acquire_auth_info().await.map_err(|e| anyhow::anyhow!(e))?;
+    let downloader = ChunkDownloader::new(auth_info)?;
+    let meta = downloader.get_file_metadata(file_id).await?;
+    let chunk_size = downloader.negotiate_chunk_size().await;
+    let port = ensure_server().await?;
+
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let source = StreamSource {
+        file_id: file_id.to_string(),
+        size: meta.size,
+        chunk_size,
+        validator: chunk_cache::validator(&meta),
+        content_type: guess_content_type(file_id),
+    };
+    {
+        let mut sessions = sessions().lock().unwrap();
+        let (map, order) = &mut *sessions;
+        map.insert(token.clone(), source.clone());
+        order.push_back(token.clone());
+        while order.len() > MAX_SESSIONS {
+            if let Some(oldest) = order.pop_front() {
+                map.remove(&oldest);
+            }
+        }
+    }
+
+    Ok(StreamInfo {
+        url: format!("http://127.0.0.1:{}/stream/{}", port, token),
+        file_id: source.file_id,
+        size: source.size,
+        content_type: source.content_type.to_string(),
+    })
+}
+
+// 退出时停掉播放服务
+pub async fn stop() -> bool {
+    match server_state().lock().await.take() {
+        Some(server) => {
+            let _ = server.shutdown.send(());
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_range_headers() {
+        assert_eq!(parse_range(None, 100), None);
+        assert_eq!(parse_range(Some("bytes=0-9"), 100), Some(Ok((0, 9))));
+        assert_eq!(parse_range(Some("bytes=90-"), 100), Some(Ok((90, 99))));
+        assert_eq!(parse_range(Some("bytes=-10"), 100), Some(Ok((90, 99))));
+        // 结尾超出文件大小时截到最后一个字节
+        assert_eq!(parse_range(Some("bytes=50-1000"), 100), Some(Ok((50, 99))));
+        assert_eq!(parse_range(Some("bytes=100-"), 100), Some(Err(())));
+        assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), Some(Err(())));
+        assert_eq!(parse_range(Some("items=0-1"), 100), Some(Err(())));
+    }
+
+    #[test]
+    fn guesses_media_types() {
+        assert_eq!(guess_content_type("videos/会议.MP4"), "video/mp4");
+        assert_eq!(guess_content_type("music/a.flac"), "audio/flac");
+        assert_eq!(guess_content_type("doc/readme"), "application/octet-stream");
+    }
+}
@@ -0,0 +1,340 @@
+// 运行指标
+// 给自助终端和批量部署的机器做监控：正在进行的传输、失败率、蓝牙重连次数、认证失败次数
+//
+// 思考：计数器用原子变量，记录的地方（认证、蓝牙连接、传输结束）只是加一，不加锁也不用await。
+// 正在进行的传输数量在采集时直接从任务表里数，不另外维护一份，免得和任务表对不上。
+// 两种输出方式：
+// 1. 本地接口的 GET /api/metrics，默认是Prometheus文本格式，?format=json返回JSON
+// 2. 设置里metrics.dump_interval_secs不为0时，定时把JSON写到应用数据目录的metrics.json，
+//    抓不了HTTP的监控工具直接读文件
+// 计数器从程序启动开始累计，重启后清零，Prometheus按counter处理没问题。
+// 平均速度按每次传输实际传了多少字节、花了多久累计，不按任务表里的瞬时速度算。
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::fs;
+
+use crate::download::DownloadStatus;
+use crate::transfer_manager::{download_tasks, upload_tasks};
+use crate::upload::UploadStatus;
+use crate::{cpen_device_manager, settings};
+
+// 关闭定时写文件时，多久检查一次设置有没有打开
+const DUMP_IDLE_CHECK: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Counter {
+    DownloadsCompleted,
+    DownloadsFailed,
+    UploadsCompleted,
+    UploadsFailed,
+    AuthFailures,
+    BleConnects,
+    BleReconnects,
+    BleConnectFailures,
+}
+
+impl Counter {
+    const ALL: [Counter; 8] = [
+        Counter::DownloadsCompleted,
+        Counter::DownloadsFailed,
+        Counter::UploadsCompleted,
+        Counter::UploadsFailed,
+        Counter::AuthFailures,
+        Counter::BleConnects,
+        Counter::BleReconnects,
+        Counter::BleConnectFailures,
+    ];
+
+    // JSON里的名字，Prometheus里加上camfc_前缀和_total后缀
+    fn name(self) -> &'static str {
+        match self {
+            Counter::DownloadsCompleted => "downloads_completed",
+            Counter::DownloadsFailed => "downloads_failed",
+            Counter::UploadsCompleted => "uploads_completed",
+            Counter::UploadsFailed => "uploads_failed",
+            Counter::AuthFailures => "auth_failures",
+            Counter::BleConnects => "ble_connects",
+            Counter::BleReconnects => "ble_reconnects",
+            Counter::BleConnectFailures => "ble_connect_failures",
+        }
+    }
+
+    fn help(self) -> &'static str {
+        match self {
+            Counter::DownloadsCompleted => "Downloads finished successfully",
+            Counter::DownloadsFailed => "Download attempts that ended with an error",
+            Counter::UploadsCompleted => "Uploads finished successfully",
+            Counter::UploadsFailed => "Upload attempts that ended with an error",
+            Counter::AuthFailures => "Requests rejected by the backend with 401/403",
+            Counter::BleConnects => "Successful pen connections",
+            Counter::BleReconnects => "Connections re-established after the pen dropped",
+            Counter::BleConnectFailures => "Failed pen connection attempts",
+        }
+    }
+}
+
+static COUNTERS: [AtomicU64; Counter::ALL.len()] = [const { AtomicU64::new(0) }; Counter::ALL.len()];
+static STARTED_AT: OnceLock<Instant> = OnceLock::new();
+
+// 计数器加一
+pub fn record(counter: Counter) {
+    STARTED_AT.get_or_init(Instant::now);
+    COUNTERS[counter as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn value(counter: Counter) -> u64 {
+    COUNTERS[counter as usize].load(Ordering::Relaxed)
+}
+
+// 累计传输的字节数和用时（毫秒），算平均速度用
+static DOWNLOAD_BYTES: AtomicU64 = AtomicU64::new(0);
+static DOWNLOAD_MILLIS: AtomicU64 = AtomicU64::new(0);
+static UPLOAD_BYTES: AtomicU64 = AtomicU64::new(0);
+static UPLOAD_MILLIS: AtomicU64 = AtomicU64::new(0);
+
+// 记一次下载传了多少字节、花了多久
+pub fn record_download_throughput(bytes: u64, elapsed: Duration) {
+    if bytes > 0 {
+        DOWNLOAD_BYTES.fetch_add(bytes, Ordering::Relaxed);
+        DOWNLOAD_MILLIS.fetch_add(elapsed.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+// 记一次上传传了多少字节、花了多久
+pub fn record_upload_throughput(bytes: u64, elapsed: Duration) {
+    if bytes > 0 {
+        UPLOAD_BYTES.fetch_add(bytes, Ordering::Relaxed);
+        UPLOAD_MILLIS.fetch_add(elapsed.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+// 累计的传输量和用时
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Throughput {
+    pub download_bytes: u64,
+    pub download_millis: u64,
+    pub upload_bytes: u64,
+    pub upload_millis: u64,
+}
+
+impl Throughput {
+    pub fn download_kbps(&self) -> Option<f64> {
+        kbps(self.download_bytes, self.download_millis)
+    }
+
+    pub fn upload_kbps(&self) -> Option<f64> {
+        kbps(self.upload_bytes, self.upload_millis)
+    }
+
+    // 从earlier到现在这段时间里的量
+    pub fn since(&self, earlier: &Throughput) -> Throughput {
+        Throughput {
+            download_bytes: self.download_bytes.saturating_sub(earlier.download_bytes),
+            download_millis: self.download_millis.saturating_sub(earlier.download_millis),
+            upload_bytes: self.upload_bytes.saturating_sub(earlier.upload_bytes),
+            upload_millis: self.upload_millis.saturating_sub(earlier.upload_millis),
+        }
+    }
+}
+
+fn kbps(bytes: u64, millis: u64) -> Option<f64> {
+    (millis > 0).then(|| bytes as f64 / 1024.0 / (millis as f64 / 1000.0))
+}
+
+pub fn throughput() -> Throughput {
+    Throughput {
+        download_bytes: DOWNLOAD_BYTES.load(Ordering::Relaxed),
+        download_millis: DOWNLOAD_MILLIS.load(Ordering::Relaxed),
+        upload_bytes: UPLOAD_BYTES.load(Ordering::Relaxed),
+        upload_millis: UPLOAD_MILLIS.load(Ordering::Relaxed),
+    }
+}
+
+// 各状态的任务数量
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TransferCounts {
+    pub active: usize,     // 等待开始和传输中
+    pub paused: usize,
+    pub failed: usize,
+    pub completed: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    pub timestamp: i64,
+    pub uptime_secs: u64,
+    pub downloads: TransferCounts,
+    pub uploads: TransferCounts,
+    pub counters: BTreeMap<&'static str, u64>,
+    pub download_error_rate: f64,   // 结束的下载里失败的比例
+    pub upload_error_rate: f64,
+    pub avg_download_kbps: f64,     // 平均下载速度，还没有下载过时为0
+    pub avg_upload_kbps: f64,
+    pub ble_connected: bool,
+    pub auth_locked: bool,
+}
+
+fn error_rate(failed: u64, completed: u64) -> f64 {
+    match failed + completed {
+        0 => 0.0,
+        total => failed as f64 / total as f64,
+    }
+}
+
+// 采集当前指标
+pub async fn snapshot() -> MetricsSnapshot {
+    let mut downloads = TransferCounts::default();
+    let tasks: Vec<_> = download_tasks().lock().await.values().cloned().collect();
+    for task in tasks {
+        match task.get_progress().await.status {
+            DownloadStatus::Pending | DownloadStatus::Downloading | DownloadStatus::WaitingForServer(_) | DownloadStatus::Verifying(_) | DownloadStatus::Following => downloads.active += 1,
+            DownloadStatus::Paused => downloads.paused += 1,
+            DownloadStatus::Completed => downloads.completed += 1,
+            DownloadStatus::Error(_) => downloads.failed += 1,
+        }
+    }
+
+    let mut uploads = TransferCounts::default();
+    let tasks: Vec<_> = upload_tasks().lock().await.values().cloned().collect();
+    for task in tasks {
+        match task.get_progress().await.status {
+            UploadStatus::Pending | UploadStatus::Uploading | UploadStatus::WaitingForServer(_) => uploads.active += 1,
+            UploadStatus::Paused => uploads.paused += 1,
+            UploadStatus::Completed => uploads.completed += 1,
+            UploadStatus::FileInUse | UploadStatus::Error(_) => uploads.failed += 1,
+        }
+    }
+
+    let counters: BTreeMap<&'static str, u64> = Counter::ALL
+        .iter()
+        .map(|&counter| (counter.name(), value(counter)))
+        .collect();
+
+    MetricsSnapshot {
+        timestamp: chrono::Utc::now().timestamp(),
+        uptime_secs: STARTED_AT.get_or_init(Instant::now).elapsed().as_secs(),
+        downloads,
+        uploads,
+        download_error_rate: error_rate(value(Counter::DownloadsFailed), value(Counter::DownloadsCompleted)),
+        upload_error_rate: error_rate(value(Counter::UploadsFailed), value(Counter::UploadsCompleted)),
+        avg_download_kbps: throughput().download_kbps().unwrap_or(0.0),
+        avg_upload_kbps: throughput().upload_kbps().unwrap_or(0.0),
+        counters,
+        ble_connected: cpen_device_manager::connection_snapshot().is_connected(),
+        auth_locked: crate::auth::lock_remaining().is_some(),
+    }
+}
+
+// 输出一个指标，labels为空表示没有标签
+fn push_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    out.push_str(&format!("# HELP camfc_{} {}\n# TYPE camfc_{} {}\n", name, help, name, kind));
+    for (labels, value) in samples {
+        out.push_str(&format!("camfc_{}{} {}\n", name, labels, value));
+    }
+}
+
+fn state_samples(counts: &TransferCounts) -> Vec<(String, f64)> {
+    [("active", counts.active), ("paused", counts.paused), ("failed", counts.failed), ("completed", counts.completed)]
+        .iter()
+        .map(|(state, count)| (format!("{{state=\"{}\"}}", state), *count as f64))
+        .collect()
+}
+
+// 转成Prometheus文本格式
+pub fn render_prometheus(snapshot: &MetricsSnapshot) -> String {
+    let mut out = String::new();
+    let single = |value: f64| vec![(String::new(), value)];
+
+    push_metric(&mut out, "downloads", "gauge", "Download tasks by state", &state_samples(&snapshot.downloads));
+    push_metric(&mut out, "uploads", "gauge", "Upload tasks by state", &state_samples(&snapshot.uploads));
+    push_metric(&mut out, "uptime_seconds", "gauge", "Seconds since metrics started", &single(snapshot.uptime_secs as f64));
+    push_metric(&mut out, "ble_connected", "gauge", "Whether the pen is connected", &single(snapshot.ble_connected as u8 as f64));
+    push_metric(&mut out, "auth_locked", "gauge", "Whether authentication is locked after repeated failures",
+        &single(snapshot.auth_locked as u8 as f64));
+
+    for counter in Counter::ALL {
+        let value = snapshot.counters.get(counter.name()).copied().unwrap_or(0);
+        push_metric(&mut out, &format!("{}_total", counter.name()), "counter", counter.help(), &single(value as f64));
+    }
+    out
+}
+
+// 把指标写到文件，先写临时文件再重命名，监控工具不会读到写了一半的文件
+pub async fn write_json(path: &Path) -> Result<()> {
+    let content = serde_json::to_string_pretty(&snapshot().await).context("序列化运行指标失败")?;
+    let mut tmp_name = path.as_os_str().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = std::path::PathBuf::from(tmp_name);
+    fs::write(&tmp_path, content).await
+        .context(format!("写入运行指标失败: {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path).await
+        .context(format!("保存运行指标失败: {:?}", path))?;
+    Ok(())
+}
+
+// 定时写metrics.json，间隔从设置里读，改了设置不用重启
+pub async fn dump_loop() {
+    STARTED_AT.get_or_init(Instant::now);
+    loop {
+        let interval = settings::get().metrics.dump_interval_secs;
+        if interval == 0 {
+            tokio::time::sleep(DUMP_IDLE_CHECK).await;
+            continue;
+        }
+
+        match crate::storage::get_app_data_dir() {
+            Ok(dir) => {
+                if let Err(e) = write_json(&dir.join("metrics.json")).await {
+                    println!("{:#}", e);
+                }
+            }
+            Err(e) => println!("获取数据目录失败，跳过写运行指标: {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_rate_handles_no_transfers() {
+        assert_eq!(error_rate(0, 0), 0.0);
+        assert_eq!(error_rate(1, 3), 0.25);
+    }
+
+    #[tokio::test]
+    async fn renders_counters_and_transfer_states() {
+        record(Counter::BleReconnects);
+        let snapshot = snapshot().await;
+        assert!(snapshot.counters["ble_reconnects"] >= 1);
+
+        let text = render_prometheus(&snapshot);
+        assert!(text.contains("# TYPE camfc_ble_reconnects_total counter\n"));
+        assert!(text.contains("camfc_downloads{state=\"active\"} "));
+        assert!(text.contains("camfc_auth_failures_total "));
+        // 每个样本一行，"名字 值"
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            assert_eq!(line.split(' ').count(), 2, "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_json_dump() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.json");
+        write_json(&path).await.unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(value["counters"]["uploads_failed"].is_u64());
+        assert!(value["downloads"]["active"].is_u64());
+        assert!(!dir.path().join("metrics.json.tmp").exists());
+    }
+}
@@ -0,0 +1,416 @@
+// 测试用的模拟后端
+// 只在cargo test时编译，给下载/上传的集成测试提供一个本地HTTP服务
+//
+// 思考：下载/上传的HTTP逻辑（Range分片、断点续传、上传init/chunk/finish）
+// 原来只能连真实后端手动测。这里用axum实现一个够用的后端：
+// 1. 文件内容放在内存里，GET /download/{path} 支持Range
+// 2. /upload/init、/upload/chunk、/upload/status、/upload/finish 和真实后端参数一致
+//    GET /public/{path} 不需要认证，当作网上的普通文件（测试从网址上传）
+// 3. 认证头里的Totp必须是VALID_TOTP，否则返回401
+// 4. 可以按key注入故障：接下来N次请求失败，或者从某个位置开始一直失败，
+//    或者从某个位置开始Range响应只返回一半（模拟连接中途断掉、响应被截短）
+// 5. 记录每次请求的分片位置，测试里检查续传时有没有重复请求
+//
+// 服务跑在单独的线程和runtime上，所有测试共用一个，
+// 每个#[tokio::test]结束时自己的runtime会被销毁，服务不能跟着它走。
+// 测试之间靠不同的文件路径互相隔离。
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
+use axum::body::Bytes;
+use axum::extract::{Multipart, Path, Query};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::auth::AuthInfo;
+use crate::transfer_http::CONTENT_HASH_HEADER;
+
+pub const VALID_DEVICE_ID: &str = "mock-device";
+pub const VALID_TOTP: &str = "246810";
+
+// 注入的故障
+#[derive(Debug, Default, Clone, Copy)]
+struct Fault {
+    fail_next: u32,           // 接下来几次请求返回503
+    fail_from: Option<u64>,   // 下载：Range起点>=这个字节位置就失败；上传：分片序号>=这个值就失败
+    short_from: Option<u64>,  // 下载：Range起点>=这个字节位置的响应只返回前一半
+}
+
+#[derive(Default)]
+struct MockState {
+    files: HashMap<String, Vec<u8>>,                  // 云盘路径 -> 文件内容
+    uploads: HashMap<String, BTreeMap<u32, Vec<u8>>>, // upload_id -> 已收到的分片
+    faults: HashMap<String, Fault>,                   // 下载按文件路径，上传按upload_id
+    requests: HashMap<String, Vec<u64>>,              // 成功的请求：下载记Range起点，上传记分片序号
+    modified: HashMap<String, i64>,                   // 云盘路径 -> 修改时间（Unix时间戳）
+    public: HashMap<String, Vec<u8>>,                 // /public/下的路径 -> 文件内容
+    lost_chunks: HashSet<(String, u32)>,              // 下次收到时回复成功但不保存的分片
+    content_types: HashMap<String, String>,           // 云盘路径 -> 上传时带的content_type
+}
+
+static STATE: OnceLock<Mutex<MockState>> = OnceLock::new();
+static BASE_URL: OnceLock<String> = OnceLock::new();
+
+fn state() -> std::sync::MutexGuard<'static, MockState> {
+    STATE
+        .get_or_init(|| Mutex::new(MockState::default()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+// 启动模拟后端（只启动一次），并把后端配置指向它
+pub fn start() -> String {
+    BASE_URL
+        .get_or_init(|| {
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                let runtime = tokio::runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .build()
+                    .expect("创建模拟后端runtime失败");
+                runtime.block_on(async move {
+                    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+                        .await
+                        .expect("模拟后端监听失败");
+                    let port = listener.local_addr().expect("获取模拟后端端口失败").port();
+                    tx.send(port).expect("通知模拟后端端口失败");
+                    axum::serve(listener, build_router())
+                        .await
+                        .expect("模拟后端异常退出");
+                });
+            });
+
+            let port = rx.recv().expect("模拟后端启动失败");
+            crate::config::set_backend_for_test("http://127.0.0.1", port);
+            format!("http://127.0.0.1:{}", port)
+        })
+        .clone()
+}
+
+// 能通过模拟后端认证的认证信息
+pub fn valid_auth() -> AuthInfo {
+    AuthInfo {
+        device_id: VALID_DEVICE_ID.to_string(),
+        totp: VALID_TOTP.to_string(),
+        challenge: None,
+    }
+}
+
+// 会被模拟后端拒绝的认证信息
+pub fn invalid_auth() -> AuthInfo {
+    AuthInfo {
+        device_id: VALID_DEVICE_ID.to_string(),
+        totp: "000000".to_string(),
+        challenge: None,
+    }
+}
+
+// 在云盘里放一个文件
+pub fn put_file(path: &str, content: Vec<u8>) {
+    state().files.insert(path.to_string(), content);
+}
+
+// 设置云盘文件的修改时间，下载时通过Last-Modified返回
+pub fn set_modified(path: &str, modified_at: i64) {
+    state().modified.insert(path.to_string(), modified_at);
+}
+
+// 上传完成时客户端告诉我们的修改时间
+pub fn get_modified(path: &str) -> Option<i64> {
+    state().modified.get(path).copied()
+}
+
+// 上传完成时客户端告诉我们的文件类型
+pub fn get_content_type(path: &str) -> Option<String> {
+    state().content_types.get(path).cloned()
+}
+
+// 读取云盘里的文件（上传完成后检查内容）
+pub fn get_file(path: &str) -> Option<Vec<u8>> {
+    state().files.get(path).cloned()
+}
+
+// 放一个不需要认证就能下载的文件，地址是 {base_url}/public/{path}
+pub fn put_public(path: &str, content: Vec<u8>) {
+    state().public.insert(path.to_string(), content);
+}
+
+// 上传会话的这个分片下次到达时"丢掉"：回复成功但不保存，模拟服务器端丢了分片
+pub fn lose_chunk(upload_id: &str, index: u32) {
+    state().lost_chunks.insert((upload_id.to_string(), index));
+}
+
+// 上传会话过期：服务器上的会话和已收到的分片都没了
+pub fn expire_upload(upload_id: &str) {
+    state().uploads.remove(upload_id);
+}
+
+// 接下来times次请求返回503，模拟网络抖动
+pub fn fail_next(key: &str, times: u32) {
+    state().faults.entry(key.to_string()).or_default().fail_next = times;
+}
+
+// 从某个位置开始的请求一直失败，直到clear_faults
+pub fn fail_from(key: &str, position: u64) {
+    state().faults.entry(key.to_string()).or_default().fail_from = Some(position);
+}
+
+// 从某个位置开始的Range响应只返回前一半，直到clear_faults
+pub fn truncate_from(key: &str, position: u64) {
+    state().faults.entry(key.to_string()).or_default().short_from = Some(position);
+}
+
+pub fn clear_faults(key: &str) {
+    state().faults.remove(key);
+}
+
+// 成功请求过的位置（下载是Range起点，上传是分片序号）
+pub fn requests(key: &str) -> Vec<u64> {
+    state().requests.get(key).cloned().unwrap_or_default()
+}
+
+// 检查故障注入，需要失败时返回503
+fn check_fault(key: &str, position: u64) -> Option<Response> {
+    let mut state = state();
+    let fault = state.faults.get_mut(key)?;
+    if fault.fail_next > 0 {
+        fault.fail_next -= 1;
+        return Some((StatusCode::SERVICE_UNAVAILABLE, "模拟网络故障").into_response());
+    }
+    if fault.fail_from.is_some_and(|from| position >= from) {
+        return Some((StatusCode::SERVICE_UNAVAILABLE, "模拟网络故障").into_response());
+    }
+    None
+}
+
+// 这个位置的响应要不要截短
+fn is_truncated(key: &str, position: u64) -> bool {
+    state().faults.get(key).and_then(|fault| fault.short_from).is_some_and(|from| position >= from)
+}
+
+fn record_request(key: &str, position: u64) {
+    state().requests.entry(key.to_string()).or_default().push(position);
+}
+
+// 检查认证头，格式和auth::AuthInfo::get_auth_header一致
+fn check_auth(headers: &HeaderMap) -> Option<Response> {
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| serde_json::from_str::<serde_json::Value>(v).ok())
+        .is_some_and(|auth| auth["Id"] == VALID_DEVICE_ID && auth["Totp"] == VALID_TOTP);
+    if authorized {
+        None
+    } else {
+        Some((StatusCode::UNAUTHORIZED, "认证失败").into_response())
+    }
+}
+
+// 解析 Range: bytes=start-end
+fn parse_range(headers: &HeaderMap, len: u64) -> Option<(u64, u64)> {
+    let range = headers.get(header::RANGE)?.to_str().ok()?;
+    let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.parse::<u64>().ok()?;
+    let end = end
+        .parse::<u64>()
+        .unwrap_or(len.saturating_sub(1))
+        .min(len.saturating_sub(1));
+    Some((start, end))
+}
+
+async fn download_handler(Path(path): Path<String>, headers: HeaderMap) -> Response {
+    if let Some(response) = check_auth(&headers) {
+        return response;
+    }
+
+    let (content, modified) = {
+        let state = state();
+        match state.files.get(&path) {
+            Some(content) => (content.clone(), state.modified.get(&path).copied()),
+            None => return (StatusCode::NOT_FOUND, "文件不存在").into_response(),
+        }
+    };
+    let len = content.len() as u64;
+    let last_modified = modified
+        .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+        .map(|t| t.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+
+    match parse_range(&headers, len) {
+        Some((start, end)) => {
+            if let Some(response) = check_fault(&path, start) {
+                return response;
+            }
+            if start > end || start >= len {
+                return StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+            }
+            record_request(&path, start);
+            let mut body = content[start as usize..=end as usize].to_vec();
+            if is_truncated(&path, start) {
+                body.truncate(body.len() / 2);
+            }
+            (
+                StatusCode::PARTIAL_CONTENT,
+                [(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len))],
+                body,
+            )
+                .into_response()
+        }
+        // 没有Range就是HEAD或者整个文件下载
+        None => {
+            let hash = hex::encode(Sha256::digest(&content));
+            let mut response = (
+                [(header::CONTENT_LENGTH, len.to_string()), (HeaderName::from_static(CONTENT_HASH_HEADER), hash)],
+                content,
+            )
+                .into_response();
+            if let Some(value) = last_modified.and_then(|v| HeaderValue::from_str(&v).ok()) {
+                response.headers_mut().insert(header::LAST_MODIFIED, value);
+            }
+            response
+        }
+    }
+}
+
+async fn public_handler(Path(path): Path<String>) -> Response {
+    match state().public.get(&path) {
+        Some(content) => content.clone().into_response(),
+        None => (StatusCode::NOT_FOUND, "文件不存在").into_response(),
+    }
+}
+
+async fn upload_init_handler(headers: HeaderMap) -> Response {
+    if let Some(response) = check_auth(&headers) {
+        return response;
+    }
+
+    let upload_id = uuid::Uuid::new_v4().simple().to_string();
+    state().uploads.insert(upload_id.clone(), BTreeMap::new());
+    Json(serde_json::json!({ "upload_id": upload_id })).into_response()
+}
+
+#[derive(Deserialize)]
+struct ChunkQuery {
+    upload_id: String,
+    index: u32,
+}
+
+async fn upload_chunk_handler(
+    Query(query): Query<ChunkQuery>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if let Some(response) = check_auth(&headers) {
+        return response;
+    }
+    if let Some(response) = check_fault(&query.upload_id, query.index as u64) {
+        return response;
+    }
+
+    let mut data: Option<Bytes> = None;
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some("file") {
+            data = field.bytes().await.ok();
+        }
+    }
+    let data = match data {
+        Some(data) => data,
+        None => return (StatusCode::BAD_REQUEST, "缺少file字段").into_response(),
+    };
+
+    let mut state = state();
+    if state.lost_chunks.remove(&(query.upload_id.clone(), query.index)) {
+        return Json(serde_json::json!({ "success": true })).into_response();
+    }
+    let chunks = match state.uploads.get_mut(&query.upload_id) {
+        Some(chunks) => chunks,
+        None => return (StatusCode::NOT_FOUND, "上传会话不存在").into_response(),
+    };
+    chunks.insert(query.index, data.to_vec());
+    state
+        .requests
+        .entry(query.upload_id.clone())
+        .or_default()
+        .push(query.index as u64);
+    Json(serde_json::json!({ "success": true })).into_response()
+}
+
+async fn upload_status_handler(Path(upload_id): Path<String>, headers: HeaderMap) -> Response {
+    if let Some(response) = check_auth(&headers) {
+        return response;
+    }
+
+    match state().uploads.get(&upload_id) {
+        Some(chunks) => {
+            let uploaded: Vec<u32> = chunks.keys().copied().collect();
+            Json(serde_json::json!({ "uploaded_chunks": uploaded })).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "上传会话不存在").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct FinishQuery {
+    upload_id: String,
+    filename: String,
+    total_chunks: u32,
+    target_path: Option<String>,
+    modified_at: Option<i64>,
+    content_type: Option<String>,
+}
+
+async fn upload_finish_handler(Query(query): Query<FinishQuery>, headers: HeaderMap) -> Response {
+    if let Some(response) = check_auth(&headers) {
+        return response;
+    }
+
+    let mut state = state();
+    let chunks = match state.uploads.get(&query.upload_id) {
+        Some(chunks) => chunks,
+        None => return (StatusCode::NOT_FOUND, "上传会话不存在").into_response(),
+    };
+    if chunks.len() as u32 != query.total_chunks {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("分片不完整: {}/{}", chunks.len(), query.total_chunks),
+        )
+            .into_response();
+    }
+
+    let content: Vec<u8> = chunks.values().flatten().copied().collect();
+    let path = match query.target_path.as_deref().map(|p| p.trim_matches('/')) {
+        Some(dir) if !dir.is_empty() => format!("{}/{}", dir, query.filename),
+        _ => query.filename.clone(),
+    };
+    state.uploads.remove(&query.upload_id);
+    state.files.insert(path.clone(), content);
+    match query.modified_at {
+        Some(modified_at) => state.modified.insert(path.clone(), modified_at),
+        None => state.modified.remove(&path),
+    };
+    match query.content_type {
+        Some(content_type) => state.content_types.insert(path.clone(), content_type),
+        None => state.content_types.remove(&path),
+    };
+    Json(serde_json::json!({ "success": true, "path": path })).into_response()
+}
+
+fn build_router() -> Router {
+    Router::new()
+        .route("/download/{*path}", get(download_handler))
+        .route("/public/{*path}", get(public_handler))
+        .route("/upload/init", post(upload_init_handler))
+        .route("/upload/chunk", post(upload_chunk_handler))
+        .route("/upload/status/{upload_id}", get(upload_status_handler))
+        .route("/upload/finish", post(upload_finish_handler))
+        // 每个测试有自己的runtime，连接不能留给下一个测试复用
+        .layer(axum::middleware::map_response(|mut response: Response| async move {
+            response
+                .headers_mut()
+                .insert(header::CONNECTION, HeaderValue::from_static("close"));
+            response
+        }))
+}
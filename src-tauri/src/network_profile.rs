@@ -0,0 +1,282 @@
+// 网络类型检测
+// 判断当前网络是不是按流量计费的，按设置里的策略限制传输
+//
+// 思考：用手机热点或者蜂窝网络时，随手点一个几个G的下载就可能把流量用光。
+// 检测方法按平台不同：
+// 1. Windows：NetworkInformation的连接费用（Fixed/Variable、漫游、超出流量上限都算计费）
+// 2. Linux：先问NetworkManager（nmcli）这个网卡是不是计费的，问不到就按网卡名猜：
+//    wwan/ppp是蜂窝网络，usb/rndis是手机USB共享，都当作计费
+// 3. 其他平台检测不了，按不计费处理
+// 检测不准时用户可以在设置里用treat_as_metered手动指定。
+// 策略只对计费网络生效：pause_large/ask时大文件在分片之间停下等待（ask会通知前端询问），
+// 换到不计费的网络或者用户允许后继续；cap_bandwidth时所有传输共用一个速度上限（rate_limiter.rs）。
+// 停下等待的任务还占着并发名额，和让路给高优先级下载的低优先级任务一样。
+// 网络状态由常驻服务定时刷新，命令行模式不启动这个服务，不受策略限制。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::event_emitter;
+use crate::rate_limiter::RateLimiter;
+use crate::settings::{self, MeteredPolicy};
+use crate::transfer_queue::Direction;
+
+// 定时检测网络的间隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// 停下等待的任务多久检查一次自己有没有被暂停
+const HOLD_RECHECK: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionKind {
+    Ethernet,
+    Wifi,
+    Cellular,
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NetworkProfile {
+    pub kind: ConnectionKind,
+    pub detected_metered: bool,
+    pub source: &'static str,   // windows/nmcli/heuristic/unknown
+    pub name: Option<String>,   // 网络名或网卡名
+}
+
+impl NetworkProfile {
+    fn unknown() -> Self {
+        Self { kind: ConnectionKind::Unknown, detected_metered: false, source: "unknown", name: None }
+    }
+
+    // 考虑了手动指定之后是否计费
+    pub fn is_metered(&self) -> bool {
+        settings::get().metered.treat_as_metered.unwrap_or(self.detected_metered)
+    }
+}
+
+static PROFILE: OnceLock<RwLock<NetworkProfile>> = OnceLock::new();
+static PROFILE_CHANGED: OnceLock<Notify> = OnceLock::new();
+// 用户允许在当前网络上传大文件，换网络后失效
+static APPROVED: AtomicBool = AtomicBool::new(false);
+// cap_bandwidth的限速器
+static LIMITER: RateLimiter = RateLimiter::new();
+
+fn profile_lock() -> &'static RwLock<NetworkProfile> {
+    PROFILE.get_or_init(|| RwLock::new(NetworkProfile::unknown()))
+}
+
+fn profile_changed() -> &'static Notify {
+    PROFILE_CHANGED.get_or_init(Notify::new)
+}
+
+pub fn current() -> NetworkProfile {
+    profile_lock().read().unwrap().clone()
+}
+
+pub fn is_approved() -> bool {
+    APPROVED.load(Ordering::SeqCst)
+}
+
+// 用户允许在当前的计费网络上继续传输
+pub fn approve() {
+    APPROVED.store(true, Ordering::SeqCst);
+    profile_changed().notify_waiters();
+}
+
+// 按网卡名猜网络类型和是否计费
+fn classify_interface(name: &str) -> (ConnectionKind, bool) {
+    // 蜂窝网卡和手机USB共享
+    const CELLULAR: [&str; 4] = ["ww", "ppp", "usb", "rndis"];
+    if CELLULAR.iter().any(|prefix| name.starts_with(prefix)) {
+        (ConnectionKind::Cellular, true)
+    } else if name.starts_with("wl") {
+        (ConnectionKind::Wifi, false)
+    } else {
+        (ConnectionKind::Ethernet, false)
+    }
+}
+
+// 从/proc/net/route里找默认路由的网卡
+fn default_route_interface(route_table: &str) -> Option<String> {
+    route_table.lines().skip(1).find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let iface = fields.next()?;
+        let destination = fields.next()?;
+        (destination == "00000000").then(|| iface.to_string())
+    })
+}
+
+// nmcli -g GENERAL.METERED的输出：yes/no，后面可能跟着"(guessed)"，unknown表示不知道
+fn parse_nmcli_metered(output: &str) -> Option<bool> {
+    match output.split_whitespace().next() {
+        Some("yes") => Some(true),
+        Some("no") => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(windows)]
+fn detect() -> NetworkProfile {
+    use windows::Networking::Connectivity::{NetworkCostType, NetworkInformation};
+
+    let result = (|| -> windows::core::Result<NetworkProfile> {
+        let profile = NetworkInformation::GetInternetConnectionProfile()?;
+        let cost = profile.GetConnectionCost()?;
+        let cost_type = cost.NetworkCostType()?;
+        let metered = cost_type == NetworkCostType::Fixed
+            || cost_type == NetworkCostType::Variable
+            || cost.Roaming()?
+            || cost.OverDataLimit()?;
+        let kind = if profile.IsWwanConnectionProfile()? {
+            ConnectionKind::Cellular
+        } else if profile.IsWlanConnectionProfile()? {
+            ConnectionKind::Wifi
+        } else {
+            ConnectionKind::Ethernet
+        };
+        Ok(NetworkProfile {
+            kind,
+            detected_metered: metered,
+            source: "windows",
+            name: profile.ProfileName().ok().map(|name| name.to_string()),
+        })
+    })();
+
+    match result {
+        Ok(profile) => profile,
+        Err(e) => {
+            println!("获取网络信息失败: {}", e);
+            NetworkProfile::unknown()
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn detect() -> NetworkProfile {
+    let iface = match std::fs::read_to_string("/proc/net/route").ok().and_then(|table| default_route_interface(&table)) {
+        Some(iface) => iface,
+        None => return NetworkProfile::unknown(),
+    };
+    let (kind, guessed) = classify_interface(&iface);
+
+    let nmcli = std::process::Command::new("nmcli")
+        .args(["-g", "GENERAL.METERED", "device", "show", &iface])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| parse_nmcli_metered(&String::from_utf8_lossy(&output.stdout)));
+
+    let (detected_metered, source) = match nmcli {
+        Some(metered) => (metered, "nmcli"),
+        None => (guessed, "heuristic"),
+    };
+    NetworkProfile { kind, detected_metered, source, name: Some(iface) }
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn detect() -> NetworkProfile {
+    NetworkProfile::unknown()
+}
+
+// 重新检测网络，网络变了返回true
+pub async fn refresh() -> bool {
+    let profile = match tokio::task::spawn_blocking(detect).await {
+        Ok(profile) => profile,
+        Err(_) => NetworkProfile::unknown(),
+    };
+
+    let mut current = profile_lock().write().unwrap();
+    if *current == profile {
+        return false;
+    }
+    println!("网络变化: {:?} -> {:?}", *current, profile);
+    *current = profile.clone();
+    drop(current);
+
+    APPROVED.store(false, Ordering::SeqCst);
+    event_emitter::emit_event("network-profile-changed", serde_json::json!({
+        "kind": profile.kind,
+        "metered": profile.is_metered(),
+        "name": profile.name,
+    }));
+    profile_changed().notify_waiters();
+    true
+}
+
+// 常驻服务：定时检测网络
+pub async fn watch_loop() {
+    loop {
+        refresh().await;
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+// 这个大小的传输现在要不要停下等待
+pub fn should_hold(total_size: u64) -> bool {
+    let metered = settings::get().metered;
+    if !matches!(metered.policy, MeteredPolicy::PauseLarge | MeteredPolicy::Ask) {
+        return false;
+    }
+    total_size >= metered.large_file_mb.saturating_mul(1024 * 1024)
+        && current().is_metered()
+        && !is_approved()
+}
+
+// 通知前端有传输因为计费网络停下了，ask策略时前端据此询问用户
+pub fn notify_held(direction: &str, id: &str, file_name: &str, total_size: u64) {
+    println!("当前是计费网络，{}任务 {} 暂停等待（{} 字节）", direction, file_name, total_size);
+    event_emitter::emit_transfer_event("metered-transfer-held", serde_json::json!({
+        "direction": direction,
+        "id": id,
+        "file_name": file_name,
+        "total_size": total_size,
+        "policy": settings::get().metered.policy,
+    }));
+}
+
+// 等网络变化或者用户允许，最多等HOLD_RECHECK，调用方醒来后检查自己的状态
+pub async fn wait_for_change() {
+    let _ = tokio::time::timeout(HOLD_RECHECK, profile_changed().notified()).await;
+}
+
+// cap_bandwidth策略下传完一个分片后记账，返回按速度上限可以继续的时间，不限速时为None
+pub fn reserve(direction: Direction, bytes: u64) -> Option<Instant> {
+    let metered = settings::get().metered;
+    if metered.policy != MeteredPolicy::CapBandwidth || !current().is_metered() {
+        return None;
+    }
+    LIMITER.reserve(direction, bytes, metered.bandwidth_cap_kbps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_default_route_interface() {
+        let table = "Iface\tDestination\tGateway\tFlags\n\
+                     docker0\t000011AC\t00000000\t0001\n\
+                     wlp2s0\t00000000\t0101A8C0\t0003\n";
+        assert_eq!(default_route_interface(table), Some("wlp2s0".to_string()));
+        assert_eq!(default_route_interface("Iface\tDestination\n"), None);
+    }
+
+    #[test]
+    fn classifies_interfaces() {
+        assert_eq!(classify_interface("wwan0"), (ConnectionKind::Cellular, true));
+        assert_eq!(classify_interface("usb0"), (ConnectionKind::Cellular, true));
+        assert_eq!(classify_interface("wlp2s0"), (ConnectionKind::Wifi, false));
+        assert_eq!(classify_interface("enp3s0"), (ConnectionKind::Ethernet, false));
+    }
+
+    #[test]
+    fn parses_nmcli_output() {
+        assert_eq!(parse_nmcli_metered("yes (guessed)\n"), Some(true));
+        assert_eq!(parse_nmcli_metered("no\n"), Some(false));
+        assert_eq!(parse_nmcli_metered("unknown\n"), None);
+    }
+}
@@ -0,0 +1,227 @@
+// 首次使用引导
+// 按顺序完成：检查蓝牙权限并开启蓝牙 → 扫描 → 选择笔 → 连接 → 读取设备ID → 检查后端连通
+//
+// 思考：前端只需要画一个向导页面，每一步的逻辑都在这里。
+// 每一步开始/结束都发onboarding-step事件，失败时带上给用户看的处理建议。
+// 扫描到多支笔时Rust没法替用户选，这一步返回action_required和候选列表，
+// 前端让用户选好后带着address再调用一次start_onboarding，从连接那一步继续。
+// 蓝牙这一步先检查权限（见ble_permissions），没有权限时处理建议换成对应平台的具体办法。
+
+use std::time::Duration;
+use serde::Serialize;
+
+use crate::ble_permissions;
+use crate::device_state::DeviceInfo;
+use crate::event_emitter;
+
+// 检查后端连通的超时时间
+const BACKEND_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnboardingStep {
+    Bluetooth,  // 开启蓝牙
+    Scan,       // 扫描Cpen设备
+    Pick,       // 选择要使用的笔
+    Connect,    // 连接笔
+    DeviceId,   // 读取设备ID
+    Backend,    // 检查后端是否可以访问
+}
+
+impl OnboardingStep {
+    // 这一步失败时给用户的处理建议
+    fn hint(self) -> &'static str {
+        match self {
+            OnboardingStep::Bluetooth => "请在系统设置里打开蓝牙；如果电脑没有蓝牙，可以插入USB蓝牙适配器",
+            OnboardingStep::Scan => "请确认笔已开机并靠近电脑，然后重试；改过名字的笔要先在设置里调整设备识别规则",
+            OnboardingStep::Pick => "找到多支笔，请选择要使用的那一支",
+            OnboardingStep::Connect => "请确认笔没有被其他电脑连接，必要时重启笔后重试",
+            OnboardingStep::DeviceId => "读取设备ID失败，请重启笔后重试；如果一直失败，笔的固件可能需要更新",
+            OnboardingStep::Backend => "无法访问云盘服务器，请检查网络连接，或者检查CAMFC_BASE/CAMFC_PORT配置",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Running,
+    Success,
+    Failed,
+    ActionRequired,  // 需要用户操作后再继续（选择笔）
+}
+
+// onboarding-step事件内容
+#[derive(Clone, Serialize)]
+struct StepEvent<'a> {
+    step: OnboardingStep,
+    status: StepStatus,
+    message: String,
+    hint: Option<&'static str>,
+    devices: Option<&'a [DeviceInfo]>,
+}
+
+fn emit_step(step: OnboardingStep, status: StepStatus, message: String, devices: Option<&[DeviceInfo]>) {
+    let hint = matches!(status, StepStatus::Failed | StepStatus::ActionRequired).then(|| step.hint());
+    emit_step_with_hint(step, status, message, hint, devices);
+}
+
+fn emit_step_with_hint(
+    step: OnboardingStep,
+    status: StepStatus,
+    message: String,
+    hint: Option<&'static str>,
+    devices: Option<&[DeviceInfo]>,
+) {
+    println!("[引导] {:?}: {:?} - {}", step, status, message);
+    event_emitter::emit_event("onboarding-step", StepEvent { step, status, message, hint, devices });
+}
+
+// 引导结果
+#[derive(Clone, Default, Serialize)]
+pub struct OnboardingResult {
+    pub success: bool,
+    pub failed_step: Option<OnboardingStep>,
+    pub error: Option<String>,
+    pub hint: Option<&'static str>,
+    pub needs_selection: bool,            // 有多支笔，需要用户选择后带address重新调用
+    pub candidates: Vec<DeviceInfo>,
+    pub device: Option<DeviceInfo>,
+    pub device_id: Option<String>,
+    pub backend_url: Option<String>,
+}
+
+impl OnboardingResult {
+    fn failed(step: OnboardingStep, error: String) -> Self {
+        Self::failed_with_hint(step, error, step.hint())
+    }
+
+    fn failed_with_hint(step: OnboardingStep, error: String, hint: &'static str) -> Self {
+        emit_step_with_hint(step, StepStatus::Failed, error.clone(), Some(hint), None);
+        Self {
+            failed_step: Some(step),
+            error: Some(error),
+            hint: Some(hint),
+            ..Default::default()
+        }
+    }
+}
+
+// 检查后端是否可以访问，调用 /test
+async fn check_backend() -> anyhow::Result<String> {
+    let base_url = crate::config::get_backend_url()?;
+    let url = format!("{}/test", base_url);
+
+    crate::http_client::shared_client()?
+        .get(&url)
+        .timeout(BACKEND_CHECK_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(base_url)
+}
+
+// 执行引导流程
+// address为None时扫描并自动选择唯一的一支笔，有多支笔时返回needs_selection
+pub async fn run(address: Option<String>) -> OnboardingResult {
+    // 1. 先检查权限，不用拿设备管理器的锁
+    emit_step(OnboardingStep::Bluetooth, StepStatus::Running, "正在检查蓝牙权限".to_string(), None);
+    let permissions = ble_permissions::check_ble_permissions().await;
+    if permissions.is_denied() {
+        let hint = permissions.remediation.unwrap_or(OnboardingStep::Bluetooth.hint());
+        return OnboardingResult::failed_with_hint(OnboardingStep::Bluetooth, permissions.message, hint);
+    }
+
+    let manager_lock = match crate::get_cpen_device_manager() {
+        Ok(manager) => manager,
+        Err(e) => return OnboardingResult::failed(OnboardingStep::Bluetooth, e),
+    };
+    let mut manager = manager_lock.lock().await;
+
+    // 1. 开启蓝牙
+    emit_step(OnboardingStep::Bluetooth, StepStatus::Running, "正在检查蓝牙".to_string(), None);
+    if let Err(e) = manager.ensure_bluetooth_enabled().await {
+        return OnboardingResult::failed(OnboardingStep::Bluetooth, e);
+    }
+    emit_step(OnboardingStep::Bluetooth, StepStatus::Success, "蓝牙已开启".to_string(), None);
+
+    // 2/3. 扫描并选择笔，已经选好时跳过扫描
+    let target = match address {
+        Some(address) => {
+            emit_step(OnboardingStep::Pick, StepStatus::Success, format!("已选择设备: {}", address), None);
+            address
+        }
+        None => {
+            emit_step(OnboardingStep::Scan, StepStatus::Running, "正在扫描Cpen设备".to_string(), None);
+            let devices = match manager.scan_cpen_devices().await {
+                Ok(devices) => devices,
+                Err(e) => return OnboardingResult::failed(OnboardingStep::Scan, e),
+            };
+            if devices.is_empty() {
+                return OnboardingResult::failed(OnboardingStep::Scan, "没有找到Cpen设备".to_string());
+            }
+            emit_step(OnboardingStep::Scan, StepStatus::Success, format!("找到 {} 支笔", devices.len()), None);
+
+            if devices.len() > 1 {
+                emit_step(
+                    OnboardingStep::Pick,
+                    StepStatus::ActionRequired,
+                    format!("找到 {} 支笔，请选择一支", devices.len()),
+                    Some(devices.as_slice()),
+                );
+                return OnboardingResult {
+                    needs_selection: true,
+                    hint: Some(OnboardingStep::Pick.hint()),
+                    candidates: devices,
+                    ..Default::default()
+                };
+            }
+
+            let device = &devices[0];
+            emit_step(OnboardingStep::Pick, StepStatus::Success, format!("使用设备: {}", device.name), None);
+            device.address.clone()
+        }
+    };
+
+    // 4. 连接
+    emit_step(OnboardingStep::Connect, StepStatus::Running, format!("正在连接: {}", target), None);
+    let device = match manager.connect_to_device(&target).await {
+        Ok(device) => device,
+        Err(e) => return OnboardingResult::failed(OnboardingStep::Connect, e),
+    };
+    emit_step(OnboardingStep::Connect, StepStatus::Success, format!("已连接: {}", device.name), None);
+
+    // 5. 读取设备ID
+    emit_step(OnboardingStep::DeviceId, StepStatus::Running, "正在读取设备ID".to_string(), None);
+    let device_id = match manager.get_device_id().await {
+        Ok(id) => id,
+        Err(e) => return OnboardingResult::failed(OnboardingStep::DeviceId, e),
+    };
+    emit_step(OnboardingStep::DeviceId, StepStatus::Success, format!("设备ID: {}", device_id), None);
+
+    // 后端检查不需要蓝牙，先把锁放掉
+    drop(manager);
+
+    // 6. 检查后端
+    emit_step(OnboardingStep::Backend, StepStatus::Running, "正在检查服务器连接".to_string(), None);
+    let backend_url = match check_backend().await {
+        Ok(url) => url,
+        Err(e) => {
+            let mut result = OnboardingResult::failed(OnboardingStep::Backend, e.to_string());
+            // 笔已经配好了，前端可以只提示网络问题
+            result.device = Some(device);
+            result.device_id = Some(device_id);
+            return result;
+        }
+    };
+    emit_step(OnboardingStep::Backend, StepStatus::Success, format!("服务器可以访问: {}", backend_url), None);
+
+    OnboardingResult {
+        success: true,
+        device: Some(device),
+        device_id: Some(device_id),
+        backend_url: Some(backend_url),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,368 @@
+// 发件箱模式
+// 放进发件箱目录的文件自动上传到固定的云盘目录，传完后移到本地的已发送目录
+//
+// 思考：有些地方（前台、扫描仪旁边的电脑）用的人不会操作界面，只会把文件拖到一个文件夹里。
+// 打开发件箱模式后由常驻服务定时检查发件箱（没有引入文件系统监听库，和网络、电源检测一样轮询）：
+// 1. 只看发件箱第一层的普通文件，隐藏文件和子目录（包括默认的sent目录）不管
+// 2. 文件还在复制进来时大小和修改时间会变，连续两次检查都没变才上传
+// 3. 上传走普通的上传任务（排队、并发、自动重试、队列持久化都一样），
+//    重启后传输队列恢复出来的同一个文件的任务直接接着用，不重复上传
+// 4. 传完的文件移到已发送目录，重名时在文件名后面加时间；
+//    自动重试用完还失败的文件留在发件箱里，文件没变就不再重试，改动过或者重新打开发件箱模式后再传
+// 关闭发件箱模式只是不再检查新文件，已经在传的文件传完照样移走。
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::settings::{self, OutboxSettings};
+use crate::transfer_manager::{spawn_upload, upload_tasks};
+use crate::upload::UploadStatus;
+use crate::event_emitter;
+
+// 文件的大小和修改时间，用来判断文件有没有写完、有没有改过
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FileStamp {
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxFile {
+    pub path: String,
+    pub upload_id: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct OutboxState {
+    in_flight: HashMap<PathBuf, String>,             // 正在上传的文件 -> upload_id
+    failed: HashMap<PathBuf, (FileStamp, String)>,   // 失败的文件，文件没变就不再传
+    sent_count: u64,
+    last_error: Option<String>,
+}
+
+static STATE: OnceLock<Mutex<OutboxState>> = OnceLock::new();
+static WAKE: OnceLock<Notify> = OnceLock::new();
+
+fn state() -> &'static Mutex<OutboxState> {
+    STATE.get_or_init(|| Mutex::new(OutboxState::default()))
+}
+
+fn wake() -> &'static Notify {
+    WAKE.get_or_init(Notify::new)
+}
+
+fn sent_dir(outbox: &OutboxSettings, dir: &Path) -> PathBuf {
+    match &outbox.sent_dir {
+        Some(sent) => PathBuf::from(sent),
+        None => dir.join("sent"),
+    }
+}
+
+// 发件箱里要处理的文件：第一层的普通文件，跳过隐藏文件
+async fn list_files(dir: &Path) -> std::io::Result<HashMap<PathBuf, FileStamp>> {
+    let mut files = HashMap::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let metadata = match entry.metadata().await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => continue,
+        };
+        files.insert(entry.path(), FileStamp { size: metadata.len(), modified: metadata.modified().ok() });
+    }
+    Ok(files)
+}
+
+// 和上一次检查比，大小和修改时间都没变的文件才算写完了
+fn stable_files(previous: &HashMap<PathBuf, FileStamp>, current: &HashMap<PathBuf, FileStamp>) -> Vec<PathBuf> {
+    let mut ready: Vec<PathBuf> = current
+        .iter()
+        .filter(|(path, stamp)| previous.get(*path) == Some(*stamp))
+        .map(|(path, _)| path.clone())
+        .collect();
+    ready.sort();
+    ready
+}
+
+// 已发送目录里的目标路径，重名时在文件名后面加时间
+fn sent_target(sent_dir: &Path, file_name: &str, taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let target = sent_dir.join(file_name);
+    if !taken(&target) {
+        return target;
+    }
+    let name = Path::new(file_name);
+    let stem = name.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let suffix = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    match name.extension() {
+        Some(ext) => sent_dir.join(format!("{} ({}).{}", stem, suffix, ext.to_string_lossy())),
+        None => sent_dir.join(format!("{} ({})", stem, suffix)),
+    }
+}
+
+// 传完的文件移到已发送目录，跨磁盘时改成复制再删除
+async fn move_to_sent(path: &Path, sent_dir: &Path) -> std::io::Result<PathBuf> {
+    tokio::fs::create_dir_all(sent_dir).await?;
+    let file_name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let target = sent_target(sent_dir, &file_name, |p| p.exists());
+    if tokio::fs::rename(path, &target).await.is_err() {
+        tokio::fs::copy(path, &target).await?;
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(target)
+}
+
+// 检查正在上传的文件，传完的移走，自动重试用完还失败的记下来
+async fn check_in_flight(outbox: &OutboxSettings) {
+    let in_flight: Vec<(PathBuf, String)> = state().lock().unwrap().in_flight.iter().map(|(p, id)| (p.clone(), id.clone())).collect();
+    let retry = settings::get().retry;
+
+    for (path, upload_id) in in_flight {
+        let task = upload_tasks().lock().await.get(&upload_id).cloned();
+        let Some(task) = task else {
+            state().lock().unwrap().in_flight.remove(&path);
+            continue;
+        };
+        let error = match task.get_progress().await.status {
+            UploadStatus::Completed => None,
+            UploadStatus::FileInUse => Some("文件正在被其他程序使用".to_string()),
+            UploadStatus::Error(e) if !retry.auto_retry || task.retry_count() >= retry.max_attempts => Some(e),
+            _ => continue,
+        };
+        state().lock().unwrap().in_flight.remove(&path);
+
+        match error {
+            None => {
+                let dir = path.parent().unwrap_or(Path::new("."));
+                match move_to_sent(&path, &sent_dir(outbox, dir)).await {
+                    Ok(target) => {
+                        println!("[发件箱] 已上传并移到 {:?}", target);
+                        state().lock().unwrap().sent_count += 1;
+                        event_emitter::emit_event("outbox-file-sent", serde_json::json!({
+                            "path": path.to_string_lossy(),
+                            "sent_path": target.to_string_lossy(),
+                            "remote_path": outbox.remote_path,
+                        }));
+                    }
+                    Err(e) => {
+                        // 已经传上去了，移不走的话下次检查会再传一次，所以也当作失败记下
+                        record_failure(&path, format!("上传成功，但移到已发送目录失败: {}", e)).await;
+                    }
+                }
+            }
+            Some(e) => record_failure(&path, format!("上传失败: {}", e)).await,
+        }
+    }
+}
+
+async fn record_failure(path: &Path, error: String) {
+    println!("[发件箱] {:?} {}", path, error);
+    if let Ok(metadata) = tokio::fs::metadata(path).await {
+        let stamp = FileStamp { size: metadata.len(), modified: metadata.modified().ok() };
+        state().lock().unwrap().failed.insert(path.to_path_buf(), (stamp, error.clone()));
+    }
+    event_emitter::emit_event("outbox-file-failed", serde_json::json!({
+        "path": path.to_string_lossy(),
+        "error": error,
+    }));
+}
+
+// 上传写完了的新文件
+async fn upload_ready(outbox: &OutboxSettings, ready: Vec<PathBuf>, current: &HashMap<PathBuf, FileStamp>) {
+    let ready: Vec<PathBuf> = {
+        let mut state = state().lock().unwrap();
+        // 文件改过了就不再算失败
+        state.failed.retain(|path, (stamp, _)| current.get(path) == Some(stamp));
+        ready
+            .into_iter()
+            .filter(|path| !state.in_flight.contains_key(path) && !state.failed.contains_key(path))
+            .collect()
+    };
+    if ready.is_empty() {
+        return;
+    }
+
+    // 传输队列恢复出来的任务（还没传完的）接着用
+    let mut existing = HashMap::new();
+    let tasks: Vec<_> = upload_tasks().lock().await.iter().map(|(id, task)| (id.clone(), task.clone())).collect();
+    for (upload_id, task) in tasks {
+        if matches!(task.get_progress().await.status, UploadStatus::Pending | UploadStatus::Uploading | UploadStatus::Paused) {
+            existing.insert(task.file_path().to_path_buf(), upload_id);
+        }
+    }
+
+    let auth_info = match crate::acquire_auth_info().await {
+        Ok(auth_info) => auth_info,
+        Err(e) => {
+            println!("[发件箱] 获取认证信息失败，稍后再试: {}", e);
+            state().lock().unwrap().last_error = Some(e);
+            return;
+        }
+    };
+    let target = (!outbox.remote_path.is_empty()).then_some(outbox.remote_path.as_str());
+
+    let mut started = false;
+    for path in ready {
+        if let Some(upload_id) = existing.get(&path) {
+            state().lock().unwrap().in_flight.insert(path, upload_id.clone());
+            continue;
+        }
+        match crate::create_and_register_upload(&path.to_string_lossy(), &auth_info, target).await {
+            Ok((upload_id, task)) => {
+                println!("[发件箱] 开始上传 {:?}，upload_id: {}", path, upload_id);
+                spawn_upload(task);
+                started = true;
+                state().lock().unwrap().in_flight.insert(path, upload_id);
+            }
+            Err(e) => record_failure(&path, e).await,
+        }
+    }
+    if started {
+        state().lock().unwrap().last_error = None;
+    }
+}
+
+// 常驻服务：定时检查发件箱
+pub async fn run() {
+    let mut previous: HashMap<PathBuf, FileStamp> = HashMap::new();
+    loop {
+        let outbox = settings::get().outbox;
+        check_in_flight(&outbox).await;
+
+        match (&outbox.dir, outbox.enabled) {
+            (Some(dir), true) => match list_files(Path::new(dir)).await {
+                Ok(current) => {
+                    let ready = stable_files(&previous, &current);
+                    upload_ready(&outbox, ready, &current).await;
+                    previous = current;
+                }
+                Err(e) => {
+                    println!("[发件箱] 读取发件箱 {} 失败: {}", dir, e);
+                    state().lock().unwrap().last_error = Some(format!("读取发件箱失败: {}", e));
+                    previous.clear();
+                }
+            },
+            _ => previous.clear(),
+        }
+
+        let interval = Duration::from_secs(outbox.poll_interval_secs.max(1));
+        let _ = tokio::time::timeout(interval, wake().notified()).await;
+    }
+}
+
+/// 打开发件箱模式
+///
+/// dir是发件箱目录，remote_path是上传到的云盘目录（不传表示根目录），
+/// sent_dir是传完后移到的目录（不传表示发件箱下的sent目录）。目录不存在时会创建。
+/// 设置会保存，下次启动继续生效。之前失败的文件会重新上传。
+#[tauri::command]
+pub async fn enable_outbox(dir: String, remote_path: Option<String>, sent_dir: Option<String>) -> Result<serde_json::Value, String> {
+    println!("前端调用enable_outbox命令，发件箱: {}，云盘目录: {:?}", dir, remote_path);
+
+    tokio::fs::create_dir_all(&dir).await.map_err(|e| format!("创建发件箱目录失败: {}", e))?;
+    if let Some(sent) = &sent_dir {
+        tokio::fs::create_dir_all(sent).await.map_err(|e| format!("创建已发送目录失败: {}", e))?;
+    }
+
+    settings::update(serde_json::json!({
+        "outbox": {
+            "enabled": true,
+            "dir": dir,
+            "remote_path": remote_path.unwrap_or_default(),
+            "sent_dir": sent_dir,
+        }
+    }))
+        .await
+        .map_err(|e| format!("保存发件箱设置失败: {}", e))?;
+
+    state().lock().unwrap().failed.clear();
+    wake().notify_waiters();
+    Ok(status())
+}
+
+/// 关闭发件箱模式
+///
+/// 不再检查新文件，已经在传的文件传完后照样移到已发送目录
+#[tauri::command]
+pub async fn disable_outbox() -> Result<serde_json::Value, String> {
+    println!("前端调用disable_outbox命令...");
+
+    settings::update(serde_json::json!({ "outbox": { "enabled": false } }))
+        .await
+        .map_err(|e| format!("保存发件箱设置失败: {}", e))?;
+    Ok(status())
+}
+
+/// 获取发件箱状态
+///
+/// 返回值：{"enabled", "dir", "remote_path", "sent_dir", "uploading", "failed", "sent_count", "last_error"}
+#[tauri::command]
+pub async fn get_outbox_status() -> Result<serde_json::Value, String> {
+    Ok(status())
+}
+
+fn status() -> serde_json::Value {
+    let outbox = settings::get().outbox;
+    let sent = outbox.dir.as_ref().map(|dir| sent_dir(&outbox, Path::new(dir)).to_string_lossy().to_string());
+    let state = state().lock().unwrap();
+    let uploading: Vec<OutboxFile> = state.in_flight.iter().map(|(path, upload_id)| OutboxFile {
+        path: path.to_string_lossy().to_string(),
+        upload_id: Some(upload_id.clone()),
+        error: None,
+    }).collect();
+    let failed: Vec<OutboxFile> = state.failed.iter().map(|(path, (_, error))| OutboxFile {
+        path: path.to_string_lossy().to_string(),
+        upload_id: None,
+        error: Some(error.clone()),
+    }).collect();
+    serde_json::json!({
+        "enabled": outbox.enabled,
+        "dir": outbox.dir,
+        "remote_path": outbox.remote_path,
+        "sent_dir": sent,
+        "uploading": uploading,
+        "failed": failed,
+        "sent_count": state.sent_count,
+        "last_error": state.last_error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(size: u64) -> FileStamp {
+        FileStamp { size, modified: None }
+    }
+
+    #[test]
+    fn waits_until_file_stops_changing() {
+        let a = PathBuf::from("outbox/a.pdf");
+        let b = PathBuf::from("outbox/b.pdf");
+        let first = HashMap::from([(a.clone(), stamp(10))]);
+        assert!(stable_files(&HashMap::new(), &first).is_empty());
+
+        // a还在变大，b刚出现
+        let second = HashMap::from([(a.clone(), stamp(20)), (b.clone(), stamp(5))]);
+        assert!(stable_files(&first, &second).is_empty());
+
+        let third = HashMap::from([(a.clone(), stamp(20)), (b.clone(), stamp(5))]);
+        assert_eq!(stable_files(&second, &third), vec![a, b]);
+    }
+
+    #[test]
+    fn renames_when_sent_file_exists() {
+        let sent = Path::new("sent");
+        assert_eq!(sent_target(sent, "scan.pdf", |_| false), sent.join("scan.pdf"));
+
+        let renamed = sent_target(sent, "scan.pdf", |_| true);
+        let name = renamed.file_name().unwrap().to_string_lossy().to_string();
+        assert!(name.starts_with("scan (") && name.ends_with(").pdf"), "{}", name);
+    }
+}
@@ -0,0 +1,164 @@
+//! 笔的协议版本
+//!
+//! 连上笔以后发一次getProtoVersion，笔回复协议版本和支持的功能，
+//! 电量查询、固件升级这些新命令先查这里，笔不支持时直接返回UnsupportedFirmware错误，
+//! 不去发一个老固件不认识的命令然后等到接收超时。
+//!
+//! 思考：
+//! 1. 回复格式是"版本号"或"版本号;功能,功能"，比如"2;battery"。
+//!    只有版本号时按版本推算功能（见PenFeature::min_version），以后固件可以单独开关某个功能
+//! 2. 老固件不认识getProtoVersion，不回复或者回复看不懂的内容，都当作版本0：
+//!    只有setTime/getTotp/getId，和原来一样能用
+//! 3. 和device_state一样不依赖btleplug，关掉ble功能时类型也在
+
+use std::fmt;
+use serde::Serialize;
+
+use crate::command_deadline::CommandError;
+
+/// 需要协议支持的功能
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PenFeature {
+    Battery,     // 电量查询（getBattery）
+    Dfu,         // 固件升级
+    Challenge,   // 挑战应答：setTime带随机数，getTotp同时返回HMAC(随机数)
+}
+
+impl PenFeature {
+    const ALL: [PenFeature; 3] = [PenFeature::Battery, PenFeature::Dfu, PenFeature::Challenge];
+
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "battery" => Some(PenFeature::Battery),
+            "dfu" => Some(PenFeature::Dfu),
+            "challenge" => Some(PenFeature::Challenge),
+            _ => None,
+        }
+    }
+
+    // 回复里没有功能列表时，从哪个协议版本开始支持
+    fn min_version(self) -> u32 {
+        match self {
+            PenFeature::Battery => 2,
+            PenFeature::Dfu => 3,
+            PenFeature::Challenge => 3,
+        }
+    }
+}
+
+impl fmt::Display for PenFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PenFeature::Battery => write!(f, "电量查询"),
+            PenFeature::Dfu => write!(f, "固件升级"),
+            PenFeature::Challenge => write!(f, "挑战应答认证"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PenProtocol {
+    pub version: u32,
+    pub features: Vec<PenFeature>,
+}
+
+impl PenProtocol {
+    /// 不认识getProtoVersion的老固件
+    pub fn legacy() -> Self {
+        Self { version: 0, features: Vec::new() }
+    }
+
+    /// 解析getProtoVersion的回复，看不懂时返回None
+    pub fn parse(response: &str) -> Option<Self> {
+        let response = response.trim();
+        let (version, features) = match response.split_once(';') {
+            Some((version, features)) => (version, Some(features)),
+            None => (response, None),
+        };
+        let version = version.trim().trim_start_matches(['v', 'V']).parse::<u32>().ok()?;
+        let features = match features {
+            Some(list) => list.split(',').filter_map(PenFeature::parse).collect(),
+            None => PenFeature::ALL.into_iter().filter(|f| version >= f.min_version()).collect(),
+        };
+        Some(Self { version, features })
+    }
+
+    pub fn supports(&self, feature: PenFeature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    /// 不支持时返回给前端的错误
+    pub fn require(&self, feature: PenFeature) -> Result<(), CommandError> {
+        if self.supports(feature) {
+            return Ok(());
+        }
+        Err(CommandError::UnsupportedFirmware {
+            feature,
+            protocol_version: self.version,
+            message: format!("笔的固件不支持{}（协议版本{}），请先升级固件", feature, self.version),
+        })
+    }
+}
+
+/// 回复是不是一个TOTP（6到8位数字）
+/// 
+/// 通知通道里可能还有上一条命令晚到的回复（比如getProtoVersion等超时以后才回），
+/// 不能什么UTF-8都当成TOTP
+pub fn is_totp_code(code: &str) -> bool {
+    (6..=8).contains(&code.len()) && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// 拆开挑战应答模式下getTotp的回复："TOTP:HMAC"，返回(TOTP, HMAC)
+/// 没有冒号说明笔没按挑战应答回复，整个当作TOTP；冒号后面是空的也当作没有HMAC
+pub fn split_totp_response(response: &str) -> (String, Option<String>) {
+    match response.split_once(':') {
+        Some((totp, proof)) => {
+            let proof = proof.trim();
+            (totp.trim().to_string(), (!proof.is_empty()).then(|| proof.to_ascii_lowercase()))
+        }
+        None => (response.trim().to_string(), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_version_and_features() {
+        assert_eq!(
+            PenProtocol::parse("2;battery,dfu,unknown"),
+            Some(PenProtocol { version: 2, features: vec![PenFeature::Battery, PenFeature::Dfu] })
+        );
+        // 只有版本号时按版本推算
+        assert_eq!(PenProtocol::parse("v2").unwrap().features, vec![PenFeature::Battery]);
+        assert_eq!(PenProtocol::parse("3\n").unwrap().features, PenFeature::ALL.to_vec());
+        // 明确给了空列表就是都不支持
+        assert!(PenProtocol::parse("3;").unwrap().features.is_empty());
+        assert_eq!(PenProtocol::parse("unknown command"), None);
+    }
+
+    #[test]
+    fn splits_challenge_responses() {
+        assert_eq!(split_totp_response("123456:AB01ff"), ("123456".to_string(), Some("ab01ff".to_string())));
+        assert_eq!(split_totp_response("123456"), ("123456".to_string(), None));
+        assert_eq!(split_totp_response("123456:"), ("123456".to_string(), None));
+    }
+
+    #[test]
+    fn recognizes_totp_codes() {
+        assert!(is_totp_code("123456"));
+        assert!(is_totp_code("12345678"));
+        assert!(!is_totp_code("12345"));
+        assert!(!is_totp_code("2;battery"));
+        assert!(!is_totp_code("１２３４５６"));
+    }
+
+    #[test]
+    fn legacy_firmware_rejects_new_features() {
+        let err = PenProtocol::legacy().require(PenFeature::Battery).unwrap_err();
+        assert!(matches!(err, CommandError::UnsupportedFirmware { protocol_version: 0, .. }));
+        assert!(PenProtocol::parse("2").unwrap().require(PenFeature::Battery).is_ok());
+    }
+}
@@ -0,0 +1,246 @@
+// 电源状态
+// 系统睡眠/唤醒、省电模式时暂停传输和蓝牙检查，唤醒后丢掉旧的蓝牙连接重新连
+//
+// 思考：电脑合盖睡眠再打开以后，蓝牙连接其实已经断了，但btleplug的句柄和设备管理器里的状态
+// 还以为连着，TOTP缓存也是睡眠前的，接下来的get_totp和传输全都卡在这个"僵尸连接"上，只能重启程序。
+// 这里跟踪电源状态：
+// 1. 睡眠前（Windows的挂起通知）标记为挂起，传输在分片之间停下并把已写入的分片落盘，蓝牙监控也停下
+// 2. 唤醒后先让蓝牙句柄、笔的连接状态和认证缓存全部失效，等网络恢复一会儿，再让传输继续，
+//    下次用到笔时重新连接
+// 3. 省电模式时（设置里pause_on_battery_saver）也暂停传输和蓝牙监控，退出省电模式后继续
+// 不是所有平台都有睡眠前的通知，所以另外比较墙上时间和单调时钟：
+// 单调时钟在睡眠期间不走，两者差出一大截说明刚睡醒过，按唤醒处理。
+// 用户手动把系统时间往后调也会被当成唤醒，多重连一次蓝牙，没有别的影响。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::{auth, event_emitter, settings};
+
+// 定时检查的间隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// 墙上时间比单调时钟多走了这么久才算睡眠过
+const SLEEP_GAP_THRESHOLD: Duration = Duration::from_secs(20);
+// 唤醒后等网络恢复的时间
+const RESUME_SETTLE: Duration = Duration::from_secs(3);
+// 停下等待的任务多久检查一次自己有没有被暂停
+const PAUSE_RECHECK: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerState {
+    Active,
+    BatterySaver,
+    Suspended,
+}
+
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+static BATTERY_SAVER: AtomicBool = AtomicBool::new(false);
+// 正在处理唤醒，系统通知和时钟检测同时发现唤醒时只处理一次
+static RESUMING: AtomicBool = AtomicBool::new(false);
+static STATE_CHANGED: OnceLock<Notify> = OnceLock::new();
+
+fn state_changed() -> &'static Notify {
+    STATE_CHANGED.get_or_init(Notify::new)
+}
+
+pub fn current() -> PowerState {
+    if SUSPENDED.load(Ordering::SeqCst) {
+        PowerState::Suspended
+    } else if BATTERY_SAVER.load(Ordering::SeqCst) {
+        PowerState::BatterySaver
+    } else {
+        PowerState::Active
+    }
+}
+
+// 传输和蓝牙检查现在要不要停下
+pub fn should_pause() -> bool {
+    match current() {
+        PowerState::Active => false,
+        PowerState::Suspended => true,
+        PowerState::BatterySaver => settings::get().power.pause_on_battery_saver,
+    }
+}
+
+// 等电源状态变化，最多等PAUSE_RECHECK，调用方醒来后检查自己的状态
+pub async fn wait_for_change() {
+    let _ = tokio::time::timeout(PAUSE_RECHECK, state_changed().notified()).await;
+}
+
+// 一直等到不需要暂停，返回是否等待过
+pub async fn wait_until_active() -> bool {
+    let mut waited = false;
+    while should_pause() {
+        waited = true;
+        wait_for_change().await;
+    }
+    waited
+}
+
+// 系统马上要睡眠
+fn enter_suspend() {
+    if SUSPENDED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    println!("[电源] 系统即将睡眠，暂停传输和蓝牙检查");
+    event_emitter::emit_event("power-suspend", serde_json::json!({}));
+    state_changed().notify_waiters();
+}
+
+// 系统刚唤醒：旧的蓝牙连接和认证缓存都不能再用
+async fn resume(slept: Option<Duration>) {
+    if RESUMING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    SUSPENDED.store(true, Ordering::SeqCst);
+    println!("[电源] 系统已唤醒（睡眠了 {:?}），重置蓝牙连接", slept);
+
+    crate::cpen_device_manager::cancel_operations();
+    match crate::get_cpen_device_manager() {
+        Ok(manager) => manager.lock().await.invalidate_bluetooth().await,
+        Err(e) => println!("[电源] 获取设备管理器失败: {}", e),
+    }
+    auth::invalidate_credentials();
+
+    tokio::time::sleep(RESUME_SETTLE).await;
+    SUSPENDED.store(false, Ordering::SeqCst);
+    RESUMING.store(false, Ordering::SeqCst);
+    event_emitter::emit_event("power-resume", serde_json::json!({
+        "slept_secs": slept.map(|d| d.as_secs()),
+    }));
+    state_changed().notify_waiters();
+}
+
+// 墙上时间比单调时钟多走的部分超过阈值时，返回睡眠的时长
+fn sleep_gap(wall: Duration, monotonic: Duration) -> Option<Duration> {
+    let gap = wall.checked_sub(monotonic)?;
+    (gap >= SLEEP_GAP_THRESHOLD).then_some(gap)
+}
+
+// powerprofilesctl get的输出
+fn parse_power_profile(output: &str) -> bool {
+    output.trim() == "power-saver"
+}
+
+#[cfg(windows)]
+fn detect_battery_saver() -> bool {
+    use windows::Win32::System::Power::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+
+    let mut status = SYSTEM_POWER_STATUS::default();
+    // SystemStatusFlag为1表示省电模式已开启
+    unsafe { GetSystemPowerStatus(&mut status) }.is_ok() && status.SystemStatusFlag == 1
+}
+
+#[cfg(target_os = "linux")]
+fn detect_battery_saver() -> bool {
+    std::process::Command::new("powerprofilesctl")
+        .arg("get")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .is_some_and(|output| parse_power_profile(&String::from_utf8_lossy(&output.stdout)))
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn detect_battery_saver() -> bool {
+    false
+}
+
+// 注册Windows的睡眠/唤醒通知，回调在系统线程里执行，唤醒的处理交回tokio运行时
+#[cfg(windows)]
+fn register_suspend_notification() {
+    use std::ffi::c_void;
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::System::Power::{PowerRegisterSuspendResumeNotification, DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS};
+    use windows::Win32::UI::WindowsAndMessaging::{
+        DEVICE_NOTIFY_CALLBACK, PBT_APMRESUMEAUTOMATIC, PBT_APMRESUMESUSPEND, PBT_APMSUSPEND,
+    };
+
+    static RUNTIME: OnceLock<tokio::runtime::Handle> = OnceLock::new();
+    if RUNTIME.set(tokio::runtime::Handle::current()).is_err() {
+        return;
+    }
+
+    unsafe extern "system" fn callback(_context: *const c_void, kind: u32, _setting: *const c_void) -> u32 {
+        if kind == PBT_APMSUSPEND {
+            enter_suspend();
+        } else if kind == PBT_APMRESUMEAUTOMATIC || kind == PBT_APMRESUMESUSPEND {
+            if let Some(runtime) = RUNTIME.get() {
+                runtime.spawn(resume(None));
+            }
+        }
+        0
+    }
+
+    // 注册期间一直要用，直接泄漏掉
+    let params: &'static mut DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS = Box::leak(Box::new(DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+        Callback: Some(callback),
+        Context: std::ptr::null_mut(),
+    }));
+    let mut handle: *mut c_void = std::ptr::null_mut();
+    let result = unsafe {
+        PowerRegisterSuspendResumeNotification(
+            DEVICE_NOTIFY_CALLBACK,
+            HANDLE(params as *mut DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS as *mut c_void),
+            &mut handle,
+        )
+    };
+    if result.is_err() {
+        println!("[电源] 注册睡眠通知失败: {:?}，改用时钟检测", result);
+    }
+}
+
+#[cfg(not(windows))]
+fn register_suspend_notification() {}
+
+// 常驻服务：注册睡眠通知，定时检查省电模式和时钟跳变
+pub async fn watch_loop() {
+    register_suspend_notification();
+
+    let mut last_wall = SystemTime::now();
+    let mut last_monotonic = Instant::now();
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+
+        let wall = SystemTime::now();
+        let monotonic = Instant::now();
+        let wall_elapsed = wall.duration_since(last_wall).unwrap_or_default();
+        if let Some(slept) = sleep_gap(wall_elapsed, monotonic - last_monotonic) {
+            resume(Some(slept)).await;
+        }
+        last_wall = wall;
+        last_monotonic = monotonic;
+
+        let battery_saver = tokio::task::spawn_blocking(detect_battery_saver).await.unwrap_or(false);
+        if BATTERY_SAVER.swap(battery_saver, Ordering::SeqCst) != battery_saver {
+            println!("[电源] 省电模式{}", if battery_saver { "已开启" } else { "已关闭" });
+            event_emitter::emit_event("power-state-changed", serde_json::json!({ "state": current() }));
+            state_changed().notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_sleep_from_clock_gap() {
+        let secs = Duration::from_secs;
+        assert_eq!(sleep_gap(secs(5), secs(5)), None);
+        assert_eq!(sleep_gap(secs(6), secs(5)), None);
+        assert_eq!(sleep_gap(secs(3605), secs(5)), Some(secs(3600)));
+        // 时间往回调不算
+        assert_eq!(sleep_gap(secs(0), secs(5)), None);
+    }
+
+    #[test]
+    fn parses_power_profile() {
+        assert!(parse_power_profile("power-saver\n"));
+        assert!(!parse_power_profile("balanced\n"));
+    }
+}
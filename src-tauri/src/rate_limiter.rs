@@ -0,0 +1,96 @@
+// 共享限速器（令牌桶）
+// 计费网络限速、后台模式限速、方向调度的链路限速都用这个：传完一个分片后调用reserve，
+// 按速度上限算出这些字节要占多长时间，排在上一次的发送时间后面，返回这个分片可以发出的时间
+//
+// 思考：
+// 1. 原来network_profile和background_mode各写了一份令牌桶，上传下载排在同一个桶里，
+//    下载分片又大又快时桶里的时间几乎都被下载占了，上传每个分片都要排很久
+// 2. 打开方向调度（transfer.direction_schedule.enabled）时每个方向一个桶，两个方向都在传时
+//    按transfer_manager::direction_share分限速（比如70/30），只有一个方向在传时这个方向用全部限速；
+//    没打开时两个方向排在同一个桶里，上传下载加起来不超过限速，不然两个方向各用满限速就成了两倍
+// 3. 状态变了（比如回到前台）时reset，之前排到很后面的发送时间作废
+// 4. 几个限速器同时生效时不能一个等完再等下一个（等的时间会加起来），
+//    由transfer_manager::throttle在每个限速器上都reserve，然后等到其中最晚的那个时间
+
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::settings;
+use crate::transfer_manager;
+use crate::transfer_queue::Direction;
+
+#[derive(Default)]
+pub struct RateLimiter {
+    // 下一次可以发送的时间：方向调度打开时每个方向一个，没打开时两个方向共用shared_next（见思考2）
+    download_next: Mutex<Option<Instant>>,
+    upload_next: Mutex<Option<Instant>>,
+    shared_next: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub const fn new() -> Self {
+        Self { download_next: Mutex::new(None), upload_next: Mutex::new(None), shared_next: Mutex::new(None) }
+    }
+
+    fn next_send_at(&self, direction: Direction, split: bool) -> &Mutex<Option<Instant>> {
+        match (split, direction) {
+            (false, _) => &self.shared_next,
+            (true, Direction::Download) => &self.download_next,
+            (true, Direction::Upload) => &self.upload_next,
+        }
+    }
+
+    // 记下传完的bytes字节，返回按cap_kbps（方向调度打开时是这个方向分到的部分）这些字节可以发出的时间，
+    // 调用方等到这个时间再继续；cap_kbps为0表示不限速，返回None
+    pub fn reserve(&self, direction: Direction, bytes: u64, cap_kbps: u64) -> Option<Instant> {
+        if cap_kbps == 0 {
+            return None;
+        }
+
+        let split = settings::get().transfer.direction_schedule.enabled;
+        let share = if split { transfer_manager::direction_share(direction) } else { 1.0 };
+        let cost = cost(bytes, cap_kbps, share);
+        let mut next = self.next_send_at(direction, split).lock().unwrap();
+        let now = Instant::now();
+        let send_at = next.map_or(now, |at| at.max(now)) + cost;
+        *next = Some(send_at);
+        Some(send_at)
+    }
+
+    pub fn reset(&self) {
+        *self.download_next.lock().unwrap() = None;
+        *self.upload_next.lock().unwrap() = None;
+        *self.shared_next.lock().unwrap() = None;
+    }
+}
+
+// 按分到的限速（cap_kbps * share）传bytes字节要多久
+fn cost(bytes: u64, cap_kbps: u64, share: f64) -> Duration {
+    Duration::from_secs_f64(bytes as f64 / (cap_kbps as f64 * 1024.0 * share))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_cap_by_share() {
+        let secs = |bytes, share| cost(bytes, 100, share).as_secs_f64();
+        // 100KB/s的限速，独占时100KB要1秒；70/30分时两边每秒分别只能传70KB和30KB
+        assert!((secs(100 * 1024, 1.0) - 1.0).abs() < 1e-6);
+        assert!((secs(70 * 1024, 0.7) - 1.0).abs() < 1e-6);
+        assert!((secs(30 * 1024, 0.3) - 1.0).abs() < 1e-6);
+        assert!((secs(100 * 1024, 0.3) - 1.0 / 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn shares_one_bucket_without_schedule() {
+        // 默认没打开方向调度，下载排过的时间上传也要排
+        let limiter = RateLimiter::new();
+        let download = limiter.reserve(Direction::Download, 100 * 1024, 100).unwrap();
+        let upload = limiter.reserve(Direction::Upload, 100 * 1024, 100).unwrap();
+        assert!(upload >= download + Duration::from_secs(1));
+        assert_eq!(limiter.reserve(Direction::Upload, 1024, 0), None);
+    }
+}
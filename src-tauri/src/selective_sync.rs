@@ -0,0 +1,207 @@
+// 选择性同步
+// 用户选择要同步到本地的云盘文件夹（和常见网盘客户端的"选择性同步"一样），没选的文件夹不下载
+//
+// 思考：
+// 1. 选择存在设置的sync.selected_folders里，空列表表示全部同步（和原来一样）
+// 2. 选中一个文件夹就包含它下面的所有子文件夹；选中文件夹的上级目录也要进去遍历，但只走通往选中文件夹的那条路
+// 3. 拉取时（download_folder，见plan_pull）按选择过滤：要拉的文件夹在某个选中的文件夹里面时全部下载，
+//    是选中文件夹的上级时只下载选中的部分；和选择完全无关的文件夹是用户明确点了下载，不受选择限制；
+//    上次同步过、云端没变、本地文件还在的不再下载。本地根目录不存在或不能写时直接失败（见destination模块）
+// 4. get_remote_tree给前端的文件夹选择器用，只列目录，每个目录带选中状态（选中/部分选中/未选中），
+//    前端按这个画三态的勾选框
+
+use std::path::Path;
+use anyhow::{Context, Result};
+use futures::future::{BoxFuture, FutureExt};
+use serde::Serialize;
+
+use crate::auth::AuthInfo;
+use crate::cloud_api::{self, RemoteEntry};
+use crate::{settings, sync_state};
+
+// get_remote_tree最多展开几层，再深的目录太多，一次列不完
+pub const MAX_TREE_DEPTH: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionState {
+    Selected,     // 整个文件夹都同步
+    Partial,      // 只同步里面的部分子文件夹
+    Unselected,   // 不同步
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RemoteTreeNode {
+    pub name: String,
+    pub path: String,
+    pub state: SelectionState,
+    pub children: Option<Vec<RemoteTreeNode>>,   // None表示超过depth没有展开
+}
+
+// 统一成没有首尾/的形式，根目录是空字符串
+pub fn normalize(path: &str) -> String {
+    path.split('/').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("/")
+}
+
+// path是不是folder本身或者在folder里面
+fn is_within(path: &str, folder: &str) -> bool {
+    folder.is_empty()
+        || path == folder
+        || path.strip_prefix(folder).is_some_and(|rest| rest.starts_with('/'))
+}
+
+// 整理用户选的文件夹：去掉重复的、已经被上级包含的，排好序
+pub fn normalize_selection(paths: &[String]) -> Vec<String> {
+    let mut folders: Vec<String> = paths.iter().map(|p| normalize(p)).collect();
+    folders.sort();
+    folders.dedup();
+    let mut result: Vec<String> = Vec::new();
+    for folder in folders {
+        if !result.iter().any(|kept| is_within(&folder, kept)) {
+            result.push(folder);
+        }
+    }
+    result
+}
+
+pub struct Selection {
+    folders: Vec<String>,   // 空表示全部同步
+}
+
+impl Selection {
+    pub fn new(folders: &[String]) -> Self {
+        Self { folders: normalize_selection(folders) }
+    }
+
+    // 设置里当前的选择
+    pub fn current() -> Self {
+        Self::new(&settings::get().sync.selected_folders)
+    }
+
+    pub fn state(&self, path: &str) -> SelectionState {
+        let path = normalize(path);
+        if self.folders.is_empty() || self.folders.iter().any(|folder| is_within(&path, folder)) {
+            SelectionState::Selected
+        } else if self.folders.iter().any(|folder| is_within(folder, &path)) {
+            SelectionState::Partial
+        } else {
+            SelectionState::Unselected
+        }
+    }
+}
+
+// 一次拉取的计划
+pub struct PullPlan {
+    pub files: Vec<RemoteEntry>,   // 要下载的文件
+    pub up_to_date: Vec<String>,   // 和上次同步时一样、本地也还在的文件，不用再下载
+}
+
+// 拉取root时要下载哪些文件，按选择性同步的设置过滤（见思考3），
+// 目录列表和文件有没有变化查同步状态库（见sync_state模块）
+pub async fn plan_pull(auth_info: &AuthInfo, root: &str, local_root: &Path) -> Result<PullPlan> {
+    crate::destination::ensure_sync_root(local_root).await?;
+    let selection = Selection::current();
+    let filtered = selection.state(root) == SelectionState::Partial;
+
+    let mut plan = PullPlan { files: Vec::new(), up_to_date: Vec::new() };
+    let mut dirs = vec![normalize(root)];
+    while let Some(dir) = dirs.pop() {
+        let entries = sync_state::list_dir(auth_info, &dir)
+            .await
+            .with_context(|| format!("列出文件夹 {} 失败", dir))?;
+        for (entry, record) in entries {
+            let state = selection.state(&entry.path);
+            if entry.is_dir {
+                if !filtered || state != SelectionState::Unselected {
+                    dirs.push(normalize(&entry.path));
+                }
+            } else if !filtered || state == SelectionState::Selected {
+                if record.last_synced.is_some() && local_root.join(&entry.path).exists() {
+                    plan.up_to_date.push(entry.path);
+                } else {
+                    plan.files.push(entry);
+                }
+            }
+        }
+    }
+    Ok(plan)
+}
+
+// 列出path下的目录树，展开depth层
+pub async fn remote_tree(auth_info: &AuthInfo, path: &str, depth: u32) -> Result<RemoteTreeNode> {
+    let selection = Selection::current();
+    let path = normalize(path);
+    let name = path.rsplit('/').next().unwrap_or_default().to_string();
+    let children = list_children(auth_info, &selection, path.clone(), depth.clamp(1, MAX_TREE_DEPTH)).await?;
+    Ok(RemoteTreeNode { name, state: selection.state(&path), path, children: Some(children) })
+}
+
+fn list_children<'a>(
+    auth_info: &'a AuthInfo,
+    selection: &'a Selection,
+    path: String,
+    depth: u32,
+) -> BoxFuture<'a, Result<Vec<RemoteTreeNode>>> {
+    async move {
+        let entries = cloud_api::list_dir(auth_info, &path)
+            .await
+            .with_context(|| format!("列出文件夹 {} 失败", path))?;
+        let mut nodes = Vec::new();
+        for entry in entries.into_iter().filter(|e| e.is_dir) {
+            let child_path = normalize(&entry.path);
+            let children = if depth > 1 {
+                Some(list_children(auth_info, selection, child_path.clone(), depth - 1).await?)
+            } else {
+                None
+            };
+            nodes.push(RemoteTreeNode {
+                name: entry.name,
+                state: selection.state(&child_path),
+                path: child_path,
+                children,
+            });
+        }
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(nodes)
+    }
+    .boxed()
+}
+
+// 保存新的选择，返回整理后的列表
+pub async fn set_selected(paths: &[String]) -> Result<Vec<String>> {
+    let folders = normalize_selection(paths);
+    settings::update(serde_json::json!({ "sync": { "selected_folders": folders } })).await?;
+    Ok(folders)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn drops_nested_and_duplicate_folders() {
+        assert_eq!(
+            normalize_selection(&strings(&["/photos/2024/", "photos", "docs/work", "docs/work/", "docs/workshop"])),
+            strings(&["docs/work", "docs/workshop", "photos"])
+        );
+    }
+
+    #[test]
+    fn reports_tri_state_selection() {
+        let selection = Selection::new(&strings(&["photos/2024", "docs"]));
+        assert_eq!(selection.state(""), SelectionState::Partial);
+        assert_eq!(selection.state("photos"), SelectionState::Partial);
+        assert_eq!(selection.state("photos/2024/trip"), SelectionState::Selected);
+        assert_eq!(selection.state("/docs/"), SelectionState::Selected);
+        assert_eq!(selection.state("photos/2023"), SelectionState::Unselected);
+        // 名字前缀相同的兄弟目录不算
+        assert_eq!(selection.state("photos/20245"), SelectionState::Unselected);
+
+        // 没有选择时全部同步
+        assert_eq!(Selection::new(&[]).state("anything"), SelectionState::Selected);
+    }
+}
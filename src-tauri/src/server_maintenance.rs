@@ -0,0 +1,134 @@
+// 后端维护中（503 + Retry-After）
+// 后端维护时会返回503并在Retry-After里告诉我们多久以后再来
+//
+// 思考：原来分片请求失败后隔1秒重试，3次机会3秒就用完，维护几分钟任务就全失败了，
+// 维护结束后还得用户一个个点重试。现在：
+// 1. transfer_http::check_response看到503带Retry-After，就记下全局的恢复时间，
+//    发server-maintenance事件（前端显示"服务器维护中，预计xx恢复"）
+// 2. 分片请求失败时如果后端在维护，任务进入WaitingForServer状态（带恢复时间），
+//    等到恢复时间再请求同一个分片，这次失败不算重试次数
+// 3. 恢复时间一到发server-maintenance-ended事件；维护还没结束的话后端会再返回503，重新开始等
+// Retry-After可能是秒数也可能是HTTP日期，太长的按MAX_WAIT算，避免一个错误的值让任务等上一天；
+// 只有503不带Retry-After的还是按普通失败处理。
+
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::event_emitter;
+
+// 单次最多等多久
+const MAX_WAIT: Duration = Duration::from_secs(3600);
+// 最少等多久（Retry-After: 0时也别马上打过去）
+const MIN_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    until: Instant,
+    resume_at: i64,   // Unix时间戳（秒），给前端显示
+}
+
+static WINDOW: OnceLock<Mutex<Option<Window>>> = OnceLock::new();
+
+fn window() -> std::sync::MutexGuard<'static, Option<Window>> {
+    WINDOW.get_or_init(|| Mutex::new(None)).lock().unwrap()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceStatus {
+    pub active: bool,
+    pub resume_at: Option<i64>,
+}
+
+// 解析Retry-After：秒数或者HTTP日期，now是当前Unix时间戳
+pub fn parse_retry_after(value: &str, now: i64) -> Option<Duration> {
+    let value = value.trim();
+    let wait = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let at = crate::transfer_http::parse_http_date(value)?;
+            Duration::from_secs(at.saturating_sub(now).max(0) as u64)
+        }
+    };
+    Some(wait.clamp(MIN_WAIT, MAX_WAIT))
+}
+
+// 后端说retry_after以后再来，恢复时间只往后延不提前
+pub fn note_unavailable(retry_after: Duration) {
+    let until = Instant::now() + retry_after;
+    let resume_at = chrono::Utc::now().timestamp() + retry_after.as_secs() as i64;
+    let started = {
+        let mut window = window();
+        let started = !window.is_some_and(|w| w.until > Instant::now());
+        match window.as_ref() {
+            Some(w) if w.until >= until => return,
+            _ => *window = Some(Window { until, resume_at }),
+        }
+        started
+    };
+
+    println!("后端维护中，{} 秒后恢复", retry_after.as_secs());
+    event_emitter::emit_event("server-maintenance", serde_json::json!({
+        "resume_at": resume_at,
+        "retry_after_secs": retry_after.as_secs(),
+    }));
+    if started {
+        tauri::async_runtime::spawn(announce_end());
+    }
+}
+
+// 等到恢复时间（中途被延后就继续等），然后发结束事件
+async fn announce_end() {
+    loop {
+        let until = match *window() {
+            Some(w) => w.until,
+            None => return,
+        };
+        tokio::time::sleep_until(until.into()).await;
+        let mut window = window();
+        if window.is_some_and(|w| w.until <= Instant::now()) {
+            *window = None;
+            break;
+        }
+    }
+    println!("后端维护预计已结束，继续传输");
+    event_emitter::emit_event("server-maintenance-ended", serde_json::json!({}));
+}
+
+// 后端在维护时返回预计恢复的Unix时间戳
+pub fn resume_at() -> Option<i64> {
+    window().filter(|w| w.until > Instant::now()).map(|w| w.resume_at)
+}
+
+pub fn status() -> MaintenanceStatus {
+    let resume_at = resume_at();
+    MaintenanceStatus { active: resume_at.is_some(), resume_at }
+}
+
+// 等到恢复时间
+pub async fn wait() {
+    loop {
+        let until = match *window() {
+            Some(w) if w.until > Instant::now() => w.until,
+            _ => return,
+        };
+        tokio::time::sleep_until(until.into()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_retry_after_values() {
+        let now = 784111777; // Sun, 06 Nov 1994 08:49:37 GMT
+        assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("0", now), Some(MIN_WAIT));
+        assert_eq!(parse_retry_after("999999", now), Some(MAX_WAIT));
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:51:37 GMT", now), Some(Duration::from_secs(120)));
+        // 已经过去的时间也至少等一会儿
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now), Some(MIN_WAIT));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
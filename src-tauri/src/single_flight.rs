@@ -0,0 +1,94 @@
+// 并发请求合并（single-flight）
+// 同一时间只跑一次，后来的调用者等同一个结果
+//
+// 思考：前端好几个组件同时调用get_totp时，缓存是冷的，
+// 每个调用都会排队去和笔交互一次。这里让第一个调用者真正执行，
+// 执行期间进来的调用者直接等它的结果，执行完以后下一次调用重新执行。
+// 结果要被多个调用者共享，所以必须是Clone的（错误也要能Clone）。
+
+use std::future::Future;
+use std::sync::Mutex;
+use futures::future::{BoxFuture, FutureExt, Shared};
+
+pub struct SingleFlight<T> {
+    inflight: Mutex<Option<Shared<BoxFuture<'static, T>>>>,
+}
+
+impl<T: Clone + Send + Sync + 'static> SingleFlight<T> {
+    pub const fn new() -> Self {
+        Self {
+            inflight: Mutex::new(None),
+        }
+    }
+
+    // 有正在执行的请求就等它，没有就用make创建一个并执行
+    pub async fn run<F, Fut>(&self, make: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T> + Send + 'static,
+    {
+        let (future, joined) = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.as_ref() {
+                Some(future) => (future.clone(), true),
+                None => {
+                    let future = make().boxed().shared();
+                    *inflight = Some(future.clone());
+                    (future, false)
+                }
+            }
+        };
+        if joined {
+            println!("[合并请求] 已有相同请求在执行，等待它的结果");
+        }
+
+        let result = future.clone().await;
+
+        // 执行完了，清掉记录，下一次调用重新执行
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.as_ref().is_some_and(|current| current.ptr_eq(&future)) {
+            *inflight = None;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn concurrent_calls_share_one_execution() {
+        static FLIGHT: SingleFlight<Result<u32, String>> = SingleFlight::new();
+        let calls = Arc::new(AtomicU32::new(0));
+
+        let tasks: Vec<_> = (0..5)
+            .map(|_| {
+                let calls = calls.clone();
+                tokio::spawn(async move {
+                    FLIGHT
+                        .run(|| async move {
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            Ok(calls.fetch_add(1, Ordering::SeqCst) + 1)
+                        })
+                        .await
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), Ok(1));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn next_call_after_completion_runs_again() {
+        let flight: SingleFlight<u32> = SingleFlight::new();
+        assert_eq!(flight.run(|| async { 1 }).await, 1);
+        assert_eq!(flight.run(|| async { 2 }).await, 2);
+    }
+}
@@ -0,0 +1,101 @@
+// 传输速度采样模块
+// 按秒记录每个任务传输的字节数，保存在环形缓冲区里
+//
+// 思考：原来进度里的speed_kbps一直是0，前端也画不出速度曲线。
+// 这里每个任务持有一个SpeedSampler，分片完成时调用record记录字节数，
+// 按秒聚合，最多保留最近SPEED_HISTORY_SECONDS秒，前端可以直接拿去画图。
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+
+// 最多保留最近2分钟的采样
+const SPEED_HISTORY_SECONDS: usize = 120;
+// 计算当前速度时取最近几秒的平均值，避免分片粒度导致数值跳来跳去
+const CURRENT_SPEED_WINDOW_SECONDS: usize = 3;
+
+// 单个采样点
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpeedSample {
+    pub timestamp: i64,    // 秒级时间戳
+    pub bytes: u64,        // 这一秒内传输的字节数
+    pub speed_kbps: f64,   // 这一秒的速度 KB/s
+}
+
+// 速度采样器，每个传输任务一个
+pub struct SpeedSampler {
+    // (秒级时间戳, 这一秒传输的字节数)
+    buckets: Mutex<VecDeque<(i64, u64)>>,
+}
+
+impl SpeedSampler {
+    pub fn new() -> Self {
+        Self {
+            buckets: Mutex::new(VecDeque::with_capacity(SPEED_HISTORY_SECONDS)),
+        }
+    }
+
+    // 记录一次传输的字节数（分片完成时调用）
+    pub fn record(&self, bytes: u64) {
+        let now = chrono::Utc::now().timestamp();
+        let mut buckets = self.buckets.lock().unwrap();
+        Self::advance_to(&mut buckets, now);
+        if let Some(last) = buckets.back_mut() {
+            last.1 += bytes;
+        }
+    }
+
+    // 获取速度历史（只包含已经结束的完整秒）
+    pub fn history(&self) -> Vec<SpeedSample> {
+        let now = chrono::Utc::now().timestamp();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.is_empty() {
+            return Vec::new();
+        }
+        // 补齐到当前秒，中间没有数据的秒记为0
+        Self::advance_to(&mut buckets, now);
+
+        buckets
+            .iter()
+            .filter(|(timestamp, _)| *timestamp < now)
+            .map(|&(timestamp, bytes)| SpeedSample {
+                timestamp,
+                bytes,
+                speed_kbps: bytes as f64 / 1024.0,
+            })
+            .collect()
+    }
+
+    // 当前速度 KB/s（最近几秒的平均值）
+    pub fn current_speed_kbps(&self) -> f64 {
+        let history = self.history();
+        if history.is_empty() {
+            return 0.0;
+        }
+        let window: Vec<&SpeedSample> = history
+            .iter()
+            .rev()
+            .take(CURRENT_SPEED_WINDOW_SECONDS)
+            .collect();
+        let total: u64 = window.iter().map(|s| s.bytes).sum();
+        total as f64 / 1024.0 / window.len() as f64
+    }
+
+    // 把缓冲区推进到指定的秒，空缺的秒补0，超出容量的丢弃最旧的
+    fn advance_to(buckets: &mut VecDeque<(i64, u64)>, now: i64) {
+        let next = match buckets.back() {
+            Some(&(last, _)) if last >= now => return,
+            // 间隔太久的话只需要补最近SPEED_HISTORY_SECONDS秒
+            Some(&(last, _)) => std::cmp::max(last + 1, now - SPEED_HISTORY_SECONDS as i64 + 1),
+            None => now,
+        };
+
+        for timestamp in next..=now {
+            buckets.push_back((timestamp, 0));
+        }
+
+        while buckets.len() > SPEED_HISTORY_SECONDS {
+            buckets.pop_front();
+        }
+    }
+}
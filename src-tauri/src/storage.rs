@@ -81,6 +81,11 @@ pub async fn save_app_data(key: String, value: String) -> Result<(), String> {
 }
 
 pub fn get_app_data_dir() -> Result<PathBuf, String> {
+    // 测试时不要写到用户真实的数据目录里
+    if cfg!(test) {
+        return Ok(std::env::temp_dir().join("CAMFC-test"));
+    }
+    
     let data_dir = dirs::data_dir()
         .ok_or_else(|| "获取应用数据目录失败".to_string())?
         .join("CAMFC");
@@ -0,0 +1,134 @@
+// 磁盘占用统计
+// get_storage_usage_breakdown按类别统计这个应用在磁盘上占了多少空间，设置页里显示，用户看了再决定清哪一块
+//
+// 思考：
+// 1. 临时目录（settings::scratch_dir）下按子目录分类：分片缓存、上传快照、WebDAV写入中的文件，
+//    其他认不出来的算"其他临时文件"
+// 2. 应用数据目录（设置、任务队列、传输历史、目录缓存等）整个算一类；
+//    用户把临时目录设在数据目录里面时跳过它，不重复计算
+// 3. 下载目录里没下载完的文件借用cleanup的扫描，正在下载的也算上
+// 下载完成的文件是用户自己的数据，不算在里面。
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::Result;
+use serde::Serialize;
+use tokio::fs;
+
+use crate::cleanup::{self, OrphanKind};
+use crate::{chunk_cache, file_lock, settings, webdav};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageKind {
+    ChunkCache,         // 分片磁盘缓存
+    Snapshots,          // 上传用的文件快照
+    WebdavStaging,      // WebDAV写入中的文件
+    OtherScratch,       // 临时目录里的其他文件
+    AppData,            // 应用数据目录
+    PartialDownloads,   // 没下载完的文件（连同元数据）
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageItem {
+    pub kind: UsageKind,
+    pub path: String,
+    pub bytes: u64,
+    pub files: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageBreakdown {
+    pub scratch_dir: String,
+    pub items: Vec<UsageItem>,
+    pub total_bytes: u64,
+    pub cache_limit_bytes: u64,   // storage.max_cache_mb
+}
+
+// 统计目录下所有文件的大小和个数，不跟随符号链接，skip里的目录不进去
+async fn dir_usage(dir: &Path, skip: &[PathBuf]) -> (u64, u64) {
+    let mut bytes = 0;
+    let mut files = 0;
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let Ok(mut entries) = fs::read_dir(&current).await else { continue };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let Ok(file_type) = entry.file_type().await else { continue };
+            let path = entry.path();
+            if file_type.is_dir() {
+                if !skip.contains(&path) {
+                    pending.push(path);
+                }
+            } else if file_type.is_file() {
+                bytes += entry.metadata().await.map(|m| m.len()).unwrap_or(0);
+                files += 1;
+            }
+        }
+    }
+    (bytes, files)
+}
+
+fn item(kind: UsageKind, path: &Path, (bytes, files): (u64, u64)) -> UsageItem {
+    UsageItem { kind, path: path.to_string_lossy().to_string(), bytes, files }
+}
+
+// 按类别统计临时目录和数据目录
+pub async fn breakdown(scratch_dir: &Path, data_dir: &Path, download_dir: Option<&Path>) -> Result<StorageBreakdown> {
+    let known = [
+        (UsageKind::ChunkCache, scratch_dir.join(chunk_cache::CACHE_DIR_NAME)),
+        (UsageKind::Snapshots, scratch_dir.join(file_lock::SNAPSHOT_DIR_NAME)),
+        (UsageKind::WebdavStaging, scratch_dir.join(webdav::SCRATCH_DIR_NAME)),
+    ];
+    let known_dirs: Vec<PathBuf> = known.iter().map(|(_, dir)| dir.clone()).collect();
+
+    let mut items = Vec::new();
+    for (kind, dir) in &known {
+        items.push(item(*kind, dir, dir_usage(dir, &[]).await));
+    }
+    items.push(item(UsageKind::OtherScratch, scratch_dir, dir_usage(scratch_dir, &known_dirs).await));
+    let skip = if scratch_dir.starts_with(data_dir) { vec![scratch_dir.to_path_buf()] } else { Vec::new() };
+    items.push(item(UsageKind::AppData, data_dir, dir_usage(data_dir, &skip).await));
+
+    if let Some(download_dir) = download_dir {
+        // 不传正在下载的路径，正在下载的也算没下载完
+        let report = cleanup::cleanup(download_dir, data_dir, Duration::ZERO, &HashSet::new(), false).await?;
+        let partial: Vec<_> = report.files.iter().filter(|f| f.kind == OrphanKind::PartialDownload).collect();
+        let bytes = partial.iter().map(|f| f.size).sum();
+        items.push(item(UsageKind::PartialDownloads, download_dir, (bytes, partial.len() as u64)));
+    }
+
+    Ok(StorageBreakdown {
+        scratch_dir: scratch_dir.to_string_lossy().to_string(),
+        total_bytes: items.iter().map(|i| i.bytes).sum(),
+        items,
+        cache_limit_bytes: settings::get().storage.max_cache_mb.saturating_mul(1024 * 1024),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn groups_usage_by_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        let scratch_dir = data_dir.join("scratch");
+        let cache_dir = scratch_dir.join(chunk_cache::CACHE_DIR_NAME);
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        std::fs::write(cache_dir.join("a.chunk"), [0u8; 10]).unwrap();
+        std::fs::write(cache_dir.join("b.chunk"), [0u8; 5]).unwrap();
+        std::fs::write(scratch_dir.join("stray.tmp"), [0u8; 3]).unwrap();
+        std::fs::write(data_dir.join("settings.json"), [0u8; 7]).unwrap();
+
+        let report = breakdown(&scratch_dir, &data_dir, None).await.unwrap();
+        let bytes_of = |kind| report.items.iter().find(|i| i.kind == kind).map(|i| (i.bytes, i.files));
+        assert_eq!(bytes_of(UsageKind::ChunkCache), Some((15, 2)));
+        assert_eq!(bytes_of(UsageKind::Snapshots), Some((0, 0)));
+        assert_eq!(bytes_of(UsageKind::OtherScratch), Some((3, 1)));
+        // 临时目录在数据目录里时不重复计算
+        assert_eq!(bytes_of(UsageKind::AppData), Some((7, 1)));
+        assert_eq!(report.total_bytes, 25);
+    }
+}
@@ -0,0 +1,250 @@
+// 后台任务监督
+// 后台任务都从这里spawn，记下JoinHandle，任务panic时上报，退出时等任务收尾
+//
+// 思考：原来spawn完就把JoinHandle丢了，任务panic了只剩一行panic输出，没人知道这个任务已经没了，
+// 退出时也没办法等正在写盘的任务收尾。现在分两种任务：
+// 1. 一次性任务（传输、目录加载、本地服务）：panic时记日志并发background-task-failed事件，不重启
+// 2. 常驻服务（蓝牙监控、运行指标）：panic后按退避时间重启，正常返回就不再重启
+// 退出时先结束常驻服务，再等一次性任务结束（有总超时），超时的直接abort。
+// 注意release版本是panic=abort，panic会直接退出进程，由崩溃报告记录；
+// 这里的捕获和重启在开发版本里起作用，退出时的等待两种版本都有用。
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use futures::FutureExt;
+use tokio::task::JoinHandle;
+
+use crate::event_emitter;
+
+// 常驻服务panic后重启的等待时间，连续panic时翻倍
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TaskKind {
+    Job,
+    Service,
+}
+
+struct TrackedTask {
+    name: String,
+    kind: TaskKind,
+    handle: JoinHandle<()>,
+}
+
+pub struct TaskSupervisor {
+    tasks: Mutex<HashMap<u64, TrackedTask>>,
+    next_id: AtomicU64,
+    shutting_down: AtomicBool,
+}
+
+static SUPERVISOR: OnceLock<TaskSupervisor> = OnceLock::new();
+
+// 全局的任务监督
+pub fn global() -> &'static TaskSupervisor {
+    SUPERVISOR.get_or_init(TaskSupervisor::new)
+}
+
+// 在全局监督下启动一次性任务
+pub fn spawn<F>(name: impl Into<String>, future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    global().spawn(name, future);
+}
+
+// 在全局监督下启动常驻服务
+pub fn spawn_service<F, Fut>(name: impl Into<String>, factory: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    global().spawn_service(name, factory);
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "未知错误".to_string()
+    }
+}
+
+fn report_failure(name: &str, message: &str) {
+    eprintln!("后台任务 {} 异常退出: {}", name, message);
+    event_emitter::emit_event("background-task-failed", serde_json::json!({
+        "name": name,
+        "message": message,
+    }));
+}
+
+impl TaskSupervisor {
+    pub fn new() -> Self {
+        Self {
+            tasks: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    // 登记任务，任务结束时自己从表里删掉
+    // future里已经处理了panic，一定会走到删除那一步
+    fn track<F>(&'static self, name: String, kind: TaskKind, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // 拿着锁spawn，任务马上结束的话也要等登记完才删得掉
+        let mut tasks = self.tasks.lock().unwrap();
+        let handle = tokio::spawn(async move {
+            future.await;
+            self.tasks.lock().unwrap().remove(&id);
+        });
+        tasks.insert(id, TrackedTask { name, kind, handle });
+    }
+
+    pub fn spawn<F>(&'static self, name: impl Into<String>, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        let task_name = name.clone();
+        self.track(name, TaskKind::Job, async move {
+            if let Err(panic) = AssertUnwindSafe(future).catch_unwind().await {
+                report_failure(&task_name, &panic_message(&*panic));
+            }
+        });
+    }
+
+    pub fn spawn_service<F, Fut>(&'static self, name: impl Into<String>, factory: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        let task_name = name.clone();
+        self.track(name, TaskKind::Service, async move {
+            let mut backoff = RESTART_BACKOFF_MIN;
+            loop {
+                let panic = match AssertUnwindSafe(factory()).catch_unwind().await {
+                    Ok(()) => return,
+                    Err(panic) => panic,
+                };
+                report_failure(&task_name, &panic_message(&*panic));
+                if self.shutting_down.load(Ordering::SeqCst) {
+                    return;
+                }
+                println!("{:?}后重启后台服务 {}", backoff, task_name);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+            }
+        });
+    }
+
+    // 还在运行的任务名
+    pub fn running(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.tasks.lock().unwrap().values().map(|task| task.name.clone()).collect();
+        names.sort();
+        names
+    }
+
+    // 退出前调用：结束常驻服务，等一次性任务结束，超过timeout的直接abort
+    // 等待期间新spawn的任务也会等，返回被强制结束的一次性任务数
+    pub async fn shutdown(&self, timeout: Duration) -> usize {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut aborted = 0;
+
+        loop {
+            let drained: Vec<TrackedTask> = self.tasks.lock().unwrap().drain().map(|(_, task)| task).collect();
+            if drained.is_empty() {
+                break;
+            }
+            for mut task in drained {
+                if task.kind == TaskKind::Service {
+                    task.handle.abort();
+                    continue;
+                }
+                if tokio::time::timeout_at(deadline, &mut task.handle).await.is_err() {
+                    println!("后台任务 {} 退出超时，强制结束", task.name);
+                    task.handle.abort();
+                    aborted += 1;
+                }
+            }
+        }
+        aborted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    fn supervisor() -> &'static TaskSupervisor {
+        Box::leak(Box::new(TaskSupervisor::new()))
+    }
+
+    async fn wait_until_idle(supervisor: &TaskSupervisor) {
+        for _ in 0..100 {
+            if supervisor.running().is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("任务没有结束: {:?}", supervisor.running());
+    }
+
+    #[tokio::test]
+    async fn finished_and_panicked_jobs_are_removed() {
+        let supervisor = supervisor();
+        supervisor.spawn("ok", async {});
+        supervisor.spawn("boom", async { panic!("测试panic") });
+        wait_until_idle(supervisor).await;
+    }
+
+    #[tokio::test]
+    async fn service_restarts_after_panic() {
+        let supervisor = supervisor();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        supervisor.spawn_service("flaky", move || {
+            let counter = counter.clone();
+            async move {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("第一次启动失败");
+                }
+            }
+        });
+
+        tokio::time::sleep(RESTART_BACKOFF_MIN + Duration::from_millis(500)).await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        wait_until_idle(supervisor).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_jobs_and_aborts_the_rest() {
+        let supervisor = supervisor();
+        let finished = Arc::new(AtomicBool::new(false));
+        let flag = finished.clone();
+        supervisor.spawn("short", async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            flag.store(true, Ordering::SeqCst);
+        });
+        supervisor.spawn("stuck", std::future::pending());
+        supervisor.spawn_service("loop", std::future::pending);
+
+        let aborted = supervisor.shutdown(Duration::from_millis(300)).await;
+        assert!(finished.load(Ordering::SeqCst));
+        assert_eq!(aborted, 1);
+        assert!(supervisor.running().is_empty());
+    }
+}
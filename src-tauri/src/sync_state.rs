@@ -0,0 +1,366 @@
+// 同步状态库
+// 记下云盘上每个同步过的路径的信息（大小、修改时间、哈希、目录ETag、上次同步时间），
+// 存在应用数据目录的sync_state.json里，拉取（selective_sync::plan_pull）时先查这里
+//
+// 思考：
+// 1. 原来每次拉取都把整棵目录树完整列一遍，再给每个文件建下载任务（每个都要请求一次元数据），
+//    文件多的时候光是确认"没变化"就要几分钟。现在每个目录带着上次的ETag去列，没变化的后端返回304，
+//    直接用库里的子项；文件记录和云端一致、上次已经同步到本地、本地文件还在的就不再建任务
+// 2. 所有读写都通过transaction：在一份拷贝上修改，写盘（先写临时文件再重命名）成功后才换上去，
+//    写盘失败整个事务作废，内存里和磁盘上不会出现改了一半的状态；
+//    同时只有一个事务在跑，并发的拉取和下载完成回调不会互相覆盖
+// 3. 云端的文件变了（大小或修改时间不一样）就清掉last_synced和hash，下次拉取时重新下载；
+//    目录从云端消失时连同下面的记录一起删掉
+// 4. 只有拉取时列过的路径才有记录，单独下载一个文件不会写进来
+// 5. 只读的查询（ETag、子项）用read，直接在库上读，不拷贝整个库
+// 6. 下载完成的mark_synced不走事务：一次拉取几百个文件时每下完一个就整个重写一遍文件太浪费，
+//    先改内存，等SAVE_DELAY合在一起写，退出时把还没写的写下去（flush_pending）。
+//    没来得及写就崩溃了，最多是下次拉取时把这几个文件再下载一遍
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::auth::AuthInfo;
+use crate::cloud_api::{self, ConditionalList, RemoteEntry};
+use crate::selective_sync::normalize;
+use crate::storage::get_app_data_dir;
+
+// mark_synced以后等多久写盘（见思考6）
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncRecord {
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified_at: Option<i64>,   // 云端修改时间（Unix时间戳，秒）
+    pub hash: Option<String>,       // 下载完成时算出的SHA256
+    pub etag: Option<String>,       // 目录列表的ETag，只有目录有
+    pub last_synced: Option<i64>,   // 上次同步到本地的时间，None表示还没同步过或者云端变了
+}
+
+impl SyncRecord {
+    fn from_entry(entry: &RemoteEntry) -> Self {
+        Self {
+            is_dir: entry.is_dir,
+            size: entry.size,
+            modified_at: entry.modified_timestamp(),
+            hash: None,
+            etag: None,
+            last_synced: None,
+        }
+    }
+
+    // 和云端的信息比有没有变化
+    fn same_remote(&self, other: &SyncRecord) -> bool {
+        self.is_dir == other.is_dir && self.size == other.size && self.modified_at == other.modified_at
+    }
+}
+
+// 只读访问（见思考5）
+pub struct View<'a> {
+    records: &'a BTreeMap<String, SyncRecord>,
+}
+
+impl View<'_> {
+    pub fn get(&self, path: &str) -> Option<&SyncRecord> {
+        self.records.get(&normalize(path))
+    }
+
+    // dir下面的直接子项
+    pub fn children(&self, dir: &str) -> Vec<(String, SyncRecord)> {
+        let dir = normalize(dir);
+        let prefix = if dir.is_empty() { String::new() } else { format!("{}/", dir) };
+        self.records
+            .range(prefix.clone()..)
+            .take_while(|(path, _)| path.starts_with(&prefix))
+            .filter(|(path, _)| !path.is_empty() && !path[prefix.len()..].contains('/'))
+            .map(|(path, record)| (path.clone(), record.clone()))
+            .collect()
+    }
+}
+
+pub struct Transaction<'a> {
+    records: &'a mut BTreeMap<String, SyncRecord>,
+    changed: bool,
+}
+
+impl Transaction<'_> {
+    fn view(&self) -> View<'_> {
+        View { records: self.records }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&SyncRecord> {
+        self.records.get(&normalize(path))
+    }
+
+    pub fn children(&self, dir: &str) -> Vec<(String, SyncRecord)> {
+        self.view().children(dir)
+    }
+
+    fn put(&mut self, path: String, record: SyncRecord) {
+        if self.records.get(&path) != Some(&record) {
+            self.records.insert(path, record);
+            self.changed = true;
+        }
+    }
+
+    // 删掉path和它下面的所有记录
+    pub fn remove_tree(&mut self, path: &str) {
+        let path = normalize(path);
+        let prefix = format!("{}/", path);
+        let before = self.records.len();
+        self.records.retain(|p, _| *p != path && !p.starts_with(&prefix));
+        self.changed |= self.records.len() != before;
+    }
+
+    // 用新列出来的内容更新dir：没变的子项保留同步信息，变了的清掉，云端没有了的删掉
+    pub fn apply_listing(&mut self, dir: &str, etag: Option<String>, entries: &[RemoteEntry]) {
+        let dir = normalize(dir);
+        let mut dir_record = self.records.get(&dir).cloned().unwrap_or(SyncRecord {
+            is_dir: true,
+            size: 0,
+            modified_at: None,
+            hash: None,
+            etag: None,
+            last_synced: None,
+        });
+        dir_record.etag = etag;
+        self.put(dir.clone(), dir_record);
+
+        let listed: BTreeMap<String, SyncRecord> = entries
+            .iter()
+            .map(|entry| (normalize(&entry.path), SyncRecord::from_entry(entry)))
+            .collect();
+        for (path, _) in self.children(&dir) {
+            if !listed.contains_key(&path) {
+                self.remove_tree(&path);
+            }
+        }
+        for (path, fresh) in listed {
+            let record = match self.records.get(&path) {
+                Some(old) if old.same_remote(&fresh) => old.clone(),
+                Some(old) => {
+                    // 文件变成了目录或者反过来，下面的旧记录都没用了
+                    if old.is_dir != fresh.is_dir {
+                        self.remove_tree(&path);
+                    }
+                    fresh
+                }
+                None => fresh,
+            };
+            self.put(path, record);
+        }
+    }
+
+    // 下载完成，记下同步时间和哈希；不是拉取时列出来的路径不记
+    pub fn mark_synced(&mut self, path: &str, hash: Option<String>) {
+        let path = normalize(path);
+        if let Some(record) = self.records.get(&path) {
+            let mut record = record.clone();
+            record.last_synced = Some(chrono::Utc::now().timestamp());
+            record.hash = hash.or(record.hash);
+            self.put(path, record);
+        }
+    }
+}
+
+struct StateDb {
+    records: BTreeMap<String, SyncRecord>,
+    loaded: bool,
+    dirty: bool,            // 有没写盘的mark_synced（见思考6）
+    save_scheduled: bool,   // 已经安排了延迟写盘
+}
+
+static STATE_DB: OnceLock<Mutex<StateDb>> = OnceLock::new();
+
+fn state_db() -> &'static Mutex<StateDb> {
+    STATE_DB.get_or_init(|| Mutex::new(StateDb {
+        records: BTreeMap::new(),
+        loaded: false,
+        dirty: false,
+        save_scheduled: false,
+    }))
+}
+
+fn state_path() -> Result<PathBuf> {
+    let data_dir = get_app_data_dir().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(data_dir.join("sync_state.json"))
+}
+
+async fn ensure_loaded(db: &mut StateDb) {
+    if db.loaded {
+        return;
+    }
+    db.loaded = true;
+    let Ok(path) = state_path() else { return };
+    if let Ok(content) = fs::read_to_string(&path).await {
+        match serde_json::from_str(&content) {
+            Ok(records) => db.records = records,
+            Err(e) => println!("同步状态库格式错误，重新开始: {}", e),
+        }
+    }
+}
+
+async fn save(records: &BTreeMap<String, SyncRecord>) -> Result<()> {
+    let path = state_path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await
+            .context(format!("创建数据目录失败: {:?}", parent))?;
+    }
+    let content = serde_json::to_string(records).context("序列化同步状态失败")?;
+    let mut tmp_name = path.as_os_str().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    fs::write(&tmp_path, content).await
+        .context(format!("写入同步状态失败: {:?}", tmp_path))?;
+    fs::rename(&tmp_path, &path).await
+        .context(format!("保存同步状态失败: {:?}", path))?;
+    Ok(())
+}
+
+// 在一个事务里读写同步状态，有修改时写盘成功才生效（见思考2）
+pub async fn transaction<R>(f: impl FnOnce(&mut Transaction<'_>) -> R) -> Result<R> {
+    let mut db = state_db().lock().await;
+    ensure_loaded(&mut db).await;
+
+    let mut records = db.records.clone();
+    let mut tx = Transaction { records: &mut records, changed: false };
+    let result = f(&mut tx);
+    if tx.changed {
+        save(&records).await?;
+        db.records = records;
+        db.dirty = false;
+    }
+    Ok(result)
+}
+
+// 只读查询，不拷贝整个库（见思考5）
+pub async fn read<R>(f: impl FnOnce(&View<'_>) -> R) -> R {
+    let mut db = state_db().lock().await;
+    ensure_loaded(&mut db).await;
+    f(&View { records: &db.records })
+}
+
+// 列出目录，带上库里的ETag，没变化时直接用库里的子项
+pub async fn list_dir(auth_info: &AuthInfo, dir: &str) -> Result<Vec<(RemoteEntry, SyncRecord)>> {
+    let etag = read(|view| view.get(dir).and_then(|record| record.etag.clone())).await;
+    let children = match cloud_api::list_dir_if_changed(auth_info, dir, etag.as_deref()).await? {
+        ConditionalList::NotModified => {
+            println!("目录 {} 没有变化，使用同步状态库", dir);
+            read(|view| view.children(dir)).await
+        }
+        ConditionalList::Modified { entries, etag } => {
+            transaction(|tx| {
+                tx.apply_listing(dir, etag, &entries);
+                tx.children(dir)
+            }).await?
+        }
+    };
+    Ok(children
+        .into_iter()
+        .map(|(path, record)| {
+            let entry = RemoteEntry {
+                name: path.rsplit('/').next().unwrap_or_default().to_string(),
+                path,
+                is_dir: record.is_dir,
+                size: record.size,
+                mime_type: None,
+                modified_at: record.modified_at.map(serde_json::Value::from),
+            };
+            (entry, record)
+        })
+        .collect())
+}
+
+// 下载完成时调用，先改内存，过一会儿再写盘（见思考6）
+pub async fn mark_synced(path: &str, hash: Option<String>) {
+    let mut db = state_db().lock().await;
+    ensure_loaded(&mut db).await;
+    let mut tx = Transaction { records: &mut db.records, changed: false };
+    tx.mark_synced(path, hash);
+    if !tx.changed {
+        return;
+    }
+
+    db.dirty = true;
+    if !db.save_scheduled {
+        db.save_scheduled = true;
+        tauri::async_runtime::spawn(async {
+            tokio::time::sleep(SAVE_DELAY).await;
+            flush_pending().await;
+        });
+    }
+}
+
+// 把还没写盘的mark_synced写下去，退出时也调用
+pub async fn flush_pending() {
+    let mut db = state_db().lock().await;
+    db.save_scheduled = false;
+    if !db.dirty {
+        return;
+    }
+    match save(&db.records).await {
+        Ok(()) => db.dirty = false,
+        Err(e) => println!("保存同步状态失败: {:#}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, is_dir: bool, size: u64) -> RemoteEntry {
+        RemoteEntry {
+            name: path.rsplit('/').next().unwrap().to_string(),
+            path: path.to_string(),
+            is_dir,
+            size,
+            mime_type: None,
+            modified_at: Some(serde_json::Value::from(1_700_000_000)),
+        }
+    }
+
+    #[test]
+    fn keeps_sync_info_for_unchanged_entries() {
+        let mut records = BTreeMap::new();
+        let mut tx = Transaction { records: &mut records, changed: false };
+        tx.apply_listing("docs", Some("v1".to_string()), &[
+            entry("docs/a.txt", false, 10),
+            entry("docs/b.txt", false, 20),
+            entry("docs/old", true, 0),
+        ]);
+        tx.apply_listing("docs/old", None, &[entry("docs/old/c.txt", false, 5)]);
+        tx.mark_synced("docs/a.txt", Some("abc".to_string()));
+        tx.mark_synced("docs/b.txt", None);
+        tx.mark_synced("elsewhere.txt", None);
+        assert!(tx.get("elsewhere.txt").is_none());
+
+        // b.txt变大了，old目录没了
+        tx.changed = false;
+        tx.apply_listing("/docs/", Some("v2".to_string()), &[
+            entry("docs/a.txt", false, 10),
+            entry("docs/b.txt", false, 25),
+        ]);
+        assert!(tx.changed);
+        assert_eq!(tx.get("docs").unwrap().etag.as_deref(), Some("v2"));
+        assert_eq!(tx.get("docs/a.txt").unwrap().hash.as_deref(), Some("abc"));
+        assert!(tx.get("docs/a.txt").unwrap().last_synced.is_some());
+        assert!(tx.get("docs/b.txt").unwrap().last_synced.is_none());
+        assert!(tx.get("docs/old/c.txt").is_none());
+        assert_eq!(tx.children("docs").len(), 2);
+
+        // 内容一样时不算修改，不用写盘
+        tx.changed = false;
+        tx.apply_listing("docs", Some("v2".to_string()), &[
+            entry("docs/a.txt", false, 10),
+            entry("docs/b.txt", false, 25),
+        ]);
+        assert!(!tx.changed);
+    }
+}
@@ -0,0 +1,278 @@
+// 匿名统计上报
+// 传输成功率、平均速度、蓝牙重连次数这些数字只在用户自己的电脑上（metrics.rs），
+// 分不清是个别用户的问题还是某个系统/某种适配器普遍的问题。
+// 用户同意后按设置的间隔把这些计数发给统计地址，preview_telemetry可以先看到要发的完整内容
+//
+// 思考：
+// 1. 默认关闭，只有set_telemetry_enabled(true)才打开；打开时随机生成install_id，关闭时删掉，
+//    再打开就是一个新的ID，和以前的数据对不上
+// 2. 只发计数和比例：不发文件名、路径、设备地址、后端账号，请求也不带认证头。
+//    适配器描述里可能带MAC地址，发之前去掉
+// 3. 每次发的是上次发送成功以后的增量，不是启动以来的累计，后端直接加起来就行；
+//    打开时把起点设成当前值，打开之前的数据不会发出去
+// 4. 预览和实际发送用同一个build_report，预览看到的就是会发的内容（只有时间段长度会不同）
+// 5. 发送失败不重置起点，下次检查时连同这段时间的数据一起再发
+
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::metrics::{self, Counter, Throughput};
+use crate::settings::{self, TelemetrySettings};
+
+// 多久检查一次是否到了上报时间
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+// 上报请求的超时
+const SEND_TIMEOUT: Duration = Duration::from_secs(15);
+
+// 某一时刻的计数，算增量用（见思考3）
+#[derive(Debug, Clone, Copy, Default)]
+struct Sample {
+    downloads_completed: u64,
+    downloads_failed: u64,
+    uploads_completed: u64,
+    uploads_failed: u64,
+    ble_connects: u64,
+    ble_reconnects: u64,
+    ble_connect_failures: u64,
+    throughput: Throughput,
+}
+
+impl Sample {
+    fn now() -> Self {
+        Self {
+            downloads_completed: metrics::value(Counter::DownloadsCompleted),
+            downloads_failed: metrics::value(Counter::DownloadsFailed),
+            uploads_completed: metrics::value(Counter::UploadsCompleted),
+            uploads_failed: metrics::value(Counter::UploadsFailed),
+            ble_connects: metrics::value(Counter::BleConnects),
+            ble_reconnects: metrics::value(Counter::BleReconnects),
+            ble_connect_failures: metrics::value(Counter::BleConnectFailures),
+            throughput: metrics::throughput(),
+        }
+    }
+}
+
+// 上次发送成功时的计数和时间
+struct Baseline {
+    sample: Sample,
+    at: Instant,
+}
+
+static BASELINE: OnceLock<Mutex<Baseline>> = OnceLock::new();
+
+fn baseline() -> &'static Mutex<Baseline> {
+    BASELINE.get_or_init(|| Mutex::new(Baseline { sample: Sample::default(), at: Instant::now() }))
+}
+
+fn reset_baseline() {
+    *baseline().lock().unwrap() = Baseline { sample: Sample::now(), at: Instant::now() };
+}
+
+// 发出去的内容，字段就是全部（见思考2）
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TelemetryReport {
+    pub install_id: String,              // 匿名ID，没打开时为空
+    pub app_version: &'static str,
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub adapter_model: Option<String>,   // 蓝牙适配器描述（已去掉地址），没有适配器或没有编译蓝牙时为None
+    pub period_secs: u64,                // 这份数据覆盖多长时间
+    pub downloads_completed: u64,
+    pub downloads_failed: u64,
+    pub download_success_rate: Option<f64>,   // 这段时间没有下载时为None
+    pub uploads_completed: u64,
+    pub uploads_failed: u64,
+    pub upload_success_rate: Option<f64>,
+    pub avg_download_kbps: Option<f64>,
+    pub avg_upload_kbps: Option<f64>,
+    pub ble_connects: u64,
+    pub ble_reconnects: u64,
+    pub ble_connect_failures: u64,
+}
+
+// 本地预览
+#[derive(Debug, Clone, Serialize)]
+pub struct TelemetryPreview {
+    pub enabled: bool,
+    pub endpoint: Option<String>,     // 会发到哪里，地址拿不到时为None
+    pub next_report_in_secs: u64,     // 还有多久发下一次（没打开时也按打开算）
+    pub report: TelemetryReport,
+}
+
+fn success_rate(completed: u64, failed: u64) -> Option<f64> {
+    let total = completed + failed;
+    (total > 0).then(|| completed as f64 / total as f64)
+}
+
+// 去掉描述里像MAC地址的部分（AA:BB:CC:DD:EE:FF或AA-BB-...）
+fn strip_addresses(info: &str) -> String {
+    let is_address = |token: &str| {
+        let token = token.trim_matches(|c: char| !c.is_ascii_hexdigit());
+        let parts: Vec<&str> = token.split(|c| c == ':' || c == '-').collect();
+        parts.len() == 6 && parts.iter().all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))
+    };
+    info.split_whitespace().filter(|token| !is_address(token)).collect::<Vec<_>>().join(" ")
+}
+
+async fn adapter_model() -> Option<String> {
+    #[cfg(feature = "ble")]
+    {
+        crate::bluetooth::BluetoothManager::selected_adapter_info().await
+            .map(|info| strip_addresses(&info))
+            .filter(|info| !info.is_empty())
+    }
+    #[cfg(not(feature = "ble"))]
+    {
+        None
+    }
+}
+
+// 按起点和当前计数组装上报内容（见思考4）
+fn build_report(config: &TelemetrySettings, since: &Sample, now: &Sample, period: Duration, adapter_model: Option<String>) -> TelemetryReport {
+    let delta = |current: u64, earlier: u64| current.saturating_sub(earlier);
+    let downloads_completed = delta(now.downloads_completed, since.downloads_completed);
+    let downloads_failed = delta(now.downloads_failed, since.downloads_failed);
+    let uploads_completed = delta(now.uploads_completed, since.uploads_completed);
+    let uploads_failed = delta(now.uploads_failed, since.uploads_failed);
+    let throughput = now.throughput.since(&since.throughput);
+
+    TelemetryReport {
+        install_id: config.install_id.clone().unwrap_or_default(),
+        app_version: crate::updater::CURRENT_VERSION,
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        adapter_model,
+        period_secs: period.as_secs(),
+        downloads_completed,
+        downloads_failed,
+        download_success_rate: success_rate(downloads_completed, downloads_failed),
+        uploads_completed,
+        uploads_failed,
+        upload_success_rate: success_rate(uploads_completed, uploads_failed),
+        avg_download_kbps: throughput.download_kbps(),
+        avg_upload_kbps: throughput.upload_kbps(),
+        ble_connects: delta(now.ble_connects, since.ble_connects),
+        ble_reconnects: delta(now.ble_reconnects, since.ble_reconnects),
+        ble_connect_failures: delta(now.ble_connect_failures, since.ble_connect_failures),
+    }
+}
+
+// 上报地址：设置里有就用设置的，没有就用当前后端的/telemetry
+fn endpoint(config: &TelemetrySettings) -> Result<String> {
+    match config.endpoint.as_deref().map(str::trim).filter(|e| !e.is_empty()) {
+        Some(endpoint) => Ok(endpoint.to_string()),
+        None => Ok(format!("{}/telemetry", crate::config::get_backend_url()?)),
+    }
+}
+
+// 当前要发的内容和发送时的起点
+async fn current_report(config: &TelemetrySettings) -> (TelemetryReport, Sample) {
+    let (since, elapsed) = {
+        let baseline = baseline().lock().unwrap();
+        (baseline.sample, baseline.at.elapsed())
+    };
+    let now = Sample::now();
+    (build_report(config, &since, &now, elapsed, adapter_model().await), now)
+}
+
+// 打开或关闭上报（见思考1、3）
+pub async fn set_enabled(enabled: bool) -> Result<TelemetrySettings> {
+    let current = settings::get().telemetry;
+    let install_id = match (enabled, current.enabled) {
+        (true, true) => current.install_id.clone(),   // 已经打开时不换ID
+        (true, false) => Some(uuid::Uuid::new_v4().to_string()),
+        (false, _) => None,
+    };
+
+    let settings = settings::update(serde_json::json!({
+        "telemetry": { "enabled": enabled, "install_id": install_id },
+    })).await?;
+    if enabled && !current.enabled {
+        reset_baseline();
+    }
+    println!("[统计上报] 已{}", if enabled { "打开" } else { "关闭" });
+    Ok(settings.telemetry)
+}
+
+// 现在会发的内容
+pub async fn preview() -> TelemetryPreview {
+    let config = settings::get().telemetry;
+    let (report, _) = current_report(&config).await;
+    let interval = Duration::from_secs(config.interval_hours.saturating_mul(3600));
+    TelemetryPreview {
+        enabled: config.enabled,
+        endpoint: endpoint(&config).ok(),
+        next_report_in_secs: interval.saturating_sub(baseline().lock().unwrap().at.elapsed()).as_secs(),
+        report,
+    }
+}
+
+async fn send(config: &TelemetrySettings) -> Result<()> {
+    let url = endpoint(config)?;
+    let (report, sample) = current_report(config).await;
+    crate::http_client::shared_client()?
+        .post(&url)
+        .timeout(SEND_TIMEOUT)
+        .json(&report)
+        .send().await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("发送到{}失败", url))?;
+
+    // 见思考5
+    *baseline().lock().unwrap() = Baseline { sample, at: Instant::now() };
+    Ok(())
+}
+
+// 后台任务：到时间就上报一次
+pub async fn report_loop() {
+    baseline();
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+
+        let config = settings::get().telemetry;
+        let interval = Duration::from_secs(config.interval_hours.saturating_mul(3600));
+        if !config.enabled || baseline().lock().unwrap().at.elapsed() < interval {
+            continue;
+        }
+        match send(&config).await {
+            Ok(()) => println!("[统计上报] 已发送"),
+            Err(e) => println!("[统计上报] {:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_only_deltas_since_last_send() {
+        let config = TelemetrySettings { enabled: true, install_id: Some("id".into()), ..TelemetrySettings::default() };
+        let since = Sample { downloads_completed: 5, downloads_failed: 1, ble_reconnects: 2, ..Sample::default() };
+        let now = Sample {
+            downloads_completed: 8,
+            downloads_failed: 2,
+            ble_reconnects: 2,
+            throughput: Throughput { upload_bytes: 2048, upload_millis: 1000, ..Throughput::default() },
+            ..Sample::default()
+        };
+
+        let report = build_report(&config, &since, &now, Duration::from_secs(60), None);
+        assert_eq!(report.install_id, "id");
+        assert_eq!((report.downloads_completed, report.downloads_failed), (3, 1));
+        assert_eq!(report.download_success_rate, Some(0.75));
+        assert_eq!(report.upload_success_rate, None);
+        assert_eq!(report.avg_upload_kbps, Some(2.0));
+        assert_eq!(report.avg_download_kbps, None);
+        assert_eq!(report.ble_reconnects, 0);
+    }
+
+    #[test]
+    fn strips_addresses_from_adapter_info() {
+        assert_eq!(strip_addresses("hci0 (AA:BB:CC:DD:EE:FF) Intel"), "hci0 Intel");
+        assert_eq!(strip_addresses("WinRT"), "WinRT");
+    }
+}
@@ -0,0 +1,67 @@
+// 复制TOTP到剪贴板，过一会儿自动清空
+// 和硬件令牌一样：点一下复制验证码，粘贴到别的地方，过了有效期剪贴板里就不留验证码
+//
+// 思考：验证码留在剪贴板里，用户之后随手一粘就可能贴到聊天窗口里。
+// 复制后开始倒计时，每秒发totp-clipboard-countdown事件（前端显示"xx秒后清除"），
+// 到时间清空剪贴板并发totp-clipboard-cleared事件。
+// 清空前先看剪贴板里还是不是我们复制的验证码，用户这期间复制了别的东西就不动它。
+// 倒计时期间再复制一次，旧的倒计时作废，从新的验证码重新开始。
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tauri_plugin_clipboard_manager::ClipboardExt;
+
+use crate::event_emitter;
+
+// 每次复制加一，倒计时发现不是自己这一轮就停下
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// 把验证码写到剪贴板，clear_after为0时不自动清空
+pub fn copy(app_handle: &tauri::AppHandle, totp: String, clear_after: u64) -> Result<(), String> {
+    app_handle
+        .clipboard()
+        .write_text(totp.clone())
+        .map_err(|e| format!("复制到剪贴板失败: {}", e))?;
+
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    if clear_after == 0 {
+        return Ok(());
+    }
+
+    let app_handle = app_handle.clone();
+    tauri::async_runtime::spawn(async move {
+        for remaining in (1..=clear_after).rev() {
+            if GENERATION.load(Ordering::SeqCst) != generation {
+                return;
+            }
+            event_emitter::emit_event("totp-clipboard-countdown", serde_json::json!({
+                "remaining_secs": remaining,
+            }));
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        if GENERATION.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        clear_if_unchanged(&app_handle, &totp);
+    });
+    Ok(())
+}
+
+// 剪贴板里还是我们复制的验证码时才清空
+fn clear_if_unchanged(app_handle: &tauri::AppHandle, totp: &str) {
+    let clipboard = app_handle.clipboard();
+    let cleared = match clipboard.read_text() {
+        Ok(current) if current == totp => match clipboard.clear() {
+            Ok(_) => true,
+            Err(e) => {
+                println!("清空剪贴板失败: {}", e);
+                false
+            }
+        },
+        _ => false,
+    };
+    println!("TOTP剪贴板倒计时结束，{}", if cleared { "已清空" } else { "剪贴板内容已变化，没有清空" });
+    event_emitter::emit_event("totp-clipboard-cleared", serde_json::json!({
+        "cleared": cleared,
+    }));
+}
@@ -0,0 +1,199 @@
+// 文件夹上传的排除规则
+// 遍历文件夹时跳过node_modules、*.tmp、隐藏文件、超过大小限制的文件
+//
+// 思考：规则写法跟.gitignore一样，用户不用再学一套：
+// 1. 不带"/"的规则匹配任意一层的名字，node_modules会排除所有叫这个名字的目录
+// 2. 带"/"的规则从上传的文件夹开始匹配相对路径，build/*.log只排除顶层build目录下的日志
+// 3. 以"/"结尾的规则只匹配目录；"#"开头的是注释
+// 4. "!"开头的规则把前面排除的再加回来，后面的规则优先
+// 目录被排除时整个目录都不遍历，和gitignore一样，里面的文件不能再用"!"加回来。
+// 规则由每次上传时传进来，格式错误直接报错，不要悄悄忽略掉用户写的规则。
+
+use std::path::Path;
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::{Deserialize, Serialize};
+
+// 一次上传的过滤条件
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterRules {
+    pub exclude: Vec<String>,          // gitignore风格的排除规则
+    pub exclude_hidden: bool,          // 排除"."开头的文件和目录
+    pub max_file_size: Option<u64>,    // 超过这个大小的文件不上传（字节）
+}
+
+struct Rule {
+    pattern: String,
+    matcher: GlobMatcher,
+    anchored: bool,      // 匹配相对路径，否则只匹配名字
+    dir_only: bool,
+    negated: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Result<Option<Self>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (negated, rest) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, rest) = match rest.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let anchored = rest.contains('/');
+        let glob = rest.trim_start_matches('/');
+
+        let matcher = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()
+            .context(format!("排除规则格式错误: {}", line))?
+            .compile_matcher();
+
+        Ok(Some(Self {
+            pattern: line.to_string(),
+            matcher,
+            anchored,
+            dir_only,
+            negated,
+        }))
+    }
+
+    fn matches(&self, relative: &str, name: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.matcher.is_match(relative)
+        } else {
+            self.matcher.is_match(name)
+        }
+    }
+}
+
+pub struct TransferFilter {
+    rules: Vec<Rule>,
+    exclude_hidden: bool,
+    max_file_size: Option<u64>,
+}
+
+impl TransferFilter {
+    pub fn new(rules: &FilterRules) -> Result<Self> {
+        let mut parsed = Vec::new();
+        for line in &rules.exclude {
+            if let Some(rule) = Rule::parse(line)? {
+                parsed.push(rule);
+            }
+        }
+        Ok(Self {
+            rules: parsed,
+            exclude_hidden: rules.exclude_hidden,
+            max_file_size: rules.max_file_size,
+        })
+    }
+
+    // 按规则判断是否排除，返回排除原因
+    // relative是相对于上传文件夹的路径
+    fn excluded_by_rules(&self, relative: &Path, is_dir: bool) -> Option<String> {
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        let name = relative.rsplit('/').next().unwrap_or(&relative).to_string();
+
+        if self.exclude_hidden && name.starts_with('.') {
+            return Some("隐藏文件".to_string());
+        }
+
+        // 后面的规则优先，倒着找第一条匹配的
+        let rule = self.rules.iter().rev().find(|rule| rule.matches(&relative, &name, is_dir))?;
+        if rule.negated {
+            return None;
+        }
+        Some(format!("匹配排除规则 {}", rule.pattern))
+    }
+
+    // 目录是否排除，排除的目录不再往下遍历
+    pub fn excludes_dir(&self, relative: &Path) -> Option<String> {
+        self.excluded_by_rules(relative, true)
+    }
+
+    // 文件是否排除
+    pub fn excludes_file(&self, relative: &Path, size: u64) -> Option<String> {
+        if let Some(reason) = self.excluded_by_rules(relative, false) {
+            return Some(reason);
+        }
+        match self.max_file_size {
+            Some(max) if size > max => Some(format!("超过大小限制 {} 字节", max)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(exclude: &[&str]) -> TransferFilter {
+        TransferFilter::new(&FilterRules {
+            exclude: exclude.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn name_rules_match_at_any_depth() {
+        let f = filter(&["node_modules", "*.tmp", "# 注释", ""]);
+        assert!(f.excludes_dir(Path::new("node_modules")).is_some());
+        assert!(f.excludes_dir(Path::new("web/node_modules")).is_some());
+        assert!(f.excludes_file(Path::new("a/b/c.tmp"), 1).is_some());
+        assert!(f.excludes_file(Path::new("a/b/c.txt"), 1).is_none());
+    }
+
+    #[test]
+    fn path_rules_are_anchored() {
+        let f = filter(&["build/*.log", "/dist/", "docs/**/draft.md"]);
+        assert!(f.excludes_file(Path::new("build/out.log"), 1).is_some());
+        assert!(f.excludes_file(Path::new("src/build/out.log"), 1).is_none());
+        assert!(f.excludes_file(Path::new("build/sub/out.log"), 1).is_none());
+        assert!(f.excludes_dir(Path::new("dist")).is_some());
+        assert!(f.excludes_file(Path::new("dist"), 1).is_none());
+        assert!(f.excludes_file(Path::new("docs/a/b/draft.md"), 1).is_some());
+    }
+
+    #[test]
+    fn later_negation_wins() {
+        let f = filter(&["*.log", "!keep.log"]);
+        assert!(f.excludes_file(Path::new("x.log"), 1).is_some());
+        assert!(f.excludes_file(Path::new("logs/keep.log"), 1).is_none());
+
+        let f = filter(&["!keep.log", "*.log"]);
+        assert!(f.excludes_file(Path::new("keep.log"), 1).is_some());
+    }
+
+    #[test]
+    fn hidden_and_size_limits() {
+        let f = TransferFilter::new(&FilterRules {
+            exclude: Vec::new(),
+            exclude_hidden: true,
+            max_file_size: Some(100),
+        })
+        .unwrap();
+        assert!(f.excludes_dir(Path::new(".git")).is_some());
+        assert!(f.excludes_file(Path::new("a/.DS_Store"), 1).is_some());
+        assert!(f.excludes_file(Path::new("big.bin"), 101).is_some());
+        assert!(f.excludes_file(Path::new("ok.bin"), 100).is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let rules = FilterRules {
+            exclude: vec!["[abc".to_string()],
+            ..Default::default()
+        };
+        assert!(TransferFilter::new(&rules).is_err());
+    }
+}
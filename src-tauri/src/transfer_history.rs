@@ -0,0 +1,135 @@
+// 传输历史
+// 已结束的任务从任务表里清理掉之前，先把结果（没成功的连同任务日志）记到应用数据目录的transfer_history.jsonl
+//
+// 思考：任务表只放还有用的任务（进行中、暂停、刚结束），结束很久的任务一直留着会越攒越多。
+// 清理掉以后前端还要能看到传过什么，所以每个任务记一行JSON，
+// 文件只保留最近MAX_HISTORY_ENTRIES条，写的时候先写临时文件再重命名。
+
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::storage::get_app_data_dir;
+use crate::transfer_log::LogLine;
+
+// 历史文件最多保留的条数
+const MAX_HISTORY_ENTRIES: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub direction: String,        // download/upload
+    pub id: String,               // 下载是file_id，上传是upload_id
+    pub file_name: String,
+    pub local_path: String,       // 下载的保存位置或上传的本地文件
+    pub total_size: u64,
+    pub status: String,           // completed/error/file_in_use
+    pub error: Option<String>,
+    pub finished_at: i64,         // 发现任务结束的时间（Unix时间戳）
+    #[serde(default)]
+    pub log: Vec<LogLine>,        // 没成功的任务的日志，成功的不记
+}
+
+fn history_path() -> Result<PathBuf> {
+    let data_dir = get_app_data_dir().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(data_dir.join("transfer_history.jsonl"))
+}
+
+// 读取历史，格式不对的行跳过
+async fn read_entries(path: &Path) -> Vec<HistoryEntry> {
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(_) => return Vec::new(),
+    };
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+async fn append_to(path: &Path, entries: &[HistoryEntry]) -> Result<()> {
+    let mut all = read_entries(path).await;
+    all.extend_from_slice(entries);
+    let skip = all.len().saturating_sub(MAX_HISTORY_ENTRIES);
+
+    let mut content = String::new();
+    for entry in &all[skip..] {
+        content.push_str(&serde_json::to_string(entry).context("序列化传输历史失败")?);
+        content.push('\n');
+    }
+
+    let mut tmp_name = path.as_os_str().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    fs::write(&tmp_path, content).await
+        .context(format!("写入传输历史失败: {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path).await
+        .context(format!("保存传输历史失败: {:?}", path))?;
+    Ok(())
+}
+
+// 最近的limit条，新的在前
+async fn load_from(path: &Path, limit: usize) -> Vec<HistoryEntry> {
+    let mut entries = read_entries(path).await;
+    entries.reverse();
+    entries.truncate(limit);
+    entries
+}
+
+// 追加历史记录
+pub async fn append(entries: &[HistoryEntry]) -> Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    append_to(&history_path()?, entries).await
+}
+
+// 读取最近的历史记录
+pub async fn load(limit: usize) -> Result<Vec<HistoryEntry>> {
+    Ok(load_from(&history_path()?, limit).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: usize) -> HistoryEntry {
+        HistoryEntry {
+            direction: "download".to_string(),
+            id: id.to_string(),
+            file_name: format!("{}.bin", id),
+            local_path: format!("/tmp/{}.bin", id),
+            total_size: 100,
+            status: "completed".to_string(),
+            error: None,
+            finished_at: id as i64,
+            log: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_newest_entries_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transfer_history.jsonl");
+
+        append_to(&path, &[entry(1), entry(2)]).await.unwrap();
+        append_to(&path, &[entry(3)]).await.unwrap();
+
+        let loaded = load_from(&path, 2).await;
+        assert_eq!(loaded, vec![entry(3), entry(2)]);
+    }
+
+    #[tokio::test]
+    async fn trims_to_max_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transfer_history.jsonl");
+
+        let entries: Vec<HistoryEntry> = (0..MAX_HISTORY_ENTRIES + 5).map(entry).collect();
+        append_to(&path, &entries).await.unwrap();
+
+        let loaded = load_from(&path, usize::MAX).await;
+        assert_eq!(loaded.len(), MAX_HISTORY_ENTRIES);
+        assert_eq!(loaded[0], entry(MAX_HISTORY_ENTRIES + 4));
+        assert_eq!(loaded.last().unwrap(), &entry(5));
+    }
+}
@@ -0,0 +1,414 @@
+// 传输HTTP层
+// 下载、上传和云盘文件管理接口共用的请求构建和错误处理
+//
+// 思考：download.rs、upload.rs、cloud_api.rs原来各自拼URL、取认证头、检查状态码、拼错误信息，
+// 写法大同小异，细节却不一样（错误信息格式不同，新加的接口容易忘了note_response_status）。
+// 统一收到这里：
+// 1. Endpoint列出后端的所有接口，URL只在这里拼，路径参数统一urlencode
+// 2. TransferHttp::request取共享客户端并注入认证头。后端地址和客户端在创建TransferHttp时定下来，
+//    每个任务有自己的TransferHttp，运行中切换后端（backend_profiles.rs）时，
+//    已经创建的任务还是发到原来的后端，不会一个文件传到一半换了主机
+// 3. send发送请求、记录认证结果，非2xx转成"{action}失败: 状态 - 内容"
+// 需要自己处理某些状态码的调用方（304、404回退）用send_raw拿原始响应，判断完再交给check_response。
+// 分片重试的次数和间隔也放在这里，下载和上传保持一致。
+// 新任务的分片大小也在这里协商：设置里指定的或者后端建议的大小，限制在后端和我们都接受的范围内。
+// 老后端没有/capabilities接口，就按没有限制处理。
+// 每个请求带一个新生成的X-Request-Id，本地日志和错误信息里也带上它，
+// 用户报问题时给出请求ID，后端就能在自己的日志里找到对应的那次请求。
+// 响应的JSON用parse_json解析：格式对不上时日志里打出原始内容（最多RAW_BODY_LOG_LIMIT字节），
+// 错误是BackendProtocolError，带上出问题的字段，不再只有一句"解析响应失败"。
+
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::auth::AuthInfo;
+use crate::{chunks, config, settings};
+
+// 请求ID的请求头
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// 下载接口返回文件SHA256（十六进制）的响应头，不是所有后端都有
+pub const CONTENT_HASH_HEADER: &str = "x-content-sha256";
+
+// 单个分片最多尝试几次
+pub const CHUNK_ATTEMPTS: u32 = 3;
+// 分片请求失败后等多久再重试
+pub const CHUNK_RETRY_DELAY: Duration = Duration::from_secs(1);
+// 响应解析失败时日志里最多打印多少字节的原始内容
+const RAW_BODY_LOG_LIMIT: usize = 2048;
+
+// 后端接口
+#[derive(Debug, Clone, Copy)]
+pub enum Endpoint<'a> {
+    Download(&'a str),        // GET/HEAD /download/{path}
+    ChunkHashes(&'a str),     // GET /download/hashes/{path}
+    UploadInit,               // POST /upload/init
+    UploadChunk,              // POST /upload/chunk
+    UploadFinish,             // POST /upload/finish
+    UploadStatus(&'a str),    // GET /upload/status/{upload_id}
+    UploadRemote,             // POST /upload/remote（后端自己去拉取URL）
+    Files,                    // GET /files/
+    File(&'a str),            // DELETE /files/{path}
+    Directories,              // POST /files/directories
+    Metadata,                 // GET /files/metadata
+    Search,                   // GET /files/search
+    Trash,                    // GET/DELETE /files/trash
+    TrashRestore,             // POST /files/trash/restore
+    ShareCreate,              // POST /share/create
+    StorageQuota,             // GET /storage/quota
+    Capabilities,             // GET /capabilities
+}
+
+impl Endpoint<'_> {
+    // 接口路径（不含后端地址）
+    pub fn path(&self) -> String {
+        match self {
+            Endpoint::Download(path) => format!("/download/{}", urlencoding::encode(path)),
+            Endpoint::ChunkHashes(path) => format!("/download/hashes/{}", urlencoding::encode(path)),
+            Endpoint::UploadInit => "/upload/init".to_string(),
+            Endpoint::UploadChunk => "/upload/chunk".to_string(),
+            Endpoint::UploadFinish => "/upload/finish".to_string(),
+            Endpoint::UploadStatus(upload_id) => format!("/upload/status/{}", urlencoding::encode(upload_id)),
+            Endpoint::UploadRemote => "/upload/remote".to_string(),
+            Endpoint::Files => "/files/".to_string(),
+            Endpoint::File(path) => format!("/files/{}", urlencoding::encode(path)),
+            Endpoint::Directories => "/files/directories".to_string(),
+            Endpoint::Metadata => "/files/metadata".to_string(),
+            Endpoint::Search => "/files/search".to_string(),
+            Endpoint::Trash => "/files/trash".to_string(),
+            Endpoint::TrashRestore => "/files/trash/restore".to_string(),
+            Endpoint::ShareCreate => "/share/create".to_string(),
+            Endpoint::StorageQuota => "/storage/quota".to_string(),
+            Endpoint::Capabilities => "/capabilities".to_string(),
+        }
+    }
+
+}
+
+// 后端能力，字段都可能缺失
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BackendCapabilities {
+    pub min_chunk_size: Option<u64>,
+    pub max_chunk_size: Option<u64>,
+    pub preferred_chunk_size: Option<u64>,
+    pub remote_fetch: bool,   // 支持/upload/remote，能自己下载URL存到云盘
+}
+
+// 按后端地址缓存的后端能力，同一个后端只查一次
+static CAPABILITIES: OnceLock<Mutex<Option<(String, BackendCapabilities)>>> = OnceLock::new();
+
+// 按设置和后端能力确定分片大小
+// configured是设置里指定的大小（0表示自动），没指定就用后端建议的，都没有用默认值，
+// 最后限制在后端和chunks::MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE都接受的范围内
+pub fn choose_chunk_size(configured: u64, capabilities: &BackendCapabilities) -> u64 {
+    let wanted = match configured {
+        0 => capabilities.preferred_chunk_size.unwrap_or(chunks::DEFAULT_CHUNK_SIZE),
+        configured => configured,
+    };
+    let min = capabilities.min_chunk_size.unwrap_or(0).max(chunks::MIN_CHUNK_SIZE);
+    let max = capabilities.max_chunk_size.unwrap_or(u64::MAX).min(chunks::MAX_CHUNK_SIZE);
+    if min > max {
+        // 后端的范围和我们的没有交集，只能按自己的范围来
+        println!("后端分片大小范围 {:?}-{:?} 无效，忽略", capabilities.min_chunk_size, capabilities.max_chunk_size);
+        return wanted.clamp(chunks::MIN_CHUNK_SIZE, chunks::MAX_CHUNK_SIZE);
+    }
+    wanted.clamp(min, max)
+}
+
+// 带认证信息的请求构建器
+#[derive(Clone)]
+pub struct TransferHttp {
+    client: Client,
+    base_url: String,   // 创建时的后端地址，之后的请求都发到这里
+    auth_info: AuthInfo,
+}
+
+impl TransferHttp {
+    // 使用共享的HTTP客户端，连接参数见设置里的network
+    pub fn new(auth_info: AuthInfo) -> Result<Self> {
+        let client = crate::http_client::shared_client()?;
+        let base_url = config::get_backend_url()?;
+        Ok(Self { client, base_url, auth_info })
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    // 换一份认证信息（TOTP过期了），客户端和后端地址不变
+    pub fn with_auth(&self, auth_info: AuthInfo) -> Self {
+        Self { client: self.client.clone(), base_url: self.base_url.clone(), auth_info }
+    }
+
+    // 构建请求并注入认证头和请求ID
+    pub fn request(&self, method: Method, endpoint: Endpoint<'_>) -> Result<RequestBuilder> {
+        let url = format!("{}{}", self.base_url, endpoint.path());
+        Ok(self.client
+            .request(method, url)
+            .headers(self.auth_info.get_auth_header()?)
+            .header(REQUEST_ID_HEADER, new_request_id()))
+    }
+
+    // 查询后端能力，后端没有这个接口时当作没有限制
+    // 网络错误不缓存，下次新任务再查
+    pub async fn capabilities(&self) -> BackendCapabilities {
+        let base_url = self.base_url.clone();
+        let cache = CAPABILITIES.get_or_init(|| Mutex::new(None));
+        if let Some((cached_url, capabilities)) = cache.lock().unwrap().as_ref() {
+            if *cached_url == base_url {
+                return capabilities.clone();
+            }
+        }
+
+        let capabilities = match self.fetch_capabilities().await {
+            Ok(capabilities) => capabilities,
+            Err(e) => {
+                println!("{:#}，使用默认参数", e);
+                return BackendCapabilities::default();
+            }
+        };
+        println!("后端能力: {:?}", capabilities);
+        *cache.lock().unwrap() = Some((base_url, capabilities.clone()));
+        capabilities
+    }
+
+    async fn fetch_capabilities(&self) -> Result<BackendCapabilities> {
+        let request = self.request(Method::GET, Endpoint::Capabilities)?;
+        let response = send_raw(request, "查询后端能力").await?;
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND || status == StatusCode::METHOD_NOT_ALLOWED {
+            return Ok(BackendCapabilities::default());
+        }
+        let response = check_response(response, "查询后端能力").await?;
+        parse_json(response, "查询后端能力").await
+    }
+
+    // 新任务使用的分片大小
+    pub async fn negotiate_chunk_size(&self) -> u64 {
+        let configured = settings::get().transfer.chunk_size_kb.saturating_mul(1024);
+        let chunk_size = choose_chunk_size(configured, &self.capabilities().await);
+        println!("分片大小: {} 字节", chunk_size);
+        chunk_size
+    }
+}
+
+// 解析HTTP日期（Last-Modified等响应头），返回Unix时间戳（秒）
+pub fn parse_http_date(value: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|t| t.timestamp())
+}
+
+pub fn new_request_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+// 放在响应的extensions里，check_response出错时带上
+#[derive(Debug, Clone)]
+struct RequestId(String);
+
+// 发送请求，只处理网络错误，状态码由调用方判断
+pub async fn send_raw(request: RequestBuilder, action: &str) -> Result<Response> {
+    let (client, request) = request.build_split();
+    let request = request.with_context(|| format!("{}失败", action))?;
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+    println!("[请求 {}] {} {} {}", request_id, action, request.method(), request.url().path());
+    let request_headers = request.headers().clone();
+
+    let mut response = client
+        .execute(request)
+        .await
+        .with_context(|| format!("{}失败（请求ID: {}）", action, request_id))?;
+    crate::auth::note_response_status(response.status(), &request_headers);
+    crate::clock_drift::note_response(response.status(), &request_headers, response.headers());
+    response.extensions_mut().insert(RequestId(request_id));
+    Ok(response)
+}
+
+// 检查响应状态，失败时带上后端返回的错误信息和请求ID
+pub async fn check_response(response: Response, action: &str) -> Result<Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    // 后端维护中，记下恢复时间，分片请求会等到那时再试（见server_maintenance.rs）
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| crate::server_maintenance::parse_retry_after(v, chrono::Utc::now().timestamp()));
+        if let Some(retry_after) = retry_after {
+            crate::server_maintenance::note_unavailable(retry_after);
+        }
+    }
+    let request_id = response.extensions().get::<RequestId>().map(|id| id.0.clone());
+    let error_text = response.text().await.unwrap_or_default();
+    match request_id {
+        Some(request_id) => Err(anyhow::anyhow!("{}失败: {} - {}（请求ID: {}）", action, status, error_text, request_id)),
+        None => Err(anyhow::anyhow!("{}失败: {} - {}", action, status, error_text)),
+    }
+}
+
+/// 后端返回的JSON和约定的格式对不上
+#[derive(Debug)]
+pub struct BackendProtocolError {
+    pub action: String,
+    pub field: Option<String>,   // 出问题的字段，比如upload_id、uploaded_chunks[2]；整个响应都不对时为None
+    pub message: String,
+}
+
+impl fmt::Display for BackendProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.field {
+            Some(field) => write!(f, "{}失败: 后端响应的字段 {} 格式不对: {}", self.action, field, self.message),
+            None => write!(f, "{}失败: 后端响应格式不对: {}", self.action, self.message),
+        }
+    }
+}
+
+impl std::error::Error for BackendProtocolError {}
+
+// 日志里的原始响应，太长的截断
+fn truncate_body(body: &str) -> String {
+    if body.len() <= RAW_BODY_LOG_LIMIT {
+        return body.to_string();
+    }
+    let mut end = RAW_BODY_LOG_LIMIT;
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}…（共 {} 字节）", &body[..end], body.len())
+}
+
+// 解析响应内容，出错时记下原始内容，返回带字段路径的BackendProtocolError
+pub fn parse_body<T: DeserializeOwned>(body: &str, action: &str) -> Result<T, BackendProtocolError> {
+    let deserializer = &mut serde_json::Deserializer::from_str(body);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        println!("[后端响应] {}的响应解析失败，原始内容: {}", action, truncate_body(body));
+        let message = e.inner().to_string();
+        let path = e.path().to_string();
+        // 缺字段时路径停在上一层，字段名在错误信息里
+        let missing = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.split('`').next());
+        let field = match (path.as_str(), missing) {
+            (".", Some(name)) => Some(name.to_string()),
+            (".", None) => None,
+            (path, Some(name)) => Some(format!("{}.{}", path, name)),
+            (path, None) => Some(path.to_string()),
+        };
+        BackendProtocolError { action: action.to_string(), field, message }
+    })
+}
+
+// 读出响应内容并解析
+pub async fn parse_json<T: DeserializeOwned>(response: Response, action: &str) -> Result<T> {
+    let body = response.text().await
+        .with_context(|| format!("{}失败: 读取响应失败", action))?;
+    Ok(parse_body(&body, action)?)
+}
+
+// 发送请求，非2xx当作错误
+pub async fn send(request: RequestBuilder, action: &str) -> Result<Response> {
+    let response = send_raw(request, action).await?;
+    check_response(response, action).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_path_parameters() {
+        assert_eq!(Endpoint::Download("ds/下载 1.png").path(), "/download/ds%2F%E4%B8%8B%E8%BD%BD%201.png");
+        assert_eq!(Endpoint::ChunkHashes("a b").path(), "/download/hashes/a%20b");
+        assert_eq!(Endpoint::File("dir/x").path(), "/files/dir%2Fx");
+        assert_eq!(Endpoint::Files.path(), "/files/");
+    }
+
+    #[test]
+    fn every_request_gets_its_own_id() {
+        crate::mock_backend::start();
+        let http = TransferHttp::new(crate::mock_backend::valid_auth()).unwrap();
+        let id_of = |request: RequestBuilder| {
+            request.build().unwrap().headers()[REQUEST_ID_HEADER].to_str().unwrap().to_string()
+        };
+        let first = id_of(http.request(Method::GET, Endpoint::Files).unwrap());
+        let second = id_of(http.request(Method::GET, Endpoint::Files).unwrap());
+        assert_eq!(first.len(), 32);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn protocol_errors_name_the_field() {
+        #[derive(Debug, Deserialize)]
+        struct Status {
+            #[allow(dead_code)]
+            chunks: Vec<u32>,
+        }
+
+        let err = parse_body::<Status>(r#"{"other": 1}"#, "查询").unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("chunks"));
+        let err = parse_body::<Status>(r#"{"chunks": [0, "1"]}"#, "查询").unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("chunks[1]"));
+        let err = parse_body::<Status>("<html>502</html>", "查询").unwrap_err();
+        assert_eq!(err.field, None);
+
+        let long = "错".repeat(RAW_BODY_LOG_LIMIT);
+        assert!(truncate_body(&long).ends_with(&format!("（共 {} 字节）", long.len())));
+    }
+
+    #[test]
+    fn parses_http_dates() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 +0000"), Some(784111777));
+        assert_eq!(parse_http_date("昨天"), None);
+    }
+
+    #[test]
+    fn chunk_size_prefers_setting_then_backend() {
+        let none = BackendCapabilities::default();
+        assert_eq!(choose_chunk_size(0, &none), chunks::DEFAULT_CHUNK_SIZE);
+        assert_eq!(choose_chunk_size(1024 * 1024, &none), 1024 * 1024);
+
+        let backend = BackendCapabilities {
+            min_chunk_size: Some(128 * 1024),
+            max_chunk_size: Some(4 * 1024 * 1024),
+            preferred_chunk_size: Some(2 * 1024 * 1024),
+            remote_fetch: false,
+        };
+        assert_eq!(choose_chunk_size(0, &backend), 2 * 1024 * 1024);
+        assert_eq!(choose_chunk_size(8 * 1024 * 1024, &backend), 4 * 1024 * 1024);
+        assert_eq!(choose_chunk_size(64 * 1024, &backend), 128 * 1024);
+    }
+
+    #[test]
+    fn chunk_size_stays_within_own_bounds() {
+        let bogus = BackendCapabilities {
+            min_chunk_size: Some(1),
+            max_chunk_size: Some(u64::MAX),
+            preferred_chunk_size: Some(1),
+            remote_fetch: false,
+        };
+        assert_eq!(choose_chunk_size(0, &bogus), chunks::MIN_CHUNK_SIZE);
+
+        let disjoint = BackendCapabilities {
+            min_chunk_size: Some(64 * 1024 * 1024),
+            max_chunk_size: Some(128 * 1024 * 1024),
+            preferred_chunk_size: None,
+            remote_fetch: false,
+        };
+        assert_eq!(choose_chunk_size(0, &disjoint), chunks::DEFAULT_CHUNK_SIZE);
+    }
+}
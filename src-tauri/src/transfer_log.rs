@@ -0,0 +1,67 @@
+// 单个传输任务的日志
+// 每个任务保留最近MAX_LOG_LINES行：分片重试、HTTP错误、暂停、出错、完成，前端用get_transfer_log查看
+//
+// 思考：一个任务失败了，原因在全局日志里和几十个别的任务的输出混在一起，用户根本找不到。
+// 任务自己再记一份，只记对排查有用的事件（不记每个分片成功），
+// 自动重试时新任务接着用旧任务的日志，能看到前面几次失败的原因；
+// 任务从任务表清理掉时，没成功的任务把日志一起写进传输历史。
+// 写进来的内容和全局日志一样先打码。
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+
+// 每个任务最多保留的行数
+const MAX_LOG_LINES: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogLine {
+    pub at: i64,          // 毫秒时间戳
+    pub message: String,
+}
+
+#[derive(Default)]
+pub struct TransferLog {
+    lines: Mutex<VecDeque<LogLine>>,
+}
+
+impl TransferLog {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn push(&self, message: &str) {
+        let line = LogLine {
+            at: chrono::Utc::now().timestamp_millis(),
+            message: auth::redact(message),
+        };
+        let mut lines = self.lines.lock().unwrap();
+        lines.push_back(line);
+        while lines.len() > MAX_LOG_LINES {
+            lines.pop_front();
+        }
+    }
+
+    pub fn lines(&self) -> Vec<LogLine> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_latest_lines() {
+        let log = TransferLog::new();
+        for i in 0..MAX_LOG_LINES + 3 {
+            log.push(&format!("第{}行", i));
+        }
+        let lines = log.lines();
+        assert_eq!(lines.len(), MAX_LOG_LINES);
+        assert_eq!(lines[0].message, "第3行");
+        assert_eq!(lines.last().unwrap().message, format!("第{}行", MAX_LOG_LINES + 2));
+    }
+}
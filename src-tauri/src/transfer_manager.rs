@@ -0,0 +1,747 @@
+// 传输管理器
+// 统一管理所有下载/上传任务：任务注册表、后台执行、失败后自动重试、清理已结束的任务
+//
+// 思考：原来任务表是lib.rs里的两个全局变量，每个命令自己tokio::spawn一下，
+// 失败了任务就一直停在Error状态。现在后台执行都走这里，
+// 失败后按设置里的重试策略（次数/退避时间）重新创建任务继续传，
+// 下载靠已经写到磁盘的部分续传，上传复用原来的upload_id，由服务器告诉我们还缺哪些分片。
+// 同时进行的任务数由concurrency.rs限制，超出的任务在Pending状态排队。
+// 上传和下载同时进行时按设置的比例分带宽（方向调度，见下面DirectionGuard）。
+
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use tokio::sync::{Mutex, Notify};
+
+use crate::download::{DownloadTask, DownloadStatus};
+use crate::upload::{UploadTask, UploadStatus};
+use crate::metrics::{self, Counter};
+use crate::rate_limiter::RateLimiter;
+use crate::settings::DirectionSchedule;
+use crate::transfer_history::{self, HistoryEntry};
+use crate::transfer_queue::Direction;
+use crate::{concurrency, cpen_device_manager, event_emitter, settings, supervisor, sync_state};
+
+// 下载任务表，file_id -> 任务
+static DOWNLOAD_TASKS: OnceLock<Mutex<HashMap<String, Arc<DownloadTask>>>> = OnceLock::new();
+// 上传任务表，upload_id -> 任务
+static UPLOAD_TASKS: OnceLock<Mutex<HashMap<String, Arc<UploadTask>>>> = OnceLock::new();
+
+pub fn download_tasks() -> &'static Mutex<HashMap<String, Arc<DownloadTask>>> {
+    DOWNLOAD_TASKS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn upload_tasks() -> &'static Mutex<HashMap<String, Arc<UploadTask>>> {
+    UPLOAD_TASKS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// 任务已经在执行中，又调用了一次start()
+// 两个start()同时跑会交替处理分片，状态和进度都会乱掉，后来的调用直接返回这个错误
+#[derive(Debug)]
+pub struct TaskAlreadyRunning;
+
+impl std::fmt::Display for TaskAlreadyRunning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "任务已经在执行中")
+    }
+}
+
+impl std::error::Error for TaskAlreadyRunning {}
+
+// 传输优先级
+// High是用户点击触发的下载，Low是后台同步/镜像这类不着急的下载
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferPriority {
+    #[default]
+    High,
+    Low,
+}
+
+impl TransferPriority {
+    // 解析前端传来的优先级字符串，不认识的按High处理
+    pub fn parse(value: Option<&str>) -> Self {
+        match value.map(|v| v.to_ascii_lowercase()) {
+            Some(v) if v == "low" => TransferPriority::Low,
+            _ => TransferPriority::High,
+        }
+    }
+}
+
+// 正在进行的高优先级下载数量
+// 思考：现在没有统一的调度队列，所有任务都是各自spawn出去跑的。
+// 低优先级任务每下载一个分片前检查一下，有高优先级任务在跑就先停下来等，
+// 带宽全部让给用户手动点的下载，高优先级任务都结束后再继续。
+static HIGH_PRIORITY_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static PRIORITY_NOTIFY: OnceLock<Notify> = OnceLock::new();
+
+fn priority_notify() -> &'static Notify {
+    PRIORITY_NOTIFY.get_or_init(Notify::new)
+}
+
+// 高优先级任务运行期间持有，drop时唤醒等待中的低优先级任务
+pub struct HighPriorityGuard;
+
+impl HighPriorityGuard {
+    pub fn new() -> Self {
+        HIGH_PRIORITY_ACTIVE.fetch_add(1, Ordering::SeqCst);
+        HighPriorityGuard
+    }
+}
+
+impl Drop for HighPriorityGuard {
+    fn drop(&mut self) {
+        if HIGH_PRIORITY_ACTIVE.fetch_sub(1, Ordering::SeqCst) == 1 {
+            priority_notify().notify_waiters();
+        }
+    }
+}
+
+pub fn is_high_priority_active() -> bool {
+    HIGH_PRIORITY_ACTIVE.load(Ordering::SeqCst) > 0
+}
+
+// 低优先级任务在这里等待，直到没有高优先级任务在运行
+// 返回是否等待过
+pub async fn yield_to_high_priority() -> bool {
+    let mut waited = false;
+    loop {
+        // 先注册等待再检查计数，避免错过中间发出的通知
+        let notified = priority_notify().notified();
+        if !is_high_priority_active() {
+            return waited;
+        }
+        waited = true;
+        notified.await;
+    }
+}
+
+// 正在传的下载/上传任务数
+// 思考：上传和下载同时进行时，下载分片又大又快，在上下行不对称的链路上会把上传挤得几乎不动。
+// 任务传输期间持有DirectionGuard，两个方向都有任务在传时，共享限速器（rate_limiter.rs）
+// 按transfer.direction_schedule的比例（比如70/30）给两个方向分限速；只有一个方向在传时不分。
+// 限速来自计费网络、后台模式，以及这里的链路限速（link_kbps），都没有时比例不起作用。
+static ACTIVE_DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+static ACTIVE_UPLOADS: AtomicUsize = AtomicUsize::new(0);
+// 方向调度的链路限速器
+static LINK_LIMITER: RateLimiter = RateLimiter::new();
+
+fn active_count(direction: Direction) -> &'static AtomicUsize {
+    match direction {
+        Direction::Download => &ACTIVE_DOWNLOADS,
+        Direction::Upload => &ACTIVE_UPLOADS,
+    }
+}
+
+// 任务传输期间持有，算这个方向在传
+pub struct DirectionGuard(Direction);
+
+impl DirectionGuard {
+    pub fn new(direction: Direction) -> Self {
+        active_count(direction).fetch_add(1, Ordering::SeqCst);
+        DirectionGuard(direction)
+    }
+}
+
+impl Drop for DirectionGuard {
+    fn drop(&mut self) {
+        active_count(self.0).fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// 按调度设置和两个方向在传的任务数，算这个方向分到限速的多少（0到1）
+fn share_of(direction: Direction, schedule: &DirectionSchedule, downloads: usize, uploads: usize) -> f64 {
+    let other_active = match direction {
+        Direction::Download => uploads > 0,
+        Direction::Upload => downloads > 0,
+    };
+    if !schedule.enabled || !other_active {
+        return 1.0;
+    }
+    let download = schedule.download_percent.clamp(1, 99) as f64 / 100.0;
+    match direction {
+        Direction::Download => download,
+        Direction::Upload => 1.0 - download,
+    }
+}
+
+// 这个方向现在分到限速的多少，限速器按这个比例缩小速度上限
+pub fn direction_share(direction: Direction) -> f64 {
+    share_of(
+        direction,
+        &settings::get().transfer.direction_schedule,
+        ACTIVE_DOWNLOADS.load(Ordering::SeqCst),
+        ACTIVE_UPLOADS.load(Ordering::SeqCst),
+    )
+}
+
+// 传完一个分片后调用：计费网络、后台模式和方向调度的链路限速同时记账，
+// 只等其中最晚的那个时间，不是一个等完再等下一个（见rate_limiter.rs思考4）
+pub async fn throttle(direction: Direction, bytes: u64) {
+    let schedule = settings::get().transfer.direction_schedule;
+    let link = if schedule.enabled { LINK_LIMITER.reserve(direction, bytes, schedule.link_kbps) } else { None };
+    let send_at = [
+        crate::network_profile::reserve(direction, bytes),
+        crate::background_mode::reserve(direction, bytes),
+        link,
+    ].into_iter().flatten().max();
+    if let Some(send_at) = send_at {
+        tokio::time::sleep_until(send_at).await;
+    }
+}
+
+// 任务开始/结束时发transfer-status事件，窗口可以按任务订阅（见event_emitter::subscribe_transfer_events）
+fn emit_status(direction: &str, id: &str, status: &str, error: Option<String>) {
+    event_emitter::emit_transfer_event("transfer-status", serde_json::json!({
+        "direction": direction,
+        "id": id,
+        "status": status,
+        "error": error,
+    }));
+}
+
+// 在后台执行下载任务，失败后按重试策略自动重试
+pub fn spawn_download(task: Arc<DownloadTask>) {
+    supervisor::spawn(format!("download:{}", task.file_id()), async move {
+        let file_id = task.file_id().to_string();
+        // 排队等并发名额，等待期间任务还是Pending
+        let permit = concurrency::acquire().await;
+        if matches!(task.get_progress().await.status, DownloadStatus::Paused) {
+            println!("下载任务 {} 排队时已暂停", file_id);
+            return;
+        }
+        println!("后台下载任务开始: {}", file_id);
+        emit_status("download", &file_id, "started", None);
+
+        // 传输期间要不时找笔拿TOTP，保持蓝牙连接不睡眠
+        let session = cpen_device_manager::open_session();
+        // 跟随模式下本地已经有完整文件的任务（已经是Completed）不用再下载，直接跟随
+        let started_at = Instant::now();
+        let progress = task.get_progress().await;
+        let direction = DirectionGuard::new(Direction::Download);
+        let result = match progress.status {
+            DownloadStatus::Completed => Ok(()),
+            _ => task.start().await,
+        };
+        drop(direction);
+        let downloaded = task.get_progress().await.downloaded.saturating_sub(progress.downloaded);
+        metrics::record_download_throughput(downloaded, started_at.elapsed());
+        drop(permit);
+        // 跟随期间不占并发名额，蓝牙会话也不一直开着，每次检查时follow自己打开（见DownloadTask::follow）
+        drop(session);
+        let result = match result {
+            Ok(()) if task.follow_enabled() => {
+                emit_status("download", &file_id, "following", None);
+                task.follow().await
+            }
+            result => result,
+        };
+        match result {
+            Ok(_) => {
+                // 暂停时start()也返回Ok，只有真的完成了才计数
+                if matches!(task.get_progress().await.status, DownloadStatus::Completed) {
+                    metrics::record(Counter::DownloadsCompleted);
+                    sync_state::mark_synced(&file_id, task.sha256().map(str::to_string)).await;
+                    emit_status("download", &file_id, "completed", None);
+                } else {
+                    emit_status("download", &file_id, "paused", None);
+                }
+                println!("后台下载完成: {}，保存到: {:?}", file_id, task.save_path());
+            }
+            Err(e) if e.is::<TaskAlreadyRunning>() => {
+                println!("下载任务 {} 已经在执行中，不重复启动", file_id);
+            }
+            Err(e) => {
+                println!("后台下载失败: {}，错误: {}", file_id, e);
+                metrics::record(Counter::DownloadsFailed);
+                emit_status("download", &file_id, "failed", Some(e.to_string()));
+                auto_retry_download(task).await;
+            }
+        }
+    });
+}
+
+// 在后台执行上传任务，失败后按重试策略自动重试
+pub fn spawn_upload(task: Arc<UploadTask>) {
+    supervisor::spawn(format!("upload:{}", task.upload_id()), async move {
+        let upload_id = task.upload_id().to_string();
+        let permit = concurrency::acquire().await;
+        if matches!(task.get_progress().await.status, UploadStatus::Paused) {
+            println!("上传任务 {} 排队时已暂停", upload_id);
+            return;
+        }
+        println!("后台上传任务开始: {}", upload_id);
+        emit_status("upload", &upload_id, "started", None);
+
+        let session = cpen_device_manager::open_session();
+        let started_at = Instant::now();
+        let uploaded_before = task.get_progress().await.uploaded;
+        let direction = DirectionGuard::new(Direction::Upload);
+        let result = task.start().await;
+        drop(direction);
+        let uploaded = task.get_progress().await.uploaded.saturating_sub(uploaded_before);
+        metrics::record_upload_throughput(uploaded, started_at.elapsed());
+        drop(session);
+        drop(permit);
+        match result {
+            Ok(_) => {
+                match task.get_progress().await.status {
+                    UploadStatus::Completed => {
+                        metrics::record(Counter::UploadsCompleted);
+                        emit_status("upload", &upload_id, "completed", None);
+                    }
+                    UploadStatus::FileInUse => emit_status("upload", &upload_id, "file_in_use", None),
+                    _ => emit_status("upload", &upload_id, "paused", None),
+                }
+                println!("后台上传完成: {}", upload_id);
+            }
+            Err(e) if e.is::<TaskAlreadyRunning>() => {
+                println!("上传任务 {} 已经在执行中，不重复启动", upload_id);
+            }
+            Err(e) => {
+                println!("后台上传失败: {}，错误: {}", upload_id, e);
+                metrics::record(Counter::UploadsFailed);
+                emit_status("upload", &upload_id, "failed", Some(e.to_string()));
+                auto_retry_upload(task).await;
+            }
+        }
+    });
+}
+
+// 退出前暂停所有进行中的传输，任务会把已下载的分片落盘后返回
+pub async fn pause_active() {
+    let downloads: Vec<_> = download_tasks().lock().await.values().cloned().collect();
+    for task in downloads {
+        if matches!(task.get_progress().await.status, DownloadStatus::Pending | DownloadStatus::Downloading | DownloadStatus::WaitingForServer(_) | DownloadStatus::Verifying(_) | DownloadStatus::Following) {
+            task.pause().await;
+        }
+    }
+
+    let uploads: Vec<_> = upload_tasks().lock().await.values().cloned().collect();
+    for task in uploads {
+        if matches!(task.get_progress().await.status, UploadStatus::Pending | UploadStatus::Uploading | UploadStatus::WaitingForServer(_)) {
+            task.pause().await;
+        }
+    }
+}
+
+// ids里还在执行start()的任务数（排队等并发名额的不算）
+pub async fn running_among(ids: &[String]) -> usize {
+    let downloads = download_tasks().lock().await.iter()
+        .filter(|(id, task)| ids.contains(id) && task.is_running())
+        .count();
+    let uploads = upload_tasks().lock().await.iter()
+        .filter(|(id, task)| ids.contains(id) && task.is_running())
+        .count();
+    downloads + uploads
+}
+
+// 暂停排队中（queued）和正在传（in_flight）的任务，返回暂停了的任务ID
+pub async fn pause_transfers(queued: bool, in_flight: bool) -> Vec<String> {
+    let mut paused = Vec::new();
+    let downloads: Vec<_> = download_tasks().lock().await.values().cloned().collect();
+    for task in downloads {
+        let status = task.get_progress().await.status;
+        let active = matches!(status, DownloadStatus::Downloading | DownloadStatus::WaitingForServer(_) | DownloadStatus::Verifying(_));
+        if (queued && matches!(status, DownloadStatus::Pending)) || (in_flight && active) {
+            task.pause().await;
+            paused.push(task.file_id().to_string());
+        }
+    }
+
+    let uploads: Vec<_> = upload_tasks().lock().await.values().cloned().collect();
+    for task in uploads {
+        let status = task.get_progress().await.status;
+        let active = matches!(status, UploadStatus::Uploading | UploadStatus::WaitingForServer(_));
+        if (queued && matches!(status, UploadStatus::Pending)) || (in_flight && active) {
+            task.pause().await;
+            paused.push(task.upload_id().to_string());
+        }
+    }
+    paused
+}
+
+// 检查任务表里的是不是还是这个任务（用户可能已经手动重试过了）
+async fn is_current_download(task: &Arc<DownloadTask>) -> bool {
+    download_tasks()
+        .lock()
+        .await
+        .get(task.file_id())
+        .is_some_and(|current| Arc::ptr_eq(current, task))
+}
+
+async fn is_current_upload(task: &Arc<UploadTask>) -> bool {
+    upload_tasks()
+        .lock()
+        .await
+        .get(task.upload_id())
+        .is_some_and(|current| Arc::ptr_eq(current, task))
+}
+
+async fn auto_retry_download(failed: Arc<DownloadTask>) {
+    let policy = settings::get().retry;
+    let mut attempt = failed.retry_count();
+
+    loop {
+        attempt += 1;
+        if !policy.auto_retry || attempt > policy.max_attempts {
+            failed.note(format!("下载任务 {} 不再自动重试（已重试 {} 次）", failed.file_id(), attempt - 1));
+            return;
+        }
+
+        let backoff = policy.backoff_for(attempt);
+        failed.note(format!("下载任务 {} 将在 {:?} 后自动重试（第 {}/{} 次）",
+            failed.file_id(), backoff, attempt, policy.max_attempts));
+        tokio::time::sleep(backoff).await;
+
+        if !is_current_download(&failed).await {
+            println!("下载任务 {} 已被替换，取消自动重试", failed.file_id());
+            return;
+        }
+
+        match recreate_download(&failed, attempt).await {
+            Ok(task) => {
+                spawn_download(task);
+                return;
+            }
+            Err(e) => {
+                // 重新创建都失败了（比如网络还没恢复），记下次数继续等
+                failed.note(format!("下载任务 {} 自动重试失败: {}", failed.file_id(), e));
+                failed.set_retry_count(attempt);
+            }
+        }
+    }
+}
+
+async fn auto_retry_upload(failed: Arc<UploadTask>) {
+    let policy = settings::get().retry;
+    let mut attempt = failed.retry_count();
+
+    loop {
+        attempt += 1;
+        if !policy.auto_retry || attempt > policy.max_attempts {
+            failed.note(format!("上传任务 {} 不再自动重试（已重试 {} 次）", failed.upload_id(), attempt - 1));
+            return;
+        }
+
+        let backoff = policy.backoff_for(attempt);
+        failed.note(format!("上传任务 {} 将在 {:?} 后自动重试（第 {}/{} 次）",
+            failed.upload_id(), backoff, attempt, policy.max_attempts));
+        tokio::time::sleep(backoff).await;
+
+        if !is_current_upload(&failed).await {
+            println!("上传任务 {} 已被替换，取消自动重试", failed.upload_id());
+            return;
+        }
+
+        match recreate_upload(&failed, attempt).await {
+            Ok(task) => {
+                spawn_upload(task);
+                return;
+            }
+            Err(e) => {
+                failed.note(format!("上传任务 {} 自动重试失败: {}", failed.upload_id(), e));
+                failed.set_retry_count(attempt);
+            }
+        }
+    }
+}
+
+// 重新创建下载任务并替换任务表里的旧任务
+// 已经写到磁盘的部分会在start()里续传
+async fn recreate_download(old: &Arc<DownloadTask>, retry_count: u32) -> Result<Arc<DownloadTask>, String> {
+    // TOTP只有30秒有效期，重试时必须重新获取认证信息
+    let auth_info = crate::acquire_auth_info().await?;
+
+    let mut task = DownloadTask::new(old.file_id().to_string(), old.save_path().to_path_buf(), auth_info)
+        .await
+        .map_err(|e| format!("创建下载任务失败: {}", e))?;
+    task.inherit_log(old);
+    task.set_queue_seq(old.queue_seq());
+    task.set_retry_count(retry_count);
+    task.set_priority(old.priority().await).await;
+    task.set_follow(old.follow_enabled());
+
+    let task = Arc::new(task);
+    download_tasks().lock().await.insert(old.file_id().to_string(), task.clone());
+    Ok(task)
+}
+
+// 复用原来的upload_id（和当前的上传会话）重新创建上传任务
+async fn recreate_upload(old: &Arc<UploadTask>, retry_count: u32) -> Result<Arc<UploadTask>, String> {
+    let auth_info = crate::acquire_auth_info().await?;
+
+    let mut task = UploadTask::resume_session(
+        old.file_path().to_path_buf(),
+        auth_info,
+        old.target_path(),
+        old.upload_id().to_string(),
+        old.chunk_size(),
+    )
+        .await
+        .map_err(|e| format!("创建上传任务失败: {}", e))?;
+    task.set_session_id(old.session_id());
+    task.restore_completed_chunks(&old.completed_chunk_list());
+    task.inherit_log(old);
+    task.set_queue_seq(old.queue_seq());
+    task.set_retry_count(retry_count);
+
+    let task = Arc::new(task);
+    upload_tasks().lock().await.insert(old.upload_id().to_string(), task.clone());
+    Ok(task)
+}
+
+// 手动重试传输任务
+// id可以是下载任务的file_id或上传任务的upload_id，只有出错或暂停的任务可以重试
+// 返回任务方向（"download"/"upload"）和新的重试次数
+pub async fn retry_transfer(id: &str) -> Result<(&'static str, u32), String> {
+    restart_transfer(id, true).await
+}
+
+// 重新创建任务并开始，count_as_retry为false时不算重试次数（比如切换后端后在新后端上继续）
+pub async fn restart_transfer(id: &str, count_as_retry: bool) -> Result<(&'static str, u32), String> {
+    let bump = u32::from(count_as_retry);
+    let download = download_tasks().lock().await.get(id).cloned();
+    if let Some(old) = download {
+        match old.get_progress().await.status {
+            DownloadStatus::Error(_) | DownloadStatus::Paused => {}
+            _ => return Err(format!("下载任务 {} 没有失败，不需要重试", id)),
+        }
+        let task = recreate_download(&old, old.retry_count() + bump).await?;
+        let retry_count = task.retry_count();
+        spawn_download(task);
+        return Ok(("download", retry_count));
+    }
+
+    let upload = upload_tasks().lock().await.get(id).cloned();
+    if let Some(old) = upload {
+        match old.get_progress().await.status {
+            UploadStatus::Error(_) | UploadStatus::Paused | UploadStatus::FileInUse => {}
+            _ => return Err(format!("上传任务 {} 没有失败，不需要重试", id)),
+        }
+        let task = recreate_upload(&old, old.retry_count() + bump).await?;
+        let retry_count = task.retry_count();
+        spawn_upload(task);
+        return Ok(("upload", retry_count));
+    }
+
+    Err(format!("传输任务不存在: {}", id))
+}
+
+// 已结束任务的清理
+// 任务本身不记结束时间，清理时第一次看到任务已结束就记下时间。
+// 超过finished_task_ttl_mins，或者已结束的任务超过max_finished_tasks时，先写到传输历史再从任务表删掉。
+// 计时期间被重试（状态不再是已结束）的任务重新计时。
+
+// 多久清理一次
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// (方向, 任务ID)，下载的file_id和上传的upload_id可能重复
+type TaskKey = (&'static str, String);
+
+struct FinishedMark {
+    since: Instant,
+    at: i64,    // 记录到历史里的结束时间
+}
+
+static FINISHED_MARKS: OnceLock<std::sync::Mutex<HashMap<TaskKey, FinishedMark>>> = OnceLock::new();
+
+fn finished_marks() -> &'static std::sync::Mutex<HashMap<TaskKey, FinishedMark>> {
+    FINISHED_MARKS.get_or_init(|| std::sync::Mutex::new(HashMap::new()))
+}
+
+// 选出要清理的任务：超过保留时间的，加上超出数量上限的最早结束的那些
+fn select_expired<K: Clone>(finished: &[(K, Instant)], now: Instant, ttl: Duration, max_finished: usize) -> Vec<K> {
+    let mut sorted: Vec<&(K, Instant)> = finished.iter().collect();
+    sorted.sort_by_key(|(_, since)| *since);
+    let over_limit = sorted.len().saturating_sub(max_finished);
+
+    sorted
+        .into_iter()
+        .enumerate()
+        .filter(|(index, (_, since))| *index < over_limit || now.duration_since(*since) >= ttl)
+        .map(|(_, (key, _))| key.clone())
+        .collect()
+}
+
+// 已结束的任务，finished_at等真正清理时再填
+enum FinishedTask {
+    Download(Arc<DownloadTask>, HistoryEntry),
+    Upload(Arc<UploadTask>, HistoryEntry),
+}
+
+async fn collect_finished() -> HashMap<TaskKey, FinishedTask> {
+    let mut finished = HashMap::new();
+
+    let downloads: Vec<_> = download_tasks().lock().await.values().cloned().collect();
+    for task in downloads {
+        let progress = task.get_progress().await;
+        let (status, error) = match progress.status {
+            DownloadStatus::Completed => ("completed", None),
+            DownloadStatus::Error(e) => ("error", Some(e)),
+            _ => continue,
+        };
+        let entry = HistoryEntry {
+            direction: "download".to_string(),
+            id: progress.file_id.clone(),
+            file_name: progress.file_name,
+            local_path: task.save_path().to_string_lossy().to_string(),
+            total_size: progress.total_size,
+            status: status.to_string(),
+            error,
+            finished_at: 0,
+            log: if status == "completed" { Vec::new() } else { task.log_lines() },
+        };
+        finished.insert(("download", progress.file_id), FinishedTask::Download(task, entry));
+    }
+
+    let uploads: Vec<_> = upload_tasks().lock().await.values().cloned().collect();
+    for task in uploads {
+        let progress = task.get_progress().await;
+        let (status, error) = match progress.status {
+            UploadStatus::Completed => ("completed", None),
+            UploadStatus::FileInUse => ("file_in_use", None),
+            UploadStatus::Error(e) => ("error", Some(e)),
+            _ => continue,
+        };
+        let entry = HistoryEntry {
+            direction: "upload".to_string(),
+            id: progress.upload_id.clone(),
+            file_name: progress.filename,
+            local_path: task.file_path().to_string_lossy().to_string(),
+            total_size: progress.total_size,
+            status: status.to_string(),
+            error,
+            finished_at: 0,
+            log: if status == "completed" { Vec::new() } else { task.log_lines() },
+        };
+        finished.insert(("upload", progress.upload_id), FinishedTask::Upload(task, entry));
+    }
+
+    finished
+}
+
+// 清理一次，返回清理掉的任务数
+pub async fn prune_finished_tasks() -> usize {
+    let policy = settings::get().transfer;
+    let ttl = Duration::from_secs(policy.finished_task_ttl_mins * 60);
+    let now = Instant::now();
+    let mut finished = collect_finished().await;
+
+    let expired: Vec<(TaskKey, i64)> = {
+        let mut marks = finished_marks().lock().unwrap();
+        marks.retain(|key, _| finished.contains_key(key));
+        let unix_now = chrono::Utc::now().timestamp();
+        for key in finished.keys() {
+            marks.entry(key.clone()).or_insert(FinishedMark { since: now, at: unix_now });
+        }
+
+        let candidates: Vec<(TaskKey, Instant)> = marks.iter().map(|(key, mark)| (key.clone(), mark.since)).collect();
+        select_expired(&candidates, now, ttl, policy.max_finished_tasks)
+            .into_iter()
+            .map(|key| {
+                let at = marks[&key].at;
+                (key, at)
+            })
+            .collect()
+    };
+    if expired.is_empty() {
+        return 0;
+    }
+
+    let mut tasks = Vec::new();
+    for (key, at) in &expired {
+        if let Some(task) = finished.remove(key) {
+            tasks.push((key.clone(), *at, task));
+        }
+    }
+    let entries: Vec<HistoryEntry> = tasks
+        .iter()
+        .map(|(_, at, task)| {
+            let mut entry = match task {
+                FinishedTask::Download(_, entry) | FinishedTask::Upload(_, entry) => entry.clone(),
+            };
+            entry.finished_at = *at;
+            entry
+        })
+        .collect();
+
+    // 先写历史再删任务，写失败就留到下次
+    if let Err(e) = transfer_history::append(&entries).await {
+        println!("保存传输历史失败，暂不清理已结束的任务: {:#}", e);
+        return 0;
+    }
+
+    let mut removed = 0;
+    for (key, _, task) in tasks {
+        // 期间被重试替换掉的任务不删
+        let is_same = match &task {
+            FinishedTask::Download(task, _) => {
+                let mut registry = download_tasks().lock().await;
+                let same = registry.get(&key.1).is_some_and(|current| Arc::ptr_eq(current, task));
+                if same {
+                    registry.remove(&key.1);
+                }
+                same
+            }
+            FinishedTask::Upload(task, _) => {
+                let mut registry = upload_tasks().lock().await;
+                let same = registry.get(&key.1).is_some_and(|current| Arc::ptr_eq(current, task));
+                if same {
+                    registry.remove(&key.1);
+                }
+                same
+            }
+        };
+        finished_marks().lock().unwrap().remove(&key);
+        if is_same {
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        println!("已清理 {} 个已结束的传输任务", removed);
+    }
+    removed
+}
+
+// 定时清理已结束的任务
+pub async fn prune_loop() {
+    loop {
+        tokio::time::sleep(PRUNE_INTERVAL).await;
+        prune_finished_tasks().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_old_and_over_limit_tasks() {
+        let now = Instant::now();
+        let ago = |secs: u64| now - Duration::from_secs(secs);
+        let finished = vec![("a", ago(10)), ("b", ago(600)), ("c", ago(30)), ("d", ago(20))];
+
+        let mut expired = select_expired(&finished, now, Duration::from_secs(300), 10);
+        assert_eq!(expired, vec!["b"]);
+
+        // 数量超过上限时，先清理最早结束的
+        expired = select_expired(&finished, now, Duration::from_secs(300), 2);
+        assert_eq!(expired, vec!["b", "c"]);
+
+        expired = select_expired(&finished, now, Duration::from_secs(300), 0);
+        assert_eq!(expired.len(), 4);
+    }
+
+    #[test]
+    fn splits_bandwidth_only_when_both_directions_active() {
+        let schedule = DirectionSchedule { enabled: true, ..DirectionSchedule::default() };
+        assert_eq!(share_of(Direction::Download, &schedule, 2, 1), 0.7);
+        assert!((share_of(Direction::Upload, &schedule, 2, 1) - 0.3).abs() < 1e-9);
+        // 另一个方向没有任务时独占
+        assert_eq!(share_of(Direction::Upload, &schedule, 0, 3), 1.0);
+        assert_eq!(share_of(Direction::Download, &DirectionSchedule::default(), 2, 1), 1.0);
+    }
+}
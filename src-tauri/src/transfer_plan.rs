@@ -0,0 +1,378 @@
+// 传输计划（dry run）
+// 批量操作真正执行前，先算出要传哪些文件、一共多少字节、哪些会覆盖云端已有的文件、哪些会被删除，
+// 让用户确认后再执行
+//
+// 思考：一次选几百个文件上传，传到一半才发现覆盖了云端的同名文件，或者有一堆文件被跳过，已经晚了。
+// 计划只读本地文件信息和一次目标目录的列表，不创建上传会话、不改任何东西。
+// 判断跳过的规则和真正上传时一致（upload::check_source），计划里能传的文件执行时也能传。
+// 上传不会删除云端文件，deletions留给以后的同步用。
+// 文件夹上传在遍历时就按排除规则过滤，排除的目录整个不进去，node_modules这种目录很大，遍历一遍都要很久。
+// 符号链接的目录不进去，避免链接成环一直遍历下去。
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::fs;
+
+use crate::auth::AuthInfo;
+use crate::cloud_api::{self, RemoteEntry};
+use crate::settings::SymlinkPolicy;
+use crate::transfer_filter::TransferFilter;
+use crate::upload::{self, SourceCheck};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanAction {
+    Upload,
+    Skip,
+}
+
+// 和云端已有文件的冲突，执行时会覆盖
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanConflict {
+    pub remote_size: u64,
+    pub remote_modified_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedFile {
+    pub local_path: String,
+    pub remote_dir: Option<String>,          // 上传到的云端目录，None是根目录
+    pub remote_path: String,
+    pub size: u64,
+    pub action: PlanAction,
+    pub reason: Option<String>,              // 跳过的原因
+    pub conflict: Option<PlanConflict>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TransferPlan {
+    pub files: Vec<PlannedFile>,
+    pub transfer_count: usize,
+    pub total_bytes: u64,
+    pub skipped_count: usize,
+    pub conflict_count: usize,
+    pub deletions: Vec<String>,              // 会被删除的云端路径
+    pub create_dirs: Vec<String>,            // 要新建的云端目录，父目录在前
+}
+
+impl TransferPlan {
+    fn push(&mut self, file: PlannedFile) {
+        match file.action {
+            PlanAction::Upload => {
+                self.transfer_count += 1;
+                self.total_bytes += file.size;
+            }
+            PlanAction::Skip => self.skipped_count += 1,
+        }
+        self.files.push(file);
+    }
+
+    // 要上传文件的云端目录，检查冲突时逐个列出
+    pub fn remote_dirs(&self) -> Vec<Option<String>> {
+        let dirs: BTreeSet<Option<String>> = self.files
+            .iter()
+            .filter(|file| file.action == PlanAction::Upload)
+            .map(|file| file.remote_dir.clone())
+            .collect();
+        dirs.into_iter().collect()
+    }
+
+    // 用云端目录现有的内容标记会覆盖的文件
+    pub fn mark_conflicts(&mut self, remote_dir: Option<&str>, remote_entries: &[RemoteEntry]) {
+        let existing: HashMap<&str, &RemoteEntry> = remote_entries
+            .iter()
+            .filter(|entry| !entry.is_dir)
+            .map(|entry| (entry.name.as_str(), entry))
+            .collect();
+
+        for file in &mut self.files {
+            if file.action != PlanAction::Upload || file.remote_dir.as_deref() != remote_dir {
+                continue;
+            }
+            let name = file.remote_path.rsplit('/').next().unwrap_or(&file.remote_path);
+            if let Some(entry) = existing.get(name) {
+                if file.conflict.is_none() {
+                    self.conflict_count += 1;
+                }
+                file.conflict = Some(PlanConflict {
+                    remote_size: entry.size,
+                    remote_modified_at: entry.modified_timestamp(),
+                });
+            }
+        }
+    }
+
+    fn log_summary(&self) {
+        println!("上传计划: {} 个文件 {} 字节，跳过 {} 个",
+            self.transfer_count, self.total_bytes, self.skipped_count);
+    }
+}
+
+// 按计划建出云端目录
+// 已经存在的目录后端会报错，忽略就行，真建不出来后面的文件上传会失败
+pub async fn create_remote_dirs(auth_info: &AuthInfo, plan: &TransferPlan) {
+    for dir in &plan.create_dirs {
+        let (parent, name) = match dir.rsplit_once('/') {
+            Some((parent, name)) => (parent, name),
+            None => ("", dir.as_str()),
+        };
+        if let Err(e) = cloud_api::create_dir(auth_info, parent, name).await {
+            println!("创建目录 {} 失败（可能已存在）: {}", dir, e);
+        }
+    }
+}
+
+// 去掉首尾的"/"，空路径当作根目录
+fn normalize_dir(dir: Option<&str>) -> Option<String> {
+    dir.map(|p| p.trim_matches('/'))
+        .filter(|p| !p.is_empty())
+        .map(|p| p.to_string())
+}
+
+// 目录下的路径
+fn join_remote(dir: Option<&str>, name: &str) -> String {
+    match dir {
+        Some(dir) => format!("{}/{}", dir, name),
+        None => name.to_string(),
+    }
+}
+
+// 检查一个要上传的文件，返回大小、动作和跳过原因
+async fn check_file(path: &Path, symlink_policy: SymlinkPolicy) -> (u64, PlanAction, Option<String>) {
+    match upload::check_source(path, symlink_policy).await {
+        Ok(SourceCheck::Regular(size)) => (size, PlanAction::Upload, None),
+        Ok(SourceCheck::SkippedSymlink) => (0, PlanAction::Skip, Some("符号链接，按设置跳过".to_string())),
+        Err(e) => (0, PlanAction::Skip, Some(format!("{:#}", e))),
+    }
+}
+
+// 批量上传的计划，所有文件都传到target_path下
+// 会覆盖哪些文件要再用mark_conflicts标记
+pub async fn plan_upload(
+    file_paths: &[String],
+    target_path: Option<&str>,
+    symlink_policy: SymlinkPolicy,
+) -> TransferPlan {
+    let remote_dir = normalize_dir(target_path);
+
+    let mut plan = TransferPlan::default();
+    for file_path in file_paths {
+        let path = Path::new(file_path);
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let (size, action, reason) = check_file(path, symlink_policy).await;
+
+        plan.push(PlannedFile {
+            local_path: file_path.clone(),
+            remote_dir: remote_dir.clone(),
+            remote_path: join_remote(remote_dir.as_deref(), &name),
+            size,
+            action,
+            reason,
+            conflict: None,
+        });
+    }
+
+    plan.log_summary();
+    plan
+}
+
+// 文件夹上传的计划：整个文件夹传到target_path/<文件夹名>下，保持目录结构
+// 排除的文件也列在计划里（action为Skip），排除的目录只列目录本身
+pub async fn plan_folder_upload(
+    folder: &Path,
+    target_path: Option<&str>,
+    filter: &TransferFilter,
+    symlink_policy: SymlinkPolicy,
+) -> Result<TransferPlan> {
+    let metadata = fs::metadata(folder).await
+        .context(format!("无法读取文件夹: {:?}", folder))?;
+    if !metadata.is_dir() {
+        return Err(anyhow::anyhow!("{:?} 不是文件夹", folder));
+    }
+    let folder_name = folder
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .context("无法获取文件夹名")?;
+    let root_dir = join_remote(normalize_dir(target_path).as_deref(), &folder_name);
+
+    let mut plan = TransferPlan::default();
+    // (本地目录, 相对路径)
+    let mut pending = vec![(folder.to_path_buf(), PathBuf::new())];
+
+    while let Some((dir, relative_dir)) = pending.pop() {
+        let remote_dir = if relative_dir.as_os_str().is_empty() {
+            root_dir.clone()
+        } else {
+            format!("{}/{}", root_dir, relative_dir.to_string_lossy().replace('\\', "/"))
+        };
+        // 先序遍历，父目录一定在子目录前面，空目录也照样建出来
+        plan.create_dirs.push(remote_dir.clone());
+
+        let mut entries = fs::read_dir(&dir).await
+            .context(format!("读取目录失败: {:?}", dir))?;
+        let mut children = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            children.push(entry);
+        }
+        // 按名字排序，计划的顺序固定，前端显示和测试都方便
+        children.sort_by_key(|entry| entry.file_name());
+
+        let mut subdirs = Vec::new();
+        for entry in children {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            let relative = relative_dir.join(&name);
+            let file_type = match entry.file_type().await {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    println!("获取文件类型失败，跳过: {:?} - {}", path, e);
+                    continue;
+                }
+            };
+
+            if file_type.is_dir() {
+                if let Some(reason) = filter.excludes_dir(&relative) {
+                    plan.push(PlannedFile {
+                        local_path: path.to_string_lossy().to_string(),
+                        remote_dir: Some(remote_dir.clone()),
+                        remote_path: join_remote(Some(&remote_dir), &name),
+                        size: 0,
+                        action: PlanAction::Skip,
+                        reason: Some(reason),
+                        conflict: None,
+                    });
+                } else {
+                    subdirs.push((path, relative));
+                }
+                continue;
+            }
+
+            let is_symlink_dir = file_type.is_symlink() && fs::metadata(&path).await.is_ok_and(|m| m.is_dir());
+            let (size, mut action, mut reason) = if is_symlink_dir {
+                (0, PlanAction::Skip, Some("指向目录的符号链接，不进入".to_string()))
+            } else {
+                check_file(&path, symlink_policy).await
+            };
+            if action == PlanAction::Upload {
+                if let Some(excluded) = filter.excludes_file(&relative, size) {
+                    action = PlanAction::Skip;
+                    reason = Some(excluded);
+                }
+            }
+
+            plan.push(PlannedFile {
+                local_path: path.to_string_lossy().to_string(),
+                remote_dir: Some(remote_dir.clone()),
+                remote_path: join_remote(Some(&remote_dir), &name),
+                size,
+                action,
+                reason,
+                conflict: None,
+            });
+        }
+
+        // 倒着压栈，按名字顺序遍历子目录
+        pending.extend(subdirs.into_iter().rev());
+    }
+
+    plan.log_summary();
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(name: &str, size: u64) -> RemoteEntry {
+        RemoteEntry {
+            name: name.to_string(),
+            path: name.to_string(),
+            is_dir: false,
+            size,
+            mime_type: None,
+            modified_at: Some(serde_json::json!(1_600_000_000)),
+        }
+    }
+
+    #[tokio::test]
+    async fn plans_upload_without_side_effects() {
+        let dir = tempfile::tempdir().unwrap();
+        let new_file = dir.path().join("new.txt");
+        let existing_file = dir.path().join("report.docx");
+        std::fs::write(&new_file, b"hello").unwrap();
+        std::fs::write(&existing_file, vec![0u8; 100]).unwrap();
+        let missing = dir.path().join("missing.bin");
+
+        let paths: Vec<String> = [&new_file, &existing_file, &missing]
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect();
+        let mut plan = plan_upload(&paths, Some("/docs/"), SymlinkPolicy::Follow).await;
+        assert_eq!(plan.remote_dirs(), vec![Some("docs".to_string())]);
+        plan.mark_conflicts(Some("docs"), &[remote("report.docx", 42)]);
+
+        assert_eq!(plan.transfer_count, 2);
+        assert_eq!(plan.total_bytes, 105);
+        assert_eq!(plan.skipped_count, 1);
+        assert_eq!(plan.conflict_count, 1);
+        assert!(plan.deletions.is_empty());
+        assert!(plan.create_dirs.is_empty());
+
+        assert_eq!(plan.files[0].remote_path, "docs/new.txt");
+        assert!(plan.files[0].conflict.is_none());
+        let conflict = plan.files[1].conflict.as_ref().unwrap();
+        assert_eq!(conflict.remote_size, 42);
+        assert_eq!(conflict.remote_modified_at, Some(1_600_000_000));
+        assert_eq!(plan.files[2].action, PlanAction::Skip);
+        assert!(plan.files[2].reason.is_some());
+    }
+
+    #[test]
+    fn remote_path_ignores_empty_target() {
+        assert_eq!(join_remote(normalize_dir(None).as_deref(), "a.txt"), "a.txt");
+        assert_eq!(join_remote(normalize_dir(Some("/")).as_deref(), "a.txt"), "a.txt");
+        assert_eq!(join_remote(normalize_dir(Some("x/y")).as_deref(), "a.txt"), "x/y/a.txt");
+    }
+
+    #[tokio::test]
+    async fn folder_plan_applies_filters_during_traversal() {
+        let root = tempfile::tempdir().unwrap();
+        let folder = root.path().join("project");
+        std::fs::create_dir_all(folder.join("src")).unwrap();
+        std::fs::create_dir_all(folder.join("node_modules/pkg")).unwrap();
+        std::fs::write(folder.join("README.md"), b"readme").unwrap();
+        std::fs::write(folder.join("src/main.rs"), b"fn main() {}").unwrap();
+        std::fs::write(folder.join("src/cache.tmp"), b"x").unwrap();
+        std::fs::write(folder.join("big.bin"), vec![0u8; 1000]).unwrap();
+        std::fs::write(folder.join("node_modules/pkg/index.js"), b"x").unwrap();
+
+        let filter = TransferFilter::new(&crate::transfer_filter::FilterRules {
+            exclude: vec!["node_modules/".to_string(), "*.tmp".to_string()],
+            exclude_hidden: false,
+            max_file_size: Some(100),
+        })
+        .unwrap();
+        let plan = plan_folder_upload(&folder, Some("backup"), &filter, SymlinkPolicy::Follow).await.unwrap();
+
+        let uploads: Vec<&str> = plan.files
+            .iter()
+            .filter(|f| f.action == PlanAction::Upload)
+            .map(|f| f.remote_path.as_str())
+            .collect();
+        assert_eq!(uploads, vec!["backup/project/README.md", "backup/project/src/main.rs"]);
+        assert_eq!(plan.total_bytes, 6 + 12);
+        // big.bin、node_modules目录、cache.tmp，node_modules里面的文件不会出现
+        assert_eq!(plan.skipped_count, 3);
+        assert!(plan.files.iter().all(|f| !f.local_path.contains("index.js")));
+        assert_eq!(plan.create_dirs, vec!["backup/project", "backup/project/src"]);
+        assert_eq!(
+            plan.remote_dirs(),
+            vec![Some("backup/project".to_string()), Some("backup/project/src".to_string())]
+        );
+    }
+}
@@ -0,0 +1,379 @@
+// 传输队列持久化
+// 没结束的传输定时记到应用数据目录的transfer_queue.json，下次启动时按原来的顺序恢复
+//
+// 思考：任务表只在内存里，退出或者崩溃以后正在传的、排队的任务全没了，用户得一个一个重新点。现在：
+// 1. 每个任务创建时分一个递增的序号，重试时新建的任务沿用旧序号，恢复时按序号排
+// 2. 常驻服务定时把排队中、暂停、传输中（包括跟随中）的任务写到文件（有变化才写），
+//    退出时在暂停任务之前再写一次，记下的是退出前真实的状态
+// 3. 启动时按序号重新创建任务：设置里resume_on_startup打开时重新排队开始传，
+//    关闭时恢复成暂停，用户点重试再继续；退出前就是用户暂停的任务不管设置都恢复成暂停。
+//    结果用transfers-restored事件告诉前端
+// 下载靠磁盘上的部分和元数据续传，上传复用原来的upload_id，由服务器告诉我们还缺哪些分片；
+// 关着程序时会话过期了就重新申请一个（见UploadTask::query_uploaded_chunks）。
+// 重新创建任务要连后端（启动时笔可能还没连上），失败的条目留在文件里过一会儿再试，
+// 试了MAX_RESTORE_ATTEMPTS次还不行、或者要上传的本地文件已经不在了就放弃。
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::download::{DownloadStatus, DownloadTask};
+use crate::storage::get_app_data_dir;
+use crate::transfer_manager::{self, download_tasks, upload_tasks, TransferPriority};
+use crate::upload::{UploadStatus, UploadTask};
+use crate::{event_emitter, settings};
+
+// 多久检查一次任务表有没有变化
+const PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+// 恢复失败的条目多久再试一次
+const RESTORE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+// 恢复失败多少次后放弃
+const MAX_RESTORE_ATTEMPTS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueuedTransfer {
+    pub seq: u64,
+    pub direction: Direction,
+    pub id: String,                      // 下载是file_id，上传是upload_id
+    pub local_path: String,              // 下载的保存位置或上传的本地文件
+    #[serde(default)]
+    pub target_path: Option<String>,     // 上传的云端目录
+    #[serde(default)]
+    pub chunk_size: u64,                 // 上传会话的分片大小
+    #[serde(default)]
+    pub session_id: Option<String>,      // 上传会话过期重新申请过时，后端现在的会话ID
+    #[serde(default)]
+    pub completed_chunks: Vec<u32>,      // 上传已完成的分片，恢复后查不到服务器状态时按这个续传
+    #[serde(default)]
+    pub priority: TransferPriority,
+    #[serde(default)]
+    pub follow: bool,                    // 下载是不是跟随模式，恢复后下载完接着跟随
+    pub status: String,                  // 记下时的状态：pending/paused/active
+    #[serde(default)]
+    pub restore_attempts: u32,
+}
+
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+static EXITING: AtomicBool = AtomicBool::new(false);
+// 还没恢复成功的条目，写文件时一起写进去
+static UNRESTORED: OnceLock<Mutex<Vec<QueuedTransfer>>> = OnceLock::new();
+
+fn unrestored() -> &'static Mutex<Vec<QueuedTransfer>> {
+    UNRESTORED.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+// 新任务的排队序号
+pub fn next_seq() -> u64 {
+    NEXT_SEQ.fetch_add(1, Ordering::SeqCst)
+}
+
+fn queue_path() -> Result<PathBuf> {
+    let data_dir = get_app_data_dir().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(data_dir.join("transfer_queue.json"))
+}
+
+async fn read_entries(path: &Path) -> Vec<QueuedTransfer> {
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(_) => return Vec::new(),
+    };
+    match serde_json::from_str(&content) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("传输队列文件格式错误，忽略: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+async fn write_entries(path: &Path, entries: &[QueuedTransfer]) -> Result<()> {
+    let content = serde_json::to_string_pretty(entries).context("序列化传输队列失败")?;
+    let mut tmp_name = path.as_os_str().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    fs::write(&tmp_path, content).await
+        .context(format!("写入传输队列失败: {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path).await
+        .context(format!("保存传输队列失败: {:?}", path))?;
+    Ok(())
+}
+
+// 任务表里没结束的任务，加上还没恢复的条目，按序号排好
+async fn snapshot() -> Vec<QueuedTransfer> {
+    let mut entries = unrestored().lock().unwrap().clone();
+
+    let downloads: Vec<_> = download_tasks().lock().await.values().cloned().collect();
+    for task in downloads {
+        let progress = task.get_progress().await;
+        let status = match progress.status {
+            DownloadStatus::Pending => "pending",
+            DownloadStatus::Paused => "paused",
+            DownloadStatus::Downloading | DownloadStatus::WaitingForServer(_) | DownloadStatus::Verifying(_) | DownloadStatus::Following => "active",
+            _ => continue,
+        };
+        entries.push(QueuedTransfer {
+            seq: task.queue_seq(),
+            direction: Direction::Download,
+            id: progress.file_id,
+            local_path: task.save_path().to_string_lossy().to_string(),
+            target_path: None,
+            chunk_size: 0,
+            session_id: None,
+            completed_chunks: Vec::new(),
+            priority: progress.priority,
+            follow: task.follow_enabled(),
+            status: status.to_string(),
+            restore_attempts: 0,
+        });
+    }
+
+    let uploads: Vec<_> = upload_tasks().lock().await.values().cloned().collect();
+    for task in uploads {
+        let progress = task.get_progress().await;
+        let status = match progress.status {
+            UploadStatus::Pending => "pending",
+            UploadStatus::Paused => "paused",
+            UploadStatus::Uploading | UploadStatus::WaitingForServer(_) => "active",
+            _ => continue,
+        };
+        entries.push(QueuedTransfer {
+            seq: task.queue_seq(),
+            direction: Direction::Upload,
+            id: progress.upload_id,
+            local_path: task.file_path().to_string_lossy().to_string(),
+            target_path: task.target_path().map(|s| s.to_string()),
+            chunk_size: task.chunk_size(),
+            session_id: Some(task.session_id()).filter(|session_id| session_id != &progress.upload_id),
+            completed_chunks: task.completed_chunk_list(),
+            priority: TransferPriority::default(),
+            follow: false,
+            status: status.to_string(),
+            restore_attempts: 0,
+        });
+    }
+
+    entries.sort_by_key(|entry| entry.seq);
+    entries
+}
+
+// 退出前在暂停任务之前调用：保存一次，之后定时保存不再写文件，免得把退出时的暂停状态写进去
+pub async fn save_for_exit() -> Result<()> {
+    EXITING.store(true, Ordering::SeqCst);
+    write_entries(&queue_path()?, &snapshot().await).await
+}
+
+async fn is_in_task_table(entry: &QueuedTransfer) -> bool {
+    match entry.direction {
+        Direction::Download => download_tasks().lock().await.contains_key(&entry.id),
+        Direction::Upload => upload_tasks().lock().await.contains_key(&entry.id),
+    }
+}
+
+// 重新创建一个任务，放进任务表；resume为false时恢复成暂停
+// 返回文件名；Err里的bool表示还值不值得再试
+async fn restore_one(entry: &QueuedTransfer, resume: bool) -> Result<String, (String, bool)> {
+    if entry.direction == Direction::Upload && !Path::new(&entry.local_path).exists() {
+        return Err((format!("本地文件已不存在: {}", entry.local_path), false));
+    }
+    let auth_info = crate::acquire_auth_info().await.map_err(|e| (e, true))?;
+
+    match entry.direction {
+        Direction::Download => {
+            let task = DownloadTask::new(entry.id.clone(), PathBuf::from(&entry.local_path), auth_info)
+                .await
+                .map_err(|e| (format!("创建下载任务失败: {:#}", e), true))?;
+            task.set_queue_seq(entry.seq);
+            task.set_priority(entry.priority).await;
+            task.set_follow(entry.follow);
+            task.note("启动时从传输队列恢复".to_string());
+            let file_name = task.get_progress().await.file_name;
+
+            let task = Arc::new(task);
+            download_tasks().lock().await.insert(entry.id.clone(), task.clone());
+            if resume {
+                transfer_manager::spawn_download(task);
+            } else {
+                task.pause().await;
+            }
+            Ok(file_name)
+        }
+        Direction::Upload => {
+            let task = UploadTask::resume_session(
+                PathBuf::from(&entry.local_path),
+                auth_info,
+                entry.target_path.as_deref(),
+                entry.id.clone(),
+                entry.chunk_size,
+            )
+                .await
+                .map_err(|e| (format!("创建上传任务失败: {:#}", e), true))?;
+            if let Some(session_id) = &entry.session_id {
+                task.set_session_id(session_id.clone());
+            }
+            task.restore_completed_chunks(&entry.completed_chunks);
+            task.set_queue_seq(entry.seq);
+            task.note("启动时从传输队列恢复".to_string());
+            let file_name = task.get_progress().await.filename;
+
+            let task = Arc::new(task);
+            upload_tasks().lock().await.insert(entry.id.clone(), task.clone());
+            if resume {
+                transfer_manager::spawn_upload(task);
+            } else {
+                task.pause().await;
+            }
+            Ok(file_name)
+        }
+    }
+}
+
+// 恢复后要不要直接开始传：用户自己暂停的任务保持暂停（见思考3）
+fn should_resume(entry: &QueuedTransfer, resume_on_startup: bool) -> bool {
+    resume_on_startup && entry.status != "paused"
+}
+
+// 按顺序恢复还没恢复的条目，结果通过transfers-restored事件告诉前端
+async fn restore_pending() {
+    let entries = std::mem::take(&mut *unrestored().lock().unwrap());
+    if entries.is_empty() {
+        return;
+    }
+    let resume = settings::get().transfer.resume_on_startup;
+
+    let mut restored = Vec::new();
+    let mut failed = Vec::new();
+    let mut remaining = Vec::new();
+    for mut entry in entries {
+        // 服务重启后再读一遍文件时，已经恢复过的任务不能重复创建
+        if is_in_task_table(&entry).await {
+            continue;
+        }
+
+        let resume_entry = should_resume(&entry, resume);
+        match restore_one(&entry, resume_entry).await {
+            Ok(file_name) => restored.push(serde_json::json!({
+                "direction": entry.direction,
+                "id": entry.id,
+                "file_name": file_name,
+                "previous_status": entry.status,
+                "resumed": resume_entry,
+            })),
+            Err((error, retry)) => {
+                entry.restore_attempts += 1;
+                let give_up = !retry || entry.restore_attempts >= MAX_RESTORE_ATTEMPTS;
+                println!("恢复传输任务 {} 失败（第 {} 次）: {}", entry.id, entry.restore_attempts, error);
+                failed.push(serde_json::json!({
+                    "direction": entry.direction,
+                    "id": entry.id,
+                    "error": error,
+                    "given_up": give_up,
+                }));
+                if !give_up {
+                    remaining.push(entry);
+                }
+            }
+        }
+    }
+
+    println!("传输队列恢复: {} 个已恢复，{} 个失败，{} 个稍后再试", restored.len(), failed.len(), remaining.len());
+    let remaining_count = remaining.len();
+    unrestored().lock().unwrap().extend(remaining);
+    event_emitter::emit_transfer_event("transfers-restored", serde_json::json!({
+        "resumed": resume,
+        "restored": restored,
+        "failed": failed,
+        "remaining": remaining_count,
+    }));
+}
+
+// 常驻服务：启动时恢复上次的队列，之后定时保存
+pub async fn run() {
+    let path = match queue_path() {
+        Ok(path) => path,
+        Err(e) => {
+            println!("获取传输队列文件路径失败: {}", e);
+            return;
+        }
+    };
+
+    // 恢复的任务沿用原来的序号，新任务排在它们后面
+    let entries = read_entries(&path).await;
+    for entry in &entries {
+        NEXT_SEQ.fetch_max(entry.seq + 1, Ordering::SeqCst);
+    }
+    *unrestored().lock().unwrap() = entries;
+    let mut last_saved: Option<Vec<QueuedTransfer>> = None;
+    let mut since_restore = RESTORE_RETRY_INTERVAL;
+    loop {
+        if since_restore >= RESTORE_RETRY_INTERVAL {
+            restore_pending().await;
+            since_restore = Duration::ZERO;
+        }
+
+        let entries = snapshot().await;
+        if !EXITING.load(Ordering::SeqCst) && last_saved.as_ref() != Some(&entries) {
+            match write_entries(&path, &entries).await {
+                Ok(()) => last_saved = Some(entries),
+                Err(e) => println!("{:#}", e),
+            }
+        }
+
+        tokio::time::sleep(PERSIST_INTERVAL).await;
+        since_restore += PERSIST_INTERVAL;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(seq: u64) -> QueuedTransfer {
+        QueuedTransfer {
+            seq,
+            direction: Direction::Download,
+            id: format!("ds/{}.bin", seq),
+            local_path: format!("/tmp/{}.bin", seq),
+            target_path: None,
+            chunk_size: 0,
+            session_id: None,
+            completed_chunks: Vec::new(),
+            priority: TransferPriority::Low,
+            follow: false,
+            status: "paused".to_string(),
+            restore_attempts: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_queue_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transfer_queue.json");
+
+        assert!(read_entries(&path).await.is_empty());
+        write_entries(&path, &[entry(1), entry(2)]).await.unwrap();
+        assert_eq!(read_entries(&path).await, vec![entry(1), entry(2)]);
+
+        fs::write(&path, "not json").await.unwrap();
+        assert!(read_entries(&path).await.is_empty());
+    }
+
+    #[test]
+    fn keeps_paused_tasks_paused() {
+        let paused = entry(1);
+        let active = QueuedTransfer { status: "active".to_string(), ..entry(2) };
+        assert!(!should_resume(&paused, true));
+        assert!(should_resume(&active, true));
+        assert!(!should_resume(&active, false));
+    }
+}
@@ -0,0 +1,105 @@
+// 检查更新
+// 远程配置（config模块里的data.json）里带了最新版本号和下载地址，
+// 和当前版本（Cargo.toml里的version）比较，有新版本时告诉前端。
+//
+// 思考：没有接tauri-plugin-updater，那个需要签名密钥和单独的更新服务器，
+// 现在发版还是手动打包。先只做检查，前端拿到download_url后用opener打开下载页面。
+// 以后要自动更新的话，把这里的检查结果换成updater插件的check就行。
+
+use std::cmp::Ordering;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::config;
+
+// 当前版本
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// 检查更新结果
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateInfo {
+    pub current_version: String,
+    pub latest_version: Option<String>,    // 远程配置里没有版本号时为None
+    pub update_available: bool,
+    pub download_url: Option<String>,
+    pub release_notes: Option<String>,
+}
+
+// 把"v1.2.3-beta"这样的版本号拆成数字列表[1, 2, 3]
+// 预发布后缀忽略，解析不了的部分当作0
+fn parse_version(version: &str) -> Vec<u64> {
+    let version = version.trim().trim_start_matches(['v', 'V']);
+    let version = version.split(['-', '+']).next().unwrap_or_default();
+    version
+        .split('.')
+        .map(|part| part.trim().parse::<u64>().unwrap_or(0))
+        .collect()
+}
+
+// 比较两个版本号，长度不同时短的补0（1.2 == 1.2.0）
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let a = parse_version(a);
+    let b = parse_version(b);
+    let len = a.len().max(b.len());
+    for i in 0..len {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        match x.cmp(&y) {
+            Ordering::Equal => continue,
+            other => return other,
+        }
+    }
+    Ordering::Equal
+}
+
+// 请求远程配置，检查是否有新版本
+pub async fn check_for_updates() -> Result<UpdateInfo> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .context("创建HTTP客户端失败")?;
+
+    let remote = config::fetch_remote_config(&client).await?;
+
+    let latest_version = remote
+        .latest_version
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    let update_available = latest_version
+        .as_deref()
+        .is_some_and(|latest| compare_versions(latest, CURRENT_VERSION) == Ordering::Greater);
+
+    println!(
+        "检查更新: 当前版本 {}，最新版本 {}",
+        CURRENT_VERSION,
+        latest_version.as_deref().unwrap_or("未知")
+    );
+
+    Ok(UpdateInfo {
+        current_version: CURRENT_VERSION.to_string(),
+        latest_version,
+        update_available,
+        download_url: remote.download_url,
+        release_notes: remote.release_notes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_numeric_parts() {
+        assert_eq!(compare_versions("0.2.0", "0.1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("0.1.10", "0.1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0.0", "1.0.1"), Ordering::Less);
+    }
+
+    #[test]
+    fn ignores_prefix_suffix_and_missing_parts() {
+        assert_eq!(compare_versions("v1.2", "1.2.0"), Ordering::Equal);
+        assert_eq!(compare_versions("1.2.0-beta", "1.2.0"), Ordering::Equal);
+        assert_eq!(compare_versions("V2", "1.9.9"), Ordering::Greater);
+    }
+}
@@ -6,30 +6,54 @@
 // 2. 支持4MB分片（与后端API一致）
 // 3. 支持断点续传，可以查询已上传分片
 // 4. 提供上传进度信息
+// 5. 创建任务时检测文件的MIME类型（见content_type模块），init和finish都带上content_type
+// 6. finish成功后HEAD一次云端文件核对大小（后端在响应头里给了SHA256时也核对哈希），
+//    后端拼分片出错时当场发现，不用等到下次下载才知道。HEAD本身失败时不算出错，只是没有核对
 
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Duration;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use tokio::sync::{mpsc, Mutex};
 use tokio::fs::{self, File};
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use anyhow::{Result, Context};
 use serde::{Serialize, Deserialize};
-use reqwest::{Client, multipart};
+use reqwest::{Method, StatusCode, multipart};
+use futures::StreamExt;
 
-// 导入下载模块中的AuthInfo
-use crate::download::AuthInfo;
-// 导入配置模块
-use crate::config;
+// 导入认证模块中的AuthInfo
+use crate::auth::AuthInfo;
+// 导入速度采样模块
+use crate::speed::{SpeedSampler, SpeedSample};
+// 导入分片计算
+use crate::chunks;
+// 导入设置模块
+use crate::settings::{self, InUsePolicy, SymlinkPolicy};
+// 导入文件占用检测
+use crate::file_lock;
+// 导入传输HTTP层
+use crate::transfer_http::{self, Endpoint, TransferHttp, CHUNK_ATTEMPTS, CHUNK_RETRY_DELAY};
+// 导入网络类型检测（计费网络策略）
+use crate::network_profile;
+// 导入电源状态（睡眠/省电模式时暂停）
+use crate::power;
+// 导入任务日志
+use crate::transfer_log::{LogLine, TransferLog};
+// 导入传输队列（排队序号）
+use crate::transfer_queue::{self, Direction};
+// 导入后端维护状态
+use crate::server_maintenance;
+// 导入任务重复启动的错误
+use crate::transfer_manager::{self, TaskAlreadyRunning};
+// 导入MIME类型检测
+use crate::content_type;
+// 导入文件哈希计算
+use crate::checksum_manifest;
 
-// 默认分片大小 256KB
-const CHUNK_SIZE: u64 = 256 * 1024; // 256KB
-
-// 获取基础URL的辅助函数
-fn get_base_url() -> Result<String> {
-    config::get_backend_url()
-}
+// finish前核对分片时最多补传几轮
+const RECONCILE_ROUNDS: u32 = 2;
 
 // 上传状态枚举
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,7 +62,9 @@ pub enum UploadStatus {
     Uploading,    // 上传中
     Paused,       // 已暂停
     Completed,    // 已完成
+    FileInUse,    // 文件正在被其他程序使用，没有上传
     Error(String), // 错误
+    WaitingForServer(i64), // 后端维护中，等到这个时间（Unix时间戳）再继续
 }
 
 // 上传进度信息
@@ -51,72 +77,160 @@ pub struct UploadProgress {
     pub status: UploadStatus,      // 上传状态
     pub chunks_total: u32,         // 总分片数
     pub chunks_completed: u32,     // 已完成分片数
+    pub chunk_size: u64,           // 分片大小（字节）
     pub speed_kbps: f64,           // 上传速度 KB/s
+    pub retry_count: u32,          // 已重试次数
+    pub verified: bool,            // 上传完成后核对过云端文件
+}
+
+// 上传完成后云端文件和本地文件对不上
+#[derive(Debug)]
+pub struct PostUploadMismatch {
+    pub path: String,                    // 云端路径
+    pub local_size: u64,
+    pub remote_size: Option<u64>,        // None表示云端没有这个文件
+    pub local_hash: Option<String>,      // 只有后端给了哈希时才计算
+    pub remote_hash: Option<String>,
+}
+
+impl fmt::Display for PostUploadMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.remote_size {
+            None => write!(f, "[post_upload_mismatch] 上传完成后云端找不到文件: {}", self.path),
+            Some(remote_size) if remote_size != self.local_size => write!(
+                f,
+                "[post_upload_mismatch] 云端文件大小不一致: {}，本地 {} 字节，云端 {} 字节",
+                self.path, self.local_size, remote_size
+            ),
+            Some(_) => write!(
+                f,
+                "[post_upload_mismatch] 云端文件哈希不一致: {}，本地 {}，云端 {}",
+                self.path,
+                self.local_hash.as_deref().unwrap_or("-"),
+                self.remote_hash.as_deref().unwrap_or("-")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PostUploadMismatch {}
+
+// HEAD拿到的云端文件信息
+#[derive(Debug, Clone)]
+struct RemoteCopy {
+    size: u64,
+    hash: Option<String>,   // 小写十六进制SHA256
 }
 
 // 上传响应数据结构
+// 不同版本的后端字段名写法不一样（upload_id/uploadId、uploaded_chunks/uploadedChunks），都认；
+// 有的后端会话ID是数字，也转成字符串；其他字段不管
 #[derive(Debug, Deserialize)]
 struct InitUploadResponse {
+    #[serde(alias = "uploadId", alias = "id", deserialize_with = "string_or_number")]
     upload_id: String,
-    // 这里可能还有其他字段，根据后端API调整
 }
 
 #[derive(Debug, Deserialize)]
 struct UploadStatusResponse {
+    #[serde(alias = "uploadedChunks", alias = "chunks")]
     uploaded_chunks: Vec<u32>,
-    // 可能还有其他状态信息
+}
+
+fn string_or_number<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber {
+        String(String),
+        Number(u64),
+    }
+    Ok(match StringOrNumber::deserialize(deserializer)? {
+        StringOrNumber::String(value) => value,
+        StringOrNumber::Number(value) => value.to_string(),
+    })
+}
+
+// 上传源文件的检查结果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceCheck {
+    Regular(u64),     // 可以上传的普通文件（跟随符号链接后），值是文件大小
+    SkippedSymlink,   // 符号链接，按设置跳过
+}
+
+// 特殊文件的类型名称，用在错误信息里
+#[cfg(unix)]
+fn special_file_kind(file_type: &std::fs::FileType) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+    if file_type.is_fifo() {
+        "命名管道"
+    } else if file_type.is_socket() {
+        "套接字"
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        "设备文件"
+    } else {
+        "特殊文件"
+    }
+}
+
+#[cfg(not(unix))]
+fn special_file_kind(_file_type: &std::fs::FileType) -> &'static str {
+    "特殊文件"
+}
+
+// 检查上传源是不是可以读的普通文件
+// 思考：原来直接fs::metadata，会顺着符号链接走，管道、设备文件也能拿到"大小"，
+// 上传时读管道会一直阻塞，读/dev/zero会读出无穷多数据。文件夹上传和同步时这种文件更容易混进来，
+// 所以先用symlink_metadata看清楚是什么：符号链接按设置跟随或跳过，目录和特殊文件直接报错。
+pub async fn check_source(path: &Path, policy: SymlinkPolicy) -> Result<SourceCheck> {
+    let link_meta = fs::symlink_metadata(path).await
+        .context(format!("读取文件信息失败: {:?}", path))?;
+
+    let meta = if link_meta.file_type().is_symlink() {
+        if policy == SymlinkPolicy::Skip {
+            println!("跳过符号链接: {:?}", path);
+            return Ok(SourceCheck::SkippedSymlink);
+        }
+        fs::metadata(path).await
+            .context(format!("符号链接指向的文件不存在: {:?}", path))?
+    } else {
+        link_meta
+    };
+
+    if meta.is_dir() {
+        return Err(anyhow::anyhow!("{:?} 是目录，不能作为文件上传", path));
+    }
+    if !meta.is_file() {
+        return Err(anyhow::anyhow!(
+            "{:?} 不是普通文件（{}），不能上传",
+            path,
+            special_file_kind(&meta.file_type())
+        ));
+    }
+    Ok(SourceCheck::Regular(meta.len()))
 }
 
 // 分片上传器
 pub struct ChunkUploader {
-    client: Client,
-    auth_info: AuthInfo,
+    http: TransferHttp,
 }
 
 impl ChunkUploader {
     // 创建新的上传器
     pub fn new(auth_info: AuthInfo) -> Result<Self> {
-        // 创建HTTP客户端，设置合适的超时时间
-        let client = Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()
-            .context("创建HTTP客户端失败")?;
-            
-        Ok(Self { client, auth_info })
+        Ok(Self { http: TransferHttp::new(auth_info)? })
     }
     
     // 初始化上传 - 调用 /upload/init
-    // 后端不需要任何参数，只需要认证头
-    pub async fn init_upload(&self, _filename: &str, _total_size: u64) -> Result<String> {
-        let base_url = get_base_url()?;
-        let url = format!("{}/upload/init", base_url);
-        
-        // 获取认证头
-        let headers = self.auth_info.get_auth_header()?;
-        
+    // 后端只需要认证头；content_type作为查询参数告诉后端文件类型，不认识这个参数的老后端会忽略
+    pub async fn init_upload(&self, _filename: &str, _total_size: u64, content_type: &str) -> Result<String> {
         // 发送POST请求，不需要body
-        let response = self.client
-            .post(&url)
-            .headers(headers)
-            .send()
-            .await
-            .context("初始化上传失败")?;
-            
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "初始化上传失败: {} - {}", 
-                status, 
-                error_text
-            ));
-        }
+        let request = self.http
+            .request(Method::POST, Endpoint::UploadInit)?
+            .query(&[("content_type", content_type)]);
+        let response = transfer_http::send(request, "初始化上传").await?;
         
         // 解析响应，获取 upload_id
-        let response_data: InitUploadResponse = response
-            .json()
-            .await
-            .context("解析初始化响应失败")?;
+        let response_data: InitUploadResponse = transfer_http::parse_json(response, "初始化上传").await?;
             
         println!("上传初始化成功，获取到 upload_id: {}", response_data.upload_id);
         Ok(response_data.upload_id)
@@ -129,40 +243,19 @@ impl ChunkUploader {
         chunk_index: u32,
         chunk_data: &[u8],
     ) -> Result<()> {
-        let base_url = get_base_url()?;
-        let url = format!("{}/upload/chunk", base_url);
-        
-        // 获取认证头
-        let headers = self.auth_info.get_auth_header()?;
-        
         // 构建multipart表单，只包含文件数据
         // upload_id 和 index 作为查询参数传递
         let form = multipart::Form::new()
             .part("file", multipart::Part::bytes(chunk_data.to_vec()).file_name(format!("chunk_{:04}", chunk_index)));
         
-        // 发送请求，使用查询参数传递 upload_id 和 index
-        let response = self.client
-            .post(&url)
+        let request = self.http
+            .request(Method::POST, Endpoint::UploadChunk)?
             .query(&[
                 ("upload_id", upload_id),
                 ("index", &chunk_index.to_string()),
             ])
-            .headers(headers)
-            .multipart(form)
-            .send()
-            .await
-            .context("上传分片失败")?;
-            
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "上传分片 {} 失败: {} - {}", 
-                chunk_index,
-                status, 
-                error_text
-            ));
-        }
+            .multipart(form);
+        transfer_http::send(request, &format!("上传分片 {}", chunk_index)).await?;
         
         println!("分片 {} 上传成功", chunk_index);
         Ok(())
@@ -175,16 +268,12 @@ impl ChunkUploader {
         filename: &str,
         total_chunks: u32,
         target_path: Option<&str>,
+        modified_at: Option<i64>,
+        content_type: Option<&str>,
     ) -> Result<String> {
         eprintln!("[finish_upload] 开始处理，upload_id={}, filename={}, total_chunks={}, target_path={:?}", 
                  upload_id, filename, total_chunks, target_path);
         
-        let base_url = get_base_url()?;
-        let url = format!("{}/upload/finish", base_url);
-        
-        // 获取认证头
-        let headers = self.auth_info.get_auth_header()?;
-        
         // 构建查询参数
         let total_chunks_str = total_chunks.to_string();
         let mut params = vec![
@@ -199,29 +288,24 @@ impl ChunkUploader {
             params.push(("target_path", path));
         }
         
-        eprintln!("[finish_upload] 发送请求到: {}", url);
+        // 本地文件的修改时间（Unix时间戳，秒），服务器用它作为云端文件的修改时间
+        let modified_at_str = modified_at.map(|t| t.to_string());
+        if let Some(modified_at) = modified_at_str.as_deref() {
+            params.push(("modified_at", modified_at));
+        }
+        
+        // 文件的MIME类型，后端预览和浏览器下载时用
+        if let Some(content_type) = content_type {
+            params.push(("content_type", content_type));
+        }
+        
         eprintln!("[finish_upload] 参数: {:?}", params);
         
         // 发送POST请求
-        let response = self.client
-            .post(&url)
-            .headers(headers)
-            .query(&params)
-            .send()
-            .await
-            .context("完成上传失败")?;
-            
-        eprintln!("[finish_upload] 收到响应状态: {:?}", response.status());
-        
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "完成上传失败: {} - {}", 
-                status, 
-                error_text
-            ));
-        }
+        let request = self.http
+            .request(Method::POST, Endpoint::UploadFinish)?
+            .query(&params);
+        let response = transfer_http::send(request, "完成上传").await?;
         
         // 解析响应，获取文件ID等信息
         let response_text = response.text().await.context("读取完成响应失败")?;
@@ -231,52 +315,95 @@ impl ChunkUploader {
     }
     
     // 查询上传状态 - 调用 /upload/status/{upload_id}
-    pub async fn get_upload_status(&self, upload_id: &str) -> Result<Vec<u32>> {
-        let base_url = get_base_url()?;
-        let url = format!("{}/upload/status/{}", base_url, upload_id);
-        
-        // 获取认证头
-        let headers = self.auth_info.get_auth_header()?;
-        
-        // 发送GET请求
-        let response = self.client
-            .get(&url)
-            .headers(headers)
-            .send()
-            .await
-            .context("查询上传状态失败")?;
-            
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(
-                "查询上传状态失败: {} - {}", 
-                status, 
-                error_text
-            ));
+    // 返回None表示服务器上已经没有这个会话了（404，过期或者被清理了）
+    pub async fn get_upload_status(&self, upload_id: &str) -> Result<Option<Vec<u32>>> {
+        let request = self.http.request(Method::GET, Endpoint::UploadStatus(upload_id))?;
+        let response = transfer_http::send_raw(request, "查询上传状态").await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
         }
+        let response = transfer_http::check_response(response, "查询上传状态").await?;
         
         // 解析响应，获取已上传分片列表
-        let status_data: UploadStatusResponse = response
-            .json()
-            .await
-            .context("解析上传状态失败")?;
+        let status_data: UploadStatusResponse = transfer_http::parse_json(response, "查询上传状态").await?;
             
-        Ok(status_data.uploaded_chunks)
+        Ok(Some(status_data.uploaded_chunks))
+    }
+    
+    // 新任务的分片大小（按设置和后端能力协商）
+    pub async fn negotiate_chunk_size(&self) -> u64 {
+        self.http.negotiate_chunk_size().await
+    }
+    
+    // 后端是否能自己拉取URL
+    pub async fn supports_remote_fetch(&self) -> bool {
+        self.http.capabilities().await.remote_fetch
+    }
+    
+    // 让后端下载url保存到target_path - 调用 /upload/remote
+    pub async fn upload_remote(&self, url: &str, filename: &str, target_path: Option<&str>) -> Result<()> {
+        let request = self.http
+            .request(Method::POST, Endpoint::UploadRemote)?
+            .json(&serde_json::json!({
+                "url": url,
+                "filename": filename,
+                "target_path": target_path,
+            }));
+        transfer_http::send(request, "后端拉取URL").await?;
+        Ok(())
+    }
+    
+    // HEAD云端文件，拿大小和哈希，文件不存在时返回None
+    async fn stat_remote(&self, path: &str) -> Result<Option<RemoteCopy>> {
+        let request = self.http.request(Method::HEAD, Endpoint::Download(path))?;
+        let response = transfer_http::send_raw(request, "核对云端文件").await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = transfer_http::check_response(response, "核对云端文件").await?;
+        
+        let headers = response.headers();
+        let size = headers
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| s.parse::<u64>().ok())
+            .context("核对云端文件: 响应没有Content-Length")?;
+        let hash = headers
+            .get(transfer_http::CONTENT_HASH_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.trim().to_ascii_lowercase())
+            .filter(|s| s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit()));
+        Ok(Some(RemoteCopy { size, hash }))
     }
 }
 
 // 上传任务管理器
 pub struct UploadTask {
     upload_id: String,
+    // 后端的上传会话，一开始就是upload_id；会话过期重新申请以后换成新的（见renew_session），
+    // upload_id还是任务的ID不变，前端和任务表都按它找任务
+    session_id: std::sync::Mutex<String>,
+    resumed: bool,   // 复用已有会话创建的任务，开始前要先确认会话还在
     filename: String,
     file_path: PathBuf,
     total_size: u64,
     uploaded_size: Arc<AtomicU64>,
+    // 已经上传完成的分片，uploaded_size总是从这里算出来（见mark_chunk_completed）
+    completed_chunks: Arc<std::sync::Mutex<BTreeSet<u32>>>,
     status: Arc<Mutex<UploadStatus>>,
     uploader: ChunkUploader,
+    chunk_size: u64,
     chunks_total: u32,
+    modified_at: Option<i64>,
+    content_type: String,   // 文件的MIME类型
     target_path: Option<String>,
+    speed: Arc<SpeedSampler>,
+    retry_count: AtomicU32,
+    queue_seq: AtomicU64,
+    log: Arc<TransferLog>,
+    parallel_chunks: usize,
+    verified: AtomicBool,   // 上传完成后核对过云端文件（见文件开头第6条）
+    running: Mutex<()>,   // start()执行期间一直拿着，保证同一时间只有一个start()
 }
 
 impl UploadTask {
@@ -285,6 +412,28 @@ impl UploadTask {
         file_path: PathBuf,
         auth_info: AuthInfo,
         target_path: Option<&str>,
+    ) -> Result<Self> {
+        Self::create(file_path, auth_info, target_path, None).await
+    }
+    
+    // 复用已有的上传会话创建任务
+    // 重试时使用，start()会向服务器查询已上传的分片，只补传缺少的部分
+    // 分片序号是按原来的分片大小算的，必须沿用原来的chunk_size
+    pub async fn resume_session(
+        file_path: PathBuf,
+        auth_info: AuthInfo,
+        target_path: Option<&str>,
+        upload_id: String,
+        chunk_size: u64,
+    ) -> Result<Self> {
+        Self::create(file_path, auth_info, target_path, Some((upload_id, chunk_size))).await
+    }
+    
+    async fn create(
+        file_path: PathBuf,
+        auth_info: AuthInfo,
+        target_path: Option<&str>,
+        existing_session: Option<(String, u64)>,
     ) -> Result<Self> {
         // 获取文件名
         let filename = file_path
@@ -293,74 +442,154 @@ impl UploadTask {
             .context("无法获取文件名")?
             .to_string();
         
-        // 获取文件大小
-        let total_size = fs::metadata(&file_path).await
-            .context("获取文件大小失败")?
-            .len();
+        // 获取文件大小，符号链接和特殊文件按设置处理
+        let total_size = match check_source(&file_path, settings::get().transfer.symlink_policy).await? {
+            SourceCheck::Regular(size) => size,
+            SourceCheck::SkippedSymlink => {
+                return Err(anyhow::anyhow!("{:?} 是符号链接，按设置跳过", file_path));
+            }
+        };
+        
+        // 本地修改时间，完成上传时告诉服务器（被占用时上传快照，也用原文件的时间）
+        let modified_at = fs::metadata(&file_path).await
+            .ok()
+            .and_then(|meta| meta.modified().ok())
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64);
+        let content_type = content_type::detect_file(&file_path).await;
         
         // 创建上传器
         let uploader = ChunkUploader::new(auth_info)?;
         
-        // 初始化上传，获取upload_id
-        let upload_id = uploader.init_upload(&filename, total_size).await?;
-        
-        // 计算总分片数
-        let chunks_total = if total_size > 0 {
-            ((total_size as f64) / (CHUNK_SIZE as f64)).ceil() as u32
-        } else {
-            1
+        // 初始化上传，获取upload_id（已有会话就直接复用，分片大小也沿用）
+        let resumed = existing_session.is_some();
+        let (upload_id, chunk_size) = match existing_session {
+            Some(session) => session,
+            None => {
+                let upload_id = uploader.init_upload(&filename, total_size, &content_type).await?;
+                (upload_id, uploader.negotiate_chunk_size().await)
+            }
         };
         
-        println!("创建上传任务: {}, 大小: {} 字节, 分片数: {}", filename, total_size, chunks_total);
+        // 计算总分片数，空文件也要上传一个空分片，后端才能完成上传
+        let chunks_total = chunks::chunk_count(total_size, chunk_size).max(1);
+        
+        println!("创建上传任务: {}, 大小: {} 字节, 类型: {}, 分片大小: {} 字节, 分片数: {}", filename, total_size, content_type, chunk_size, chunks_total);
         
         Ok(Self {
             upload_id: upload_id.clone(),
+            session_id: std::sync::Mutex::new(upload_id),
+            resumed,
             filename,
             file_path,
             total_size,
             uploaded_size: Arc::new(AtomicU64::new(0)),
+            completed_chunks: Arc::new(std::sync::Mutex::new(BTreeSet::new())),
             status: Arc::new(Mutex::new(UploadStatus::Pending)),
             uploader,
+            chunk_size,
             chunks_total,
+            modified_at,
+            content_type,
             target_path: target_path.map(|s| s.to_string()),
+            speed: Arc::new(SpeedSampler::new()),
+            retry_count: AtomicU32::new(0),
+            queue_seq: AtomicU64::new(transfer_queue::next_seq()),
+            log: TransferLog::new(),
+            parallel_chunks: settings::get().transfer.upload_parallel_chunks.max(1),
+            verified: AtomicBool::new(false),
+            running: Mutex::new(()),
         })
     }
     
     // 开始上传（或恢复上传）
     pub async fn start(&self) -> Result<()> {
+        // 已经有一个start()在跑，不能再改状态
+        let Ok(_running) = self.running.try_lock() else {
+            return Err(TaskAlreadyRunning.into());
+        };
+        
         // 更新状态为上传中
         *self.status.lock().await = UploadStatus::Uploading;
         
-        println!("开始上传文件: {}, upload_id: {}", self.filename, self.upload_id);
+        self.note(format!("开始上传文件: {}, upload_id: {}", self.filename, self.upload_id));
         
-        // 查询已上传分片，实现断点续传
-        let uploaded_chunks = self.uploader.get_upload_status(&self.upload_id).await
-            .unwrap_or_else(|_| vec![]); // 如果查询失败，当做没有已上传分片
-        
-        println!("已上传分片: {:?}", uploaded_chunks);
+        // 文件被其他程序占用时按设置等待或者改传快照，快照在上传结束后删除
+        let snapshot = match self.prepare_source().await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                self.note(e.to_string());
+                *self.status.lock().await = UploadStatus::FileInUse;
+                return Err(e);
+            }
+        };
+        let source = match &snapshot {
+            Some(snapshot) => snapshot.path(),
+            None => self.file_path.as_path(),
+        };
         
-        // 打开文件
-        let mut file = File::open(&self.file_path).await
-            .context("打开文件失败")?;
+        // 查询已上传分片，实现断点续传；新建的小文件任务不用查
+        let small_file = self.total_size <= self.chunk_size;
+        let uploaded_chunks = if small_file && !self.resumed {
+            Vec::new()
+        } else {
+            match self.query_uploaded_chunks().await {
+                Ok(uploaded_chunks) => uploaded_chunks,
+                Err(e) => {
+                    self.set_error(format!("{:#}", e)).await;
+                    return Err(e);
+                }
+            }
+        };
         
-        // 计算已上传大小
-        let mut already_uploaded = 0u64;
-        for &chunk_index in &uploaded_chunks {
-            let chunk_start = (chunk_index as u64) * CHUNK_SIZE;
-            let chunk_end = if chunk_index == self.chunks_total - 1 {
-                self.total_size - 1
-            } else {
-                chunk_start + CHUNK_SIZE - 1
-            };
-            already_uploaded += chunk_end - chunk_start + 1;
+        // 空文件和不超过一个分片的小文件走快速路径
+        if small_file {
+            return self.upload_small_file(source).await;
         }
         
-        // 更新已上传大小
-        self.uploaded_size.store(already_uploaded, Ordering::SeqCst);
+        println!("已上传分片: {:?}", uploaded_chunks);
+        
+        // 按服务器的记录重新算已上传大小（服务器返回的序号超出范围时按0算）
+        let already_uploaded = self.reset_completed_chunks(&uploaded_chunks);
         
         println!("已上传大小: {} 字节", already_uploaded);
         
-        // 分片上传
+        // 分片上传：读文件和上传分开，中间用有界channel连起来
+        // 上传跟不上时读分片的一方在send上等着，内存里最多只有
+        // parallel_chunks个正在上传的分片 + channel里parallel_chunks个 + 正在读的1个
+        let parallel = self.parallel_chunks;
+        let (chunk_tx, chunk_rx) = mpsc::channel::<(u32, Vec<u8>)>(parallel);
+        let reader = self.read_chunks(source, &uploaded_chunks, chunk_tx);
+        let uploads = async {
+            let mut results = futures::stream::unfold(chunk_rx, |mut rx| async move {
+                rx.recv().await.map(|chunk| (chunk, rx))
+            })
+                .map(move |(chunk_index, chunk_data)| async move {
+                    self.upload_chunk_with_retry(chunk_index, &chunk_data).await
+                })
+                .buffer_unordered(parallel);
+            // 有分片失败就不再等别的分片，channel的接收端随之丢掉，读分片的一方也会停下
+            while let Some(result) = results.next().await {
+                result?;
+            }
+            Ok::<(), anyhow::Error>(())
+        };
+        let (read_result, upload_result) = futures::join!(reader, uploads);
+        upload_result?;
+        if !read_result? {
+            // 中途暂停了，已经读出来的分片传完就停下
+            return Ok(());
+        }
+        
+        self.finish(source).await
+    }
+    
+    // 按顺序读出还没上传的分片放进channel，channel满了就等上传的一方取走
+    // 每个分片读之前检查睡眠/省电、计费网络和暂停；返回false表示中途暂停或出错，没有读完
+    async fn read_chunks(&self, source: &Path, uploaded_chunks: &[u32], chunk_tx: mpsc::Sender<(u32, Vec<u8>)>) -> Result<bool> {
+        let mut file = File::open(source).await
+            .context("打开文件失败")?;
+        
         for chunk_index in 0..self.chunks_total {
             // 跳过已上传的分片
             if uploaded_chunks.contains(&chunk_index) {
@@ -368,122 +597,383 @@ impl UploadTask {
                 continue;
             }
             
-            // 检查状态，如果暂停了就退出循环
-            {
-                let status = self.status.lock().await;
-                match *status {
-                    UploadStatus::Paused => {
-                        println!("上传已暂停");
-                        return Ok(());
-                    }
-                    UploadStatus::Error(_) => {
-                        // 如果已经有错误，直接返回
-                        return Ok(());
-                    }
-                    _ => {}
+            // 系统要睡眠或者省电模式时先停下，唤醒后再继续
+            if power::should_pause() {
+                self.note(format!("系统睡眠/省电模式，上传暂停等待: {}", self.filename));
+                while power::should_pause() && matches!(*self.status.lock().await, UploadStatus::Uploading) {
+                    power::wait_for_change().await;
                 }
             }
             
-            // 计算分片范围
-            let start = (chunk_index as u64) * CHUNK_SIZE;
-            let end = if chunk_index == self.chunks_total - 1 {
-                self.total_size - 1
-            } else {
-                start + CHUNK_SIZE - 1
-            };
-            
-            let chunk_size = (end - start + 1) as usize;
+            // 计费网络上的大文件先停下，换网络、用户允许或者暂停时才往下走
+            if network_profile::should_hold(self.total_size) {
+                self.note("当前是计费网络，大文件暂停等待".to_string());
+                network_profile::notify_held("upload", &self.upload_id, &self.filename, self.total_size);
+                while network_profile::should_hold(self.total_size)
+                    && matches!(*self.status.lock().await, UploadStatus::Uploading)
+                {
+                    network_profile::wait_for_change().await;
+                }
+            }
             
-            // 读取分片数据
-            file.seek(std::io::SeekFrom::Start(start)).await
-                .context("移动文件指针失败")?;
+            // 检查状态，暂停了或者已经有分片失败了就不再往下读
+            match *self.status.lock().await {
+                UploadStatus::Paused => {
+                    println!("上传已暂停");
+                    return Ok(false);
+                }
+                UploadStatus::Error(_) => return Ok(false),
+                _ => {}
+            }
             
-            let mut chunk_data = vec![0u8; chunk_size];
-            let bytes_read = file.read_exact(&mut chunk_data).await
-                .context("读取分片数据失败")?;
+            let chunk_data = self.read_chunk(&mut file, chunk_index).await?;
             
-            if bytes_read != chunk_size {
-                return Err(anyhow::anyhow!(
-                    "读取分片数据大小不匹配: 期望 {}, 实际 {}", 
-                    chunk_size, 
-                    bytes_read
-                ));
+            // 上传的一方已经因为出错停下了
+            if chunk_tx.send((chunk_index, chunk_data)).await.is_err() {
+                return Ok(false);
             }
-            
-            // 分片重试机制
-            let mut last_error = None;
-            for retry_count in 0..3 { // 最多重试3次
-                match self.uploader.upload_chunk(
-                    &self.upload_id,
-                    chunk_index,
-                    &chunk_data,
-                ).await {
-                    Ok(_) => {
-                        // 更新进度
-                        eprintln!("[start] 分片 {} 上传成功，准备更新进度", chunk_index);
-                        self.uploaded_size.fetch_add(chunk_size as u64, Ordering::SeqCst);
-                        eprintln!("[start] 获得锁，更新进度");
-                        
-                        let current_uploaded = self.uploaded_size.load(Ordering::SeqCst);
-                        eprintln!("[start] 分片 {}/{} 上传成功 ({}/{} 字节)，当前进度: {}/{} 字节", 
-                            chunk_index + 1, 
-                            self.chunks_total,
-                            chunk_size,
-                            chunk_size,
-                            current_uploaded,
-                            self.total_size
-                        );
-                        
-                        last_error = None;
-                        break; // 成功，跳出重试循环
-                    }
-                    Err(e) => {
-                        println!("上传分片 {} 失败: {}, 重试 {}/3", chunk_index, e, retry_count + 1);
-                        last_error = Some(e);
-                        // 等待一下再重试
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                    }
+        }
+        Ok(true)
+    }
+    
+    // 从文件里读出一个分片
+    async fn read_chunk(&self, file: &mut File, chunk_index: u32) -> Result<Vec<u8>> {
+        // 计算分片范围
+        let start = (chunk_index as u64) * self.chunk_size;
+        let chunk_size = chunks::chunk_len(chunk_index, self.total_size, self.chunk_size) as usize;
+        
+        // 读取分片数据
+        file.seek(std::io::SeekFrom::Start(start)).await
+            .context("移动文件指针失败")?;
+        
+        let mut chunk_data = vec![0u8; chunk_size];
+        let bytes_read = file.read_exact(&mut chunk_data).await
+            .context("读取分片数据失败")?;
+        
+        if bytes_read != chunk_size {
+            return Err(anyhow::anyhow!(
+                "读取分片数据大小不匹配: 期望 {}, 实际 {}", 
+                chunk_size, 
+                bytes_read
+            ));
+        }
+        Ok(chunk_data)
+    }
+    
+    // 空文件和不超过一个分片的小文件：一次读完整个文件，上传唯一的一个分片
+    // 只有一个分片，不需要查询续传状态；空文件也不用打开文件读0字节
+    async fn upload_small_file(&self, source: &Path) -> Result<()> {
+        let data = if self.total_size == 0 {
+            Vec::new()
+        } else {
+            fs::read(source).await.context("读取文件失败")?
+        };
+        if data.len() as u64 != self.total_size {
+            let error_msg = format!("文件大小在上传前发生变化: 期望 {} 字节，实际 {} 字节", self.total_size, data.len());
+            self.set_error(error_msg.clone()).await;
+            return Err(anyhow::anyhow!(error_msg));
+        }
+        
+        println!("小文件一次上传: {}，{} 字节", self.filename, data.len());
+        self.upload_chunk_with_retry(0, &data).await?;
+        self.finish(source).await
+    }
+    
+    // 检查文件是否被其他程序占用
+    // 没被占用返回None，照常读原文件；按设置复制了快照时返回快照，从快照读
+    async fn prepare_source(&self) -> Result<Option<tempfile::NamedTempFile>> {
+        if !file_lock::is_in_use(&self.file_path).await {
+            return Ok(None);
+        }
+        
+        let transfer = settings::get().transfer;
+        match transfer.in_use_policy {
+            InUsePolicy::Fail => {}
+            InUsePolicy::Wait => {
+                let timeout = std::time::Duration::from_secs(transfer.in_use_wait_secs);
+                if file_lock::wait_until_free(&self.file_path, timeout).await {
+                    return Ok(None);
                 }
             }
-            
-            // 检查重试后是否还有错误
-            if let Some(e) = last_error {
-                *self.status.lock().await = UploadStatus::Error(format!("分片 {} 上传失败: {}", chunk_index, e));
-                return Err(anyhow::anyhow!("分片 {} 上传失败: {}", chunk_index, e));
+            InUsePolicy::Snapshot => return file_lock::snapshot(&self.file_path).await.map(Some),
+        }
+        Err(anyhow::anyhow!("文件正在被其他程序使用，暂不上传: {:?}", self.file_path))
+    }
+    
+    // 上传一个分片，失败时重试，全部失败后把任务标记为出错
+    async fn upload_chunk_with_retry(&self, chunk_index: u32, chunk_data: &[u8]) -> Result<()> {
+        let chunk_size = chunk_data.len();
+        let mut last_error = None;
+        for retry_count in 0..CHUNK_ATTEMPTS {
+            match self.upload_waiting_for_server(chunk_index, chunk_data).await {
+                Ok(_) => {
+                    // 更新进度
+                    eprintln!("[start] 分片 {} 上传成功，准备更新进度", chunk_index);
+                    let current_uploaded = self.mark_chunk_completed(chunk_index);
+                    self.speed.record(chunk_size as u64);
+                    crate::bandwidth::record_uploaded(chunk_size as u64).await;
+                    crate::concurrency::record_chunk(true);
+                    transfer_manager::throttle(Direction::Upload, chunk_size as u64).await;
+                    
+                    eprintln!("[start] 分片 {}/{} 上传成功 ({} 字节)，当前进度: {}/{} 字节", 
+                        chunk_index + 1, 
+                        self.chunks_total,
+                        chunk_size,
+                        current_uploaded,
+                        self.total_size
+                    );
+                    
+                    last_error = None;
+                    break; // 成功，跳出重试循环
+                }
+                Err(e) => {
+                    self.note(format!("上传分片 {} 失败: {}, 重试 {}/{}", chunk_index, e, retry_count + 1, CHUNK_ATTEMPTS));
+                    crate::concurrency::record_chunk(false);
+                    last_error = Some(e);
+                    // 等待一下再重试
+                    tokio::time::sleep(CHUNK_RETRY_DELAY).await;
+                }
             }
         }
         
-        // 所有分片上传完成，调用完成接口
+        // 检查重试后是否还有错误
+        if let Some(e) = last_error {
+            self.set_error(format!("分片 {} 上传失败: {}", chunk_index, e)).await;
+            return Err(anyhow::anyhow!("分片 {} 上传失败: {}", chunk_index, e));
+        }
+        Ok(())
+    }
+    
+    // 上传分片，后端维护中（503 + Retry-After）时等到恢复时间再上传，这样的失败不算重试次数
+    async fn upload_waiting_for_server(&self, chunk_index: u32, chunk_data: &[u8]) -> Result<()> {
+        loop {
+            let result = self.uploader.upload_chunk(&self.session_id(), chunk_index, chunk_data).await;
+            match (result, server_maintenance::resume_at()) {
+                (Err(_), Some(resume_at)) if self.wait_for_server(resume_at).await => continue,
+                (result, _) => return result,
+            }
+        }
+    }
+    
+    // 状态改成WaitingForServer，等到后端恢复再改回上传中；已经暂停或出错时不等，返回false
+    // 并行上传时几个分片会一起等，谁先醒来谁把状态改回去
+    async fn wait_for_server(&self, resume_at: i64) -> bool {
+        {
+            let mut status = self.status.lock().await;
+            if !matches!(*status, UploadStatus::Uploading | UploadStatus::WaitingForServer(_)) {
+                return false;
+            }
+            *status = UploadStatus::WaitingForServer(resume_at);
+        }
+        self.note(format!("后端维护中，等到 {} 再继续上传", resume_at));
+        server_maintenance::wait().await;
+        let mut status = self.status.lock().await;
+        if matches!(*status, UploadStatus::WaitingForServer(_)) {
+            *status = UploadStatus::Uploading;
+        }
+        true
+    }
+    
+    // 所有分片上传完成，先和服务器核对分片，再调用完成接口
+    async fn finish(&self, source: &Path) -> Result<()> {
         eprintln!("[start] 所有分片上传完成，共 {} 个分片，准备调用 finish_upload", self.chunks_total);
         
-        match self.uploader.finish_upload(&self.upload_id, &self.filename, self.chunks_total, self.target_path.as_deref()).await {
+        if let Err(e) = self.reconcile_chunks(source).await {
+            let error_msg = format!("核对分片失败: {}", e);
+            self.set_error(error_msg.clone()).await;
+            return Err(anyhow::anyhow!(error_msg));
+        }
+        
+        match self.uploader.finish_upload(&self.session_id(), &self.filename, self.chunks_total, self.target_path.as_deref(), self.modified_at, Some(&self.content_type)).await {
             Ok(result) => {
-                eprintln!("[start] 上传完成: {}", result);
+                self.note(format!("上传完成: {}", result));
+                if settings::get().transfer.verify_after_upload {
+                    match self.verify_upload(source).await {
+                        Ok(true) => self.note("云端文件核对一致".to_string()),
+                        Ok(false) => {}
+                        Err(e) => {
+                            let error_msg = e.to_string();
+                            self.set_error(error_msg.clone()).await;
+                            return Err(e.into());
+                        }
+                    }
+                }
                 *self.status.lock().await = UploadStatus::Completed;
                 Ok(())
             }
             Err(e) => {
                 let error_msg = format!("[start] 完成上传失败: {}", e);
-                eprintln!("错误: {}", error_msg);
-                *self.status.lock().await = UploadStatus::Error(error_msg.clone());
+                self.set_error(error_msg.clone()).await;
                 Err(anyhow::anyhow!(error_msg))
             }
         }
     }
     
+    // 云端文件的完整路径，和后端finish时拼的一样：target_path/filename
+    fn remote_path(&self) -> String {
+        match self.target_path.as_deref().map(|p| p.trim_matches('/')) {
+            Some(dir) if !dir.is_empty() => format!("{}/{}", dir, self.filename),
+            _ => self.filename.clone(),
+        }
+    }
+    
+    // finish后核对云端文件（见文件开头第6条）
+    // 返回是否核对过，HEAD失败时返回Ok(false)
+    async fn verify_upload(&self, source: &Path) -> std::result::Result<bool, PostUploadMismatch> {
+        let path = self.remote_path();
+        let remote = match self.uploader.stat_remote(&path).await {
+            Ok(remote) => remote,
+            Err(e) => {
+                self.note(format!("核对云端文件失败，跳过核对: {:#}", e));
+                return Ok(false);
+            }
+        };
+        let mut mismatch = PostUploadMismatch {
+            path,
+            local_size: self.total_size,
+            remote_size: remote.as_ref().map(|r| r.size),
+            local_hash: None,
+            remote_hash: remote.as_ref().and_then(|r| r.hash.clone()),
+        };
+        let Some(remote) = remote else {
+            return Err(mismatch);
+        };
+        if remote.size != self.total_size {
+            return Err(mismatch);
+        }
+        if let Some(remote_hash) = remote.hash {
+            match checksum_manifest::hash_file(source).await {
+                Ok(local_hash) if local_hash == remote_hash => {}
+                Ok(local_hash) => {
+                    mismatch.local_hash = Some(local_hash);
+                    return Err(mismatch);
+                }
+                Err(e) => self.note(format!("计算本地文件哈希失败，只核对了大小: {:#}", e)),
+            }
+        }
+        self.verified.store(true, Ordering::SeqCst);
+        Ok(true)
+    }
+    
+    // 完成前再查一次服务器收到了哪些分片，缺的重新上传
+    // 分片请求回复了成功但服务器没存下（连接中途断开、代理重放顺序乱了）时，
+    // 直接finish会得到一个"上传成功"但内容不对的文件
+    // 查询失败（老后端）时照常finish，补传后还缺就报错，不去finish
+    async fn reconcile_chunks(&self, source: &Path) -> Result<()> {
+        for round in 0..=RECONCILE_ROUNDS {
+            let uploaded = match self.uploader.get_upload_status(&self.session_id()).await {
+                Ok(Some(uploaded)) => uploaded,
+                // 上传途中会话过期了，换一个新会话，缺的分片全部重传
+                Ok(None) => self.renew_session().await?,
+                Err(e) => {
+                    self.note(format!("查询上传状态失败，不核对分片: {}", e));
+                    return Ok(());
+                }
+            };
+            let missing: Vec<u32> = (0..self.chunks_total)
+                .filter(|chunk_index| !uploaded.contains(chunk_index))
+                .collect();
+            // 进度以服务器的记录为准，丢了的分片不再算在已上传里
+            self.reset_completed_chunks(&uploaded);
+            if missing.is_empty() {
+                return Ok(());
+            }
+            if round == RECONCILE_ROUNDS {
+                return Err(anyhow::anyhow!("补传后服务器仍缺少分片 {:?}", missing));
+            }
+            
+            self.note(format!("服务器缺少分片 {:?}，重新上传", missing));
+            let mut file = File::open(source).await.context("打开文件失败")?;
+            for chunk_index in missing {
+                let chunk_data = if self.total_size == 0 {
+                    Vec::new()
+                } else {
+                    self.read_chunk(&mut file, chunk_index).await?
+                };
+                self.upload_chunk_with_retry(chunk_index, &chunk_data).await?;
+            }
+        }
+        Ok(())
+    }
+    
+    // 查询服务器已经收到的分片
+    // 思考：上传会话在服务器上有有效期，程序关着的时候过期了，恢复出来的任务拿着原来的upload_id
+    // 查状态会得到404，原来当作"没有已上传分片"继续传，每个分片都404，重试多少次都一样。
+    // 现在404时重新申请一个会话，分片大小和本地记下的分片还按原来的算；
+    // 查询本身失败（网络问题、老后端）时沿用本地记下的分片，完成前reconcile_chunks还会再核对
+    async fn query_uploaded_chunks(&self) -> Result<Vec<u32>> {
+        match self.uploader.get_upload_status(&self.session_id()).await {
+            Ok(Some(uploaded)) => Ok(uploaded),
+            Ok(None) => self.renew_session().await,
+            Err(e) => {
+                self.note(format!("查询上传状态失败，按本地记录续传: {}", e));
+                Ok(self.completed_chunk_list())
+            }
+        }
+    }
+    
+    // 会话在服务器上已经不存在，重新申请一个，返回新会话里已经有的分片
+    async fn renew_session(&self) -> Result<Vec<u32>> {
+        let old_session = self.session_id();
+        let session_id = self.uploader.init_upload(&self.filename, self.total_size, &self.content_type).await
+            .context("上传会话已过期，重新申请会话失败")?;
+        self.note(format!("上传会话 {} 在服务器上已经不存在，换成新会话 {}", old_session, session_id));
+        self.set_session_id(session_id.clone());
+        // 新会话一般是空的，后端认出是同一个文件时可能已经有一部分分片，只传它缺的
+        match self.uploader.get_upload_status(&session_id).await {
+            Ok(Some(uploaded)) => Ok(uploaded),
+            _ => Ok(Vec::new()),
+        }
+    }
+    
+    // 已上传大小 = 完成的分片大小之和，同一个分片重传成功不会算两次
+    fn completed_size(&self, completed: &BTreeSet<u32>) -> u64 {
+        let size = completed
+            .iter()
+            .map(|&chunk_index| chunks::chunk_len(chunk_index, self.total_size, self.chunk_size))
+            .sum();
+        self.uploaded_size.store(size, Ordering::SeqCst);
+        size
+    }
+    
+    // 一个分片上传成功，返回新的已上传大小
+    fn mark_chunk_completed(&self, chunk_index: u32) -> u64 {
+        let mut completed = self.completed_chunks.lock().unwrap_or_else(|e| e.into_inner());
+        completed.insert(chunk_index);
+        self.completed_size(&completed)
+    }
+    
+    // 用服务器返回的已上传分片替换本地的记录，返回新的已上传大小
+    fn reset_completed_chunks(&self, uploaded: &[u32]) -> u64 {
+        let mut completed = self.completed_chunks.lock().unwrap_or_else(|e| e.into_inner());
+        *completed = uploaded.iter().copied().filter(|&index| index < self.chunks_total).collect();
+        self.completed_size(&completed)
+    }
+    
+    // 本地记下的已完成分片
+    pub fn completed_chunk_list(&self) -> Vec<u32> {
+        self.completed_chunks.lock().unwrap_or_else(|e| e.into_inner()).iter().copied().collect()
+    }
+    
+    // 恢复任务时带上之前记下的分片，查询上传状态失败时按这个续传
+    pub fn restore_completed_chunks(&self, chunks: &[u32]) {
+        self.reset_completed_chunks(chunks);
+    }
+    
     // 暂停上传
     pub async fn pause(&self) {
         *self.status.lock().await = UploadStatus::Paused;
-        println!("上传已暂停");
+        self.note("上传已暂停".to_string());
     }
     
     // 获取上传进度
     pub async fn get_progress(&self) -> UploadProgress {
         let uploaded = self.uploaded_size.load(Ordering::SeqCst);
+        let chunks_completed = self.completed_chunks.lock().unwrap_or_else(|e| e.into_inner()).len() as u32;
         let status = self.status.lock().await.clone();
         
-        // 简单计算速度（暂时用0，后续可以添加时间计算）
-        let speed_kbps = 0.0;
+        // 最近几秒的平均速度
+        let speed_kbps = self.speed.current_speed_kbps();
         
         UploadProgress {
             upload_id: self.upload_id.clone(),
@@ -492,12 +982,388 @@ impl UploadTask {
             uploaded,
             status,
             chunks_total: self.chunks_total,
-            chunks_completed: if self.total_size > 0 {
-                ((uploaded as f64) / (self.total_size as f64) * (self.chunks_total as f64)) as u32
-            } else {
-                0
-            },
+            chunks_completed,
+            chunk_size: self.chunk_size,
             speed_kbps,
+            retry_count: self.retry_count.load(Ordering::SeqCst),
+            verified: self.verified.load(Ordering::SeqCst),
         }
     }
-}
\ No newline at end of file
+    
+    pub fn upload_id(&self) -> &str {
+        &self.upload_id
+    }
+    
+    pub fn session_id(&self) -> String {
+        self.session_id.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+    
+    // 恢复任务时换成之前重新申请过的会话
+    pub fn set_session_id(&self, session_id: String) {
+        *self.session_id.lock().unwrap_or_else(|e| e.into_inner()) = session_id;
+    }
+    
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+    
+    pub fn file_path(&self) -> &std::path::Path {
+        &self.file_path
+    }
+    
+    pub fn target_path(&self) -> Option<&str> {
+        self.target_path.as_deref()
+    }
+    
+    // start()是不是还在执行（暂停以后要等当前的分片写完才返回）
+    pub fn is_running(&self) -> bool {
+        self.running.try_lock().is_err()
+    }
+    
+    // 重试时新建的任务要延续之前的重试次数
+    pub fn set_retry_count(&self, count: u32) {
+        self.retry_count.store(count, Ordering::SeqCst);
+    }
+    
+    pub fn retry_count(&self) -> u32 {
+        self.retry_count.load(Ordering::SeqCst)
+    }
+    
+    // 排队序号，重启后按这个顺序恢复
+    pub fn queue_seq(&self) -> u64 {
+        self.queue_seq.load(Ordering::SeqCst)
+    }
+    
+    // 重试或恢复时新建的任务沿用原来的序号
+    pub fn set_queue_seq(&self, seq: u64) {
+        self.queue_seq.store(seq, Ordering::SeqCst);
+    }
+    
+    // 同时上传几个分片，默认按设置里的transfer.upload_parallel_chunks（只有测试用）
+    #[cfg(test)]
+    pub fn set_parallel_chunks(&mut self, parallel: usize) {
+        self.parallel_chunks = parallel.max(1);
+    }
+    
+    // 重试时新建的任务接着写之前任务的日志
+    pub fn inherit_log(&mut self, previous: &UploadTask) {
+        self.log = previous.log.clone();
+    }
+    
+    // 任务日志，最近的在后
+    pub fn log_lines(&self) -> Vec<LogLine> {
+        self.log.lines()
+    }
+    
+    // 输出日志并记到任务日志里
+    pub fn note(&self, message: String) {
+        println!("{}", message);
+        self.log.push(&message);
+    }
+    
+    // 标记为出错，错误原因记到任务日志里
+    async fn set_error(&self, message: String) {
+        self.note(format!("上传出错: {}", message));
+        *self.status.lock().await = UploadStatus::Error(message);
+    }
+    
+    // 获取速度历史（每秒一个采样点），给前端画速度曲线用
+    pub fn get_speed_history(&self) -> Vec<SpeedSample> {
+        self.speed.history()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_backend;
+    
+    const CHUNK_SIZE: u64 = chunks::DEFAULT_CHUNK_SIZE;
+
+    // 生成测试文件内容，不是全0，分片拼错能被发现
+    fn test_content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 17 % 253) as u8).collect()
+    }
+
+    // 在临时目录写一个2.5个分片大小的文件
+    async fn write_test_file(dir: &std::path::Path, name: &str) -> (PathBuf, Vec<u8>) {
+        let content = test_content((CHUNK_SIZE * 2 + CHUNK_SIZE / 2) as usize);
+        let path = dir.join(name);
+        fs::write(&path, &content).await.unwrap();
+        (path, content)
+    }
+
+    #[tokio::test]
+    async fn uploads_file_with_init_chunk_finish() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let (file_path, content) = write_test_file(dir.path(), "basic.bin").await;
+
+        let task = UploadTask::new(file_path, mock_backend::valid_auth(), Some("tests/upload"))
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        assert_eq!(mock_backend::get_file("tests/upload/basic.bin"), Some(content));
+        assert_eq!(mock_backend::get_content_type("tests/upload/basic.bin").as_deref(), Some(content_type::FALLBACK));
+        assert_eq!(mock_backend::requests(task.upload_id()), vec![0, 1, 2]);
+        assert!(matches!(task.get_progress().await.status, UploadStatus::Completed));
+        assert!(task.get_progress().await.verified);
+    }
+
+    #[tokio::test]
+    async fn detects_mismatch_after_finish() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let (file_path, content) = write_test_file(dir.path(), "verify.bin").await;
+
+        let task = UploadTask::new(file_path.clone(), mock_backend::valid_auth(), Some("/tests/upload/"))
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+        assert!(task.verify_upload(&file_path).await.unwrap());
+
+        // 大小一样但内容不一样，靠哈希发现
+        let mut corrupted = content.clone();
+        corrupted[0] ^= 0xff;
+        mock_backend::put_file("tests/upload/verify.bin", corrupted);
+        let e = task.verify_upload(&file_path).await.unwrap_err();
+        assert!(e.local_hash.is_some());
+        assert!(e.to_string().starts_with("[post_upload_mismatch]"));
+
+        mock_backend::put_file("tests/upload/verify.bin", content[1..].to_vec());
+        let e = task.verify_upload(&file_path).await.unwrap_err();
+        assert_eq!(e.remote_size, Some(content.len() as u64 - 1));
+    }
+
+    #[tokio::test]
+    async fn uploads_chunks_in_parallel() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let (file_path, content) = write_test_file(dir.path(), "parallel.bin").await;
+
+        let mut task = UploadTask::new(file_path, mock_backend::valid_auth(), Some("tests/upload"))
+            .await
+            .unwrap();
+        task.set_parallel_chunks(3);
+        task.start().await.unwrap();
+
+        // 分片到达服务器的顺序不固定，拼出来的文件要一样
+        assert_eq!(mock_backend::get_file("tests/upload/parallel.bin"), Some(content));
+        let mut requests = mock_backend::requests(task.upload_id());
+        requests.sort();
+        assert_eq!(requests, vec![0, 1, 2]);
+        assert_eq!(task.get_progress().await.uploaded, CHUNK_SIZE * 2 + CHUNK_SIZE / 2);
+    }
+
+    #[tokio::test]
+    async fn resends_chunks_the_server_lost_before_finishing() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let (file_path, content) = write_test_file(dir.path(), "lost.bin").await;
+
+        let task = UploadTask::new(file_path, mock_backend::valid_auth(), Some("tests/upload"))
+            .await
+            .unwrap();
+        mock_backend::lose_chunk(task.upload_id(), 1);
+        task.start().await.unwrap();
+
+        assert_eq!(mock_backend::get_file("tests/upload/lost.bin"), Some(content));
+        assert_eq!(mock_backend::requests(task.upload_id()), vec![0, 2, 1]);
+        // 补传的分片不会算两次
+        let progress = task.get_progress().await;
+        assert_eq!(progress.uploaded, CHUNK_SIZE * 2 + CHUNK_SIZE / 2);
+        assert_eq!(progress.chunks_completed, 3);
+    }
+
+    #[tokio::test]
+    async fn rejects_second_start_while_running() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let (file_path, content) = write_test_file(dir.path(), "twice.bin").await;
+
+        let task = UploadTask::new(file_path, mock_backend::valid_auth(), Some("tests/upload"))
+            .await
+            .unwrap();
+        let (first, second) = tokio::join!(task.start(), task.start());
+        first.unwrap();
+        assert!(second.unwrap_err().is::<TaskAlreadyRunning>());
+
+        // 每个分片只上传一次
+        assert_eq!(mock_backend::get_file("tests/upload/twice.bin"), Some(content));
+        assert_eq!(mock_backend::requests(task.upload_id()), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn resumes_session_without_reuploading_chunks() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let (file_path, content) = write_test_file(dir.path(), "resume.bin").await;
+
+        // 第二个分片开始一直失败
+        let task = UploadTask::new(file_path.clone(), mock_backend::valid_auth(), Some("tests/upload"))
+            .await
+            .unwrap();
+        let upload_id = task.upload_id().to_string();
+        let chunk_size = task.chunk_size();
+        mock_backend::fail_from(&upload_id, 1);
+        assert!(task.start().await.is_err());
+
+        // 复用同一个上传会话，服务器告诉我们分片0已经上传过
+        mock_backend::clear_faults(&upload_id);
+        let task = UploadTask::resume_session(file_path, mock_backend::valid_auth(), Some("tests/upload"), upload_id.clone(), chunk_size)
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        assert_eq!(mock_backend::get_file("tests/upload/resume.bin"), Some(content));
+        assert_eq!(mock_backend::requests(&upload_id), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn renews_expired_session_on_resume() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let (file_path, content) = write_test_file(dir.path(), "expired.bin").await;
+
+        let task = UploadTask::new(file_path.clone(), mock_backend::valid_auth(), Some("tests/upload"))
+            .await
+            .unwrap();
+        let upload_id = task.upload_id().to_string();
+        let chunk_size = task.chunk_size();
+        mock_backend::fail_from(&upload_id, 1);
+        assert!(task.start().await.is_err());
+
+        // 程序关着的时候会话过期了，恢复时换一个新会话重新上传
+        mock_backend::expire_upload(&upload_id);
+        let task = UploadTask::resume_session(file_path, mock_backend::valid_auth(), Some("tests/upload"), upload_id.clone(), chunk_size)
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        assert_eq!(task.upload_id(), upload_id);
+        assert_ne!(task.session_id(), upload_id);
+        assert_eq!(mock_backend::get_file("tests/upload/expired.bin"), Some(content));
+        assert_eq!(mock_backend::requests(&task.session_id()), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn retries_flaky_chunk_uploads() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let (file_path, content) = write_test_file(dir.path(), "flaky.bin").await;
+
+        let task = UploadTask::new(file_path, mock_backend::valid_auth(), Some("tests/upload"))
+            .await
+            .unwrap();
+        // 连续失败两次，第三次重试成功
+        mock_backend::fail_next(task.upload_id(), 2);
+        task.start().await.unwrap();
+
+        assert_eq!(mock_backend::get_file("tests/upload/flaky.bin"), Some(content));
+    }
+
+    #[tokio::test]
+    async fn uploads_empty_file_as_single_chunk() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("empty.bin");
+        fs::write(&file_path, b"").await.unwrap();
+
+        let task = UploadTask::new(file_path, mock_backend::valid_auth(), Some("tests/upload"))
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        assert_eq!(mock_backend::get_file("tests/upload/empty.bin"), Some(Vec::new()));
+        assert_eq!(mock_backend::requests(task.upload_id()), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn sends_local_modified_time() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let (file_path, _) = write_test_file(dir.path(), "mtime.bin").await;
+        crate::download::set_modified_time(&file_path, 1_500_000_000).await.unwrap();
+
+        let task = UploadTask::new(file_path, mock_backend::valid_auth(), Some("tests/upload"))
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        assert_eq!(mock_backend::get_modified("tests/upload/mtime.bin"), Some(1_500_000_000));
+    }
+
+    #[tokio::test]
+    async fn uploads_tiny_file_in_one_chunk() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("tiny.bin");
+        let content = test_content(100);
+        fs::write(&file_path, &content).await.unwrap();
+
+        let task = UploadTask::new(file_path, mock_backend::valid_auth(), Some("tests/upload"))
+            .await
+            .unwrap();
+        task.start().await.unwrap();
+
+        assert_eq!(mock_backend::get_file("tests/upload/tiny.bin"), Some(content));
+        assert_eq!(mock_backend::requests(task.upload_id()), vec![0]);
+        assert_eq!(task.get_progress().await.uploaded, 100);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks_follow_or_skip_by_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target.bin");
+        fs::write(&target, b"hello").await.unwrap();
+        let link = dir.path().join("link.bin");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        assert_eq!(check_source(&link, SymlinkPolicy::Follow).await.unwrap(), SourceCheck::Regular(5));
+        assert_eq!(check_source(&link, SymlinkPolicy::Skip).await.unwrap(), SourceCheck::SkippedSymlink);
+        assert_eq!(check_source(&target, SymlinkPolicy::Skip).await.unwrap(), SourceCheck::Regular(5));
+
+        // 指向不存在文件的链接
+        let broken = dir.path().join("broken.bin");
+        std::os::unix::fs::symlink(dir.path().join("missing.bin"), &broken).unwrap();
+        assert!(check_source(&broken, SymlinkPolicy::Follow).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_directories_and_special_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let error = check_source(dir.path(), SymlinkPolicy::Follow).await.unwrap_err().to_string();
+        assert!(error.contains("目录"), "{}", error);
+
+        #[cfg(unix)]
+        {
+            let error = check_source(Path::new("/dev/null"), SymlinkPolicy::Follow).await.unwrap_err().to_string();
+            assert!(error.contains("设备文件"), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_credentials() {
+        mock_backend::start();
+        let dir = tempfile::tempdir().unwrap();
+        let (file_path, _) = write_test_file(dir.path(), "auth.bin").await;
+
+        let result = UploadTask::new(file_path, mock_backend::invalid_auth(), None).await;
+
+        let error = result.err().expect("认证失败时不应该创建任务").to_string();
+        assert!(error.contains("401"), "{}", error);
+    }
+
+    #[test]
+    fn accepts_backend_response_variants() {
+        let init: InitUploadResponse = transfer_http::parse_body(r#"{"uploadId": 42, "extra": true}"#, "初始化上传").unwrap();
+        assert_eq!(init.upload_id, "42");
+        let status: UploadStatusResponse = transfer_http::parse_body(r#"{"uploadedChunks": [0, 2]}"#, "查询上传状态").unwrap();
+        assert_eq!(status.uploaded_chunks, vec![0, 2]);
+
+        let err = transfer_http::parse_body::<InitUploadResponse>(r#"{"session": "x"}"#, "初始化上传").unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("upload_id"));
+    }
+}
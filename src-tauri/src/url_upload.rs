@@ -0,0 +1,259 @@
+// 从网址上传到云盘
+// upload_from_url(url, target_path)：把网上的文件直接存到云盘，不用先下载到本地再上传
+//
+// 思考：
+// 1. 后端的/capabilities里remote_fetch为true时，让后端自己去下载（/upload/remote），
+//    客户端不用过一遍数据，最省流量
+// 2. 后端不支持时由客户端中转：边从网址读边按协商好的分片大小切块，
+//    走和普通上传一样的init/chunk/finish，内存里最多留一个分片
+// 3. 网址可能不给Content-Length，这时总大小未知，进度里total为null；
+//    上传会话init时也不需要总大小，最后按实际分片数finish
+// 4. 中转要占一个传输名额（concurrency），也受计费网络和后台模式的限速
+// 5. 请求网址时用不带认证头的共享客户端，认证信息不能发给第三方网站
+// 文件名优先用Content-Disposition里的，没有就取网址路径的最后一段。
+// 文件类型优先用网址返回的Content-Type，没有或者是application/octet-stream时按文件名猜。
+
+use anyhow::{Context, Result};
+use reqwest::header;
+use serde::Serialize;
+
+use crate::auth::AuthInfo;
+use crate::event_emitter;
+use crate::transfer_http::{CHUNK_ATTEMPTS, CHUNK_RETRY_DELAY};
+use crate::transfer_manager::{self, DirectionGuard};
+use crate::transfer_queue::Direction;
+use crate::upload::ChunkUploader;
+use crate::{concurrency, content_type, supervisor};
+
+// 文件名实在取不到时用这个
+const FALLBACK_FILENAME: &str = "download";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchMode {
+    Server,   // 后端拉取
+    Client,   // 客户端中转
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UrlUploadJob {
+    pub job_id: String,
+    pub mode: FetchMode,
+    pub filename: String,
+}
+
+// 检查网址，只接受http/https
+fn parse_url(url: &str) -> Result<reqwest::Url> {
+    let parsed = reqwest::Url::parse(url.trim()).context("网址格式不正确")?;
+    match parsed.scheme() {
+        "http" | "https" => Ok(parsed),
+        scheme => Err(anyhow::anyhow!("不支持的网址协议: {}", scheme)),
+    }
+}
+
+// 网址路径的最后一段
+fn filename_from_url(url: &reqwest::Url) -> Option<String> {
+    let segment = url.path_segments()?.filter(|s| !s.is_empty()).next_back()?;
+    let name = urlencoding::decode(segment).ok()?.trim().to_string();
+    sanitize(&name)
+}
+
+// Content-Disposition: attachment; filename="a.pdf" / filename*=UTF-8''%E6%96%87.pdf
+fn filename_from_disposition(value: &str) -> Option<String> {
+    let mut plain = None;
+    for part in value.split(';').map(str::trim) {
+        if let Some(encoded) = part.strip_prefix("filename*=") {
+            let encoded = encoded.rsplit("''").next().unwrap_or(encoded);
+            if let Some(name) = urlencoding::decode(encoded.trim_matches('"')).ok().and_then(|n| sanitize(&n)) {
+                return Some(name);
+            }
+        } else if let Some(name) = part.strip_prefix("filename=") {
+            plain = sanitize(name.trim_matches('"'));
+        }
+    }
+    plain
+}
+
+// 网址返回的文件类型，去掉charset之类的参数
+fn content_type_of(response: &reqwest::Response, filename: &str) -> String {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty() && v != content_type::FALLBACK)
+        .unwrap_or_else(|| content_type::detect(filename, &[]))
+}
+
+// 去掉路径部分，不让网站指定的文件名跑到别的目录去
+fn sanitize(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    (!name.is_empty() && name != "." && name != "..").then(|| name.to_string())
+}
+
+// 开始从网址上传，后端能拉取时等后端完成后返回，否则在后台中转，进度通过事件通知
+pub async fn start(auth_info: AuthInfo, url: &str, target_path: Option<String>) -> Result<UrlUploadJob> {
+    let parsed = parse_url(url)?;
+    let job_id = uuid::Uuid::new_v4().to_string();
+    let uploader = ChunkUploader::new(auth_info)?;
+
+    if uploader.supports_remote_fetch().await {
+        let filename = filename_from_url(&parsed).unwrap_or_else(|| FALLBACK_FILENAME.to_string());
+        println!("后端拉取网址: {} -> {:?}/{}", parsed, target_path, filename);
+        uploader.upload_remote(parsed.as_str(), &filename, target_path.as_deref()).await?;
+        return Ok(UrlUploadJob { job_id, mode: FetchMode::Server, filename });
+    }
+
+    // 先连上网址拿到文件名，连不上直接报错给前端
+    let response = crate::http_client::shared_client()?
+        .get(parsed.clone())
+        .send()
+        .await
+        .context("请求网址失败")?
+        .error_for_status()
+        .context("网址返回错误")?;
+    let filename = response
+        .headers()
+        .get(header::CONTENT_DISPOSITION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(filename_from_disposition)
+        .or_else(|| filename_from_url(response.url()))
+        .unwrap_or_else(|| FALLBACK_FILENAME.to_string());
+
+    let job = UrlUploadJob { job_id: job_id.clone(), mode: FetchMode::Client, filename: filename.clone() };
+    supervisor::spawn(format!("url_upload:{}", job_id), async move {
+        let url = parsed.to_string();
+        match relay(&job_id, response, &uploader, &filename, target_path.as_deref()).await {
+            Ok(size) => {
+                println!("网址上传完成: {} -> {}，{} 字节", url, filename, size);
+                event_emitter::emit_event("url-upload-completed", serde_json::json!({
+                    "job_id": job_id,
+                    "url": url,
+                    "filename": filename,
+                    "target_path": target_path,
+                    "size": size,
+                }));
+            }
+            Err(e) => {
+                println!("网址上传失败: {}，错误: {:#}", url, e);
+                event_emitter::emit_event("url-upload-failed", serde_json::json!({
+                    "job_id": job_id,
+                    "url": url,
+                    "error": format!("{:#}", e),
+                }));
+            }
+        }
+    });
+    Ok(job)
+}
+
+// 客户端中转：边读边按分片上传，返回总字节数
+async fn relay(
+    job_id: &str,
+    mut response: reqwest::Response,
+    uploader: &ChunkUploader,
+    filename: &str,
+    target_path: Option<&str>,
+) -> Result<u64> {
+    let _permit = concurrency::acquire().await;
+    let _direction = DirectionGuard::new(Direction::Upload);
+    let _session = crate::cpen_device_manager::open_session();
+    let total = response.content_length();
+    let content_type = content_type_of(&response, filename);
+    let upload_id = uploader.init_upload(filename, total.unwrap_or(0), &content_type).await?;
+    let chunk_size = uploader.negotiate_chunk_size().await as usize;
+
+    let mut buffer: Vec<u8> = Vec::with_capacity(chunk_size);
+    let mut chunk_index = 0u32;
+    let mut uploaded = 0u64;
+    loop {
+        let piece = response.chunk().await.context("读取网址内容失败")?;
+        let finished = piece.is_none();
+        if let Some(piece) = piece {
+            crate::bandwidth::record_downloaded(piece.len() as u64).await;
+            buffer.extend_from_slice(&piece);
+        }
+
+        // 凑满一个分片就上传；读完后剩下的不满一个分片也传上去，空文件传一个空分片
+        while buffer.len() >= chunk_size || (finished && (!buffer.is_empty() || chunk_index == 0)) {
+            let rest = buffer.split_off(buffer.len().min(chunk_size));
+            let chunk = std::mem::replace(&mut buffer, rest);
+            upload_with_retry(uploader, &upload_id, chunk_index, &chunk).await?;
+            chunk_index += 1;
+            uploaded += chunk.len() as u64;
+            event_emitter::emit_event("url-upload-progress", serde_json::json!({
+                "job_id": job_id,
+                "uploaded": uploaded,
+                "total": total,
+            }));
+        }
+        if finished {
+            break;
+        }
+    }
+
+    uploader.finish_upload(&upload_id, filename, chunk_index, target_path, None, Some(&content_type)).await?;
+    Ok(uploaded)
+}
+
+async fn upload_with_retry(uploader: &ChunkUploader, upload_id: &str, chunk_index: u32, chunk: &[u8]) -> Result<()> {
+    let mut last_error = None;
+    for retry_count in 0..CHUNK_ATTEMPTS {
+        if retry_count > 0 {
+            tokio::time::sleep(CHUNK_RETRY_DELAY).await;
+        }
+        match uploader.upload_chunk(upload_id, chunk_index, chunk).await {
+            Ok(()) => {
+                crate::bandwidth::record_uploaded(chunk.len() as u64).await;
+                transfer_manager::throttle(Direction::Upload, chunk.len() as u64).await;
+                return Ok(());
+            }
+            Err(e) => {
+                println!("网址上传分片 {} 失败: {}, 重试 {}/{}", chunk_index, e, retry_count + 1, CHUNK_ATTEMPTS);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("上传分片 {} 失败", chunk_index)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_backend;
+
+    #[test]
+    fn picks_filename_from_headers_and_url() {
+        assert_eq!(filename_from_disposition("attachment; filename=\"a.pdf\""), Some("a.pdf".to_string()));
+        assert_eq!(
+            filename_from_disposition("attachment; filename=\"x.pdf\"; filename*=UTF-8''%E6%8A%A5%E5%91%8A.pdf"),
+            Some("报告.pdf".to_string())
+        );
+        // 不让网站用文件名指定目录
+        assert_eq!(filename_from_disposition("attachment; filename=\"../../evil.sh\""), Some("evil.sh".to_string()));
+        assert_eq!(filename_from_disposition("inline"), None);
+
+        let url = parse_url("https://example.com/files/%E5%9B%BE%E7%89%87.png?x=1").unwrap();
+        assert_eq!(filename_from_url(&url), Some("图片.png".to_string()));
+        assert_eq!(filename_from_url(&parse_url("https://example.com/").unwrap()), None);
+        assert!(parse_url("file:///etc/passwd").is_err());
+    }
+
+    #[tokio::test]
+    async fn relays_remote_file_in_chunks() {
+        let base_url = mock_backend::start();
+        let content: Vec<u8> = (0..(crate::chunks::DEFAULT_CHUNK_SIZE * 2 + 100) as usize)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        mock_backend::put_public("web/relay.bin", content.clone());
+
+        let url = format!("{}/public/web/relay.bin", base_url);
+        let response = crate::http_client::shared_client().unwrap().get(&url).send().await.unwrap();
+        let uploader = ChunkUploader::new(mock_backend::valid_auth()).unwrap();
+        let size = relay("job", response, &uploader, "relay.bin", Some("tests/url_upload")).await.unwrap();
+
+        assert_eq!(size, content.len() as u64);
+        assert_eq!(mock_backend::get_file("tests/url_upload/relay.bin"), Some(content));
+    }
+}
@@ -0,0 +1,559 @@
+// WebDAV服务模块
+// 在本机启动一个WebDAV服务，把云盘挂载到资源管理器/访达里使用
+//
+// 思考：WebDAV协议本身交给dav-server处理，这里只实现它需要的文件系统接口，
+// 所有操作都转发给后端：列目录/删除/建目录走cloud_api，
+// 读文件按分片下载（ChunkDownloader），写文件先落到临时文件，flush时走上传流程。
+// 认证信息每次都从笔获取，服务只监听127.0.0.1，不对外暴露。
+// 只监听本机还不够：网页可以用DNS重绑定（恶意域名解析到127.0.0.1）访问这个端口，读写删云盘文件。所以：
+// 1. 每次启动生成一个随机路径前缀，挂载地址是 http://127.0.0.1:端口/<前缀>/，不知道前缀的请求一律404
+// 2. Host必须是127.0.0.1:端口或localhost:端口，带了Origin时Origin也必须是本机地址，否则403
+// 写文件只支持整个文件重写：临时文件从空文件开始，已经存在的文件不截断地打开（局部PUT、追加）
+// 会把没写到的部分丢掉，这种打开直接拒绝。
+
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{Result, Context};
+use bytes::{Buf, Bytes};
+use dav_server::davpath::DavPath;
+use dav_server::fakels::FakeLs;
+use dav_server::fs::{
+    DavDirEntry, DavFile, DavFileSystem, DavMetaData, FsError, FsFuture, FsResult, FsStream,
+    OpenOptions, ReadDirMeta,
+};
+use dav_server::DavHandler;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use futures::FutureExt;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{oneshot, Mutex};
+
+use crate::cloud_api::{self, RemoteEntry};
+use crate::auth::AuthInfo;
+use crate::download::ChunkDownloader;
+use crate::upload::UploadTask;
+use crate::supervisor;
+
+// 读文件时每次从后端取的大小
+const READ_AHEAD_SIZE: u64 = 256 * 1024;
+// TOTP有效期30秒，认证信息用20秒就重新获取
+const AUTH_MAX_AGE: Duration = Duration::from_secs(20);
+// 写入中的文件放在临时目录下的这个子目录里
+pub const SCRATCH_DIR_NAME: &str = "webdav";
+
+// 正在运行的WebDAV服务
+struct RunningServer {
+    port: u16,
+    secret: String,   // 这次启动的路径前缀
+    shutdown: oneshot::Sender<()>,
+}
+
+static WEBDAV_SERVER: OnceLock<Mutex<Option<RunningServer>>> = OnceLock::new();
+
+fn server_state() -> &'static Mutex<Option<RunningServer>> {
+    WEBDAV_SERVER.get_or_init(|| Mutex::new(None))
+}
+
+async fn acquire_auth() -> FsResult<AuthInfo> {
+    crate::acquire_auth_info().await.map_err(|e| {
+        println!("WebDAV获取认证信息失败: {}", e);
+        FsError::Forbidden
+    })
+}
+
+fn general_failure(e: anyhow::Error) -> FsError {
+    println!("WebDAV操作失败: {}", e);
+    FsError::GeneralFailure
+}
+
+// DavPath转成云盘路径，例如 "/ds/下载.png" -> "ds/下载.png"
+fn remote_path(path: &DavPath) -> String {
+    String::from_utf8_lossy(path.as_bytes())
+        .trim_matches('/')
+        .to_string()
+}
+
+// 拆成父目录和文件名
+fn split_parent(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some((parent, name)) => (parent, name),
+        None => ("", path),
+    }
+}
+
+// 文件/目录元数据
+#[derive(Debug, Clone)]
+struct CloudMeta {
+    is_dir: bool,
+    size: u64,
+    modified: SystemTime,
+}
+
+impl From<&RemoteEntry> for CloudMeta {
+    fn from(entry: &RemoteEntry) -> Self {
+        let modified = entry
+            .modified_timestamp()
+            .and_then(|t| u64::try_from(t).ok())
+            .map(|t| UNIX_EPOCH + Duration::from_secs(t))
+            .unwrap_or(UNIX_EPOCH);
+        Self {
+            is_dir: entry.is_dir,
+            size: entry.size,
+            modified,
+        }
+    }
+}
+
+impl DavMetaData for CloudMeta {
+    fn len(&self) -> u64 {
+        self.size
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        Ok(self.modified)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+}
+
+struct CloudDirEntry {
+    entry: RemoteEntry,
+}
+
+impl DavDirEntry for CloudDirEntry {
+    fn name(&self) -> Vec<u8> {
+        self.entry.name.as_bytes().to_vec()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let meta = CloudMeta::from(&self.entry);
+        async move { Ok(Box::new(meta) as Box<dyn DavMetaData>) }.boxed()
+    }
+}
+
+// 只读打开的远程文件，按需分段下载
+#[derive(Debug)]
+struct CloudReadFile {
+    file_id: String,
+    meta: CloudMeta,
+    pos: u64,
+    // 最近一次下载的数据 (起始偏移, 数据)
+    buffer: Option<(u64, Bytes)>,
+    auth: Option<(Instant, AuthInfo)>,
+}
+
+impl CloudReadFile {
+    async fn downloader(&mut self) -> FsResult<ChunkDownloader> {
+        let fresh = self.auth.as_ref().is_some_and(|(t, _)| t.elapsed() < AUTH_MAX_AGE);
+        if !fresh {
+            self.auth = Some((Instant::now(), acquire_auth().await?));
+        }
+        let auth = self.auth.as_ref().map(|(_, a)| a.clone()).ok_or(FsError::Forbidden)?;
+        ChunkDownloader::new(auth).map_err(general_failure)
+    }
+
+    async fn read_at_pos(&mut self, count: usize) -> FsResult<Bytes> {
+        if self.pos >= self.meta.size || count == 0 {
+            return Ok(Bytes::new());
+        }
+
+        let cached = self.buffer.as_ref().is_some_and(|(start, data)| {
+            self.pos >= *start && self.pos < *start + data.len() as u64
+        });
+        if !cached {
+            let start = self.pos;
+            let end = std::cmp::min(start + READ_AHEAD_SIZE, self.meta.size) - 1;
+            let downloader = self.downloader().await?;
+            let data = downloader
+                .download_chunk(&self.file_id, (start / READ_AHEAD_SIZE) as u32, start, end)
+                .await
+                .map_err(general_failure)?;
+            crate::bandwidth::record_downloaded(data.len() as u64).await;
+            self.buffer = Some((start, Bytes::from(data)));
+        }
+
+        let (start, data) = self.buffer.as_ref().ok_or(FsError::GeneralFailure)?;
+        let offset = (self.pos - start) as usize;
+        let len = std::cmp::min(count, data.len() - offset);
+        let slice = data.slice(offset..offset + len);
+        self.pos += len as u64;
+        Ok(slice)
+    }
+}
+
+impl DavFile for CloudReadFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let meta = self.meta.clone();
+        async move { Ok(Box::new(meta) as Box<dyn DavMetaData>) }.boxed()
+    }
+
+    fn write_buf(&mut self, _buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        async move { Err(FsError::Forbidden) }.boxed()
+    }
+
+    fn write_bytes(&mut self, _buf: Bytes) -> FsFuture<'_, ()> {
+        async move { Err(FsError::Forbidden) }.boxed()
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, Bytes> {
+        self.read_at_pos(count).boxed()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(offset) => self.meta.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        async move {
+            let new_pos = new_pos.ok_or(FsError::GeneralFailure)?;
+            self.pos = new_pos;
+            Ok(new_pos)
+        }
+        .boxed()
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        async move { Ok(()) }.boxed()
+    }
+}
+
+// 写入的文件，先写到本地临时文件，flush时上传
+#[derive(Debug)]
+struct CloudWriteFile {
+    remote_path: String,
+    local_path: PathBuf,
+    file: tokio::fs::File,
+    size: u64,
+    dirty: bool,
+}
+
+impl CloudWriteFile {
+    async fn create(remote_path: String) -> FsResult<Self> {
+        let (_, name) = split_parent(&remote_path);
+        // 上传用本地文件名作为云盘文件名，所以每个文件放在单独的临时目录里
+        let dir = crate::settings::scratch_subdir(SCRATCH_DIR_NAME)
+            .join(uuid::Uuid::new_v4().to_string());
+        tokio::fs::create_dir_all(&dir).await.map_err(|_| FsError::GeneralFailure)?;
+        let local_path = dir.join(name);
+        let file = tokio::fs::File::create(&local_path)
+            .await
+            .map_err(|_| FsError::GeneralFailure)?;
+
+        Ok(Self {
+            remote_path,
+            local_path,
+            file,
+            size: 0,
+            dirty: true,
+        })
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> FsResult<()> {
+        self.file.write_all(data).await.map_err(|_| FsError::GeneralFailure)?;
+        let pos = self.file.stream_position().await.map_err(|_| FsError::GeneralFailure)?;
+        self.size = std::cmp::max(self.size, pos);
+        self.dirty = true;
+        Ok(())
+    }
+
+    // 把临时文件上传到云盘
+    async fn upload(&mut self) -> FsResult<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.file.sync_all().await.map_err(|_| FsError::GeneralFailure)?;
+
+        let (parent, _) = split_parent(&self.remote_path);
+        let target_path = (!parent.is_empty()).then_some(parent);
+        let auth = acquire_auth().await?;
+
+        println!("WebDAV上传文件: {:?} -> {}", self.local_path, self.remote_path);
+        let task = UploadTask::new(self.local_path.clone(), auth, target_path)
+            .await
+            .map_err(general_failure)?;
+        task.start().await.map_err(general_failure)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for CloudWriteFile {
+    fn drop(&mut self) {
+        // 清理临时目录
+        if let Some(dir) = self.local_path.parent() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+impl DavFile for CloudWriteFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        let meta = CloudMeta {
+            is_dir: false,
+            size: self.size,
+            modified: SystemTime::now(),
+        };
+        async move { Ok(Box::new(meta) as Box<dyn DavMetaData>) }.boxed()
+    }
+
+    fn write_buf(&mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        async move {
+            while buf.has_remaining() {
+                let chunk = buf.chunk().to_vec();
+                self.write_all(&chunk).await?;
+                buf.advance(chunk.len());
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<'_, ()> {
+        async move { self.write_all(&buf).await }.boxed()
+    }
+
+    fn read_bytes(&mut self, _count: usize) -> FsFuture<'_, Bytes> {
+        async move { Err(FsError::Forbidden) }.boxed()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        async move {
+            self.file.seek(pos).await.map_err(|_| FsError::GeneralFailure)
+        }
+        .boxed()
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        self.upload().boxed()
+    }
+}
+
+// 转发到云盘后端的文件系统
+#[derive(Clone)]
+struct CloudDavFs;
+
+impl DavFileSystem for CloudDavFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let remote = remote_path(path);
+
+            if options.write {
+                let overwrite = options.truncate && !options.append;
+                if options.create_new || !overwrite || !options.create {
+                    let auth = acquire_auth().await?;
+                    let exists = cloud_api::find_entry(&auth, &remote).await.map_err(general_failure)?.is_some();
+                    if exists && options.create_new {
+                        return Err(FsError::Exists);
+                    }
+                    // 只支持整个文件重写（见开头的说明）
+                    if exists && !overwrite {
+                        return Err(FsError::NotImplemented);
+                    }
+                    if !exists && !options.create && !options.create_new {
+                        return Err(FsError::NotFound);
+                    }
+                }
+                let file = CloudWriteFile::create(remote).await?;
+                return Ok(Box::new(file) as Box<dyn DavFile>);
+            }
+
+            let auth = acquire_auth().await?;
+            let entry = cloud_api::find_entry(&auth, &remote)
+                .await
+                .map_err(general_failure)?
+                .ok_or(FsError::NotFound)?;
+            if entry.is_dir {
+                return Err(FsError::Forbidden);
+            }
+
+            let file = CloudReadFile {
+                file_id: remote,
+                meta: CloudMeta::from(&entry),
+                pos: 0,
+                buffer: None,
+                auth: Some((Instant::now(), auth)),
+            };
+            Ok(Box::new(file) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        _meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>> {
+        async move {
+            let auth = acquire_auth().await?;
+            let remote = format!("/{}", remote_path(path));
+            let entries = cloud_api::list_dir(&auth, &remote).await.map_err(general_failure)?;
+
+            let entries: Vec<FsResult<Box<dyn DavDirEntry>>> = entries
+                .into_iter()
+                .map(|entry| Ok(Box::new(CloudDirEntry { entry }) as Box<dyn DavDirEntry>))
+                .collect();
+            Ok(Box::pin(futures::stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let auth = acquire_auth().await?;
+            let entry = cloud_api::find_entry(&auth, &remote_path(path))
+                .await
+                .map_err(general_failure)?
+                .ok_or(FsError::NotFound)?;
+            Ok(Box::new(CloudMeta::from(&entry)) as Box<dyn DavMetaData>)
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let auth = acquire_auth().await?;
+            let remote = remote_path(path);
+            let (parent, name) = split_parent(&remote);
+            cloud_api::create_dir(&auth, parent, name).await.map_err(general_failure)
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let auth = acquire_auth().await?;
+            cloud_api::delete(&auth, &remote_path(path), false).await.map_err(general_failure)
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let auth = acquire_auth().await?;
+            cloud_api::delete(&auth, &remote_path(path), false).await.map_err(general_failure)
+        }
+        .boxed()
+    }
+}
+
+// 检查请求是不是本机的WebDAV客户端发来的（见开头的说明）
+fn check_request(host: Option<&str>, origin: Option<&str>, path: &str, port: u16, secret: &str) -> Result<(), StatusCode> {
+    let local_hosts = [format!("127.0.0.1:{}", port), format!("localhost:{}", port)];
+    if !host.is_some_and(|host| local_hosts.iter().any(|h| h.eq_ignore_ascii_case(host))) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if let Some(origin) = origin {
+        let allowed = local_hosts.iter().any(|h| origin.eq_ignore_ascii_case(&format!("http://{}", h)));
+        if !allowed {
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+
+    let prefix = format!("/{}", secret);
+    let under_prefix = path.strip_prefix(&prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+    if !under_prefix {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(())
+}
+
+fn mount_url(port: u16, secret: &str) -> String {
+    format!("http://127.0.0.1:{}/{}/", port, secret)
+}
+
+// 启动WebDAV服务，port为0时由系统分配端口，返回实际监听的端口
+pub async fn start(port: u16) -> Result<u16> {
+    let mut state = server_state().lock().await;
+    if let Some(server) = state.as_ref() {
+        return Err(anyhow::anyhow!("WebDAV服务已在运行，端口: {}", server.port));
+    }
+
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+        .await
+        .context(format!("监听端口 {} 失败", port))?;
+    let port = listener.local_addr().context("获取监听地址失败")?.port();
+
+    let secret = uuid::Uuid::new_v4().simple().to_string();
+    let dav = DavHandler::builder()
+        .filesystem(Box::new(CloudDavFs))
+        .locksystem(FakeLs::new())
+        .strip_prefix(format!("/{}", secret))
+        .build_handler();
+
+    let app_secret = secret.clone();
+    let app = axum::Router::new().fallback(move |req: axum::extract::Request| {
+        let dav = dav.clone();
+        let secret = app_secret.clone();
+        async move {
+            let get_header = |name: header::HeaderName| req.headers().get(name).and_then(|v| v.to_str().ok());
+            if let Err(status) = check_request(get_header(header::HOST), get_header(header::ORIGIN), req.uri().path(), port, &secret) {
+                println!("WebDAV拒绝请求: {} {}（{}）", req.method(), req.uri().path(), status);
+                return status.into_response();
+            }
+            dav.handle(req).await.into_response()
+        }
+    });
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    supervisor::spawn("webdav", async move {
+        let result = axum::serve(listener, app)
+            .with_graceful_shutdown(async {
+                shutdown_rx.await.ok();
+            })
+            .await;
+        if let Err(e) = result {
+            println!("WebDAV服务异常退出: {}", e);
+        }
+        println!("WebDAV服务已停止");
+    });
+
+    println!("WebDAV服务已启动，端口: {}", port);
+    *state = Some(RunningServer { port, secret, shutdown: shutdown_tx });
+    Ok(port)
+}
+
+// 停止WebDAV服务，没有在运行时返回false
+pub async fn stop() -> bool {
+    match server_state().lock().await.take() {
+        Some(server) => {
+            let _ = server.shutdown.send(());
+            true
+        }
+        None => false,
+    }
+}
+
+// 正在运行时返回监听的端口
+pub async fn running_port() -> Option<u16> {
+    server_state().lock().await.as_ref().map(|s| s.port)
+}
+
+// 正在运行时返回挂载地址（带这次启动的路径前缀）
+pub async fn running_url() -> Option<String> {
+    server_state().lock().await.as_ref().map(|s| mount_url(s.port, &s.secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_foreign_hosts_and_missing_secret() {
+        let check = |host, origin, path| check_request(host, origin, path, 8080, "s3cret");
+        assert_eq!(check(Some("127.0.0.1:8080"), None, "/s3cret/ds/a.txt"), Ok(()));
+        assert_eq!(check(Some("localhost:8080"), Some("http://localhost:8080"), "/s3cret"), Ok(()));
+        // DNS重绑定：Host是别的域名
+        assert_eq!(check(Some("evil.example:8080"), None, "/s3cret/"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(check(None, None, "/s3cret/"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(check(Some("127.0.0.1:8080"), Some("https://evil.example"), "/s3cret/"), Err(StatusCode::FORBIDDEN));
+        // 不知道前缀
+        assert_eq!(check(Some("127.0.0.1:8080"), None, "/"), Err(StatusCode::NOT_FOUND));
+        assert_eq!(check(Some("127.0.0.1:8080"), None, "/s3cretX/"), Err(StatusCode::NOT_FOUND));
+    }
+}
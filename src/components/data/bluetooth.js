@@ -40,6 +40,19 @@
 
 import { invoke } from '@tauri-apps/api/core'
 
+/**
+ * 取出命令错误的文字
+ * 
+ * get_totp、scan_cpen_devices、connect_cpen_device、get_device_id返回的是带kind字段的对象
+ * （{kind: 'timeout', message, ...}，见Rust端command_deadline模块），直接拼进字符串会变成[object Object]
+ * 
+ * @param {unknown} error invoke抛出的错误
+ * @returns {string} 错误信息
+ */
+function errorMessage(error) {
+  return typeof error === 'object' && error !== null && 'message' in error ? error.message : String(error)
+}
+
 /**
  * 获取TOTP
  * 
@@ -70,9 +83,9 @@ export async function getTotp() {
     
     return totp
   } catch (error) {
-    console.error(`获取TOTP失败: ${error}`)
-    // 直接抛出错误，让调用者处理
-    throw new Error(`获取TOTP失败: ${error}`)
+    console.error(`获取TOTP失败: ${errorMessage(error)}`)
+    // 直接抛出错误，让调用者处理（cause里是原始错误，可以看kind区分超时）
+    throw new Error(`获取TOTP失败: ${errorMessage(error)}`, { cause: error })
   }
 }
 
@@ -98,8 +111,8 @@ export async function scanCpenDevices() {
     
     return devices
   } catch (error) {
-    console.error(`扫描Cpen设备失败: ${error}`)
-    throw new Error(`扫描失败: ${error}`)
+    console.error(`扫描Cpen设备失败: ${errorMessage(error)}`)
+    throw new Error(`扫描失败: ${errorMessage(error)}`, { cause: error })
   }
 }
 
@@ -126,8 +139,8 @@ export async function connectCpenDevice(address) {
     
     return deviceInfo
   } catch (error) {
-    console.error(`连接Cpen设备失败: ${error}`)
-    throw new Error(`连接失败: ${error}`)
+    console.error(`连接Cpen设备失败: ${errorMessage(error)}`)
+    throw new Error(`连接失败: ${errorMessage(error)}`, { cause: error })
   }
 }
 
@@ -153,8 +166,8 @@ export async function getDeviceId() {
     
     return deviceId
   } catch (error) {
-    console.error(`获取设备ID失败: ${error}`)
-    throw new Error(`获取设备ID失败: ${error}`)
+    console.error(`获取设备ID失败: ${errorMessage(error)}`)
+    throw new Error(`获取设备ID失败: ${errorMessage(error)}`, { cause: error })
   }
 }
 